@@ -0,0 +1,243 @@
+use serde_json::{Map, Value};
+
+/// Longitude jump between consecutive vertices past which the segment is taken to cross the
+/// antimeridian instead of going most of the way around the globe.
+const CROSSING_SPAN: f64 = 180_f64;
+
+type Position = Vec<f64>;
+
+/// Longitude wrapped into -180..=180.
+fn wrap_longitude(longitude: f64) -> f64 {
+    if (-180_f64..=180_f64).contains(&longitude) {
+        return longitude
+    }
+    (longitude + 180_f64).rem_euclid(360_f64) - 180_f64
+}
+
+fn clamp_latitude(position: &mut Position) {
+    position[1] = position[1].clamp(-90_f64, 90_f64);
+}
+
+/// Positions of an Esri JSON part, None when any of them is not a numeric x/y(/z/m) array.
+fn positions(part: &Value) -> Option<Vec<Position>> {
+    part.as_array()?
+        .iter()
+        .map(|position| {
+            let position = position.as_array()?
+                .iter()
+                .map(Value::as_f64)
+                .collect::<Option<Position>>()?;
+            Some(position).filter(|position| position.len() >= 2)
+        })
+        .collect()
+}
+
+fn to_value(part: Vec<Position>) -> Value {
+    Value::from(part.into_iter().map(Value::from).collect::<Vec<Value>>())
+}
+
+/// Position where the segment from `start` to `end` reaches `longitude`, interpolating every
+/// other ordinate.
+fn interpolate(start: &Position, end: &Position, longitude: f64) -> Position {
+    let ratio = (longitude - start[0]) / (end[0] - start[0]);
+    let mut position: Position = start.iter()
+        .zip(end)
+        .map(|(a, b)| a + (b - a) * ratio)
+        .collect();
+    position[0] = longitude;
+    position
+}
+
+/// Split a path at every antimeridian crossing.
+fn split_path(path: Vec<Position>) -> Vec<Vec<Position>> {
+    let mut paths = vec![];
+    let mut current: Vec<Position> = vec![];
+    for mut position in path {
+        position[0] = wrap_longitude(position[0]);
+        clamp_latitude(&mut position);
+        if let Some(previous) = current.last() {
+            let span = position[0] - previous[0];
+            if span.abs() > CROSSING_SPAN {
+                let edge = 180_f64.copysign(previous[0]);
+                let mut shifted = position.clone();
+                shifted[0] += 360_f64.copysign(previous[0]);
+                let mut crossing = interpolate(previous, &shifted, edge);
+                current.push(crossing.clone());
+                paths.push(std::mem::take(&mut current));
+                crossing[0] = -edge;
+                current.push(crossing);
+            }
+        }
+        current.push(position);
+    }
+    paths.push(current);
+    paths.into_iter().filter(|path| path.len() > 1).collect()
+}
+
+/// Sutherland-Hodgman clip of an open ring against the half plane of longitudes on the `keep`
+/// side of `edge`.
+fn clip_ring(ring: &[Position], edge: f64, keep: impl Fn(f64) -> bool) -> Vec<Position> {
+    let mut clipped = vec![];
+    for (index, position) in ring.iter().enumerate() {
+        let previous = &ring[(index + ring.len() - 1) % ring.len()];
+        match (keep(previous[0]), keep(position[0])) {
+            (true, true) => clipped.push(position.clone()),
+            (true, false) => clipped.push(interpolate(previous, position, edge)),
+            (false, true) => {
+                clipped.push(interpolate(previous, position, edge));
+                clipped.push(position.clone());
+            }
+            (false, false) => {}
+        }
+    }
+    clipped
+}
+
+/// Cut a ring crossing the antimeridian into the rings on either side. Rings circling a pole
+/// (whose longitudes do not come back once unwrapped) are closed through that pole first.
+fn split_ring(ring: Vec<Position>) -> Vec<Vec<Position>> {
+    let mut ring = ring;
+    if ring.len() > 1 && ring.first() == ring.last() {
+        ring.pop();
+    }
+    if ring.len() < 3 {
+        return vec![]
+    }
+    ring[0][0] = wrap_longitude(ring[0][0]);
+    clamp_latitude(&mut ring[0]);
+    let mut crossed = false;
+    for index in 1..ring.len() {
+        ring[index][0] = wrap_longitude(ring[index][0]);
+        clamp_latitude(&mut ring[index]);
+        let span = ring[index][0] - ring[index - 1][0];
+        if span.abs() > CROSSING_SPAN {
+            ring[index][0] -= 360_f64.copysign(span);
+            crossed = true;
+        }
+    }
+    let first = ring[0].clone();
+    let last = ring[ring.len() - 1].clone();
+    let closing_span = first[0] - last[0];
+    if closing_span.abs() > CROSSING_SPAN {
+        let latitude_sum: f64 = ring.iter().map(|position| position[1]).sum();
+        let pole = 90_f64.copysign(latitude_sum);
+        let mut end = first.clone();
+        end[0] -= 360_f64.copysign(closing_span);
+        let mut last_pole = last.clone();
+        last_pole[1] = pole;
+        let mut end_pole = end.clone();
+        end_pole[1] = pole;
+        ring.push(end);
+        ring.push(end_pole);
+        ring.push(last_pole);
+        crossed = true;
+    }
+    if !crossed {
+        ring.push(first);
+        return vec![ring]
+    }
+    let mut rings = vec![];
+    for window in [-1_f64, 0_f64, 1_f64] {
+        let (min, max) = (360_f64 * window - 180_f64, 360_f64 * window + 180_f64);
+        let clipped = clip_ring(&ring, min, |longitude| longitude >= min);
+        let mut clipped = clip_ring(&clipped, max, |longitude| longitude <= max);
+        if clipped.len() < 3 {
+            continue
+        }
+        for position in clipped.iter_mut() {
+            position[0] -= 360_f64 * window;
+        }
+        clipped.push(clipped[0].clone());
+        rings.push(clipped);
+    }
+    rings
+}
+
+/// Split the paths and rings of an Esri JSON geometry in WGS84 that cross the antimeridian, so
+/// they no longer span the whole world, and clamp latitudes past the poles. Parts with
+/// positions that are not numeric are left as is.
+pub(crate) fn fix_antimeridian(geometry: &mut Map<String, Value>) {
+    if let (Some(x), Some(y)) = (
+        geometry.get("x").and_then(Value::as_f64),
+        geometry.get("y").and_then(Value::as_f64),
+    ) {
+        geometry.insert("x".to_owned(), Value::from(wrap_longitude(x)));
+        geometry.insert("y".to_owned(), Value::from(y.clamp(-90_f64, 90_f64)));
+    }
+    if let Some(points) = geometry.get_mut("points") {
+        if let Some(mut positions) = positions(points) {
+            for position in positions.iter_mut() {
+                position[0] = wrap_longitude(position[0]);
+                clamp_latitude(position);
+            }
+            *points = to_value(positions);
+        }
+    }
+    for (key, split) in [
+        ("paths", split_path as fn(Vec<Position>) -> Vec<Vec<Position>>),
+        ("rings", split_ring),
+    ] {
+        let parts = match geometry.get_mut(key).and_then(Value::as_array_mut) {
+            Some(parts) => parts,
+            None => continue,
+        };
+        let mut fixed = vec![];
+        for part in parts.drain(..) {
+            match positions(&part) {
+                Some(positions) => fixed.extend(split(positions).into_iter().map(to_value)),
+                None => fixed.push(part),
+            }
+        }
+        *parts = fixed;
+    }
+}
+
+#[cfg(test)]
+mod antimeridian_tests {
+    use serde_json::json;
+    use super::fix_antimeridian;
+
+    #[test]
+    fn fix_antimeridian_should_split_path_when_crossing() {
+        let mut geometry = json!({"paths": [[[178.0, 0.0], [-178.0, 4.0], [-177.0, 4.0]]]});
+        let geometry = geometry.as_object_mut().unwrap();
+        fix_antimeridian(geometry);
+        assert_eq!(
+            geometry["paths"],
+            json!([[[178.0, 0.0], [180.0, 2.0]], [[-180.0, 2.0], [-178.0, 4.0], [-177.0, 4.0]]]),
+        );
+    }
+
+    #[test]
+    fn fix_antimeridian_should_split_ring_when_crossing() {
+        let mut geometry = json!({"rings": [
+            [[179.0, 0.0], [179.0, 1.0], [-179.0, 1.0], [-179.0, 0.0], [179.0, 0.0]],
+        ]});
+        let geometry = geometry.as_object_mut().unwrap();
+        fix_antimeridian(geometry);
+        assert_eq!(
+            geometry["rings"],
+            json!([
+                [[180.0, 0.0], [179.0, 0.0], [179.0, 1.0], [180.0, 1.0], [180.0, 0.0]],
+                [[-180.0, 0.0], [-180.0, 1.0], [-179.0, 1.0], [-179.0, 0.0], [-180.0, 0.0]],
+            ]),
+        );
+    }
+
+    #[test]
+    fn fix_antimeridian_should_wrap_ring_when_past_antimeridian() {
+        let mut geometry = json!({"rings": [[[199.0, 0.0], [199.0, 1.0], [200.0, 1.0], [199.0, 0.0]]]});
+        let geometry = geometry.as_object_mut().unwrap();
+        fix_antimeridian(geometry);
+        assert_eq!(geometry["rings"], json!([[[-161.0, 0.0], [-161.0, 1.0], [-160.0, 1.0], [-161.0, 0.0]]]));
+    }
+
+    #[test]
+    fn fix_antimeridian_should_clamp_latitude_when_past_pole() {
+        let mut geometry = json!({"x": 190.0, "y": 90.5});
+        let geometry = geometry.as_object_mut().unwrap();
+        fix_antimeridian(geometry);
+        assert_eq!(geometry["x"], json!(-170.0));
+        assert_eq!(geometry["y"], json!(90.0));
+    }
+}
@@ -0,0 +1,159 @@
+#[cfg(feature = "archive")]
+use std::fs::read_dir;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+#[cfg(not(feature = "archive"))]
+use crate::capability::{Capability, CapabilityError};
+use crate::checksum::ChecksumFile;
+
+/// Compression level of `.tar.zst` archives, zstd's default.
+#[cfg(feature = "archive")]
+const ZSTD_LEVEL: i32 = 3;
+
+/// Where the archive of a run's output files is written.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ArchiveTarget {
+    /// Streamed to standard output, given as `-`
+    Stdout,
+    File(PathBuf),
+}
+
+impl ArchiveTarget {
+    pub(crate) fn from_path(path: &Path) -> Self {
+        if path.as_os_str() == "-" {
+            ArchiveTarget::Stdout
+        } else {
+            ArchiveTarget::File(path.to_owned())
+        }
+    }
+
+    /// Files ending in `.tar.zst` (or `.tzst`) are zstd compressed, everything else is a plain
+    /// tar. Pipe standard output through zstd to compress it.
+    fn is_zstd(&self) -> bool {
+        match self {
+            ArchiveTarget::Stdout => false,
+            ArchiveTarget::File(path) => {
+                let name = path.to_string_lossy().to_ascii_lowercase();
+                name.ends_with(".tar.zst") || name.ends_with(".tzst")
+            }
+        }
+    }
+}
+
+/// Every file under `directory`, subdirectories such as the attachments of a layer included,
+/// in name order so the same outputs always make the same archive.
+#[cfg(feature = "archive")]
+fn output_files(directory: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = read_dir(directory)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    paths.sort();
+    let mut files = vec![];
+    for path in paths {
+        if path.is_dir() {
+            files.extend(output_files(&path)?);
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Add every file under `directory` to a tar archive written to `writer`.
+#[cfg(feature = "archive")]
+fn append_files<W: Write>(directory: &Path, writer: W) -> io::Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    for path in output_files(directory)? {
+        builder.append_path_with_name(&path, path.strip_prefix(directory).unwrap())?;
+    }
+    builder.into_inner()
+}
+
+#[cfg(feature = "archive")]
+fn write_tar<W: Write>(directory: &Path, writer: W, zstd: bool) -> io::Result<W> {
+    if zstd {
+        append_files(directory, zstd::Encoder::new(writer, ZSTD_LEVEL)?)?.finish()
+    } else {
+        append_files(directory, writer)
+    }
+}
+
+#[cfg(not(feature = "archive"))]
+fn write_tar<W: Write>(_directory: &Path, _writer: W, _zstd: bool) -> io::Result<W> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, CapabilityError::Disabled(Capability::Archive)))
+}
+
+/// Archive the output files of a run to the target. `stdout` is the standard output reserved
+/// for the archive, required when streaming to it. Archive files are written under a `.part`
+/// name until complete and get a `.sha256` sidecar.
+pub(crate) fn write_archive(
+    directory: &Path,
+    target: &ArchiveTarget,
+    stdout: Option<&File>,
+) -> io::Result<()> {
+    match target {
+        ArchiveTarget::Stdout => {
+            let stdout = stdout.ok_or_else(|| io::Error::other("Standard output was not reserved for the archive"))?;
+            write_tar(directory, stdout, false)?.flush()
+        }
+        ArchiveTarget::File(path) => {
+            write_tar(directory, ChecksumFile::create_part(path)?, target.is_zstd())?.finish()?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(test, feature = "archive"))]
+mod archive_tests {
+    use std::fs::{create_dir_all, read, write, File};
+    use std::path::Path;
+    use super::{write_archive, ArchiveTarget};
+
+    #[test]
+    fn write_archive_should_compress_when_path_ends_in_tar_zst() {
+        let outputs = tempfile::tempdir().unwrap();
+        write(outputs.path().join("Parcels.csv"), "OBJECTID\n1\n").unwrap();
+        write(outputs.path().join("Roads.csv"), "OBJECTID\n2\n").unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("outputs.tar.zst");
+        write_archive(outputs.path(), &ArchiveTarget::from_path(&path), None).unwrap();
+
+        let decoder = zstd::Decoder::new(File::open(&path).unwrap()).unwrap();
+        let mut archive = tar::Archive::new(decoder);
+        let names: Vec<String> = archive.entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect();
+        assert_eq!(names, vec!["Parcels.csv", "Roads.csv"]);
+        assert!(!directory.path().join("outputs.tar.zst.part").exists());
+        assert!(directory.path().join("outputs.tar.zst.sha256").is_file());
+        assert!(read(&path).unwrap().starts_with(&[0x28, 0xb5, 0x2f, 0xfd]));
+    }
+
+    #[test]
+    fn write_archive_should_include_attachments_when_written_to_subdirectories() {
+        let outputs = tempfile::tempdir().unwrap();
+        write(outputs.path().join("Parcels.csv"), "OBJECTID,_ATTACHMENTS\n7,Parcels_attachments/7\n").unwrap();
+        create_dir_all(outputs.path().join("Parcels_attachments").join("7")).unwrap();
+        write(outputs.path().join("Parcels_attachments").join("7").join("deed.pdf"), "%PDF").unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("outputs.tar");
+        write_archive(outputs.path(), &ArchiveTarget::from_path(&path), None).unwrap();
+
+        let mut archive = tar::Archive::new(File::open(&path).unwrap());
+        let names: Vec<String> = archive.entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect();
+        assert_eq!(names, vec!["Parcels.csv", "Parcels_attachments/7/deed.pdf"]);
+    }
+
+    #[test]
+    fn from_path_should_stream_to_stdout_when_passed_dash() {
+        assert_eq!(ArchiveTarget::from_path(Path::new("-")), ArchiveTarget::Stdout);
+        let result = write_archive(Path::new("."), &ArchiveTarget::Stdout, None);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use reqwest::{Response, Url};
+use serde_json::{Map, Value};
+use tokio::sync::Semaphore;
+use crate::connection::HostConnections;
+use crate::metadata::{object_id, RestServiceMetadata};
+use crate::partition::file_name_part;
+use crate::report::{self, WarningKind};
+
+/// Column holding the directory the attachments of each feature were downloaded into.
+pub(crate) const ATTACHMENTS_COLUMN: &str = "_ATTACHMENTS";
+
+#[derive(Debug)]
+pub(crate) enum AttachmentError {
+    ErrorJsonResponse(String),
+    MissingKey(String),
+}
+
+impl Display for AttachmentError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AttachmentError::ErrorJsonResponse(error) => {
+                write!(f, "Attachment request returned an error: {}", error)
+            }
+            AttachmentError::MissingKey(key) => {
+                write!(f, "Attachment response is missing the key \"{}\"", key)
+            }
+        }
+    }
+}
+
+impl Error for AttachmentError {}
+
+/// Downloads the attachments of a layer's features (`{layer}/{oid}/attachments`) into a
+/// directory per feature.
+#[derive(Debug, Clone)]
+pub(crate) struct AttachmentDownloader {
+    layer_url: String,
+    layer_name: String,
+    oid_field: String,
+    output_path: PathBuf,
+    /// Directory of the layer's attachments, relative to `output_path`
+    directory: String,
+}
+
+impl AttachmentDownloader {
+    /// Downloader of the attachments of `layer` into `output_path`, None when the layer has no
+    /// attachments.
+    pub(crate) fn new(layer: &RestServiceMetadata, output_path: &Path) -> Option<Self> {
+        if !layer.supports_attachments() {
+            return None
+        }
+        Some(Self {
+            layer_url: layer.url.to_owned(),
+            layer_name: layer.name.to_owned(),
+            oid_field: layer.oid_field_name()?.to_owned(),
+            output_path: output_path.to_owned(),
+            directory: format!("{}_attachments", layer.name),
+        })
+    }
+
+    /// Download the attachments of `feature`, returning the directory holding them relative to
+    /// the output directory. Empty when the feature has none or they could not be downloaded,
+    /// which is warned about.
+    pub(crate) async fn download(
+        &self,
+        connections: &HostConnections,
+        permits: Option<&Semaphore>,
+        feature: &Map<String, Value>,
+    ) -> String {
+        let oid = match feature.get("attributes").and_then(|attributes| attributes.get(&self.oid_field)).and_then(object_id) {
+            Some(oid) => oid,
+            None => return String::new(),
+        };
+        match self.try_download(connections, permits, oid).await {
+            Ok(directory) => directory,
+            Err(error) => {
+                report::warn_about(WarningKind::Skipped, &self.layer_name, format_args!(
+                    "Attachments of feature {} of {} were not downloaded: {}",
+                    oid,
+                    self.layer_name,
+                    error,
+                ));
+                String::new()
+            }
+        }
+    }
+
+    async fn try_download(
+        &self,
+        connections: &HostConnections,
+        permits: Option<&Semaphore>,
+        oid: i64,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let attachments_url = format!("{}/{}/attachments", self.layer_url, oid);
+        let infos_url = Url::parse_with_params(&attachments_url, [("f", "json")])?;
+        let infos: Value = send(connections, permits, infos_url).await?.json().await?;
+        if let Some(error) = infos.get("error") {
+            return Err(AttachmentError::ErrorJsonResponse(error.to_string()).into())
+        }
+        let infos = infos["attachmentInfos"]
+            .as_array()
+            .ok_or(AttachmentError::MissingKey("attachmentInfos".to_owned()))?;
+        if infos.is_empty() {
+            return Ok(String::new())
+        }
+        let relative_directory = format!("{}/{}", self.directory, oid);
+        let directory = self.output_path.join(&relative_directory);
+        create_dir_all(&directory)?;
+        for info in infos {
+            let id = info["id"]
+                .as_i64()
+                .ok_or(AttachmentError::MissingKey("attachmentInfos[id]".to_owned()))?;
+            let url = Url::parse(&format!("{}/{}", attachments_url, id))?;
+            let contents = send(connections, permits, url).await?.bytes().await?;
+            // Prefixed with the id since a feature's attachments may share a name
+            let name = info["name"].as_str().unwrap_or_default();
+            std::fs::write(directory.join(format!("{}_{}", id, file_name_part(name))), contents)?;
+        }
+        Ok(relative_directory)
+    }
+}
+
+async fn send(
+    connections: &HostConnections,
+    permits: Option<&Semaphore>,
+    url: Url,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    let _permit = match permits {
+        Some(permits) => Some(permits.acquire().await?),
+        None => None,
+    };
+    let (client, _) = connections.client();
+    Ok(client.get(url).await?.send().await?.error_for_status()?)
+}
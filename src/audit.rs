@@ -0,0 +1,154 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use clap::ValueEnum;
+use reqwest::header::USER_AGENT;
+use reqwest::RequestBuilder;
+use serde::{Deserialize, Serialize};
+
+/// Header carrying the run id when sent as a header.
+pub(crate) const RUN_ID_HEADER: &str = "X-Scraper-Run-Id";
+/// Query parameter carrying the run id when sent as a parameter. ArcGIS Server ignores unknown
+/// parameters but keeps them in its request logs.
+pub(crate) const RUN_ID_PARAMETER: &str = "scraperRunId";
+
+/// Where the tool comes from, given in the User-Agent so server admins can look us up.
+const PROJECT_URL: &str = "https://github.com/ClasicRando/arcgis_scraper";
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum ContactError {
+    InvalidEmail(String),
+}
+
+impl Display for ContactError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContactError::InvalidEmail(value) => write!(f, "\"{}\" is not an email address", value),
+        }
+    }
+}
+
+impl Error for ContactError {}
+
+/// Parse the contact email given in the User-Agent. Only the general shape is checked since
+/// the address is for people to read.
+pub(crate) fn parse_contact(value: &str) -> Result<String, ContactError> {
+    let trimmed = value.trim();
+    let is_email = trimmed.split_once('@')
+        .is_some_and(|(user, domain)| !user.is_empty() && domain.contains('.') && !domain.starts_with('.'))
+        && !trimmed.chars().any(|chr| chr.is_whitespace() || matches!(chr, ';' | '(' | ')'));
+    if !is_email {
+        return Err(ContactError::InvalidEmail(value.to_owned()))
+    }
+    Ok(trimmed.to_owned())
+}
+
+/// User-Agent sent with every request, naming the tool, its version and where it comes from,
+/// plus a contact email when given, as many public GIS endpoints require of sustained scraping.
+pub(crate) fn user_agent(contact: Option<&str>) -> String {
+    let mut comment = format!("+{}", PROJECT_URL);
+    if let Some(contact) = contact {
+        comment.push_str(&format!("; {}", contact));
+    }
+    format!("{}/{} ({})", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), comment)
+}
+
+/// Random (version 4) UUID identifying a single run, so server admins can correlate our traffic
+/// with the run history and progress events.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub(crate) struct RunId(String);
+
+impl RunId {
+    pub(crate) fn generate() -> Self {
+        let mut bytes: [u8; 16] = rand::random();
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        Self(format!(
+            "{}-{}-{}-{}-{}",
+            &hex[0..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..32],
+        ))
+    }
+}
+
+impl Display for RunId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Where the run id is placed in every request sent to a service.
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+pub(crate) enum RunIdPlacement {
+    /// The X-Scraper-Run-Id header
+    Header,
+    /// The scraperRunId query parameter, for servers that only log URLs
+    Query,
+}
+
+/// Run id and where it goes, along with the User-Agent, attached to requests by the service
+/// client.
+#[derive(Debug, Clone)]
+pub(crate) struct RunAudit {
+    pub(crate) run_id: RunId,
+    pub(crate) placement: RunIdPlacement,
+    pub(crate) user_agent: String,
+}
+
+impl RunAudit {
+    pub(crate) fn attach_to_request(&self, request: RequestBuilder) -> RequestBuilder {
+        let request = request.header(USER_AGENT, &self.user_agent);
+        match self.placement {
+            RunIdPlacement::Header => request.header(RUN_ID_HEADER, self.run_id.to_string()),
+            RunIdPlacement::Query => request.query(&[(RUN_ID_PARAMETER, self.run_id.to_string())]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod audit_tests {
+    use super::{parse_contact, user_agent, ContactError, RunAudit, RunId, RunIdPlacement};
+
+    #[test]
+    fn generate_should_format_version_4_uuid() {
+        let run_id = RunId::generate().to_string();
+        let groups: Vec<&str> = run_id.split('-').collect();
+        assert_eq!(groups.iter().map(|group| group.len()).collect::<Vec<_>>(), vec![8, 4, 4, 4, 12]);
+        assert!(groups[2].starts_with('4'));
+        assert!(matches!(groups[3].chars().next(), Some('8' | '9' | 'a' | 'b')));
+    }
+
+    #[test]
+    fn attach_to_request_should_add_query_parameter_when_placement_is_query() {
+        let audit = RunAudit {
+            run_id: RunId("b1946ac9-2a4b-4c5d-8e6f-0123456789ab".to_owned()),
+            placement: RunIdPlacement::Query,
+            user_agent: user_agent(None),
+        };
+        let request = audit.attach_to_request(reqwest::Client::new().get("https://example.com/query?f=json"))
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.url().query(),
+            Some("f=json&scraperRunId=b1946ac9-2a4b-4c5d-8e6f-0123456789ab"),
+        );
+        assert_eq!(request.headers()["user-agent"], user_agent(None).as_str());
+    }
+
+    #[test]
+    fn user_agent_should_include_contact_when_given() {
+        let contact = parse_contact("gis-team@example.com").unwrap();
+        assert_eq!(
+            user_agent(Some(&contact)),
+            format!(
+                "arcgis_scraper/{} (+https://github.com/ClasicRando/arcgis_scraper; gis-team@example.com)",
+                env!("CARGO_PKG_VERSION"),
+            ),
+        );
+        assert_eq!(parse_contact("gis team"), Err(ContactError::InvalidEmail("gis team".to_owned())));
+    }
+}
@@ -0,0 +1,494 @@
+use std::env;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use clap::ValueEnum;
+use reqwest::header::USER_AGENT;
+use reqwest::RequestBuilder;
+use serde_json::Value;
+use tokio::sync::{Mutex, OnceCell};
+
+/// Future returned by [`AuthProvider`] methods, boxed so the trait stays object safe.
+pub type AuthFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, Box<dyn Error + Send + Sync>>> + Send + 'a>>;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum AuthError {
+    MissingSetting(String),
+    TokenRequest(String),
+    /// Server whose `rest/info` does not publish a token endpoint
+    TokenUrlDiscovery(String),
+    /// The server rejected a token that cannot be refreshed
+    StaticTokenRejected,
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::MissingSetting(setting) => {
+                write!(f, "Authentication requires {}", setting)
+            }
+            AuthError::TokenRequest(raw_json) => {
+                write!(f, "Could not obtain a token. Raw JSON:\n{}", raw_json)
+            }
+            AuthError::TokenUrlDiscovery(info_url) => {
+                write!(f, "{} does not publish a token endpoint, pass --token-url", info_url)
+            }
+            AuthError::StaticTokenRejected => {
+                write!(f, "The server rejected the ARCGIS_TOKEN token, it has probably expired. Generate a new one")
+            }
+        }
+    }
+}
+
+impl Error for AuthError {}
+
+/// Source of the credentials attached to every request sent to a service. Implement this for
+/// identity providers not covered by the built in methods.
+pub trait AuthProvider: Send + Sync {
+    /// Current token, obtaining one first when none is cached. None for providers without tokens.
+    fn get_token(&self) -> AuthFuture<'_, Option<String>>;
+
+    /// Obtain a new token after the server rejected `rejected`. Nothing happens when the cached
+    /// token was already replaced, so concurrent requests failing together refresh only once.
+    fn refresh<'a>(&'a self, rejected: Option<&'a str>) -> AuthFuture<'a, ()>;
+
+    /// Add the credentials to a request. By default the token is sent as the `token` parameter,
+    /// which every ArcGIS Server version accepts.
+    fn attach_to_request(&self, request: RequestBuilder) -> AuthFuture<'_, RequestBuilder> {
+        Box::pin(async move {
+            Ok(match self.get_token().await? {
+                Some(token) => request.query(&[("token", token)]),
+                None => request,
+            })
+        })
+    }
+}
+
+/// Public services, nothing is attached.
+pub(crate) struct AnonymousAuth;
+
+impl AuthProvider for AnonymousAuth {
+    fn get_token(&self) -> AuthFuture<'_, Option<String>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    fn refresh<'a>(&'a self, _rejected: Option<&'a str>) -> AuthFuture<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn attach_to_request(&self, request: RequestBuilder) -> AuthFuture<'_, RequestBuilder> {
+        Box::pin(async { Ok(request) })
+    }
+}
+
+/// Long lived developer API key, sent in place of a token.
+pub(crate) struct ApiKeyAuth {
+    api_key: String,
+}
+
+impl ApiKeyAuth {
+    pub(crate) fn new(api_key: &str) -> Self {
+        Self { api_key: api_key.to_owned() }
+    }
+}
+
+impl AuthProvider for ApiKeyAuth {
+    fn get_token(&self) -> AuthFuture<'_, Option<String>> {
+        Box::pin(async { Ok(Some(self.api_key.to_owned())) })
+    }
+
+    fn refresh<'a>(&'a self, _rejected: Option<&'a str>) -> AuthFuture<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Token generated beforehand (e.g. from the portal's generateToken page). Nothing can replace
+/// it, so the scrape fails once the server rejects it.
+pub(crate) struct StaticTokenAuth {
+    token: String,
+}
+
+impl StaticTokenAuth {
+    pub(crate) fn new(token: &str) -> Self {
+        Self { token: token.to_owned() }
+    }
+}
+
+impl AuthProvider for StaticTokenAuth {
+    fn get_token(&self) -> AuthFuture<'_, Option<String>> {
+        Box::pin(async { Ok(Some(self.token.to_owned())) })
+    }
+
+    fn refresh<'a>(&'a self, _rejected: Option<&'a str>) -> AuthFuture<'a, ()> {
+        Box::pin(async { Err(AuthError::StaticTokenRejected.into()) })
+    }
+}
+
+/// `rest/info` url of the server hosting `service_url`.
+fn info_url(service_url: &str) -> Option<String> {
+    let index = service_url.find("/rest/")?;
+    Some(format!("{}/rest/info", &service_url[..index]))
+}
+
+/// Token endpoint the server hosting `service_url` publishes in its `rest/info`.
+async fn discover_token_url(service_url: &str, user_agent: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let info_url = info_url(service_url).ok_or_else(|| AuthError::MissingSetting("--token-url".to_owned()))?;
+    let info_json: Value = reqwest::Client::new()
+        .get(&info_url)
+        .header(USER_AGENT, user_agent)
+        .query(&[("f", "json")])
+        .send()
+        .await?
+        .json()
+        .await?;
+    let token_url = info_json["authInfo"]["tokenServicesUrl"]
+        .as_str()
+        .ok_or(AuthError::TokenUrlDiscovery(info_url))?;
+    Ok(token_url.to_owned())
+}
+
+/// Tokens are replaced this long before they expire, so requests in flight never carry a token
+/// that expires mid scrape.
+const REFRESH_MARGIN_MINUTES: i64 = 5;
+
+/// Token and when it expires, if the token endpoint said so.
+#[derive(Debug, Clone)]
+struct CachedToken {
+    value: String,
+    expires: Option<DateTime<Utc>>,
+}
+
+impl CachedToken {
+    fn expires_soon(&self, now: DateTime<Utc>) -> bool {
+        match self.expires {
+            Some(expires) => expires - Duration::minutes(REFRESH_MARGIN_MINUTES) <= now,
+            None => false,
+        }
+    }
+}
+
+/// Expiry of a `generateToken` response, given as epoch milliseconds.
+fn generate_token_expiry(token_json: &Value) -> Option<DateTime<Utc>> {
+    token_json["expires"].as_i64().and_then(|millis| Utc.timestamp_millis_opt(millis).single())
+}
+
+/// Expiry of an OAuth2 token response, given as seconds from now.
+fn oauth2_expiry(token_json: &Value) -> Option<DateTime<Utc>> {
+    token_json["expires_in"].as_i64().map(|seconds| Utc::now() + Duration::seconds(seconds))
+}
+
+/// POST a form to a token endpoint and read the token from `token_key` of the JSON response.
+async fn request_token(
+    token_url: &str,
+    user_agent: &str,
+    form: &[(&str, &str)],
+    token_key: &str,
+    expiry: fn(&Value) -> Option<DateTime<Utc>>,
+) -> Result<CachedToken, Box<dyn Error + Send + Sync>> {
+    let token_json: Value = reqwest::Client::new()
+        .post(token_url)
+        .header(USER_AGENT, user_agent)
+        .form(form)
+        .send()
+        .await?
+        .json()
+        .await?;
+    let token = token_json[token_key]
+        .as_str()
+        .ok_or_else(|| AuthError::TokenRequest(token_json.to_string()))?;
+    Ok(CachedToken { value: token.to_owned(), expires: expiry(&token_json) })
+}
+
+/// Token shared by every request of a run. The lock is held while a new token is requested so
+/// concurrent requests wait for it rather than requesting their own.
+#[derive(Debug, Default)]
+struct TokenCache {
+    token: Mutex<Option<CachedToken>>,
+}
+
+impl TokenCache {
+    async fn get<F, Fut>(&self, request: F) -> Result<String, Box<dyn Error + Send + Sync>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<CachedToken, Box<dyn Error + Send + Sync>>>,
+    {
+        let mut token = self.token.lock().await;
+        match token.as_ref() {
+            Some(cached) if !cached.expires_soon(Utc::now()) => Ok(cached.value.to_owned()),
+            _ => {
+                let new_token = request().await?;
+                let value = new_token.value.to_owned();
+                *token = Some(new_token);
+                Ok(value)
+            }
+        }
+    }
+
+    async fn refresh<F, Fut>(
+        &self,
+        rejected: Option<&str>,
+        request: F,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<CachedToken, Box<dyn Error + Send + Sync>>>,
+    {
+        let mut token = self.token.lock().await;
+        if let (Some(cached), Some(rejected)) = (token.as_ref(), rejected) {
+            if cached.value != rejected && !cached.expires_soon(Utc::now()) {
+                return Ok(())
+            }
+        }
+        *token = Some(request().await?);
+        Ok(())
+    }
+}
+
+/// Username and password exchanged for a token at an ArcGIS `generateToken` endpoint, the one
+/// published by the server of `service_url` unless given.
+pub(crate) struct TokenAuth {
+    token_url: OnceCell<String>,
+    service_url: Option<String>,
+    username: String,
+    password: String,
+    user_agent: String,
+    token: TokenCache,
+}
+
+impl TokenAuth {
+    pub(crate) fn new(
+        token_url: Option<&str>,
+        service_url: Option<&str>,
+        username: &str,
+        password: &str,
+        user_agent: &str,
+    ) -> Self {
+        Self {
+            token_url: OnceCell::new_with(token_url.map(str::to_owned)),
+            service_url: service_url.map(str::to_owned),
+            username: username.to_owned(),
+            password: password.to_owned(),
+            user_agent: user_agent.to_owned(),
+            token: TokenCache::default(),
+        }
+    }
+
+    async fn generate_token(&self) -> Result<CachedToken, Box<dyn Error + Send + Sync>> {
+        let token_url = self.token_url
+            .get_or_try_init(|| async {
+                let service_url = self.service_url
+                    .as_deref()
+                    .ok_or_else(|| AuthError::MissingSetting("--token-url".to_owned()))?;
+                discover_token_url(service_url, &self.user_agent).await
+            })
+            .await?;
+        request_token(
+            token_url,
+            &self.user_agent,
+            &[
+                ("username", self.username.as_str()),
+                ("password", self.password.as_str()),
+                ("client", "requestip"),
+                ("f", "json"),
+            ],
+            "token",
+            generate_token_expiry,
+        ).await
+    }
+}
+
+impl AuthProvider for TokenAuth {
+    fn get_token(&self) -> AuthFuture<'_, Option<String>> {
+        Box::pin(async { Ok(Some(self.token.get(|| self.generate_token()).await?)) })
+    }
+
+    fn refresh<'a>(&'a self, rejected: Option<&'a str>) -> AuthFuture<'a, ()> {
+        Box::pin(async move { self.token.refresh(rejected, || self.generate_token()).await })
+    }
+}
+
+/// OAuth2 client credentials grant, for applications registered with the portal.
+pub(crate) struct OAuth2Auth {
+    token_url: String,
+    client_id: String,
+    client_secret: String,
+    user_agent: String,
+    token: TokenCache,
+}
+
+impl OAuth2Auth {
+    pub(crate) fn new(token_url: &str, client_id: &str, client_secret: &str, user_agent: &str) -> Self {
+        Self {
+            token_url: token_url.to_owned(),
+            client_id: client_id.to_owned(),
+            client_secret: client_secret.to_owned(),
+            user_agent: user_agent.to_owned(),
+            token: TokenCache::default(),
+        }
+    }
+
+    async fn request_access_token(&self) -> Result<CachedToken, Box<dyn Error + Send + Sync>> {
+        request_token(
+            &self.token_url,
+            &self.user_agent,
+            &[
+                ("client_id", self.client_id.as_str()),
+                ("client_secret", self.client_secret.as_str()),
+                ("grant_type", "client_credentials"),
+                ("f", "json"),
+            ],
+            "access_token",
+            oauth2_expiry,
+        ).await
+    }
+}
+
+impl AuthProvider for OAuth2Auth {
+    fn get_token(&self) -> AuthFuture<'_, Option<String>> {
+        Box::pin(async { Ok(Some(self.token.get(|| self.request_access_token()).await?)) })
+    }
+
+    fn refresh<'a>(&'a self, rejected: Option<&'a str>) -> AuthFuture<'a, ()> {
+        Box::pin(async move { self.token.refresh(rejected, || self.request_access_token()).await })
+    }
+}
+
+/// Built in authentication methods selectable from the command line.
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+pub(crate) enum AuthMethod {
+    /// No credentials
+    Anonymous,
+    /// --username and the ARCGIS_PASSWORD variable exchanged at --token-url (generateToken),
+    /// by default the token endpoint the server publishes. Tokens are renewed before they expire
+    Token,
+    /// A token generated beforehand, from the ARCGIS_TOKEN variable. It cannot be renewed
+    StaticToken,
+    /// --client-id and the ARCGIS_CLIENT_SECRET variable exchanged at --token-url
+    Oauth2,
+    /// The ARCGIS_API_KEY variable
+    ApiKey,
+}
+
+/// Command line settings of the built in methods. Secrets are read from environment variables so
+/// they never end up in the run history.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AuthSettings {
+    pub(crate) token_url: Option<String>,
+    /// Service the token endpoint is discovered from when no token url is given
+    pub(crate) service_url: Option<String>,
+    pub(crate) username: Option<String>,
+    pub(crate) client_id: Option<String>,
+    /// Sent when requesting tokens, like every other request
+    pub(crate) user_agent: String,
+}
+
+fn required(value: &Option<String>, setting: &str) -> Result<String, AuthError> {
+    value.to_owned().ok_or_else(|| AuthError::MissingSetting(setting.to_owned()))
+}
+
+fn required_env(name: &str) -> Result<String, AuthError> {
+    env::var(name).map_err(|_| AuthError::MissingSetting(format!("the {} environment variable", name)))
+}
+
+impl AuthMethod {
+    pub(crate) fn provider(
+        &self,
+        settings: &AuthSettings,
+    ) -> Result<Box<dyn AuthProvider>, AuthError> {
+        let provider: Box<dyn AuthProvider> = match self {
+            AuthMethod::Anonymous => Box::new(AnonymousAuth),
+            AuthMethod::Token => {
+                if settings.token_url.is_none() && settings.service_url.is_none() {
+                    return Err(AuthError::MissingSetting("--token-url".to_owned()))
+                }
+                Box::new(TokenAuth::new(
+                    settings.token_url.as_deref(),
+                    settings.service_url.as_deref(),
+                    &required(&settings.username, "--username")?,
+                    &required_env("ARCGIS_PASSWORD")?,
+                    &settings.user_agent,
+                ))
+            }
+            AuthMethod::StaticToken => Box::new(StaticTokenAuth::new(&required_env("ARCGIS_TOKEN")?)),
+            AuthMethod::Oauth2 => Box::new(OAuth2Auth::new(
+                &required(&settings.token_url, "--token-url")?,
+                &required(&settings.client_id, "--client-id")?,
+                &required_env("ARCGIS_CLIENT_SECRET")?,
+                &settings.user_agent,
+            )),
+            AuthMethod::ApiKey => Box::new(ApiKeyAuth::new(&required_env("ARCGIS_API_KEY")?)),
+        };
+        Ok(provider)
+    }
+}
+
+#[cfg(test)]
+mod auth_tests {
+    use chrono::{Duration, TimeZone, Utc};
+    use serde_json::json;
+    use super::{
+        generate_token_expiry, info_url, ApiKeyAuth, AuthError, AuthMethod, AuthProvider, AuthSettings,
+        CachedToken, StaticTokenAuth, TokenCache,
+    };
+
+    #[tokio::test]
+    async fn attach_to_request_should_add_token_parameter_when_using_api_key() {
+        let auth = ApiKeyAuth::new("abc123");
+        let request = auth.attach_to_request(reqwest::Client::new().get("https://example.com/query?f=json"))
+            .await
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(request.url().query(), Some("f=json&token=abc123"));
+    }
+
+    #[test]
+    fn provider_should_fail_when_token_url_missing() {
+        let result = AuthMethod::Token.provider(&AuthSettings::default());
+        assert_eq!(result.err(), Some(AuthError::MissingSetting("--token-url".to_owned())));
+    }
+
+    #[test]
+    fn info_url_should_point_to_server_root_when_passed_layer_url() {
+        assert_eq!(
+            info_url("https://example.com/arcgis/rest/services/Parcels/FeatureServer/0").as_deref(),
+            Some("https://example.com/arcgis/rest/info"),
+        );
+        assert_eq!(info_url("https://example.com/Parcels"), None);
+    }
+
+    #[tokio::test]
+    async fn refresh_should_fail_when_static_token_rejected() {
+        let auth = StaticTokenAuth::new("abc123");
+        assert_eq!(auth.get_token().await.unwrap().as_deref(), Some("abc123"));
+        assert!(auth.refresh(Some("abc123")).await.is_err());
+    }
+
+    #[test]
+    fn generate_token_expiry_should_read_epoch_millis_when_passed_expires() {
+        let expiry = generate_token_expiry(&json!({"token": "abc", "expires": 1656676800000_i64}));
+        assert_eq!(expiry, Some(Utc.with_ymd_and_hms(2022, 7, 1, 12, 0, 0).unwrap()));
+    }
+
+    #[test]
+    fn expires_soon_should_return_true_when_within_refresh_margin() {
+        let now = Utc::now();
+        let token = CachedToken { value: "abc".to_owned(), expires: Some(now + Duration::minutes(2)) };
+        assert!(token.expires_soon(now));
+        let token = CachedToken { value: "abc".to_owned(), expires: Some(now + Duration::hours(2)) };
+        assert!(!token.expires_soon(now));
+    }
+
+    #[tokio::test]
+    async fn refresh_should_keep_token_when_rejected_token_already_replaced() {
+        let cache = TokenCache::default();
+        let fresh = || async {
+            Ok(CachedToken { value: "new".to_owned(), expires: None })
+        };
+        cache.get(fresh).await.unwrap();
+        cache.refresh(Some("old"), || async { panic!("token should not be requested") }).await.unwrap();
+        assert_eq!(cache.get(fresh).await.unwrap(), "new");
+    }
+}
@@ -0,0 +1,286 @@
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use reqwest::StatusCode;
+use serde::Serialize;
+use crate::audit::RunId;
+use crate::deadline::DeadlineError;
+use crate::disk::DiskSpaceError;
+use crate::meter::RequestLimitError;
+use crate::report;
+use crate::scraping::RestServiceScrapingError;
+use crate::service::ServiceError;
+
+/// Outcome of a layer of the run, ordered from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LayerOutcome {
+    Success,
+    SuccessWithWarnings,
+    /// Failure expected to go away on a later run (timeouts, server errors, disk space)
+    RetryableFailure,
+    /// Failure that will happen again until the arguments or the layer change
+    PermanentFailure,
+}
+
+impl Display for LayerOutcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LayerOutcome::Success => write!(f, "success"),
+            LayerOutcome::SuccessWithWarnings => write!(f, "success with warnings"),
+            LayerOutcome::RetryableFailure => write!(f, "retryable failure"),
+            LayerOutcome::PermanentFailure => write!(f, "permanent failure"),
+        }
+    }
+}
+
+/// What is written for layers whose count query found no features.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub(crate) enum EmptyLayerPolicy {
+    /// An output file without features (a CSV header, an empty GeoParquet file or topology),
+    /// written by scraping the layer as usual
+    Write,
+    /// No output file, the layer is not scraped
+    Skip,
+}
+
+pub(crate) enum BatchError {
+    /// Layers that failed with --keep-going, by the worst of their outcomes
+    LayersFailed(LayerOutcome, usize),
+    /// Layers without features when --allow-empty is not set
+    EmptyLayers(Vec<String>),
+}
+
+/// Errors returned from main are printed with Debug, show the message instead of the variant.
+impl Debug for BatchError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Display for BatchError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BatchError::LayersFailed(outcome, count) => {
+                write!(f, "{} layers failed, the worst with a {}", count, outcome)
+            }
+            BatchError::EmptyLayers(names) => write!(
+                f,
+                "{} layers have no features and were not written ({}), pass --allow-empty to accept them",
+                names.len(),
+                names.join(", "),
+            ),
+        }
+    }
+}
+
+impl Error for BatchError {}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::REQUEST_TIMEOUT
+}
+
+/// Whether rerunning the scrape later could get past `error`. Requests that ran out of tries,
+/// stalled or failed at the transport level, servers that were overloaded, a full disk and a run
+/// interrupted or stopped by --max-duration or --max-requests are retryable. Everything else
+/// (missing layers, rejected parameters or credentials, unexpected responses) is permanent.
+pub(crate) fn classify_error(error: &(dyn Error + Send + Sync + 'static)) -> LayerOutcome {
+    if let Some(BatchError::LayersFailed(outcome, _)) = error.downcast_ref() {
+        return *outcome
+    }
+    let retryable = if let Some(error) = error.downcast_ref::<RestServiceScrapingError>() {
+        match error {
+            RestServiceScrapingError::TooManyRetires(_)
+            | RestServiceScrapingError::Stalled(_)
+            | RestServiceScrapingError::TimedOut(_) => true,
+            RestServiceScrapingError::InvalidResponse(status) => is_retryable_status(*status),
+            _ => false,
+        }
+    } else if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+        error.is_timeout() || error.is_connect() || error.is_body() || error.status().is_some_and(is_retryable_status)
+    } else {
+        error.is::<DeadlineError>()
+            || error.is::<RequestLimitError>()
+            || error.is::<ServiceError>()
+            || error.is::<DiskSpaceError>()
+            || error.is::<io::Error>()
+    };
+    if retryable {
+        LayerOutcome::RetryableFailure
+    } else {
+        LayerOutcome::PermanentFailure
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct LayerResult {
+    pub(crate) url: String,
+    pub(crate) name: String,
+    pub(crate) outcome: LayerOutcome,
+    pub(crate) features: usize,
+    pub(crate) warnings: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+/// Outcome of every layer of a run, written to --batch-report so orchestrators can rerun only
+/// the retryable layers.
+#[derive(Debug, Serialize)]
+pub(crate) struct BatchReport {
+    run_id: String,
+    created_at: DateTime<Utc>,
+    /// Worst outcome of the layers and of the run itself
+    outcome: LayerOutcome,
+    /// Error that stopped the run before every layer was attempted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    pub(crate) layers: Vec<LayerResult>,
+    #[serde(skip)]
+    keep_going: bool,
+    #[serde(skip)]
+    warnings_at_start: usize,
+}
+
+impl BatchReport {
+    pub(crate) fn new(run_id: &RunId, keep_going: bool) -> Self {
+        Self {
+            run_id: run_id.to_string(),
+            created_at: Utc::now(),
+            outcome: LayerOutcome::Success,
+            error: None,
+            layers: vec![],
+            keep_going,
+            warnings_at_start: 0,
+        }
+    }
+
+    /// Start counting the warnings of the next layer, along with the warnings about it by name.
+    pub(crate) fn start_layer(&mut self) {
+        self.warnings_at_start = report::warnings().len();
+    }
+
+    fn push(&mut self, url: &str, name: &str, features: usize, error: Option<&(dyn Error + Send + Sync + 'static)>) {
+        let warnings = report::warnings().iter()
+            .enumerate()
+            .filter(|(index, warning)| *index >= self.warnings_at_start || warning.layer.as_deref() == Some(name))
+            .count();
+        let outcome = match error {
+            Some(error) => classify_error(error),
+            None if warnings > 0 => LayerOutcome::SuccessWithWarnings,
+            None => LayerOutcome::Success,
+        };
+        self.outcome = self.outcome.max(outcome);
+        self.layers.push(LayerResult {
+            url: url.to_owned(),
+            name: name.to_owned(),
+            outcome,
+            features,
+            warnings,
+            error: error.map(ToString::to_string),
+        });
+    }
+
+    /// Record a layer scraped without error.
+    pub(crate) fn succeed(&mut self, url: &str, name: &str, features: usize) {
+        self.push(url, name, features, None);
+    }
+
+    /// Record a layer that failed with `error`. Without --keep-going, and for errors that stop
+    /// the whole run (--max-duration, --max-requests), the error is handed back to end the run.
+    pub(crate) fn fail(
+        &mut self,
+        url: &str,
+        name: &str,
+        error: Box<dyn Error + Send + Sync>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.keep_going || error.is::<DeadlineError>() || error.is::<RequestLimitError>() {
+            return Err(error)
+        }
+        report::warn(format_args!("Could not scrape layer \"{}\" ({}). {}", name, url, error));
+        self.push(url, name, 0, Some(error.as_ref()));
+        Ok(())
+    }
+
+    /// Layers that failed, as the error ending the run. None when every layer succeeded.
+    pub(crate) fn failures(&self) -> Option<BatchError> {
+        let failed = self.layers.iter()
+            .filter(|layer| layer.outcome >= LayerOutcome::RetryableFailure)
+            .count();
+        (failed > 0).then_some(BatchError::LayersFailed(self.outcome, failed))
+    }
+
+    /// Complete the report with the error that ended the run, if it was not a layer failure.
+    pub(crate) fn finish(&mut self, error: Option<&(dyn Error + Send + Sync + 'static)>) {
+        if let Some(error) = error.filter(|error| !error.is::<BatchError>()) {
+            self.outcome = self.outcome.max(classify_error(error));
+            self.error = Some(error.to_string());
+        }
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        writeln!(file)?;
+        file.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod batch_tests {
+    use std::io;
+    use std::time::Duration;
+    use reqwest::StatusCode;
+    use crate::audit::RunId;
+    use crate::deadline::DeadlineError;
+    use crate::metadata::RestServiceMetadataError;
+    use crate::meter::RequestLimitError;
+    use crate::scraping::RestServiceScrapingError;
+    use super::{classify_error, BatchError, BatchReport, LayerOutcome};
+
+    #[test]
+    fn classify_error_should_mark_retryable_when_failure_is_transient() {
+        assert_eq!(classify_error(&RestServiceScrapingError::TooManyRetires(5)), LayerOutcome::RetryableFailure);
+        assert_eq!(
+            classify_error(&RestServiceScrapingError::InvalidResponse(StatusCode::SERVICE_UNAVAILABLE)),
+            LayerOutcome::RetryableFailure,
+        );
+        assert_eq!(classify_error(&RestServiceScrapingError::TimedOut(30)), LayerOutcome::RetryableFailure);
+        assert_eq!(classify_error(&io::Error::from(io::ErrorKind::StorageFull)), LayerOutcome::RetryableFailure);
+        assert_eq!(classify_error(&RequestLimitError::Reached(100)), LayerOutcome::RetryableFailure);
+        assert_eq!(
+            classify_error(&RestServiceScrapingError::InvalidResponse(StatusCode::NOT_FOUND)),
+            LayerOutcome::PermanentFailure,
+        );
+        assert_eq!(classify_error(&RestServiceMetadataError::MissingOidField), LayerOutcome::PermanentFailure);
+    }
+
+    #[test]
+    fn fail_should_keep_going_when_not_stopping_the_run() {
+        let mut report = BatchReport::new(&RunId::generate(), true);
+        report.start_layer();
+        report.succeed("https://example.com/0", "Parcels", 10);
+        report.start_layer();
+        report.fail("https://example.com/1", "Roads", Box::new(RestServiceScrapingError::TooManyRetires(5))).unwrap();
+        report.start_layer();
+        report.fail("https://example.com/2", "Zoning", Box::new(RestServiceMetadataError::MissingOidField)).unwrap();
+        let deadline = DeadlineError::Reached(Duration::from_secs(60));
+        assert!(report.fail("https://example.com/3", "Lots", Box::new(deadline)).is_err());
+
+        assert_eq!(report.layers.len(), 3);
+        assert_eq!(report.layers[1].outcome, LayerOutcome::RetryableFailure);
+        assert_eq!(report.outcome, LayerOutcome::PermanentFailure);
+        assert!(matches!(report.failures(), Some(BatchError::LayersFailed(LayerOutcome::PermanentFailure, 2))));
+    }
+
+    #[test]
+    fn fail_should_return_error_when_not_keeping_going() {
+        let mut report = BatchReport::new(&RunId::generate(), false);
+        assert!(report.fail("https://example.com/1", "Roads", Box::new(RestServiceScrapingError::TooManyRetires(5))).is_err());
+        assert!(report.layers.is_empty());
+    }
+}
@@ -0,0 +1,159 @@
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::OnceLock;
+
+/// Optional capability compiled in through a cargo feature, so slim builds can leave out its
+/// dependencies.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Capability {
+    /// HTTPS connections, needs native-tls (the system library) or rustls (static builds)
+    Tls,
+    /// H3 cell indexes (`--cell-index h3`), needs h3o
+    H3,
+    /// Tar and .tar.zst archives of the output files (`--archive`), needs tar and zstd
+    Archive,
+    /// Loading layers into PostGIS (`--postgres-url`), needs tokio-postgres
+    Postgres,
+    /// GeoParquet output (`--output-format geoparquet`), needs parquet and arrow
+    Parquet,
+    /// Any vector format OGR writes (`--output-format ogr:<driver>`), needs the GDAL library
+    Gdal,
+}
+
+impl Capability {
+    const ALL: [Capability; 6] = [
+        Capability::Tls,
+        Capability::H3,
+        Capability::Archive,
+        Capability::Postgres,
+        Capability::Parquet,
+        Capability::Gdal,
+    ];
+
+    /// Cargo feature enabling the capability.
+    pub(crate) fn feature(&self) -> &'static str {
+        match self {
+            Capability::Tls => "rustls",
+            Capability::H3 => "h3",
+            Capability::Archive => "archive",
+            Capability::Postgres => "postgres",
+            Capability::Parquet => "parquet",
+            Capability::Gdal => "gdal",
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        match self {
+            Capability::Tls => cfg!(any(feature = "native-tls", feature = "rustls")),
+            Capability::H3 => cfg!(feature = "h3"),
+            Capability::Archive => cfg!(feature = "archive"),
+            Capability::Postgres => cfg!(feature = "postgres"),
+            Capability::Parquet => cfg!(feature = "parquet"),
+            Capability::Gdal => cfg!(feature = "gdal"),
+        }
+    }
+
+    /// Fail when the capability was left out of this build.
+    pub(crate) fn require(&self) -> Result<(), CapabilityError> {
+        if self.is_enabled() {
+            Ok(())
+        } else {
+            Err(CapabilityError::Disabled(*self))
+        }
+    }
+}
+
+/// Fail when the url needs a capability left out of this build.
+pub(crate) fn require_for_url(url: &str) -> Result<(), CapabilityError> {
+    if url.get(..8).is_some_and(|scheme| scheme.eq_ignore_ascii_case("https://")) {
+        Capability::Tls.require()?;
+    }
+    Ok(())
+}
+
+/// Text of `--version`, listing the features this binary was built with so a slim or static
+/// build can be told apart from a full one.
+pub(crate) fn long_version(version: &'static str) -> &'static str {
+    static LONG_VERSION: OnceLock<String> = OnceLock::new();
+    LONG_VERSION.get_or_init(|| {
+        let tls = if cfg!(feature = "native-tls") {
+            "native-tls"
+        } else if cfg!(feature = "rustls") {
+            "rustls"
+        } else {
+            "none"
+        };
+        let disabled: Vec<String> = Capability::ALL
+            .iter()
+            .filter(|capability| !capability.is_enabled())
+            .map(|capability| format!("{} (--features {})", capability, capability.feature()))
+            .collect();
+        let features: Vec<&str> = Capability::ALL.iter()
+            .filter(|capability| capability.is_enabled() && **capability != Capability::Tls)
+            .map(|capability| capability.feature())
+            .chain(cfg!(feature = "brotli").then_some("brotli"))
+            .collect();
+        let features = if features.is_empty() { "none".to_owned() } else { features.join(", ") };
+        let mut text = format!("{}\nTLS: {}\nFeatures: {}", version, tls, features);
+        if !disabled.is_empty() {
+            text.push_str(&format!("\nNot available: {}", disabled.join(", ")));
+        }
+        text
+    })
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Capability::Tls => write!(f, "HTTPS connections"),
+            Capability::H3 => write!(f, "H3 cell indexes"),
+            Capability::Archive => write!(f, "Output archives"),
+            Capability::Postgres => write!(f, "PostGIS loads"),
+            Capability::Parquet => write!(f, "GeoParquet outputs"),
+            Capability::Gdal => write!(f, "OGR outputs"),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+pub(crate) enum CapabilityError {
+    Disabled(Capability),
+}
+
+/// Errors returned from main are printed with Debug, show the rebuild hint instead of the variant.
+impl Debug for CapabilityError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Display for CapabilityError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CapabilityError::Disabled(capability) => write!(
+                f,
+                "{} are not available in this build, rebuild with --features {}",
+                capability,
+                capability.feature(),
+            ),
+        }
+    }
+}
+
+impl Error for CapabilityError {}
+
+#[cfg(test)]
+mod capability_tests {
+    use super::{require_for_url, Capability, CapabilityError};
+
+    #[test]
+    fn require_for_url_should_need_tls_when_passed_https() {
+        let result = require_for_url("HTTPS://example.com/arcgis/rest/services/Parcels/FeatureServer/0");
+        if Capability::Tls.is_enabled() {
+            assert_eq!(result, Ok(()));
+        } else {
+            assert_eq!(result, Err(CapabilityError::Disabled(Capability::Tls)));
+        }
+        assert_eq!(require_for_url("http://127.0.0.1:8080/arcgis/rest/services"), Ok(()));
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::Write;
+use clap::ValueEnum;
+use reqwest::Url;
+use serde_json::Value;
+use tablestream::{Stream, col};
+use crate::client::ServiceClient;
+use crate::report;
+
+/// Service types holding queryable layers.
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+pub(crate) enum ServiceType {
+    FeatureServer,
+    MapServer,
+}
+
+impl ServiceType {
+    fn from_str(value: &str) -> Option<ServiceType> {
+        match value {
+            "FeatureServer" => Some(ServiceType::FeatureServer),
+            "MapServer" => Some(ServiceType::MapServer),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ServiceType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServiceType::FeatureServer => write!(f, "FeatureServer"),
+            ServiceType::MapServer => write!(f, "MapServer"),
+        }
+    }
+}
+
+/// Limits of a catalog walk, so walking a large server does not enumerate thousands of
+/// irrelevant services.
+#[derive(Debug, Clone)]
+pub(crate) struct CatalogOptions {
+    /// Folder levels walked below the starting directory. 0 only walks the starting directory
+    pub(crate) max_depth: usize,
+    pub(crate) service_types: Vec<ServiceType>,
+    /// Walk map services that only serve cached tiles
+    pub(crate) include_cached: bool,
+    pub(crate) max_layers: Option<usize>,
+}
+
+/// Layer or table found by a catalog walk.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CatalogLayer {
+    pub(crate) service: String,
+    pub(crate) name: String,
+    pub(crate) geometry_type: String,
+    pub(crate) url: String,
+}
+
+async fn get_json(client: &ServiceClient, url: &str) -> Result<Value, Box<dyn Error + Send + Sync>> {
+    let request_url = Url::parse_with_params(url, [("f", "json")])?;
+    Ok(client.get(request_url).await?.send().await?.json().await?)
+}
+
+/// Path of the folder `name`, listed in `folder`, relative to the services root. Servers list
+/// folders either by full path or by name alone.
+fn folder_path(folder: &str, name: &str) -> String {
+    if folder.is_empty() || name.starts_with(&format!("{}/", folder)) {
+        name.to_owned()
+    } else {
+        format!("{}/{}", folder, name)
+    }
+}
+
+/// Map services with a tile cache and no query capability only serve tiles, so none of their
+/// layers can be scraped.
+fn is_tiles_only(service_json: &Value) -> bool {
+    let cached = service_json["singleFusedMapCache"].as_bool().unwrap_or(false);
+    let queryable = service_json["capabilities"]
+        .as_str()
+        .map(|capabilities| capabilities.split(',').any(|capability| capability.trim() == "Query"))
+        .unwrap_or(true);
+    cached && !queryable
+}
+
+/// Layers and tables of a service, skipping group layers that only contain other layers.
+fn service_layers(service: &str, service_url: &str, service_json: &Value) -> Vec<CatalogLayer> {
+    ["layers", "tables"].into_iter()
+        .flat_map(|key| service_json[key].as_array().cloned().unwrap_or_default())
+        .filter(|layer| {
+            layer["type"].as_str() != Some("Group Layer")
+                && layer["subLayerIds"].as_array().map(|ids| ids.is_empty()).unwrap_or(true)
+        })
+        .filter_map(|layer| {
+            let id = layer["id"].as_i64()?;
+            Some(CatalogLayer {
+                service: service.to_owned(),
+                name: layer["name"].as_str().unwrap_or_default().to_owned(),
+                geometry_type: layer["geometryType"]
+                    .as_str()
+                    .map(|geometry_type| geometry_type.trim_start_matches("esriGeometry").to_owned())
+                    .unwrap_or_else(|| "Table".to_owned()),
+                url: format!("{}/{}", service_url, id),
+            })
+        })
+        .collect()
+}
+
+/// Name of the service at `url` (e.g. `Utilities/Water/MapServer`) when it is the root of a
+/// FeatureServer or MapServer rather than one of its layers.
+pub(crate) fn service_root_name(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let path = url.path().trim_end_matches('/');
+    let (parent, service_type) = path.rsplit_once('/')?;
+    ServiceType::from_str(service_type)?;
+    let name = match path.find("/rest/services/") {
+        Some(index) => &path[index + "/rest/services/".len()..],
+        None => &path[parent.rfind('/').map_or(0, |index| index + 1)..],
+    };
+    Some(name.to_owned())
+}
+
+/// Layers and tables of the FeatureServer or MapServer at `service_url`.
+pub(crate) async fn list_service_layers(
+    client: &ServiceClient,
+    service_url: &str,
+) -> Result<Vec<CatalogLayer>, Box<dyn Error + Send + Sync>> {
+    let service_url = service_url.trim_end_matches('/');
+    let service = service_root_name(service_url).unwrap_or_else(|| service_url.to_owned());
+    let service_json = get_json(client, service_url).await?;
+    if let Some(message) = service_json["error"]["message"].as_str() {
+        return Err(format!("Could not read the service {}. {}", service_url, message).into())
+    }
+    Ok(service_layers(&service, service_url, &service_json))
+}
+
+/// Walk the services directory at `root_url` (the `rest/services` url or one of its folders)
+/// breadth first and collect the layers of every matching service. A FeatureServer or
+/// MapServer url only lists the layers of that service.
+pub(crate) async fn walk_catalog(
+    client: &ServiceClient,
+    root_url: &str,
+    options: &CatalogOptions,
+) -> Result<Vec<CatalogLayer>, Box<dyn Error + Send + Sync>> {
+    if service_root_name(root_url).is_some() {
+        let mut layers = list_service_layers(client, root_url).await?;
+        if let Some(max_layers) = options.max_layers {
+            layers.truncate(max_layers);
+        }
+        return Ok(layers)
+    }
+    let root_url = root_url.trim_end_matches('/');
+    let services_root = match root_url.find("/rest/services") {
+        Some(index) => &root_url[..index + "/rest/services".len()],
+        None => root_url,
+    };
+    let start_folder = root_url[services_root.len()..].trim_matches('/').to_owned();
+    let mut folders = VecDeque::from([(start_folder, 0)]);
+    let mut layers = vec![];
+    while let Some((folder, depth)) = folders.pop_front() {
+        let folder_url = if folder.is_empty() {
+            services_root.to_owned()
+        } else {
+            format!("{}/{}", services_root, folder)
+        };
+        let directory_json = get_json(client, &folder_url).await?;
+        if depth < options.max_depth {
+            for name in directory_json["folders"].as_array().into_iter().flatten() {
+                if let Some(name) = name.as_str() {
+                    folders.push_back((folder_path(&folder, name), depth + 1));
+                }
+            }
+        }
+        for service in directory_json["services"].as_array().into_iter().flatten() {
+            let (name, service_type) = match (service["name"].as_str(), service["type"].as_str()) {
+                (Some(name), Some(service_type)) => (name, service_type),
+                _ => continue,
+            };
+            match ServiceType::from_str(service_type) {
+                Some(service_type) if options.service_types.contains(&service_type) => {}
+                _ => continue,
+            }
+            let service_url = format!("{}/{}/{}", services_root, name, service_type);
+            let service_json = match get_json(client, &service_url).await {
+                Ok(service_json) => service_json,
+                Err(error) => {
+                    report::warn(format_args!(
+                        "Skipping {}, could not read the service. {}",
+                        service_url,
+                        error,
+                    ));
+                    continue
+                }
+            };
+            if !options.include_cached && is_tiles_only(&service_json) {
+                continue
+            }
+            let service = format!("{}/{}", name, service_type);
+            layers.append(&mut service_layers(&service, &service_url, &service_json));
+            if let Some(max_layers) = options.max_layers {
+                if layers.len() >= max_layers {
+                    report::warn(format_args!(
+                        "Stopped the catalog walk after {} layers (--max-layers)",
+                        max_layers,
+                    ));
+                    layers.truncate(max_layers);
+                    return Ok(layers)
+                }
+            }
+        }
+    }
+    Ok(layers)
+}
+
+/// Inventory of the layers a catalog walk found, shown before anything is scraped.
+pub(crate) fn write_to_console(layers: &[CatalogLayer]) -> io::Result<()> {
+    println!("Catalog Layers: {}", layers.len());
+    let mut out = io::stdout();
+    let mut stream = Stream::new(
+        &mut out,
+        vec![
+            col!(CatalogLayer: .service).header("Service"),
+            col!(CatalogLayer: .name).header("Layer"),
+            col!(CatalogLayer: .geometry_type).header("Geometry"),
+            col!(CatalogLayer: .url).header("URL"),
+        ],
+    );
+    for layer in layers {
+        stream.row(layer.to_owned())?;
+    }
+    stream.finish()?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod catalog_tests {
+    use serde_json::json;
+    use super::{folder_path, is_tiles_only, service_layers, service_root_name};
+
+    #[test]
+    fn service_root_name_should_return_name_when_url_is_service_root() {
+        assert_eq!(
+            service_root_name("https://example.com/arcgis/rest/services/Utilities/Water/MapServer/").as_deref(),
+            Some("Utilities/Water/MapServer"),
+        );
+        assert_eq!(
+            service_root_name("https://example.com/server/Parcels/FeatureServer?f=json").as_deref(),
+            Some("Parcels/FeatureServer"),
+        );
+        assert_eq!(service_root_name("https://example.com/arcgis/rest/services/Parcels/FeatureServer/0"), None);
+        assert_eq!(service_root_name("https://example.com/arcgis/rest/services/Geocoder/GeocodeServer"), None);
+    }
+
+    #[test]
+    fn folder_path_should_prefix_folder_when_name_is_relative() {
+        assert_eq!(folder_path("Utilities", "Water"), "Utilities/Water");
+        assert_eq!(folder_path("Utilities", "Utilities/Water"), "Utilities/Water");
+        assert_eq!(folder_path("", "Utilities"), "Utilities");
+    }
+
+    #[test]
+    fn is_tiles_only_should_return_true_when_cached_without_query() {
+        assert!(is_tiles_only(&json!({"singleFusedMapCache": true, "capabilities": "Map,Data"})));
+        assert!(!is_tiles_only(&json!({"singleFusedMapCache": true, "capabilities": "Map,Query,Data"})));
+        assert!(!is_tiles_only(&json!({"singleFusedMapCache": false, "capabilities": "Map"})));
+    }
+
+    #[test]
+    fn service_layers_should_skip_group_layers() {
+        let service_json = json!({
+            "layers": [
+                {"id": 0, "name": "Parcels", "type": "Group Layer", "subLayerIds": [1]},
+                {"id": 1, "name": "Lots", "geometryType": "esriGeometryPolygon", "subLayerIds": null},
+            ],
+            "tables": [{"id": 2, "name": "Owners"}],
+        });
+        let layers = service_layers("Parcels/MapServer", "https://example.com/Parcels/MapServer", &service_json);
+        let summary: Vec<(&str, &str, &str)> = layers.iter()
+            .map(|layer| (layer.name.as_str(), layer.geometry_type.as_str(), layer.url.as_str()))
+            .collect();
+        assert_eq!(summary, vec![
+            ("Lots", "Polygon", "https://example.com/Parcels/MapServer/1"),
+            ("Owners", "Table", "https://example.com/Parcels/MapServer/2"),
+        ]);
+    }
+}
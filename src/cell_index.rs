@@ -0,0 +1,192 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::ops::RangeInclusive;
+use clap::ValueEnum;
+#[cfg(feature = "h3")]
+use h3o::{LatLng, Resolution};
+use serde_json::{Map, Value};
+use crate::geometry::esri_centroid;
+use crate::projection::transform_point;
+
+const WGS84: i64 = 4326;
+const GEOHASH_ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum CellIndexError {
+    InvalidResolution(CellIndexKind, u8),
+}
+
+impl Display for CellIndexError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CellIndexError::InvalidResolution(kind, resolution) => {
+                let resolutions = kind.resolutions();
+                write!(
+                    f,
+                    "{} is not a valid {} resolution, expected {} to {}",
+                    resolution,
+                    kind,
+                    resolutions.start(),
+                    resolutions.end(),
+                )
+            }
+        }
+    }
+}
+
+impl Error for CellIndexError {}
+
+/// Grid system of the cell index attached to every feature.
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+pub(crate) enum CellIndexKind {
+    /// Base 32 geohash, resolution being the number of characters
+    Geohash,
+    /// Uber H3 hexagonal cell
+    H3,
+}
+
+impl CellIndexKind {
+    fn resolutions(&self) -> RangeInclusive<u8> {
+        match self {
+            CellIndexKind::Geohash => 1..=12,
+            CellIndexKind::H3 => 0..=15,
+        }
+    }
+
+    fn default_resolution(&self) -> u8 {
+        match self {
+            CellIndexKind::Geohash => 7,
+            CellIndexKind::H3 => 9,
+        }
+    }
+
+    /// Output column holding the cell of each feature.
+    pub(crate) fn column(&self) -> &'static str {
+        match self {
+            CellIndexKind::Geohash => "_GEOHASH",
+            CellIndexKind::H3 => "_H3_CELL",
+        }
+    }
+}
+
+impl Display for CellIndexKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CellIndexKind::Geohash => write!(f, "geohash"),
+            CellIndexKind::H3 => write!(f, "H3"),
+        }
+    }
+}
+
+/// Geohash of `precision` characters for the cell holding the coordinate.
+fn geohash(longitude: f64, latitude: f64, precision: u8) -> String {
+    let mut longitude_range = (-180_f64, 180_f64);
+    let mut latitude_range = (-90_f64, 90_f64);
+    let mut hash = String::with_capacity(precision as usize);
+    let (mut bits, mut value, mut is_longitude) = (0, 0, true);
+    while hash.len() < precision as usize {
+        let (range, coordinate) = if is_longitude {
+            (&mut longitude_range, longitude)
+        } else {
+            (&mut latitude_range, latitude)
+        };
+        let middle = (range.0 + range.1) / 2_f64;
+        value <<= 1;
+        if coordinate >= middle {
+            value |= 1;
+            range.0 = middle;
+        } else {
+            range.1 = middle;
+        }
+        is_longitude = !is_longitude;
+        bits += 1;
+        if bits == 5 {
+            hash.push(GEOHASH_ALPHABET[value] as char);
+            bits = 0;
+            value = 0;
+        }
+    }
+    hash
+}
+
+#[cfg(feature = "h3")]
+fn h3_cell(longitude: f64, latitude: f64, resolution: u8) -> Option<String> {
+    let resolution = Resolution::try_from(resolution).ok()?;
+    Some(LatLng::new(latitude, longitude).ok()?.to_cell(resolution).to_string())
+}
+
+/// Builds without the h3 feature refuse H3 indexers, see [`Capability::H3`].
+#[cfg(not(feature = "h3"))]
+fn h3_cell(_longitude: f64, _latitude: f64, _resolution: u8) -> Option<String> {
+    None
+}
+
+/// Computes the cell holding the centroid of each feature, so analytics joins on the grid do
+/// not have to be computed downstream.
+#[derive(Debug, Clone)]
+pub(crate) struct CellIndexer {
+    pub(crate) kind: CellIndexKind,
+    resolution: u8,
+    /// Spatial reference of the geometries, which must project to WGS84 client-side. Features
+    /// of layers without one get no cell
+    spatial_reference: Option<i64>,
+}
+
+impl CellIndexer {
+    /// Indexer for geometries in `spatial_reference`, using the default resolution of `kind`
+    /// when none is given.
+    pub(crate) fn new(
+        kind: CellIndexKind,
+        resolution: Option<u8>,
+        spatial_reference: Option<i64>,
+    ) -> Result<Self, CellIndexError> {
+        let resolution = resolution.unwrap_or_else(|| kind.default_resolution());
+        if !kind.resolutions().contains(&resolution) {
+            return Err(CellIndexError::InvalidResolution(kind, resolution))
+        }
+        Ok(Self { kind, resolution, spatial_reference })
+    }
+
+    /// True when the geometries can be projected to WGS84 to find their cells.
+    pub(crate) fn is_supported(&self) -> bool {
+        self.spatial_reference
+            .and_then(|wkid| transform_point(0_f64, 0_f64, wkid, WGS84))
+            .is_some()
+    }
+
+    /// Cell of the centroid of an Esri JSON feature, None when it has no geometry.
+    pub(crate) fn index(&self, feature: &Map<String, Value>) -> Option<String> {
+        let (x, y) = esri_centroid(feature.get("geometry")?.as_object()?)?;
+        let (longitude, latitude) = transform_point(x, y, self.spatial_reference?, WGS84)?;
+        match self.kind {
+            CellIndexKind::Geohash => Some(geohash(longitude, latitude, self.resolution)),
+            CellIndexKind::H3 => h3_cell(longitude, latitude, self.resolution),
+        }
+    }
+}
+
+#[cfg(test)]
+mod cell_index_tests {
+    use super::{geohash, CellIndexError, CellIndexKind, CellIndexer};
+
+    #[test]
+    fn geohash_should_match_reference_when_passed_coordinate() {
+        assert_eq!(geohash(-5.6, 42.6, 5), "ezs42");
+    }
+
+    #[cfg(feature = "h3")]
+    #[test]
+    fn index_should_return_h3_cell_of_centroid_when_passed_point() {
+        let indexer = CellIndexer::new(CellIndexKind::H3, Some(7), Some(4326)).unwrap();
+        let feature = serde_json::json!({"attributes": {}, "geometry": {"x": -122.0553238, "y": 37.3615593}});
+        assert_eq!(indexer.index(feature.as_object().unwrap()), Some("87283472bffffff".to_owned()));
+    }
+
+    #[test]
+    fn new_should_fail_when_resolution_out_of_range() {
+        assert_eq!(
+            CellIndexer::new(CellIndexKind::Geohash, Some(13), Some(4326)).unwrap_err(),
+            CellIndexError::InvalidResolution(CellIndexKind::Geohash, 13),
+        );
+    }
+}
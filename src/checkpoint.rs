@@ -0,0 +1,120 @@
+use std::error::Error;
+use std::fs::{create_dir_all, remove_file, File};
+use std::io;
+use std::io::{BufReader, Write};
+use std::path::PathBuf;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::audit::RunId;
+use crate::metadata::RestServiceMetadata;
+use crate::state::state_directory;
+
+/// How far the scrape of a layer got before the run stopped.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LayerStatus {
+    Complete,
+    /// Some features were written before the run stopped
+    Partial,
+    NotStarted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct LayerCheckpoint {
+    pub(crate) url: String,
+    pub(crate) name: String,
+    pub(crate) status: LayerStatus,
+    pub(crate) features: usize,
+}
+
+/// State of a run that stopped before scraping every layer, written to `checkpoints` in the
+/// state directory. It is saved after every layer, so a run that died can be resumed from it
+/// with --resume, and removed once the run completes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Checkpoint {
+    pub(crate) run_id: RunId,
+    pub(crate) created_at: DateTime<Utc>,
+    pub(crate) layers: Vec<LayerCheckpoint>,
+}
+
+impl Checkpoint {
+    pub(crate) fn new(run_id: &RunId) -> Self {
+        Self { run_id: run_id.to_owned(), created_at: Utc::now(), layers: vec![] }
+    }
+
+    /// Read a checkpoint written by an earlier run, for --resume.
+    pub(crate) fn read(value: &str) -> Result<Checkpoint, Box<dyn Error + Send + Sync>> {
+        let file = File::open(value)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    /// Layer scraped completely by the run.
+    pub(crate) fn completed(&self, layer: &RestServiceMetadata) -> Option<&LayerCheckpoint> {
+        self.layers.iter().find(|checkpoint| checkpoint.url == layer.url && checkpoint.status == LayerStatus::Complete)
+    }
+
+    /// Keep a layer completed by the resumed run.
+    pub(crate) fn keep(&mut self, layer: &LayerCheckpoint) -> io::Result<()> {
+        self.layers.push(layer.to_owned());
+        self.write().map(|_| ())
+    }
+
+    /// Record the features written for a layer, complete when all its features were, and save
+    /// the checkpoint.
+    pub(crate) fn record(&mut self, layer: &RestServiceMetadata, features: usize) -> io::Result<()> {
+        let expected = layer.feature_count().ok().and_then(|count| usize::try_from(count).ok());
+        let status = if Some(features) >= expected {
+            LayerStatus::Complete
+        } else if features > 0 {
+            LayerStatus::Partial
+        } else {
+            LayerStatus::NotStarted
+        };
+        self.push(layer, status, features);
+        self.write().map(|_| ())
+    }
+
+    pub(crate) fn skip(&mut self, layer: &RestServiceMetadata) {
+        self.push(layer, LayerStatus::NotStarted, 0);
+    }
+
+    fn push(&mut self, layer: &RestServiceMetadata, status: LayerStatus, features: usize) {
+        self.layers.push(LayerCheckpoint {
+            url: layer.url.to_owned(),
+            name: layer.name.to_owned(),
+            status,
+            features,
+        });
+    }
+
+    /// True when every layer was scraped completely.
+    pub(crate) fn is_complete(&self) -> bool {
+        self.layers.iter().all(|layer| layer.status == LayerStatus::Complete)
+    }
+
+    /// Path of the checkpoint of a run.
+    pub(crate) fn path(run_id: &RunId) -> io::Result<PathBuf> {
+        Ok(state_directory()?.join("checkpoints").join(format!("{}.json", run_id)))
+    }
+
+    /// Write the checkpoint, returning its path.
+    pub(crate) fn write(&self) -> io::Result<PathBuf> {
+        let path = Self::path(&self.run_id)?;
+        if let Some(directory) = path.parent() {
+            create_dir_all(directory)?;
+        }
+        let mut file = File::create(&path)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        writeln!(file)?;
+        file.sync_all()?;
+        Ok(path)
+    }
+
+    /// Remove the checkpoint once the run completed.
+    pub(crate) fn remove(&self) -> io::Result<()> {
+        match remove_file(Self::path(&self.run_id)?) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use std::fs::{rename, File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::audit::RunId;
+use crate::metadata::LayerDescription;
+use crate::report::Warning;
+
+/// Output file hashing everything written to it, so its checksum is known as soon as it is
+/// complete without reading it back.
+#[derive(Debug)]
+pub(crate) struct ChecksumFile {
+    file: File,
+    path: PathBuf,
+    /// Name the file is written under until finished, when not its final path
+    part_path: Option<PathBuf>,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl ChecksumFile {
+    pub(crate) fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: File::create(path)?,
+            path: path.to_owned(),
+            part_path: None,
+            hasher: Sha256::new(),
+            size: 0,
+        })
+    }
+
+    /// File written under a `.part` name and only given its final name once finished.
+    pub(crate) fn create_part(path: &Path) -> io::Result<Self> {
+        let mut part_name = path.as_os_str().to_owned();
+        part_name.push(".part");
+        let part_path = PathBuf::from(part_name);
+        let mut file = Self::create(&part_path)?;
+        file.path = path.to_owned();
+        file.part_path = Some(part_path);
+        Ok(file)
+    }
+
+    pub(crate) fn sync_all(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    /// Close the file, keeping what was hashed so writing can resume at its end, for writers
+    /// with more files than they keep open.
+    pub(crate) fn suspend(mut self) -> io::Result<SuspendedFile> {
+        self.file.flush()?;
+        Ok(SuspendedFile { path: self.path, part_path: self.part_path, hasher: self.hasher, size: self.size })
+    }
+
+    /// Flush the file and write its `.sha256` sidecar, in the format of `sha256sum` so it can
+    /// be checked with `sha256sum -c`.
+    pub(crate) fn finish(mut self) -> io::Result<Artifact> {
+        self.file.flush()?;
+        self.file.sync_all()?;
+        if let Some(part_path) = &self.part_path {
+            rename(part_path, &self.path)?;
+        }
+        let sha256 = format!("{:x}", self.hasher.finalize());
+        write_sidecar(&self.path, &sha256)?;
+        Ok(Artifact { path: self.path.display().to_string(), size: self.size, sha256 })
+    }
+}
+
+/// [`ChecksumFile`] closed until more is written to it.
+#[derive(Debug)]
+pub(crate) struct SuspendedFile {
+    path: PathBuf,
+    part_path: Option<PathBuf>,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl SuspendedFile {
+    /// Reopen the file to append to it.
+    pub(crate) fn resume(self) -> io::Result<ChecksumFile> {
+        let file = OpenOptions::new()
+            .append(true)
+            .open(self.part_path.as_ref().unwrap_or(&self.path))?;
+        Ok(ChecksumFile { file, path: self.path, part_path: self.part_path, hasher: self.hasher, size: self.size })
+    }
+}
+
+/// Write the `.sha256` sidecar of the file at `path`.
+fn write_sidecar(path: &Path, sha256: &str) -> io::Result<()> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut sidecar_name = path.as_os_str().to_owned();
+    sidecar_name.push(".sha256");
+    std::fs::write(PathBuf::from(sidecar_name), format!("{}  {}\n", sha256, file_name))
+}
+
+impl Write for ChecksumFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Output file written by a run.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct Artifact {
+    pub(crate) path: String,
+    pub(crate) size: u64,
+    pub(crate) sha256: String,
+}
+
+impl Artifact {
+    /// Artifact of a file written by a library, hashed once complete.
+    #[cfg(feature = "gdal")]
+    pub(crate) fn from_file(path: &Path) -> io::Result<Artifact> {
+        let mut hasher = Sha256::new();
+        let size = io::copy(&mut File::open(path)?, &mut hasher)?;
+        let sha256 = format!("{:x}", hasher.finalize());
+        write_sidecar(path, &sha256)?;
+        Ok(Artifact { path: path.display().to_string(), size, sha256 })
+    }
+
+    /// Artifact written by an earlier run, from the file and its `.sha256` sidecar. None when
+    /// either is missing.
+    pub(crate) fn from_sidecar(path: &Path) -> Option<Artifact> {
+        let mut sidecar_name = path.as_os_str().to_owned();
+        sidecar_name.push(".sha256");
+        let sidecar = std::fs::read_to_string(PathBuf::from(sidecar_name)).ok()?;
+        let sha256 = sidecar.split_whitespace().next()?.to_owned();
+        let size = std::fs::metadata(path).ok()?.len();
+        Some(Artifact { path: path.display().to_string(), size, sha256 })
+    }
+}
+
+/// Every artifact of a run with its checksum, written to `manifest.json` in the output
+/// directory along with the description, copyright text and field aliases of each layer.
+#[derive(Debug, Serialize)]
+pub(crate) struct Manifest {
+    run_id: String,
+    artifacts: Vec<Artifact>,
+    layers: Vec<LayerDescription>,
+    warnings: Vec<Warning>,
+    #[serde(skip)]
+    directory: PathBuf,
+}
+
+impl Manifest {
+    pub(crate) fn new(run_id: &RunId, directory: &Path) -> Self {
+        Self {
+            run_id: run_id.to_string(),
+            artifacts: vec![],
+            layers: vec![],
+            warnings: vec![],
+            directory: directory.to_owned(),
+        }
+    }
+
+    /// Add an artifact, relative to the output directory when written inside it.
+    pub(crate) fn push(&mut self, mut artifact: Artifact) {
+        if let Ok(relative) = Path::new(&artifact.path).strip_prefix(&self.directory) {
+            artifact.path = relative.display().to_string();
+        }
+        self.artifacts.push(artifact);
+    }
+
+    pub(crate) fn extend(&mut self, artifacts: impl IntoIterator<Item = Artifact>) {
+        for artifact in artifacts {
+            self.push(artifact);
+        }
+    }
+
+    /// Add a scraped layer, once even when its features were written to several artifacts.
+    pub(crate) fn describe(&mut self, layer: LayerDescription) {
+        if !self.layers.iter().any(|described| described.url == layer.url) {
+            self.layers.push(layer);
+        }
+    }
+
+    pub(crate) fn set_warnings(&mut self, warnings: Vec<Warning>) {
+        self.warnings = warnings;
+    }
+
+    pub(crate) fn artifacts(&self) -> &[Artifact] {
+        &self.artifacts
+    }
+
+    /// Write the manifest, returning its path.
+    pub(crate) fn write(&self) -> io::Result<PathBuf> {
+        let path = self.directory.join("manifest.json");
+        let mut file = File::create(&path)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        writeln!(file)?;
+        file.sync_all()?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod checksum_tests {
+    use std::io::Write;
+    use super::{Artifact, ChecksumFile};
+
+    #[test]
+    fn finish_should_write_sidecar_when_file_complete() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("Parcels.csv");
+        let mut file = ChecksumFile::create(&path).unwrap();
+        write!(file, "abc").unwrap();
+        let artifact = file.finish().unwrap();
+        let sha256 = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(artifact.sha256, sha256);
+        assert_eq!(artifact.size, 3);
+        assert_eq!(
+            std::fs::read_to_string(directory.path().join("Parcels.csv.sha256")).unwrap(),
+            format!("{}  Parcels.csv\n", sha256),
+        );
+        assert_eq!(Artifact::from_sidecar(&path), Some(artifact));
+    }
+}
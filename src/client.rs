@@ -0,0 +1,79 @@
+use std::error::Error;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use reqwest::{IntoUrl, RequestBuilder};
+use crate::audit::RunAudit;
+use crate::auth::AuthProvider;
+use crate::meter;
+
+/// HTTP client used for every request sent to a service, attaching the credentials and run id
+/// of the run.
+#[derive(Clone)]
+pub(crate) struct ServiceClient {
+    client: reqwest::Client,
+    auth: Arc<dyn AuthProvider>,
+    audit: RunAudit,
+}
+
+impl Debug for ServiceClient {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServiceClient").finish_non_exhaustive()
+    }
+}
+
+impl ServiceClient {
+    pub(crate) fn new(auth: Arc<dyn AuthProvider>, audit: RunAudit) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            auth,
+            audit,
+        }
+    }
+
+    /// Same credentials sent through another HTTP client, such as a freshly reconnected one.
+    pub(crate) fn with_client(&self, client: reqwest::Client) -> Self {
+        Self {
+            client,
+            auth: self.auth.clone(),
+            audit: self.audit.clone(),
+        }
+    }
+
+    /// Same run id sent with the credentials of `auth`.
+    pub(crate) fn with_auth(&self, auth: Arc<dyn AuthProvider>) -> Self {
+        Self {
+            client: self.client.clone(),
+            auth,
+            audit: self.audit.clone(),
+        }
+    }
+
+    /// Provider of the credentials attached to requests.
+    pub(crate) fn auth(&self) -> Arc<dyn AuthProvider> {
+        self.auth.clone()
+    }
+
+    /// GET request to `url` with the credentials and run id attached, ready to send. Counted as
+    /// sent by the run, failing once the run reached --max-requests.
+    pub(crate) async fn get<U: IntoUrl>(
+        &self,
+        url: U,
+    ) -> Result<RequestBuilder, Box<dyn Error + Send + Sync>> {
+        meter::count_request()?;
+        let request = self.audit.attach_to_request(self.client.get(url));
+        self.auth.attach_to_request(request).await
+    }
+
+    /// Token currently attached to requests, if the authentication method uses tokens.
+    pub(crate) async fn token(&self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        self.auth.get_token().await
+    }
+
+    /// Replace `rejected` after the server answered with an invalid token error.
+    pub(crate) async fn refresh_token(
+        &self,
+        rejected: Option<&str>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.auth.refresh(rejected).await
+    }
+}
@@ -0,0 +1,113 @@
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+pub(crate) enum ConfigError {
+    NoLayers,
+}
+
+/// Errors returned from main are printed with Debug, show the message instead of the variant.
+impl Debug for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::NoLayers => write!(f, "No layer to scrape. Pass --url, --catalog or a --config listing urls"),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// Options read from the `--config` JSON file. Every list merges with its command line
+/// equivalent so a shared config can be extended per run.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct ScrapeConfig {
+    /// Layers scraped along with the --url layers
+    pub(crate) urls: Vec<String>,
+    /// Only these fields are requested and written, every field when empty
+    pub(crate) fields: Vec<String>,
+    pub(crate) drop_fields: Vec<String>,
+    pub(crate) hash_fields: Vec<String>,
+    pub(crate) hash_salt: Option<String>,
+    pub(crate) redactions: Vec<RedactionRule>,
+    pub(crate) parse_rules: Vec<ParseRule>,
+    pub(crate) provenance: bool,
+}
+
+/// Regex replacement applied to the values of a single field, e.g. masking case numbers with
+/// `{"field": "CASE_NO", "pattern": "\\d{4}-\\d+", "replacement": "XXXX"}`. Capture groups can be
+/// referenced in the replacement as `$1` or `${name}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RedactionRule {
+    pub(crate) field: String,
+    pub(crate) pattern: String,
+    #[serde(default)]
+    pub(crate) replacement: String,
+}
+
+/// Conversion of a string field holding dates or numbers in a local format to a typed value, e.g.
+/// `{"field": "SALE_DATE", "type": "date", "format": "%d.%m.%Y"}` or
+/// `{"field": "PRICE", "type": "number", "locale": "de"}`. Values that do not parse are written
+/// as sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ParseRule {
+    pub(crate) field: String,
+    #[serde(flatten)]
+    pub(crate) parser: ValueParser,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ValueParser {
+    /// chrono format string (e.g. `%d/%m/%Y %H:%M`), written as epoch milliseconds like Esri
+    /// date fields. Values without an offset are taken as UTC, values without a time as midnight
+    Date { format: String },
+    /// Number written with the separators of the locale
+    Number { locale: NumberLocale },
+}
+
+/// Decimal and digit group separators of the number formats in use across locales.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum NumberLocale {
+    /// 1,234.5
+    #[serde(alias = "en_us", alias = "en_gb")]
+    En,
+    /// 1.234,5
+    #[serde(alias = "es", alias = "it", alias = "nl", alias = "pt", alias = "da", alias = "id")]
+    De,
+    /// 1 234,5, grouped by spaces or non-breaking spaces
+    #[serde(alias = "cs", alias = "fi", alias = "no", alias = "pl", alias = "ru", alias = "sv")]
+    Fr,
+    /// 1'234.5
+    #[serde(alias = "de_ch")]
+    Ch,
+}
+
+impl NumberLocale {
+    /// Decimal separator and the characters grouping digits.
+    pub(crate) fn separators(&self) -> (char, &'static [char]) {
+        match self {
+            NumberLocale::En => ('.', &[',']),
+            NumberLocale::De => (',', &['.']),
+            NumberLocale::Fr => (',', &[' ', '\u{a0}', '\u{202f}']),
+            NumberLocale::Ch => ('.', &['\'', '\u{2019}']),
+        }
+    }
+}
+
+impl ScrapeConfig {
+    pub(crate) fn from_file(path: &Path) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+}
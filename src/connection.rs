@@ -0,0 +1,214 @@
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use reqwest::{Client, Url};
+use crate::client::ServiceClient;
+use crate::profile;
+use crate::replica::ReplicaSet;
+use crate::report;
+
+/// When the pooled connections to a host are dropped and how the new ones are made.
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionPolicy {
+    /// Consecutive failed requests after which the host is reconnected. A stalled request always
+    /// reconnects.
+    pub(crate) failure_threshold: u32,
+    /// Pin each new connection to the next address the host name resolves to, so a dead backend
+    /// behind a load balancer is skipped.
+    pub(crate) rotate_addresses: bool,
+    /// Base urls of mirrors serving the same services, sent queries in turn with the host
+    pub(crate) replicas: Vec<String>,
+}
+
+#[derive(Debug)]
+struct ConnectionState {
+    client: Client,
+    generation: u64,
+    consecutive_failures: u32,
+    addresses: Option<Vec<SocketAddr>>,
+    next_address: usize,
+}
+
+/// Client shared by every fetch worker of a host. Replacing the client drops its connection pool,
+/// so requests after a reconnect open fresh connections.
+#[derive(Debug)]
+pub(crate) struct HostConnections {
+    host: Option<String>,
+    port: Option<u16>,
+    profile_host: Option<String>,
+    policy: ConnectionPolicy,
+    service_client: ServiceClient,
+    replicas: Option<ReplicaSet>,
+    state: Mutex<ConnectionState>,
+}
+
+impl HostConnections {
+    pub(crate) fn new(url: &str, policy: ConnectionPolicy, service_client: &ServiceClient) -> Self {
+        let replicas = ReplicaSet::new(url, &policy.replicas);
+        let url = Url::parse(url).ok();
+        Self {
+            host: url.as_ref().and_then(|url| url.host_str().map(str::to_owned)),
+            port: url.as_ref().and_then(Url::port_or_known_default),
+            profile_host: url.as_ref().and_then(|url| profile::host_key(url.as_str())),
+            policy,
+            service_client: service_client.to_owned(),
+            replicas,
+            state: Mutex::new(ConnectionState {
+                client: Client::new(),
+                generation: 0,
+                consecutive_failures: 0,
+                addresses: None,
+                next_address: 0,
+            }),
+        }
+    }
+
+    /// Current client and its generation, to report failures against.
+    pub(crate) fn client(&self) -> (ServiceClient, u64) {
+        let state = self.state.lock().unwrap();
+        (self.service_client.with_client(state.client.clone()), state.generation)
+    }
+
+    /// Host the requests are observed under for its [`profile`](crate::profile).
+    pub(crate) fn profile_host(&self) -> Option<&str> {
+        self.profile_host.as_deref()
+    }
+
+    pub(crate) fn record_success(&self) {
+        self.state.lock().unwrap().consecutive_failures = 0;
+    }
+
+    /// Replica the next attempt of `query` is sent to, with the query rewritten for it. The
+    /// query is unchanged without mirrors.
+    pub(crate) fn route(&self, query: &str) -> (String, Option<usize>) {
+        match &self.replicas {
+            Some(replicas) => {
+                let index = replicas.choose(Instant::now());
+                (replicas.route(query, index), Some(index))
+            }
+            None => (query.to_owned(), None),
+        }
+    }
+
+    /// Time a successful attempt sent to `replica`, leaving it out of the rotation for a while
+    /// when it answers much slower than the others.
+    pub(crate) fn record_replica_success(&self, replica: Option<usize>, elapsed: Duration) {
+        if let (Some(replicas), Some(index)) = (&self.replicas, replica) {
+            if replicas.record_success(index, elapsed, Instant::now()) {
+                tracing::warn!(replica = replicas.mirror(index).unwrap_or("service"), "Replica is much slower than the others, leaving it out for a while");
+            }
+        }
+    }
+
+    /// Count a failed attempt sent to `replica`, leaving it out of the rotation for a while once
+    /// its attempts keep failing.
+    pub(crate) fn record_replica_failure(&self, replica: Option<usize>) {
+        if let (Some(replicas), Some(index)) = (&self.replicas, replica) {
+            if replicas.record_failure(index, Instant::now()) {
+                tracing::warn!(replica = replicas.mirror(index).unwrap_or("service"), "Replica keeps failing, leaving it out for a while");
+            }
+        }
+    }
+
+    /// Count a failed request made with the client of `generation`, reconnecting when the
+    /// request stalled or the failure threshold is reached. Failures of a client that was
+    /// already replaced are ignored so concurrent workers reconnect only once.
+    pub(crate) async fn record_failure(&self, generation: u64, stalled: bool) {
+        let (should_reconnect, needs_addresses) = {
+            let mut state = self.state.lock().unwrap();
+            if state.generation != generation {
+                return
+            }
+            state.consecutive_failures += 1;
+            (
+                stalled || state.consecutive_failures >= self.policy.failure_threshold,
+                self.policy.rotate_addresses && state.addresses.is_none(),
+            )
+        };
+        if !should_reconnect {
+            return
+        }
+        let resolved = match (&self.host, self.port) {
+            (Some(host), Some(port)) if needs_addresses => {
+                tokio::net::lookup_host((host.as_str(), port))
+                    .await
+                    .map(|addresses| addresses.collect::<Vec<SocketAddr>>())
+                    .ok()
+            }
+            _ => None,
+        };
+        self.reconnect(generation, resolved);
+    }
+
+    fn reconnect(&self, generation: u64, resolved: Option<Vec<SocketAddr>>) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return
+        }
+        if resolved.is_some() {
+            state.addresses = resolved;
+        }
+        let mut builder = Client::builder();
+        let mut pinned_address = None;
+        if let (Some(host), Some(addresses)) = (&self.host, &state.addresses) {
+            if !addresses.is_empty() {
+                let address = addresses[state.next_address % addresses.len()];
+                builder = builder.resolve(host, address);
+                pinned_address = Some(address);
+            }
+        }
+        let client = match builder.build() {
+            Ok(client) => client,
+            Err(error) => {
+                report::warn(format_args!("Could not reconnect. {}", error));
+                return
+            }
+        };
+        let host = self.host.as_deref().unwrap_or_default();
+        match pinned_address {
+            Some(address) => tracing::info!(host, %address, "Reconnecting"),
+            None => tracing::info!(host, "Reconnecting"),
+        }
+        state.client = client;
+        state.generation += 1;
+        state.consecutive_failures = 0;
+        state.next_address += 1;
+    }
+}
+
+#[cfg(test)]
+mod host_connections_tests {
+    use std::sync::Arc;
+    use crate::audit::{user_agent, RunAudit, RunId, RunIdPlacement};
+    use crate::auth::AnonymousAuth;
+    use crate::client::ServiceClient;
+    use super::{ConnectionPolicy, HostConnections};
+
+    fn connections(failure_threshold: u32) -> HostConnections {
+        HostConnections::new(
+            "http://127.0.0.1:8080/arcgis/rest/services/Parcels/FeatureServer/0",
+            ConnectionPolicy { failure_threshold, rotate_addresses: false, replicas: vec![] },
+            &ServiceClient::new(
+                Arc::new(AnonymousAuth),
+                RunAudit { run_id: RunId::generate(), placement: RunIdPlacement::Header, user_agent: user_agent(None) },
+            ),
+        )
+    }
+
+    #[tokio::test]
+    async fn record_failure_should_reconnect_when_threshold_reached() {
+        let connections = connections(2);
+        connections.record_failure(0, false).await;
+        assert_eq!(connections.client().1, 0);
+        connections.record_failure(0, false).await;
+        assert_eq!(connections.client().1, 1);
+    }
+
+    #[tokio::test]
+    async fn record_failure_should_ignore_failure_when_client_already_replaced() {
+        let connections = connections(5);
+        connections.record_failure(0, true).await;
+        connections.record_failure(0, true).await;
+        assert_eq!(connections.client().1, 1);
+    }
+}
@@ -0,0 +1,101 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+use indicatif::HumanDuration;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum DeadlineError {
+    InvalidDuration(String),
+    Reached(Duration),
+}
+
+impl Display for DeadlineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeadlineError::InvalidDuration(value) => {
+                write!(f, "\"{}\" is not a duration, expected a value like 45m, 3h or 1h30m", value)
+            }
+            DeadlineError::Reached(duration) => {
+                write!(f, "Stopped after the maximum duration of {}", HumanDuration(*duration))
+            }
+        }
+    }
+}
+
+impl Error for DeadlineError {}
+
+/// Parse a duration made of whole numbers of hours, minutes and seconds (e.g. `3h`, `90m`,
+/// `1h30m`). A bare number is a number of seconds.
+pub(crate) fn parse_duration(value: &str) -> Result<Duration, DeadlineError> {
+    let invalid = || DeadlineError::InvalidDuration(value.to_owned());
+    let trimmed = value.trim();
+    if let Ok(seconds) = trimmed.parse::<u64>() {
+        return Ok(Duration::from_secs(seconds))
+    }
+    let mut seconds = 0_u64;
+    let mut number = String::new();
+    for chr in trimmed.chars() {
+        if chr.is_ascii_digit() {
+            number.push(chr);
+            continue
+        }
+        let unit = match chr.to_ascii_lowercase() {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        let amount = number.parse::<u64>().map_err(|_| invalid())?;
+        seconds = amount.checked_mul(unit)
+            .and_then(|amount| seconds.checked_add(amount))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() || seconds == 0 {
+        return Err(invalid())
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Point in time after which a time-boxed scrape stops starting new queries.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Deadline {
+    start: Instant,
+    duration: Duration,
+}
+
+impl Deadline {
+    pub(crate) fn new(start: Instant, duration: Duration) -> Self {
+        Self { start, duration }
+    }
+
+    pub(crate) fn is_reached(&self) -> bool {
+        self.start.elapsed() >= self.duration
+    }
+
+    pub(crate) fn error(&self) -> DeadlineError {
+        DeadlineError::Reached(self.duration)
+    }
+}
+
+#[cfg(test)]
+mod deadline_tests {
+    use std::time::Duration;
+    use super::{parse_duration, DeadlineError};
+
+    #[test]
+    fn parse_duration_should_sum_units_when_passed_compound_value() {
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("3h"), Ok(Duration::from_secs(10800)));
+        assert_eq!(parse_duration("45"), Ok(Duration::from_secs(45)));
+    }
+
+    #[test]
+    fn parse_duration_should_fail_when_unit_missing_or_unknown() {
+        assert_eq!(parse_duration("1h30"), Err(DeadlineError::InvalidDuration("1h30".to_owned())));
+        assert_eq!(parse_duration("2d"), Err(DeadlineError::InvalidDuration("2d".to_owned())));
+        assert_eq!(parse_duration("h"), Err(DeadlineError::InvalidDuration("h".to_owned())));
+        let overflow = format!("{}h", u64::MAX / 60);
+        assert_eq!(parse_duration(&overflow), Err(DeadlineError::InvalidDuration(overflow.to_owned())));
+    }
+}
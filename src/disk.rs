@@ -0,0 +1,81 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+use indicatif::HumanBytes;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum DiskSpaceError {
+    InsufficientSpace(PathBuf, u64, u64),
+}
+
+impl Display for DiskSpaceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiskSpaceError::InsufficientSpace(path, required, available) => {
+                write!(
+                    f,
+                    "Not enough disk space at {}. Estimated {} required but only {} available",
+                    path.display(),
+                    HumanBytes(*required),
+                    HumanBytes(*available),
+                )
+            }
+        }
+    }
+}
+
+impl Error for DiskSpaceError {}
+
+/// Space a scrape is expected to use, extrapolated from the size of a single sample chunk. Temp
+/// files for every chunk exist until they are collected, so the temp directory needs roughly as
+/// much room as the final output.
+#[derive(Debug, PartialEq)]
+pub(crate) struct DiskSpaceEstimate {
+    pub(crate) temp_bytes: u64,
+    pub(crate) output_bytes: u64,
+}
+
+impl DiskSpaceEstimate {
+    pub(crate) fn from_sample(sample_bytes: u64, query_count: usize) -> Self {
+        let total = sample_bytes.saturating_mul(query_count as u64);
+        Self {
+            temp_bytes: total,
+            output_bytes: total,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn same_filesystem(first: &Path, second: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(first.metadata()?.dev() == second.metadata()?.dev())
+}
+
+#[cfg(not(unix))]
+fn same_filesystem(_first: &Path, _second: &Path) -> io::Result<bool> {
+    Ok(true)
+}
+
+fn check_path(path: &Path, required: u64) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let available = fs2::available_space(path)?;
+    if available < required {
+        return Err(Box::new(DiskSpaceError::InsufficientSpace(path.to_owned(), required, available)))
+    }
+    Ok(())
+}
+
+/// Verify that the temp and output directories can hold the estimated scrape. When both
+/// directories live on the same filesystem the requirements are combined.
+pub(crate) fn check_disk_space(
+    temp_dir: &Path,
+    output_dir: &Path,
+    estimate: &DiskSpaceEstimate,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if same_filesystem(temp_dir, output_dir)? {
+        check_path(output_dir, estimate.temp_bytes.saturating_add(estimate.output_bytes))
+    } else {
+        check_path(temp_dir, estimate.temp_bytes)?;
+        check_path(output_dir, estimate.output_bytes)
+    }
+}
@@ -0,0 +1,127 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use serde_json::{json, Value};
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum DynamicLayerError {
+    InvalidDefinition(String),
+    NotMapServer(String),
+    MissingSource(String),
+}
+
+impl Display for DynamicLayerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DynamicLayerError::InvalidDefinition(reason) => {
+                write!(f, "Invalid dynamic layer definition. {}", reason)
+            }
+            DynamicLayerError::NotMapServer(url) => {
+                write!(f, "Dynamic layers can only be queried from a MapServer, got \"{}\"", url)
+            }
+            DynamicLayerError::MissingSource(url) => {
+                write!(f, "The dynamic layer definition needs a source since \"{}\" is not a map layer", url)
+            }
+        }
+    }
+}
+
+impl Error for DynamicLayerError {}
+
+/// Parse a dynamic layer definition given as JSON text, or as `@path` of a file holding the JSON.
+pub(crate) fn parse_layer_definition(value: &str) -> Result<Value, DynamicLayerError> {
+    let text = match value.strip_prefix('@') {
+        Some(path) => fs::read_to_string(path)
+            .map_err(|error| DynamicLayerError::InvalidDefinition(format!("{}: {}", path, error)))?,
+        None => value.to_owned(),
+    };
+    let definition: Value = serde_json::from_str(&text)
+        .map_err(|error| DynamicLayerError::InvalidDefinition(error.to_string()))?;
+    if !definition.is_object() {
+        return Err(DynamicLayerError::InvalidDefinition("Expected a JSON object".to_owned()))
+    }
+    Ok(definition)
+}
+
+/// Layer definition sent as the `layer` parameter of every request to a MapServer
+/// `dynamicLayer` endpoint, so the server applies joins or definition overrides before the
+/// features are returned.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DynamicLayer {
+    pub(crate) definition: String,
+}
+
+/// Split a MapServer url into the service url and the map layer id it points to, if any.
+fn map_server_parts(url: &str) -> Option<(&str, Option<i64>)> {
+    let url = url.trim_end_matches('/');
+    let end = url.find("/MapServer")? + "/MapServer".len();
+    let (service_url, rest) = url.split_at(end);
+    let layer_id = match rest.trim_start_matches('/') {
+        "" | "dynamicLayer" => None,
+        id => Some(id.parse().ok()?),
+    };
+    Some((service_url, layer_id))
+}
+
+/// Endpoint and definition to query `url` as a dynamic layer. Without a `source` in
+/// `definition` the map layer of `url` is used, and `definition_expression` overrides any
+/// expression of the definition.
+pub(crate) fn resolve(
+    url: &str,
+    definition: Option<&Value>,
+    definition_expression: Option<&str>,
+) -> Result<(String, DynamicLayer), DynamicLayerError> {
+    let (service_url, layer_id) = map_server_parts(url)
+        .ok_or_else(|| DynamicLayerError::NotMapServer(url.to_owned()))?;
+    let mut definition = definition.cloned().unwrap_or_else(|| json!({}));
+    let object = definition
+        .as_object_mut()
+        .ok_or_else(|| DynamicLayerError::InvalidDefinition("Expected a JSON object".to_owned()))?;
+    if !object.contains_key("source") {
+        let layer_id = layer_id.ok_or_else(|| DynamicLayerError::MissingSource(url.to_owned()))?;
+        object.insert("source".to_owned(), json!({"type": "mapLayer", "mapLayerId": layer_id}));
+    }
+    if !object.contains_key("id") {
+        object.insert("id".to_owned(), json!(layer_id.unwrap_or_default()));
+    }
+    if let Some(expression) = definition_expression {
+        object.insert("definitionExpression".to_owned(), json!(expression));
+    }
+    Ok((
+        format!("{}/dynamicLayer", service_url),
+        DynamicLayer { definition: definition.to_string() },
+    ))
+}
+
+#[cfg(test)]
+mod dynamic_layer_tests {
+    use serde_json::{json, Value};
+    use super::{resolve, DynamicLayerError};
+
+    const MAP_LAYER: &str = "https://example.com/arcgis/rest/services/Parcels/MapServer/3";
+
+    #[test]
+    fn resolve_should_use_map_layer_source_when_definition_has_no_source() -> Result<(), DynamicLayerError> {
+        let (url, layer) = resolve(MAP_LAYER, None, Some("ACRES > 10"))?;
+        assert_eq!(url, "https://example.com/arcgis/rest/services/Parcels/MapServer/dynamicLayer");
+        let definition: Value = serde_json::from_str(&layer.definition).unwrap();
+        assert_eq!(definition, json!({
+            "id": 3,
+            "source": {"type": "mapLayer", "mapLayerId": 3},
+            "definitionExpression": "ACRES > 10",
+        }));
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_should_fail_when_url_is_feature_server() {
+        let url = "https://example.com/arcgis/rest/services/Parcels/FeatureServer/0";
+        assert_eq!(resolve(url, None, None).unwrap_err(), DynamicLayerError::NotMapServer(url.to_owned()));
+    }
+
+    #[test]
+    fn resolve_should_fail_when_service_url_has_no_source() {
+        let url = "https://example.com/arcgis/rest/services/Parcels/MapServer";
+        assert_eq!(resolve(url, None, None).unwrap_err(), DynamicLayerError::MissingSource(url.to_owned()));
+    }
+}
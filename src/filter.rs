@@ -0,0 +1,242 @@
+use std::fmt::{Display, Formatter};
+use std::error::Error;
+use chrono::{DateTime, Utc};
+use crate::dynamic::DynamicLayer;
+use crate::geometry::Extent;
+use crate::projection::{same_spatial_reference, transform_extent};
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum FilterParseError {
+    InvalidBoundingBox(String),
+}
+
+impl Display for FilterParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FilterParseError::InvalidBoundingBox(value) => {
+                write!(f, "Expected a bounding box as xmin,ymin,xmax,ymax but got \"{}\"", value)
+            }
+        }
+    }
+}
+
+impl Error for FilterParseError {}
+
+/// Parse `xmin,ymin,xmax,ymax` into an extent.
+pub(crate) fn parse_bbox(value: &str) -> Result<Extent, FilterParseError> {
+    let bounds = value.split(',')
+        .map(|bound| bound.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|_| FilterParseError::InvalidBoundingBox(value.to_owned()))?;
+    match bounds.as_slice() {
+        [x_min, y_min, x_max, y_max] if x_min <= x_max && y_min <= y_max => {
+            Ok(Extent { x_min: *x_min, y_min: *y_min, x_max: *x_max, y_max: *y_max })
+        }
+        _ => Err(FilterParseError::InvalidBoundingBox(value.to_owned())),
+    }
+}
+
+/// Envelope used as a spatial filter along with the spatial reference of its coordinates.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BoundingBox {
+    pub(crate) extent: Extent,
+    pub(crate) spatial_reference: i64,
+}
+
+impl BoundingBox {
+    /// The same box expressed in `spatial_reference`, when it can be projected client-side.
+    pub(crate) fn to_spatial_reference(&self, spatial_reference: i64) -> Option<BoundingBox> {
+        let extent = transform_extent(&self.extent, self.spatial_reference, spatial_reference)?;
+        Some(BoundingBox { extent, spatial_reference })
+    }
+
+    /// Whether the box overlaps `extent` (in `spatial_reference`). None when the two cannot be
+    /// compared because the projection is not supported client-side.
+    pub(crate) fn intersects(&self, extent: &Extent, spatial_reference: i64) -> Option<bool> {
+        let bbox = if same_spatial_reference(self.spatial_reference, spatial_reference) {
+            self.to_owned()
+        } else {
+            self.to_spatial_reference(spatial_reference)?
+        };
+        Some(
+            bbox.extent.x_min <= extent.x_max
+                && extent.x_min <= bbox.extent.x_max
+                && bbox.extent.y_min <= extent.y_max
+                && extent.y_min <= bbox.extent.y_max
+        )
+    }
+}
+
+/// Features edited after a point in time, for incremental scrapes.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct EditedSince {
+    /// Date field holding the last edit time of each feature. Until the layer's metadata is
+    /// parsed, None stands for the edit date field of its `editFieldsInfo`
+    pub(crate) field: Option<String>,
+    pub(crate) since: DateTime<Utc>,
+}
+
+impl EditedSince {
+    /// Clause matching the features edited after `since`, None while the field is unknown.
+    fn clause(&self) -> Option<String> {
+        let field = self.field.as_ref()?;
+        Some(format!("{} > TIMESTAMP '{}'", field, self.since.format("%Y-%m-%d %H:%M:%S")))
+    }
+}
+
+/// Restrictions applied to every query of a scrape (count, OID bounds and feature chunks) so
+/// only a subset of the layer is fetched.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct QueryFilter {
+    pub(crate) bbox: Option<BoundingBox>,
+    /// SQL clause features must match, sent as the `where` of every query
+    pub(crate) where_clause: Option<String>,
+    /// Definition of a MapServer dynamic layer, sent with every request including the metadata
+    pub(crate) dynamic_layer: Option<DynamicLayer>,
+    /// Only features edited after a time, combined with the where clause
+    pub(crate) edited_since: Option<EditedSince>,
+}
+
+impl QueryFilter {
+    /// Reproject the bounding box into the layer's spatial reference when possible, so servers
+    /// that ignore `inSR` still receive coordinates they understand.
+    pub(crate) fn for_layer(&self, layer_spatial_reference: Option<i64>) -> QueryFilter {
+        let bbox = self.bbox.as_ref().map(|bbox| {
+            layer_spatial_reference
+                .and_then(|spatial_reference| bbox.to_spatial_reference(spatial_reference))
+                .unwrap_or_else(|| bbox.to_owned())
+        });
+        QueryFilter { bbox, ..self.to_owned() }
+    }
+
+    /// The same filter restricted to `bbox` instead of the requested bounding box.
+    pub(crate) fn with_bbox(&self, bbox: &BoundingBox) -> QueryFilter {
+        QueryFilter { bbox: Some(bbox.to_owned()), ..self.to_owned() }
+    }
+
+    /// The same filter without a spatial part, for layers without geometry.
+    pub(crate) fn without_bbox(&self) -> QueryFilter {
+        QueryFilter { bbox: None, ..self.to_owned() }
+    }
+
+    /// The same filter with the edit date field of the layer, `field`.
+    pub(crate) fn with_edit_field(&self, field: &str) -> QueryFilter {
+        let edited_since = self.edited_since.as_ref().map(|edited_since| EditedSince {
+            field: Some(field.to_owned()),
+            ..edited_since.to_owned()
+        });
+        QueryFilter { edited_since, ..self.to_owned() }
+    }
+
+    /// Clause features must match, the where clause and edit time combined. None when every
+    /// feature matches.
+    pub(crate) fn clause(&self) -> Option<String> {
+        let edited = self.edited_since.as_ref().and_then(EditedSince::clause);
+        match (&self.where_clause, edited) {
+            (Some(where_clause), Some(edited)) => Some(format!("({}) and ({})", where_clause, edited)),
+            (Some(where_clause), None) => Some(where_clause.to_owned()),
+            (None, edited) => edited,
+        }
+    }
+
+    /// `where` parameter of a query, every feature when no clause is given.
+    pub(crate) fn where_param(&self) -> String {
+        self.clause().unwrap_or_else(|| String::from("1=1"))
+    }
+
+    /// `where` parameter of a query further restricted to the features matching `clause`.
+    pub(crate) fn where_param_and(&self, clause: &str) -> String {
+        match self.clause() {
+            Some(where_clause) => format!("({}) and ({})", where_clause, clause),
+            None => clause.to_owned(),
+        }
+    }
+
+    /// `layer` parameter of a dynamic layer request, needed by every endpoint of the layer.
+    pub(crate) fn dynamic_layer_params(&self) -> Vec<(&'static str, String)> {
+        self.dynamic_layer.iter()
+            .map(|dynamic_layer| ("layer", dynamic_layer.definition.to_owned()))
+            .collect()
+    }
+
+    /// Parameters for a query request. `geometryType` describes the filter geometry.
+    pub(crate) fn query_params(&self) -> Vec<(&'static str, String)> {
+        let mut params = self.dynamic_layer_params();
+        params.append(&mut self.spatial_params());
+        params
+    }
+
+    fn spatial_params(&self) -> Vec<(&'static str, String)> {
+        match &self.bbox {
+            Some(bbox) => vec![
+                (
+                    "geometry",
+                    format!(
+                        "{},{},{},{}",
+                        bbox.extent.x_min,
+                        bbox.extent.y_min,
+                        bbox.extent.x_max,
+                        bbox.extent.y_max,
+                    ),
+                ),
+                ("geometryType", String::from("esriGeometryEnvelope")),
+                ("inSR", bbox.spatial_reference.to_string()),
+                ("spatialRel", String::from("esriSpatialRelIntersects")),
+            ],
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod filter_tests {
+    use chrono::{TimeZone, Utc};
+    use crate::geometry::Extent;
+    use super::{parse_bbox, BoundingBox, EditedSince, FilterParseError, QueryFilter};
+
+    #[test]
+    fn parse_bbox_should_return_extent_when_passed_negative_bounds() -> Result<(), FilterParseError> {
+        let result = parse_bbox("-123.5, 45, -122,46.25")?;
+        assert_eq!(result, Extent { x_min: -123.5, y_min: 45.0, x_max: -122.0, y_max: 46.25 });
+        Ok(())
+    }
+
+    #[test]
+    fn parse_bbox_should_fail_when_min_exceeds_max() {
+        let result = parse_bbox("10,0,5,1");
+        assert_eq!(result.unwrap_err(), FilterParseError::InvalidBoundingBox("10,0,5,1".to_owned()));
+    }
+
+    #[test]
+    fn for_layer_should_keep_bbox_when_projection_unsupported() {
+        let bbox = BoundingBox {
+            extent: Extent { x_min: -123.0, y_min: 45.0, x_max: -122.0, y_max: 46.0 },
+            spatial_reference: 4326,
+        };
+        let filter = QueryFilter { bbox: Some(bbox.clone()), ..QueryFilter::default() };
+        assert_eq!(filter.for_layer(Some(2913)).bbox, Some(bbox));
+    }
+
+    #[test]
+    fn where_param_and_should_combine_clauses_when_where_clause_given() {
+        let filter = QueryFilter { where_clause: Some("STATE='CA' or STATE='OR'".to_owned()), ..QueryFilter::default() };
+        assert_eq!(filter.where_param_and("OBJECTID >= 1"), "(STATE='CA' or STATE='OR') and (OBJECTID >= 1)");
+        assert_eq!(QueryFilter::default().where_param_and("OBJECTID >= 1"), "OBJECTID >= 1");
+        assert_eq!(QueryFilter::default().where_param(), "1=1");
+    }
+
+    #[test]
+    fn where_param_should_add_edit_time_when_edit_field_resolved() {
+        let since = Utc.with_ymd_and_hms(2024, 3, 1, 6, 30, 0).unwrap();
+        let filter = QueryFilter {
+            where_clause: Some("STATE='CA'".to_owned()),
+            edited_since: Some(EditedSince { field: None, since }),
+            ..QueryFilter::default()
+        };
+        assert_eq!(filter.where_param(), "STATE='CA'");
+        assert_eq!(
+            filter.with_edit_field("last_edited_date").where_param(),
+            "(STATE='CA') and (last_edited_date > TIMESTAMP '2024-03-01 06:30:00')",
+        );
+    }
+}
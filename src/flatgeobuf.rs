@@ -0,0 +1,488 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use chrono::{DateTime, SecondsFormat};
+use flatbuffers::{FlatBufferBuilder, VOffsetT, WIPOffset};
+use serde_json::{Map, Value};
+use crate::checksum::{Artifact, ChecksumFile};
+use crate::geometry::{esri_extent, esri_parts, Extent};
+use crate::metadata::{RestServiceFieldType, RestServiceGeometryType, RestServiceMetadata};
+use crate::pmtiles::tile_id;
+use crate::projection;
+use crate::wkt::{group_rings, Ring};
+
+/// Magic bytes opening FlatGeobuf files, major version 3.
+const MAGIC_BYTES: [u8; 8] = [b'f', b'g', b'b', 3, b'f', b'g', b'b', 1];
+/// Children of each node of the packed R-tree, the FlatGeobuf default.
+const INDEX_NODE_SIZE: usize = 16;
+/// Largest Hilbert coordinate features are sorted by, features along each axis being spread
+/// over this many cells of the layer's envelope.
+const HILBERT_MAX: f64 = 65535.0;
+
+/// Geometry types of the FlatGeobuf schema. Polylines and polygons are always written as their
+/// multi type, layers of a single geometry type being what readers expect.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GeometryType {
+    Point = 1,
+    Polygon = 3,
+    MultiPoint = 4,
+    MultiLineString = 5,
+    MultiPolygon = 6,
+}
+
+impl GeometryType {
+    fn of(geo_type: &RestServiceGeometryType) -> Option<Self> {
+        match geo_type {
+            RestServiceGeometryType::Point => Some(GeometryType::Point),
+            RestServiceGeometryType::Multipoint => Some(GeometryType::MultiPoint),
+            RestServiceGeometryType::Polyline => Some(GeometryType::MultiLineString),
+            RestServiceGeometryType::Polygon => Some(GeometryType::MultiPolygon),
+            RestServiceGeometryType::Envelope => Some(GeometryType::Polygon),
+            RestServiceGeometryType::None => None,
+        }
+    }
+}
+
+/// Column types of the FlatGeobuf schema.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ColumnType {
+    Short = 3,
+    Int = 5,
+    Long = 7,
+    Float = 9,
+    Double = 10,
+    String = 11,
+    /// ISO 8601 text
+    DateTime = 13,
+}
+
+impl ColumnType {
+    fn of(field_type: &RestServiceFieldType) -> Self {
+        match field_type {
+            RestServiceFieldType::OID => ColumnType::Long,
+            RestServiceFieldType::Integer => ColumnType::Int,
+            RestServiceFieldType::SmallInteger => ColumnType::Short,
+            RestServiceFieldType::Double => ColumnType::Double,
+            RestServiceFieldType::Single | RestServiceFieldType::Float => ColumnType::Float,
+            RestServiceFieldType::Date => ColumnType::DateTime,
+            _ => ColumnType::String,
+        }
+    }
+}
+
+/// Offset in the vtable of the field at `index` of a FlatBuffers table.
+const fn slot(index: VOffsetT) -> VOffsetT {
+    4 + 2 * index
+}
+
+#[derive(Debug, Clone)]
+struct FlatgeobufColumn {
+    name: String,
+    column_type: ColumnType,
+    /// Position of the column's values in the records
+    index: usize,
+}
+
+/// Typed columns and geometry type of a layer's FlatGeobuf file, taken from the record columns
+/// without the geometry columns.
+#[derive(Debug, Clone)]
+pub(crate) struct FlatgeobufLayout {
+    name: String,
+    columns: Vec<FlatgeobufColumn>,
+    geo_type: RestServiceGeometryType,
+    geometry_type: GeometryType,
+    /// EPSG code of the geometries, when known
+    srid: Option<i64>,
+}
+
+/// Feature encoded as a size prefixed FlatBuffer, with its bounds for the spatial index.
+#[derive(Debug)]
+pub(crate) struct EncodedFeature {
+    extent: Option<Extent>,
+    bytes: Vec<u8>,
+}
+
+impl FlatgeobufLayout {
+    /// Layout of the records of `layer` with `columns`, None for tables. Columns that are not
+    /// fields of the layer (code descriptions, provenance, cells) are text, except the measure
+    /// column.
+    pub(crate) fn new(layer: &RestServiceMetadata, columns: &[String], measure_column: Option<&str>) -> Option<Self> {
+        let geometry_type = GeometryType::of(&layer.geo_type)?;
+        let columns = columns.iter()
+            .enumerate()
+            .filter_map(|(index, name)| {
+                let field = layer.fields.iter().find(|field| field.name == *name);
+                let column_type = match field {
+                    Some(field) if field.field_type == RestServiceFieldType::Geometry => return None,
+                    Some(field) => ColumnType::of(&field.field_type),
+                    None if measure_column == Some(name.as_str()) => ColumnType::Double,
+                    None => ColumnType::String,
+                };
+                Some(FlatgeobufColumn { name: name.to_owned(), column_type, index })
+            })
+            .collect();
+        let srid = layer.output_spatial_reference()
+            .map(|wkid| if projection::same_spatial_reference(wkid, 3857) { 3857 } else { wkid });
+        Some(Self { name: layer.name.to_owned(), columns, geo_type: layer.geo_type.clone(), geometry_type, srid })
+    }
+
+    /// Properties of a record, each value prefixed by the index of its column. Empty values and
+    /// values that do not parse as the type of their column are left out, FlatGeobuf's null.
+    fn properties(&self, record: &[String]) -> Vec<u8> {
+        let mut properties = vec![];
+        for (column_index, column) in self.columns.iter().enumerate() {
+            let value = match record.get(column.index).filter(|value| !value.is_empty()) {
+                Some(value) => value,
+                None => continue,
+            };
+            let integer = || {
+                value.parse::<i64>()
+                    .ok()
+                    .or_else(|| value.parse::<f64>().ok().filter(|number| number.fract() == 0.0).map(|number| number as i64))
+            };
+            let encoded: Option<Vec<u8>> = match column.column_type {
+                ColumnType::Short => integer().and_then(|number| i16::try_from(number).ok()).map(|number| number.to_le_bytes().to_vec()),
+                ColumnType::Int => integer().and_then(|number| i32::try_from(number).ok()).map(|number| number.to_le_bytes().to_vec()),
+                ColumnType::Long => integer().map(|number| number.to_le_bytes().to_vec()),
+                ColumnType::Float => value.parse::<f32>().ok().map(|number| number.to_le_bytes().to_vec()),
+                ColumnType::Double => value.parse::<f64>().ok().map(|number| number.to_le_bytes().to_vec()),
+                ColumnType::DateTime => {
+                    // Dates are sent as milliseconds since the epoch, unless already formatted
+                    let text = integer()
+                        .and_then(DateTime::from_timestamp_millis)
+                        .map_or_else(|| value.to_owned(), |date| date.to_rfc3339_opts(SecondsFormat::Millis, true));
+                    Some(text_bytes(&text))
+                }
+                ColumnType::String => Some(text_bytes(value)),
+            };
+            if let Some(encoded) = encoded {
+                properties.extend((column_index as u16).to_le_bytes());
+                properties.extend(encoded);
+            }
+        }
+        properties
+    }
+
+    /// Encode the record of a feature and its geometry.
+    pub(crate) fn encode(&self, record: &[String], feature: &Map<String, Value>) -> EncodedFeature {
+        let geometry = feature.get("geometry").and_then(Value::as_object);
+        let mut builder = FlatBufferBuilder::new();
+        let encoded_geometry = geometry.and_then(|geometry| self.geometry(&mut builder, geometry));
+        let properties = builder.create_vector(&self.properties(record));
+        let table = builder.start_table();
+        if let Some(encoded_geometry) = encoded_geometry {
+            builder.push_slot_always(slot(0), encoded_geometry);
+        }
+        builder.push_slot_always(slot(1), properties);
+        let root = builder.end_table(table);
+        builder.finish_size_prefixed(root, None);
+        EncodedFeature {
+            extent: encoded_geometry.and(geometry).and_then(esri_extent),
+            bytes: builder.finished_data().to_vec(),
+        }
+    }
+
+    /// Geometry table of an Esri JSON geometry, None when it has no coordinates.
+    fn geometry<'a>(
+        &self,
+        builder: &mut FlatBufferBuilder<'a>,
+        geometry: &Map<String, Value>,
+    ) -> Option<WIPOffset<flatbuffers::TableFinishedWIPOffset>> {
+        let coordinate = |key: &str| geometry.get(key).and_then(Value::as_f64);
+        match self.geo_type {
+            RestServiceGeometryType::Point => {
+                let (x, y) = coordinate("x").zip(coordinate("y"))?;
+                Some(geometry_table(builder, &[vec![(x, y)]], None))
+            }
+            RestServiceGeometryType::Multipoint => {
+                let points: Vec<(f64, f64)> = esri_parts(geometry, "points").into_iter().flatten().collect();
+                (!points.is_empty()).then(|| geometry_table(builder, &[points], None))
+            }
+            RestServiceGeometryType::Polyline => {
+                let paths: Vec<Ring> = esri_parts(geometry, "paths")
+                    .into_iter()
+                    .filter(|path| path.len() >= 2)
+                    .collect();
+                (!paths.is_empty()).then(|| geometry_table(builder, &paths, None))
+            }
+            RestServiceGeometryType::Polygon => {
+                let polygons = group_rings(esri_parts(geometry, "rings"));
+                if polygons.is_empty() {
+                    return None
+                }
+                let parts: Vec<_> = polygons.iter()
+                    .map(|polygon| geometry_table(builder, polygon, Some(GeometryType::Polygon)))
+                    .collect();
+                let parts = builder.create_vector(&parts);
+                let table = builder.start_table();
+                builder.push_slot_always(slot(7), parts);
+                Some(builder.end_table(table))
+            }
+            RestServiceGeometryType::Envelope => {
+                let (x_min, y_min) = coordinate("xmin").zip(coordinate("ymin"))?;
+                let (x_max, y_max) = coordinate("xmax").zip(coordinate("ymax"))?;
+                let ring = vec![(x_min, y_min), (x_max, y_min), (x_max, y_max), (x_min, y_max), (x_min, y_min)];
+                Some(geometry_table(builder, &[ring], None))
+            }
+            RestServiceGeometryType::None => None,
+        }
+    }
+}
+
+/// String value of a property, prefixed by its length.
+fn text_bytes(text: &str) -> Vec<u8> {
+    let mut bytes = (text.len() as u32).to_le_bytes().to_vec();
+    bytes.extend(text.as_bytes());
+    bytes
+}
+
+/// Geometry table of the parts (rings, paths or a single sequence of points), with the end of
+/// each part when there are several. Parts of multi polygons name their type.
+fn geometry_table<'a>(
+    builder: &mut FlatBufferBuilder<'a>,
+    parts: &[Ring],
+    part_type: Option<GeometryType>,
+) -> WIPOffset<flatbuffers::TableFinishedWIPOffset> {
+    let xy: Vec<f64> = parts.iter().flatten().flat_map(|(x, y)| [*x, *y]).collect();
+    let xy = builder.create_vector(&xy);
+    let ends = (parts.len() > 1).then(|| {
+        let ends: Vec<u32> = parts.iter()
+            .scan(0, |end, part| {
+                *end += part.len() as u32;
+                Some(*end)
+            })
+            .collect();
+        builder.create_vector(&ends)
+    });
+    let table = builder.start_table();
+    if let Some(ends) = ends {
+        builder.push_slot_always(slot(0), ends);
+    }
+    builder.push_slot_always(slot(1), xy);
+    if let Some(part_type) = part_type {
+        builder.push_slot_always(slot(6), part_type as u8);
+    }
+    builder.end_table(table)
+}
+
+/// Feature spooled until the file is finished, its position in the spool file.
+struct SpooledFeature {
+    extent: Option<Extent>,
+    offset: u64,
+    size: usize,
+}
+
+/// Node of the packed R-tree, its bounds and either the offset of its feature (leaves) or the
+/// index of its first child.
+#[derive(Clone, Copy)]
+struct IndexNode {
+    extent: Extent,
+    offset: u64,
+}
+
+impl IndexNode {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for value in [self.extent.x_min, self.extent.y_min, self.extent.x_max, self.extent.y_max] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        writer.write_all(&self.offset.to_le_bytes())
+    }
+}
+
+/// Bounds of nodes never matching a search, for features without a geometry.
+const NO_EXTENT: Extent = Extent { x_min: f64::INFINITY, y_min: f64::INFINITY, x_max: f64::NEG_INFINITY, y_max: f64::NEG_INFINITY };
+
+/// Range of the nodes of each level of a packed R-tree of `items` leaves, from the leaves up. The
+/// root comes first in the tree and the leaves last.
+fn level_bounds(items: usize) -> Vec<(usize, usize)> {
+    let mut level_sizes = vec![items];
+    let mut size = items;
+    loop {
+        size = size.div_ceil(INDEX_NODE_SIZE);
+        level_sizes.push(size);
+        if size == 1 {
+            break
+        }
+    }
+    let mut end: usize = level_sizes.iter().sum();
+    level_sizes.iter()
+        .map(|size| {
+            end -= size;
+            (end, end + size)
+        })
+        .collect()
+}
+
+/// Packed Hilbert R-tree over the leaves, sorted by the caller.
+fn packed_rtree(leaves: Vec<IndexNode>) -> Vec<IndexNode> {
+    let levels = level_bounds(leaves.len());
+    let mut nodes = vec![IndexNode { extent: NO_EXTENT, offset: 0 }; levels[0].1];
+    nodes[levels[0].0..levels[0].1].copy_from_slice(&leaves);
+    for level in 0..levels.len() - 1 {
+        let (start, end) = levels[level];
+        let parents = levels[level + 1].0..;
+        for (parent, first_child) in parents.zip((start..end).step_by(INDEX_NODE_SIZE)) {
+            let extent = nodes[first_child..end.min(first_child + INDEX_NODE_SIZE)]
+                .iter()
+                .fold(NO_EXTENT, |extent, node| extent.union(&node.extent));
+            nodes[parent] = IndexNode { extent, offset: first_child as u64 };
+        }
+    }
+    nodes
+}
+
+/// Writes the features of a layer into a FlatGeobuf file with a packed Hilbert R-tree index, so
+/// it can be queried by bounding box over HTTP range requests. The index comes before the
+/// features, which are spooled to a temporary file next to the output until every chunk is
+/// written.
+pub(crate) struct FlatgeobufWriter {
+    layout: FlatgeobufLayout,
+    path: std::path::PathBuf,
+    spool: File,
+    features: Vec<SpooledFeature>,
+    spooled_bytes: u64,
+}
+
+impl FlatgeobufWriter {
+    pub(crate) fn create(path: &Path, layout: FlatgeobufLayout) -> io::Result<Self> {
+        let directory = path.parent().unwrap_or(Path::new("."));
+        Ok(Self {
+            layout,
+            path: path.to_owned(),
+            spool: tempfile::tempfile_in(directory)?,
+            features: vec![],
+            spooled_bytes: 0,
+        })
+    }
+
+    /// Spool the features of a chunk.
+    pub(crate) fn write(&mut self, features: Vec<EncodedFeature>) -> io::Result<()> {
+        let mut spool = BufWriter::new(&mut self.spool);
+        for feature in features {
+            spool.write_all(&feature.bytes)?;
+            self.features.push(SpooledFeature {
+                extent: feature.extent,
+                offset: self.spooled_bytes,
+                size: feature.bytes.len(),
+            });
+            self.spooled_bytes += feature.bytes.len() as u64;
+        }
+        spool.flush()
+    }
+
+    fn header(&self, envelope: Option<&Extent>) -> Vec<u8> {
+        let mut builder = FlatBufferBuilder::new();
+        let name = builder.create_string(&self.layout.name);
+        let envelope = envelope.map(|extent| builder.create_vector(&[extent.x_min, extent.y_min, extent.x_max, extent.y_max]));
+        let columns: Vec<_> = self.layout.columns.iter()
+            .map(|column| {
+                let name = builder.create_string(&column.name);
+                let table = builder.start_table();
+                builder.push_slot_always(slot(0), name);
+                builder.push_slot_always(slot(1), column.column_type as u8);
+                builder.end_table(table)
+            })
+            .collect();
+        let columns = builder.create_vector(&columns);
+        let crs = self.layout.srid.map(|srid| {
+            let org = builder.create_string("EPSG");
+            let table = builder.start_table();
+            builder.push_slot_always(slot(0), org);
+            builder.push_slot_always(slot(1), srid as i32);
+            builder.end_table(table)
+        });
+        let table = builder.start_table();
+        builder.push_slot_always(slot(0), name);
+        if let Some(envelope) = envelope {
+            builder.push_slot_always(slot(1), envelope);
+        }
+        builder.push_slot_always(slot(2), self.layout.geometry_type as u8);
+        builder.push_slot_always(slot(7), columns);
+        builder.push_slot_always(slot(8), self.features.len() as u64);
+        // Files without features have no index
+        let node_size = if self.features.is_empty() { 0 } else { INDEX_NODE_SIZE as u16 };
+        builder.push_slot_always(slot(9), node_size);
+        if let Some(crs) = crs {
+            builder.push_slot_always(slot(10), crs);
+        }
+        let root = builder.end_table(table);
+        builder.finish_size_prefixed(root, None);
+        builder.finished_data().to_vec()
+    }
+
+    /// Sort the features along a Hilbert curve over the layer's envelope and write the header,
+    /// the index and the features.
+    pub(crate) fn finish(mut self) -> io::Result<Artifact> {
+        let envelope = self.features.iter()
+            .filter_map(|feature| feature.extent)
+            .reduce(|envelope, extent| envelope.union(&extent));
+        if let Some(envelope) = &envelope {
+            let width = envelope.x_max - envelope.x_min;
+            let height = envelope.y_max - envelope.y_min;
+            let cell = |value: f64, min: f64, size: f64| {
+                if size > 0.0 { (HILBERT_MAX * (value - min) / size) as u32 } else { 0 }
+            };
+            // Features without a geometry sort first
+            self.features.sort_by_cached_key(|feature| {
+                feature.extent.map(|extent| {
+                    let x = cell((extent.x_min + extent.x_max) / 2.0, envelope.x_min, width);
+                    let y = cell((extent.y_min + extent.y_max) / 2.0, envelope.y_min, height);
+                    tile_id(16, x, y) + 1
+                })
+            });
+        }
+        let mut output = ChecksumFile::create(&self.path)?;
+        let mut writer = BufWriter::new(&mut output);
+        writer.write_all(&MAGIC_BYTES)?;
+        writer.write_all(&self.header(envelope.as_ref()))?;
+        if !self.features.is_empty() {
+            let mut offset = 0;
+            let leaves = self.features.iter()
+                .map(|feature| {
+                    let node = IndexNode { extent: feature.extent.unwrap_or(NO_EXTENT), offset };
+                    offset += feature.size as u64;
+                    node
+                })
+                .collect();
+            for node in packed_rtree(leaves) {
+                node.write_to(&mut writer)?;
+            }
+        }
+        let mut buffer = vec![];
+        for feature in &self.features {
+            buffer.resize(feature.size, 0);
+            self.spool.seek(SeekFrom::Start(feature.offset))?;
+            self.spool.read_exact(&mut buffer)?;
+            writer.write_all(&buffer)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        output.finish()
+    }
+}
+
+#[cfg(test)]
+mod flatgeobuf_tests {
+    use super::{level_bounds, packed_rtree, IndexNode, INDEX_NODE_SIZE};
+    use crate::geometry::Extent;
+
+    #[test]
+    fn packed_rtree_should_put_root_first_when_passed_leaves_over_several_levels() {
+        let items = INDEX_NODE_SIZE * INDEX_NODE_SIZE + 1;
+        assert_eq!(level_bounds(items), [(20, 277), (3, 20), (1, 3), (0, 1)]);
+        assert_eq!(level_bounds(1), [(1, 2), (0, 1)]);
+        let leaves: Vec<IndexNode> = (0..items)
+            .map(|index| {
+                let x = index as f64;
+                IndexNode { extent: Extent { x_min: x, y_min: 0.0, x_max: x + 1.0, y_max: 1.0 }, offset: index as u64 * 10 }
+            })
+            .collect();
+        let nodes = packed_rtree(leaves);
+        assert_eq!(nodes.len(), 277);
+        assert_eq!(nodes[0].extent, Extent { x_min: 0.0, y_min: 0.0, x_max: items as f64, y_max: 1.0 });
+        assert_eq!(nodes[0].offset, 1);
+        assert_eq!((nodes[2].offset, nodes[2].extent.x_min), (19, 256.0));
+        assert_eq!((nodes[276].offset, nodes[276].extent.x_min), (2560, 256.0));
+    }
+}
@@ -0,0 +1,309 @@
+use std::fmt::{Display, Formatter};
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Bounding box of a set of coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub(crate) struct Extent {
+    pub(crate) x_min: f64,
+    pub(crate) y_min: f64,
+    pub(crate) x_max: f64,
+    pub(crate) y_max: f64,
+}
+
+impl Display for Extent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}, {}, {}, {}", self.x_min, self.y_min, self.x_max, self.y_max)
+    }
+}
+
+impl Extent {
+    pub(crate) fn from_point(x: f64, y: f64) -> Self {
+        Self { x_min: x, y_min: y, x_max: x, y_max: y }
+    }
+
+    fn include(&mut self, x: f64, y: f64) {
+        self.x_min = self.x_min.min(x);
+        self.y_min = self.y_min.min(y);
+        self.x_max = self.x_max.max(x);
+        self.y_max = self.y_max.max(y);
+    }
+
+    /// Parse an Esri JSON envelope (`{"xmin": .., "ymin": .., "xmax": .., "ymax": ..}`). Empty
+    /// layers report "NaN" bounds, which yield None.
+    pub(crate) fn from_esri_json(envelope: &Value) -> Option<Extent> {
+        let extent = Extent {
+            x_min: envelope["xmin"].as_f64()?,
+            y_min: envelope["ymin"].as_f64()?,
+            x_max: envelope["xmax"].as_f64()?,
+            y_max: envelope["ymax"].as_f64()?,
+        };
+        Some(extent)
+    }
+
+    /// Smallest extent covering both `self` and `other`.
+    pub(crate) fn union(&self, other: &Extent) -> Extent {
+        Extent {
+            x_min: self.x_min.min(other.x_min),
+            y_min: self.y_min.min(other.y_min),
+            x_max: self.x_max.max(other.x_max),
+            y_max: self.y_max.max(other.y_max),
+        }
+    }
+}
+
+fn push_position(position: &Value, coordinates: &mut Vec<(f64, f64)>) {
+    if let Some(position) = position.as_array() {
+        if let (Some(x), Some(y)) = (
+            position.first().and_then(Value::as_f64),
+            position.get(1).and_then(Value::as_f64),
+        ) {
+            coordinates.push((x, y));
+        }
+    }
+}
+
+/// Every x/y pair of an Esri JSON geometry (point, multipoint, polyline, polygon or envelope).
+pub(crate) fn esri_coordinates(geometry: &Map<String, Value>) -> Vec<(f64, f64)> {
+    let mut coordinates = vec![];
+    if let (Some(x), Some(y)) = (
+        geometry.get("x").and_then(Value::as_f64),
+        geometry.get("y").and_then(Value::as_f64),
+    ) {
+        coordinates.push((x, y));
+    }
+    if let (Some(x_min), Some(y_min), Some(x_max), Some(y_max)) = (
+        geometry.get("xmin").and_then(Value::as_f64),
+        geometry.get("ymin").and_then(Value::as_f64),
+        geometry.get("xmax").and_then(Value::as_f64),
+        geometry.get("ymax").and_then(Value::as_f64),
+    ) {
+        coordinates.push((x_min, y_min));
+        coordinates.push((x_max, y_max));
+    }
+    if let Some(points) = geometry.get("points").and_then(Value::as_array) {
+        for point in points {
+            push_position(point, &mut coordinates);
+        }
+    }
+    for key in ["paths", "rings"] {
+        if let Some(parts) = geometry.get(key).and_then(Value::as_array) {
+            for part in parts.iter().filter_map(Value::as_array) {
+                for position in part {
+                    push_position(position, &mut coordinates);
+                }
+            }
+        }
+    }
+    coordinates
+}
+
+/// Parts (paths or rings) of an Esri JSON geometry under `key`.
+pub(crate) fn esri_parts(geometry: &Map<String, Value>, key: &str) -> Vec<Vec<(f64, f64)>> {
+    geometry.get(key)
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_array)
+        .map(|part| {
+            let mut coordinates = vec![];
+            for position in part {
+                push_position(position, &mut coordinates);
+            }
+            coordinates
+        })
+        .collect()
+}
+
+fn mean(coordinates: &[(f64, f64)]) -> Option<(f64, f64)> {
+    if coordinates.is_empty() {
+        return None
+    }
+    let count = coordinates.len() as f64;
+    let (x, y) = coordinates.iter().fold((0_f64, 0_f64), |(x, y), point| (x + point.0, y + point.1));
+    Some((x / count, y / count))
+}
+
+/// Centroid of an Esri JSON geometry in its own (planar) coordinates. Polygons use the area
+/// weighted centroid of their rings, holes subtracting since they wind the other way, and
+/// polylines the length weighted midpoint of their segments. Degenerate shapes fall back to the
+/// mean of their coordinates.
+pub(crate) fn esri_centroid(geometry: &Map<String, Value>) -> Option<(f64, f64)> {
+    let segments = |parts: &[Vec<(f64, f64)>]| -> Vec<((f64, f64), (f64, f64))> {
+        parts.iter()
+            .flat_map(|part| part.iter().zip(part.iter().skip(1)).map(|(a, b)| (*a, *b)))
+            .collect()
+    };
+    let rings = esri_parts(geometry, "rings");
+    if !rings.is_empty() {
+        let closed: Vec<Vec<(f64, f64)>> = rings.into_iter()
+            .filter(|ring| !ring.is_empty())
+            .map(|mut ring| {
+                if ring.first() != ring.last() {
+                    ring.push(ring[0]);
+                }
+                ring
+            })
+            .collect();
+        let (mut area, mut x, mut y) = (0_f64, 0_f64, 0_f64);
+        for (a, b) in segments(&closed) {
+            let cross = a.0 * b.1 - b.0 * a.1;
+            area += cross;
+            x += (a.0 + b.0) * cross;
+            y += (a.1 + b.1) * cross;
+        }
+        if area != 0_f64 {
+            return Some((x / (3_f64 * area), y / (3_f64 * area)))
+        }
+        return mean(&closed.concat())
+    }
+    let paths = esri_parts(geometry, "paths");
+    if !paths.is_empty() {
+        let (mut length, mut x, mut y) = (0_f64, 0_f64, 0_f64);
+        for (a, b) in segments(&paths) {
+            let segment = (b.0 - a.0).hypot(b.1 - a.1);
+            length += segment;
+            x += (a.0 + b.0) / 2_f64 * segment;
+            y += (a.1 + b.1) / 2_f64 * segment;
+        }
+        if length > 0_f64 {
+            return Some((x / length, y / length))
+        }
+        return mean(&paths.concat())
+    }
+    mean(&esri_coordinates(geometry))
+}
+
+/// Ring orientation of the polygons written to the output files.
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+pub(crate) enum RingWinding {
+    /// Esri JSON, exterior rings clockwise and holes counter-clockwise
+    Esri,
+    /// RFC 7946 GeoJSON, exterior rings counter-clockwise and holes clockwise
+    Rfc7946,
+}
+
+/// Twice the signed area of a ring, positive when counter-clockwise with y pointing up.
+fn ring_area(ring: &[(f64, f64)]) -> f64 {
+    ring.iter()
+        .zip(ring.iter().cycle().skip(1))
+        .map(|(a, b)| a.0 * b.1 - b.0 * a.1)
+        .sum()
+}
+
+pub(crate) fn ring_contains(ring: &[(f64, f64)], point: &(f64, f64)) -> bool {
+    let mut inside = false;
+    for (a, b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+        if (a.1 > point.1) != (b.1 > point.1)
+            && point.0 < (b.0 - a.0) * (point.1 - a.1) / (b.1 - a.1) + a.0 {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// Wind the rings of an Esri JSON polygon as `winding` expects. Rings are classified by their
+/// Esri orientation unless `fix` is set, in which case a ring nested in an odd number of other
+/// rings is a hole and any other ring an exterior, for servers sending rings reversed.
+pub(crate) fn rewind_rings(geometry: &mut Map<String, Value>, winding: &RingWinding, fix: bool) {
+    let parts = match geometry.get_mut("rings").and_then(Value::as_array_mut) {
+        Some(parts) => parts,
+        None => return,
+    };
+    let rings: Vec<Vec<(f64, f64)>> = parts.iter()
+        .map(|part| {
+            let mut coordinates = vec![];
+            for position in part.as_array().into_iter().flatten() {
+                push_position(position, &mut coordinates);
+            }
+            coordinates
+        })
+        .collect();
+    for (index, part) in parts.iter_mut().enumerate() {
+        let ring = &rings[index];
+        let area = ring_area(ring);
+        if area == 0_f64 {
+            continue
+        }
+        let is_exterior = if fix {
+            let depth = rings.iter()
+                .enumerate()
+                .filter(|(other, other_ring)| *other != index && ring_contains(other_ring, &ring[0]))
+                .count();
+            depth % 2 == 0
+        } else {
+            area < 0_f64
+        };
+        let counter_clockwise = is_exterior == (*winding == RingWinding::Rfc7946);
+        if (area > 0_f64) != counter_clockwise {
+            if let Some(positions) = part.as_array_mut() {
+                positions.reverse();
+            }
+        }
+    }
+}
+
+/// Extent of an Esri JSON geometry, None for empty or missing geometries.
+pub(crate) fn esri_extent(geometry: &Map<String, Value>) -> Option<Extent> {
+    let coordinates = esri_coordinates(geometry);
+    let (first, rest) = coordinates.split_first()?;
+    let mut extent = Extent::from_point(first.0, first.1);
+    for (x, y) in rest {
+        extent.include(*x, *y);
+    }
+    Some(extent)
+}
+
+#[cfg(test)]
+mod esri_extent_tests {
+    use serde_json::json;
+    use super::{esri_centroid, esri_extent, rewind_rings, Extent, RingWinding};
+
+    #[test]
+    fn esri_extent_should_cover_all_rings_when_passed_polygon() {
+        let geometry = json!({"rings": [[[1, 2], [4, 2], [4, 6], [1, 2]], [[-3, 0], [0, 0], [-3, 0]]]});
+        let result = esri_extent(geometry.as_object().unwrap());
+        assert_eq!(result, Some(Extent { x_min: -3.0, y_min: 0.0, x_max: 4.0, y_max: 6.0 }));
+    }
+
+    #[test]
+    fn esri_extent_should_return_none_when_passed_empty_geometry() {
+        let geometry = json!({"paths": []});
+        assert_eq!(esri_extent(geometry.as_object().unwrap()), None);
+    }
+
+    #[test]
+    fn esri_centroid_should_exclude_hole_when_passed_polygon() {
+        let geometry = json!({"rings": [
+            [[0, 0], [0, 4], [4, 4], [4, 0], [0, 0]],
+            [[2, 0], [4, 0], [4, 4], [2, 4], [2, 0]],
+        ]});
+        assert_eq!(esri_centroid(geometry.as_object().unwrap()), Some((1.0, 2.0)));
+    }
+
+    #[test]
+    fn rewind_rings_should_fix_reversed_rings_when_fix_is_set() {
+        let mut geometry = json!({"rings": [
+            [[0, 0], [4, 0], [4, 4], [0, 4], [0, 0]],
+            [[1, 1], [1, 2], [2, 2], [2, 1], [1, 1]],
+        ]});
+        let geometry = geometry.as_object_mut().unwrap();
+        rewind_rings(geometry, &RingWinding::Esri, true);
+        assert_eq!(
+            geometry["rings"],
+            json!([
+                [[0, 0], [0, 4], [4, 4], [4, 0], [0, 0]],
+                [[1, 1], [2, 1], [2, 2], [1, 2], [1, 1]],
+            ]),
+        );
+        rewind_rings(geometry, &RingWinding::Rfc7946, false);
+        assert_eq!(
+            geometry["rings"],
+            json!([
+                [[0, 0], [4, 0], [4, 4], [0, 4], [0, 0]],
+                [[1, 1], [1, 2], [2, 2], [2, 1], [1, 1]],
+            ]),
+        );
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::Write;
+use clap::ValueEnum;
+use serde_json::{json, Map, Value};
+use tablestream::{col, Stream};
+use crate::metadata::RestServiceGeometryType;
+
+/// What happens to features whose geometry does not match the layer's geometry type.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub(crate) enum GeometryPolicy {
+    /// Write the geometry as sent, only counting it
+    Keep,
+    /// Convert the geometry to the layer's type when it can be (e.g. a single point multipoint
+    /// of a point layer), otherwise write the feature without geometry
+    Coerce,
+    /// Leave the feature out of the output
+    Drop,
+    /// Fail the scrape
+    Fail,
+}
+
+impl GeometryPolicy {
+    /// What happens to a mismatched geometry, to complete "geometries were ...".
+    pub(crate) fn outcome(&self) -> &'static str {
+        match self {
+            GeometryPolicy::Keep => "written as sent",
+            GeometryPolicy::Coerce => "coerced to the layer's type or removed",
+            GeometryPolicy::Drop => "dropped with their features",
+            GeometryPolicy::Fail => "rejected",
+        }
+    }
+}
+
+impl Display for GeometryPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GeometryPolicy::Keep => write!(f, "keep"),
+            GeometryPolicy::Coerce => write!(f, "coerce"),
+            GeometryPolicy::Drop => write!(f, "drop"),
+            GeometryPolicy::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum GeometryMismatchError {
+    /// Declared geometry type and the geometry sent
+    Mismatch(RestServiceGeometryType, String),
+}
+
+impl Display for GeometryMismatchError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GeometryMismatchError::Mismatch(geo_type, geometry) => write!(
+                f,
+                "Layer declares {} but a feature has the geometry {} (--geometry-mismatch fail)",
+                geo_type,
+                geometry,
+            ),
+        }
+    }
+}
+
+impl Error for GeometryMismatchError {}
+
+/// Shape of a geometry sent by the server, told apart by its keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum GeometryShape {
+    Point,
+    Multipoint,
+    Polyline,
+    Polygon,
+    Envelope,
+    /// Geometry object without any coordinates (e.g. `{}`, NaN coordinates or no rings)
+    Empty,
+}
+
+impl Display for GeometryShape {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GeometryShape::Point => write!(f, "point"),
+            GeometryShape::Multipoint => write!(f, "multipoint"),
+            GeometryShape::Polyline => write!(f, "polyline"),
+            GeometryShape::Polygon => write!(f, "polygon"),
+            GeometryShape::Envelope => write!(f, "envelope"),
+            GeometryShape::Empty => write!(f, "empty"),
+        }
+    }
+}
+
+impl GeometryShape {
+    fn of(geometry: &Map<String, Value>) -> Self {
+        let has_parts = |key: &str| geometry.get(key)
+            .and_then(Value::as_array)
+            .is_some_and(|parts| !parts.is_empty());
+        let is_number = |key: &str| geometry.get(key).and_then(Value::as_f64).is_some_and(f64::is_finite);
+        if is_number("x") && is_number("y") {
+            GeometryShape::Point
+        } else if has_parts("points") {
+            GeometryShape::Multipoint
+        } else if has_parts("paths") {
+            GeometryShape::Polyline
+        } else if has_parts("rings") {
+            GeometryShape::Polygon
+        } else if ["xmin", "ymin", "xmax", "ymax"].into_iter().all(is_number) {
+            GeometryShape::Envelope
+        } else {
+            GeometryShape::Empty
+        }
+    }
+
+    fn matches(&self, geo_type: &RestServiceGeometryType) -> bool {
+        matches!(
+            (self, geo_type),
+            (GeometryShape::Point, RestServiceGeometryType::Point)
+                | (GeometryShape::Multipoint, RestServiceGeometryType::Multipoint)
+                | (GeometryShape::Polyline, RestServiceGeometryType::Polyline)
+                | (GeometryShape::Polygon, RestServiceGeometryType::Polygon)
+                | (GeometryShape::Envelope, RestServiceGeometryType::Envelope)
+        )
+    }
+}
+
+/// Geometry of `shape` converted to `geo_type`, None when it cannot be without losing parts.
+fn coerce(geometry: &Map<String, Value>, shape: GeometryShape, geo_type: &RestServiceGeometryType) -> Option<Value> {
+    let single_point = || match geometry.get("points").and_then(Value::as_array).map(Vec::as_slice) {
+        Some([point]) => Some(point.to_owned()),
+        _ => None,
+    };
+    let envelope_ring = || json!([[
+        [geometry["xmin"], geometry["ymin"]],
+        [geometry["xmin"], geometry["ymax"]],
+        [geometry["xmax"], geometry["ymax"]],
+        [geometry["xmax"], geometry["ymin"]],
+        [geometry["xmin"], geometry["ymin"]],
+    ]]);
+    let coerced = match (shape, geo_type) {
+        (GeometryShape::Point, RestServiceGeometryType::Multipoint) => {
+            json!({"points": [[geometry["x"], geometry["y"]]]})
+        }
+        (GeometryShape::Multipoint, RestServiceGeometryType::Point) => {
+            let point = single_point()?;
+            json!({"x": point.get(0)?, "y": point.get(1)?})
+        }
+        (GeometryShape::Polygon, RestServiceGeometryType::Polyline) => json!({"paths": geometry["rings"]}),
+        (GeometryShape::Envelope, RestServiceGeometryType::Polygon) => json!({"rings": envelope_ring()}),
+        (GeometryShape::Envelope, RestServiceGeometryType::Polyline) => json!({"paths": envelope_ring()}),
+        _ => return None,
+    };
+    let mut coerced = coerced.as_object()?.to_owned();
+    if let Some(spatial_reference) = geometry.get("spatialReference") {
+        coerced.insert("spatialReference".to_owned(), spatial_reference.to_owned());
+    }
+    Some(Value::Object(coerced))
+}
+
+/// Features of a layer whose geometry did not match its geometry type, by shape sent.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct GeometryMismatches {
+    /// Mismatched and coerced counts of each shape
+    shapes: BTreeMap<GeometryShape, (usize, usize)>,
+    dropped: usize,
+}
+
+#[derive(Clone)]
+struct GeometryMismatchRow {
+    shape: String,
+    features: usize,
+    coerced: usize,
+}
+
+impl GeometryMismatches {
+    pub(crate) fn merge(&mut self, other: &GeometryMismatches) {
+        for (shape, (features, coerced)) in &other.shapes {
+            let counts = self.shapes.entry(*shape).or_default();
+            counts.0 += features;
+            counts.1 += coerced;
+        }
+        self.dropped += other.dropped;
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.shapes.is_empty()
+    }
+
+    /// Mismatched geometries of every shape.
+    pub(crate) fn count(&self) -> usize {
+        self.shapes.values().map(|(features, _)| features).sum()
+    }
+
+    /// Features left out of the output by the drop policy.
+    pub(crate) fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Summary of the mismatched geometries of a layer and what was done with them.
+    pub(crate) fn write_to_console(
+        &self,
+        layer: &str,
+        geo_type: &RestServiceGeometryType,
+        policy: &GeometryPolicy,
+    ) -> io::Result<()> {
+        println!("Geometries not matching {} in {} (--geometry-mismatch {})", geo_type, layer, policy);
+        let mut out = io::stdout();
+        let mut stream = Stream::new(
+            &mut out,
+            vec![
+                col!(GeometryMismatchRow: .shape).header("Geometry Sent"),
+                col!(GeometryMismatchRow: .features).header("Features"),
+                col!(GeometryMismatchRow: .coerced).header("Coerced"),
+            ],
+        );
+        for (shape, (features, coerced)) in &self.shapes {
+            stream.row(GeometryMismatchRow {
+                shape: shape.to_string(),
+                features: *features,
+                coerced: *coerced,
+            })?;
+        }
+        stream.finish()?;
+        out.flush()
+    }
+}
+
+/// Checks that the geometry of every feature matches the layer's declared geometry type, which
+/// strict schema sinks rely on, and applies the policy. Features without a geometry are not
+/// mismatches.
+#[derive(Debug, Clone)]
+pub(crate) struct GeometryGuard {
+    geo_type: RestServiceGeometryType,
+    policy: GeometryPolicy,
+}
+
+impl GeometryGuard {
+    pub(crate) fn new(geo_type: &RestServiceGeometryType, policy: GeometryPolicy) -> Self {
+        Self { geo_type: geo_type.to_owned(), policy }
+    }
+
+    pub(crate) fn policy(&self) -> &GeometryPolicy {
+        &self.policy
+    }
+
+    /// Check the geometry of a feature, returning false when the feature is to be dropped.
+    pub(crate) fn apply(
+        &self,
+        feature: &mut Map<String, Value>,
+        mismatches: &mut GeometryMismatches,
+    ) -> Result<bool, GeometryMismatchError> {
+        if self.geo_type == RestServiceGeometryType::None {
+            return Ok(true)
+        }
+        let geometry = match feature.get("geometry").and_then(Value::as_object) {
+            Some(geometry) => geometry,
+            None => return Ok(true),
+        };
+        let shape = GeometryShape::of(geometry);
+        if shape.matches(&self.geo_type) {
+            return Ok(true)
+        }
+        let counts = mismatches.shapes.entry(shape).or_default();
+        counts.0 += 1;
+        match self.policy {
+            GeometryPolicy::Keep => {}
+            GeometryPolicy::Coerce => match coerce(geometry, shape, &self.geo_type) {
+                Some(coerced) => {
+                    counts.1 += 1;
+                    feature.insert("geometry".to_owned(), coerced);
+                }
+                None => {
+                    feature.insert("geometry".to_owned(), Value::Null);
+                }
+            },
+            GeometryPolicy::Drop => {
+                mismatches.dropped += 1;
+                return Ok(false)
+            }
+            GeometryPolicy::Fail => {
+                return Err(GeometryMismatchError::Mismatch(
+                    self.geo_type.to_owned(),
+                    Value::Object(geometry.to_owned()).to_string(),
+                ))
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod geometry_guard_tests {
+    use serde_json::{json, Map, Value};
+    use crate::metadata::RestServiceGeometryType;
+    use super::{GeometryGuard, GeometryMismatches, GeometryPolicy};
+
+    fn feature(geometry: Value) -> Map<String, Value> {
+        json!({"attributes": {"OBJECTID": 1}, "geometry": geometry}).as_object().unwrap().to_owned()
+    }
+
+    #[test]
+    fn apply_should_coerce_single_point_multipoint_when_layer_is_point() {
+        let guard = GeometryGuard::new(&RestServiceGeometryType::Point, GeometryPolicy::Coerce);
+        let mut mismatches = GeometryMismatches::default();
+        let mut single = feature(json!({"points": [[1.5, 2.5]]}));
+        let mut several = feature(json!({"points": [[1, 2], [3, 4]]}));
+        let mut point = feature(json!({"x": 1, "y": 2}));
+        assert_eq!(guard.apply(&mut single, &mut mismatches), Ok(true));
+        assert_eq!(guard.apply(&mut several, &mut mismatches), Ok(true));
+        assert_eq!(guard.apply(&mut point, &mut mismatches), Ok(true));
+        assert_eq!(single["geometry"], json!({"x": 1.5, "y": 2.5}));
+        assert_eq!(several["geometry"], Value::Null);
+        assert_eq!(mismatches.count(), 2);
+    }
+
+    #[test]
+    fn apply_should_drop_feature_when_geometry_is_empty_and_policy_is_drop() {
+        let guard = GeometryGuard::new(&RestServiceGeometryType::Polygon, GeometryPolicy::Drop);
+        let mut mismatches = GeometryMismatches::default();
+        assert_eq!(guard.apply(&mut feature(json!({"rings": []})), &mut mismatches), Ok(false));
+        assert_eq!(guard.apply(&mut feature(Value::Null), &mut mismatches), Ok(true));
+        assert_eq!(mismatches.dropped(), 1);
+    }
+
+    #[test]
+    fn apply_should_fail_when_policy_is_fail() {
+        let guard = GeometryGuard::new(&RestServiceGeometryType::Polyline, GeometryPolicy::Fail);
+        let mut mismatches = GeometryMismatches::default();
+        assert!(guard.apply(&mut feature(json!({"x": 1, "y": 2})), &mut mismatches).is_err());
+    }
+}
@@ -0,0 +1,656 @@
+#[cfg(feature = "parquet")]
+use std::collections::BTreeSet;
+use std::io;
+use std::path::Path;
+#[cfg(feature = "parquet")]
+use std::sync::Arc;
+#[cfg(feature = "parquet")]
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+#[cfg(feature = "parquet")]
+use parquet::arrow::ArrowWriter;
+#[cfg(feature = "parquet")]
+use parquet::errors::ParquetError;
+use serde_json::{Map, Value};
+#[cfg(not(feature = "parquet"))]
+use crate::capability::{Capability, CapabilityError};
+#[cfg(feature = "parquet")]
+use crate::checksum::ChecksumFile;
+use crate::checksum::Artifact;
+#[cfg(feature = "parquet")]
+use crate::geometry::esri_parts;
+#[cfg(feature = "parquet")]
+use crate::metadata::{LayerDescription, RestServiceFieldType, RestServiceGeometryType};
+use crate::metadata::RestServiceMetadata;
+#[cfg(feature = "parquet")]
+use crate::projection;
+#[cfg(feature = "parquet")]
+use crate::wkt::{group_rings, Ring};
+
+/// Column holding the WKB geometry of each feature.
+#[cfg(feature = "parquet")]
+const GEOMETRY_COLUMN: &str = "geometry";
+
+/// PROJJSON of Web Mercator (EPSG:3857), written as the crs of its geometries. Other projected
+/// spatial references would need a CRS database to be described.
+#[cfg(feature = "parquet")]
+const WEB_MERCATOR_PROJJSON: &str = r#"{
+  "type": "ProjectedCRS",
+  "name": "WGS 84 / Pseudo-Mercator",
+  "base_crs": {
+    "type": "GeographicCRS",
+    "name": "WGS 84",
+    "datum": {
+      "type": "GeodeticReferenceFrame",
+      "name": "World Geodetic System 1984",
+      "ellipsoid": {"name": "WGS 84", "semi_major_axis": 6378137, "inverse_flattening": 298.257223563}
+    },
+    "coordinate_system": {
+      "subtype": "ellipsoidal",
+      "axis": [
+        {"name": "Geodetic latitude", "abbreviation": "Lat", "direction": "north", "unit": "degree"},
+        {"name": "Geodetic longitude", "abbreviation": "Lon", "direction": "east", "unit": "degree"}
+      ]
+    },
+    "id": {"authority": "EPSG", "code": 4326}
+  },
+  "conversion": {
+    "name": "Popular Visualisation Pseudo-Mercator",
+    "method": {"name": "Popular Visualisation Pseudo Mercator", "id": {"authority": "EPSG", "code": 1024}},
+    "parameters": [
+      {"name": "Latitude of natural origin", "value": 0, "unit": "degree", "id": {"authority": "EPSG", "code": 8801}},
+      {"name": "Longitude of natural origin", "value": 0, "unit": "degree", "id": {"authority": "EPSG", "code": 8802}},
+      {"name": "False easting", "value": 0, "unit": "metre", "id": {"authority": "EPSG", "code": 8806}},
+      {"name": "False northing", "value": 0, "unit": "metre", "id": {"authority": "EPSG", "code": 8807}}
+    ]
+  },
+  "coordinate_system": {
+    "subtype": "Cartesian",
+    "axis": [
+      {"name": "Easting", "abbreviation": "X", "direction": "east", "unit": "metre"},
+      {"name": "Northing", "abbreviation": "Y", "direction": "north", "unit": "metre"}
+    ]
+  },
+  "id": {"authority": "EPSG", "code": 3857}
+}"#;
+
+/// WKB geometry type codes.
+#[cfg(feature = "parquet")]
+const WKB_POINT: u32 = 1;
+#[cfg(feature = "parquet")]
+const WKB_LINESTRING: u32 = 2;
+#[cfg(feature = "parquet")]
+const WKB_POLYGON: u32 = 3;
+#[cfg(feature = "parquet")]
+const WKB_MULTIPOINT: u32 = 4;
+#[cfg(feature = "parquet")]
+const WKB_MULTILINESTRING: u32 = 5;
+#[cfg(feature = "parquet")]
+const WKB_MULTIPOLYGON: u32 = 6;
+
+/// Little endian WKB, written one geometry at a time.
+#[cfg(feature = "parquet")]
+struct WkbWriter(Vec<u8>);
+
+#[cfg(feature = "parquet")]
+impl WkbWriter {
+    fn header(&mut self, geometry_type: u32) {
+        self.0.push(1);
+        self.0.extend(geometry_type.to_le_bytes());
+    }
+
+    fn count(&mut self, count: usize) {
+        self.0.extend((count as u32).to_le_bytes());
+    }
+
+    fn position(&mut self, point: &(f64, f64)) {
+        self.0.extend(point.0.to_le_bytes());
+        self.0.extend(point.1.to_le_bytes());
+    }
+
+    fn point(&mut self, point: &(f64, f64)) {
+        self.header(WKB_POINT);
+        self.position(point);
+    }
+
+    fn line_string(&mut self, path: &[(f64, f64)]) {
+        self.header(WKB_LINESTRING);
+        self.count(path.len());
+        path.iter().for_each(|point| self.position(point));
+    }
+
+    fn polygon(&mut self, polygon: &[Ring]) {
+        self.header(WKB_POLYGON);
+        self.count(polygon.len());
+        for ring in polygon {
+            self.count(ring.len());
+            ring.iter().for_each(|point| self.position(point));
+        }
+    }
+}
+
+/// WKB of an Esri JSON geometry, ignoring z and m values, with rings grouped into polygons as
+/// for WKT. Features without a geometry get no value, geometries without coordinates an empty
+/// one.
+#[cfg(feature = "parquet")]
+fn esri_to_wkb(geo_type: &RestServiceGeometryType, feature: &Map<String, Value>) -> Option<Vec<u8>> {
+    let geometry = feature.get("geometry").and_then(Value::as_object)?;
+    let coordinate = |key: &str| geometry.get(key).and_then(Value::as_f64);
+    let mut wkb = WkbWriter(Vec::new());
+    match geo_type {
+        RestServiceGeometryType::Point => {
+            // WKB has no empty point, it is written with NaN coordinates instead
+            let point = coordinate("x").zip(coordinate("y")).unwrap_or((f64::NAN, f64::NAN));
+            wkb.point(&point);
+        }
+        RestServiceGeometryType::Multipoint => {
+            let points: Vec<(f64, f64)> = esri_parts(geometry, "points").into_iter().flatten().collect();
+            wkb.header(WKB_MULTIPOINT);
+            wkb.count(points.len());
+            points.iter().for_each(|point| wkb.point(point));
+        }
+        RestServiceGeometryType::Polyline => {
+            let paths: Vec<Ring> = esri_parts(geometry, "paths")
+                .into_iter()
+                .filter(|path| path.len() >= 2)
+                .collect();
+            match paths.as_slice() {
+                [] => wkb.line_string(&[]),
+                [path] => wkb.line_string(path),
+                paths => {
+                    wkb.header(WKB_MULTILINESTRING);
+                    wkb.count(paths.len());
+                    paths.iter().for_each(|path| wkb.line_string(path));
+                }
+            }
+        }
+        RestServiceGeometryType::Polygon => {
+            match group_rings(esri_parts(geometry, "rings")).as_slice() {
+                [] => wkb.polygon(&[]),
+                [polygon] => wkb.polygon(polygon),
+                polygons => {
+                    wkb.header(WKB_MULTIPOLYGON);
+                    wkb.count(polygons.len());
+                    polygons.iter().for_each(|polygon| wkb.polygon(polygon));
+                }
+            }
+        }
+        RestServiceGeometryType::Envelope => {
+            match (coordinate("xmin"), coordinate("ymin"), coordinate("xmax"), coordinate("ymax")) {
+                (Some(x_min), Some(y_min), Some(x_max), Some(y_max)) => wkb.polygon(&[vec![
+                    (x_min, y_min), (x_max, y_min), (x_max, y_max), (x_min, y_max), (x_min, y_min),
+                ]]),
+                _ => wkb.polygon(&[]),
+            }
+        }
+        RestServiceGeometryType::None => return None,
+    }
+    Some(wkb.0)
+}
+
+/// GeoJSON name of the type of a WKB geometry, as listed in the GeoParquet metadata.
+#[cfg(feature = "parquet")]
+fn wkb_type_name(wkb: &[u8]) -> Option<&'static str> {
+    let geometry_type = u32::from_le_bytes(wkb.get(1..5)?.try_into().ok()?);
+    match geometry_type {
+        WKB_POINT => Some("Point"),
+        WKB_LINESTRING => Some("LineString"),
+        WKB_POLYGON => Some("Polygon"),
+        WKB_MULTIPOINT => Some("MultiPoint"),
+        WKB_MULTILINESTRING => Some("MultiLineString"),
+        WKB_MULTIPOLYGON => Some("MultiPolygon"),
+        _ => None,
+    }
+}
+
+/// Parquet type of a column, from the type of its field.
+#[cfg(feature = "parquet")]
+fn column_type(field_type: &RestServiceFieldType) -> DataType {
+    match field_type {
+        RestServiceFieldType::OID => DataType::Int64,
+        RestServiceFieldType::Integer => DataType::Int32,
+        RestServiceFieldType::SmallInteger => DataType::Int16,
+        RestServiceFieldType::Double => DataType::Float64,
+        RestServiceFieldType::Single | RestServiceFieldType::Float => DataType::Float32,
+        // Dates are sent as milliseconds since the epoch
+        RestServiceFieldType::Date => DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+        _ => DataType::Utf8,
+    }
+}
+
+/// Typed columns of a layer's GeoParquet file, taken from the record columns with the geometry
+/// columns replaced by a single WKB column.
+#[cfg(feature = "parquet")]
+#[derive(Debug, Clone)]
+pub(crate) struct ParquetLayout {
+    schema: SchemaRef,
+    /// Position in the records of the values of each column but the geometry
+    indexes: Vec<usize>,
+    geo_type: RestServiceGeometryType,
+    /// Wkid of the geometries, when known. Web Mercator aliases are 3857
+    srid: Option<i64>,
+    /// Description, copyright text and field aliases of the layer
+    description: LayerDescription,
+}
+
+#[cfg(feature = "parquet")]
+impl ParquetLayout {
+    /// Layout of the records of `layer` with `columns`. Columns that are not fields of the layer
+    /// (code descriptions, provenance, cells) are text, except the measure column.
+    pub(crate) fn new(layer: &RestServiceMetadata, columns: &[String], measure_column: Option<&str>) -> Self {
+        let (indexes, mut fields): (Vec<usize>, Vec<Field>) = columns.iter()
+            .enumerate()
+            .filter_map(|(index, name)| {
+                let field = layer.fields.iter().find(|field| field.name == *name);
+                let data_type = match field {
+                    Some(field) if field.field_type == RestServiceFieldType::Geometry => return None,
+                    Some(field) => column_type(&field.field_type),
+                    None if measure_column == Some(name.as_str()) => DataType::Float64,
+                    None => DataType::Utf8,
+                };
+                Some((index, Field::new(name, data_type, true)))
+            })
+            .unzip();
+        if layer.geo_type != RestServiceGeometryType::None {
+            fields.push(Field::new(GEOMETRY_COLUMN, DataType::Binary, true));
+        }
+        let srid = layer.output_spatial_reference()
+            .map(|wkid| if projection::same_spatial_reference(wkid, 3857) { 3857 } else { wkid });
+        Self {
+            schema: Arc::new(Schema::new(fields)),
+            indexes,
+            geo_type: layer.geo_type.clone(),
+            srid,
+            description: layer.layer_description(),
+        }
+    }
+
+    fn has_geometry(&self) -> bool {
+        self.geo_type != RestServiceGeometryType::None
+    }
+
+    /// Whether the crs of the geometries is written, readers taking geometries with a null crs
+    /// as having an undefined one. Only WGS 84 and Web Mercator have a PROJJSON definition.
+    pub(crate) fn has_crs(&self) -> bool {
+        !self.has_geometry() || matches!(self.srid, Some(4326 | 3857))
+    }
+
+    /// Keep the record of a feature and its WKB geometry for the chunk's rows.
+    pub(crate) fn push(&self, rows: &mut ParquetRows, record: &[String], feature: &Map<String, Value>) {
+        rows.records.push(record.to_vec());
+        if self.has_geometry() {
+            rows.geometries.push(esri_to_wkb(&self.geo_type, feature));
+        }
+    }
+
+    /// GeoParquet file metadata of the geometry column, listing the geometry types written.
+    fn geo_metadata(&self, geometry_types: &BTreeSet<&'static str>) -> Value {
+        let mut column = serde_json::json!({
+            "encoding": "WKB",
+            "geometry_types": geometry_types,
+        });
+        match self.srid {
+            // Readers take geometries without a crs as OGC:CRS84, the longitude/latitude order
+            // of Esri's 4326 geometries
+            Some(4326) => {}
+            Some(3857) => {
+                column["crs"] = serde_json::from_str(WEB_MERCATOR_PROJJSON).expect("Web Mercator PROJJSON is valid JSON");
+            }
+            _ => column["crs"] = Value::Null,
+        }
+        serde_json::json!({
+            "version": "1.0.0",
+            "primary_column": GEOMETRY_COLUMN,
+            "columns": {GEOMETRY_COLUMN: column},
+        })
+    }
+
+    /// File metadata describing the layer: its description and copyright text when it has them,
+    /// and the alias of each field as a JSON object, since Parquet columns have no alias.
+    fn layer_metadata(&self) -> Vec<(&'static str, String)> {
+        let aliases: Map<String, Value> = self.description.fields.iter()
+            .map(|field| (field.name.to_owned(), Value::from(field.alias.as_str())))
+            .collect();
+        [
+            ("description", self.description.description.to_owned()),
+            ("copyright", self.description.copyright_text.to_owned()),
+            ("field_aliases", Some(Value::Object(aliases).to_string())),
+        ].into_iter()
+            .filter_map(|(key, value)| Some((key, value?)))
+            .collect()
+    }
+}
+
+/// Records and WKB geometries of a chunk's features, converted to a row group once the chunk is
+/// accepted.
+#[cfg(feature = "parquet")]
+#[derive(Debug, Default)]
+pub(crate) struct ParquetRows {
+    records: Vec<Vec<String>>,
+    geometries: Vec<Option<Vec<u8>>>,
+}
+
+#[cfg(feature = "parquet")]
+impl ParquetRows {
+    pub(crate) fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Drop the rows after the first `len`, e.g. of a response thrown away.
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.records.truncate(len);
+        self.geometries.truncate(len);
+    }
+}
+
+/// Integer value, from a number written with or without a fraction.
+#[cfg(feature = "parquet")]
+fn integer(value: Option<&str>) -> Option<i64> {
+    let value = value?;
+    value.parse::<i64>()
+        .ok()
+        .or_else(|| value.parse::<f64>().ok().filter(|number| number.fract() == 0.0).map(|number| number as i64))
+}
+
+#[cfg(feature = "parquet")]
+fn parquet_error(error: ParquetError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Writes the rows of a layer's chunks into a GeoParquet file, a row group per chunk.
+#[cfg(feature = "parquet")]
+pub(crate) struct GeoParquetWriter {
+    layout: ParquetLayout,
+    writer: ArrowWriter<ChecksumFile>,
+    geometry_types: BTreeSet<&'static str>,
+}
+
+#[cfg(feature = "parquet")]
+impl GeoParquetWriter {
+    pub(crate) fn create(path: &Path, layout: ParquetLayout) -> io::Result<Self> {
+        use parquet::basic::Compression;
+        use parquet::file::properties::WriterProperties;
+
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let writer = ArrowWriter::try_new(ChecksumFile::create(path)?, layout.schema.clone(), Some(properties))
+            .map_err(parquet_error)?;
+        Ok(Self { layout, writer, geometry_types: BTreeSet::new() })
+    }
+
+    /// Append the rows of a chunk as a row group. Values that do not parse as the type of their
+    /// column are written as nulls.
+    pub(crate) fn write(&mut self, rows: ParquetRows) -> io::Result<()> {
+        use arrow_array::{
+            ArrayRef, BinaryArray, Float32Array, Float64Array, Int16Array, Int32Array, Int64Array,
+            RecordBatch, StringArray, TimestampMillisecondArray,
+        };
+
+        if rows.records.is_empty() {
+            return Ok(())
+        }
+        let mut arrays: Vec<ArrayRef> = self.layout.schema.fields()
+            .iter()
+            .zip(&self.layout.indexes)
+            .map(|(field, index)| {
+                let values = rows.records.iter()
+                    .map(|record| record.get(*index).map(String::as_str).filter(|value| !value.is_empty()));
+                let array: ArrayRef = match field.data_type() {
+                    DataType::Int16 => Arc::new(values.map(|value| integer(value)?.try_into().ok()).collect::<Int16Array>()),
+                    DataType::Int32 => Arc::new(values.map(|value| integer(value)?.try_into().ok()).collect::<Int32Array>()),
+                    DataType::Int64 => Arc::new(values.map(integer).collect::<Int64Array>()),
+                    DataType::Float32 => Arc::new(values.map(|value| value?.parse().ok()).collect::<Float32Array>()),
+                    DataType::Float64 => Arc::new(values.map(|value| value?.parse().ok()).collect::<Float64Array>()),
+                    DataType::Timestamp(_, _) => Arc::new(
+                        values.map(integer).collect::<TimestampMillisecondArray>().with_timezone("UTC")
+                    ),
+                    _ => Arc::new(values.collect::<StringArray>()),
+                };
+                array
+            })
+            .collect();
+        if self.layout.has_geometry() {
+            self.geometry_types.extend(rows.geometries.iter().flatten().filter_map(|wkb| wkb_type_name(wkb)));
+            arrays.push(Arc::new(rows.geometries.iter().map(Option::as_deref).collect::<BinaryArray>()));
+        }
+        let batch = RecordBatch::try_new(self.layout.schema.clone(), arrays)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        self.writer.write(&batch).map_err(parquet_error)?;
+        // A row group per chunk, so rows are not held in memory across chunks
+        self.writer.flush().map_err(parquet_error)
+    }
+
+    /// Write the GeoParquet and layer metadata and the footer.
+    pub(crate) fn finish(mut self) -> io::Result<Artifact> {
+        use parquet::format::KeyValue;
+
+        if self.layout.has_geometry() {
+            let metadata = self.layout.geo_metadata(&self.geometry_types);
+            self.writer.append_key_value_metadata(KeyValue::new("geo".to_owned(), metadata.to_string()));
+        }
+        for (key, value) in self.layout.layer_metadata() {
+            self.writer.append_key_value_metadata(KeyValue::new(key.to_owned(), value));
+        }
+        self.writer.into_inner().map_err(parquet_error)?.finish()
+    }
+}
+
+/// Stands in for the layout when GeoParquet outputs are left out of the build, keeping no rows.
+#[cfg(not(feature = "parquet"))]
+#[derive(Debug, Clone)]
+pub(crate) struct ParquetLayout;
+
+#[cfg(not(feature = "parquet"))]
+impl ParquetLayout {
+    pub(crate) fn new(_layer: &RestServiceMetadata, _columns: &[String], _measure_column: Option<&str>) -> Self {
+        ParquetLayout
+    }
+
+    pub(crate) fn push(&self, _rows: &mut ParquetRows, _record: &[String], _feature: &Map<String, Value>) {}
+
+    pub(crate) fn has_crs(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(not(feature = "parquet"))]
+#[derive(Debug, Default)]
+pub(crate) struct ParquetRows {}
+
+#[cfg(not(feature = "parquet"))]
+impl ParquetRows {
+    pub(crate) fn len(&self) -> usize {
+        0
+    }
+
+    pub(crate) fn truncate(&mut self, _len: usize) {}
+}
+
+/// Cannot be created when GeoParquet outputs are left out of the build.
+#[cfg(not(feature = "parquet"))]
+pub(crate) enum GeoParquetWriter {}
+
+#[cfg(not(feature = "parquet"))]
+impl GeoParquetWriter {
+    pub(crate) fn create(_path: &Path, _layout: ParquetLayout) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, CapabilityError::Disabled(Capability::Parquet)))
+    }
+
+    pub(crate) fn write(&mut self, _rows: ParquetRows) -> io::Result<()> {
+        match *self {}
+    }
+
+    pub(crate) fn finish(self) -> io::Result<Artifact> {
+        match self {}
+    }
+}
+
+#[cfg(all(test, feature = "parquet"))]
+mod geoparquet_tests {
+    use std::collections::BTreeSet;
+    use std::fs::File;
+    use std::sync::Arc;
+    use arrow_array::{Array, BinaryArray, Int16Array, Int64Array, StringArray, TimestampMillisecondArray};
+    use arrow_schema::{DataType, TimeUnit};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use serde_json::{json, Value};
+    use crate::audit::{user_agent, RunAudit, RunId, RunIdPlacement};
+    use crate::auth::AnonymousAuth;
+    use crate::client::ServiceClient;
+    use crate::config::ScrapeConfig;
+    use crate::filter::QueryFilter;
+    use crate::metadata::parse_metadata;
+    use crate::scraping::output_columns;
+    use crate::transform::{transformed_field_types, FeatureTransformer};
+    use crate::wkt::wkt_fields;
+    use super::{GeoParquetWriter, ParquetLayout, ParquetRows};
+
+    #[test]
+    fn finish_should_write_typed_columns_and_geo_metadata_when_passed_rows() {
+        let client = ServiceClient::new(
+            Arc::new(AnonymousAuth),
+            RunAudit { run_id: RunId::generate(), placement: RunIdPlacement::Header, user_agent: user_agent(None) },
+        );
+        let metadata = json!({
+            "name": "Parcels",
+            "type": "Feature Layer",
+            "maxRecordCount": 1000,
+            "geometryType": "esriGeometryPolygon",
+            "fields": [
+                {"name": "OBJECTID", "type": "esriFieldTypeOID", "alias": "OBJECTID"},
+                {
+                    "name": "Order",
+                    "type": "esriFieldTypeSmallInteger",
+                    "alias": "Order",
+                    "domain": {"type": "codedValue", "codedValues": [{"code": 1, "name": "First"}]},
+                },
+                {"name": "EDITED", "type": "esriFieldTypeDate", "alias": "Edited"},
+            ],
+            "sourceSpatialReference": {"wkid": 102100},
+            "advancedQueryCapabilities": {"supportsPagination": true},
+            "description": "Tax parcels",
+            "copyrightText": "County Assessor",
+        });
+        let url = "https://example.com/arcgis/rest/services/Parcels/FeatureServer/0";
+        let mut layer = parse_metadata(&client, url, &metadata, None, &QueryFilter::default()).unwrap();
+        layer.fields = wkt_fields(layer.fields);
+        let columns = output_columns(&layer.fields, false, None, None, false);
+        let layout = ParquetLayout::new(&layer, &columns, None);
+        let exterior = json!([[0, 0], [0, 10], [10, 10], [10, 0], [0, 0]]);
+        let island = json!([[20, 20], [20, 21], [21, 21], [21, 20], [20, 20]]);
+        let mut rows = ParquetRows::default();
+        for (record, geometry) in [
+            (["1", "1", "First", "1700000000000", ""], json!({"rings": [exterior, island]})),
+            (["2", "x", "", "", ""], Value::Null),
+        ] {
+            let record: Vec<String> = record.iter().map(|value| value.to_string()).collect();
+            let feature = json!({"attributes": {}, "geometry": geometry});
+            layout.push(&mut rows, &record, feature.as_object().unwrap());
+        }
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("Parcels.parquet");
+        let mut writer = GeoParquetWriter::create(&path, layout).unwrap();
+        writer.write(rows).unwrap();
+        writer.finish().unwrap();
+
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap()).unwrap();
+        let key_value = |key: &str| builder.metadata().file_metadata().key_value_metadata().unwrap()
+            .iter()
+            .find(|entry| entry.key == key)
+            .and_then(|entry| entry.value.clone());
+        assert_eq!(key_value("description").as_deref(), Some("Tax parcels"));
+        assert_eq!(key_value("copyright").as_deref(), Some("County Assessor"));
+        let aliases: Value = serde_json::from_str(&key_value("field_aliases").unwrap()).unwrap();
+        assert_eq!(aliases, json!({"OBJECTID": "OBJECTID", "Order": "Order", "EDITED": "Edited"}));
+        let geo: Value = serde_json::from_str(&key_value("geo").unwrap()).unwrap();
+        assert_eq!(geo["primary_column"], "geometry");
+        assert_eq!(geo["columns"]["geometry"]["geometry_types"], json!(["MultiPolygon"]));
+        assert_eq!(geo["columns"]["geometry"]["crs"]["type"], "ProjectedCRS");
+        assert_eq!(geo["columns"]["geometry"]["crs"]["id"], json!({"authority": "EPSG", "code": 3857}));
+        let batch = builder.build().unwrap().next().unwrap().unwrap();
+        let schema = batch.schema();
+        let names: Vec<&str> = schema.fields().iter().map(|field| field.name().as_str()).collect();
+        assert_eq!(names, ["OBJECTID", "Order", "Order_DESC", "EDITED", "geometry"]);
+        assert_eq!(schema.field(3).data_type(), &DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())));
+        let object_ids = batch.column(0).as_any().downcast_ref::<Int64Array>().unwrap();
+        assert_eq!(object_ids.values(), &[1, 2]);
+        let orders = batch.column(1).as_any().downcast_ref::<Int16Array>().unwrap();
+        assert_eq!((orders.value(0), orders.is_null(1)), (1, true));
+        let descriptions = batch.column(2).as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!((descriptions.value(0), descriptions.is_null(1)), ("First", true));
+        let edited = batch.column(3).as_any().downcast_ref::<TimestampMillisecondArray>().unwrap();
+        assert_eq!(edited.value(0), 1_700_000_000_000);
+        let geometries = batch.column(4).as_any().downcast_ref::<BinaryArray>().unwrap();
+        assert_eq!(&geometries.value(0)[..9], &[1, 6, 0, 0, 0, 2, 0, 0, 0]);
+        assert!(geometries.is_null(1));
+    }
+
+    #[test]
+    fn geo_metadata_should_leave_crs_undefined_when_spatial_reference_has_no_projjson() {
+        let client = ServiceClient::new(
+            Arc::new(AnonymousAuth),
+            RunAudit { run_id: RunId::generate(), placement: RunIdPlacement::Header, user_agent: user_agent(None) },
+        );
+        let metadata = json!({
+            "name": "Parcels",
+            "type": "Feature Layer",
+            "maxRecordCount": 1000,
+            "geometryType": "esriGeometryPoint",
+            "fields": [{"name": "OBJECTID", "type": "esriFieldTypeOID", "alias": "OBJECTID"}],
+            "sourceSpatialReference": {"wkid": 2913},
+        });
+        let url = "https://example.com/arcgis/rest/services/Parcels/FeatureServer/0";
+        let geometry_types = BTreeSet::from(["Point"]);
+        for (output_spatial_reference, has_crs) in [(None, false), (Some(102719), false), (Some(4326), true)] {
+            let layer = parse_metadata(&client, url, &metadata, output_spatial_reference, &QueryFilter::default()).unwrap();
+            let columns = output_columns(&layer.fields, false, None, None, false);
+            let layout = ParquetLayout::new(&layer, &columns, None);
+            let geo = layout.geo_metadata(&geometry_types);
+            assert_eq!(layout.has_crs(), has_crs);
+            assert_eq!(geo["columns"]["geometry"].get("crs"), if has_crs { None } else { Some(&Value::Null) });
+        }
+    }
+
+    #[test]
+    fn new_should_type_hashed_integer_field_as_text_when_fields_are_transformed() {
+        let client = ServiceClient::new(
+            Arc::new(AnonymousAuth),
+            RunAudit { run_id: RunId::generate(), placement: RunIdPlacement::Header, user_agent: user_agent(None) },
+        );
+        let metadata = json!({
+            "name": "Parcels",
+            "type": "Feature Layer",
+            "maxRecordCount": 1000,
+            "geometryType": "esriGeometryPoint",
+            "fields": [
+                {"name": "OBJECTID", "type": "esriFieldTypeOID", "alias": "OBJECTID"},
+                {"name": "PARCEL_NO", "type": "esriFieldTypeInteger", "alias": "Parcel"},
+            ],
+            "sourceSpatialReference": {"wkid": 4326},
+        });
+        let url = "https://example.com/arcgis/rest/services/Parcels/FeatureServer/0";
+        let mut layer = parse_metadata(&client, url, &metadata, None, &QueryFilter::default()).unwrap();
+        let config = ScrapeConfig { hash_fields: vec!["parcel_no".to_owned()], ..Default::default() };
+        layer.fields = wkt_fields(transformed_field_types(layer.fields, &config));
+        let columns = output_columns(&layer.fields, false, None, None, false);
+        let layout = ParquetLayout::new(&layer, &columns, None);
+        assert_eq!(layout.schema.field(1).data_type(), &DataType::Utf8);
+
+        let mut attributes = json!({"OBJECTID": 1, "PARCEL_NO": 1234}).as_object().unwrap().to_owned();
+        FeatureTransformer::new(&config).unwrap().apply(&mut attributes);
+        let hash = attributes["PARCEL_NO"].as_str().unwrap().to_owned();
+        let mut rows = ParquetRows::default();
+        let feature = json!({"attributes": attributes, "geometry": {"x": 1.0, "y": 2.0}});
+        layout.push(&mut rows, &["1".to_owned(), hash.to_owned(), String::new()], feature.as_object().unwrap());
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("Parcels.parquet");
+        let mut writer = GeoParquetWriter::create(&path, layout).unwrap();
+        writer.write(rows).unwrap();
+        writer.finish().unwrap();
+        let batch = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap()).unwrap()
+            .build().unwrap()
+            .next().unwrap().unwrap();
+        let parcels = batch.column(1).as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(parcels.value(0), hash);
+    }
+}
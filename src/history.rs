@@ -0,0 +1,143 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+use chrono::{DateTime, Local, Utc};
+use indicatif::HumanDuration;
+use serde::{Deserialize, Serialize};
+use tablestream::{Stream, col, Column};
+use crate::audit::RunId;
+use crate::state::state_directory;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum HistoryError {
+    UnknownRun(u64),
+}
+
+impl Display for HistoryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HistoryError::UnknownRun(id) => {
+                write!(f, "No run with id {} in the history", id)
+            }
+        }
+    }
+}
+
+impl Error for HistoryError {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", content = "message", rename_all = "snake_case")]
+pub(crate) enum RunOutcome {
+    Success,
+    Failed(String),
+}
+
+impl Display for RunOutcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunOutcome::Success => write!(f, "Success"),
+            RunOutcome::Failed(message) => {
+                let first_line = message.lines().next().unwrap_or_default();
+                write!(f, "Failed: {}", first_line)
+            }
+        }
+    }
+}
+
+/// A single scrape invocation as stored in the history file of the state directory. The
+/// arguments are kept verbatim so the run can be repeated with `rerun <id>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RunRecord {
+    pub(crate) id: u64,
+    /// Missing for runs recorded before run ids were introduced
+    #[serde(default)]
+    pub(crate) run_id: Option<RunId>,
+    pub(crate) started_at: DateTime<Utc>,
+    pub(crate) duration_seconds: f64,
+    pub(crate) arguments: Vec<String>,
+    pub(crate) outcome: RunOutcome,
+}
+
+fn history_path() -> io::Result<PathBuf> {
+    Ok(state_directory()?.join("history.jsonl"))
+}
+
+pub(crate) fn load_history() -> Result<Vec<RunRecord>, Box<dyn Error + Send + Sync>> {
+    let path = history_path()?;
+    if !path.is_file() {
+        return Ok(vec![])
+    }
+    let reader = BufReader::new(File::open(path)?);
+    let mut records = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+        records.push(serde_json::from_str(&line)?);
+    }
+    Ok(records)
+}
+
+pub(crate) fn find_run(id: u64) -> Result<RunRecord, Box<dyn Error + Send + Sync>> {
+    load_history()?
+        .into_iter()
+        .find(|record| record.id == id)
+        .ok_or_else(|| HistoryError::UnknownRun(id).into())
+}
+
+pub(crate) fn record_run(
+    run_id: &RunId,
+    arguments: Vec<String>,
+    started_at: DateTime<Utc>,
+    duration: Duration,
+    outcome: RunOutcome,
+) -> Result<RunRecord, Box<dyn Error + Send + Sync>> {
+    let id = load_history()?
+        .last()
+        .map(|record| record.id + 1)
+        .unwrap_or(1);
+    let record = RunRecord {
+        id,
+        run_id: Some(run_id.to_owned()),
+        started_at,
+        duration_seconds: duration.as_secs_f64(),
+        arguments,
+        outcome,
+    };
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(history_path()?)?;
+    writeln!(&mut file, "{}", serde_json::to_string(&record)?)?;
+    Ok(record)
+}
+
+pub(crate) fn write_to_console(records: &[RunRecord]) -> io::Result<()> {
+    let mut out = io::stdout();
+    let mut stream = Stream::new(
+        &mut out,
+        vec![
+            col!(RunRecord: .id).header("ID"),
+            Column::new(|f, c: &RunRecord| {
+                write!(f, "{}", c.started_at.with_timezone(&Local).format("%Y-%m-%d %H:%M"))
+            }).header("Started"),
+            Column::new(|f, c: &RunRecord| {
+                write!(f, "{}", HumanDuration(Duration::from_secs_f64(c.duration_seconds)))
+            }).header("Duration"),
+            col!(RunRecord: .outcome).header("Outcome"),
+            Column::new(|f, c: &RunRecord| {
+                write!(f, "{}", c.arguments.join(" "))
+            }).header("Arguments"),
+        ],
+    );
+    for record in records {
+        stream.row(record.to_owned())?;
+    }
+    stream.finish()?;
+    Ok(())
+}
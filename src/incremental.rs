@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::{rename, File};
+use std::io;
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum IncrementalError {
+    InvalidTimestamp(String),
+}
+
+impl Display for IncrementalError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IncrementalError::InvalidTimestamp(value) => write!(
+                f,
+                "Could not parse the timestamp \"{}\". Expected RFC 3339 (2024-03-01T06:30:00Z), \"YYYY-MM-DD HH:MM:SS\" or \"YYYY-MM-DD\" in UTC",
+                value,
+            ),
+        }
+    }
+}
+
+impl Error for IncrementalError {}
+
+/// Parse the `--since` timestamp, an RFC 3339 date time or a UTC `YYYY-MM-DD HH:MM:SS` or
+/// `YYYY-MM-DD`.
+pub(crate) fn parse_timestamp(value: &str) -> Result<DateTime<Utc>, IncrementalError> {
+    let value = value.trim();
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Ok(timestamp.with_timezone(&Utc))
+    }
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDate::parse_from_str(value, "%Y-%m-%d").map(|date| date.and_time(NaiveTime::MIN)))
+        .map(|timestamp| timestamp.and_utc())
+        .map_err(|_| IncrementalError::InvalidTimestamp(value.to_owned()))
+}
+
+/// Time of the last complete scrape of each layer, kept in the `--state-file` so the next run
+/// only scrapes the features edited since.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ScrapeState {
+    /// Start of the last run that scraped the layer completely, by layer url
+    layers: BTreeMap<String, DateTime<Utc>>,
+}
+
+impl ScrapeState {
+    /// Read the state file at `path`, empty when it does not exist yet.
+    pub(crate) fn load(path: &Path) -> Result<ScrapeState, Box<dyn Error + Send + Sync>> {
+        match File::open(path) {
+            Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(ScrapeState::default()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Time of the last complete scrape of the layer at `url`.
+    pub(crate) fn last_scraped(&self, url: &str) -> Option<DateTime<Utc>> {
+        self.layers.get(url).copied()
+    }
+
+    /// Record the layer at `url` as scraped completely by a run started at `started_at`. The
+    /// start is kept rather than the end, so features edited during the run are scraped again.
+    pub(crate) fn record(&mut self, url: &str, started_at: DateTime<Utc>) {
+        self.layers.insert(url.to_owned(), started_at);
+    }
+
+    /// Write the state under a `.part` name first, so a run killed while writing leaves the
+    /// previous state in place.
+    pub(crate) fn write(&self, path: &Path) -> io::Result<()> {
+        let mut part_name = path.as_os_str().to_owned();
+        part_name.push(".part");
+        let part_path = PathBuf::from(part_name);
+        let mut file = File::create(&part_path)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        writeln!(file)?;
+        file.sync_all()?;
+        rename(&part_path, path)
+    }
+}
+
+#[cfg(test)]
+mod incremental_tests {
+    use chrono::{TimeZone, Utc};
+    use super::{parse_timestamp, IncrementalError, ScrapeState};
+
+    #[test]
+    fn parse_timestamp_should_read_utc_time_when_passed_rfc_3339_or_plain_dates() {
+        let expected = Utc.with_ymd_and_hms(2024, 3, 1, 6, 30, 0).unwrap();
+        assert_eq!(parse_timestamp("2024-03-01T06:30:00Z"), Ok(expected));
+        assert_eq!(parse_timestamp("2024-03-01T08:30:00+02:00"), Ok(expected));
+        assert_eq!(parse_timestamp("2024-03-01 06:30:00"), Ok(expected));
+        assert_eq!(parse_timestamp("2024-03-01"), Ok(Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap()));
+        assert_eq!(parse_timestamp("yesterday"), Err(IncrementalError::InvalidTimestamp("yesterday".to_owned())));
+    }
+
+    #[test]
+    fn load_should_read_back_recorded_layers_when_written() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("state.json");
+        assert_eq!(ScrapeState::load(&path).unwrap(), ScrapeState::default());
+        let started_at = Utc.with_ymd_and_hms(2024, 3, 1, 6, 30, 0).unwrap();
+        let mut state = ScrapeState::default();
+        state.record("https://example.com/FeatureServer/0", started_at);
+        state.write(&path).unwrap();
+        let state = ScrapeState::load(&path).unwrap();
+        assert_eq!(state.last_scraped("https://example.com/FeatureServer/0"), Some(started_at));
+        assert_eq!(state.last_scraped("https://example.com/FeatureServer/1"), None);
+    }
+}
@@ -0,0 +1,536 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use chrono::{DateTime, TimeZone, Utc};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tablestream::{col, Column, Stream};
+use tokio::sync::Semaphore;
+use crate::catalog::{self, CatalogLayer, CatalogOptions};
+use crate::checksum::{Artifact, ChecksumFile};
+use crate::client::ServiceClient;
+use crate::config::ScrapeConfig;
+use crate::filter::QueryFilter;
+use crate::metadata::get_service_count;
+use crate::report;
+use crate::scraping::handle_csv_value;
+use crate::throttle::{BandwidthLimiter, RequestPacer};
+
+/// Columns of an inventory written as CSV.
+const CSV_HEADER: &str = "url,service,name,geometry_type,count,last_edit_date,fields_hash,error";
+
+#[derive(Debug)]
+pub(crate) enum InventoryError {
+    /// CSV inventory whose header is not `CSV_HEADER`
+    Header(String),
+    /// CSV record with the wrong number of values, by line
+    Record(usize),
+}
+
+impl Display for InventoryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InventoryError::Header(header) => {
+                write!(f, "Not an inventory. Expected the header \"{}\" but found \"{}\"", CSV_HEADER, header)
+            }
+            InventoryError::Record(line) => write!(f, "Inventory record on line {} has the wrong number of values", line),
+        }
+    }
+}
+
+impl Error for InventoryError {}
+
+/// Layer of an inventory, described from its metadata and count without scraping any feature.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct InventoryLayer {
+    pub(crate) url: String,
+    pub(crate) service: String,
+    pub(crate) name: String,
+    pub(crate) geometry_type: String,
+    pub(crate) count: Option<i64>,
+    /// Last edit of the layer's data, or of the layer when the server does not tell them apart
+    pub(crate) last_edit_date: Option<DateTime<Utc>>,
+    /// Hash of the field names and types, changing with the layer's schema
+    pub(crate) fields_hash: Option<String>,
+    /// Why the layer could not be described
+    pub(crate) error: Option<String>,
+}
+
+/// Layers of every walked server at a point in time, the JSON form of an inventory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Inventory {
+    pub(crate) created_at: DateTime<Utc>,
+    pub(crate) servers: Vec<String>,
+    pub(crate) layers: Vec<InventoryLayer>,
+}
+
+/// Requests sent while describing layers, sharing the scrape's politeness controls.
+#[derive(Debug, Clone)]
+pub(crate) struct InventoryOptions {
+    pub(crate) catalog: CatalogOptions,
+    /// Layers described at once
+    pub(crate) concurrency: usize,
+    pub(crate) limiter: Option<Arc<BandwidthLimiter>>,
+    pub(crate) pacer: Option<Arc<RequestPacer>>,
+}
+
+/// Hash of the names and types of the fields in `layer_json`, in the server's order.
+fn fields_hash(layer_json: &Value) -> Option<String> {
+    let fields = layer_json["fields"].as_array()?;
+    let mut hasher = Sha256::new();
+    for field in fields {
+        hasher.update(format!(
+            "{}:{}\n",
+            field["name"].as_str().unwrap_or_default(),
+            field["type"].as_str().unwrap_or_default(),
+        ));
+    }
+    Some(format!("{:x}", hasher.finalize())[..16].to_owned())
+}
+
+/// Last edit date of `layer_json`, preferring the data's over the layer's.
+fn last_edit_date(layer_json: &Value) -> Option<DateTime<Utc>> {
+    let editing_info = &layer_json["editingInfo"];
+    let millis = editing_info["dataLastEditDate"]
+        .as_i64()
+        .or_else(|| editing_info["lastEditDate"].as_i64())?;
+    Utc.timestamp_millis_opt(millis).single()
+}
+
+async fn layer_json(
+    client: &ServiceClient,
+    url: &str,
+    options: &InventoryOptions,
+) -> Result<Value, Box<dyn Error + Send + Sync>> {
+    if let Some(pacer) = &options.pacer {
+        pacer.pause().await;
+    }
+    let request_url = Url::parse_with_params(url, [("f", "json")])?;
+    let body = client.get(request_url).await?.send().await?.bytes().await?;
+    if let Some(limiter) = &options.limiter {
+        limiter.consume(body.len()).await;
+    }
+    let layer_json: Value = serde_json::from_slice(&body)?;
+    if let Some(message) = layer_json["error"]["message"].as_str() {
+        return Err(message.into())
+    }
+    Ok(layer_json)
+}
+
+async fn describe_layer(client: &ServiceClient, layer: CatalogLayer, options: &InventoryOptions) -> InventoryLayer {
+    let mut described = InventoryLayer {
+        url: layer.url,
+        service: layer.service,
+        name: layer.name,
+        geometry_type: layer.geometry_type,
+        count: None,
+        last_edit_date: None,
+        fields_hash: None,
+        error: None,
+    };
+    let layer_json = match layer_json(client, &described.url, options).await {
+        Ok(layer_json) => layer_json,
+        Err(error) => {
+            described.error = Some(error.to_string());
+            return described
+        }
+    };
+    described.last_edit_date = last_edit_date(&layer_json);
+    described.fields_hash = fields_hash(&layer_json);
+    if let Some(pacer) = &options.pacer {
+        pacer.pause().await;
+    }
+    match get_service_count(client, &described.url, &QueryFilter::default()).await {
+        Ok(count) => described.count = count,
+        Err(error) => described.error = Some(format!("Could not count the features. {}", error)),
+    }
+    described
+}
+
+/// Walk every server and describe each layer found, at most `options.concurrency` at once.
+/// Layers that cannot be described are kept with their error.
+pub(crate) async fn take_inventory(
+    client: &ServiceClient,
+    servers: &[String],
+    options: &InventoryOptions,
+) -> Result<Inventory, Box<dyn Error + Send + Sync>> {
+    let created_at = Utc::now();
+    let mut catalog_layers = vec![];
+    for server in servers {
+        let layers = catalog::walk_catalog(client, server, &options.catalog).await?;
+        println!("Found {} layers in {}", layers.len(), server);
+        catalog_layers.extend(layers);
+    }
+    let permits = Arc::new(Semaphore::new(options.concurrency.max(1)));
+    let handles: Vec<_> = catalog_layers.into_iter()
+        .map(|layer| {
+            let client = client.to_owned();
+            let options = options.to_owned();
+            let permits = permits.clone();
+            tokio::spawn(async move {
+                let _permit = permits.acquire_owned().await;
+                describe_layer(&client, layer, &options).await
+            })
+        })
+        .collect();
+    let mut layers = Vec::with_capacity(handles.len());
+    for handle in handles {
+        layers.push(handle.await?);
+    }
+    for layer in layers.iter().filter(|layer| layer.error.is_some()) {
+        report::warn(format_args!(
+            "Could not describe layer \"{}\" ({}). {}",
+            layer.name,
+            layer.url,
+            layer.error.as_deref().unwrap_or_default(),
+        ));
+    }
+    Ok(Inventory { created_at, servers: servers.to_vec(), layers })
+}
+
+fn csv_record(layer: &InventoryLayer) -> String {
+    [
+        layer.url.to_owned(),
+        layer.service.to_owned(),
+        layer.name.to_owned(),
+        layer.geometry_type.to_owned(),
+        layer.count.map(|count| count.to_string()).unwrap_or_default(),
+        layer.last_edit_date.map(|date| date.to_rfc3339()).unwrap_or_default(),
+        layer.fields_hash.to_owned().unwrap_or_default(),
+        layer.error.to_owned().unwrap_or_default(),
+    ]
+        .iter()
+        .map(handle_csv_value)
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+impl Inventory {
+    /// Write the inventory to `path`, as JSON when it ends in `.json` and CSV otherwise.
+    pub(crate) fn write(&self, path: &Path) -> Result<Artifact, Box<dyn Error + Send + Sync>> {
+        let mut file = ChecksumFile::create_part(path)?;
+        if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("json")) {
+            serde_json::to_writer_pretty(&mut file, self)?;
+            writeln!(file)?;
+        } else {
+            writeln!(file, "{}", CSV_HEADER)?;
+            for layer in &self.layers {
+                writeln!(file, "{}", csv_record(layer))?;
+            }
+        }
+        Ok(file.finish()?)
+    }
+
+    pub(crate) fn write_to_console(&self) -> io::Result<()> {
+        println!("Inventory Layers: {}", self.layers.len());
+        let mut out = io::stdout();
+        let mut stream = Stream::new(
+            &mut out,
+            vec![
+                col!(InventoryLayer: .service).header("Service"),
+                col!(InventoryLayer: .name).header("Layer"),
+                col!(InventoryLayer: .geometry_type).header("Geometry"),
+                Column::new(|f, layer: &InventoryLayer| match layer.count {
+                    Some(count) => write!(f, "{}", count),
+                    None => write!(f, "-"),
+                }).header("Count"),
+                Column::new(|f, layer: &InventoryLayer| match layer.last_edit_date {
+                    Some(date) => write!(f, "{}", date.format("%Y-%m-%d %H:%M")),
+                    None => write!(f, "-"),
+                }).header("Last Edit"),
+            ],
+        );
+        for layer in &self.layers {
+            stream.row(layer.to_owned())?;
+        }
+        stream.finish()?;
+        out.flush()
+    }
+
+    /// Read an inventory written by [Inventory::write]. CSV inventories do not record the
+    /// servers walked, and are dated by the file's modification time.
+    pub(crate) fn read(path: &Path) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let contents = fs::read_to_string(path)?;
+        if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("json")) {
+            return Ok(serde_json::from_str(&contents)?)
+        }
+        let mut rows = csv_rows(&contents).into_iter();
+        let header = rows.next().unwrap_or_default().join(",");
+        if header != CSV_HEADER {
+            return Err(InventoryError::Header(header).into())
+        }
+        let layers = rows.enumerate()
+            .map(|(index, row)| parse_csv_record(row).ok_or(InventoryError::Record(index + 2)))
+            .collect::<Result<_, _>>()?;
+        let created_at = fs::metadata(path)?.modified()?.into();
+        Ok(Self { created_at, servers: vec![], layers })
+    }
+}
+
+/// Split CSV `contents` into rows of unquoted values, keeping line breaks found inside quotes.
+fn csv_rows(contents: &str) -> Vec<Vec<String>> {
+    let mut rows = vec![];
+    let mut row = vec![];
+    let mut value = String::new();
+    let mut quoted = false;
+    let mut chars = contents.chars().peekable();
+    while let Some(chr) = chars.next() {
+        match chr {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                value.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => row.push(std::mem::take(&mut value)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                row.push(std::mem::take(&mut value));
+                rows.push(std::mem::take(&mut row));
+            }
+            _ => value.push(chr),
+        }
+    }
+    if !value.is_empty() || !row.is_empty() {
+        row.push(value);
+        rows.push(row);
+    }
+    rows
+}
+
+fn parse_csv_record(row: Vec<String>) -> Option<InventoryLayer> {
+    let [url, service, name, geometry_type, count, last_edit_date, fields_hash, error]: [String; 8] =
+        row.try_into().ok()?;
+    let non_empty = |value: String| Some(value).filter(|value| !value.is_empty());
+    Some(InventoryLayer {
+        url,
+        service,
+        name,
+        geometry_type,
+        count: non_empty(count).and_then(|count| count.parse().ok()),
+        last_edit_date: non_empty(last_edit_date)
+            .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+            .map(|date| date.with_timezone(&Utc)),
+        fields_hash: non_empty(fields_hash),
+        error: non_empty(error),
+    })
+}
+
+/// Difference found for a layer between two inventories.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum LayerChange {
+    Added,
+    Removed,
+    Count(i64, i64),
+    Schema,
+    LastEdit(DateTime<Utc>, DateTime<Utc>),
+}
+
+impl Display for LayerChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LayerChange::Added => write!(f, "added"),
+            LayerChange::Removed => write!(f, "removed"),
+            LayerChange::Count(old, new) => write!(f, "count {} -> {}", old, new),
+            LayerChange::Schema => write!(f, "fields changed"),
+            LayerChange::LastEdit(old, new) => {
+                write!(f, "edited {} -> {}", old.format("%Y-%m-%d %H:%M"), new.format("%Y-%m-%d %H:%M"))
+            }
+        }
+    }
+}
+
+/// Layer of either inventory with every difference found, matched between inventories by url.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ChangedLayer {
+    pub(crate) url: String,
+    pub(crate) name: String,
+    pub(crate) changes: Vec<LayerChange>,
+}
+
+impl ChangedLayer {
+    fn changes_text(&self) -> String {
+        self.changes.iter().map(LayerChange::to_string).collect::<Vec<_>>().join(", ")
+    }
+}
+
+/// Pair of values compared only when both inventories know them, so layers the server could
+/// not describe once are not reported as changed.
+fn known<T>(old: &Option<T>, new: &Option<T>) -> Option<(T, T)>
+where
+    T: PartialEq + Clone,
+{
+    match (old, new) {
+        (Some(old), Some(new)) if old != new => Some((old.to_owned(), new.to_owned())),
+        _ => None,
+    }
+}
+
+/// Layers added, removed or whose count, fields or last edit date differ from `old` to `new`,
+/// in the order of `new` followed by the removed layers.
+pub(crate) fn changed_layers(old: &Inventory, new: &Inventory) -> Vec<ChangedLayer> {
+    let mut changed: Vec<ChangedLayer> = new.layers.iter()
+        .filter_map(|layer| {
+            let changes = match old.layers.iter().find(|old_layer| old_layer.url == layer.url) {
+                None => vec![LayerChange::Added],
+                Some(old_layer) => {
+                    let mut changes = vec![];
+                    if let Some((old, new)) = known(&old_layer.count, &layer.count) {
+                        changes.push(LayerChange::Count(old, new));
+                    }
+                    if known(&old_layer.fields_hash, &layer.fields_hash).is_some() {
+                        changes.push(LayerChange::Schema);
+                    }
+                    if let Some((old, new)) = known(&old_layer.last_edit_date, &layer.last_edit_date) {
+                        changes.push(LayerChange::LastEdit(old, new));
+                    }
+                    changes
+                }
+            };
+            (!changes.is_empty()).then(|| ChangedLayer {
+                url: layer.url.to_owned(),
+                name: layer.name.to_owned(),
+                changes,
+            })
+        })
+        .collect();
+    changed.extend(
+        old.layers.iter()
+            .filter(|layer| new.layers.iter().all(|new_layer| new_layer.url != layer.url))
+            .map(|layer| ChangedLayer {
+                url: layer.url.to_owned(),
+                name: layer.name.to_owned(),
+                changes: vec![LayerChange::Removed],
+            }),
+    );
+    changed
+}
+
+pub(crate) fn write_changes_to_console(changed: &[ChangedLayer]) -> io::Result<()> {
+    println!("Changed Layers: {}", changed.len());
+    let mut out = io::stdout();
+    let mut stream = Stream::new(
+        &mut out,
+        vec![
+            col!(ChangedLayer: .name).header("Layer"),
+            Column::new(|f, layer: &ChangedLayer| write!(f, "{}", layer.changes_text())).header("Changes"),
+            col!(ChangedLayer: .url).header("Url"),
+        ],
+    );
+    for layer in changed {
+        stream.row(layer.to_owned())?;
+    }
+    stream.finish()?;
+    out.flush()
+}
+
+/// Write a `--config` file scraping the changed layers that still exist.
+pub(crate) fn write_batch_config(changed: &[ChangedLayer], path: &Path) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let config = ScrapeConfig {
+        urls: changed.iter()
+            .filter(|layer| !layer.changes.contains(&LayerChange::Removed))
+            .map(|layer| layer.url.to_owned())
+            .collect(),
+        ..ScrapeConfig::default()
+    };
+    let mut json = serde_json::to_string_pretty(&config)?;
+    json.push('\n');
+    fs::write(path, json)?;
+    Ok(config.urls.len())
+}
+
+#[cfg(test)]
+mod inventory_tests {
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+    use super::{
+        changed_layers, csv_record, csv_rows, fields_hash, last_edit_date, parse_csv_record, Inventory,
+        InventoryLayer, LayerChange,
+    };
+
+    fn layer(url: &str, count: i64, fields_hash: &str) -> InventoryLayer {
+        InventoryLayer {
+            url: url.to_owned(),
+            service: "Parcels/MapServer".to_owned(),
+            name: "Lots, Parcels".to_owned(),
+            geometry_type: "Polygon".to_owned(),
+            count: Some(count),
+            last_edit_date: Utc.timestamp_millis_opt(1700000000000).single(),
+            fields_hash: Some(fields_hash.to_owned()),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn fields_hash_should_change_when_field_type_changes() {
+        let layer_json = json!({"fields": [{"name": "OBJECTID", "type": "esriFieldTypeOID"}, {"name": "VAL", "type": "esriFieldTypeInteger"}]});
+        let changed_json = json!({"fields": [{"name": "OBJECTID", "type": "esriFieldTypeOID"}, {"name": "VAL", "type": "esriFieldTypeDouble"}]});
+        assert_eq!(fields_hash(&layer_json), fields_hash(&layer_json.clone()));
+        assert_ne!(fields_hash(&layer_json), fields_hash(&changed_json));
+        assert_eq!(fields_hash(&json!({})), None);
+    }
+
+    #[test]
+    fn last_edit_date_should_prefer_data_edit_date_when_present() {
+        let layer_json = json!({"editingInfo": {"lastEditDate": 1600000000000_i64, "dataLastEditDate": 1700000000000_i64}});
+        assert_eq!(last_edit_date(&layer_json), Utc.timestamp_millis_opt(1700000000000).single());
+        assert_eq!(last_edit_date(&json!({"editingInfo": {"lastEditDate": 1600000000000_i64}})), Utc.timestamp_millis_opt(1600000000000).single());
+    }
+
+    #[test]
+    fn csv_record_should_quote_values_when_they_hold_commas() {
+        let layer = InventoryLayer {
+            url: "https://example.com/Parcels/MapServer/1".to_owned(),
+            service: "Parcels/MapServer".to_owned(),
+            name: "Lots, Parcels".to_owned(),
+            geometry_type: "Polygon".to_owned(),
+            count: Some(12),
+            last_edit_date: None,
+            fields_hash: Some("0123456789abcdef".to_owned()),
+            error: None,
+        };
+        assert_eq!(
+            csv_record(&layer),
+            "https://example.com/Parcels/MapServer/1,Parcels/MapServer,\"Lots, Parcels\",Polygon,12,,0123456789abcdef,",
+        );
+    }
+
+    #[test]
+    fn parse_csv_record_should_read_back_layer_when_written_by_csv_record() {
+        let mut written = layer("https://example.com/Parcels/MapServer/1", 12, "0123456789abcdef");
+        written.error = Some("Bad \"request\",\nretry".to_owned());
+        let rows = csv_rows(&format!("{}\r\n{}\n", csv_record(&written), csv_record(&written)));
+        assert_eq!(rows.len(), 2);
+        assert_eq!(parse_csv_record(rows[0].to_owned()), Some(written));
+    }
+
+    #[test]
+    fn changed_layers_should_list_differences_when_snapshots_differ() {
+        let snapshot = |layers| Inventory { created_at: Utc::now(), servers: vec![], layers };
+        let mut edited = layer("https://example.com/Parcels/MapServer/1", 13, "fedcba9876543210");
+        edited.last_edit_date = None;
+        let old = snapshot(vec![
+            layer("https://example.com/Parcels/MapServer/1", 12, "0123456789abcdef"),
+            layer("https://example.com/Parcels/MapServer/2", 5, "0123456789abcdef"),
+            layer("https://example.com/Parcels/MapServer/3", 5, "0123456789abcdef"),
+        ]);
+        let new = snapshot(vec![
+            edited,
+            layer("https://example.com/Parcels/MapServer/2", 5, "0123456789abcdef"),
+            layer("https://example.com/Parcels/MapServer/4", 1, "0123456789abcdef"),
+        ]);
+        let changes: Vec<_> = changed_layers(&old, &new).into_iter()
+            .map(|layer| (layer.url, layer.changes))
+            .collect();
+        assert_eq!(changes, vec![
+            ("https://example.com/Parcels/MapServer/1".to_owned(), vec![LayerChange::Count(12, 13), LayerChange::Schema]),
+            ("https://example.com/Parcels/MapServer/4".to_owned(), vec![LayerChange::Added]),
+            ("https://example.com/Parcels/MapServer/3".to_owned(), vec![LayerChange::Removed]),
+        ]);
+    }
+}
@@ -0,0 +1,82 @@
+//! Scrapes every feature of ArcGIS REST service layers into CSV files, splitting each layer
+//! into chunk queries the server can answer. The command line tool is a thin wrapper around
+//! [`run`], the [`Scraper`] embeds the same pipeline in other async programs.
+
+mod antimeridian;
+mod archive;
+mod attachments;
+mod audit;
+mod auth;
+mod batch;
+mod capability;
+mod catalog;
+mod cell_index;
+mod checkpoint;
+mod checksum;
+mod cli;
+mod client;
+mod config;
+mod connection;
+mod deadline;
+mod disk;
+mod dynamic;
+mod filter;
+mod flatgeobuf;
+mod geometry;
+mod geoparquet;
+mod geometry_guard;
+mod history;
+mod incremental;
+mod inventory;
+mod lock;
+mod logging;
+mod measure;
+mod merge;
+mod meter;
+mod metadata;
+mod ogr;
+mod partition;
+mod pbf;
+mod pipeline;
+mod pmtiles;
+mod postgis;
+mod preview;
+mod profile;
+mod progress;
+mod projection;
+mod prompt;
+mod quadtree;
+mod related;
+mod replica;
+mod report;
+mod retention;
+mod sampling;
+mod scrape_report;
+mod scraper;
+mod scraping;
+mod service;
+mod shapefile;
+mod spool;
+mod state;
+mod strategy;
+mod suspicious;
+mod throttle;
+mod topology;
+mod transform;
+mod update;
+mod vector_tiles;
+mod wkt;
+
+pub use auth::{AuthFuture, AuthProvider};
+pub use cli::run;
+pub use metadata::{RestServiceMetadata, RestServiceMetadataError};
+pub use progress::{ChunkProgress, LayerRef, LayerSummary, ProgressReporter};
+pub use scraper::{Scraper, WriterOptions};
+
+/// Request the metadata of the layer at `url` with anonymous requests. Use a [`Scraper`] to
+/// change how the layer is requested.
+pub async fn request_service_metadata(
+    url: &str,
+) -> Result<RestServiceMetadata, Box<dyn std::error::Error + Send + Sync>> {
+    Scraper::new().request_metadata(url).await
+}
@@ -0,0 +1,297 @@
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::fs::{canonicalize, create_dir_all, hard_link, remove_file, rename, write};
+use std::io;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::audit::RunId;
+use crate::report;
+use crate::state::state_directory;
+
+/// Run holding the lock of an output target, written into its lock file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct LockOwner {
+    pid: u32,
+    run_id: RunId,
+    started_at: DateTime<Utc>,
+    target: PathBuf,
+}
+
+impl LockOwner {
+    /// True while the process holding the lock is running. Other platforms cannot tell, their
+    /// locks are only released by their run or --force.
+    fn is_alive(&self) -> bool {
+        #[cfg(unix)]
+        {
+            let Ok(pid) = libc::pid_t::try_from(self.pid) else { return false };
+            // SAFETY: signal 0 only checks whether the process exists, nothing is sent
+            if unsafe { libc::kill(pid, 0) } == 0 {
+                return true
+            }
+            io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+        }
+        #[cfg(not(unix))]
+        {
+            true
+        }
+    }
+}
+
+pub(crate) enum LockError {
+    /// Another running scrape writes to the target
+    Held(LockOwner),
+    Io(PathBuf, io::Error),
+}
+
+/// Errors returned from main are printed with Debug, show who holds the lock instead of the variant.
+impl Debug for LockError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Display for LockError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::Held(owner) => write!(
+                f,
+                "{} is locked by run {} (pid {}) started at {}. Pass --force if that run is gone",
+                owner.target.display(),
+                owner.run_id,
+                owner.pid,
+                owner.started_at.to_rfc3339(),
+            ),
+            LockError::Io(path, error) => write!(f, "Could not lock {}. {}", path.display(), error),
+        }
+    }
+}
+
+impl Error for LockError {}
+
+/// Lock of an output target held for the lifetime of a run, so two scrapes writing the same
+/// files (e.g. a job fired twice by a scheduler) cannot interleave. Lock files live in
+/// `locks/` of the state directory, named by the hash of the target's normalized absolute path, and are
+/// removed when the lock is dropped. Locks left by runs that crashed are taken over.
+#[derive(Debug)]
+pub(crate) struct RunLock {
+    path: PathBuf,
+    owner: LockOwner,
+}
+
+impl RunLock {
+    /// Lock every target, failing on the first one locked by another running scrape unless
+    /// `force` is set.
+    pub(crate) fn acquire_all(targets: &[PathBuf], run_id: &RunId, force: bool) -> Result<Vec<RunLock>, Box<dyn Error + Send + Sync>> {
+        let directory = state_directory()?.join("locks");
+        let mut locks = Vec::with_capacity(targets.len());
+        for target in targets {
+            let target = normalized_target(target).map_err(|error| LockError::Io(target.to_owned(), error))?;
+            locks.push(Self::acquire(&directory, &target, run_id, force)?);
+        }
+        Ok(locks)
+    }
+
+    fn acquire(directory: &Path, target: &Path, run_id: &RunId, force: bool) -> Result<RunLock, LockError> {
+        let io_error = |error| LockError::Io(target.to_owned(), error);
+        create_dir_all(directory).map_err(io_error)?;
+        let hash = format!("{:x}", Sha256::digest(target.as_os_str().to_string_lossy().as_bytes()));
+        let path = directory.join(format!("{}.lock", &hash[..16]));
+        let owner = LockOwner {
+            pid: std::process::id(),
+            run_id: run_id.to_owned(),
+            started_at: Utc::now(),
+            target: target.to_owned(),
+        };
+        let contents = serde_json::to_vec(&owner).map_err(|error| io_error(error.into()))?;
+        // The owner is written in full before the lock file appears, hard linking fails when
+        // the lock file exists, so other runs never read a lock file still being written
+        let owner_path = directory.join(format!("{}.{}.owner", &hash[..16], owner.pid));
+        write(&owner_path, contents).map_err(io_error)?;
+        let result = Self::link(&owner_path, &path, target, force);
+        let _ = remove_file(&owner_path);
+        result.map(|_| RunLock { path, owner })
+    }
+
+    /// Link the written owner file to the lock file, replacing a stale lock once. A second
+    /// failure means another run took the lock meanwhile.
+    fn link(owner_path: &Path, path: &Path, target: &Path, force: bool) -> Result<(), LockError> {
+        let io_error = |error| LockError::Io(target.to_owned(), error);
+        for _ in 0..2 {
+            match hard_link(owner_path, path) {
+                Ok(_) => return Ok(()),
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(error) => return Err(io_error(error)),
+            }
+            // Released since the link failed
+            let Ok(contents) = std::fs::read(path) else { continue };
+            let holder = serde_json::from_slice::<LockOwner>(&contents).ok();
+            match holder {
+                Some(holder) if holder.is_alive() && !force => return Err(LockError::Held(holder)),
+                Some(holder) if holder.is_alive() => report::warn(format_args!(
+                    "Taking over the lock of {} from run {} (pid {}) with --force",
+                    target.display(),
+                    holder.run_id,
+                    holder.pid,
+                )),
+                Some(holder) => report::warn(format_args!(
+                    "Removing the stale lock of {} left by run {} (pid {} is not running)",
+                    target.display(),
+                    holder.run_id,
+                    holder.pid,
+                )),
+                None => report::warn(format_args!("Removing the unreadable lock of {}", target.display())),
+            }
+            Self::remove_stale(path, &contents, target)?;
+        }
+        Err(io_error(io::Error::new(io::ErrorKind::AlreadyExists, "another run took the lock")))
+    }
+
+    /// Remove the lock file read as `stale`. It is renamed aside first and only removed when it
+    /// is still the file that was read, a lock taken by another run in between is put back.
+    fn remove_stale(path: &Path, stale: &[u8], target: &Path) -> Result<(), LockError> {
+        let io_error = |error| LockError::Io(target.to_owned(), error);
+        let aside = path.with_extension(format!("{}.stale", std::process::id()));
+        match rename(path, &aside) {
+            Ok(_) => {}
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(io_error(error)),
+        }
+        let moved = std::fs::read(&aside);
+        if moved.as_deref().ok() == Some(stale) {
+            let _ = remove_file(&aside);
+            return Ok(())
+        }
+        // Fails when yet another run linked the lock file meanwhile, the lock is lost either way
+        let _ = hard_link(&aside, path);
+        let _ = remove_file(&aside);
+        match moved.ok().and_then(|contents| serde_json::from_slice::<LockOwner>(&contents).ok()) {
+            Some(holder) => Err(LockError::Held(holder)),
+            None => Err(io_error(io::Error::new(io::ErrorKind::AlreadyExists, "another run took the lock"))),
+        }
+    }
+}
+
+/// Absolute path of `target` with symlinks, `.` and `..` resolved, so every spelling of the same
+/// target shares one lock. The target's parent directory is created to resolve it.
+fn normalized_target(target: &Path) -> io::Result<PathBuf> {
+    let target = std::env::current_dir()?.join(target);
+    if let Ok(path) = canonicalize(&target) {
+        return Ok(path)
+    }
+    match (target.parent(), target.file_name()) {
+        (Some(parent), Some(name)) => {
+            create_dir_all(parent)?;
+            Ok(canonicalize(parent)?.join(name))
+        }
+        _ => {
+            create_dir_all(&target)?;
+            canonicalize(&target)
+        }
+    }
+}
+
+/// Runs holding a lock whose process is still running.
+pub(crate) fn active_runs() -> io::Result<Vec<RunId>> {
+    let directory = state_directory()?.join("locks");
+    let entries = match std::fs::read_dir(&directory) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(error) => return Err(error),
+    };
+    let mut runs = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("lock") {
+            continue
+        }
+        let holder = std::fs::read(path)
+            .ok()
+            .and_then(|contents| serde_json::from_slice::<LockOwner>(&contents).ok());
+        if let Some(holder) = holder.filter(LockOwner::is_alive) {
+            runs.push(holder.run_id);
+        }
+    }
+    Ok(runs)
+}
+
+/// Only removes the lock file while it is still ours, it may have been taken over with --force.
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        let holder = std::fs::read(&self.path)
+            .ok()
+            .and_then(|contents| serde_json::from_slice::<LockOwner>(&contents).ok());
+        if holder.as_ref() == Some(&self.owner) {
+            let _ = remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod lock_tests {
+    use std::path::Path;
+    use chrono::Utc;
+    use crate::audit::RunId;
+    use super::{normalized_target, LockError, LockOwner, RunLock};
+
+    #[test]
+    fn acquire_should_fail_when_target_is_locked_by_running_scrape() {
+        let directory = tempfile::tempdir().unwrap();
+        let target = Path::new("/data/output_files");
+        let lock = RunLock::acquire(directory.path(), target, &RunId::generate(), false).unwrap();
+        let result = RunLock::acquire(directory.path(), target, &RunId::generate(), false);
+        assert!(matches!(result, Err(LockError::Held(owner)) if owner.pid == std::process::id()));
+        let tiles_lock = RunLock::acquire(directory.path(), Path::new("/data/parcels.pmtiles"), &RunId::generate(), false);
+        assert!(tiles_lock.is_ok());
+        let forced = RunLock::acquire(directory.path(), target, &RunId::generate(), true).unwrap();
+        drop(lock);
+        assert!(forced.path.exists());
+        drop(forced);
+        drop(tiles_lock);
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn acquire_should_take_over_lock_when_owner_is_not_running() {
+        let directory = tempfile::tempdir().unwrap();
+        let target = Path::new("/data/output_files");
+        let lock = RunLock::acquire(directory.path(), target, &RunId::generate(), false).unwrap();
+        let stale = LockOwner {
+            pid: i32::MAX as u32,
+            run_id: RunId::generate(),
+            started_at: Utc::now(),
+            target: target.to_owned(),
+        };
+        std::fs::write(&lock.path, serde_json::to_vec(&stale).unwrap()).unwrap();
+        let path = lock.path.to_owned();
+        std::mem::forget(lock);
+        let taken = RunLock::acquire(directory.path(), target, &RunId::generate(), false).unwrap();
+        assert_eq!(taken.path, path);
+    }
+
+    #[test]
+    fn normalized_target_should_match_when_target_spelled_differently() {
+        let directory = tempfile::tempdir().unwrap();
+        let plain = normalized_target(&directory.path().join("output_files")).unwrap();
+        let dotted = normalized_target(&directory.path().join("nested/../output_files/")).unwrap();
+        assert_eq!(plain, dotted);
+        assert!(plain.is_absolute());
+        assert!(directory.path().join("nested").is_dir());
+    }
+
+    #[test]
+    fn remove_stale_should_keep_lock_when_replaced_after_read() {
+        let directory = tempfile::tempdir().unwrap();
+        let target = Path::new("/data/output_files");
+        let lock = RunLock::acquire(directory.path(), target, &RunId::generate(), false).unwrap();
+        let current = std::fs::read(&lock.path).unwrap();
+        let result = RunLock::remove_stale(&lock.path, b"{}", target);
+        assert!(matches!(result, Err(LockError::Held(owner)) if owner == lock.owner));
+        assert_eq!(std::fs::read(&lock.path).unwrap(), current);
+        assert!(RunLock::remove_stale(&lock.path, &current, target).is_ok());
+        assert!(!lock.path.exists());
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 0);
+    }
+}
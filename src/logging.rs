@@ -0,0 +1,126 @@
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
+use clap::ValueEnum;
+use tracing::level_filters::LevelFilter;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::{EnvFilter, Targets};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+/// Crate whose events are logged. The HTTP/3 and TLS dependencies emit their own events, which
+/// are only logged when asked for through `RUST_LOG`.
+const LOG_TARGET: &str = "arcgis_scraper";
+
+/// Level of the events printed to the console, from the `--verbose` and `--quiet` flags.
+pub(crate) fn console_level(verbose: bool, quiet: bool) -> LevelFilter {
+    match (verbose, quiet) {
+        (true, _) => LevelFilter::DEBUG,
+        (_, true) => LevelFilter::ERROR,
+        _ => LevelFilter::INFO,
+    }
+}
+
+/// How often --log-file starts a new file.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub(crate) enum LogRotation {
+    /// A single file, left to the service manager to rotate (e.g. logrotate with copytruncate)
+    Never,
+    /// A file per day, the date appended to its name
+    Daily,
+    /// A file per hour, the date and hour appended to its name
+    Hourly,
+}
+
+/// Where --log-file events are written and how the file is rotated.
+#[derive(Debug, Clone)]
+pub(crate) struct LogFile<'a> {
+    pub(crate) path: &'a Path,
+    pub(crate) rotation: LogRotation,
+    /// Rotated files kept, the oldest being removed. All of them when None
+    pub(crate) max_files: Option<usize>,
+}
+
+impl LogFile<'_> {
+    fn writer(&self) -> Result<BoxMakeWriter, Box<dyn Error + Send + Sync>> {
+        let rotation = match self.rotation {
+            LogRotation::Never => return Ok(BoxMakeWriter::new(Mutex::new(File::create(self.path)?))),
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Hourly => Rotation::HOURLY,
+        };
+        let directory = self.path.parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        let mut builder = RollingFileAppender::builder().rotation(rotation);
+        if let Some(name) = self.path.file_name() {
+            builder = builder.filename_prefix(name.to_string_lossy());
+        }
+        if let Some(max_files) = self.max_files {
+            builder = builder.max_log_files(max_files);
+        }
+        Ok(BoxMakeWriter::new(builder.build(directory)?))
+    }
+}
+
+/// Log the events of requests, retries and fetch workers to the console at `level`, or as the
+/// `RUST_LOG` environment variable says when it is set, and every event down to debug as JSON
+/// lines to `log_file`.
+pub(crate) fn init(level: LevelFilter, log_file: Option<&LogFile>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let console_filter = match std::env::var("RUST_LOG") {
+        Ok(directives) => EnvFilter::try_new(directives)?,
+        Err(_) => EnvFilter::default().add_directive(format!("{}={}", LOG_TARGET, level).parse()?),
+    };
+    let console = tracing_subscriber::fmt::layer()
+        .without_time()
+        .with_target(false)
+        .with_ansi(console::colors_enabled())
+        .with_writer(std::io::stdout)
+        .with_filter(console_filter);
+    let file = match log_file {
+        Some(log_file) => Some(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_current_span(true)
+                .with_span_list(false)
+                .with_writer(log_file.writer()?)
+                .with_filter(Targets::new().with_target(LOG_TARGET, LevelFilter::DEBUG)),
+        ),
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(console)
+        .with(file)
+        .try_init()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod logging_tests {
+    use std::io::Write;
+    use chrono::Utc;
+    use tracing::level_filters::LevelFilter;
+    use tracing_subscriber::fmt::MakeWriter;
+    use super::{console_level, LogFile, LogRotation};
+
+    #[test]
+    fn console_level_should_follow_flags_when_verbose_or_quiet() {
+        assert_eq!(console_level(false, false), LevelFilter::INFO);
+        assert_eq!(console_level(true, false), LevelFilter::DEBUG);
+        assert_eq!(console_level(false, true), LevelFilter::ERROR);
+    }
+
+    #[test]
+    fn writer_should_append_date_to_file_name_when_rotated_daily() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("scraper.log");
+        let log_file = LogFile { path: &path, rotation: LogRotation::Daily, max_files: Some(7) };
+        let writer = log_file.writer().unwrap();
+        writeln!(writer.make_writer(), "{{}}").unwrap();
+        let rotated = directory.path().join(format!("scraper.log.{}", Utc::now().format("%Y-%m-%d")));
+        assert_eq!(std::fs::read_to_string(rotated).unwrap(), "{}\n");
+        assert!(!path.exists());
+    }
+}
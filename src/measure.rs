@@ -0,0 +1,292 @@
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+use crate::metadata::RestServiceGeometryType;
+use crate::projection::transform_point;
+
+const WGS84: i64 = 4326;
+/// Semi-major axis of the WGS84 ellipsoid, in meters.
+const SEMI_MAJOR_AXIS: f64 = 6_378_137_f64;
+const FLATTENING: f64 = 1_f64 / 298.257_223_563;
+/// Mean earth radius used when Vincenty's formula does not converge (nearly antipodal points).
+const MEAN_RADIUS: f64 = 6_371_008.8;
+
+/// Unit of the computed area column.
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+pub(crate) enum AreaUnit {
+    SquareMeters,
+    SquareKilometers,
+    SquareFeet,
+    SquareMiles,
+    Hectares,
+    Acres,
+}
+
+impl AreaUnit {
+    fn square_meters(&self) -> f64 {
+        match self {
+            AreaUnit::SquareMeters => 1_f64,
+            AreaUnit::SquareKilometers => 1_000_000_f64,
+            AreaUnit::SquareFeet => 0.092_903_04,
+            AreaUnit::SquareMiles => 2_589_988.110_336,
+            AreaUnit::Hectares => 10_000_f64,
+            AreaUnit::Acres => 4_046.856_422_4,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            AreaUnit::SquareMeters => "SQUARE_METERS",
+            AreaUnit::SquareKilometers => "SQUARE_KILOMETERS",
+            AreaUnit::SquareFeet => "SQUARE_FEET",
+            AreaUnit::SquareMiles => "SQUARE_MILES",
+            AreaUnit::Hectares => "HECTARES",
+            AreaUnit::Acres => "ACRES",
+        }
+    }
+}
+
+/// Unit of the computed length column.
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+pub(crate) enum LengthUnit {
+    Meters,
+    Kilometers,
+    Feet,
+    Miles,
+}
+
+impl LengthUnit {
+    fn meters(&self) -> f64 {
+        match self {
+            LengthUnit::Meters => 1_f64,
+            LengthUnit::Kilometers => 1_000_f64,
+            LengthUnit::Feet => 0.3048,
+            LengthUnit::Miles => 1_609.344,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            LengthUnit::Meters => "METERS",
+            LengthUnit::Kilometers => "KILOMETERS",
+            LengthUnit::Feet => "FEET",
+            LengthUnit::Miles => "MILES",
+        }
+    }
+}
+
+/// Geodesic measurement attached to every feature of a layer: the area of polygons or the
+/// length of lines.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Measure {
+    Area(AreaUnit),
+    Length(LengthUnit),
+}
+
+impl Measure {
+    /// Measure of a layer with geometry `geo_type`, None when the requested units do not apply
+    /// to it (e.g. only an area unit for a line layer).
+    pub(crate) fn for_layer(
+        geo_type: &RestServiceGeometryType,
+        area_unit: Option<&AreaUnit>,
+        length_unit: Option<&LengthUnit>,
+    ) -> Option<Measure> {
+        match geo_type {
+            RestServiceGeometryType::Polygon | RestServiceGeometryType::Envelope => {
+                area_unit.map(|unit| Measure::Area(unit.to_owned()))
+            }
+            RestServiceGeometryType::Polyline => length_unit.map(|unit| Measure::Length(unit.to_owned())),
+            _ => None,
+        }
+    }
+
+    /// Output column holding the measurement of each feature.
+    pub(crate) fn column(&self) -> String {
+        match self {
+            Measure::Area(unit) => format!("_AREA_{}", unit.name()),
+            Measure::Length(unit) => format!("_LENGTH_{}", unit.name()),
+        }
+    }
+}
+
+/// Geodesic distance in meters between two longitude/latitude positions on the WGS84
+/// ellipsoid, using Vincenty's inverse formula.
+fn geodesic_distance(start: (f64, f64), end: (f64, f64)) -> f64 {
+    let semi_minor_axis = SEMI_MAJOR_AXIS * (1_f64 - FLATTENING);
+    let difference = (end.0 - start.0).to_radians();
+    let reduced = |latitude: f64| ((1_f64 - FLATTENING) * latitude.to_radians().tan()).atan();
+    let (sin_u1, cos_u1) = reduced(start.1).sin_cos();
+    let (sin_u2, cos_u2) = reduced(end.1).sin_cos();
+    let mut lambda = difference;
+    for _ in 0..200 {
+        let (sin_lambda, cos_lambda) = lambda.sin_cos();
+        let sin_sigma = (cos_u2 * sin_lambda).hypot(cos_u1 * sin_u2 - sin_u1 * cos_u2 * cos_lambda);
+        if sin_sigma == 0_f64 {
+            return 0_f64
+        }
+        let cos_sigma = sin_u1 * sin_u2 + cos_u1 * cos_u2 * cos_lambda;
+        let sigma = sin_sigma.atan2(cos_sigma);
+        let sin_alpha = cos_u1 * cos_u2 * sin_lambda / sin_sigma;
+        let cos_sq_alpha = 1_f64 - sin_alpha * sin_alpha;
+        let cos_2sigma_m = if cos_sq_alpha == 0_f64 {
+            0_f64
+        } else {
+            cos_sigma - 2_f64 * sin_u1 * sin_u2 / cos_sq_alpha
+        };
+        let c = FLATTENING / 16_f64 * cos_sq_alpha * (4_f64 + FLATTENING * (4_f64 - 3_f64 * cos_sq_alpha));
+        let previous = lambda;
+        lambda = difference + (1_f64 - c) * FLATTENING * sin_alpha * (
+            sigma + c * sin_sigma * (cos_2sigma_m + c * cos_sigma * (-1_f64 + 2_f64 * cos_2sigma_m.powi(2)))
+        );
+        if (lambda - previous).abs() < 1e-12 {
+            let u_sq = cos_sq_alpha * (SEMI_MAJOR_AXIS.powi(2) - semi_minor_axis.powi(2)) / semi_minor_axis.powi(2);
+            let a = 1_f64 + u_sq / 16384_f64 * (4096_f64 + u_sq * (-768_f64 + u_sq * (320_f64 - 175_f64 * u_sq)));
+            let b = u_sq / 1024_f64 * (256_f64 + u_sq * (-128_f64 + u_sq * (74_f64 - 47_f64 * u_sq)));
+            let delta_sigma = b * sin_sigma * (
+                cos_2sigma_m + b / 4_f64 * (
+                    cos_sigma * (-1_f64 + 2_f64 * cos_2sigma_m.powi(2))
+                        - b / 6_f64 * cos_2sigma_m * (-3_f64 + 4_f64 * sin_sigma.powi(2))
+                        * (-3_f64 + 4_f64 * cos_2sigma_m.powi(2))
+                )
+            );
+            return semi_minor_axis * a * (sigma - delta_sigma)
+        }
+    }
+    let (start_latitude, end_latitude) = (start.1.to_radians(), end.1.to_radians());
+    let haversine = ((end_latitude - start_latitude) / 2_f64).sin().powi(2)
+        + start_latitude.cos() * end_latitude.cos() * (difference / 2_f64).sin().powi(2);
+    2_f64 * MEAN_RADIUS * haversine.sqrt().asin()
+}
+
+/// `q` of the authalic latitude conversion for a geodetic latitude in radians.
+fn authalic_q(latitude: f64) -> f64 {
+    let eccentricity = (FLATTENING * (2_f64 - FLATTENING)).sqrt();
+    let sin = latitude.sin();
+    (1_f64 - eccentricity.powi(2)) * (
+        sin / (1_f64 - (eccentricity * sin).powi(2))
+            - ((1_f64 - eccentricity * sin) / (1_f64 + eccentricity * sin)).ln() / (2_f64 * eccentricity)
+    )
+}
+
+/// Signed area in square meters of a ring of longitude/latitude positions on the WGS84
+/// ellipsoid, computed on the authalic sphere (same surface area) with authalic latitudes.
+fn ring_area(ring: &[(f64, f64)]) -> f64 {
+    let q_pole = authalic_q(std::f64::consts::FRAC_PI_2);
+    let radius_sq = SEMI_MAJOR_AXIS.powi(2) * q_pole / 2_f64;
+    let count = ring.len();
+    let sum: f64 = (0..count)
+        .map(|index| {
+            let previous = ring[(index + count - 1) % count];
+            let next = ring[(index + 1) % count];
+            let sin_authalic = authalic_q(ring[index].1.to_radians()) / q_pole;
+            (next.0 - previous.0).to_radians() * sin_authalic
+        })
+        .sum();
+    sum * radius_sq / 2_f64
+}
+
+/// Longitude/latitude parts of an Esri JSON geometry under `key`, None when a position cannot
+/// be projected.
+fn parts(geometry: &Map<String, Value>, key: &str, spatial_reference: i64) -> Option<Vec<Vec<(f64, f64)>>> {
+    geometry.get(key)?
+        .as_array()?
+        .iter()
+        .map(|part| {
+            part.as_array()?
+                .iter()
+                .map(|position| {
+                    let position = position.as_array()?;
+                    let (x, y) = (position.first()?.as_f64()?, position.get(1)?.as_f64()?);
+                    transform_point(x, y, spatial_reference, WGS84)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Computes the measure of each feature of a layer.
+#[derive(Debug, Clone)]
+pub(crate) struct Measurer {
+    pub(crate) measure: Measure,
+    /// Spatial reference of the geometries, which must project to WGS84 client-side. Features
+    /// of layers without one are not measured
+    spatial_reference: Option<i64>,
+}
+
+impl Measurer {
+    pub(crate) fn new(measure: Measure, spatial_reference: Option<i64>) -> Self {
+        Self { measure, spatial_reference }
+    }
+
+    /// True when the geometries can be projected to WGS84 to be measured.
+    pub(crate) fn is_supported(&self) -> bool {
+        self.spatial_reference
+            .and_then(|wkid| transform_point(0_f64, 0_f64, wkid, WGS84))
+            .is_some()
+    }
+
+    /// Measurement of an Esri JSON feature in the unit of the measure, None when it has no
+    /// geometry. Holes are subtracted since Esri winds them opposite to exterior rings.
+    pub(crate) fn measure(&self, feature: &Map<String, Value>) -> Option<f64> {
+        let geometry = feature.get("geometry")?.as_object()?;
+        let spatial_reference = self.spatial_reference?;
+        match &self.measure {
+            Measure::Area(unit) => {
+                let rings = match parts(geometry, "rings", spatial_reference) {
+                    Some(rings) => rings,
+                    None => {
+                        let x_min = geometry.get("xmin")?.as_f64()?;
+                        let y_min = geometry.get("ymin")?.as_f64()?;
+                        let x_max = geometry.get("xmax")?.as_f64()?;
+                        let y_max = geometry.get("ymax")?.as_f64()?;
+                        let ring = [(x_min, y_min), (x_min, y_max), (x_max, y_max), (x_max, y_min)]
+                            .iter()
+                            .map(|(x, y)| transform_point(*x, *y, spatial_reference, WGS84))
+                            .collect::<Option<Vec<(f64, f64)>>>()?;
+                        vec![ring]
+                    }
+                };
+                let area: f64 = rings.into_iter()
+                    .map(|mut ring| {
+                        if ring.len() > 1 && ring.first() == ring.last() {
+                            ring.pop();
+                        }
+                        ring_area(&ring)
+                    })
+                    .sum();
+                Some(area.abs() / unit.square_meters())
+            }
+            Measure::Length(unit) => {
+                let length: f64 = parts(geometry, "paths", spatial_reference)?
+                    .iter()
+                    .flat_map(|path| path.iter().zip(path.iter().skip(1)))
+                    .map(|(start, end)| geodesic_distance(*start, *end))
+                    .sum();
+                Some(length / unit.meters())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod measure_tests {
+    use serde_json::json;
+    use super::{geodesic_distance, AreaUnit, Measure, Measurer};
+
+    #[test]
+    fn geodesic_distance_should_match_vincenty_reference_when_passed_flinders_peak() {
+        let flinders_peak = (144.0 + 25.0 / 60.0 + 29.5244 / 3600.0, -(37.0 + 57.0 / 60.0 + 3.7203 / 3600.0));
+        let buninyong = (143.0 + 55.0 / 60.0 + 35.3839 / 3600.0, -(37.0 + 39.0 / 60.0 + 10.1561 / 3600.0));
+        assert!((geodesic_distance(flinders_peak, buninyong) - 54_972.271).abs() < 0.001);
+    }
+
+    #[test]
+    fn measure_should_subtract_hole_when_passed_polygon() {
+        let measurer = Measurer::new(Measure::Area(AreaUnit::SquareKilometers), Some(4326));
+        let feature = json!({"geometry": {"rings": [
+            [[0, 0], [0, 1], [1, 1], [1, 0], [0, 0]],
+            [[0, 0], [0.5, 0], [0.5, 1], [0, 1], [0, 0]],
+        ]}});
+        let area = measurer.measure(feature.as_object().unwrap()).unwrap();
+        assert!((area - 12_308.778 / 2.0).abs() < 0.5, "{}", area);
+    }
+}
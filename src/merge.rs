@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use tablestream::{Stream, col, Column};
+use crate::metadata::{RestServiceField, RestServiceFieldType, RestServiceGeometryType, RestServiceMetadata};
+
+/// Name of the column identifying which layer a merged record was scraped from.
+pub(crate) const SOURCE_LAYER_COLUMN: &str = "source_layer";
+
+/// Ordered union of the column names of every layer, first appearance wins. The source layer
+/// column always comes first.
+pub(crate) fn union_columns(layer_columns: &[Vec<String>]) -> Vec<String> {
+    let mut columns = vec![SOURCE_LAYER_COLUMN.to_owned()];
+    for layer in layer_columns {
+        for column in layer {
+            if !columns.iter().any(|existing| existing.eq_ignore_ascii_case(column)) {
+                columns.push(column.to_owned());
+            }
+        }
+    }
+    columns
+}
+
+/// Split layers into the ones that can share a merged output (same geometry type as the first
+/// layer) and the ones that must be written on their own.
+pub(crate) fn partition_compatible(
+    layers: Vec<RestServiceMetadata>,
+) -> (Vec<RestServiceMetadata>, Vec<RestServiceMetadata>) {
+    let geo_type: Option<RestServiceGeometryType> = layers.first()
+        .map(|layer| layer.geo_type.clone());
+    layers.into_iter()
+        .partition(|layer| Some(&layer.geo_type) == geo_type.as_ref())
+}
+
+/// A field of the merged output after reconciling every layer that contains it.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MergedField {
+    pub(crate) name: String,
+    pub(crate) field_type: RestServiceFieldType,
+    pub(crate) length: Option<i64>,
+    pub(crate) layer_count: usize,
+}
+
+/// How a field that differs between layers was reconciled.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum SchemaResolution {
+    Promoted(String, RestServiceFieldType, RestServiceFieldType),
+    Incompatible(String, RestServiceFieldType, RestServiceFieldType),
+    Widened(String, i64),
+}
+
+impl Display for SchemaResolution {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchemaResolution::Promoted(name, from, to) => {
+                write!(f, "{} promoted from {} to {}", name, from, to)
+            }
+            SchemaResolution::Incompatible(name, first, second) => {
+                write!(f, "{} is {} and {} in different layers, stored as text", name, first, second)
+            }
+            SchemaResolution::Widened(name, length) => {
+                write!(f, "{} lengths differ, widened to {}", name, length)
+            }
+        }
+    }
+}
+
+impl SchemaResolution {
+    pub(crate) fn is_incompatible(&self) -> bool {
+        matches!(self, SchemaResolution::Incompatible(..))
+    }
+}
+
+/// Numeric width of a field type, as (rank, is floating point). None for non numeric types.
+fn numeric_rank(field_type: &RestServiceFieldType) -> Option<(u8, bool)> {
+    match field_type {
+        RestServiceFieldType::SmallInteger => Some((0, false)),
+        RestServiceFieldType::Integer | RestServiceFieldType::OID => Some((1, false)),
+        RestServiceFieldType::Single | RestServiceFieldType::Float => Some((2, true)),
+        RestServiceFieldType::Double => Some((3, true)),
+        _ => None,
+    }
+}
+
+/// Smallest type able to hold values of both `first` and `second`, or None when the types have
+/// nothing in common and the values can only be kept as text.
+pub(crate) fn promote_types(
+    first: &RestServiceFieldType,
+    second: &RestServiceFieldType,
+) -> Option<RestServiceFieldType> {
+    if first == second {
+        return Some(first.to_owned())
+    }
+    match (numeric_rank(first), numeric_rank(second)) {
+        (Some((first_rank, first_float)), Some((second_rank, second_float))) => {
+            let promoted = match (first_rank.max(second_rank), first_float || second_float) {
+                // 32-bit floats cannot represent every 32-bit integer
+                (2, true) if first_rank == 1 || second_rank == 1 => RestServiceFieldType::Double,
+                (0, _) => RestServiceFieldType::SmallInteger,
+                (1, _) => RestServiceFieldType::Integer,
+                (2, _) => RestServiceFieldType::Single,
+                _ => RestServiceFieldType::Double,
+            };
+            Some(promoted)
+        }
+        _ => match (first, second) {
+            (RestServiceFieldType::GUID, RestServiceFieldType::GlobalID)
+            | (RestServiceFieldType::GlobalID, RestServiceFieldType::GUID) => {
+                Some(RestServiceFieldType::GUID)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Union of the attribute fields of every layer (matched case-insensitively, first appearance
+/// wins the position) with type conflicts reconciled by [`promote_types`].
+pub(crate) fn union_fields(
+    layer_fields: &[&[RestServiceField]],
+) -> (Vec<MergedField>, Vec<SchemaResolution>) {
+    let mut merged: Vec<MergedField> = vec![];
+    let mut resolutions = vec![];
+    for fields in layer_fields {
+        for field in fields.iter().filter(|field| field.field_type != RestServiceFieldType::Geometry) {
+            let existing = merged.iter_mut()
+                .find(|merged_field| merged_field.name.eq_ignore_ascii_case(&field.name));
+            let merged_field = match existing {
+                Some(merged_field) => merged_field,
+                None => {
+                    merged.push(MergedField {
+                        name: field.name.to_owned(),
+                        field_type: field.field_type.to_owned(),
+                        length: field.length,
+                        layer_count: 1,
+                    });
+                    continue
+                }
+            };
+            merged_field.layer_count += 1;
+            if merged_field.field_type != field.field_type {
+                let previous = merged_field.field_type.to_owned();
+                match promote_types(&previous, &field.field_type) {
+                    Some(promoted) => {
+                        if promoted != previous {
+                            resolutions.push(
+                                SchemaResolution::Promoted(merged_field.name.to_owned(), previous, promoted.to_owned())
+                            );
+                        }
+                        merged_field.field_type = promoted;
+                    }
+                    None => {
+                        if previous != RestServiceFieldType::String {
+                            resolutions.push(
+                                SchemaResolution::Incompatible(
+                                    merged_field.name.to_owned(),
+                                    previous,
+                                    field.field_type.to_owned(),
+                                )
+                            );
+                        }
+                        merged_field.field_type = RestServiceFieldType::String;
+                    }
+                }
+            }
+            if let (Some(current), Some(length)) = (merged_field.length, field.length) {
+                if length > current {
+                    merged_field.length = Some(length);
+                    resolutions.push(SchemaResolution::Widened(merged_field.name.to_owned(), length));
+                }
+            }
+        }
+    }
+    (merged, resolutions)
+}
+
+pub(crate) fn write_schema_to_console(
+    fields: &[MergedField],
+    resolutions: &[SchemaResolution],
+    layer_total: usize,
+) -> io::Result<()> {
+    println!("Merged Schema:");
+    let mut out = io::stdout();
+    let mut stream = Stream::new(
+        &mut out,
+        vec![
+            col!(MergedField: .name).header("Name"),
+            col!(MergedField: .field_type).header("Type"),
+            Column::new(|f, c: &MergedField| {
+                match c.length {
+                    Some(length) => write!(f, "{}", length),
+                    None => write!(f, ""),
+                }
+            }).header("Length"),
+            Column::new(move |f, c: &MergedField| {
+                write!(f, "{}/{}", c.layer_count, layer_total)
+            }).header("Layers"),
+        ],
+    );
+    for field in fields {
+        stream.row(field.to_owned())?;
+    }
+    stream.finish()?;
+    for resolution in resolutions.iter().filter(|resolution| !resolution.is_incompatible()) {
+        println!("{}", resolution);
+    }
+    Ok(())
+}
+
+/// Maps the records of a single layer onto the merged column layout, filling columns the layer
+/// does not have with empty values.
+#[derive(Debug, Clone)]
+pub(crate) struct MergeLayout {
+    source_layer: String,
+    positions: Vec<Option<usize>>,
+}
+
+impl MergeLayout {
+    pub(crate) fn new(
+        source_layer: &str,
+        layer_columns: &[String],
+        merged_columns: &[String],
+    ) -> Self {
+        let indexes: HashMap<String, usize> = layer_columns.iter()
+            .enumerate()
+            .map(|(i, column)| (column.to_uppercase(), i))
+            .collect();
+        let positions = merged_columns.iter()
+            .skip(1)
+            .map(|column| indexes.get(&column.to_uppercase()).copied())
+            .collect();
+        Self {
+            source_layer: source_layer.to_owned(),
+            positions,
+        }
+    }
+
+    pub(crate) fn arrange(&self, mut record: Vec<String>) -> Vec<String> {
+        let mut arranged = Vec::with_capacity(self.positions.len() + 1);
+        arranged.push(self.source_layer.to_owned());
+        for position in &self.positions {
+            let value = position
+                .and_then(|i| record.get_mut(i))
+                .map(std::mem::take)
+                .unwrap_or_default();
+            arranged.push(value);
+        }
+        arranged
+    }
+}
+
+#[cfg(test)]
+mod merge_tests {
+    use crate::metadata::RestServiceFieldType;
+    use super::{promote_types, union_columns, MergeLayout};
+
+    fn columns(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn union_columns_should_keep_first_seen_order_when_layers_overlap() {
+        let result = union_columns(&[
+            columns(&["OBJECTID", "NAME", "RINGS"]),
+            columns(&["objectid", "DISTRICT", "RINGS"]),
+        ]);
+        assert_eq!(result, columns(&["source_layer", "OBJECTID", "NAME", "RINGS", "DISTRICT"]));
+    }
+
+    #[test]
+    fn arrange_should_fill_missing_columns_when_layer_lacks_them() {
+        let merged = columns(&["source_layer", "OBJECTID", "NAME", "RINGS", "DISTRICT"]);
+        let layout = MergeLayout::new("District 2", &columns(&["OBJECTID", "DISTRICT", "RINGS"]), &merged);
+        let result = layout.arrange(columns(&["7", "North", "[]"]));
+        assert_eq!(result, columns(&["District 2", "7", "", "[]", "North"]));
+    }
+
+    #[test]
+    fn promote_types_should_return_double_when_passed_integer_and_single() {
+        let result = promote_types(&RestServiceFieldType::Integer, &RestServiceFieldType::Single);
+        assert_eq!(result, Some(RestServiceFieldType::Double));
+    }
+
+    #[test]
+    fn promote_types_should_return_integer_when_passed_small_integer_and_integer() {
+        let result = promote_types(&RestServiceFieldType::SmallInteger, &RestServiceFieldType::Integer);
+        assert_eq!(result, Some(RestServiceFieldType::Integer));
+    }
+
+    #[test]
+    fn promote_types_should_return_none_when_passed_date_and_double() {
+        let result = promote_types(&RestServiceFieldType::Date, &RestServiceFieldType::Double);
+        assert_eq!(result, None);
+    }
+}
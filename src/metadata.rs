@@ -1,17 +1,48 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashSet};
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::fs::File;
 use std::io;
+use std::io::{BufReader, Write};
+use std::path::Path;
+use serde::{Serialize, Serializer};
 use serde_json::{json, Value};
+use regex::{Regex, RegexBuilder};
 use reqwest::Url;
 use tablestream::{Stream, col, Column};
+use crate::client::ServiceClient;
+use crate::filter::{BoundingBox, QueryFilter};
+use crate::geometry::Extent;
+use crate::projection;
+
+/// Most chunk queries planned for a layer. Layers needing more report a count or OID range no
+/// real service has, and planning them would exhaust memory.
+const MAX_CHUNK_COUNT: i64 = 1_000_000;
+/// Percentiles requested by a single statistics query, keeping its URL reasonably short
+const PERCENTILES_PER_QUERY: usize = 50;
 
 #[derive(Debug, PartialEq)]
-pub(crate) enum RestServiceMetadataError {
+pub enum RestServiceMetadataError {
     FieldParsing(String, String),
     FieldTypeParsing(String),
     MissingKey(String),
     MissingOidField,
+    MissingSpatialReference,
+    InvalidMaxRecordCount(i64),
+    InvalidCount(i64),
+    /// Max and min OID reported by the layer that do not form a range
+    InvalidOidRange(i64, i64),
+    TooManyChunks(i64),
+    /// Option describing a single layer (e.g. --metadata-file) with several layers to scrape
+    SingleLayerOption(&'static str, usize),
+    /// --override-oid-range that is not MIN:MAX
+    OidRangeParsing(String),
+    /// --where clause the server rejected, with its error message
+    InvalidWhereClause(String, String),
+    /// Layer without an edit date field to scrape the features edited since a time from
+    NoEditField(String),
+    /// --edit-field that is not a date field of the layer, with the layer name
+    UnknownEditField(String, String),
 }
 
 impl Display for RestServiceMetadataError {
@@ -29,12 +60,127 @@ impl Display for RestServiceMetadataError {
             RestServiceMetadataError::MissingOidField => {
                 write!(f, "Referenced missing OID field")
             }
+            RestServiceMetadataError::MissingSpatialReference => {
+                write!(f, "No source spatial reference and no output spatial reference specified")
+            }
+            RestServiceMetadataError::InvalidMaxRecordCount(max_record_count) => {
+                write!(f, "Invalid maxRecordCount of {}, it must be positive", max_record_count)
+            }
+            RestServiceMetadataError::InvalidCount(count) => {
+                write!(f, "Invalid feature count of {}", count)
+            }
+            RestServiceMetadataError::InvalidOidRange(max_oid, min_oid) => {
+                write!(f, "Invalid OID range, max OID {} is not above min OID {}", max_oid, min_oid)
+            }
+            RestServiceMetadataError::OidRangeParsing(value) => {
+                write!(f, "Invalid OID range \"{}\", expected MIN:MAX with MIN not above MAX", value)
+            }
+            RestServiceMetadataError::InvalidWhereClause(where_clause, message) => {
+                write!(f, "The server rejected the where clause \"{}\". {}", where_clause, message)
+            }
+            RestServiceMetadataError::NoEditField(name) => {
+                write!(f, "Layer \"{}\" reports no edit date field (editFieldsInfo), pass it with --edit-field", name)
+            }
+            RestServiceMetadataError::UnknownEditField(field, name) => {
+                write!(f, "Layer \"{}\" has no date field \"{}\"", name, field)
+            }
+            RestServiceMetadataError::SingleLayerOption(option, count) => {
+                write!(f, "{} describes a single layer but {} layers would be scraped", option, count)
+            }
+            RestServiceMetadataError::TooManyChunks(chunk_count) => {
+                write!(f, "Scraping would take {} chunk queries, more than the limit of {}", chunk_count, MAX_CHUNK_COUNT)
+            }
         }
     }
 }
 
 impl Error for RestServiceMetadataError {}
 
+/// Parse the `--show-fields` pattern, a regex matched case-insensitively like ArcGIS field names.
+pub(crate) fn parse_field_pattern(value: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(value).case_insensitive(true).build()
+}
+
+/// Parse the `MIN:MAX` value of `--override-oid-range` into the max and min OID.
+pub(crate) fn parse_oid_range(value: &str) -> Result<(i64, i64), RestServiceMetadataError> {
+    let invalid = || RestServiceMetadataError::OidRangeParsing(value.to_owned());
+    let (min_oid, max_oid) = value.split_once(':').ok_or_else(invalid)?;
+    let min_oid: i64 = min_oid.trim().parse().map_err(|_| invalid())?;
+    let max_oid: i64 = max_oid.trim().parse().map_err(|_| invalid())?;
+    if min_oid > max_oid {
+        return Err(invalid())
+    }
+    Ok((max_oid, min_oid))
+}
+
+/// Object id held by a JSON value. Servers send 64-bit object ids as integers, but statistics
+/// responses may send them as doubles and some proxies as strings.
+pub(crate) fn object_id(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(number) => number.as_i64().or_else(|| {
+            number.as_f64()
+                .filter(|number| number.fract() == 0.0 && number.abs() < i64::MAX as f64)
+                .map(|number| number as i64)
+        }),
+        Value::String(text) => text.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Values replacing the ones provided by the server (or a metadata file), for servers reporting
+/// wrong values or failing to answer the count and statistics queries. Overridden values are
+/// never requested.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MetadataOverrides {
+    pub(crate) count: Option<i64>,
+    /// Max and min OID
+    pub(crate) max_min_oid: Option<(i64, i64)>,
+    pub(crate) max_record_count: Option<i64>,
+}
+
+impl MetadataOverrides {
+    /// Replace the values of the layer, logging every value overridden.
+    fn apply(&self, layer: &mut RestServiceMetadata) {
+        if let Some(count) = self.count {
+            tracing::info!(
+                layer = %layer.name,
+                "Overriding the feature count of \"{}\" with {} (was {})",
+                layer.name,
+                count,
+                describe_value(layer.source_count),
+            );
+            layer.source_count = Some(count);
+        }
+        if let Some((max_oid, min_oid)) = self.max_min_oid {
+            tracing::info!(
+                layer = %layer.name,
+                "Overriding the OID range of \"{}\" with {}:{} (was {})",
+                layer.name,
+                min_oid,
+                max_oid,
+                describe_value(layer.max_min_oid.map(|(max_oid, min_oid)| format!("{}:{}", min_oid, max_oid))),
+            );
+            layer.max_min_oid = Some((max_oid, min_oid));
+        }
+        if let Some(max_record_count) = self.max_record_count {
+            tracing::info!(
+                layer = %layer.name,
+                "Overriding the maxRecordCount of \"{}\" with {} (was {})",
+                layer.name,
+                max_record_count,
+                layer.max_record_count,
+            );
+            layer.max_record_count = max_record_count;
+        }
+    }
+}
+
+/// Value replaced by an override, the count and OID range are only known when they came from a
+/// metadata file.
+fn describe_value<T: Display>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_else(|| "not requested".to_owned())
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub(crate) enum RestServiceGeometryType {
     Point,
@@ -58,6 +204,12 @@ impl Display for RestServiceGeometryType {
     }
 }
 
+impl Serialize for RestServiceGeometryType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
 impl RestServiceGeometryType {
     pub(crate) fn from_str(
         geo_type: &str
@@ -78,6 +230,7 @@ impl RestServiceGeometryType {
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub(crate) enum RestServiceFieldType {
     Blob,
     Date,
@@ -116,6 +269,12 @@ impl Display for RestServiceFieldType {
     }
 }
 
+impl Serialize for RestServiceFieldType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
 impl RestServiceFieldType {
     pub(crate) fn from_str(
         field_type: &str,
@@ -259,55 +418,69 @@ mod rest_service_field_type_tests {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub(crate) struct RestServiceField {
     pub(crate) name: String,
     pub(crate) field_type: RestServiceFieldType,
     alias: String,
-    pub(crate) codes: Option<HashMap<String, String>>,
+    pub(crate) length: Option<i64>,
+    pub(crate) codes: Option<BTreeMap<String, String>>,
+    /// Minimum and maximum of the field's range domain
+    pub(crate) range: Option<(f64, f64)>,
+}
+
+/// Bounds of a range domain, None for other domains or ranges without two numeric bounds.
+fn parse_range_domain(domain_value: &Value) -> Option<(f64, f64)> {
+    if domain_value["type"].as_str() != Some("range") {
+        return None
+    }
+    match domain_value["range"].as_array().map(Vec::as_slice) {
+        Some([min, max]) => Some((min.as_f64()?, max.as_f64()?)),
+        _ => None,
+    }
 }
 
 fn parse_domain(
     domain_value: &Value,
-) -> Result<Option<HashMap<String, String>>, RestServiceMetadataError> {
+) -> Result<Option<BTreeMap<String, String>>, RestServiceMetadataError> {
     match domain_value {
-        Value::Object(domain) => {
-            let is_code = domain["type"].as_str().unwrap_or_default() == "codedValue";
+        Value::Object(_) => {
+            let is_code = domain_value["type"].as_str().unwrap_or_default() == "codedValue";
             if !is_code {
                 return Ok(None)
             }
-            let coded_values = domain["codedValues"].as_array()
+            let coded_values = domain_value["codedValues"].as_array()
                 .ok_or(
                     RestServiceMetadataError::FieldParsing(
                         "codedValues value is not an object".to_owned(),
-                        domain["codedValues"].to_string().to_owned(),
+                        domain_value["codedValues"].to_string().to_owned(),
                     )
                 )?;
-            let mut codes = HashMap::new();
+            let mut codes = BTreeMap::new();
             for coded_value in coded_values {
-                let coded_value_obj = coded_value.as_object()
+                coded_value.as_object()
                     .ok_or(
                         RestServiceMetadataError::FieldParsing(
                             "codedValues value is not an object".to_owned(),
-                            domain["codedValues"].to_string().to_owned(),
+                            domain_value["codedValues"].to_string().to_owned(),
                         )
                     )?;
-                let code = match &coded_value_obj["code"] {
+                let code = match &coded_value["code"] {
                     Value::Number(num) => num.to_string(),
                     Value::String(string) => string.to_owned(),
                     _ => return Err(
                         RestServiceMetadataError::FieldParsing(
                             "Expected Number or String for code Value".to_owned(),
-                            coded_value_obj["code"].to_string(),
+                            coded_value["code"].to_string(),
                         )
                     ),
                 };
-                let name = match &coded_value_obj["name"] {
+                let name = match &coded_value["name"] {
                     Value::String(string) => string.to_owned(),
                     _ => return Err(
                         RestServiceMetadataError::FieldParsing(
                             "Expected String for name Value".to_owned(),
-                            coded_value_obj["code"].to_string(),
+                            coded_value["code"].to_string(),
                         )
                     ),
                 };
@@ -352,67 +525,242 @@ impl RestServiceField {
             name: field_name.to_owned(),
             field_type: field_type_enum,
             alias: field_alias.to_owned(),
+            length: field["length"].as_i64(),
             codes,
+            range: parse_range_domain(domain_value),
         };
         Ok(result)
     }
 
-    fn for_geometry(name: &str) -> RestServiceField {
+    pub(crate) fn for_geometry(name: &str) -> RestServiceField {
         RestServiceField {
             name: name.to_owned(),
             field_type: RestServiceFieldType::Geometry,
             alias: name.to_owned(),
-            codes: None
+            length: None,
+            codes: None,
+            range: None,
         }
     }
 }
 
-#[derive(Debug)]
-pub(crate) struct RestServiceMetadata {
-    url: String,
+#[derive(Debug, Clone, Serialize)]
+pub struct RestServiceMetadata {
+    pub(crate) url: String,
     pub(crate) name: String,
+    #[serde(rename = "feature_count")]
     source_count: Option<i64>,
     max_record_count: i64,
     pagination_enabled: bool,
+    stats_enabled: bool,
+    /// The layer reports `supportsPercentileStatistics`
+    percentiles_enabled: bool,
+    /// The layer lists PBF in its supported query formats
+    pbf_enabled: bool,
+    /// The layer reports `hasAttachments`
+    attachments_enabled: bool,
     server_type: String,
+    /// Description and copyright text of the layer, None when blank
+    description: Option<String>,
+    copyright_text: Option<String>,
     pub(crate) geo_type: RestServiceGeometryType,
     pub(crate) fields: Vec<RestServiceField>,
     oid_field: Option<RestServiceField>,
     max_min_oid: Option<(i64, i64)>,
+    relationships: Vec<LayerRelationship>,
+    /// Why the OIDs of the layer are taken as virtual, i.e. generated per request by a query
+    /// layer and not stable between queries. None when they are stable
+    virtual_oids: Option<String>,
+    /// Fields sent as outFields, every field when None
+    out_fields: Option<Vec<String>>,
     source_spatial_reference: Option<i64>,
+    /// Esri WKT of the layer's spatial reference, when the metadata holds one
+    source_spatial_reference_wkt: Option<String>,
     output_spatial_reference: Option<i64>,
+    pub(crate) extent: Option<LayerExtent>,
+    #[serde(skip)]
+    pub(crate) filter: QueryFilter,
+    #[serde(skip)]
+    pub(crate) client: ServiceClient,
+}
+
+/// Field of a layer with the alias shown for it by ArcGIS clients.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct FieldAlias {
+    pub(crate) name: String,
+    pub(crate) alias: String,
+}
+
+/// Descriptive metadata of a scraped layer carried into the outputs, since the copyright text
+/// of a source has to accompany every distribution of its data.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct LayerDescription {
+    pub(crate) name: String,
+    pub(crate) url: String,
+    pub(crate) description: Option<String>,
+    pub(crate) copyright_text: Option<String>,
+    pub(crate) fields: Vec<FieldAlias>,
+}
+
+/// Relationship of a layer to another layer or table of its service, whose related records are
+/// queried with `queryRelatedRecords`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct LayerRelationship {
+    pub(crate) id: i64,
+    pub(crate) name: String,
+    pub(crate) related_table_id: Option<i64>,
+}
+
+impl LayerRelationship {
+    fn from_json(relationship: &Value) -> Option<LayerRelationship> {
+        Some(LayerRelationship {
+            id: relationship["id"].as_i64()?,
+            name: relationship["name"].as_str()?.to_owned(),
+            related_table_id: relationship["relatedTableId"].as_i64(),
+        })
+    }
+}
+
+/// Extent of the layer's features and the spatial reference its bounds are expressed in.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct LayerExtent {
+    pub(crate) bounds: Extent,
+    pub(crate) spatial_reference: Option<i64>,
+}
+
+impl LayerExtent {
+    fn from_esri_json(extent: &Value) -> Option<LayerExtent> {
+        let spatial_reference = &extent["spatialReference"];
+        Some(LayerExtent {
+            bounds: Extent::from_esri_json(extent)?,
+            spatial_reference: spatial_reference["latestWkid"]
+                .as_i64()
+                .or_else(|| spatial_reference["wkid"].as_i64()),
+        })
+    }
 }
 
 impl RestServiceMetadata {
-    fn scrape_count(&self) -> i64 {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Lower the maxRecordCount to `count`, never raising it.
+    pub(crate) fn limit_max_record_count(&mut self, count: i64) {
+        self.max_record_count = self.max_record_count.min(count);
+    }
+
+    pub(crate) fn scrape_count(&self) -> i64 {
         if self.max_record_count <= 10000 { self.max_record_count } else { 10000 }
     }
 
-    fn is_table(&self) -> bool {
-        self.server_type == "TABLE"
+    /// Description of the layer, often HTML.
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
     }
 
-    fn incremental_oid(&self) -> bool {
-        if self.oid_field.is_none() {
-            return false;
+    /// Copyright text of the layer, the attribution its data must be distributed with.
+    pub fn copyright_text(&self) -> Option<&str> {
+        self.copyright_text.as_deref()
+    }
+
+    pub(crate) fn layer_description(&self) -> LayerDescription {
+        LayerDescription {
+            name: self.name.to_owned(),
+            url: self.url.to_owned(),
+            description: self.description.to_owned(),
+            copyright_text: self.copyright_text.to_owned(),
+            fields: self.fields.iter()
+                .filter(|field| field.field_type != RestServiceFieldType::Geometry)
+                .map(|field| FieldAlias { name: field.name.to_owned(), alias: field.alias.to_owned() })
+                .collect(),
         }
-        if let Some(max_min) = self.max_min_oid {
-            let difference = max_min.0 - max_min.1 + 1;
-            self.source_count.map(|count| difference == count).unwrap_or(false)
-        } else {
-            false
+    }
+
+    /// Only request and write the fields named in `names` (case-insensitive). The OID field is
+    /// always requested since deduplication and checkpoints rely on it, but only written when
+    /// selected.
+    pub(crate) fn select_fields(&mut self, names: &[String]) {
+        let selected: HashSet<String> = names.iter()
+            .map(|name| name.trim().to_uppercase())
+            .collect();
+        self.fields.retain(|field| {
+            field.field_type == RestServiceFieldType::Geometry
+                || selected.contains(&field.name.to_uppercase())
+        });
+        let mut out_fields: Vec<String> = self.fields.iter()
+            .filter(|field| field.field_type != RestServiceFieldType::Geometry)
+            .map(|field| field.name.to_owned())
+            .collect();
+        if let Some(oid_field) = &self.oid_field {
+            if !out_fields.contains(&oid_field.name) {
+                out_fields.push(oid_field.name.to_owned());
+            }
         }
+        self.out_fields = Some(out_fields);
     }
 
-    fn pagination_query(&self, query_index: i64) -> Result<String, Box<dyn Error + Send + Sync>> {
+    fn out_fields_param(&self) -> String {
+        match &self.out_fields {
+            Some(fields) => fields.join(","),
+            None => String::from("*"),
+        }
+    }
+
+    pub fn is_table(&self) -> bool {
+        self.server_type == "TABLE"
+    }
+
+    pub fn supports_pagination(&self) -> bool {
+        self.pagination_enabled
+    }
+
+    pub fn supports_pbf(&self) -> bool {
+        self.pbf_enabled
+    }
+
+    /// Why the OIDs of the layer are not stable between queries, None when they are.
+    pub(crate) fn virtual_oids(&self) -> Option<&str> {
+        self.virtual_oids.as_deref()
+    }
+
+    /// Take the OIDs of the layer as virtual because of `reason`.
+    pub(crate) fn mark_virtual_oids(&mut self, reason: &str) {
+        if self.oid_field.is_some() && self.virtual_oids.is_none() {
+            self.virtual_oids = Some(reason.to_owned());
+        }
+    }
+
+    /// True when the features of the layer can have attachments, queried by OID.
+    pub(crate) fn supports_attachments(&self) -> bool {
+        self.attachments_enabled && self.oid_field.is_some()
+    }
+
+    pub(crate) fn relationships(&self) -> &[LayerRelationship] {
+        &self.relationships
+    }
+
+    pub(crate) fn supports_oid_ranges(&self) -> bool {
+        self.oid_field.is_some() && self.max_min_oid.is_some()
+    }
+
+    fn pagination_query(
+        &self,
+        query_index: i64,
+        record_count: i64,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
         let result_offset = format!("{}", query_index * self.scrape_count());
-        let result_record_count = format!("{}", self.scrape_count());
+        let result_record_count = format!("{}", record_count);
         let mut geometry_options = self.geometry_options()?;
         let mut url_params = vec![
-            ("where", String::from("1=1")),
+            ("where", self.filter.where_param()),
             ("resultOffset", result_offset),
             ("resultRecordCount", result_record_count),
-            ("outFields", String::from("*")),
+            ("outFields", self.out_fields_param()),
             ("f", String::from("json")),
         ];
         url_params.append(&mut geometry_options);
@@ -423,26 +771,34 @@ impl RestServiceMetadata {
         Ok(url.to_string())
     }
 
-    fn geometry_options(&self) -> Result<Vec<(&str, String)>, &str> {
+    fn geometry_options(&self) -> Result<Vec<(&str, String)>, RestServiceMetadataError> {
+        self.geometry_options_for(&self.filter)
+    }
+
+    fn geometry_options_for(&self, filter: &QueryFilter) -> Result<Vec<(&str, String)>, RestServiceMetadataError> {
         if self.is_table() {
-            Ok(vec![])
+            Ok(filter.dynamic_layer_params())
         } else {
             let geometry_type = self.geo_type.to_string();
             let out_spatial_reference = self.output_spatial_reference
                 .unwrap_or(
-                    self.source_spatial_reference.ok_or(
-                        "No source spatial reference and no output spatial reference specified"
-                    )?
+                    self.source_spatial_reference.ok_or(RestServiceMetadataError::MissingSpatialReference)?
                 )
                 .to_string();
-            Ok(vec![
-                ("geometryType", geometry_type),
-                ("outSR", out_spatial_reference),
-            ])
+            let mut options = filter.query_params();
+            if filter.bbox.is_none() {
+                options.push(("geometryType", geometry_type));
+            }
+            options.push(("outSR", out_spatial_reference));
+            Ok(options)
         }
     }
 
-    fn oid_query(&self, query_index: i64) -> Result<String, Box<dyn Error + Send + Sync>> {
+    fn oid_query(
+        &self,
+        query_index: i64,
+        record_count: i64,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
         let oid_field_name = self.oid_field
             .to_owned()
             .ok_or(Box::new(RestServiceMetadataError::MissingOidField))?
@@ -451,17 +807,27 @@ impl RestServiceMetadata {
             .ok_or(Box::new(RestServiceMetadataError::MissingOidField))?
             .1;
         let lower_bound = min_oid + (query_index * self.scrape_count());
-        let where_clause = format!(
+        self.oid_bounds_query(&oid_field_name, lower_bound, lower_bound.saturating_add(record_count - 1))
+    }
+
+    /// Query for the features with an object id from `lower_bound` to `upper_bound` inclusive.
+    fn oid_bounds_query(
+        &self,
+        oid_field_name: &str,
+        lower_bound: i64,
+        upper_bound: i64,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let where_clause = self.filter.where_param_and(&format!(
             "{} >= {} and {} <= {}",
             oid_field_name,
             lower_bound,
             oid_field_name,
-            lower_bound + self.scrape_count() - 1,
-        );
+            upper_bound,
+        ));
         let mut geometry_options = self.geometry_options()?;
         let mut url_params = vec![
             ("where", where_clause),
-            ("outFields", String::from("*")),
+            ("outFields", self.out_fields_param()),
             ("f", String::from("json")),
         ];
         url_params.append(&mut geometry_options);
@@ -472,81 +838,357 @@ impl RestServiceMetadata {
         Ok(url.to_string())
     }
 
-    pub(crate) fn queries(&self) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
-        if !self.pagination_enabled && self.oid_field.is_none() {
-            return Err(Box::new(RestServiceMetadataError::MissingOidField))
+    /// Query for the chunk at `query_index`, limited to the first `record_count` features of the
+    /// chunk. Uses pagination when the service supports it, otherwise OID ranges.
+    pub(crate) fn chunk_query(
+        &self,
+        query_index: i64,
+        record_count: i64,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        if self.pagination_enabled {
+            self.pagination_query(query_index, record_count)
+        } else {
+            self.oid_query(query_index, record_count)
+        }
+    }
+
+    /// Query for the features with the given object ids.
+    pub(crate) fn object_ids_query(
+        &self,
+        object_ids: &[i64],
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let object_ids = object_ids.iter()
+            .map(|object_id| object_id.to_string())
+            .collect::<Vec<String>>()
+            .join(",");
+        let mut geometry_options = self.geometry_options()?;
+        let mut url_params = vec![
+            ("where", self.filter.where_param()),
+            ("objectIds", object_ids),
+            ("outFields", self.out_fields_param()),
+            ("f", String::from("json")),
+        ];
+        url_params.append(&mut geometry_options);
+        let url = Url::parse_with_params(
+            format!("{}/query", self.url).as_str(),
+            url_params,
+        )?;
+        Ok(url.to_string())
+    }
+
+    /// Query for every feature intersecting `bbox`, used by the quadtree strategy where each
+    /// envelope is known to hold no more than one chunk of features.
+    pub(crate) fn envelope_query(
+        &self,
+        bbox: &BoundingBox,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let filter = self.filter.with_bbox(bbox);
+        let mut geometry_options = self.geometry_options_for(&filter)?;
+        let mut url_params = vec![
+            ("where", filter.where_param()),
+            ("outFields", self.out_fields_param()),
+            ("f", String::from("json")),
+        ];
+        url_params.append(&mut geometry_options);
+        let url = Url::parse_with_params(
+            format!("{}/query", self.url).as_str(),
+            url_params,
+        )?;
+        Ok(url.to_string())
+    }
+
+    pub fn oid_field_name(&self) -> Option<&str> {
+        self.oid_field.as_ref().map(|field| field.name.as_str())
+    }
+
+    pub fn spatial_reference(&self) -> Option<i64> {
+        self.source_spatial_reference
+    }
+
+    /// Spatial reference of the scraped geometries (outSR).
+    pub fn output_spatial_reference(&self) -> Option<i64> {
+        self.output_spatial_reference.or(self.source_spatial_reference)
+    }
+
+    /// Esri WKT of the spatial reference of the scraped geometries, known when they are not
+    /// projected from the layer's own spatial reference and its metadata holds the WKT.
+    pub(crate) fn output_spatial_reference_wkt(&self) -> Option<&str> {
+        let projected = match (self.output_spatial_reference, self.source_spatial_reference) {
+            (Some(output), Some(source)) => !projection::same_spatial_reference(output, source),
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        self.source_spatial_reference_wkt.as_deref().filter(|_| !projected)
+    }
+
+    pub fn feature_count(&self) -> Result<i64, RestServiceMetadataError> {
+        self.source_count
+            .ok_or(RestServiceMetadataError::MissingKey("count".to_owned()))
+    }
+
+    /// Number of chunks holding `features` features.
+    fn chunk_count(&self, features: i64) -> Result<i64, RestServiceMetadataError> {
+        if features < 0 {
+            return Err(RestServiceMetadataError::InvalidCount(features))
         }
-        let mut result: Vec<String> = vec![];
-        let mut remaining_records_count = self.source_count
-            .ok_or(Box::new(RestServiceMetadataError::MissingKey("count".to_owned())))?;
-        let mut query_index = 0_i64;
         let scrape_chunk_count = self.scrape_count();
-        while remaining_records_count > 0 {
-            if self.pagination_enabled {
-                result.push(self.pagination_query(query_index)?);
-            } else {
-                result.push(self.oid_query(query_index)?);
-            }
-            query_index += 1;
-            remaining_records_count = if remaining_records_count > scrape_chunk_count {
-                remaining_records_count - scrape_chunk_count
-            } else {
-                0
+        let chunk_count = features / scrape_chunk_count + i64::from(features % scrape_chunk_count != 0);
+        if chunk_count > MAX_CHUNK_COUNT {
+            return Err(RestServiceMetadataError::TooManyChunks(chunk_count))
+        }
+        Ok(chunk_count)
+    }
+
+    /// Number of OID range chunks needed to cover every object id between the min and max OID.
+    fn oid_range_chunk_count(&self) -> Result<i64, RestServiceMetadataError> {
+        let (max_oid, min_oid) = self.max_min_oid
+            .ok_or(RestServiceMetadataError::MissingOidField)?;
+        let oid_count = max_oid.checked_sub(min_oid)
+            .filter(|span| *span >= 0)
+            .and_then(|span| span.checked_add(1))
+            .ok_or(RestServiceMetadataError::InvalidOidRange(max_oid, min_oid))?;
+        self.chunk_count(oid_count)
+    }
+
+    /// One pagination query per chunk of the feature count.
+    pub(crate) fn pagination_queries(&self) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+        let scrape_chunk_count = self.scrape_count();
+        let chunk_count = self.chunk_count(self.feature_count()?)?;
+        (0..chunk_count)
+            .map(|query_index| self.pagination_query(query_index, scrape_chunk_count))
+            .collect()
+    }
+
+    /// OID range queries covering every object id between the min and max OID. Gaps in the
+    /// object ids (deleted or filtered out features) mean the feature count cannot tell how many
+    /// ranges are needed.
+    pub(crate) fn oid_range_queries(&self) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+        if self.oid_field.is_none() {
+            return Err(Box::new(RestServiceMetadataError::MissingOidField))
+        }
+        (0..self.oid_range_chunk_count()?)
+            .map(|query_index| self.oid_query(query_index, self.scrape_count()))
+            .collect()
+    }
+
+    /// OID range queries holding about one chunk of features each, their bounds taken from
+    /// percentiles of the layer's object ids so the gaps left by deleted or filtered out features
+    /// do not produce many near empty ranges. None when the layer cannot compute percentiles or
+    /// its object ids have too few gaps to need balancing, [`Self::oid_range_queries`] then
+    /// being as good.
+    pub(crate) async fn balanced_oid_range_queries(&self) -> Result<Option<Vec<String>>, Box<dyn Error + Send + Sync>> {
+        let (oid_field_name, (max_oid, min_oid)) = match (self.oid_field_name(), self.max_min_oid) {
+            (Some(oid_field_name), Some(max_min_oid)) => (oid_field_name, max_min_oid),
+            _ => return Err(Box::new(RestServiceMetadataError::MissingOidField)),
+        };
+        if !self.stats_enabled || !self.percentiles_enabled || self.source_count.is_none() {
+            return Ok(None)
+        }
+        let chunk_count = self.chunk_count(self.feature_count()?)?;
+        if chunk_count >= self.oid_range_chunk_count()? {
+            return Ok(None)
+        }
+        let fractions: Vec<f64> = (1..chunk_count)
+            .map(|chunk| chunk as f64 / chunk_count as f64)
+            .collect();
+        let mut boundaries = Vec::with_capacity(fractions.len());
+        for batch in fractions.chunks(PERCENTILES_PER_QUERY) {
+            let percentiles = get_service_oid_percentiles(
+                &self.client,
+                &self.url,
+                oid_field_name,
+                batch,
+                &self.filter,
+            ).await?;
+            match percentiles {
+                Some(mut percentiles) => boundaries.append(&mut percentiles),
+                None => return Ok(None),
             }
         }
-        Ok(result)
+        balanced_oid_ranges(min_oid, max_oid, boundaries)
+            .into_iter()
+            .map(|(lower_bound, upper_bound)| self.oid_bounds_query(oid_field_name, lower_bound, upper_bound))
+            .collect::<Result<Vec<String>, _>>()
+            .map(Some)
+    }
+
+    pub fn write_to_console(&self) -> io::Result<()> {
+        print!("{}", self.console_summary(FieldListing::for_console(false), None)?);
+        Ok(())
     }
 
-    pub(crate) fn write_to_console(&self) -> io::Result<()> {
-        println!("URL: {}", self.url);
-        println!("Name: {}", self.name);
-        println!("Feature Count: {}", self.source_count.unwrap_or(-1));
-        println!("Max Scrape Chunk Count: {}", self.max_record_count);
-        println!("Server Type: {}", self.server_type);
+    /// Summary of the layer shown before scraping, with its fields listed as `listing`. When
+    /// `shown_fields` is given, only fields with a name or alias matching it are listed.
+    pub(crate) fn console_summary(&self, listing: FieldListing, shown_fields: Option<&Regex>) -> io::Result<String> {
+        let mut out = Vec::new();
+        writeln!(out, "URL: {}", self.url)?;
+        writeln!(out, "Name: {}", self.name)?;
+        writeln!(out, "Feature Count: {}", self.source_count.unwrap_or(-1))?;
+        writeln!(out, "Max Scrape Chunk Count: {}", self.max_record_count)?;
+        writeln!(out, "Server Type: {}", self.server_type)?;
+        writeln!(out, "Supports PBF: {}", self.pbf_enabled)?;
+        if self.attachments_enabled {
+            writeln!(out, "Has Attachments: true (--attachments to download them)")?;
+        }
+        if !self.relationships.is_empty() {
+            writeln!(out, "Relationships: {} (--related-records to scrape them)", self.relationships.len())?;
+        }
         if !self.is_table() {
-            println!("Geometry Type: {}", self.geo_type);
-        }
-        let mut out = io::stdout();
-        let mut stream = Stream::new(
-            &mut out,
-            vec![
-                col!(RestServiceField: .name).header("Name"),
-                col!(RestServiceField: .field_type).header("Type"),
-                col!(RestServiceField: .alias).header("Alias"),
-                Column::new(|f, c: &RestServiceField| {
-                    write!(f, "{}", &c.codes.is_some())
-                }).header("Is Coded?"),
-            ],
-        );
-        for field in self.fields.iter() {
-            stream.row(field.to_owned())?;
+            writeln!(out, "Geometry Type: {}", self.geo_type)?;
+        }
+        if let Some(copyright_text) = &self.copyright_text {
+            writeln!(out, "Copyright: {}", copyright_text)?;
+        }
+        if let Some(reason) = &self.virtual_oids {
+            writeln!(
+                out,
+                "Virtual OIDs: {}, OID ranges and envelopes may return inconsistent features so only pagination and object id batches are tried",
+                reason,
+            )?;
+        }
+        let fields: Vec<&RestServiceField> = self.fields.iter()
+            .filter(|field| {
+                shown_fields.is_none_or(|pattern| pattern.is_match(&field.name) || pattern.is_match(&field.alias))
+            })
+            .collect();
+        let table = match listing {
+            FieldListing::Table(width) => Some(fields_table(&fields)?)
+                .filter(|table| table.lines().all(|line| console::measure_text_width(line) <= width)),
+            FieldListing::Plain => None,
+        };
+        match table {
+            Some(table) => out.write_all(table.as_bytes())?,
+            None => {
+                writeln!(out, "Fields:")?;
+                for field in &fields {
+                    write!(out, "  {}: {}", field.name, field.field_type)?;
+                    if field.alias != field.name {
+                        write!(out, ", alias \"{}\"", field.alias)?;
+                    }
+                    if field.codes.is_some() {
+                        write!(out, ", coded")?;
+                    }
+                    writeln!(out)?;
+                }
+            }
+        }
+        if let Some(pattern) = shown_fields {
+            writeln!(out, "{} of {} fields match --show-fields {}", fields.len(), self.fields.len(), pattern)?;
         }
-        stream.finish()?;
         if let Some(oid_field) = &self.oid_field {
-            println!("OID Field: {}", oid_field.name);
+            writeln!(out, "OID Field: {}", oid_field.name)?;
         }
         if let Some(reference) = &self.source_spatial_reference {
-            println!("Service Spatial Reference: {}", reference);
+            writeln!(out, "Service Spatial Reference: {}", reference)?;
+        }
+        if let Some(extent) = &self.extent {
+            match extent.spatial_reference {
+                Some(reference) => writeln!(out, "Extent: {} (wkid {})", extent.bounds, reference)?,
+                None => writeln!(out, "Extent: {}", extent.bounds)?,
+            }
+        }
+        if let Some(bbox) = &self.filter.bbox {
+            writeln!(out, "Bounding Box Filter: {} (wkid {})", bbox.extent, bbox.spatial_reference)?;
+        }
+        String::from_utf8(out).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+}
+
+/// Table of `fields` as wide as its content.
+fn fields_table(fields: &[&RestServiceField]) -> io::Result<String> {
+    let mut out = Vec::new();
+    let mut stream = Stream::new(
+        &mut out,
+        vec![
+            col!(RestServiceField: .name).header("Name"),
+            col!(RestServiceField: .field_type).header("Type"),
+            col!(RestServiceField: .alias).header("Alias"),
+            Column::new(|f, c: &RestServiceField| {
+                write!(f, "{}", &c.codes.is_some())
+            }).header("Is Coded?"),
+        ],
+    ).max_width(UNBOUNDED_TABLE_WIDTH).grow(false);
+    for field in fields {
+        stream.row((*field).to_owned())?;
+    }
+    stream.finish()?;
+    String::from_utf8(out).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+/// Width the fields table is laid out in, wide enough that no column of a real layer is cut.
+const UNBOUNDED_TABLE_WIDTH: usize = u16::MAX as usize;
+
+/// How the fields of a layer are listed in its console summary.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum FieldListing {
+    /// Table when it fits in this width, otherwise one line per field since cells would be cut
+    Table(usize),
+    /// One line per field, for narrow or dumb terminals
+    Plain,
+}
+
+impl FieldListing {
+    /// Listing suited to stdout. Terminals get a table when it fits their width while logs and
+    /// pipes always get one, since no terminal wraps their lines. Dumb terminals and `no_table`
+    /// get plain lines.
+    pub(crate) fn for_console(no_table: bool) -> Self {
+        if no_table || std::env::var("TERM").is_ok_and(|term| term == "dumb") {
+            return FieldListing::Plain
+        }
+        let term = console::Term::stdout();
+        match term.size_checked().filter(|_| term.features().is_attended()) {
+            Some((_, columns)) => FieldListing::Table(columns as usize),
+            None => FieldListing::Table(UNBOUNDED_TABLE_WIDTH),
         }
-        Ok(())
     }
 }
 
 #[cfg(test)]
 mod misc_tests {
+    use serde_json::json;
+    use super::{balanced_oid_ranges, object_id, parse_oid_range, RestServiceMetadataError};
 
     #[test]
     fn parse_fields_should_succeed_when_passed_valid_json_array() {
 
     }
+
+    #[test]
+    fn parse_oid_range_should_return_max_and_min_when_passed_min_colon_max() {
+        assert_eq!(parse_oid_range("1:2500"), Ok((2500, 1)));
+        assert_eq!(parse_oid_range(" -5 : 5"), Ok((5, -5)));
+        assert_eq!(parse_oid_range("7:7"), Ok((7, 7)));
+        for value in ["2500:1", "1-2500", "1:", "a:b"] {
+            assert_eq!(parse_oid_range(value), Err(RestServiceMetadataError::OidRangeParsing(value.to_owned())));
+        }
+    }
+
+    #[test]
+    fn object_id_should_keep_64_bit_values_when_passed_numbers_or_text() {
+        assert_eq!(object_id(&json!(3_000_000_000_i64)), Some(3_000_000_000));
+        assert_eq!(object_id(&json!(9_007_199_254_740_993_i64)), Some(9_007_199_254_740_993));
+        assert_eq!(object_id(&json!(3_000_000_000.0)), Some(3_000_000_000));
+        assert_eq!(object_id(&json!("4294967296")), Some(4_294_967_296));
+        assert_eq!(object_id(&json!(1.5)), None);
+        assert_eq!(object_id(&json!(null)), None);
+    }
+
+    #[test]
+    fn balanced_oid_ranges_should_end_ranges_at_percentiles_when_passed_unsorted_boundaries() {
+        assert_eq!(
+            balanced_oid_ranges(1, 100_000, vec![90_000, 10, 90_000, 500]),
+            vec![(1, 10), (11, 500), (501, 90_000), (90_001, 100_000)],
+        );
+        assert_eq!(balanced_oid_ranges(5, 10, vec![10, 2]), vec![(5, 10)]);
+    }
 }
 
-fn parse_fields(
-    fields_json: &Vec<Value>,
+pub(crate) fn parse_fields(
+    fields_json: &[Value],
     geo_type: &RestServiceGeometryType,
 ) -> Result<Vec<RestServiceField>, RestServiceMetadataError> {
     let mut fields: Vec<RestServiceField> = fields_json.iter()
-        .map(|field| RestServiceField::new(field))
+        .map(RestServiceField::new)
         .collect::<Result<Vec<RestServiceField>, RestServiceMetadataError>>()?
         .into_iter()
         .filter(|field| field.field_type != RestServiceFieldType::Geometry)
@@ -583,42 +1225,86 @@ fn advanced_options(metadata_json: &Value) -> (bool, bool) {
                 metadata_json["supportsPagination"].as_bool().unwrap_or_default()
             ),
             |advanced_query| {
-                let stats = advanced_query["supportsStatistics"]
-                    .as_bool()
+                let stats = advanced_query.get("supportsStatistics")
+                    .and_then(Value::as_bool)
                     .unwrap_or(false);
-                let pagination = advanced_query["supportsPagination"]
-                    .as_bool()
+                let pagination = advanced_query.get("supportsPagination")
+                    .and_then(Value::as_bool)
                     .unwrap_or(false);
                 (stats, pagination)
             }
         )
 }
 
-async fn get_service_count(
-    client: &reqwest::Client,
+pub(crate) async fn get_service_count(
+    client: &ServiceClient,
     url: &str,
+    filter: &QueryFilter,
 ) -> Result<Option<i64>, Box<dyn Error+ Sync + Send>> {
+    let mut url_params = vec![
+        ("where", filter.where_param()),
+        ("returnCountOnly", String::from("true")),
+        ("f", String::from("json")),
+    ];
+    url_params.append(&mut filter.query_params());
     let count_url = Url::parse_with_params(
         format!("{}/query", url).as_str(),
-        [("where", "1=1"), ("returnCountOnly", "true"), ("f", "json")],
+        url_params,
     )?;
     let count_json: Value = client.get(count_url)
+        .await?
         .send()
         .await?
         .json()
         .await?;
+    if let (Some(where_clause), Some(message)) = (filter.clause(), count_json["error"]["message"].as_str()) {
+        return Err(RestServiceMetadataError::InvalidWhereClause(where_clause, message.to_owned()).into())
+    }
     Ok(count_json["count"].as_i64())
 }
 
+/// Extent of the features actually in the layer (`returnExtentOnly`). Servers that do not
+/// support the parameter answer with an error or a body that is not JSON, both treated as no
+/// extent.
+async fn get_service_extent(
+    client: &ServiceClient,
+    url: &str,
+    filter: &QueryFilter,
+) -> Result<Option<LayerExtent>, Box<dyn Error+ Sync + Send>> {
+    let mut url_params = vec![
+        ("where", filter.where_param()),
+        ("returnExtentOnly", String::from("true")),
+        ("f", String::from("json")),
+    ];
+    url_params.append(&mut filter.dynamic_layer_params());
+    let extent_url = Url::parse_with_params(
+        format!("{}/query", url).as_str(),
+        url_params,
+    )?;
+    let response = client.get(extent_url)
+        .await?
+        .send()
+        .await?;
+    let extent_json: Value = match response.json().await {
+        Ok(extent_json) => extent_json,
+        Err(_) => return Ok(None),
+    };
+    Ok(LayerExtent::from_esri_json(&extent_json["extent"]))
+}
+
 async fn get_service_metadata(
-    client: &reqwest::Client,
+    client: &ServiceClient,
     url: &str,
+    filter: &QueryFilter,
 ) -> Result<Value, Box<dyn Error+ Sync + Send>> {
+    let mut url_params = vec![("f", String::from("json"))];
+    url_params.append(&mut filter.dynamic_layer_params());
     let metadata_url = Url::parse_with_params(
         url,
-        [("f", "json")],
+        url_params,
     )?;
     let metadata_json: Value = client.get(metadata_url)
+        .await?
         .send()
         .await?
         .json()
@@ -641,54 +1327,167 @@ fn out_statistics_parameter(oid_field_name: String) -> String {
     ]).to_string()
 }
 
+/// Name of the date field holding the last edit time of the layer's features, `requested` when
+/// given (matched case-insensitively) otherwise the edit date field of its `editFieldsInfo`.
+fn edit_date_field(
+    metadata_json: &Value,
+    fields: &[RestServiceField],
+    requested: Option<&str>,
+    layer_name: &str,
+) -> Result<String, RestServiceMetadataError> {
+    let name = match requested {
+        Some(name) => name,
+        None => metadata_json["editFieldsInfo"]["editDateField"]
+            .as_str()
+            .ok_or_else(|| RestServiceMetadataError::NoEditField(layer_name.to_owned()))?,
+    };
+    fields.iter()
+        .find(|field| field.name.eq_ignore_ascii_case(name) && field.field_type == RestServiceFieldType::Date)
+        .map(|field| field.name.to_owned())
+        .ok_or_else(|| RestServiceMetadataError::UnknownEditField(name.to_owned(), layer_name.to_owned()))
+}
+
+/// Consecutive inclusive OID ranges from `min_oid` to `max_oid`, each ending at one of the
+/// `boundaries` (percentiles of the object ids) within the range.
+fn balanced_oid_ranges(min_oid: i64, max_oid: i64, mut boundaries: Vec<i64>) -> Vec<(i64, i64)> {
+    boundaries.sort_unstable();
+    boundaries.dedup();
+    let mut ranges = Vec::with_capacity(boundaries.len() + 1);
+    let mut lower_bound = min_oid;
+    for boundary in boundaries.into_iter().filter(|boundary| *boundary >= min_oid && *boundary < max_oid) {
+        ranges.push((lower_bound, boundary));
+        lower_bound = boundary + 1;
+    }
+    ranges.push((lower_bound, max_oid));
+    ranges
+}
+
+/// Discrete percentiles of the object ids of the layer matching `filter`, one per fraction. None
+/// when the service returns an error or no values, e.g. percentiles over the OID field are not
+/// allowed.
+async fn get_service_oid_percentiles(
+    client: &ServiceClient,
+    url: &str,
+    oid_field_name: &str,
+    fractions: &[f64],
+    filter: &QueryFilter,
+) -> Result<Option<Vec<i64>>, Box<dyn Error + Sync + Send>> {
+    let out_statistics: Vec<Value> = fractions.iter()
+        .enumerate()
+        .map(|(index, fraction)| json!({
+            "statisticType": "percentile_disc",
+            "statisticParameters": { "value": fraction },
+            "onStatisticField": oid_field_name,
+            "outStatisticFieldName": format!("PERCENTILE_{}", index),
+        }))
+        .collect();
+    let mut url_params = vec![
+        ("where", filter.where_param()),
+        ("outStatistics", Value::Array(out_statistics).to_string()),
+        ("f", String::from("json")),
+    ];
+    url_params.append(&mut filter.query_params());
+    let percentiles_url = Url::parse_with_params(
+        format!("{}/query", url).as_str(),
+        url_params,
+    )?;
+    let percentiles_json: Value = client.get(percentiles_url)
+        .await?
+        .send()
+        .await?
+        .json()
+        .await?;
+    let percentiles = percentiles_json["features"]
+        .as_array()
+        .and_then(|features| features.first())
+        .and_then(|feature| feature["attributes"].as_object())
+        .and_then(|attributes| {
+            (0..fractions.len())
+                .map(|index| object_id(&attributes[&format!("PERCENTILE_{}", index)]))
+                .collect()
+        });
+    Ok(percentiles)
+}
+
 async fn get_service_max_min(
-    client: &reqwest::Client,
+    client: &ServiceClient,
     url: &str,
     oid_field_name: String,
     stats_enabled: bool,
+    filter: &QueryFilter,
 ) -> Result<Option<(i64, i64)>, Box<dyn Error + Sync + Send>> {
     let result = if stats_enabled {
-        get_service_max_min_stats(&client, url, oid_field_name).await?
+        get_service_max_min_stats(client, url, oid_field_name, filter).await?
     } else {
-        get_service_max_min_oid(&client, url).await?
+        get_service_max_min_oid(client, url, filter).await?
     };
     Ok(result)
 }
 
-async fn get_service_max_min_oid(
-    client: &reqwest::Client,
+/// Every object id of the layer matching `filter`, in ascending order. None when the service does
+/// not return an `objectIds` array.
+pub(crate) async fn request_object_ids(
+    client: &ServiceClient,
     url: &str,
-) -> Result<Option<(i64, i64)>, Box<dyn Error + Sync + Send>> {
-    let max_min_url = Url::parse_with_params(
+    filter: &QueryFilter,
+) -> Result<Option<Vec<i64>>, Box<dyn Error + Sync + Send>> {
+    let mut url_params = vec![
+        ("where", filter.where_param()),
+        ("returnIdsOnly", String::from("true")),
+        ("f", String::from("json")),
+    ];
+    url_params.append(&mut filter.query_params());
+    let object_ids_url = Url::parse_with_params(
         format!("{}/query", url).as_str(),
-        [("where","1=1"),("returnIdsOnly","true"),("f","json")],
+        url_params,
     )?;
-    let max_min_json: Value = client.get(max_min_url)
+    let object_ids_json: Value = client.get(object_ids_url)
+        .await?
         .send()
         .await?
         .json()
         .await?;
-    let max_min_oid = max_min_json["objectIds"]
+    let object_ids = object_ids_json["objectIds"]
         .as_array()
-        .map(|object_ids_json| {
-            let max = object_ids_json[object_ids_json.len() - 1].as_i64().unwrap_or_default();
-            let min = object_ids_json[0].as_i64().unwrap_or_default();
-            (max, min)
+        .map(|object_ids| {
+            let mut object_ids: Vec<i64> = object_ids.iter()
+                .filter_map(object_id)
+                .collect();
+            object_ids.sort_unstable();
+            object_ids
         });
+    Ok(object_ids)
+}
+
+async fn get_service_max_min_oid(
+    client: &ServiceClient,
+    url: &str,
+    filter: &QueryFilter,
+) -> Result<Option<(i64, i64)>, Box<dyn Error + Sync + Send>> {
+    let max_min_oid = request_object_ids(client, url, filter)
+        .await?
+        .and_then(|object_ids| Some((*object_ids.last()?, *object_ids.first()?)));
     Ok(max_min_oid)
 }
 
 async fn get_service_max_min_stats(
-    client: &reqwest::Client,
+    client: &ServiceClient,
     url: &str,
     oid_field_name: String,
+    filter: &QueryFilter,
 ) -> Result<Option<(i64, i64)>, Box<dyn Error + Sync + Send>> {
-    let out_statistics = out_statistics_parameter(oid_field_name);
+    let mut url_params = vec![
+        ("where", filter.where_param()),
+        ("outStatistics", out_statistics_parameter(oid_field_name)),
+        ("f", String::from("json")),
+    ];
+    url_params.append(&mut filter.query_params());
     let max_min_url = Url::parse_with_params(
         format!("{}/query", url).as_str(),
-        [("outStatistics", out_statistics.as_str()), ("f", "json")],
+        url_params,
     )?;
     let max_min_json: Value = client.get(max_min_url)
+        .await?
         .header("User-Agent", "Reqwest Rust Test")
         .send()
         .await?
@@ -696,22 +1495,39 @@ async fn get_service_max_min_stats(
         .await?;
     let max_min_oid = max_min_json["features"]
         .as_array()
-        .and_then(|features| if features.len() > 0 { Some(&features[0]) } else { None })
+        .and_then(|features| if !features.is_empty() { Some(&features[0]) } else { None })
         .and_then(|feature| feature["attributes"].as_object())
-        .map(|attributes| (
-            attributes["MAX_VALUE"].as_i64().unwrap_or_default(),
-            attributes["MIN_VALUE"].as_i64().unwrap_or_default()
-        ));
+        .and_then(|attributes| Some((object_id(&attributes["MAX_VALUE"])?, object_id(&attributes["MIN_VALUE"])?)));
     Ok(max_min_oid)
 }
 
-pub(crate) async fn request_service_metadata(
+/// Layer described by its metadata JSON. The feature count, extent and OID bounds need further
+/// requests and are left empty.
+/// OID field name ArcGIS generates for query layers over data without a unique integer column.
+const GENERATED_OID_FIELD: &str = "ESRI_OID";
+
+/// Why the layer's OIDs look virtual, from the hints query layers leave in their metadata: an
+/// OID field ArcGIS generated, or an objectIdField naming a column other than the OID field.
+fn virtual_oid_hint(metadata_json: &Value, oid_field: Option<&RestServiceField>) -> Option<String> {
+    let oid_field = oid_field?;
+    if oid_field.name.eq_ignore_ascii_case(GENERATED_OID_FIELD) {
+        return Some(format!("its OID field {} is generated by ArcGIS", oid_field.name))
+    }
+    match metadata_json["objectIdField"].as_str() {
+        Some(name) if !name.eq_ignore_ascii_case(&oid_field.name) => {
+            Some(format!("its objectIdField {} is not its OID field {}", name, oid_field.name))
+        }
+        _ => None,
+    }
+}
+
+pub(crate) fn parse_metadata(
+    client: &ServiceClient,
     url: &str,
+    metadata_json: &Value,
     output_spatial_reference: Option<i64>,
-) -> Result<RestServiceMetadata, Box<dyn Error + Sync + Send>> {
-    let client = reqwest::Client::new();
-    let source_count = get_service_count(&client, url).await?;
-    let metadata_json = get_service_metadata(&client, url).await?;
+    filter: &QueryFilter,
+) -> Result<RestServiceMetadata, RestServiceMetadataError> {
     let name = metadata_json["name"]
         .as_str()
         .ok_or(RestServiceMetadataError::MissingKey("name".to_owned()))?
@@ -719,11 +1535,26 @@ pub(crate) async fn request_service_metadata(
     let max_record_count = metadata_json["maxRecordCount"]
         .as_i64()
         .ok_or(RestServiceMetadataError::MissingKey("maxRecordCount".to_owned()))?;
-    let (stats_enabled, pagination_enabled) = advanced_options(&metadata_json);
+    if max_record_count <= 0 {
+        return Err(RestServiceMetadataError::InvalidMaxRecordCount(max_record_count))
+    }
+    let (stats_enabled, pagination_enabled) = advanced_options(metadata_json);
+    let pbf_enabled = metadata_json["supportedQueryFormats"]
+        .as_str()
+        .is_some_and(|formats| formats.split(',').any(|format| format.trim().eq_ignore_ascii_case("pbf")));
+    let attachments_enabled = metadata_json["hasAttachments"].as_bool().unwrap_or(false);
+    let percentiles_enabled = metadata_json["advancedQueryCapabilities"]["supportsPercentileStatistics"]
+        .as_bool()
+        .unwrap_or(false);
     let server_type = metadata_json["type"]
         .as_str()
         .ok_or(RestServiceMetadataError::MissingKey("type[server]".to_owned()))?
         .to_owned();
+    let text = |key: &str| metadata_json[key]
+        .as_str()
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .map(str::to_owned);
     let geo_type = if server_type == "table" {
         RestServiceGeometryType::None
     } else {
@@ -742,32 +1573,603 @@ pub(crate) async fn request_service_metadata(
     let oid_field = fields.iter()
         .find(|field| field.field_type == RestServiceFieldType::OID)
         .map(|field| field.to_owned());
-    let spatial_reference = metadata_json["sourceSpatialReference"]
-        .as_object()
-        .and_then(|obj| obj["wkid"].as_i64());
-    let max_min_oid = if !pagination_enabled && oid_field.is_some() {
-        get_service_max_min(
-            &client,
-            url,
-            oid_field.to_owned().unwrap().name,
-            stats_enabled
-        ).await?
+    let virtual_oids = virtual_oid_hint(metadata_json, oid_field.as_ref());
+    let spatial_reference = metadata_json["sourceSpatialReference"]["wkid"].as_i64();
+    let spatial_reference_wkt = [&metadata_json["sourceSpatialReference"], &metadata_json["extent"]["spatialReference"]]
+        .into_iter()
+        .find_map(|reference| reference["wkt"].as_str())
+        .map(str::to_owned);
+    let filter = if geo_type == RestServiceGeometryType::None {
+        filter.without_bbox()
     } else {
-        None
+        filter.for_layer(spatial_reference)
     };
-    let rest_metadata = RestServiceMetadata {
+    let filter = match &filter.edited_since {
+        Some(edited_since) => {
+            let edit_field = edit_date_field(metadata_json, &fields, edited_since.field.as_deref(), &name)?;
+            filter.with_edit_field(&edit_field)
+        }
+        None => filter,
+    };
+    Ok(RestServiceMetadata {
         url: url.to_owned(),
         name,
-        source_count,
+        source_count: None,
         max_record_count,
         pagination_enabled,
+        stats_enabled,
+        percentiles_enabled,
+        pbf_enabled,
+        attachments_enabled,
         server_type,
+        description: text("description"),
+        copyright_text: text("copyrightText"),
         geo_type,
         fields,
         oid_field,
-        max_min_oid,
+        max_min_oid: None,
+        relationships: metadata_json["relationships"]
+            .as_array()
+            .map(|relationships| relationships.iter().filter_map(LayerRelationship::from_json).collect())
+            .unwrap_or_default(),
+        virtual_oids,
+        out_fields: None,
         source_spatial_reference: spatial_reference,
+        source_spatial_reference_wkt: spatial_reference_wkt,
         output_spatial_reference,
+        extent: None,
+        filter,
+        client: client.to_owned(),
+    })
+}
+
+pub(crate) async fn request_service_metadata(
+    client: &ServiceClient,
+    url: &str,
+    output_spatial_reference: Option<i64>,
+    filter: &QueryFilter,
+    overrides: &MetadataOverrides,
+) -> Result<RestServiceMetadata, Box<dyn Error + Sync + Send>> {
+    let metadata_json = get_service_metadata(client, url, filter).await?;
+    let mut rest_metadata = parse_metadata(client, url, &metadata_json, output_spatial_reference, filter)?;
+    overrides.apply(&mut rest_metadata);
+    complete_metadata(rest_metadata, &metadata_json).await
+}
+
+/// Layer described by a saved metadata file, the layer's `?f=json` response. The file may also
+/// hold the feature `count` and the `oidRange` as `[min, max]`. Values of the file take
+/// precedence over the server's, so wrong counts or maxRecordCounts can be corrected. Offline,
+/// nothing missing from the file is requested.
+pub(crate) async fn read_service_metadata(
+    client: &ServiceClient,
+    url: &str,
+    output_spatial_reference: Option<i64>,
+    filter: &QueryFilter,
+    path: &Path,
+    offline: bool,
+    overrides: &MetadataOverrides,
+) -> Result<RestServiceMetadata, Box<dyn Error + Sync + Send>> {
+    let metadata_json: Value = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    let mut rest_metadata = parse_metadata(client, url, &metadata_json, output_spatial_reference, filter)?;
+    rest_metadata.source_count = metadata_json["count"].as_i64();
+    rest_metadata.max_min_oid = match metadata_json["oidRange"].as_array().map(Vec::as_slice) {
+        Some([min_oid, max_oid]) => object_id(min_oid).zip(object_id(max_oid)).map(|(min_oid, max_oid)| (max_oid, min_oid)),
+        Some(_) => return Err(RestServiceMetadataError::MissingKey("oidRange[min, max]".to_owned()).into()),
+        None => None,
     };
+    overrides.apply(&mut rest_metadata);
+    if offline {
+        if rest_metadata.geo_type != RestServiceGeometryType::None {
+            rest_metadata.extent = LayerExtent::from_esri_json(&metadata_json["extent"]);
+        }
+        return Ok(rest_metadata)
+    }
+    complete_metadata(rest_metadata, &metadata_json).await
+}
+
+/// Request the values of the layer missing from its metadata JSON.
+async fn complete_metadata(
+    mut rest_metadata: RestServiceMetadata,
+    metadata_json: &Value,
+) -> Result<RestServiceMetadata, Box<dyn Error + Sync + Send>> {
+    let client = &rest_metadata.client;
+    let url = rest_metadata.url.as_str();
+    if rest_metadata.source_count.is_none() {
+        rest_metadata.source_count = get_service_count(client, url, &rest_metadata.filter).await?;
+    }
+    if rest_metadata.geo_type != RestServiceGeometryType::None {
+        rest_metadata.extent = match get_service_extent(client, url, &rest_metadata.filter).await? {
+            Some(extent) => Some(extent),
+            None => LayerExtent::from_esri_json(&metadata_json["extent"]),
+        };
+    }
+    // OID bounds are needed for the OID range strategy, and as a fallback for paginated services
+    // when they are cheap to get
+    if let (Some(oid_field), None) = (&rest_metadata.oid_field, rest_metadata.max_min_oid) {
+        if !rest_metadata.pagination_enabled || rest_metadata.stats_enabled {
+            rest_metadata.max_min_oid = get_service_max_min(
+                client,
+                url,
+                oid_field.name.to_owned(),
+                rest_metadata.stats_enabled,
+                &rest_metadata.filter,
+            ).await?;
+        }
+    }
     Ok(rest_metadata)
 }
+
+/// Property tests feeding the planner every shape of metadata real servers have sent: missing
+/// keys, zero or negative counts, inverted OID ranges and odd capability combinations. Planning
+/// must end in queries or a typed error, never a panic.
+#[cfg(test)]
+mod planner_property_tests {
+    use std::sync::{Arc, OnceLock};
+    use proptest::prelude::*;
+    use reqwest::Url;
+    use serde_json::{json, Map, Value};
+    use crate::audit::{user_agent, RunAudit, RunId, RunIdPlacement};
+    use crate::auth::AnonymousAuth;
+    use crate::client::ServiceClient;
+    use crate::filter::QueryFilter;
+    use crate::strategy::ScrapeStrategy;
+    use super::{parse_metadata, RestServiceMetadataError};
+
+    /// Shared by every case, building an HTTP client loads the system certificates.
+    pub(super) fn client() -> &'static ServiceClient {
+        static CLIENT: OnceLock<ServiceClient> = OnceLock::new();
+        CLIENT.get_or_init(|| ServiceClient::new(
+            Arc::new(AnonymousAuth),
+            RunAudit { run_id: RunId::generate(), placement: RunIdPlacement::Header, user_agent: user_agent(None) },
+        ))
+    }
+
+    /// Counts around the edges, with the occasional arbitrary value.
+    fn count() -> impl Strategy<Value = i64> {
+        prop_oneof![4 => -3_i64..500, 1 => any::<i64>(), 1 => Just(i64::MIN), 1 => Just(i64::MAX)]
+    }
+
+    fn field() -> impl Strategy<Value = Value> {
+        (
+            proptest::option::of(prop_oneof![Just("OBJECTID"), Just("SHAPE"), Just("NAME"), Just("")]),
+            proptest::option::of(prop_oneof![
+                Just("esriFieldTypeOID"),
+                Just("esriFieldTypeString"),
+                Just("esriFieldTypeGeometry"),
+                Just("esriFieldTypeInteger"),
+                Just("esriFieldTypeUnknown"),
+            ]),
+            any::<bool>(),
+            prop_oneof![
+                Just(Value::Null),
+                Just(json!({})),
+                Just(json!({"type": "codedValue"})),
+                Just(json!({"type": "codedValue", "codedValues": [{"code": 1}]})),
+                Just(json!({"type": "codedValue", "codedValues": [{"code": 1, "name": "One"}, 5]})),
+                Just(json!({"type": "range", "range": [0, 10]})),
+            ],
+        ).prop_map(|(name, field_type, has_alias, domain)| {
+            let mut field = Map::new();
+            if let Some(name) = name {
+                field.insert("name".to_owned(), json!(name));
+            }
+            if let Some(field_type) = field_type {
+                field.insert("type".to_owned(), json!(field_type));
+            }
+            if has_alias {
+                field.insert("alias".to_owned(), json!("Alias"));
+            }
+            field.insert("domain".to_owned(), domain);
+            Value::Object(field)
+        })
+    }
+
+    fn metadata_json() -> impl Strategy<Value = Value> {
+        (
+            proptest::option::of(count()),
+            proptest::option::of(prop_oneof![Just("Feature Layer"), Just("table"), Just("TABLE"), Just("Raster Layer")]),
+            proptest::option::of(prop_oneof![
+                Just("esriGeometryPoint"),
+                Just("esriGeometryPolygon"),
+                Just("esriGeometryPolyline"),
+                Just("esriGeometryEnvelope"),
+                Just("esriGeometryUnknown"),
+            ]),
+            proptest::option::of(proptest::collection::vec(field(), 0..4)),
+            prop_oneof![
+                Just(Value::Null),
+                Just(json!({})),
+                Just(json!({"supportsPagination": true, "supportsStatistics": false})),
+                Just(json!({"supportsPagination": "yes", "supportsStatistics": 1})),
+            ],
+            any::<bool>(),
+            prop_oneof![Just(Value::Null), Just(json!({})), Just(json!({"wkid": 4326})), Just(json!({"wkid": "4326"}))],
+            proptest::option::of(prop_oneof![Just("JSON, geoJSON, PBF"), Just("")]),
+        ).prop_map(|(max_record_count, server_type, geometry_type, fields, advanced, pagination, spatial_reference, formats)| {
+            let mut metadata = Map::new();
+            metadata.insert("name".to_owned(), json!("Parcels"));
+            if let Some(max_record_count) = max_record_count {
+                metadata.insert("maxRecordCount".to_owned(), json!(max_record_count));
+            }
+            if let Some(server_type) = server_type {
+                metadata.insert("type".to_owned(), json!(server_type));
+            }
+            if let Some(geometry_type) = geometry_type {
+                metadata.insert("geometryType".to_owned(), json!(geometry_type));
+            }
+            if let Some(fields) = fields {
+                metadata.insert("fields".to_owned(), Value::Array(fields));
+            }
+            metadata.insert("advancedQueryCapabilities".to_owned(), advanced);
+            metadata.insert("supportsPagination".to_owned(), json!(pagination));
+            metadata.insert("sourceSpatialReference".to_owned(), spatial_reference);
+            if let Some(formats) = formats {
+                metadata.insert("supportedQueryFormats".to_owned(), json!(formats));
+            }
+            Value::Object(metadata)
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn plan_should_return_queries_or_typed_error_when_passed_any_metadata(
+            metadata_json in metadata_json(),
+            feature_count in proptest::option::of(count()),
+            max_min_oid in proptest::option::of((count(), count())),
+            output_spatial_reference in proptest::option::of(prop_oneof![Just(4326_i64), Just(3857_i64)]),
+        ) {
+            let url = "https://example.com/arcgis/rest/services/Parcels/FeatureServer/0";
+            let filter = QueryFilter::default();
+            let mut layer = match parse_metadata(client(), url, &metadata_json, output_spatial_reference, &filter) {
+                Ok(layer) => layer,
+                Err(_) => return Ok(()),
+            };
+            prop_assert!(layer.scrape_count() > 0);
+            layer.source_count = feature_count;
+            layer.max_min_oid = max_min_oid;
+            for strategy in ScrapeStrategy::Auto.chain(&layer) {
+                // Object id batches and the quadtree need the server to plan
+                let queries = match strategy {
+                    ScrapeStrategy::Pagination => layer.pagination_queries(),
+                    ScrapeStrategy::OidRanges => layer.oid_range_queries(),
+                    _ => continue,
+                };
+                match queries {
+                    Ok(queries) => {
+                        prop_assert!(queries.len() as i64 <= super::MAX_CHUNK_COUNT);
+                        for query in &queries {
+                            let query = Url::parse(query)?;
+                            prop_assert!(query.query_pairs().any(|(key, value)| key == "f" && value == "json"));
+                        }
+                    }
+                    Err(error) => prop_assert!(error.is::<RestServiceMetadataError>(), "{}", error),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod metadata_file_tests {
+    use std::io::Write;
+    use std::sync::Arc;
+    use serde_json::json;
+    use crate::audit::{user_agent, RunAudit, RunId, RunIdPlacement};
+    use crate::auth::AnonymousAuth;
+    use crate::client::ServiceClient;
+    use crate::filter::QueryFilter;
+    use super::{read_service_metadata, MetadataOverrides};
+
+    #[tokio::test]
+    async fn read_service_metadata_should_use_saved_values_when_offline() {
+        let client = ServiceClient::new(
+            Arc::new(AnonymousAuth),
+            RunAudit { run_id: RunId::generate(), placement: RunIdPlacement::Header, user_agent: user_agent(None) },
+        );
+        let metadata = json!({
+            "name": "Parcels",
+            "type": "Feature Layer",
+            "maxRecordCount": 1000,
+            "geometryType": "esriGeometryPoint",
+            "description": " ",
+            "copyrightText": "County GIS Department",
+            "fields": [
+                {"name": "OBJECTID", "type": "esriFieldTypeOID", "alias": "OBJECTID"},
+                {"name": "NAME", "type": "esriFieldTypeString", "alias": "Name"},
+            ],
+            "sourceSpatialReference": {"wkid": 4326},
+            "advancedQueryCapabilities": {"supportsPagination": true},
+            "extent": {"xmin": 0, "ymin": 0, "xmax": 10, "ymax": 10, "spatialReference": {"wkid": 4326}},
+            "count": 2500,
+            "oidRange": [1, 2600],
+        });
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(metadata.to_string().as_bytes()).unwrap();
+
+        let url = "http://127.0.0.1:1/arcgis/rest/services/Parcels/FeatureServer/0";
+        let rest_metadata = read_service_metadata(&client, url, None, &QueryFilter::default(), file.path(), true, &MetadataOverrides::default())
+            .await
+            .unwrap();
+
+        assert_eq!(rest_metadata.source_count, Some(2500));
+        assert_eq!(rest_metadata.max_min_oid, Some((2600, 1)));
+        assert!(rest_metadata.extent.is_some());
+        let description = rest_metadata.layer_description();
+        assert_eq!(description.description, None);
+        assert_eq!(description.copyright_text.as_deref(), Some("County GIS Department"));
+        assert_eq!(description.fields.iter().map(|field| field.alias.as_str()).collect::<Vec<_>>(), vec!["OBJECTID", "Name"]);
+    }
+}
+
+#[cfg(test)]
+mod query_filter_tests {
+    use std::collections::HashMap;
+    use chrono::{TimeZone, Utc};
+    use reqwest::Url;
+    use serde_json::json;
+    use crate::filter::{BoundingBox, EditedSince, QueryFilter};
+    use crate::geometry::Extent;
+    use super::planner_property_tests::client;
+    use super::{parse_metadata, RestServiceMetadataError};
+
+    fn query_params(query: &str) -> HashMap<String, String> {
+        Url::parse(query).unwrap().query_pairs().into_owned().collect()
+    }
+
+    #[test]
+    fn chunk_query_should_send_envelope_and_where_when_filtered() {
+        let metadata = json!({
+            "name": "Parcels",
+            "type": "Feature Layer",
+            "maxRecordCount": 1000,
+            "geometryType": "esriGeometryPolygon",
+            "fields": [{"name": "OBJECTID", "type": "esriFieldTypeOID", "alias": "OBJECTID"}],
+            "sourceSpatialReference": {"wkid": 2913},
+            "advancedQueryCapabilities": {"supportsPagination": true},
+        });
+        let filter = QueryFilter {
+            bbox: Some(BoundingBox {
+                extent: Extent { x_min: -123.0, y_min: 45.0, x_max: -122.0, y_max: 46.0 },
+                spatial_reference: 4326,
+            }),
+            where_clause: Some("STATE='OR'".to_owned()),
+            ..QueryFilter::default()
+        };
+        let url = "https://example.com/arcgis/rest/services/Parcels/FeatureServer/0";
+        let mut layer = parse_metadata(client(), url, &metadata, None, &filter).unwrap();
+        layer.max_min_oid = Some((2600, 1));
+
+        let pagination = query_params(&layer.chunk_query(1, 1000).unwrap());
+        layer.pagination_enabled = false;
+        let oid_range = query_params(&layer.chunk_query(1, 1000).unwrap());
+
+        assert_eq!(pagination["where"], "STATE='OR'");
+        assert_eq!(pagination["resultOffset"], "1000");
+        assert_eq!(oid_range["where"], "(STATE='OR') and (OBJECTID >= 1001 and OBJECTID <= 2000)");
+        for params in [&pagination, &oid_range] {
+            assert_eq!(params["geometry"], "-123,45,-122,46");
+            assert_eq!(params["geometryType"], "esriGeometryEnvelope");
+            assert_eq!(params["inSR"], "4326");
+            assert_eq!(params["spatialRel"], "esriSpatialRelIntersects");
+            assert_eq!(params["outSR"], "2913");
+        }
+    }
+
+    #[test]
+    fn select_fields_should_request_selected_and_oid_fields_when_fields_given() {
+        let metadata = json!({
+            "name": "Parcels",
+            "type": "Feature Layer",
+            "maxRecordCount": 1000,
+            "geometryType": "esriGeometryPolygon",
+            "fields": [
+                {"name": "OBJECTID", "type": "esriFieldTypeOID", "alias": "OBJECTID"},
+                {"name": "PARCEL_ID", "type": "esriFieldTypeString", "alias": "Parcel"},
+                {"name": "OWNER", "type": "esriFieldTypeString", "alias": "Owner"},
+                {"name": "ACRES", "type": "esriFieldTypeDouble", "alias": "Acres"},
+            ],
+            "sourceSpatialReference": {"wkid": 2913},
+            "advancedQueryCapabilities": {"supportsPagination": true},
+        });
+        let url = "https://example.com/arcgis/rest/services/Parcels/FeatureServer/0";
+        let mut layer = parse_metadata(client(), url, &metadata, None, &QueryFilter::default()).unwrap();
+        assert_eq!(query_params(&layer.chunk_query(0, 1000).unwrap())["outFields"], "*");
+
+        layer.select_fields(&["acres".to_owned(), " parcel_id".to_owned()]);
+
+        let names: Vec<&str> = layer.fields.iter().map(|field| field.name.as_str()).collect();
+        assert_eq!(names, vec!["PARCEL_ID", "ACRES", "RINGS"]);
+        assert_eq!(query_params(&layer.chunk_query(0, 1000).unwrap())["outFields"], "PARCEL_ID,ACRES,OBJECTID");
+    }
+
+    #[test]
+    fn parse_metadata_should_filter_by_edit_date_field_when_scraping_edits_since() {
+        let metadata = json!({
+            "name": "Parcels",
+            "type": "Feature Layer",
+            "maxRecordCount": 1000,
+            "geometryType": "esriGeometryPolygon",
+            "fields": [
+                {"name": "OBJECTID", "type": "esriFieldTypeOID", "alias": "OBJECTID"},
+                {"name": "OWNER", "type": "esriFieldTypeString", "alias": "Owner"},
+                {"name": "last_edited_date", "type": "esriFieldTypeDate", "alias": "Edited"},
+            ],
+            "editFieldsInfo": {"editDateField": "last_edited_date"},
+            "sourceSpatialReference": {"wkid": 2913},
+            "advancedQueryCapabilities": {"supportsPagination": true},
+        });
+        let url = "https://example.com/arcgis/rest/services/Parcels/FeatureServer/0";
+        let filter = |field: Option<&str>| QueryFilter {
+            edited_since: Some(EditedSince {
+                field: field.map(str::to_owned),
+                since: Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap(),
+            }),
+            ..QueryFilter::default()
+        };
+
+        let layer = parse_metadata(client(), url, &metadata, None, &filter(None)).unwrap();
+        assert_eq!(
+            query_params(&layer.chunk_query(0, 1000).unwrap())["where"],
+            "last_edited_date > TIMESTAMP '2024-03-01 00:00:00'",
+        );
+        let layer = parse_metadata(client(), url, &metadata, None, &filter(Some("LAST_EDITED_DATE"))).unwrap();
+        assert_eq!(layer.filter.where_param(), "last_edited_date > TIMESTAMP '2024-03-01 00:00:00'");
+        assert_eq!(
+            parse_metadata(client(), url, &metadata, None, &filter(Some("OWNER"))).unwrap_err(),
+            RestServiceMetadataError::UnknownEditField("OWNER".to_owned(), "Parcels".to_owned()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod console_tests {
+    use std::sync::Arc;
+    use serde_json::json;
+    use crate::audit::{user_agent, RunAudit, RunId, RunIdPlacement};
+    use crate::auth::AnonymousAuth;
+    use crate::client::ServiceClient;
+    use crate::filter::QueryFilter;
+    use crate::strategy::ScrapeStrategy;
+    use super::{parse_field_pattern, parse_metadata, FieldListing, RestServiceMetadata};
+
+    fn layer() -> RestServiceMetadata {
+        let client = ServiceClient::new(
+            Arc::new(AnonymousAuth),
+            RunAudit { run_id: RunId::generate(), placement: RunIdPlacement::Header, user_agent: user_agent(None) },
+        );
+        let metadata = json!({
+            "name": "Parcels",
+            "type": "Feature Layer",
+            "maxRecordCount": 2000,
+            "geometryType": "esriGeometryPolygon",
+            "copyrightText": "County Assessor",
+            "fields": [
+                {"name": "OBJECTID", "type": "esriFieldTypeOID", "alias": "OBJECTID"},
+                {
+                    "name": "OWNER_NAME",
+                    "type": "esriFieldTypeString",
+                    "alias": "Name of the owner of record as listed on the most recent deed",
+                },
+                {
+                    "name": "LAND_USE",
+                    "type": "esriFieldTypeSmallInteger",
+                    "alias": "Land Use",
+                    "domain": {"type": "codedValue", "codedValues": [{"code": 1, "name": "Residential"}]},
+                },
+            ],
+            "sourceSpatialReference": {"wkid": 2913},
+            "advancedQueryCapabilities": {"supportsPagination": true},
+        });
+        let url = "https://example.com/arcgis/rest/services/Parcels/FeatureServer/0";
+        parse_metadata(&client, url, &metadata, None, &QueryFilter::default()).unwrap()
+    }
+
+    #[test]
+    fn console_summary_should_not_cut_aliases_when_width_is_unbounded() {
+        insta::assert_snapshot!(layer().console_summary(FieldListing::Table(u16::MAX as usize), None).unwrap());
+    }
+
+    #[test]
+    fn console_summary_should_list_fields_as_lines_when_table_is_wider_than_terminal() {
+        let layer = layer();
+        assert_eq!(
+            layer.console_summary(FieldListing::Table(80), None).unwrap(),
+            layer.console_summary(FieldListing::Plain, None).unwrap(),
+        );
+    }
+
+    #[test]
+    fn console_summary_should_list_fields_as_lines_when_plain() {
+        insta::assert_snapshot!(layer().console_summary(FieldListing::Plain, None).unwrap());
+    }
+
+    #[test]
+    fn parse_metadata_should_find_virtual_oids_when_object_id_field_is_not_oid_field() {
+        let client = layer().client;
+        let metadata = |object_id_field: &str| json!({
+            "name": "Permits",
+            "type": "Feature Layer",
+            "maxRecordCount": 1000,
+            "geometryType": "esriGeometryPoint",
+            "objectIdField": object_id_field,
+            "fields": [
+                {"name": "OBJECTID", "type": "esriFieldTypeOID", "alias": "OBJECTID"},
+                {"name": "PERMIT_ID", "type": "esriFieldTypeInteger", "alias": "Permit"},
+            ],
+        });
+        let url = "https://example.com/arcgis/rest/services/Permits/MapServer/0";
+        let mut layer = parse_metadata(&client, url, &metadata("PERMIT_ID"), None, &QueryFilter::default()).unwrap();
+        layer.max_min_oid = Some((10, 1));
+
+        assert!(layer.virtual_oids().is_some());
+        assert_eq!(
+            ScrapeStrategy::Auto.chain(&layer),
+            vec![ScrapeStrategy::ObjectIds],
+        );
+        let layer = parse_metadata(&client, url, &metadata("objectid"), None, &QueryFilter::default()).unwrap();
+        assert!(layer.virtual_oids().is_none());
+    }
+
+    #[test]
+    fn parse_metadata_should_support_attachments_when_layer_has_attachments_and_oid_field() {
+        let client = layer().client;
+        let metadata = |oid_type: &str| json!({
+            "name": "Parcels",
+            "type": "Feature Layer",
+            "maxRecordCount": 1000,
+            "geometryType": "esriGeometryPoint",
+            "hasAttachments": true,
+            "fields": [{"name": "OBJECTID", "type": oid_type, "alias": "OBJECTID"}],
+        });
+        let url = "https://example.com/arcgis/rest/services/Parcels/FeatureServer/0";
+
+        let layer = parse_metadata(&client, url, &metadata("esriFieldTypeOID"), None, &QueryFilter::default()).unwrap();
+        assert!(layer.supports_attachments());
+        let layer = parse_metadata(&client, url, &metadata("esriFieldTypeInteger"), None, &QueryFilter::default()).unwrap();
+        assert!(!layer.supports_attachments());
+    }
+
+    #[test]
+    fn parse_metadata_should_keep_spatial_reference_wkt_when_geometries_are_not_projected() {
+        let client = layer().client;
+        let wkt = "PROJCS[\"NAD_1983_HARN_StatePlane_Oregon_North_FIPS_3601_Feet_Intl\"]";
+        let metadata = json!({
+            "name": "Parcels",
+            "type": "Feature Layer",
+            "maxRecordCount": 1000,
+            "geometryType": "esriGeometryPolygon",
+            "fields": [{"name": "OBJECTID", "type": "esriFieldTypeOID", "alias": "OBJECTID"}],
+            "extent": {"spatialReference": {"wkid": 2913, "wkt": wkt}},
+            "sourceSpatialReference": {"wkid": 2913},
+        });
+        let url = "https://example.com/arcgis/rest/services/Parcels/FeatureServer/0";
+
+        let layer = parse_metadata(&client, url, &metadata, None, &QueryFilter::default()).unwrap();
+        assert_eq!(layer.output_spatial_reference_wkt(), Some(wkt));
+        let layer = parse_metadata(&client, url, &metadata, Some(2913), &QueryFilter::default()).unwrap();
+        assert_eq!(layer.output_spatial_reference_wkt(), Some(wkt));
+        let layer = parse_metadata(&client, url, &metadata, Some(4326), &QueryFilter::default()).unwrap();
+        assert_eq!(layer.output_spatial_reference_wkt(), None);
+    }
+
+    #[test]
+    fn serialize_should_use_esri_type_names_when_written_as_json() {
+        let json = serde_json::to_value(layer()).unwrap();
+
+        assert_eq!(json["geo_type"], "esriGeometryPolygon");
+        assert_eq!(json["fields"][2]["field_type"], "esriFieldTypeSmallInteger");
+        assert_eq!(json["fields"][2]["codes"], json!({"1": "Residential"}));
+        assert_eq!(json["source_spatial_reference"], 2913);
+        assert!(json.get("client").is_none());
+    }
+
+    #[test]
+    fn console_summary_should_only_list_matching_fields_when_show_fields_given() {
+        let pattern = parse_field_pattern("^(owner|land use)").unwrap();
+        let summary = layer().console_summary(FieldListing::Plain, Some(&pattern)).unwrap();
+        assert!(summary.contains("  OWNER_NAME: esriFieldTypeString"));
+        assert!(summary.contains("  LAND_USE: esriFieldTypeSmallInteger"));
+        assert!(!summary.contains("  OBJECTID: "));
+        assert!(summary.contains("2 of 4 fields match --show-fields ^(owner|land use)"));
+    }
+}
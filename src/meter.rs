@@ -0,0 +1,126 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use serde::Serialize;
+
+/// Requests sent to the services by this run, every request through a
+/// [`ServiceClient`](crate::client::ServiceClient) counted.
+static METER: RequestMeter = RequestMeter::new();
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum RequestLimitError {
+    Reached(u64),
+}
+
+impl Display for RequestLimitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestLimitError::Reached(max) => write!(
+                f,
+                "Reached --max-requests after {} requests, nothing more is requested from the services",
+                max,
+            ),
+        }
+    }
+}
+
+impl Error for RequestLimitError {}
+
+/// Requests sent by the run and what they are estimated to cost, for metered services billing
+/// per request.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct RequestUsage {
+    pub(crate) requests: u64,
+    /// Requests times --cost-per-request, e.g. in ArcGIS Online credits
+    pub(crate) estimated_cost: Option<f64>,
+}
+
+impl Display for RequestUsage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Sent {} requests", self.requests)?;
+        if let Some(cost) = self.estimated_cost {
+            write!(f, " (estimated cost {:.2})", cost)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct RequestMeter {
+    sent: AtomicU64,
+    max_requests: AtomicU64,
+    cost_per_request: Mutex<Option<f64>>,
+}
+
+impl RequestMeter {
+    const fn new() -> Self {
+        Self {
+            sent: AtomicU64::new(0),
+            max_requests: AtomicU64::new(u64::MAX),
+            cost_per_request: Mutex::new(None),
+        }
+    }
+
+    fn configure(&self, max_requests: Option<u64>, cost_per_request: Option<f64>) {
+        self.max_requests.store(max_requests.unwrap_or(u64::MAX), Ordering::SeqCst);
+        *self.cost_per_request.lock().unwrap() = cost_per_request;
+    }
+
+    fn count(&self) -> Result<(), RequestLimitError> {
+        let max_requests = self.max_requests.load(Ordering::SeqCst);
+        self.sent
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |sent| (sent < max_requests).then_some(sent + 1))
+            .map(|_| ())
+            .map_err(|_| RequestLimitError::Reached(max_requests))
+    }
+
+    fn reached(&self) -> Option<RequestLimitError> {
+        let max_requests = self.max_requests.load(Ordering::SeqCst);
+        (self.sent.load(Ordering::SeqCst) >= max_requests).then_some(RequestLimitError::Reached(max_requests))
+    }
+
+    fn usage(&self) -> RequestUsage {
+        let requests = self.sent.load(Ordering::SeqCst);
+        RequestUsage {
+            requests,
+            estimated_cost: self.cost_per_request.lock().unwrap().map(|cost| cost * requests as f64),
+        }
+    }
+}
+
+/// Cap the requests of the run at `max_requests` and price each at `cost_per_request`.
+pub(crate) fn configure(max_requests: Option<u64>, cost_per_request: Option<f64>) {
+    METER.configure(max_requests, cost_per_request);
+}
+
+/// Count a request about to be sent, failing once the run sent --max-requests requests.
+pub(crate) fn count_request() -> Result<(), RequestLimitError> {
+    METER.count()
+}
+
+/// The error stopping the run once it sent --max-requests requests, None before.
+pub(crate) fn limit_reached() -> Option<RequestLimitError> {
+    METER.reached()
+}
+
+/// Requests sent so far and their estimated cost.
+pub(crate) fn usage() -> RequestUsage {
+    METER.usage()
+}
+
+#[cfg(test)]
+mod meter_tests {
+    use super::{RequestLimitError, RequestMeter, RequestUsage};
+
+    #[test]
+    fn count_should_refuse_requests_when_max_requests_reached() {
+        let meter = RequestMeter::new();
+        meter.configure(Some(2), Some(0.5));
+        assert_eq!(meter.count(), Ok(()));
+        assert_eq!(meter.count(), Ok(()));
+        assert_eq!(meter.reached(), Some(RequestLimitError::Reached(2)));
+        assert_eq!(meter.count(), Err(RequestLimitError::Reached(2)));
+        assert_eq!(meter.usage(), RequestUsage { requests: 2, estimated_cost: Some(1.0) });
+    }
+}
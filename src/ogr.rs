@@ -0,0 +1,390 @@
+#[cfg(feature = "gdal")]
+use std::collections::BTreeSet;
+use std::io;
+use std::path::Path;
+#[cfg(feature = "gdal")]
+use std::path::PathBuf;
+#[cfg(feature = "gdal")]
+use chrono::{DateTime, SecondsFormat};
+#[cfg(feature = "gdal")]
+use gdal::errors::GdalError;
+#[cfg(feature = "gdal")]
+use gdal::spatial_ref::{AxisMappingStrategy, SpatialRef};
+#[cfg(feature = "gdal")]
+use gdal::vector::{Feature, FieldDefn, Geometry, LayerAccess, LayerOptions, OGRFieldType, OGRwkbGeometryType};
+#[cfg(feature = "gdal")]
+use gdal::{Dataset, DriverManager, Metadata};
+use serde_json::{Map, Value};
+#[cfg(not(feature = "gdal"))]
+use crate::capability::{Capability, CapabilityError};
+use crate::checksum::Artifact;
+#[cfg(feature = "gdal")]
+use crate::metadata::{RestServiceFieldType, RestServiceGeometryType};
+use crate::metadata::RestServiceMetadata;
+#[cfg(feature = "gdal")]
+use crate::{projection, wkt};
+
+/// Kind of an OGR field, from the type of the layer field it holds.
+#[cfg(feature = "gdal")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ColumnKind {
+    Integer,
+    Real,
+    DateTime,
+    Text,
+}
+
+#[cfg(feature = "gdal")]
+impl ColumnKind {
+    fn of(field_type: &RestServiceFieldType) -> Self {
+        match field_type {
+            RestServiceFieldType::OID | RestServiceFieldType::Integer | RestServiceFieldType::SmallInteger => ColumnKind::Integer,
+            RestServiceFieldType::Double | RestServiceFieldType::Single | RestServiceFieldType::Float => ColumnKind::Real,
+            RestServiceFieldType::Date => ColumnKind::DateTime,
+            _ => ColumnKind::Text,
+        }
+    }
+
+    /// Value of `value` for a field of this kind, None for empty values and values that do not
+    /// parse as the kind, OGR's null.
+    fn value(&self, value: &str) -> Option<OgrValue> {
+        if value.is_empty() {
+            return None
+        }
+        let integer = || {
+            value.parse::<i64>()
+                .ok()
+                .or_else(|| value.parse::<f64>().ok().filter(|number| number.fract() == 0.0).map(|number| number as i64))
+        };
+        match self {
+            ColumnKind::Integer => integer().map(OgrValue::Integer),
+            ColumnKind::Real => value.parse::<f64>().ok().map(OgrValue::Real),
+            // Dates are sent as milliseconds since the epoch, unless already formatted
+            ColumnKind::DateTime => Some(OgrValue::Text(
+                integer()
+                    .and_then(DateTime::from_timestamp_millis)
+                    .map_or_else(|| value.to_owned(), |date| date.to_rfc3339_opts(SecondsFormat::Millis, true)),
+            )),
+            ColumnKind::Text => Some(OgrValue::Text(value.to_owned())),
+        }
+    }
+}
+
+/// Value of a record converted for its OGR field.
+#[cfg(feature = "gdal")]
+#[derive(Debug, PartialEq)]
+enum OgrValue {
+    Integer(i64),
+    Real(f64),
+    /// Text, dates included as ISO 8601 which OGR parses for date fields
+    Text(String),
+}
+
+#[cfg(feature = "gdal")]
+#[derive(Debug, Clone)]
+struct OgrColumn {
+    name: String,
+    kind: ColumnKind,
+    /// Position of the column's values in the records
+    index: usize,
+}
+
+/// Fields, geometry type and driver of a layer's OGR output, taken from the record columns
+/// without the geometry columns.
+#[cfg(feature = "gdal")]
+#[derive(Debug, Clone)]
+pub(crate) struct OgrLayout {
+    /// Short name of the OGR driver writing the file, e.g. GML or DXF
+    driver: String,
+    name: String,
+    columns: Vec<OgrColumn>,
+    geo_type: RestServiceGeometryType,
+    /// EPSG code of the geometries, when known
+    srid: Option<i64>,
+}
+
+/// Records and WKT geometries of a chunk's features, written once the chunk is accepted.
+#[cfg(feature = "gdal")]
+#[derive(Debug, Default)]
+pub(crate) struct OgrFeatures {
+    records: Vec<Vec<String>>,
+    geometries: Vec<String>,
+}
+
+#[cfg(feature = "gdal")]
+impl OgrFeatures {
+    pub(crate) fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Drop the features after the first `len`, e.g. of a response thrown away.
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.records.truncate(len);
+        self.geometries.truncate(len);
+    }
+}
+
+#[cfg(feature = "gdal")]
+impl OgrLayout {
+    /// Layout of the records of `layer` with `columns`, written by `driver`. Columns that are
+    /// not fields of the layer (code descriptions, provenance, cells) are text, except the
+    /// measure column.
+    pub(crate) fn new(driver: &str, layer: &RestServiceMetadata, columns: &[String], measure_column: Option<&str>) -> Self {
+        let columns = columns.iter()
+            .enumerate()
+            .filter_map(|(index, name)| {
+                let field = layer.fields.iter().find(|field| field.name == *name);
+                let kind = match field {
+                    Some(field) if field.field_type == RestServiceFieldType::Geometry => return None,
+                    Some(field) => ColumnKind::of(&field.field_type),
+                    None if measure_column == Some(name.as_str()) => ColumnKind::Real,
+                    None => ColumnKind::Text,
+                };
+                Some(OgrColumn { name: name.to_owned(), kind, index })
+            })
+            .collect();
+        let srid = layer.output_spatial_reference()
+            .map(|wkid| if projection::same_spatial_reference(wkid, 3857) { 3857 } else { wkid });
+        Self {
+            driver: driver.to_owned(),
+            name: layer.name.to_owned(),
+            columns,
+            geo_type: layer.geo_type.clone(),
+            srid,
+        }
+    }
+
+    /// Keep the record of a feature and its WKT geometry for the chunk's features.
+    pub(crate) fn push(&self, features: &mut OgrFeatures, record: &[String], feature: &Map<String, Value>) {
+        features.records.push(record.to_vec());
+        features.geometries.push(wkt::esri_to_wkt(&self.geo_type, feature));
+    }
+}
+
+#[cfg(feature = "gdal")]
+fn gdal_error(error: GdalError) -> io::Error {
+    io::Error::other(error)
+}
+
+/// Extension of the files written by the OGR driver `driver`, its name in lower case when the
+/// driver does not declare one.
+#[cfg(feature = "gdal")]
+pub(crate) fn driver_extension(driver: &str) -> String {
+    DriverManager::get_driver_by_name(driver)
+        .ok()
+        .and_then(|driver| driver.metadata_item("DMD_EXTENSION", ""))
+        .and_then(|extensions| extensions.split_whitespace().next().map(str::to_owned))
+        .unwrap_or_else(|| driver.to_lowercase())
+}
+
+#[cfg(not(feature = "gdal"))]
+pub(crate) fn driver_extension(driver: &str) -> String {
+    driver.to_lowercase()
+}
+
+/// Fail unless `driver` is an OGR driver able to create vector files.
+#[cfg(feature = "gdal")]
+pub(crate) fn check_driver(driver: &str) -> io::Result<()> {
+    let found = DriverManager::get_driver_by_name(driver).map_err(gdal_error)?;
+    if found.metadata_item("DCAP_VECTOR", "").is_none() || found.metadata_item("DCAP_CREATE", "").is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("The OGR driver {} cannot create vector files", driver),
+        ))
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "gdal"))]
+pub(crate) fn check_driver(_driver: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, CapabilityError::Disabled(Capability::Gdal)))
+}
+
+#[cfg(feature = "gdal")]
+fn geometry_type(geo_type: &RestServiceGeometryType) -> OGRwkbGeometryType::Type {
+    match geo_type {
+        RestServiceGeometryType::Point => OGRwkbGeometryType::wkbPoint,
+        RestServiceGeometryType::Multipoint => OGRwkbGeometryType::wkbMultiPoint,
+        // Each feature keeps its single or multi part type
+        RestServiceGeometryType::Polyline | RestServiceGeometryType::Polygon => OGRwkbGeometryType::wkbUnknown,
+        RestServiceGeometryType::Envelope => OGRwkbGeometryType::wkbPolygon,
+        RestServiceGeometryType::None => OGRwkbGeometryType::wkbNone,
+    }
+}
+
+#[cfg(feature = "gdal")]
+fn field_type(kind: ColumnKind) -> OGRFieldType::Type {
+    match kind {
+        ColumnKind::Integer => OGRFieldType::OFTInteger64,
+        ColumnKind::Real => OGRFieldType::OFTReal,
+        ColumnKind::DateTime => OGRFieldType::OFTDateTime,
+        ColumnKind::Text => OGRFieldType::OFTString,
+    }
+}
+
+/// Files next to `path` sharing its name, the files of a multi-file format like MapInfo TAB.
+#[cfg(feature = "gdal")]
+fn sibling_files(path: &Path) -> BTreeSet<PathBuf> {
+    let stem = path.file_stem().map(|stem| stem.to_owned());
+    path.parent()
+        .and_then(|directory| std::fs::read_dir(directory).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|sibling| sibling.is_file() && sibling.file_stem().map(|stem| stem.to_owned()) == stem)
+        .collect()
+}
+
+/// Writes the features of a layer's chunks through an OGR driver, a transaction per chunk when
+/// the driver supports them.
+#[cfg(feature = "gdal")]
+pub(crate) struct OgrWriter {
+    layout: OgrLayout,
+    dataset: Dataset,
+    /// Names of the fields as created, drivers may launder them
+    field_names: Vec<String>,
+    /// Whether the driver supports transactions
+    transactions: bool,
+    path: PathBuf,
+}
+
+#[cfg(feature = "gdal")]
+fn create_features(dataset: &Dataset, layout: &OgrLayout, field_names: &[String], features: &OgrFeatures) -> gdal::errors::Result<()> {
+    let layer = dataset.layer(0)?;
+    for (record, wkt) in features.records.iter().zip(&features.geometries) {
+        let mut feature = Feature::new(layer.defn())?;
+        for (column, name) in layout.columns.iter().zip(field_names) {
+            match record.get(column.index).and_then(|value| column.kind.value(value)) {
+                Some(OgrValue::Integer(value)) => feature.set_field_integer64(name, value)?,
+                Some(OgrValue::Real(value)) => feature.set_field_double(name, value)?,
+                Some(OgrValue::Text(value)) => feature.set_field_string(name, &value)?,
+                None => {}
+            }
+        }
+        if !wkt.is_empty() {
+            feature.set_geometry(Geometry::from_wkt(wkt)?)?;
+        }
+        feature.create(&layer)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "gdal")]
+impl OgrWriter {
+    pub(crate) fn create(path: &Path, layout: OgrLayout) -> io::Result<Self> {
+        let driver = DriverManager::get_driver_by_name(&layout.driver).map_err(gdal_error)?;
+        let mut dataset = driver.create_vector_only(path).map_err(gdal_error)?;
+        let srs = layout.srid
+            .and_then(|srid| u32::try_from(srid).ok())
+            .and_then(|code| SpatialRef::from_epsg(code).ok())
+            .map(|mut srs| {
+                // Esri geometries are in x, y order whatever the axis order of the definition
+                srs.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+                srs
+            });
+        let field_names = {
+            let layer = dataset.create_layer(LayerOptions {
+                name: &layout.name,
+                srs: srs.as_ref(),
+                ty: geometry_type(&layout.geo_type),
+                options: None,
+            }).map_err(gdal_error)?;
+            for column in &layout.columns {
+                FieldDefn::new(&column.name, field_type(column.kind))
+                    .and_then(|field| field.add_to_layer(&layer))
+                    .map_err(gdal_error)?;
+            }
+            layer.defn().fields().map(|field| field.name()).collect()
+        };
+        let transactions = dataset.start_transaction()
+            .and_then(|transaction| transaction.rollback())
+            .is_ok();
+        Ok(Self { layout, dataset, field_names, transactions, path: path.to_owned() })
+    }
+
+    /// Append the features of a chunk.
+    pub(crate) fn write(&mut self, features: OgrFeatures) -> io::Result<()> {
+        if !self.transactions {
+            return create_features(&self.dataset, &self.layout, &self.field_names, &features).map_err(gdal_error)
+        }
+        let transaction = self.dataset.start_transaction().map_err(gdal_error)?;
+        create_features(&transaction, &self.layout, &self.field_names, &features).map_err(gdal_error)?;
+        transaction.commit().map_err(gdal_error)
+    }
+
+    /// Close the dataset and checksum every file the driver wrote.
+    pub(crate) fn finish(self) -> io::Result<Vec<Artifact>> {
+        self.dataset.close().map_err(gdal_error)?;
+        let mut artifacts = vec![];
+        for path in sibling_files(&self.path) {
+            if path.extension().is_some_and(|extension| extension == "sha256") {
+                continue
+            }
+            artifacts.push(Artifact::from_file(&path)?);
+        }
+        Ok(artifacts)
+    }
+}
+
+/// Stands in for the layout when OGR outputs are left out of the build, keeping no features.
+#[cfg(not(feature = "gdal"))]
+#[derive(Debug, Clone)]
+pub(crate) struct OgrLayout;
+
+#[cfg(not(feature = "gdal"))]
+impl OgrLayout {
+    pub(crate) fn new(_driver: &str, _layer: &RestServiceMetadata, _columns: &[String], _measure_column: Option<&str>) -> Self {
+        OgrLayout
+    }
+
+    pub(crate) fn push(&self, _features: &mut OgrFeatures, _record: &[String], _feature: &Map<String, Value>) {}
+}
+
+#[cfg(not(feature = "gdal"))]
+#[derive(Debug, Default)]
+pub(crate) struct OgrFeatures {}
+
+#[cfg(not(feature = "gdal"))]
+impl OgrFeatures {
+    pub(crate) fn len(&self) -> usize {
+        0
+    }
+
+    pub(crate) fn truncate(&mut self, _len: usize) {}
+}
+
+/// Cannot be created when OGR outputs are left out of the build.
+#[cfg(not(feature = "gdal"))]
+pub(crate) enum OgrWriter {}
+
+#[cfg(not(feature = "gdal"))]
+impl OgrWriter {
+    pub(crate) fn create(_path: &Path, _layout: OgrLayout) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, CapabilityError::Disabled(Capability::Gdal)))
+    }
+
+    pub(crate) fn write(&mut self, _features: OgrFeatures) -> io::Result<()> {
+        match *self {}
+    }
+
+    pub(crate) fn finish(self) -> io::Result<Vec<Artifact>> {
+        match self {}
+    }
+}
+
+#[cfg(all(test, feature = "gdal"))]
+mod ogr_tests {
+    use super::{ColumnKind, OgrValue};
+
+    #[test]
+    fn value_should_convert_to_field_kind_when_value_parses() {
+        assert_eq!(ColumnKind::Integer.value("42.0"), Some(OgrValue::Integer(42)));
+        assert_eq!(ColumnKind::Integer.value("4.5"), None);
+        assert_eq!(ColumnKind::Real.value("4.5"), Some(OgrValue::Real(4.5)));
+        assert_eq!(
+            ColumnKind::DateTime.value("1600000000000"),
+            Some(OgrValue::Text("2020-09-13T12:26:40.000Z".to_owned())),
+        );
+        assert_eq!(ColumnKind::Text.value(""), None);
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use crate::checksum::{Artifact, ChecksumFile, SuspendedFile};
+use crate::scraping::create_output_file;
+
+/// Partition files kept open at once. Layers split into more partitions close the least
+/// recently written file before opening another, rather than running out of file handles.
+const MAX_OPEN_PARTITIONS: usize = 64;
+
+/// Partition of a feature, the value of the `field` attribute (matched case-insensitively) as
+/// text. Missing and null values share the NULL partition.
+pub(crate) fn partition_value(feature: &Map<String, Value>, field: &str) -> String {
+    let value = feature.get("attributes")
+        .and_then(Value::as_object)
+        .and_then(|attributes| {
+            attributes.iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(field))
+                .map(|(_, value)| value)
+        });
+    match value {
+        None | Some(Value::Null) => "NULL".to_owned(),
+        Some(Value::String(string)) => string.to_owned(),
+        Some(other) => other.to_string(),
+    }
+}
+
+/// Partition value usable as part of a file name on every platform.
+pub(crate) fn file_name_part(value: &str) -> String {
+    let part: String = value.trim()
+        .chars()
+        .map(|chr| if chr.is_alphanumeric() || chr == '-' || chr == '.' { chr } else { '_' })
+        .collect();
+    if part.is_empty() { "EMPTY".to_owned() } else { part }
+}
+
+/// Part of the file names of a partition. Values that are not already a lowercase file name
+/// get a short hash of the value appended, so values differing only in replaced characters
+/// ("A/B" and "A_B") or in case (which case-insensitive file systems do not tell apart) never
+/// share a file.
+pub(crate) fn partition_file_part(value: &str) -> String {
+    let part = file_name_part(value);
+    if part == value && !value.chars().any(char::is_uppercase) {
+        return part
+    }
+    let hash = format!("{:x}", Sha256::digest(value.as_bytes()));
+    format!("{}_{}", part, &hash[..8])
+}
+
+#[derive(Debug)]
+enum PartitionFile {
+    Open { file: ChecksumFile, last_written: u64 },
+    Closed(SuspendedFile),
+}
+
+/// Output file of each partition of a layer, created with the header row the first time a
+/// record of the partition is written. At most [`MAX_OPEN_PARTITIONS`] files are open at once.
+#[derive(Debug)]
+pub(crate) struct PartitionWriters {
+    directory: PathBuf,
+    prefix: String,
+    columns: Vec<String>,
+    files: HashMap<String, PartitionFile>,
+    /// Appends so far, ordering the open files by when they were last written
+    appends: u64,
+}
+
+impl PartitionWriters {
+    /// Writers of files named `{prefix}_{value}.csv` in `directory`.
+    pub(crate) fn new(directory: &Path, prefix: &str, columns: Vec<String>) -> Self {
+        Self {
+            directory: directory.to_owned(),
+            prefix: prefix.to_owned(),
+            columns,
+            files: HashMap::new(),
+            appends: 0,
+        }
+    }
+
+    /// Append the records of `value` from a chunk's temp file to the partition's output file.
+    pub(crate) fn append(&mut self, value: &str, records: &mut File) -> io::Result<()> {
+        let file_name = format!("{}_{}.csv", self.prefix, partition_file_part(value));
+        self.appends += 1;
+        let mut file = match self.files.remove(&file_name) {
+            Some(PartitionFile::Open { file, .. }) => file,
+            Some(PartitionFile::Closed(suspended)) => {
+                self.close_least_recent()?;
+                suspended.resume()?
+            }
+            None => {
+                self.close_least_recent()?;
+                create_output_file(&self.directory.join(&file_name), &self.columns)?
+            }
+        };
+        records.seek(SeekFrom::Start(0))?;
+        io::copy(records, &mut file)?;
+        self.files.insert(file_name, PartitionFile::Open { file, last_written: self.appends });
+        Ok(())
+    }
+
+    /// Close the least recently written file when another one is about to be opened while
+    /// [`MAX_OPEN_PARTITIONS`] are open.
+    fn close_least_recent(&mut self) -> io::Result<()> {
+        let open = self.files.values()
+            .filter(|file| matches!(file, PartitionFile::Open { .. }))
+            .count();
+        if open < MAX_OPEN_PARTITIONS {
+            return Ok(())
+        }
+        let least_recent = self.files.iter()
+            .filter_map(|(name, file)| match file {
+                PartitionFile::Open { last_written, .. } => Some((*last_written, name.to_owned())),
+                PartitionFile::Closed(_) => None,
+            })
+            .min();
+        if let Some((_, name)) = least_recent {
+            if let Some(PartitionFile::Open { file, .. }) = self.files.remove(&name) {
+                self.files.insert(name, PartitionFile::Closed(file.suspend()?));
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of partition files written.
+    pub(crate) fn file_count(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Finish every partition file, returning them in name order.
+    pub(crate) fn finish(self) -> io::Result<Vec<Artifact>> {
+        let mut files: Vec<(String, PartitionFile)> = self.files.into_iter().collect();
+        files.sort_by(|(name, _), (other, _)| name.cmp(other));
+        files.into_iter()
+            .map(|(_, file)| match file {
+                PartitionFile::Open { file, .. } => file.finish(),
+                PartitionFile::Closed(suspended) => suspended.resume()?.finish(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod partition_tests {
+    use std::io::Write;
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+    use super::{file_name_part, partition_file_part, partition_value, PartitionWriters, MAX_OPEN_PARTITIONS};
+
+    #[test]
+    fn partition_value_should_match_field_case_insensitively() {
+        let feature = json!({"attributes": {"COUNTY_FIPS": "06037", "CODE": 12, "NOTE": null}});
+        let feature = feature.as_object().unwrap();
+        assert_eq!(partition_value(feature, "county_fips"), "06037");
+        assert_eq!(partition_value(feature, "CODE"), "12");
+        assert_eq!(partition_value(feature, "NOTE"), "NULL");
+    }
+
+    #[test]
+    fn file_name_part_should_replace_path_separators() {
+        assert_eq!(file_name_part("Los Angeles/County"), "Los_Angeles_County");
+        assert_eq!(file_name_part("  "), "EMPTY");
+    }
+
+    #[test]
+    fn partition_file_part_should_add_hash_when_values_could_share_a_file() {
+        assert_eq!(partition_file_part("06037"), "06037");
+        assert_ne!(partition_file_part("A/B"), partition_file_part("A_B"));
+        assert!(partition_file_part("A/B").starts_with("A_B_"));
+        assert_ne!(partition_file_part("Foo").to_lowercase(), partition_file_part("foo").to_lowercase());
+        assert_ne!(partition_file_part(""), partition_file_part("EMPTY"));
+    }
+
+    #[test]
+    fn append_should_reopen_closed_files_when_more_partitions_than_open_limit() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut writers = PartitionWriters::new(directory.path(), "Parcels", vec!["CODE".to_owned()]);
+        let mut append = |value: &str| {
+            let mut records = tempfile::tempfile().unwrap();
+            writeln!(records, "{}", value).unwrap();
+            writers.append(value, &mut records).unwrap();
+        };
+        for _ in 0..2 {
+            for code in 0..MAX_OPEN_PARTITIONS + 1 {
+                append(&code.to_string());
+            }
+        }
+        let artifacts = writers.finish().unwrap();
+        assert_eq!(artifacts.len(), MAX_OPEN_PARTITIONS + 1);
+        let first = std::fs::read_to_string(directory.path().join("Parcels_0.csv")).unwrap();
+        assert_eq!(first, "CODE\n0\n0\n");
+        assert_eq!(artifacts[0].sha256, format!("{:x}", Sha256::digest(first.as_bytes())));
+    }
+}
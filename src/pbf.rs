@@ -0,0 +1,476 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use serde_json::{json, Map, Value};
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_BYTES: u8 = 2;
+const WIRE_FIXED32: u8 = 5;
+
+/// Origin of the quantized y values, `upperLeft` counting downwards.
+const ORIGIN_UPPER_LEFT: u64 = 0;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum PbfError {
+    Truncated,
+    InvalidWireType(u8),
+    MissingFeatureResult,
+}
+
+impl Display for PbfError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PbfError::Truncated => write!(f, "PBF response ended in the middle of a message"),
+            PbfError::InvalidWireType(wire_type) => {
+                write!(f, "PBF response has a field of unknown wire type {}", wire_type)
+            }
+            PbfError::MissingFeatureResult => write!(f, "PBF response has no feature result"),
+        }
+    }
+}
+
+impl Error for PbfError {}
+
+/// Raw value of a protobuf field.
+#[derive(Debug, Clone, Copy)]
+enum FieldValue<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+impl<'a> FieldValue<'a> {
+    fn varint(&self) -> u64 {
+        match self {
+            FieldValue::Varint(value) | FieldValue::Fixed64(value) => *value,
+            FieldValue::Fixed32(value) => *value as u64,
+            FieldValue::Bytes(_) => 0,
+        }
+    }
+
+    fn double(&self) -> f64 {
+        match self {
+            FieldValue::Fixed64(value) => f64::from_bits(*value),
+            _ => 0_f64,
+        }
+    }
+
+    fn bytes(&self) -> &'a [u8] {
+        match self {
+            FieldValue::Bytes(bytes) => bytes,
+            _ => &[],
+        }
+    }
+
+    fn string(&self) -> String {
+        String::from_utf8_lossy(self.bytes()).into_owned()
+    }
+
+    /// Values of a repeated scalar field, packed or not.
+    fn packed(&self) -> Result<Vec<u64>, PbfError> {
+        match self {
+            FieldValue::Bytes(bytes) => {
+                let mut position = 0;
+                let mut values = vec![];
+                while position < bytes.len() {
+                    values.push(read_varint(bytes, &mut position)?);
+                }
+                Ok(values)
+            }
+            other => Ok(vec![other.varint()]),
+        }
+    }
+}
+
+fn read_varint(bytes: &[u8], position: &mut usize) -> Result<u64, PbfError> {
+    let mut value = 0_u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *bytes.get(*position).ok_or(PbfError::Truncated)?;
+        *position += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value)
+        }
+    }
+    Err(PbfError::Truncated)
+}
+
+fn read_slice<'a>(bytes: &'a [u8], position: &mut usize, length: usize) -> Result<&'a [u8], PbfError> {
+    let end = position.checked_add(length).filter(|end| *end <= bytes.len()).ok_or(PbfError::Truncated)?;
+    let slice = &bytes[*position..end];
+    *position = end;
+    Ok(slice)
+}
+
+/// Every field of a message, in the order written.
+fn read_fields(bytes: &[u8]) -> Result<Vec<(u64, FieldValue<'_>)>, PbfError> {
+    let mut position = 0;
+    let mut fields = vec![];
+    while position < bytes.len() {
+        let key = read_varint(bytes, &mut position)?;
+        let value = match (key & 0x7) as u8 {
+            WIRE_VARINT => FieldValue::Varint(read_varint(bytes, &mut position)?),
+            WIRE_FIXED64 => {
+                let slice = read_slice(bytes, &mut position, 8)?;
+                FieldValue::Fixed64(u64::from_le_bytes(slice.try_into().unwrap()))
+            }
+            WIRE_BYTES => {
+                let length = read_varint(bytes, &mut position)? as usize;
+                FieldValue::Bytes(read_slice(bytes, &mut position, length)?)
+            }
+            WIRE_FIXED32 => {
+                let slice = read_slice(bytes, &mut position, 4)?;
+                FieldValue::Fixed32(u32::from_le_bytes(slice.try_into().unwrap()))
+            }
+            wire_type => return Err(PbfError::InvalidWireType(wire_type)),
+        };
+        fields.push((key >> 3, value));
+    }
+    Ok(fields)
+}
+
+fn zigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+/// Attribute value of a feature, null when no variant is set.
+fn decode_value(bytes: &[u8]) -> Result<Value, PbfError> {
+    let value = match read_fields(bytes)?.last() {
+        Some((1, value)) => Value::String(value.string()),
+        Some((2, value)) => {
+            // Go through the shortest text of the float so 0.1 does not become 0.10000000149
+            let single = f32::from_bits(value.varint() as u32);
+            single.to_string().parse::<f64>().map(Value::from).unwrap_or(Value::Null)
+        }
+        Some((3, value)) => Value::from(value.double()),
+        Some((4, value)) => Value::from(zigzag(value.varint()) as i32),
+        Some((5, value)) => Value::from(value.varint() as u32),
+        Some((6, value)) => Value::from(value.varint() as i64),
+        Some((7, value)) => Value::from(value.varint()),
+        Some((8, value)) => Value::from(zigzag(value.varint())),
+        Some((9, value)) => Value::from(value.varint() != 0),
+        _ => Value::Null,
+    };
+    Ok(value)
+}
+
+/// Scale and translation turning the integer coordinates of each dimension (x, y, z, m) back
+/// into the output spatial reference.
+#[derive(Debug, Clone)]
+struct Transform {
+    upper_left: bool,
+    scale: [f64; 4],
+    translate: [f64; 4],
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self { upper_left: false, scale: [1_f64; 4], translate: [0_f64; 4] }
+    }
+}
+
+impl Transform {
+    fn decode(bytes: &[u8]) -> Result<Self, PbfError> {
+        let mut transform = Transform::default();
+        for (field, value) in read_fields(bytes)? {
+            match field {
+                1 => transform.upper_left = value.varint() == ORIGIN_UPPER_LEFT,
+                2 | 3 => {
+                    // Messages list x, y, m then z
+                    let target = if field == 2 { &mut transform.scale } else { &mut transform.translate };
+                    for (index, value) in read_fields(value.bytes())? {
+                        let dimension = match index {
+                            1 => 0,
+                            2 => 1,
+                            3 => 3,
+                            4 => 2,
+                            _ => continue,
+                        };
+                        target[dimension] = value.double();
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(transform)
+    }
+
+    fn apply(&self, dimension: usize, value: i64) -> f64 {
+        let scale = self.scale[dimension];
+        let offset = value as f64 * scale;
+        let coordinate = if dimension == 1 && self.upper_left {
+            self.translate[dimension] - offset
+        } else {
+            self.translate[dimension] + offset
+        };
+        // Drop the float noise below the precision of the quantization
+        let decimals = (-scale.log10()).ceil().clamp(0_f64, 15_f64) as i32;
+        let factor = 10_f64.powi(decimals);
+        (coordinate * factor).round() / factor
+    }
+}
+
+/// Whole coordinates are written without a fraction, as they are in the JSON responses.
+fn coordinate_value(coordinate: f64) -> Value {
+    if coordinate.fract() == 0_f64 && coordinate.abs() < 1e15 {
+        Value::from(coordinate as i64)
+    } else {
+        Value::from(coordinate)
+    }
+}
+
+/// Esri JSON geometry of a feature's delta encoded coordinates.
+fn decode_geometry(
+    bytes: &[u8],
+    geometry_type: u64,
+    dimensions: usize,
+    has_z: bool,
+    transform: &Transform,
+) -> Result<Option<Value>, PbfError> {
+    let mut lengths = vec![];
+    let mut coordinates = vec![];
+    for (field, value) in read_fields(bytes)? {
+        match field {
+            2 => lengths.extend(value.packed()?),
+            3 => coordinates.extend(value.packed()?.into_iter().map(zigzag)),
+            _ => {}
+        }
+    }
+    let mut current = vec![0_i64; dimensions];
+    let positions: Vec<Value> = coordinates.chunks_exact(dimensions)
+        .map(|deltas| {
+            let position: Vec<Value> = deltas.iter()
+                .enumerate()
+                .map(|(dimension, delta)| {
+                    current[dimension] += delta;
+                    coordinate_value(transform.apply(dimension, current[dimension]))
+                })
+                .collect();
+            Value::from(position)
+        })
+        .collect();
+    if positions.is_empty() {
+        return Ok(None)
+    }
+    if lengths.is_empty() {
+        lengths.push(positions.len() as u64);
+    }
+    let mut parts = vec![];
+    let mut start = 0;
+    for length in lengths {
+        let end = (start + length as usize).min(positions.len());
+        parts.push(Value::from(positions[start..end].to_vec()));
+        start = end;
+    }
+    let geometry = match geometry_type {
+        0 => {
+            let position = positions[0].as_array().unwrap();
+            let mut point = json!({"x": position[0], "y": position[1]});
+            if has_z {
+                point["z"] = position[2].to_owned();
+            }
+            point
+        }
+        1 => json!({"points": positions}),
+        2 => json!({"paths": parts}),
+        _ => json!({"rings": parts}),
+    };
+    Ok(Some(geometry))
+}
+
+/// Esri `f=pbf` query response (FeatureCollectionPBuffer) whose features are decoded one at a
+/// time into the shape of the `f=json` features, so they go through the same pipeline whatever
+/// the format.
+pub(crate) struct PbfFeatureCollection<'a> {
+    geometry_type: u64,
+    dimensions: usize,
+    has_z: bool,
+    transform: Transform,
+    names: Vec<String>,
+    features: Vec<&'a [u8]>,
+    pub(crate) exceeded_transfer_limit: bool,
+}
+
+impl<'a> PbfFeatureCollection<'a> {
+    /// Decode the header of the response, leaving its features encoded.
+    pub(crate) fn decode(bytes: &'a [u8]) -> Result<Self, PbfError> {
+        let query_result = read_fields(bytes)?
+            .into_iter()
+            .find(|(field, _)| *field == 2)
+            .ok_or(PbfError::MissingFeatureResult)?
+            .1;
+        let feature_result = read_fields(query_result.bytes())?
+            .into_iter()
+            .find(|(field, _)| *field == 1)
+            .ok_or(PbfError::MissingFeatureResult)?
+            .1;
+        let mut geometry_type = 127;
+        let (mut has_z, mut has_m, mut exceeded_transfer_limit) = (false, false, false);
+        let mut transform = Transform::default();
+        let mut names = vec![];
+        let mut features = vec![];
+        for (field, value) in read_fields(feature_result.bytes())? {
+            match field {
+                7 => geometry_type = value.varint(),
+                9 => exceeded_transfer_limit = value.varint() != 0,
+                10 => has_z = value.varint() != 0,
+                11 => has_m = value.varint() != 0,
+                12 => transform = Transform::decode(value.bytes())?,
+                13 => {
+                    let name = read_fields(value.bytes())?
+                        .into_iter()
+                        .find(|(field, _)| *field == 1)
+                        .map(|(_, name)| name.string())
+                        .unwrap_or_default();
+                    names.push(name);
+                }
+                15 => features.push(value.bytes()),
+                _ => {}
+            }
+        }
+        if has_m && !has_z {
+            // Without z the m values sit in the third position, so use the m transform there
+            transform.scale[2] = transform.scale[3];
+            transform.translate[2] = transform.translate[3];
+        }
+        Ok(Self {
+            geometry_type,
+            dimensions: 2 + has_z as usize + has_m as usize,
+            has_z,
+            transform,
+            names,
+            features,
+            exceeded_transfer_limit,
+        })
+    }
+
+    /// Esri JSON of each feature, decoded as it is reached.
+    pub(crate) fn features(&self) -> impl Iterator<Item = Result<Value, PbfError>> + '_ {
+        self.features.iter().map(|bytes| self.decode_feature(bytes))
+    }
+
+    fn decode_feature(&self, bytes: &[u8]) -> Result<Value, PbfError> {
+        let mut attributes = Map::new();
+        let mut geometry = None;
+        let mut attribute_index = 0;
+        for (field, value) in read_fields(bytes)? {
+            match field {
+                1 => {
+                    if let Some(name) = self.names.get(attribute_index) {
+                        attributes.insert(name.to_owned(), decode_value(value.bytes())?);
+                    }
+                    attribute_index += 1;
+                }
+                2 => {
+                    geometry = decode_geometry(
+                        value.bytes(),
+                        self.geometry_type,
+                        self.dimensions,
+                        self.has_z,
+                        &self.transform,
+                    )?
+                }
+                _ => {}
+            }
+        }
+        let mut feature = Map::new();
+        feature.insert("attributes".to_owned(), Value::Object(attributes));
+        if let Some(geometry) = geometry {
+            feature.insert("geometry".to_owned(), geometry);
+        }
+        Ok(Value::Object(feature))
+    }
+}
+
+#[cfg(test)]
+mod pbf_tests {
+    use serde_json::json;
+    use super::{PbfError, PbfFeatureCollection};
+
+    fn varint(mut value: u64, buffer: &mut Vec<u8>) {
+        while value >= 0x80 {
+            buffer.push((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+        buffer.push(value as u8);
+    }
+
+    fn message(field: u64, bytes: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![];
+        varint(field << 3 | 2, &mut buffer);
+        varint(bytes.len() as u64, &mut buffer);
+        buffer.extend_from_slice(bytes);
+        buffer
+    }
+
+    fn scalar(field: u64, value: u64) -> Vec<u8> {
+        let mut buffer = vec![];
+        varint(field << 3, &mut buffer);
+        varint(value, &mut buffer);
+        buffer
+    }
+
+    fn double(field: u64, value: f64) -> Vec<u8> {
+        let mut buffer = vec![];
+        varint(field << 3 | 1, &mut buffer);
+        buffer.extend_from_slice(&value.to_le_bytes());
+        buffer
+    }
+
+    fn packed(field: u64, values: &[u64]) -> Vec<u8> {
+        let mut bytes = vec![];
+        for value in values {
+            varint(*value, &mut bytes);
+        }
+        message(field, &bytes)
+    }
+
+    fn zigzag(value: i64) -> u64 {
+        ((value << 1) ^ (value >> 63)) as u64
+    }
+
+    #[test]
+    fn features_should_rebuild_esri_json_when_passed_polygon() {
+        let transform = [
+            scalar(1, 0),
+            message(2, &[double(1, 0.5), double(2, 0.5)].concat()),
+            message(3, &[double(1, 100.0), double(2, 50.0)].concat()),
+        ].concat();
+        let geometry = [
+            packed(2, &[4]),
+            packed(3, &[0, 0, 2, 0, 0, 2, -2, 0].map(zigzag)),
+        ].concat();
+        let feature = [
+            message(1, &scalar(6, 7)),
+            message(1, &message(1, b"Main St")),
+            message(1, &[]),
+            message(2, &geometry),
+        ].concat();
+        let feature_result = [
+            scalar(7, 3),
+            scalar(9, 1),
+            message(12, &transform),
+            message(13, &message(1, b"OBJECTID")),
+            message(13, &message(1, b"NAME")),
+            message(13, &message(1, b"NOTE")),
+            message(15, &feature),
+        ].concat();
+        let bytes = [message(1, b"1.0"), message(2, &message(1, &feature_result))].concat();
+        let collection = PbfFeatureCollection::decode(&bytes).unwrap();
+        assert!(collection.exceeded_transfer_limit);
+        assert_eq!(
+            collection.features().collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![json!({
+                "attributes": {"OBJECTID": 7, "NAME": "Main St", "NOTE": null},
+                "geometry": {"rings": [[[100, 50], [101, 50], [101, 49], [100, 49]]]},
+            })],
+        );
+    }
+
+    #[test]
+    fn decode_should_fail_when_message_truncated() {
+        let bytes = message(2, &message(1, &scalar(7, 3)));
+        assert_eq!(PbfFeatureCollection::decode(&bytes[..bytes.len() - 1]).err(), Some(PbfError::Truncated));
+    }
+}
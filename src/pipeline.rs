@@ -0,0 +1,853 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+use conv::*;
+use indicatif::HumanBytes;
+use reqwest::Url;
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use crate::checksum::ChecksumFile;
+use crate::deadline::{Deadline, DeadlineError};
+use crate::disk::{DiskSpaceError, DiskSpaceEstimate};
+use crate::flatgeobuf::FlatgeobufWriter;
+use crate::geoparquet::GeoParquetWriter;
+use crate::geometry_guard::{GeometryMismatches, GeometryPolicy};
+use crate::metadata::RestServiceMetadata;
+use crate::meter::{self, RequestLimitError};
+use crate::ogr::OgrWriter;
+use crate::partition::PartitionWriters;
+use crate::progress::{LayerRef, LayerSummary, ProgressTracker};
+use crate::quadtree::SeenObjectIds;
+use crate::report::WarningKind;
+use crate::sampling::{SampleMethod, SampleSize};
+use crate::scraping::{FetchOptions, FetchedChunk, ResponseFormat, ShortPages};
+use crate::shapefile::ShapefileWriter;
+use crate::strategy::{ScrapeStrategy, StrategyError};
+use crate::suspicious::{SuspiciousCoordinates, SuspiciousPolicy};
+use crate::transform::{DomainViolations, NumericAnomalies};
+use crate::{disk, profile, report, sampling, scraping, strategy};
+
+/// Uncompressed response bytes of a layer past which the server's lack of compression is
+/// worth a warning.
+const UNCOMPRESSED_WARNING_BYTES: usize = 100 * 1024 * 1024;
+
+/// How the features of a layer are picked and split into chunk queries, shared by the CLI and
+/// the library's [`crate::Scraper`].
+#[derive(Debug, Clone)]
+pub(crate) struct ScrapeSettings {
+    pub(crate) strategy: ScrapeStrategy,
+    /// Only scrape a sample of the layer
+    pub(crate) sample: Option<SampleSize>,
+    pub(crate) sample_method: SampleMethod,
+    /// Warn instead of failing when the estimated size exceeds the free disk space
+    pub(crate) ignore_disk_space: bool,
+    /// Chunks fetching or waiting to be written at once, the backpressure of slow outputs
+    pub(crate) max_pending_chunks: usize,
+}
+
+/// Format requested for the layer's queries, json when pbf was asked for but the layer does
+/// not support it.
+pub(crate) fn response_format(layer: &RestServiceMetadata, format: &ResponseFormat) -> ResponseFormat {
+    match format {
+        ResponseFormat::Auto if layer.supports_pbf() => ResponseFormat::Pbf,
+        ResponseFormat::Pbf if !layer.supports_pbf() => {
+            report::warn(format_args!(
+                "Layer \"{}\" does not support PBF queries, using json",
+                layer.name,
+            ));
+            ResponseFormat::Json
+        }
+        ResponseFormat::Auto => ResponseFormat::Json,
+        format => *format,
+    }
+}
+
+type ChunkHandle = JoinHandle<Result<FetchedChunk, Box<dyn Error + Sync + Send>>>;
+
+/// Fetch worker of a chunk, aborted when dropped before it is collected, so the workers of a
+/// layer whose fetch returned early (a failed chunk, a full disk, a failed write) stop with it.
+struct ChunkTask(ChunkHandle);
+
+impl Drop for ChunkTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Whether `error` stops the run rather than the scrape of a layer, as --max-duration and
+/// --max-requests do. What was fetched before is kept and the run writes its checkpoint.
+fn stops_run(error: &(dyn Error + Send + Sync + 'static)) -> bool {
+    error.is::<DeadlineError>() || error.is::<RequestLimitError>()
+}
+
+/// Whether the run was stopped by --max-duration or --max-requests.
+fn is_stopped(fetch_options: &FetchOptions) -> bool {
+    fetch_options.deadline.as_ref().is_some_and(Deadline::is_reached) || meter::limit_reached().is_some()
+}
+
+/// Wait for the chunk of `task` and hand it to `writer`, or keep it in `chunks` without one.
+/// Chunks stopped by the deadline or the request limit are left out.
+async fn collect_chunk(
+    mut task: ChunkTask,
+    chunks: &mut Vec<FetchedChunk>,
+    writer: &mut Option<&mut ChunkWriter<'_>>,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let chunk = match (&mut task.0).await? {
+        Ok(chunk) => chunk,
+        Err(error) if stops_run(error.as_ref()) => return Ok(()),
+        Err(error) => return Err(error),
+    };
+    match writer {
+        Some(writer) => writer.write(chunk).await?,
+        None => chunks.push(chunk),
+    }
+    Ok(())
+}
+
+/// Fetch every query into a temp file, keeping the query order. When the output directory of
+/// `check_disk_space` is given the first query doubles as the sample of the disk space estimate. Chunks a resumed run
+/// already completed for the same queries are read back from the spool instead.
+///
+/// Chunks are collected in query order while later queries are fetched, and no query is
+/// started while `settings.max_pending_chunks` chunks are fetching or waiting to be collected,
+/// so a slow chunk or a slow `writer` holds back the fetch workers. With a `writer` the chunks
+/// are written as they are collected and none are returned.
+async fn fetch_chunks(
+    settings: &ScrapeSettings,
+    layer: &RestServiceMetadata,
+    expected_features: i64,
+    queries: Vec<String>,
+    fetch_options: Arc<FetchOptions>,
+    check_disk_space: Option<&Path>,
+    mut writer: Option<&mut ChunkWriter<'_>>,
+) -> Result<Vec<FetchedChunk>, Box<dyn Error + Sync + Send>> {
+    let mut pending: VecDeque<ChunkTask> = VecDeque::new();
+    let mut chunks = vec![];
+    let query_count = queries.len();
+    let mut resumed_chunks = BTreeMap::new();
+    if fetch_options.spool.resume_plan(&queries, fetch_options.reuses_spooled_chunks())? {
+        for chunk_id in 0..query_count {
+            if let Some(file) = fetch_options.spool.completed(chunk_id)? {
+                resumed_chunks.insert(chunk_id, FetchedChunk::resumed(file)?);
+            }
+        }
+        if !resumed_chunks.is_empty() {
+            tracing::info!(
+                layer = %layer.name,
+                "Resuming with {} of {} chunks fetched by the earlier run",
+                resumed_chunks.len(),
+                query_count,
+            );
+        }
+    }
+
+    let progress = Arc::new(ProgressTracker::new(
+        LayerRef { url: &layer.url, name: &layer.name },
+        query_count,
+        u64::value_from(expected_features)?,
+        fetch_options.progress.clone(),
+    )?);
+    let mut queries = queries.into_iter().enumerate();
+    let mut sample_chunk = None;
+    if let Some(output_path) = check_disk_space {
+        tracing::info!(layer = %layer.name, step = "1/3", "Checking available disk space");
+        if let Some((chunk_id, sample_query)) = queries.next() {
+            progress.chunk_started(chunk_id)?;
+            let chunk = match resumed_chunks.remove(&chunk_id) {
+                Some(chunk) => chunk,
+                None => scraping::fetch_query(&sample_query, chunk_id, &fetch_options).await?,
+            };
+            let estimate = DiskSpaceEstimate::from_sample(chunk.records_size()?, query_count);
+            if let Err(error) = disk::check_disk_space(fetch_options.spool.directory(), output_path, &estimate) {
+                if !settings.ignore_disk_space {
+                    return Err(error)
+                }
+                report::warn(format_args!("{}", error));
+            }
+            sample_chunk = Some(chunk);
+        }
+    }
+
+    tracing::info!(layer = %layer.name, step = "2/3", "Spawning fetch workers");
+    if let Some(chunk) = sample_chunk {
+        progress.chunk_finished(0, chunk.feature_count, chunk.bytes_downloaded)?;
+        pending.push_back(ChunkTask(tokio::spawn(async move { Ok(chunk) })));
+    }
+    for (chunk_id, query) in queries {
+        while pending.len() >= settings.max_pending_chunks.max(1) {
+            if let Some(task) = pending.pop_front() {
+                collect_chunk(task, &mut chunks, &mut writer).await?;
+            }
+        }
+        if let Some(chunk) = resumed_chunks.remove(&chunk_id) {
+            progress.chunk_started(chunk_id)?;
+            progress.chunk_finished(chunk_id, chunk.feature_count, chunk.bytes_downloaded)?;
+            pending.push_back(ChunkTask(tokio::spawn(async move { Ok(chunk) })));
+            continue
+        }
+        let fetch_options = fetch_options.clone();
+        let progress = progress.clone();
+        let handle = tokio::spawn(async move {
+            progress.chunk_started(chunk_id)?;
+            let chunk = scraping::fetch_query(
+                &query,
+                chunk_id,
+                &fetch_options,
+            ).await?;
+            progress.chunk_finished(chunk_id, chunk.feature_count, chunk.bytes_downloaded)?;
+            Ok(chunk)
+        });
+        pending.push_back(ChunkTask(handle));
+    }
+
+    tracing::info!(layer = %layer.name, step = "3/3", "Collecting fetch worker output");
+    while let Some(task) = pending.pop_front() {
+        collect_chunk(task, &mut chunks, &mut writer).await?;
+    }
+    Ok(chunks)
+}
+
+/// Where the accepted records of a layer are appended.
+pub(crate) enum RecordOutput<'a> {
+    File(&'a mut ChecksumFile),
+    Partitions(&'a mut PartitionWriters),
+    /// The records of each chunk are sent to a task writing them out, e.g. to an async writer,
+    /// waiting while the task is behind
+    Stream(&'a Sender<Vec<u8>>),
+    /// The typed rows kept by the fetch options are written as a row group per chunk
+    Parquet(&'a mut GeoParquetWriter),
+    /// The features encoded by the fetch options are spooled until the file's index is written
+    Flatgeobuf(&'a mut FlatgeobufWriter),
+    /// The shapes and attributes encoded by the fetch options are spooled until the headers
+    /// are written
+    Shapefile(&'a mut ShapefileWriter),
+    /// The records and geometries kept by the fetch options are written through OGR, a
+    /// transaction per chunk
+    Ogr(&'a mut OgrWriter),
+    /// Only the sinks of the fetch options keep the features (e.g. TopoJSON output)
+    Discard,
+}
+
+impl RecordOutput<'_> {
+    fn reborrow(&mut self) -> RecordOutput<'_> {
+        match self {
+            RecordOutput::File(file) => RecordOutput::File(file),
+            RecordOutput::Partitions(writers) => RecordOutput::Partitions(writers),
+            RecordOutput::Stream(sender) => RecordOutput::Stream(sender),
+            RecordOutput::Parquet(writer) => RecordOutput::Parquet(writer),
+            RecordOutput::Flatgeobuf(writer) => RecordOutput::Flatgeobuf(writer),
+            RecordOutput::Shapefile(writer) => RecordOutput::Shapefile(writer),
+            RecordOutput::Ogr(writer) => RecordOutput::Ogr(writer),
+            RecordOutput::Discard => RecordOutput::Discard,
+        }
+    }
+}
+
+/// Appends the records of a layer's chunks to its output as they are collected, tallying what
+/// is reported once the layer is finished.
+pub(crate) struct ChunkWriter<'a> {
+    output: RecordOutput<'a>,
+    layer: &'a RestServiceMetadata,
+    fetch_options: &'a FetchOptions,
+    features: usize,
+    bytes: usize,
+    transferred: usize,
+    decoded: usize,
+    /// Bytes of the responses the server sent without a content encoding
+    uncompressed: usize,
+    numeric_anomalies: NumericAnomalies,
+    domain_violations: DomainViolations,
+    geometry_mismatches: GeometryMismatches,
+    suspicious_coordinates: SuspiciousCoordinates,
+    short_pages: ShortPages,
+}
+
+impl<'a> ChunkWriter<'a> {
+    pub(crate) fn new(output: RecordOutput<'a>, layer: &'a RestServiceMetadata, fetch_options: &'a FetchOptions) -> Self {
+        Self {
+            output,
+            layer,
+            fetch_options,
+            features: 0,
+            bytes: 0,
+            transferred: 0,
+            decoded: 0,
+            uncompressed: 0,
+            numeric_anomalies: NumericAnomalies::default(),
+            domain_violations: DomainViolations::default(),
+            geometry_mismatches: GeometryMismatches::default(),
+            suspicious_coordinates: SuspiciousCoordinates::default(),
+            short_pages: ShortPages::default(),
+        }
+    }
+
+    /// Features written so far, and features dropped for their geometry or coordinates.
+    fn counts(&self) -> (usize, usize) {
+        (self.features, self.geometry_mismatches.dropped() + self.suspicious_coordinates.dropped())
+    }
+
+    pub(crate) async fn write(&mut self, mut chunk: FetchedChunk) -> io::Result<()> {
+        self.features += chunk.feature_count;
+        self.bytes += chunk.bytes_downloaded;
+        self.transferred += chunk.response_size.transferred;
+        self.decoded += chunk.response_size.decoded;
+        if !chunk.response_size.compressed {
+            self.uncompressed += chunk.response_size.transferred;
+        }
+        self.numeric_anomalies.merge(&chunk.numeric_anomalies);
+        self.domain_violations.merge(&chunk.domain_violations);
+        self.geometry_mismatches.merge(&chunk.geometry_mismatches);
+        self.suspicious_coordinates.merge(&chunk.suspicious_coordinates);
+        self.short_pages.merge(&chunk.short_pages);
+        if let Some(tiles) = &self.fetch_options.tiles {
+            tiles.sink.add_features(&self.layer.name, std::mem::take(&mut chunk.tile_features));
+        }
+        for topology in &self.fetch_options.topology {
+            topology.add_features(&self.layer.name, chunk.topology_features.clone());
+        }
+        if let Some(postgis) = &self.fetch_options.postgis {
+            chunk.file.seek(SeekFrom::Start(0))?;
+            let mut records = Vec::new();
+            chunk.file.read_to_end(&mut records)?;
+            postgis.send(records).await;
+        }
+        let output_file = match &mut self.output {
+            RecordOutput::File(output_file) => output_file,
+            RecordOutput::Partitions(writers) => {
+                for (value, records) in chunk.partitions.iter_mut() {
+                    writers.append(value, records)?;
+                }
+                return Ok(())
+            }
+            RecordOutput::Stream(sender) => {
+                chunk.file.seek(SeekFrom::Start(0))?;
+                let mut records = Vec::new();
+                chunk.file.read_to_end(&mut records)?;
+                return sender.send(records).await.map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+            }
+            RecordOutput::Parquet(writer) => return writer.write(std::mem::take(&mut chunk.parquet_rows)),
+            RecordOutput::Flatgeobuf(writer) => return writer.write(std::mem::take(&mut chunk.flatgeobuf_features)),
+            RecordOutput::Shapefile(writer) => return writer.write(std::mem::take(&mut chunk.shapefile_records)),
+            RecordOutput::Ogr(writer) => return writer.write(std::mem::take(&mut chunk.ogr_features)),
+            RecordOutput::Discard => return Ok(()),
+        };
+        chunk.file.seek(SeekFrom::Start(0))?;
+        io::copy(&mut chunk.file, output_file)?;
+        output_file.sync_all()
+    }
+
+    /// Log how much the layer's responses were compressed in transfer, warning when a large
+    /// part of them was sent uncompressed.
+    fn report_compression(&self) {
+        if self.transferred == 0 {
+            return
+        }
+        tracing::info!(
+            layer = %self.layer.name,
+            "Transferred {} for {} of responses ({:.1}x compression)",
+            HumanBytes(self.transferred as u64),
+            HumanBytes(self.decoded as u64),
+            self.decoded as f64 / self.transferred as f64,
+        );
+        if self.uncompressed >= UNCOMPRESSED_WARNING_BYTES {
+            report::warn(format_args!(
+                "Layer \"{}\" sent {} of responses uncompressed, the server does not honour gzip or brotli",
+                self.layer.name,
+                HumanBytes(self.uncompressed as u64),
+            ));
+        }
+    }
+
+    /// Report the anomalies of the written chunks, delete the layer's spooled chunks and report
+    /// the finished layer as a progress event. Returns the number of features written.
+    pub(crate) fn finish(self, strategy: Option<&ScrapeStrategy>) -> io::Result<usize> {
+        let layer = self.layer;
+        self.report_compression();
+        if !self.numeric_anomalies.is_empty() {
+            let policy = self.fetch_options.numeric_guard.policy();
+            self.numeric_anomalies.write_to_console(&layer.name, policy)?;
+            report::warn_about(WarningKind::CoercedValues, &layer.name, format_args!(
+                "{} NaN, Infinity or out of range values of layer \"{}\" were {}",
+                self.numeric_anomalies.count(),
+                layer.name,
+                policy.outcome(),
+            ));
+        }
+        if !self.domain_violations.is_empty() {
+            let policy = self.fetch_options.domain_guard.policy();
+            self.domain_violations.write_to_console(&layer.name, &layer.fields, policy)?;
+            report::warn_about(WarningKind::CoercedValues, &layer.name, format_args!(
+                "{} values of layer \"{}\" were outside the range domain of their field and were {}",
+                self.domain_violations.count(),
+                layer.name,
+                policy.outcome(),
+            ));
+        }
+        if !self.geometry_mismatches.is_empty() {
+            let policy = self.fetch_options.geometry_guard.policy();
+            self.geometry_mismatches.write_to_console(&layer.name, &layer.geo_type, policy)?;
+            let kind = match policy {
+                GeometryPolicy::Drop => WarningKind::DroppedFeatures,
+                _ => WarningKind::CoercedValues,
+            };
+            report::warn_about(kind, &layer.name, format_args!(
+                "{} geometries of layer \"{}\" did not match its {} geometry type and were {}",
+                self.geometry_mismatches.count(),
+                layer.name,
+                layer.geo_type,
+                policy.outcome(),
+            ));
+        }
+        if !self.suspicious_coordinates.is_empty() {
+            let policy = self.fetch_options.coordinate_guard.policy();
+            self.suspicious_coordinates.write_to_console(&layer.name, policy)?;
+            let kind = match policy {
+                SuspiciousPolicy::Drop => WarningKind::DroppedFeatures,
+                SuspiciousPolicy::Keep => WarningKind::CoercedValues,
+            };
+            report::warn_about(kind, &layer.name, format_args!(
+                "{} features of layer \"{}\" had coordinates at null island or outside its extent and were {}",
+                self.suspicious_coordinates.count(),
+                layer.name,
+                policy.outcome(),
+            ));
+        }
+        if !self.short_pages.is_empty() {
+            // Named by host, since servers cutting pages short tend to do it for every layer
+            let host = Url::parse(&layer.url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_owned))
+                .unwrap_or_default();
+            report::warn_about(WarningKind::General, &layer.name, format_args!(
+                "{} pages of layer \"{}\" from {} held fewer features than requested without being flagged as truncated ({}), they were fetched again in halves",
+                self.short_pages.count(),
+                layer.name,
+                host,
+                self.short_pages.patterns(),
+            ));
+            if let Some(cap) = self.short_pages.cap() {
+                profile::observe_page_cap(&layer.url, cap);
+            }
+        }
+        self.fetch_options.spool.remove()?;
+        self.fetch_options.progress.on_finish(LayerRef { url: &layer.url, name: &layer.name }, &LayerSummary {
+            strategy: strategy.map(ScrapeStrategy::to_string),
+            features: self.features,
+            bytes: self.bytes,
+        })?;
+        Ok(self.features)
+    }
+}
+
+/// Append the records of the chunks to the layer's output. Returns the number of features
+/// written.
+async fn write_chunks(
+    output: RecordOutput<'_>,
+    layer: &RestServiceMetadata,
+    strategy: Option<&ScrapeStrategy>,
+    fetch_options: &FetchOptions,
+    chunks: Vec<FetchedChunk>,
+) -> io::Result<usize> {
+    let mut writer = ChunkWriter::new(output, layer, fetch_options);
+    for chunk in chunks {
+        writer.write(chunk).await?;
+    }
+    writer.finish(strategy)
+}
+
+pub(crate) async fn scrape_layer(
+    settings: &ScrapeSettings,
+    layer: &RestServiceMetadata,
+    fetch_options: Arc<FetchOptions>,
+    output_path: &Path,
+    mut output: RecordOutput<'_>,
+) -> Result<usize, Box<dyn Error + Sync + Send>> {
+    if let Some(size) = &settings.sample {
+        let queries = sampling::sample_queries(layer, size, &settings.sample_method).await?;
+        let sample_count = size.features(layer.feature_count()?);
+        let mut writer = ChunkWriter::new(output, layer, &fetch_options);
+        fetch_chunks(
+            settings,
+            layer,
+            sample_count,
+            queries,
+            Arc::new(FetchOptions {
+                spool: fetch_options.spool.join("sample"),
+                ..fetch_options.as_ref().clone()
+            }),
+            Some(output_path),
+            Some(&mut writer),
+        ).await?;
+        return Ok(writer.finish(None)?)
+    }
+
+    let expected_count = layer.feature_count()?;
+    let chain = settings.strategy.chain(layer);
+    if chain.is_empty() {
+        return Err(StrategyError::NoStrategy(layer.name.to_owned()).into())
+    }
+    let mut disk_space_checked = false;
+    let mut best_attempt: Option<(ScrapeStrategy, Vec<FetchedChunk>, usize)> = None;
+    for (attempt, strategy) in chain.iter().enumerate() {
+        let is_last = attempt + 1 == chain.len();
+        let attempt_options = Arc::new(FetchOptions {
+            seen_object_ids: layer.oid_field_name()
+                .filter(|_| *strategy == ScrapeStrategy::Quadtree)
+                .map(|oid_field| Arc::new(SeenObjectIds::new(oid_field))),
+            spool: fetch_options.spool.join(&strategy.to_string()),
+            ..fetch_options.as_ref().clone()
+        });
+        // The output of the last strategy is kept whatever its count when no earlier attempt
+        // returned features, so its chunks are written as they arrive
+        if is_last && best_attempt.is_none() {
+            let queries = strategy::plan_queries(layer, strategy).await?;
+            let mut writer = ChunkWriter::new(output, layer, &fetch_options);
+            let result = fetch_chunks(
+                settings,
+                layer,
+                expected_count,
+                queries,
+                attempt_options,
+                Some(output_path).filter(|_| !disk_space_checked),
+                Some(&mut writer),
+            ).await;
+            match result {
+                Err(error) if !stops_run(error.as_ref()) => return Err(error),
+                _ => {}
+            }
+            let (written_count, dropped_count) = writer.counts();
+            if i64::value_from(written_count + dropped_count)? == expected_count {
+                tracing::info!(layer = %layer.name, "Scraped {} features with the {} strategy", written_count, strategy);
+            } else {
+                report::warn_about(WarningKind::CountMismatch, &layer.name, format_args!(
+                    "The {} strategy returned {} of {} features",
+                    strategy,
+                    written_count,
+                    expected_count,
+                ));
+            }
+            return Ok(writer.finish(Some(strategy))?)
+        }
+        let result = match strategy::plan_queries(layer, strategy).await {
+            Ok(queries) => {
+                let check_disk_space = !disk_space_checked;
+                disk_space_checked = true;
+                fetch_chunks(
+                    settings,
+                    layer,
+                    expected_count,
+                    queries,
+                    attempt_options,
+                    Some(output_path).filter(|_| check_disk_space),
+                    None,
+                ).await
+            }
+            Err(error) => Err(error),
+        };
+        let chunks = match result {
+            Ok(chunks) => chunks,
+            Err(error) if error.is::<DiskSpaceError>() => return Err(error),
+            Err(error) if stops_run(error.as_ref()) => break,
+            Err(error) if !is_last => {
+                report::warn(format_args!(
+                    "The {} strategy failed, trying the next one. {}",
+                    strategy,
+                    error,
+                ));
+                continue
+            }
+            Err(error) if best_attempt.is_none() => return Err(error),
+            Err(error) => {
+                report::warn(format_args!("The {} strategy failed. {}", strategy, error));
+                break
+            }
+        };
+        let written_count: usize = chunks.iter().map(|chunk| chunk.feature_count).sum();
+        // Features dropped for their geometry or coordinates were returned, they do not call for
+        // another strategy
+        let dropped_count: usize = chunks.iter()
+            .map(|chunk| chunk.geometry_mismatches.dropped() + chunk.suspicious_coordinates.dropped())
+            .sum();
+        if i64::value_from(written_count + dropped_count)? == expected_count {
+            tracing::info!(layer = %layer.name, "Scraped {} features with the {} strategy", written_count, strategy);
+            return Ok(write_chunks(output.reborrow(), layer, Some(strategy), &fetch_options, chunks).await?)
+        }
+        report::warn_about(WarningKind::CountMismatch, &layer.name, format_args!(
+            "The {} strategy returned {} of {} features",
+            strategy,
+            written_count,
+            expected_count,
+        ));
+        let is_best = best_attempt.as_ref()
+            .map(|(_, _, best_count)| written_count > *best_count)
+            .unwrap_or(true);
+        if is_best {
+            best_attempt = Some((strategy.to_owned(), chunks, written_count));
+        }
+        if is_stopped(&fetch_options) {
+            break
+        }
+    }
+    if let Some((strategy, chunks, written_count)) = best_attempt {
+        report::warn_about(WarningKind::CountMismatch, &layer.name, format_args!(
+            "Keeping the output of the {} strategy with {} of {} features",
+            strategy,
+            written_count,
+            expected_count,
+        ));
+        return Ok(write_chunks(output, layer, Some(&strategy), &fetch_options, chunks).await?)
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod pipeline_tests {
+    use std::future::Future;
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+    use reqwest::Url;
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::Notify;
+    use crate::scraper::{Scraper, WriterOptions};
+    use super::ChunkTask;
+
+    type Features = Pin<Box<dyn Future<Output = Option<Vec<i64>>> + Send>>;
+
+    /// Answers a feature query of `strategy` asking for `object_ids` with the object ids returned,
+    /// None to fail the query.
+    type FeaturesHandler = Arc<dyn Fn(&'static str, Vec<i64>) -> Features + Send + Sync>;
+
+    const FEATURE_COUNT: i64 = 6;
+
+    /// Serve a table of 6 features, 2 per query, on a local port. Planning requests are answered
+    /// from the table, feature queries by `handler`. Returns the layer's url and the strategy of
+    /// every feature query received.
+    async fn serve_layer(handler: FeaturesHandler) -> (String, Arc<Mutex<Vec<&'static str>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/arcgis/rest/services/Permits/FeatureServer/0",
+            listener.local_addr().unwrap(),
+        );
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let server_received = received.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let handler = handler.clone();
+                let received = server_received.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buffer = [0; 4096];
+                    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match stream.read(&mut buffer).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(read) => request.extend_from_slice(&buffer[..read]),
+                        }
+                    }
+                    let request = String::from_utf8_lossy(&request).into_owned();
+                    let target = request.split_whitespace().nth(1).unwrap_or("/");
+                    let url = Url::parse(&format!("http://stub{}", target)).unwrap();
+                    let body = respond(&url, &handler, &received).await;
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body,
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        (url, received)
+    }
+
+    async fn respond(url: &Url, handler: &FeaturesHandler, received: &Mutex<Vec<&'static str>>) -> String {
+        let query = |key: &str| url.query_pairs()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.into_owned());
+        if !url.path().ends_with("/query") {
+            return json!({
+                "name": "Permits",
+                "type": "table",
+                "maxRecordCount": 2,
+                "advancedQueryCapabilities": {"supportsPagination": true, "supportsStatistics": true},
+                "sourceSpatialReference": {"wkid": 4326},
+                "objectIdField": "OBJECTID",
+                "fields": [
+                    {"name": "OBJECTID", "type": "esriFieldTypeOID", "alias": "OBJECTID"},
+                    {"name": "PERMIT_NO", "type": "esriFieldTypeString", "alias": "Permit", "length": 20},
+                ],
+            }).to_string()
+        }
+        let numbers = |text: &str| -> Vec<i64> {
+            text.split(|c: char| !c.is_ascii_digit())
+                .filter_map(|number| number.parse().ok())
+                .collect()
+        };
+        let (strategy, object_ids) = if query("returnCountOnly").as_deref() == Some("true") {
+            return json!({"count": FEATURE_COUNT}).to_string()
+        } else if query("returnIdsOnly").as_deref() == Some("true") {
+            return json!({"objectIdFieldName": "OBJECTID", "objectIds": (1..=FEATURE_COUNT).collect::<Vec<_>>()}).to_string()
+        } else if query("outStatistics").is_some() {
+            return json!({"features": [{"attributes": {"MAX_VALUE": FEATURE_COUNT, "MIN_VALUE": 1}}]}).to_string()
+        } else if let Some(offset) = query("resultOffset") {
+            let offset: i64 = offset.parse().unwrap();
+            let count: i64 = query("resultRecordCount").unwrap().parse().unwrap();
+            ("pagination", (offset + 1..=(offset + count).min(FEATURE_COUNT)).collect())
+        } else if let Some(object_ids) = query("objectIds") {
+            ("object-ids", numbers(&object_ids))
+        } else {
+            let bounds = numbers(&query("where").unwrap());
+            ("oid-ranges", (bounds[0]..=bounds[1].min(FEATURE_COUNT)).collect())
+        };
+        received.lock().unwrap().push(strategy);
+        match handler(strategy, object_ids).await {
+            Some(object_ids) => {
+                let features: Vec<Value> = object_ids.into_iter()
+                    .map(|object_id| json!({"attributes": {"OBJECTID": object_id, "PERMIT_NO": format!("P-{}", object_id)}}))
+                    .collect();
+                json!({"features": features, "exceededTransferLimit": false}).to_string()
+            }
+            // A feature that is not an object fails the query at once, error responses are only
+            // given up on after a pause
+            None => json!({"features": ["invalid"], "exceededTransferLimit": false}).to_string(),
+        }
+    }
+
+    fn handler<F>(features: F) -> FeaturesHandler
+    where
+        F: Fn(&'static str, Vec<i64>) -> Option<Vec<i64>> + Send + Sync + 'static,
+    {
+        Arc::new(move |strategy, object_ids| {
+            let returned = features(strategy, object_ids);
+            Box::pin(async move { returned })
+        })
+    }
+
+    async fn scrape_to_csv(url: &str) -> (usize, Vec<String>) {
+        let scraper = Scraper::new().query_retries(1);
+        let layer = scraper.request_metadata(url).await.unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("permits.csv");
+        let written = scraper.scrape_to_csv(&layer, &path).await.unwrap();
+        let rows = std::fs::read_to_string(&path).unwrap()
+            .lines()
+            .skip(1)
+            .map(str::to_owned)
+            .collect();
+        (written, rows)
+    }
+
+    #[tokio::test]
+    async fn scrape_layer_should_use_next_strategy_when_primary_fails() {
+        let (url, received) = serve_layer(handler(|strategy, object_ids| {
+            Some(object_ids).filter(|_| strategy != "pagination")
+        })).await;
+        let (written, rows) = scrape_to_csv(&url).await;
+        assert_eq!(written, 6);
+        assert_eq!(rows.len(), 6);
+        let received = received.lock().unwrap();
+        assert!(received.contains(&"oid-ranges"));
+        assert!(!received.contains(&"object-ids"));
+    }
+
+    #[tokio::test]
+    async fn scrape_layer_should_keep_best_attempt_when_every_strategy_miscounts() {
+        // Pagination loses the last feature, the later strategies lose more
+        let (url, received) = serve_layer(handler(|strategy, object_ids| {
+            let last_returned = match strategy {
+                "pagination" => 5,
+                "oid-ranges" => 4,
+                _ => 3,
+            };
+            Some(object_ids.into_iter().filter(|object_id| *object_id <= last_returned).collect())
+        })).await;
+        let (written, rows) = scrape_to_csv(&url).await;
+        assert_eq!(written, 5);
+        assert_eq!(rows.len(), 5);
+        assert!(rows.iter().all(|row| !row.contains("P-6")));
+        assert!(received.lock().unwrap().contains(&"object-ids"));
+    }
+
+    /// Writer signalling once the first record follows the header row.
+    struct RecordsWriter {
+        written: Vec<u8>,
+        first_record: Arc<Notify>,
+    }
+
+    impl AsyncWrite for RecordsWriter {
+        fn poll_write(mut self: Pin<&mut Self>, _: &mut Context<'_>, buffer: &[u8]) -> Poll<io::Result<usize>> {
+            self.written.extend_from_slice(buffer);
+            if self.written.iter().filter(|byte| **byte == b'\n').count() > 1 {
+                self.first_record.notify_one();
+            }
+            Poll::Ready(Ok(buffer.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn scrape_layer_should_stream_last_strategy_when_earlier_strategies_fail() {
+        // The last query of the object ids strategy is only answered once records were written,
+        // which never happens when its chunks are collected first
+        let first_record = Arc::new(Notify::new());
+        let notified = first_record.clone();
+        let (url, _) = serve_layer(Arc::new(move |strategy, object_ids| {
+            let notified = notified.clone();
+            Box::pin(async move {
+                if strategy != "object-ids" {
+                    return None
+                }
+                if object_ids.contains(&FEATURE_COUNT) {
+                    notified.notified().await;
+                    return Some(object_ids.into_iter().filter(|object_id| *object_id < FEATURE_COUNT).collect())
+                }
+                Some(object_ids)
+            })
+        })).await;
+        let scraper = Scraper::new().query_retries(1);
+        let layer = scraper.request_metadata(&url).await.unwrap();
+        let mut writer = RecordsWriter { written: Vec::new(), first_record };
+        let written = tokio::time::timeout(
+            Duration::from_secs(10),
+            scraper.scrape_to_writer(&layer, &WriterOptions::new(), &mut writer),
+        ).await.expect("the last strategy was not streamed").unwrap();
+        assert_eq!(written, 5);
+        assert_eq!(String::from_utf8(writer.written).unwrap().lines().count(), 6);
+    }
+
+    #[tokio::test]
+    async fn chunk_task_should_abort_worker_when_dropped_before_collected() {
+        let held = Arc::new(());
+        let worker_held = held.clone();
+        let task = ChunkTask(tokio::spawn(async move {
+            let _held = worker_held;
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+            Err("never finishes".into())
+        }));
+        tokio::task::yield_now().await;
+        assert_eq!(Arc::strong_count(&held), 2);
+        drop(task);
+        for _ in 0..100 {
+            if Arc::strong_count(&held) == 1 {
+                break
+            }
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(Arc::strong_count(&held), 1);
+    }
+}
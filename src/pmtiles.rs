@@ -0,0 +1,363 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use rayon::prelude::*;
+use serde_json::{json, Map, Value};
+use crate::checksum::{Artifact, ChecksumFile};
+use crate::geometry::Extent;
+use crate::metadata::LayerDescription;
+use crate::vector_tiles::{encode_tile, tile_range, to_lon_lat, write_varint, TileFeature, TileLayer};
+
+/// Deepest zoom level tiles are generated for.
+pub(crate) const MAX_ZOOM: u8 = 16;
+const HEADER_LENGTH: usize = 127;
+/// Readers fetch the first 16 KiB of an archive, which must hold the header and root directory.
+const ROOT_LENGTH: usize = 16_384 - HEADER_LENGTH;
+const COMPRESSION_NONE: u8 = 1;
+const TILE_TYPE_MVT: u8 = 1;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum PmtilesError {
+    InvalidZoomRange(String),
+}
+
+impl Display for PmtilesError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PmtilesError::InvalidZoomRange(value) => {
+                write!(f, "Expected zoom levels as min-max between 0 and {} but got \"{}\"", MAX_ZOOM, value)
+            }
+        }
+    }
+}
+
+impl Error for PmtilesError {}
+
+/// Inclusive range of zoom levels tiles are generated for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ZoomRange {
+    pub(crate) min: u8,
+    pub(crate) max: u8,
+}
+
+/// Parse `min-max` (or a single zoom level) into a zoom range.
+pub(crate) fn parse_zoom_range(value: &str) -> Result<ZoomRange, PmtilesError> {
+    let error = || PmtilesError::InvalidZoomRange(value.to_owned());
+    let (min, max) = value.split_once('-').unwrap_or((value, value));
+    let min: u8 = min.trim().parse().map_err(|_| error())?;
+    let max: u8 = max.trim().parse().map_err(|_| error())?;
+    if min > max || max > MAX_ZOOM {
+        return Err(error())
+    }
+    Ok(ZoomRange { min, max })
+}
+
+/// PMTiles id of a tile: tiles of lower zooms first, then the Hilbert curve index in the zoom.
+pub(crate) fn tile_id(zoom: u8, x: u32, y: u32) -> u64 {
+    let zoom_start = ((1_u64 << (2 * u32::from(zoom))) - 1) / 3;
+    let (mut x, mut y) = (u64::from(x), u64::from(y));
+    let mut index = 0;
+    let mut size = (1_u64 << zoom) / 2;
+    while size > 0 {
+        let rx = u64::from(x & size > 0);
+        let ry = u64::from(y & size > 0);
+        index += size * size * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = size - 1 - (x & (size - 1));
+                y = size - 1 - (y & (size - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        size /= 2;
+    }
+    zoom_start + index
+}
+
+/// Directory entry pointing at tile data, or at a leaf directory when `run_length` is 0.
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    tile_id: u64,
+    offset: u64,
+    length: u64,
+    run_length: u64,
+}
+
+fn encode_directory(entries: &[Entry]) -> Vec<u8> {
+    let mut buffer = vec![];
+    write_varint(&mut buffer, entries.len() as u64);
+    let mut last_id = 0;
+    for entry in entries {
+        write_varint(&mut buffer, entry.tile_id - last_id);
+        last_id = entry.tile_id;
+    }
+    for entry in entries {
+        write_varint(&mut buffer, entry.run_length);
+    }
+    for entry in entries {
+        write_varint(&mut buffer, entry.length);
+    }
+    for (index, entry) in entries.iter().enumerate() {
+        let contiguous = index > 0
+            && entry.offset == entries[index - 1].offset + entries[index - 1].length;
+        write_varint(&mut buffer, if contiguous { 0 } else { entry.offset + 1 });
+    }
+    buffer
+}
+
+/// Root directory and leaf directories for `entries`. Leaves are only used when the entries
+/// do not fit in the root, growing until the root pointing at them does.
+fn build_directories(entries: &[Entry]) -> (Vec<u8>, Vec<u8>) {
+    let root = encode_directory(entries);
+    if root.len() <= ROOT_LENGTH {
+        return (root, vec![])
+    }
+    let mut leaf_size = 4096;
+    loop {
+        let mut leaves = vec![];
+        let mut root_entries = vec![];
+        for chunk in entries.chunks(leaf_size) {
+            let leaf = encode_directory(chunk);
+            root_entries.push(Entry {
+                tile_id: chunk[0].tile_id,
+                offset: leaves.len() as u64,
+                length: leaf.len() as u64,
+                run_length: 0,
+            });
+            leaves.extend_from_slice(&leaf);
+        }
+        let root = encode_directory(&root_entries);
+        if root.len() <= ROOT_LENGTH {
+            return (root, leaves)
+        }
+        leaf_size *= 2;
+    }
+}
+
+/// Sink receiving the features of a layer and the spatial reference its geometries are in.
+#[derive(Debug, Clone)]
+pub(crate) struct TileOutput {
+    pub(crate) sink: Arc<PmtilesSink>,
+    pub(crate) spatial_reference: i64,
+}
+
+/// Features of one scraped layer.
+#[derive(Debug)]
+struct SinkLayer {
+    name: String,
+    features: Vec<TileFeature>,
+}
+
+/// Collects the features of every scraped layer and writes them as a single PMTiles archive
+/// of vector tiles, ready to be hosted as a static file.
+#[derive(Debug)]
+pub(crate) struct PmtilesSink {
+    zooms: ZoomRange,
+    layers: Mutex<Vec<SinkLayer>>,
+    /// Descriptions of the layers by name, for the archive's metadata
+    descriptions: Mutex<BTreeMap<String, LayerDescription>>,
+}
+
+impl PmtilesSink {
+    pub(crate) fn new(zooms: ZoomRange) -> Self {
+        Self { zooms, layers: Mutex::new(vec![]), descriptions: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub(crate) fn describe_layer(&self, layer: LayerDescription) {
+        self.descriptions.lock().unwrap().insert(layer.name.to_owned(), layer);
+    }
+
+    pub(crate) fn add_features(&self, layer_name: &str, mut features: Vec<TileFeature>) {
+        let mut layers = self.layers.lock().unwrap();
+        match layers.iter_mut().find(|layer| layer.name == layer_name) {
+            Some(layer) => layer.features.append(&mut features),
+            None => layers.push(SinkLayer { name: layer_name.to_owned(), features }),
+        }
+    }
+
+    /// `vector_layers` metadata describing the fields of every layer, with the copyright texts
+    /// of the layers as the archive's `attribution`.
+    fn metadata(&self, layers: &[SinkLayer]) -> Value {
+        let descriptions = self.descriptions.lock().unwrap();
+        let vector_layers: Vec<Value> = layers.iter()
+            .map(|layer| {
+                let mut fields = Map::new();
+                for (key, value) in layer.features.iter().flat_map(|feature| &feature.properties) {
+                    fields.entry(key.to_owned()).or_insert_with(|| json!(value.type_name()));
+                }
+                let mut vector_layer = json!({
+                    "id": layer.name,
+                    "fields": fields,
+                    "minzoom": self.zooms.min,
+                    "maxzoom": self.zooms.max,
+                });
+                if let Some(description) = descriptions.get(&layer.name).and_then(|layer| layer.description.as_ref()) {
+                    vector_layer["description"] = json!(description);
+                }
+                vector_layer
+            })
+            .collect();
+        let mut attributions: Vec<&str> = vec![];
+        for layer in layers {
+            let copyright_text = descriptions.get(&layer.name).and_then(|layer| layer.copyright_text.as_deref());
+            if let Some(copyright_text) = copyright_text.filter(|text| !attributions.contains(text)) {
+                attributions.push(copyright_text);
+            }
+        }
+        let mut metadata = json!({"vector_layers": vector_layers, "generator": "arcgis_scraper"});
+        if !attributions.is_empty() {
+            metadata["attribution"] = json!(attributions.join("; "));
+        }
+        metadata
+    }
+
+    /// Cut every feature into the tiles of each zoom level, appending the encoded tiles to
+    /// `tile_data`. Returns the directory entries sorted by tile id.
+    ///
+    /// Clipping and encoding the tiles of a zoom level is spread over the rayon pool, with the
+    /// encoded tiles written in tile id order once the level is done.
+    fn write_tiles(&self, layers: &[SinkLayer], tile_data: &mut File) -> io::Result<Vec<Entry>> {
+        let mut entries = vec![];
+        let mut offset = 0;
+        let mut writer = BufWriter::new(tile_data);
+        for zoom in self.zooms.min..=self.zooms.max {
+            let mut tiles: BTreeMap<u64, Vec<Vec<u8>>> = BTreeMap::new();
+            for layer in layers {
+                let mut layer_tiles: HashMap<(u32, u32), Vec<&TileFeature>> = HashMap::new();
+                for feature in &layer.features {
+                    let ((x_min, x_max), (y_min, y_max)) = tile_range(&feature.bounds, zoom);
+                    for x in x_min..=x_max {
+                        for y in y_min..=y_max {
+                            layer_tiles.entry((x, y)).or_default().push(feature);
+                        }
+                    }
+                }
+                let encoded: Vec<(u64, Vec<u8>)> = layer_tiles.into_par_iter()
+                    .filter_map(|((x, y), features)| {
+                        let mut tile = TileLayer::default();
+                        for feature in features {
+                            tile.add(feature, zoom, x, y);
+                        }
+                        tile.has_features().then(|| (tile_id(zoom, x, y), tile.encode(&layer.name)))
+                    })
+                    .collect();
+                for (tile_id, tile) in encoded {
+                    tiles.entry(tile_id).or_default().push(tile);
+                }
+            }
+            let encoded: Vec<(u64, Vec<u8>)> = tiles.into_par_iter()
+                .map(|(tile_id, layers)| (tile_id, encode_tile(&layers)))
+                .collect();
+            for (tile_id, tile) in encoded {
+                writer.write_all(&tile)?;
+                entries.push(Entry { tile_id, offset, length: tile.len() as u64, run_length: 1 });
+                offset += tile.len() as u64;
+            }
+        }
+        writer.flush()?;
+        Ok(entries)
+    }
+
+    /// Write the archive to `path`, returning the number of tiles written and the archive.
+    pub(crate) fn write(&self, path: &Path) -> Result<(usize, Artifact), Box<dyn Error + Send + Sync>> {
+        let layers = self.layers.lock().unwrap();
+        let mut tile_data = tempfile::tempfile()?;
+        let entries = self.write_tiles(&layers, &mut tile_data)?;
+        let (root, leaves) = build_directories(&entries);
+        let metadata = serde_json::to_vec(&self.metadata(&layers))?;
+        let tile_data_length = entries.iter().map(|entry| entry.length).sum::<u64>();
+        let bounds = layers.iter()
+            .flat_map(|layer| layer.features.iter().map(|feature| feature.bounds))
+            .reduce(|bounds, other| bounds.union(&other))
+            .unwrap_or(Extent { x_min: 0_f64, y_min: 0_f64, x_max: 0_f64, y_max: 0_f64 });
+        let (min_lon, min_lat) = to_lon_lat(bounds.x_min, bounds.y_min);
+        let (max_lon, max_lat) = to_lon_lat(bounds.x_max, bounds.y_max);
+
+        let root_offset = HEADER_LENGTH as u64;
+        let metadata_offset = root_offset + root.len() as u64;
+        let leaves_offset = metadata_offset + metadata.len() as u64;
+        let tile_data_offset = leaves_offset + leaves.len() as u64;
+        let mut header = Vec::with_capacity(HEADER_LENGTH);
+        header.extend_from_slice(b"PMTiles");
+        header.push(3);
+        for value in [
+            root_offset,
+            root.len() as u64,
+            metadata_offset,
+            metadata.len() as u64,
+            leaves_offset,
+            leaves.len() as u64,
+            tile_data_offset,
+            tile_data_length,
+            entries.len() as u64,
+            entries.len() as u64,
+            entries.len() as u64,
+        ] {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+        header.extend_from_slice(&[1, COMPRESSION_NONE, COMPRESSION_NONE, TILE_TYPE_MVT, self.zooms.min, self.zooms.max]);
+        let e7 = |degrees: f64| ((degrees * 10_000_000_f64).round() as i32).to_le_bytes();
+        for degrees in [min_lon, min_lat, max_lon, max_lat] {
+            header.extend_from_slice(&e7(degrees));
+        }
+        header.push(self.zooms.min);
+        header.extend_from_slice(&e7((min_lon + max_lon) / 2_f64));
+        header.extend_from_slice(&e7((min_lat + max_lat) / 2_f64));
+
+        let mut file = BufWriter::new(ChecksumFile::create(path)?);
+        file.write_all(&header)?;
+        file.write_all(&root)?;
+        file.write_all(&metadata)?;
+        file.write_all(&leaves)?;
+        tile_data.seek(SeekFrom::Start(0))?;
+        io::copy(&mut tile_data, &mut file)?;
+        let artifact = file.into_inner().map_err(|error| error.into_error())?.finish()?;
+        Ok((entries.len(), artifact))
+    }
+}
+
+#[cfg(test)]
+mod pmtiles_tests {
+    use super::{build_directories, encode_directory, parse_zoom_range, tile_id, Entry, PmtilesError, ZoomRange};
+
+    #[test]
+    fn tile_id_should_follow_hilbert_order_when_passed_first_zooms() {
+        assert_eq!(tile_id(0, 0, 0), 0);
+        assert_eq!(
+            [tile_id(1, 0, 0), tile_id(1, 0, 1), tile_id(1, 1, 1), tile_id(1, 1, 0)],
+            [1, 2, 3, 4],
+        );
+        assert_eq!(tile_id(2, 0, 0), 5);
+    }
+
+    #[test]
+    fn parse_zoom_range_should_fail_when_max_exceeds_limit() {
+        assert_eq!(parse_zoom_range("4-12"), Ok(ZoomRange { min: 4, max: 12 }));
+        assert_eq!(parse_zoom_range("10"), Ok(ZoomRange { min: 10, max: 10 }));
+        assert_eq!(parse_zoom_range("0-20"), Err(PmtilesError::InvalidZoomRange("0-20".to_owned())));
+    }
+
+    #[test]
+    fn encode_directory_should_write_zero_offset_when_tiles_contiguous() {
+        let entries = vec![
+            Entry { tile_id: 1, offset: 0, length: 10, run_length: 1 },
+            Entry { tile_id: 3, offset: 10, length: 5, run_length: 1 },
+        ];
+        assert_eq!(encode_directory(&entries), vec![2, 1, 2, 1, 1, 10, 5, 1, 0]);
+    }
+
+    #[test]
+    fn build_directories_should_use_leaves_when_root_too_large() {
+        let entries: Vec<Entry> = (0..20_000)
+            .map(|index| Entry { tile_id: index * 3, offset: index * 1000, length: 1000, run_length: 1 })
+            .collect();
+        let (root, leaves) = build_directories(&entries);
+        assert!(root.len() <= super::ROOT_LENGTH);
+        assert!(!leaves.is_empty());
+    }
+}
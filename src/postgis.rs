@@ -0,0 +1,501 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::task::JoinHandle;
+#[cfg(not(feature = "postgres"))]
+use crate::capability::{Capability, CapabilityError};
+use crate::metadata::{RestServiceField, RestServiceFieldType, RestServiceMetadata};
+use crate::projection;
+
+/// Temporary table the CSV records of a layer are copied into before being cast into the typed
+/// table, dropped when the load commits.
+#[cfg(feature = "postgres")]
+const STAGING_TABLE: &str = "arcgis_scraper_staging";
+
+/// Column holding the geometry of each feature in loaded tables.
+const GEOMETRY_COLUMN: &str = "geom";
+
+/// Chunks of records waiting to be copied into a layer's table before the scrape of the layer
+/// waits on the database.
+const PENDING_CHUNKS: usize = 16;
+
+pub(crate) enum PostgisError {
+    /// Layer with geometry loaded while geometries are written as Esri JSON columns
+    NeedsWkt,
+    /// Option writing the records elsewhere than a single CSV file per layer
+    Unsupported(&'static str),
+    /// Query of the load of a layer the database rejected
+    #[cfg(feature = "postgres")]
+    Database(String, String),
+    /// The scrape of the layer stopped before all of its records were sent
+    #[cfg(feature = "postgres")]
+    Incomplete(String),
+}
+
+/// Errors returned from main are printed with Debug, show the message instead of the variant.
+impl Debug for PostgisError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Display for PostgisError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PostgisError::NeedsWkt => {
+                write!(f, "--postgres-url loads geometries as WKT, add --geometry-encoding wkt")
+            }
+            PostgisError::Unsupported(option) => {
+                write!(f, "--postgres-url loads the CSV file of each layer and cannot be combined with {}", option)
+            }
+            #[cfg(feature = "postgres")]
+            PostgisError::Database(table, message) => {
+                write!(f, "Could not load {} into PostGIS: {}", table, message)
+            }
+            #[cfg(feature = "postgres")]
+            PostgisError::Incomplete(table) => {
+                write!(f, "The scrape stopped before every record was loaded into {}", table)
+            }
+        }
+    }
+}
+
+impl Error for PostgisError {}
+
+/// Lowercase name with every character other than letters, digits and underscores replaced by
+/// an underscore, like the table and column names ogr2ogr launders.
+fn launder(name: &str) -> String {
+    let name: String = name.trim()
+        .to_lowercase()
+        .chars()
+        .map(|chr| if chr.is_ascii_alphanumeric() || chr == '_' { chr } else { '_' })
+        .collect();
+    if name.is_empty() || name.starts_with(|chr: char| chr.is_ascii_digit()) {
+        format!("_{}", name)
+    } else {
+        name
+    }
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Postgres type of the values of a field.
+fn postgres_type(field_type: &RestServiceFieldType) -> &'static str {
+    match field_type {
+        RestServiceFieldType::OID => "bigint",
+        RestServiceFieldType::Integer => "integer",
+        RestServiceFieldType::SmallInteger => "smallint",
+        RestServiceFieldType::Double => "double precision",
+        RestServiceFieldType::Single | RestServiceFieldType::Float => "real",
+        RestServiceFieldType::Date => "timestamptz",
+        RestServiceFieldType::GlobalID | RestServiceFieldType::GUID => "uuid",
+        RestServiceFieldType::Geometry => "geometry",
+        RestServiceFieldType::String
+        | RestServiceFieldType::Blob
+        | RestServiceFieldType::Raster
+        | RestServiceFieldType::XML => "text",
+    }
+}
+
+/// Column of a loaded table with the expression casting its staged text value.
+#[derive(Debug, Clone, PartialEq)]
+struct TableColumn {
+    name: String,
+    definition: String,
+    value: String,
+}
+
+/// Typed table a layer is loaded into.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TableDefinition {
+    /// Quoted schema and table name
+    name: String,
+    columns: Vec<TableColumn>,
+}
+
+impl TableDefinition {
+    /// Table of the CSV `columns` of a layer, typed from the layer's fields. Columns added by
+    /// the scrape (code descriptions, provenance, cell indexes) are text. Geometries are read
+    /// from the WKT column in the output spatial reference of the layer.
+    pub(crate) fn new(schema: &str, layer: &RestServiceMetadata, columns: &[String]) -> Self {
+        let srid = layer.output_spatial_reference()
+            .map(|wkid| if projection::same_spatial_reference(wkid, 3857) { 3857 } else { wkid });
+        let mut names = HashSet::new();
+        let columns = columns.iter()
+            .enumerate()
+            .map(|(index, column)| {
+                let field_type = layer.fields.iter()
+                    .find(|field| field.name == *column)
+                    .map(|field: &RestServiceField| field.field_type.clone())
+                    .unwrap_or(RestServiceFieldType::String);
+                let staged = format!("c{}", index);
+                let mut name = if field_type == RestServiceFieldType::Geometry {
+                    GEOMETRY_COLUMN.to_owned()
+                } else {
+                    launder(column)
+                };
+                while !names.insert(name.to_owned()) {
+                    name.push('_');
+                }
+                let sql_type = postgres_type(&field_type);
+                let (definition, value) = match (&field_type, srid) {
+                    (RestServiceFieldType::Geometry, Some(srid)) => (
+                        format!("geometry(Geometry, {})", srid),
+                        format!("ST_GeomFromText({}, {})", staged, srid),
+                    ),
+                    (RestServiceFieldType::Geometry, None) => {
+                        (sql_type.to_owned(), format!("ST_GeomFromText({})", staged))
+                    }
+                    (RestServiceFieldType::Date, _) => {
+                        (sql_type.to_owned(), format!("to_timestamp({}::double precision / 1000)", staged))
+                    }
+                    (_, _) if sql_type == "text" => (sql_type.to_owned(), staged),
+                    (_, _) => (sql_type.to_owned(), format!("{}::{}", staged, sql_type)),
+                };
+                TableColumn { name, definition, value }
+            })
+            .collect();
+        Self {
+            name: format!("{}.{}", quote_identifier(schema), quote_identifier(&launder(&layer.name))),
+            columns,
+        }
+    }
+
+    /// Creation of the temporary table the records are copied into, every column as text.
+    #[cfg(feature = "postgres")]
+    fn staging_statement(&self) -> String {
+        let columns: Vec<String> = (0..self.columns.len())
+            .map(|index| format!("c{} text", index))
+            .collect();
+        format!("CREATE TEMP TABLE {} ({}) ON COMMIT DROP", STAGING_TABLE, columns.join(", "))
+    }
+
+    /// Replacement of the table by the staged records.
+    #[cfg(feature = "postgres")]
+    fn replace_statement(&self) -> String {
+        let definitions: Vec<String> = self.columns.iter()
+            .map(|column| format!("{} {}", quote_identifier(&column.name), column.definition))
+            .collect();
+        let names: Vec<String> = self.columns.iter().map(|column| quote_identifier(&column.name)).collect();
+        let values: Vec<&str> = self.columns.iter().map(|column| column.value.as_str()).collect();
+        format!(
+            "DROP TABLE IF EXISTS {table};\nCREATE TABLE {table} ({definitions});\nINSERT INTO {table} ({names}) SELECT {values} FROM {staging}",
+            table = self.name,
+            definitions = definitions.join(", "),
+            names = names.join(", "),
+            values = values.join(", "),
+            staging = STAGING_TABLE,
+        )
+    }
+}
+
+enum LoadMessage {
+    /// CSV records of a chunk, without a header
+    Records(Vec<u8>),
+    /// Every record was sent, the table can replace the previous one
+    Commit,
+}
+
+/// Sends the records of a layer's chunks to its load, waiting while [PENDING_CHUNKS] chunks
+/// are not yet copied. Records sent after the load failed are dropped, the failure being
+/// reported once the scrape of the layer is done.
+#[derive(Debug, Clone)]
+pub(crate) struct PostgisRecords(Sender<LoadMessage>);
+
+impl Debug for LoadMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadMessage::Records(records) => write!(f, "Records({} bytes)", records.len()),
+            LoadMessage::Commit => write!(f, "Commit"),
+        }
+    }
+}
+
+impl PostgisRecords {
+    pub(crate) async fn send(&self, records: Vec<u8>) {
+        let _ = self.0.send(LoadMessage::Records(records)).await;
+    }
+}
+
+/// Database the scraped layers are loaded into, each layer replacing the table of the same
+/// name in a single transaction so readers never see a partial table.
+#[derive(Debug, Clone)]
+pub(crate) struct PostgisSink {
+    url: String,
+    schema: String,
+}
+
+/// Load of a single layer, streaming its records into the database while it is scraped.
+pub(crate) struct LayerLoad {
+    records: PostgisRecords,
+    table: String,
+    task: JoinHandle<Result<u64, Box<dyn Error + Send + Sync>>>,
+}
+
+impl PostgisSink {
+    /// Check the database can be reached and create the schema, before anything is scraped.
+    #[cfg(feature = "postgres")]
+    pub(crate) async fn connect(url: &str, schema: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let client = connect(url).await?;
+        client.batch_execute(&format!("CREATE SCHEMA IF NOT EXISTS {}", quote_identifier(schema))).await?;
+        Ok(Self { url: url.to_owned(), schema: schema.to_owned() })
+    }
+
+    #[cfg(not(feature = "postgres"))]
+    pub(crate) async fn connect(_url: &str, _schema: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Err(CapabilityError::Disabled(Capability::Postgres).into())
+    }
+
+    /// Start loading a layer written with the CSV `columns`.
+    pub(crate) fn start_layer(&self, layer: &RestServiceMetadata, columns: &[String]) -> LayerLoad {
+        let table = TableDefinition::new(&self.schema, layer, columns);
+        let (sender, receiver) = channel(PENDING_CHUNKS);
+        LayerLoad {
+            records: PostgisRecords(sender),
+            table: format!("{}.{}", self.schema, launder(&layer.name)),
+            task: tokio::spawn(load(self.url.to_owned(), table, receiver)),
+        }
+    }
+}
+
+impl LayerLoad {
+    pub(crate) fn records(&self) -> PostgisRecords {
+        self.records.clone()
+    }
+
+    /// Commit the load once every record was sent, returning the table and number of rows.
+    pub(crate) async fn finish(self) -> Result<(String, u64), Box<dyn Error + Send + Sync>> {
+        let _ = self.records.0.send(LoadMessage::Commit).await;
+        let rows = self.task.await??;
+        Ok((self.table, rows))
+    }
+}
+
+/// `url` without its password, None when it has none. Both `postgres://` urls and key=value
+/// connection strings are handled.
+pub(crate) fn without_password(url: &str) -> Option<String> {
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        let mut parsed = reqwest::Url::parse(url).ok()?;
+        parsed.password()?;
+        parsed.set_password(None).ok()?;
+        return Some(parsed.to_string())
+    }
+    let is_password = |pair: &&str| pair.trim_start().to_lowercase().starts_with("password=");
+    if !url.split(' ').any(|pair| is_password(&pair)) {
+        return None
+    }
+    Some(url.split(' ').filter(|pair| !is_password(pair)).collect::<Vec<_>>().join(" "))
+}
+
+/// Connect to the database, through TLS unless the url's `sslmode` is `disable`. With the
+/// default `prefer` mode servers without TLS are still reached in plain text, `require` fails
+/// instead. Urls without a password use the PGPASSWORD environment variable's.
+#[cfg(feature = "postgres")]
+async fn connect(url: &str) -> Result<tokio_postgres::Client, Box<dyn Error + Send + Sync>> {
+    use tokio_postgres::config::SslMode;
+
+    let mut config: tokio_postgres::Config = url.parse()?;
+    if config.get_password().is_none() {
+        if let Ok(password) = std::env::var("PGPASSWORD") {
+            config.password(password);
+        }
+    }
+    if config.get_ssl_mode() == SslMode::Disable {
+        return Ok(spawn_connection(&config, tokio_postgres::NoTls).await?)
+    }
+    Ok(spawn_connection(&config, tls_connector()?).await?)
+}
+
+#[cfg(all(feature = "postgres", feature = "native-tls"))]
+fn tls_connector() -> Result<postgres_native_tls::MakeTlsConnector, Box<dyn Error + Send + Sync>> {
+    Ok(postgres_native_tls::MakeTlsConnector::new(native_tls::TlsConnector::new()?))
+}
+
+#[cfg(all(feature = "postgres", feature = "rustls", not(feature = "native-tls")))]
+fn tls_connector() -> Result<tokio_postgres_rustls::MakeRustlsConnect, Box<dyn Error + Send + Sync>> {
+    let roots = rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config = rustls::ClientConfig::builder_with_provider(std::sync::Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(tokio_postgres_rustls::MakeRustlsConnect::new(config))
+}
+
+/// Without a TLS backend only `sslmode=disable` and `prefer` connect, in plain text.
+#[cfg(all(feature = "postgres", not(feature = "native-tls"), not(feature = "rustls")))]
+fn tls_connector() -> Result<tokio_postgres::NoTls, Box<dyn Error + Send + Sync>> {
+    Ok(tokio_postgres::NoTls)
+}
+
+#[cfg(feature = "postgres")]
+async fn spawn_connection<T>(config: &tokio_postgres::Config, tls: T) -> Result<tokio_postgres::Client, tokio_postgres::Error>
+where
+    T: tokio_postgres::tls::MakeTlsConnect<tokio_postgres::Socket>,
+    T::Stream: Send + 'static,
+{
+    let (client, connection) = config.connect(tls).await?;
+    tokio::spawn(async move {
+        if let Err(error) = connection.await {
+            crate::report::warn(format_args!("PostGIS connection closed: {}", error));
+        }
+    });
+    Ok(client)
+}
+
+/// Copy the records received into the staging table, then replace the layer's table with them
+/// once the commit message arrives. The transaction is rolled back when the channel closes
+/// first.
+#[cfg(feature = "postgres")]
+async fn load(
+    url: String,
+    table: TableDefinition,
+    mut receiver: Receiver<LoadMessage>,
+) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    use futures_util::{pin_mut, SinkExt};
+
+    let database_error = |error: tokio_postgres::Error| {
+        let message = match error.as_db_error() {
+            Some(db_error) => db_error.message().to_owned(),
+            None => error.to_string(),
+        };
+        PostgisError::Database(table.name.to_owned(), message)
+    };
+    let mut client = connect(&url).await.map_err(|error| match error.downcast::<tokio_postgres::Error>() {
+        Ok(error) => database_error(*error),
+        Err(error) => PostgisError::Database(table.name.to_owned(), error.to_string()),
+    })?;
+    let transaction = client.transaction().await.map_err(database_error)?;
+    transaction.batch_execute(&table.staging_statement()).await.map_err(database_error)?;
+    let sink = transaction.copy_in(&format!("COPY {} FROM STDIN (FORMAT csv)", STAGING_TABLE))
+        .await
+        .map_err(database_error)?;
+    pin_mut!(sink);
+    loop {
+        match receiver.recv().await {
+            Some(LoadMessage::Records(records)) => {
+                sink.send(bytes::Bytes::from(records)).await.map_err(database_error)?
+            }
+            Some(LoadMessage::Commit) => break,
+            None => return Err(PostgisError::Incomplete(table.name.to_owned()).into()),
+        }
+    }
+    let rows = sink.finish().await.map_err(database_error)?;
+    transaction.batch_execute(&table.replace_statement()).await.map_err(database_error)?;
+    transaction.commit().await.map_err(database_error)?;
+    Ok(rows)
+}
+
+#[cfg(not(feature = "postgres"))]
+async fn load(
+    _url: String,
+    _table: TableDefinition,
+    _receiver: Receiver<LoadMessage>,
+) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    Err(CapabilityError::Disabled(Capability::Postgres).into())
+}
+
+#[cfg(all(test, feature = "postgres"))]
+mod postgis_tests {
+    use std::sync::Arc;
+    use serde_json::json;
+    use crate::audit::{user_agent, RunAudit, RunId, RunIdPlacement};
+    use crate::auth::AnonymousAuth;
+    use crate::client::ServiceClient;
+    use crate::config::{RedactionRule, ScrapeConfig};
+    use crate::filter::QueryFilter;
+    use crate::metadata::parse_metadata;
+    use crate::scraping::output_columns;
+    use crate::transform::transformed_field_types;
+    use crate::wkt::wkt_fields;
+    use super::{launder, TableDefinition};
+
+    #[test]
+    fn launder_should_lowercase_and_replace_other_characters_when_passed_layer_name() {
+        assert_eq!(launder("Tax Parcels (2024)"), "tax_parcels__2024_");
+        assert_eq!(launder("2024_Parcels"), "_2024_parcels");
+    }
+
+    #[test]
+    fn replace_statement_should_cast_staged_values_when_fields_are_typed() {
+        let client = ServiceClient::new(
+            Arc::new(AnonymousAuth),
+            RunAudit { run_id: RunId::generate(), placement: RunIdPlacement::Header, user_agent: user_agent(None) },
+        );
+        let metadata = json!({
+            "name": "Parcels",
+            "type": "Feature Layer",
+            "maxRecordCount": 1000,
+            "geometryType": "esriGeometryPolygon",
+            "fields": [
+                {"name": "OBJECTID", "type": "esriFieldTypeOID", "alias": "OBJECTID"},
+                {
+                    "name": "Order",
+                    "type": "esriFieldTypeSmallInteger",
+                    "alias": "Order",
+                    "domain": {"type": "codedValue", "codedValues": [{"code": 1, "name": "First"}]},
+                },
+                {"name": "EDITED", "type": "esriFieldTypeDate", "alias": "Edited"},
+            ],
+            "sourceSpatialReference": {"wkid": 102100},
+            "advancedQueryCapabilities": {"supportsPagination": true},
+        });
+        let url = "https://example.com/arcgis/rest/services/Parcels/FeatureServer/0";
+        let mut layer = parse_metadata(&client, url, &metadata, None, &QueryFilter::default()).unwrap();
+        layer.fields = wkt_fields(layer.fields);
+        let columns = output_columns(&layer.fields, false, None, None, false);
+        let table = TableDefinition::new("gis", &layer, &columns);
+
+        assert_eq!(table.staging_statement(), "CREATE TEMP TABLE arcgis_scraper_staging (c0 text, c1 text, c2 text, c3 text, c4 text) ON COMMIT DROP");
+        assert_eq!(
+            table.replace_statement(),
+            "DROP TABLE IF EXISTS \"gis\".\"parcels\";\n\
+            CREATE TABLE \"gis\".\"parcels\" (\"objectid\" bigint, \"order\" smallint, \"order_desc\" text, \"edited\" timestamptz, \"geom\" geometry(Geometry, 3857));\n\
+            INSERT INTO \"gis\".\"parcels\" (\"objectid\", \"order\", \"order_desc\", \"edited\", \"geom\") \
+            SELECT c0::bigint, c1::smallint, c2, to_timestamp(c3::double precision / 1000), ST_GeomFromText(c4, 3857) FROM arcgis_scraper_staging",
+        );
+    }
+
+    #[test]
+    fn new_should_type_hashed_integer_field_as_text_when_fields_are_transformed() {
+        let client = ServiceClient::new(
+            Arc::new(AnonymousAuth),
+            RunAudit { run_id: RunId::generate(), placement: RunIdPlacement::Header, user_agent: user_agent(None) },
+        );
+        let metadata = json!({
+            "name": "Parcels",
+            "type": "table",
+            "maxRecordCount": 1000,
+            "fields": [
+                {"name": "OBJECTID", "type": "esriFieldTypeOID", "alias": "OBJECTID"},
+                {"name": "PARCEL_NO", "type": "esriFieldTypeInteger", "alias": "Parcel"},
+                {"name": "ASSESSED", "type": "esriFieldTypeDouble", "alias": "Assessed"},
+            ],
+            "advancedQueryCapabilities": {"supportsPagination": true},
+        });
+        let url = "https://example.com/arcgis/rest/services/Parcels/FeatureServer/1";
+        let mut layer = parse_metadata(&client, url, &metadata, None, &QueryFilter::default()).unwrap();
+        let config = ScrapeConfig {
+            hash_fields: vec!["parcel_no".to_owned()],
+            redactions: vec![RedactionRule {
+                field: "ASSESSED".to_owned(),
+                pattern: r"\d{3}$".to_owned(),
+                replacement: "000".to_owned(),
+            }],
+            ..Default::default()
+        };
+        layer.fields = transformed_field_types(layer.fields, &config);
+        let columns = output_columns(&layer.fields, false, None, None, false);
+        let table = TableDefinition::new("gis", &layer, &columns);
+
+        assert_eq!(
+            table.replace_statement(),
+            "DROP TABLE IF EXISTS \"gis\".\"parcels\";\n\
+            CREATE TABLE \"gis\".\"parcels\" (\"objectid\" bigint, \"parcel_no\" text, \"assessed\" text);\n\
+            INSERT INTO \"gis\".\"parcels\" (\"objectid\", \"parcel_no\", \"assessed\") \
+            SELECT c0::bigint, c1, c2 FROM arcgis_scraper_staging",
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use std::error::Error;
+use std::io;
+use serde_json::Value;
+use tablestream::{Stream, Column};
+use crate::connection::HostConnections;
+use crate::geometry::{esri_extent, Extent};
+use crate::metadata::{RestServiceFieldType, RestServiceMetadata};
+use crate::scraping::{convert_json_field, loop_until_successful};
+
+/// Fetch the first `count` features of the layer and print their attributes as a table, followed
+/// by the geometry type and the extent covered by the previewed features.
+pub(crate) async fn preview_layer(
+    layer: &RestServiceMetadata,
+    connections: &HostConnections,
+    count: u32,
+    max_tries: i32,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let query = layer.chunk_query(0, i64::from(count).min(layer.scrape_count()))?;
+    let response = loop_until_successful(connections, &query, max_tries, None).await?;
+    let features = response["features"].as_array().cloned().unwrap_or_default();
+    let fields: Vec<_> = layer.fields.iter()
+        .filter(|field| field.field_type != RestServiceFieldType::Geometry)
+        .cloned()
+        .collect();
+
+    let mut rows: Vec<Vec<String>> = vec![];
+    let mut extent: Option<Extent> = None;
+    for feature in features.iter().take(usize::try_from(count)?) {
+        let attributes = &feature["attributes"];
+        let mut row = vec![];
+        for field in &fields {
+            let values = convert_json_field(field, &attributes[field.name.as_str()])?;
+            let value = match values.as_slice() {
+                [code, description] if !description.is_empty() => {
+                    format!("{} ({})", code, description)
+                }
+                _ => values.first().cloned().unwrap_or_default(),
+            };
+            row.push(value);
+        }
+        rows.push(row);
+        if let Some(feature_extent) = feature["geometry"].as_object().and_then(esri_extent) {
+            extent = Some(match extent {
+                Some(current) => current.union(&feature_extent),
+                None => feature_extent,
+            });
+        }
+    }
+
+    let mut out = io::stdout();
+    let columns = fields.iter()
+        .enumerate()
+        .map(|(i, field)| {
+            Column::new(move |f, row: &Vec<String>| write!(f, "{}", row[i]))
+                .header(&field.name)
+        })
+        .collect();
+    let mut stream = Stream::new(&mut out, columns)
+        .title(&format!("{} (first {} features)", layer.name, rows.len()));
+    for row in rows {
+        stream.row(row)?;
+    }
+    stream.finish()?;
+    if !layer.is_table() {
+        println!("Geometry Type: {}", layer.geo_type);
+        match extent {
+            Some(extent) => println!("Preview Extent: {}", extent),
+            None => println!("Preview Extent: no geometries returned"),
+        }
+    }
+    if let Some(Value::Bool(true)) = response.get("exceededTransferLimit") {
+        println!("Server reports more features are available beyond this page");
+    }
+    Ok(())
+}
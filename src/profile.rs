@@ -0,0 +1,270 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use crate::metadata::RestServiceMetadata;
+use crate::state::state_directory;
+
+/// Multiple of a server's typical latency the stall timeout is raised to, so a slow server is
+/// not taken for a stalled one.
+const STALL_LATENCY_FACTOR: u32 = 3;
+
+/// What this run saw of each host, learned into the host's profile once the run ends.
+static OBSERVATIONS: Mutex<BTreeMap<String, HostObservations>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Default)]
+struct HostObservations {
+    in_flight: usize,
+    max_in_flight: usize,
+    /// Fewest requests in flight when the server answered 429 or 503
+    throttled_in_flight: Option<usize>,
+    latencies: Vec<Duration>,
+    pbf: Option<bool>,
+    /// Features held by the pages the server cut short without flagging them
+    page_cap: Option<usize>,
+}
+
+/// Settings learned about a server by past runs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ServerProfile {
+    /// Requests in flight at once the server handled without answering 429 or 503
+    pub(crate) max_concurrent_requests: Option<u16>,
+    /// Features per query the server returns in full, when below the maxRecordCount it reports
+    pub(crate) max_record_count: Option<i64>,
+    /// Median time of a successful query
+    pub(crate) typical_latency_ms: Option<u64>,
+    /// Whether the server answers pbf queries
+    pub(crate) pbf: Option<bool>,
+    pub(crate) updated_at: DateTime<Utc>,
+}
+
+impl ServerProfile {
+    /// Stall timeout raised to a few times the server's typical latency.
+    pub(crate) fn stall_timeout(&self, timeout: Duration) -> Duration {
+        self.typical_latency_ms
+            .map(|latency| Duration::from_millis(latency) * STALL_LATENCY_FACTOR)
+            .map_or(timeout, |timeout_of_latency| timeout.max(timeout_of_latency))
+    }
+
+    fn describe(&self) -> String {
+        let mut settings = vec![];
+        if let Some(requests) = self.max_concurrent_requests {
+            settings.push(format!("at most {} concurrent requests", requests));
+        }
+        if let Some(count) = self.max_record_count {
+            settings.push(format!("{} features per query", count));
+        }
+        if let Some(latency) = self.typical_latency_ms {
+            settings.push(format!("typical latency of {}ms", latency));
+        }
+        if self.pbf == Some(false) {
+            settings.push("json responses".to_owned());
+        }
+        settings.join(", ")
+    }
+}
+
+/// Name a host's profile is stored under, its port included when it is not the default one.
+pub(crate) fn host_key(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    Some(match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_owned(),
+    })
+}
+
+fn observe<F: FnOnce(&mut HostObservations)>(host: Option<&str>, update: F) {
+    if let Some(host) = host {
+        update(OBSERVATIONS.lock().unwrap().entry(host.to_owned()).or_default());
+    }
+}
+
+/// Request to a host, counted as in flight until dropped.
+pub(crate) struct ObservedRequest {
+    host: Option<String>,
+    started: Instant,
+}
+
+impl ObservedRequest {
+    pub(crate) fn succeeded(&self) {
+        let latency = self.started.elapsed();
+        observe(self.host.as_deref(), |observations| observations.latencies.push(latency));
+    }
+
+    /// The server answered 429 or 503, too many requests were in flight.
+    pub(crate) fn throttled(&self) {
+        observe(self.host.as_deref(), |observations| {
+            let in_flight = observations.in_flight;
+            observations.throttled_in_flight = Some(
+                observations.throttled_in_flight.map_or(in_flight, |throttled| throttled.min(in_flight)),
+            );
+        });
+    }
+}
+
+impl Drop for ObservedRequest {
+    fn drop(&mut self) {
+        observe(self.host.as_deref(), |observations| {
+            observations.in_flight = observations.in_flight.saturating_sub(1);
+        });
+    }
+}
+
+pub(crate) fn start_request(host: Option<&str>) -> ObservedRequest {
+    observe(host, |observations| {
+        observations.in_flight += 1;
+        observations.max_in_flight = observations.max_in_flight.max(observations.in_flight);
+    });
+    ObservedRequest { host: host.map(str::to_owned), started: Instant::now() }
+}
+
+/// Record whether the host answered a pbf query. A rejection wins over earlier answers.
+pub(crate) fn observe_pbf(host: Option<&str>, accepted: bool) {
+    observe(host, |observations| {
+        observations.pbf = Some(accepted && observations.pbf != Some(false));
+    });
+}
+
+/// Record the features held by the pages of a layer the host cut short.
+pub(crate) fn observe_page_cap(url: &str, returned: usize) {
+    observe(host_key(url).as_deref(), |observations| {
+        observations.page_cap = Some(observations.page_cap.map_or(returned, |cap| cap.min(returned)));
+    });
+}
+
+/// Profile of a host updated with what a run observed. Concurrency only rises to what a run
+/// without throttling reached, and drops to half of what was in flight when throttled.
+fn learn(previous: Option<&ServerProfile>, observations: &HostObservations, now: DateTime<Utc>) -> ServerProfile {
+    let previous_requests = previous.and_then(|profile| profile.max_concurrent_requests);
+    let max_concurrent_requests = match observations.throttled_in_flight {
+        Some(in_flight) => {
+            let safe = u16::try_from((in_flight / 2).max(1)).unwrap_or(u16::MAX);
+            Some(previous_requests.map_or(safe, |requests| requests.min(safe)))
+        }
+        None => previous_requests.map(|requests| {
+            requests.max(u16::try_from(observations.max_in_flight).unwrap_or(u16::MAX))
+        }),
+    };
+    let previous_count = previous.and_then(|profile| profile.max_record_count);
+    let max_record_count = match observations.page_cap.and_then(|cap| i64::try_from(cap).ok()) {
+        Some(cap) => Some(previous_count.map_or(cap, |count| count.min(cap))),
+        None => previous_count,
+    };
+    let mut latencies = observations.latencies.clone();
+    latencies.sort();
+    let typical_latency_ms = latencies.get(latencies.len() / 2)
+        .map(|latency| u64::try_from(latency.as_millis()).unwrap_or(u64::MAX))
+        .or(previous.and_then(|profile| profile.typical_latency_ms));
+    ServerProfile {
+        max_concurrent_requests,
+        max_record_count,
+        typical_latency_ms,
+        pbf: observations.pbf.or(previous.and_then(|profile| profile.pbf)),
+        updated_at: now,
+    }
+}
+
+/// Profiles of every host seen by past runs, by [`host_key`].
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub(crate) struct ServerProfiles {
+    hosts: BTreeMap<String, ServerProfile>,
+}
+
+fn profiles_path() -> std::io::Result<PathBuf> {
+    Ok(state_directory()?.join("profiles.json"))
+}
+
+impl ServerProfiles {
+    /// Stored profiles, none when the file is missing or unreadable.
+    pub(crate) fn load() -> Self {
+        profiles_path()
+            .ok()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn get(&self, url: &str) -> Option<&ServerProfile> {
+        self.hosts.get(&host_key(url)?)
+    }
+
+    /// Announce the profile of each host of `layers`, lowering the maxRecordCount of the layers
+    /// of hosts that cut pages short unless `limit_record_count` is false.
+    pub(crate) fn apply_to_layers(&self, layers: &mut [RestServiceMetadata], limit_record_count: bool) {
+        let mut announced = BTreeSet::new();
+        for layer in layers {
+            let (host, profile) = match host_key(layer.url()).and_then(|host| Some((host.clone(), self.hosts.get(&host)?))) {
+                Some(found) => found,
+                None => continue,
+            };
+            if announced.insert(host.clone()) {
+                println!("Using what past runs learned about {}: {} (--ignore-profile to skip)", host, profile.describe());
+            }
+            if let Some(count) = profile.max_record_count.filter(|_| limit_record_count) {
+                layer.limit_max_record_count(count);
+            }
+        }
+    }
+}
+
+/// Learn what this run observed into the stored profiles. Returns the hosts updated.
+pub(crate) fn save_observations() -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let observations = std::mem::take(&mut *OBSERVATIONS.lock().unwrap());
+    if observations.is_empty() {
+        return Ok(0)
+    }
+    let mut profiles = ServerProfiles::load();
+    let now = Utc::now();
+    for (host, observations) in &observations {
+        let profile = learn(profiles.hosts.get(host), observations, now);
+        profiles.hosts.insert(host.to_owned(), profile);
+    }
+    std::fs::write(profiles_path()?, serde_json::to_string_pretty(&profiles)?)?;
+    Ok(observations.len())
+}
+
+#[cfg(test)]
+mod profile_tests {
+    use std::time::Duration;
+    use chrono::Utc;
+    use super::{learn, HostObservations, ServerProfile};
+
+    #[test]
+    fn learn_should_halve_concurrency_and_keep_page_cap_when_server_throttled() {
+        let observations = HostObservations {
+            max_in_flight: 8,
+            throttled_in_flight: Some(6),
+            latencies: vec![Duration::from_millis(300), Duration::from_millis(100), Duration::from_millis(200)],
+            pbf: Some(false),
+            page_cap: Some(600),
+            ..HostObservations::default()
+        };
+        let previous = ServerProfile {
+            max_concurrent_requests: Some(4),
+            max_record_count: Some(1000),
+            typical_latency_ms: None,
+            pbf: Some(true),
+            updated_at: Utc::now(),
+        };
+
+        let profile = learn(Some(&previous), &observations, Utc::now());
+
+        assert_eq!(profile.max_concurrent_requests, Some(3));
+        assert_eq!(profile.max_record_count, Some(600));
+        assert_eq!(profile.typical_latency_ms, Some(200));
+        assert_eq!(profile.pbf, Some(false));
+        assert_eq!(profile.stall_timeout(Duration::from_millis(100)), Duration::from_millis(600));
+
+        let calm = HostObservations { max_in_flight: 5, ..HostObservations::default() };
+        let profile = learn(Some(&profile), &calm, Utc::now());
+        assert_eq!(profile.max_concurrent_requests, Some(5));
+        assert_eq!(profile.max_record_count, Some(600));
+        assert_eq!(profile.typical_latency_ms, Some(200));
+    }
+}
@@ -0,0 +1,469 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+use serde::Serialize;
+use crate::audit::RunId;
+
+/// Layer whose progress is reported. Its url tells apart layers of the same name in different
+/// services.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayerRef<'a> {
+    pub url: &'a str,
+    pub name: &'a str,
+}
+
+/// Progress of a layer as one of its chunk queries finishes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkProgress {
+    pub chunk_id: usize,
+    /// Features and bytes of the finished chunk
+    pub features: usize,
+    pub bytes: usize,
+    pub completed_chunks: usize,
+    pub chunk_count: usize,
+    /// Features and bytes of the layer's chunks finished so far
+    pub total_features: u64,
+    pub total_bytes: u64,
+    /// Feature count of the layer
+    pub expected_features: u64,
+    /// Throughput smoothed over the recent chunks, so a few unusually fast or slow ones do not
+    /// swing the ETA
+    pub features_per_second: f64,
+    pub eta: Option<Duration>,
+}
+
+/// A layer once its features were written to the output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerSummary {
+    /// Strategy whose attempt was written, None when the layer was scraped without one
+    pub strategy: Option<String>,
+    pub features: usize,
+    pub bytes: usize,
+}
+
+/// Receives the progress of every layer scraped, so embedders can surface it in their own UI.
+/// The console progress bar and the `--progress-events` file are implementations. Every
+/// method does nothing by default.
+pub trait ProgressReporter: Debug + Send + Sync {
+    /// The chunk queries of a layer are planned and about to be fetched
+    fn on_plan(&self, _layer: LayerRef<'_>, _chunk_count: usize, _expected_features: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn on_chunk_start(&self, _layer: LayerRef<'_>, _chunk_id: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn on_chunk_done(&self, _layer: LayerRef<'_>, _progress: &ChunkProgress) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// A request of the chunk failed and is tried again, `attempt` counting the failures so far
+    fn on_retry(&self, _layer: LayerRef<'_>, _chunk_id: usize, _attempt: i32, _error: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn on_finish(&self, _layer: LayerRef<'_>, _summary: &LayerSummary) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Every reporter of a run, called in order.
+#[derive(Debug, Default)]
+pub(crate) struct ProgressReporters(Vec<Arc<dyn ProgressReporter>>);
+
+impl ProgressReporters {
+    pub(crate) fn push(&mut self, reporter: Arc<dyn ProgressReporter>) {
+        self.0.push(reporter);
+    }
+}
+
+impl ProgressReporter for ProgressReporters {
+    fn on_plan(&self, layer: LayerRef<'_>, chunk_count: usize, expected_features: u64) -> io::Result<()> {
+        self.0.iter().try_for_each(|reporter| reporter.on_plan(layer, chunk_count, expected_features))
+    }
+
+    fn on_chunk_start(&self, layer: LayerRef<'_>, chunk_id: usize) -> io::Result<()> {
+        self.0.iter().try_for_each(|reporter| reporter.on_chunk_start(layer, chunk_id))
+    }
+
+    fn on_chunk_done(&self, layer: LayerRef<'_>, progress: &ChunkProgress) -> io::Result<()> {
+        self.0.iter().try_for_each(|reporter| reporter.on_chunk_done(layer, progress))
+    }
+
+    fn on_retry(&self, layer: LayerRef<'_>, chunk_id: usize, attempt: i32, error: &str) -> io::Result<()> {
+        self.0.iter().try_for_each(|reporter| reporter.on_retry(layer, chunk_id, attempt, error))
+    }
+
+    fn on_finish(&self, layer: LayerRef<'_>, summary: &LayerSummary) -> io::Result<()> {
+        self.0.iter().try_for_each(|reporter| reporter.on_finish(layer, summary))
+    }
+}
+
+/// Progress bar of each layer being fetched, cleared once all its chunks are done.
+#[derive(Debug, Default)]
+pub(crate) struct ConsoleProgress {
+    bars: Mutex<HashMap<String, ProgressBar>>,
+}
+
+impl ConsoleProgress {
+
+    fn clear(&self, layer: LayerRef<'_>) {
+        if let Some(bar) = self.bars.lock().unwrap().remove(layer.url) {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+impl ProgressReporter for ConsoleProgress {
+    fn on_plan(&self, layer: LayerRef<'_>, chunk_count: usize, expected_features: u64) -> io::Result<()> {
+        let progress_style = ProgressStyle::with_template(
+            "{bar:60.cyan/blue} {pos:>9}/{len:9} features {msg}"
+        ).map_err(io::Error::other)?.progress_chars("##-");
+        let bar = ProgressBar::new(expected_features);
+        bar.set_style(progress_style);
+        bar.set_message(format!("0/{} chunks, {}", chunk_count, HumanBytes(0)));
+        self.bars.lock().unwrap().insert(layer.url.to_owned(), bar);
+        Ok(())
+    }
+
+    fn on_chunk_done(&self, layer: LayerRef<'_>, progress: &ChunkProgress) -> io::Result<()> {
+        if let Some(bar) = self.bars.lock().unwrap().get(layer.url) {
+            bar.inc(progress.features as u64);
+            bar.set_message(format!(
+                "{}/{} chunks, {}, {:.0} features/s, ETA {}",
+                progress.completed_chunks,
+                progress.chunk_count,
+                HumanBytes(progress.total_bytes),
+                progress.features_per_second,
+                progress.eta.map(|eta| HumanDuration(eta).to_string()).unwrap_or_else(|| "unknown".to_owned()),
+            ));
+        }
+        if progress.completed_chunks == progress.chunk_count {
+            self.clear(layer);
+        }
+        Ok(())
+    }
+
+    /// Chunks skipped once the deadline is reached never complete, clear their bar here.
+    fn on_finish(&self, layer: LayerRef<'_>, _summary: &LayerSummary) -> io::Result<()> {
+        self.clear(layer);
+        Ok(())
+    }
+}
+
+/// Machine readable progress of a scrape, written as one JSON object per line.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum ProgressEvent<'a> {
+    /// A chunk query finished and its features were written to a temp file
+    Chunk {
+        layer: &'a str,
+        chunk_id: usize,
+        features: usize,
+        bytes: usize,
+        completed_chunks: usize,
+        chunk_count: usize,
+        total_features: u64,
+        total_bytes: u64,
+        expected_features: u64,
+        timestamp: DateTime<Utc>,
+    },
+    /// The features of a layer were written to the output file
+    Layer {
+        layer: &'a str,
+        strategy: Option<String>,
+        features: usize,
+        bytes: usize,
+        timestamp: DateTime<Utc>,
+    },
+}
+
+/// Event as written, tagged with the id of the run that produced it.
+#[derive(Debug, Serialize)]
+struct RunEvent<'a> {
+    run_id: &'a RunId,
+    #[serde(flatten)]
+    event: &'a ProgressEvent<'a>,
+}
+
+/// Destination of the `--progress-events` JSON lines.
+#[derive(Debug)]
+pub(crate) struct ProgressEvents {
+    file: Mutex<File>,
+    run_id: RunId,
+}
+
+impl ProgressEvents {
+    pub(crate) fn create(path: &Path, run_id: &RunId) -> io::Result<Self> {
+        Ok(Self { file: Mutex::new(File::create(path)?), run_id: run_id.to_owned() })
+    }
+
+    fn emit(&self, event: &ProgressEvent) -> io::Result<()> {
+        let line = serde_json::to_string(&RunEvent { run_id: &self.run_id, event })?;
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", line)?;
+        file.flush()
+    }
+}
+
+impl ProgressReporter for ProgressEvents {
+    fn on_chunk_done(&self, layer: LayerRef<'_>, progress: &ChunkProgress) -> io::Result<()> {
+        self.emit(&ProgressEvent::Chunk {
+            layer: layer.name,
+            chunk_id: progress.chunk_id,
+            features: progress.features,
+            bytes: progress.bytes,
+            completed_chunks: progress.completed_chunks,
+            chunk_count: progress.chunk_count,
+            total_features: progress.total_features,
+            total_bytes: progress.total_bytes,
+            expected_features: progress.expected_features,
+            timestamp: Utc::now(),
+        })
+    }
+
+    fn on_finish(&self, layer: LayerRef<'_>, summary: &LayerSummary) -> io::Result<()> {
+        self.emit(&ProgressEvent::Layer {
+            layer: layer.name,
+            strategy: summary.strategy.clone(),
+            features: summary.features,
+            bytes: summary.bytes,
+            timestamp: Utc::now(),
+        })
+    }
+}
+
+/// Weight of the newest sample in the smoothed throughput.
+const SMOOTHING: f64 = 0.3;
+/// Shortest window a throughput sample is taken over. Chunks finishing together would
+/// otherwise produce absurd instantaneous rates.
+const SAMPLE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Exponentially smoothed feature throughput, so a few unusually fast or slow chunks do not
+/// swing the ETA.
+#[derive(Debug)]
+struct Throughput {
+    started: Instant,
+    window_start: Instant,
+    window_features: u64,
+    total_features: u64,
+    features_per_second: Option<f64>,
+}
+
+impl Throughput {
+    fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            window_start: now,
+            window_features: 0,
+            total_features: 0,
+            features_per_second: None,
+        }
+    }
+
+    fn update(&mut self, features: u64, now: Instant) {
+        self.window_features += features;
+        self.total_features += features;
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < SAMPLE_WINDOW {
+            return
+        }
+        let sample = self.window_features as f64 / elapsed.as_secs_f64();
+        self.features_per_second = Some(match self.features_per_second {
+            Some(smoothed) => SMOOTHING * sample + (1_f64 - SMOOTHING) * smoothed,
+            None => sample,
+        });
+        self.window_start = now;
+        self.window_features = 0;
+    }
+
+    /// Smoothed rate, or the overall average until a full sample window has passed.
+    fn rate(&self, now: Instant) -> f64 {
+        self.features_per_second.unwrap_or_else(|| {
+            let elapsed = now.duration_since(self.started).as_secs_f64();
+            if elapsed > 0_f64 { self.total_features as f64 / elapsed } else { 0_f64 }
+        })
+    }
+
+    fn eta(&self, remaining_features: u64, now: Instant) -> Option<Duration> {
+        let rate = self.rate(now);
+        if rate > 0_f64 {
+            Some(Duration::from_secs_f64(remaining_features as f64 / rate))
+        } else {
+            None
+        }
+    }
+}
+
+/// Tracks features and bytes fetched for a set of chunk queries. Fetch workers report their
+/// chunks as they finish so the progress reflects actual data rather than chunk order.
+#[derive(Debug)]
+pub(crate) struct ProgressTracker {
+    url: String,
+    name: String,
+    chunk_count: usize,
+    expected_features: u64,
+    completed_chunks: AtomicUsize,
+    features: AtomicU64,
+    bytes: AtomicU64,
+    throughput: Mutex<Throughput>,
+    reporter: Arc<dyn ProgressReporter>,
+}
+
+impl ProgressTracker {
+    pub(crate) fn new(
+        layer: LayerRef<'_>,
+        chunk_count: usize,
+        expected_features: u64,
+        reporter: Arc<dyn ProgressReporter>,
+    ) -> io::Result<Self> {
+        reporter.on_plan(layer, chunk_count, expected_features)?;
+        Ok(Self {
+            url: layer.url.to_owned(),
+            name: layer.name.to_owned(),
+            chunk_count,
+            expected_features,
+            completed_chunks: AtomicUsize::new(0),
+            features: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            throughput: Mutex::new(Throughput::new(Instant::now())),
+            reporter,
+        })
+    }
+
+    fn layer(&self) -> LayerRef<'_> {
+        LayerRef { url: &self.url, name: &self.name }
+    }
+
+    pub(crate) fn chunk_started(&self, chunk_id: usize) -> io::Result<()> {
+        self.reporter.on_chunk_start(self.layer(), chunk_id)
+    }
+
+    pub(crate) fn chunk_finished(
+        &self,
+        chunk_id: usize,
+        features: usize,
+        bytes: usize,
+    ) -> io::Result<()> {
+        let completed_chunks = self.completed_chunks.fetch_add(1, Ordering::SeqCst) + 1;
+        let total_features = self.features.fetch_add(features as u64, Ordering::SeqCst) + features as u64;
+        let total_bytes = self.bytes.fetch_add(bytes as u64, Ordering::SeqCst) + bytes as u64;
+        let now = Instant::now();
+        let (features_per_second, eta) = {
+            let mut throughput = self.throughput.lock().unwrap();
+            throughput.update(features as u64, now);
+            (
+                throughput.rate(now),
+                throughput.eta(self.expected_features.saturating_sub(total_features), now),
+            )
+        };
+        self.reporter.on_chunk_done(self.layer(), &ChunkProgress {
+            chunk_id,
+            features,
+            bytes,
+            completed_chunks,
+            chunk_count: self.chunk_count,
+            total_features,
+            total_bytes,
+            expected_features: self.expected_features,
+            features_per_second,
+            eta,
+        })
+    }
+}
+
+#[cfg(test)]
+mod throughput_tests {
+    use std::time::{Duration, Instant};
+    use super::Throughput;
+
+    #[test]
+    fn update_should_smooth_rate_when_throughput_drops() {
+        let start = Instant::now();
+        let mut throughput = Throughput::new(start);
+        throughput.update(1000, start + Duration::from_secs(1));
+        throughput.update(0, start + Duration::from_secs(2));
+        let rate = throughput.rate(start + Duration::from_secs(2));
+        assert!((rate - 700_f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn eta_should_return_none_when_nothing_fetched() {
+        let start = Instant::now();
+        let throughput = Throughput::new(start);
+        assert_eq!(throughput.eta(100, start + Duration::from_secs(5)), None);
+    }
+}
+
+#[cfg(test)]
+mod progress_event_tests {
+    use chrono::{TimeZone, Utc};
+    use super::ProgressEvent;
+
+    #[test]
+    fn serialize_should_tag_event_when_passed_layer_event() {
+        let event = ProgressEvent::Layer {
+            layer: "Parcels",
+            strategy: Some("pagination".to_owned()),
+            features: 2500,
+            bytes: 1024,
+            timestamp: Utc.with_ymd_and_hms(2022, 7, 1, 12, 0, 0).unwrap(),
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"layer","layer":"Parcels","strategy":"pagination","features":2500,"bytes":1024,"timestamp":"2022-07-01T12:00:00Z"}"#,
+        );
+    }
+}
+
+#[cfg(test)]
+mod progress_tracker_tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use super::{ChunkProgress, LayerRef, ProgressReporter, ProgressTracker};
+
+    #[derive(Debug, Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl ProgressReporter for Recorder {
+        fn on_plan(&self, layer: LayerRef<'_>, chunk_count: usize, expected_features: u64) -> io::Result<()> {
+            self.0.lock().unwrap().push(format!("plan {} {} {}", layer.name, chunk_count, expected_features));
+            Ok(())
+        }
+
+        fn on_chunk_done(&self, layer: LayerRef<'_>, progress: &ChunkProgress) -> io::Result<()> {
+            self.0.lock().unwrap().push(format!(
+                "done {} {} {}/{} {}",
+                layer.name,
+                progress.chunk_id,
+                progress.completed_chunks,
+                progress.chunk_count,
+                progress.total_features,
+            ));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn chunk_finished_should_report_running_totals_when_chunks_done() {
+        let recorder = Arc::new(Recorder::default());
+        let layer = LayerRef { url: "https://example.com/arcgis/rest/services/Parcels/FeatureServer/0", name: "Parcels" };
+        let tracker = ProgressTracker::new(layer, 2, 1500, recorder.clone()).unwrap();
+        tracker.chunk_started(1).unwrap();
+        tracker.chunk_finished(1, 1000, 2048).unwrap();
+        tracker.chunk_finished(0, 500, 1024).unwrap();
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec!["plan Parcels 2 1500", "done Parcels 1 1/2 1000", "done Parcels 0 2/2 1500"],
+        );
+    }
+}
@@ -0,0 +1,76 @@
+use crate::geometry::Extent;
+
+const WGS84: i64 = 4326;
+const WEB_MERCATOR: [i64; 4] = [3857, 102100, 102113, 900913];
+const EARTH_RADIUS: f64 = 6378137_f64;
+const MAX_MERCATOR_LATITUDE: f64 = 85.051_128_779_806_59;
+
+fn is_web_mercator(wkid: i64) -> bool {
+    WEB_MERCATOR.contains(&wkid)
+}
+
+/// True when both well-known ids describe the same coordinate system.
+pub(crate) fn same_spatial_reference(first: i64, second: i64) -> bool {
+    first == second || (is_web_mercator(first) && is_web_mercator(second))
+}
+
+fn to_web_mercator(x: f64, y: f64) -> (f64, f64) {
+    let latitude = y.clamp(-MAX_MERCATOR_LATITUDE, MAX_MERCATOR_LATITUDE);
+    let x = EARTH_RADIUS * x.to_radians();
+    let y = EARTH_RADIUS * (std::f64::consts::FRAC_PI_4 + latitude.to_radians() / 2_f64).tan().ln();
+    (x, y)
+}
+
+fn from_web_mercator(x: f64, y: f64) -> (f64, f64) {
+    let longitude = (x / EARTH_RADIUS).to_degrees();
+    let latitude = (2_f64 * (y / EARTH_RADIUS).exp().atan() - std::f64::consts::FRAC_PI_2).to_degrees();
+    (longitude, latitude)
+}
+
+/// Reproject a single coordinate. Only WGS84 and Web Mercator are supported client-side, other
+/// combinations return None and must be left to the server.
+pub(crate) fn transform_point(x: f64, y: f64, from: i64, to: i64) -> Option<(f64, f64)> {
+    if same_spatial_reference(from, to) {
+        return Some((x, y))
+    }
+    if from == WGS84 && is_web_mercator(to) {
+        Some(to_web_mercator(x, y))
+    } else if is_web_mercator(from) && to == WGS84 {
+        Some(from_web_mercator(x, y))
+    } else {
+        None
+    }
+}
+
+/// Reproject an extent by its corners. Valid for the supported projections since both keep
+/// axis-aligned boxes axis-aligned.
+pub(crate) fn transform_extent(extent: &Extent, from: i64, to: i64) -> Option<Extent> {
+    let (x_min, y_min) = transform_point(extent.x_min, extent.y_min, from, to)?;
+    let (x_max, y_max) = transform_point(extent.x_max, extent.y_max, from, to)?;
+    Some(Extent { x_min, y_min, x_max, y_max })
+}
+
+#[cfg(test)]
+mod projection_tests {
+    use super::transform_point;
+
+    #[test]
+    fn transform_point_should_return_mercator_when_passed_wgs84() {
+        let (x, y) = transform_point(-122.0, 45.0, 4326, 3857).unwrap();
+        assert!((x - -13580977.876).abs() < 0.01);
+        assert!((y - 5621521.486).abs() < 0.01);
+    }
+
+    #[test]
+    fn transform_point_should_round_trip_when_passed_mercator() {
+        let (x, y) = transform_point(-122.0, 45.0, 4326, 102100).unwrap();
+        let (longitude, latitude) = transform_point(x, y, 102100, 4326).unwrap();
+        assert!((longitude - -122.0).abs() < 1e-9);
+        assert!((latitude - 45.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn transform_point_should_return_none_when_projection_unsupported() {
+        assert_eq!(transform_point(500000.0, 4000000.0, 26910, 4326), None);
+    }
+}
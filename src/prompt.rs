@@ -0,0 +1,166 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::{IsTerminal, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use clap::ValueEnum;
+use indicatif::HumanBytes;
+use crate::metadata::{RestServiceFieldType, RestServiceMetadata};
+
+/// Seconds the confirmation prompt waits for an answer when standard input is not a terminal,
+/// e.g. inside a container started without one.
+const NON_INTERACTIVE_TIMEOUT: u64 = 30;
+
+/// Answer of the confirmation prompt taken on Enter, and when no answer arrives.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub(crate) enum PromptAnswer {
+    Yes,
+    No,
+}
+
+impl Display for PromptAnswer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PromptAnswer::Yes => write!(f, "yes"),
+            PromptAnswer::No => write!(f, "no"),
+        }
+    }
+}
+
+/// What standard input gave the prompt.
+enum Reply {
+    Line(String),
+    /// Standard input was closed, e.g. redirected from /dev/null
+    Closed,
+    TimedOut,
+}
+
+/// Answer of a line typed at the prompt, None when it is neither yes nor no.
+fn parse_answer(line: &str, default: PromptAnswer) -> Option<PromptAnswer> {
+    match line.trim().to_uppercase().as_str() {
+        "" => Some(default),
+        "Y" | "YES" => Some(PromptAnswer::Yes),
+        "N" | "NO" => Some(PromptAnswer::No),
+        _ => None,
+    }
+}
+
+/// Read a line of standard input, giving up after `timeout`. The reading thread is left blocked
+/// when it times out, nothing else reads standard input.
+fn read_reply(timeout: Option<Duration>) -> io::Result<Reply> {
+    let read = || {
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line)? {
+            0 => Ok(Reply::Closed),
+            _ => Ok(Reply::Line(line)),
+        }
+    };
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return read(),
+    };
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(read());
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(reply) => reply,
+        Err(_) => Ok(Reply::TimedOut),
+    }
+}
+
+/// Rough size of the attributes of a record, from the declared length of text fields and the
+/// usual width of the other types. Geometries are left out, their size depends on the data.
+fn estimated_record_bytes(layer: &RestServiceMetadata) -> u64 {
+    layer.fields.iter()
+        .map(|field| {
+            let width = match field.field_type {
+                RestServiceFieldType::OID | RestServiceFieldType::Integer => 8,
+                RestServiceFieldType::SmallInteger => 4,
+                RestServiceFieldType::Double | RestServiceFieldType::Single | RestServiceFieldType::Float => 12,
+                RestServiceFieldType::Date => 13,
+                RestServiceFieldType::GUID | RestServiceFieldType::GlobalID => 38,
+                RestServiceFieldType::Geometry => 0,
+                // Text is rarely as long as declared
+                _ => field.length.map_or(16, |length| length.clamp(0, 32) as u64),
+            };
+            let description = if field.codes.is_some() { 16 } else { 0 };
+            width + description + 1
+        })
+        .sum()
+}
+
+/// Ask whether to scrape `layers`, restating how many features are about to be scraped since
+/// the layer summaries may have scrolled out of view. Enter takes `default`, as does standard
+/// input closing or no answer arriving within `timeout`. Without a timeout, a standard input
+/// that is not a terminal waits 30 seconds.
+pub(crate) fn confirm_scrape(
+    layers: &[RestServiceMetadata],
+    default: PromptAnswer,
+    timeout: Option<u64>,
+) -> io::Result<bool> {
+    let features: i64 = layers.iter().filter_map(|layer| layer.feature_count().ok()).sum();
+    let bytes: u64 = layers.iter()
+        .filter_map(|layer| Some(layer.feature_count().ok()? as u64 * estimated_record_bytes(layer)))
+        .sum();
+    let interactive = io::stdin().is_terminal();
+    let timeout = timeout
+        .or((!interactive).then_some(NON_INTERACTIVE_TIMEOUT))
+        .map(Duration::from_secs);
+    let layer_count = if layers.len() > 1 { format!(" from {} layers", layers.len()) } else { String::new() };
+    let choices = match default {
+        PromptAnswer::Yes => "Y/n",
+        PromptAnswer::No => "y/N",
+    };
+    print!(
+        "Proceed with scrape of {} features (about {} of attributes){} [{}]: ",
+        features,
+        HumanBytes(bytes),
+        layer_count,
+        choices,
+    );
+    io::stdout().flush()?;
+    let answer = match read_reply(timeout) {
+        Ok(Reply::Line(line)) => match parse_answer(&line, default) {
+            Some(answer) => answer,
+            None => {
+                println!("Got response of, {:?}", line.as_bytes());
+                PromptAnswer::No
+            }
+        },
+        Ok(Reply::Closed) => {
+            println!();
+            println!("Standard input is closed, taking the default answer ({})", default);
+            default
+        }
+        Ok(Reply::TimedOut) => {
+            println!();
+            println!("No answer after {} seconds, taking the default answer ({})", timeout.unwrap_or_default().as_secs(), default);
+            default
+        }
+        Err(_) => {
+            println!("Error while reading user input. Exiting program");
+            return Ok(false)
+        }
+    };
+    if answer == PromptAnswer::No {
+        println!("Decided to not scrape. Exiting program");
+        return Ok(false)
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod prompt_tests {
+    use super::{parse_answer, PromptAnswer};
+
+    #[test]
+    fn parse_answer_should_take_default_when_passed_empty_line() {
+        assert_eq!(parse_answer("\n", PromptAnswer::Yes), Some(PromptAnswer::Yes));
+        assert_eq!(parse_answer("  \r\n", PromptAnswer::No), Some(PromptAnswer::No));
+        assert_eq!(parse_answer("yes\n", PromptAnswer::No), Some(PromptAnswer::Yes));
+        assert_eq!(parse_answer("n", PromptAnswer::Yes), Some(PromptAnswer::No));
+        assert_eq!(parse_answer("maybe", PromptAnswer::Yes), None);
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use serde_json::{Map, Value};
+use tokio::task::JoinHandle;
+use crate::filter::BoundingBox;
+use crate::geometry::Extent;
+use crate::metadata::{get_service_count, object_id, RestServiceGeometryType, RestServiceMetadata};
+use crate::report::{self, WarningKind};
+
+/// Deepest level of subdivision. Cells at this depth are scraped even when they hold more than
+/// a chunk of features (e.g. many features stacked on the same point).
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum QuadtreeError {
+    NoGeometry(String),
+    MissingExtent(String),
+    MissingCount(String),
+}
+
+impl Display for QuadtreeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuadtreeError::NoGeometry(name) => {
+                write!(f, "Layer \"{}\" has no geometry to split into envelopes", name)
+            }
+            QuadtreeError::MissingExtent(name) => {
+                write!(f, "Layer \"{}\" has no extent with a spatial reference to split", name)
+            }
+            QuadtreeError::MissingCount(bbox) => {
+                write!(f, "Service did not return a feature count for envelope {}", bbox)
+            }
+        }
+    }
+}
+
+impl Error for QuadtreeError {}
+
+type CellCount = Result<(BoundingBox, Option<i64>), Box<dyn Error + Send + Sync>>;
+
+/// The four equal quarters of `bbox`.
+fn quadrants(bbox: &BoundingBox) -> [BoundingBox; 4] {
+    let extent = &bbox.extent;
+    let x_mid = (extent.x_min + extent.x_max) / 2_f64;
+    let y_mid = (extent.y_min + extent.y_max) / 2_f64;
+    [
+        (extent.x_min, extent.y_min, x_mid, y_mid),
+        (x_mid, extent.y_min, extent.x_max, y_mid),
+        (extent.x_min, y_mid, x_mid, extent.y_max),
+        (x_mid, y_mid, extent.x_max, extent.y_max),
+    ].map(|(x_min, y_min, x_max, y_max)| BoundingBox {
+        extent: Extent { x_min, y_min, x_max, y_max },
+        spatial_reference: bbox.spatial_reference,
+    })
+}
+
+/// Envelope the subdivision starts from: the requested bounding box or the layer extent.
+fn root_envelope(layer: &RestServiceMetadata) -> Result<BoundingBox, QuadtreeError> {
+    if layer.geo_type == RestServiceGeometryType::None {
+        return Err(QuadtreeError::NoGeometry(layer.name.to_owned()))
+    }
+    if let Some(bbox) = &layer.filter.bbox {
+        return Ok(bbox.to_owned())
+    }
+    layer.extent.as_ref()
+        .and_then(|extent| Some(BoundingBox {
+            extent: extent.bounds,
+            spatial_reference: extent.spatial_reference.or_else(|| layer.spatial_reference())?,
+        }))
+        .ok_or_else(|| QuadtreeError::MissingExtent(layer.name.to_owned()))
+}
+
+/// Queries for envelopes covering the layer, each holding no more than one chunk of features.
+/// Envelopes are split into quarters until the service reports a small enough count for each.
+pub(crate) async fn quadtree_queries(
+    layer: &RestServiceMetadata,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let chunk_size = layer.scrape_count();
+    let mut pending = vec![root_envelope(layer)?];
+    let mut cells = vec![];
+    let mut depth = 0;
+    while !pending.is_empty() {
+        let handles: Vec<JoinHandle<CellCount>> = pending
+            .into_iter()
+            .map(|cell| {
+                let client = layer.client.clone();
+                let url = layer.url.to_owned();
+                let filter = layer.filter.with_bbox(&cell);
+                tokio::spawn(async move {
+                    let count = get_service_count(&client, &url, &filter).await?;
+                    Ok((cell, count))
+                })
+            })
+            .collect();
+        let mut next = vec![];
+        for handle in handles {
+            let (cell, count) = handle.await??;
+            let count = count.ok_or_else(|| QuadtreeError::MissingCount(cell.extent.to_string()))?;
+            if count == 0 {
+                continue
+            }
+            if count <= chunk_size {
+                cells.push(cell);
+            } else if depth >= MAX_DEPTH {
+                report::warn_about(WarningKind::DroppedFeatures, &layer.name, format_args!(
+                    "Envelope {} still holds {} features at the maximum depth, some may be missed",
+                    cell.extent,
+                    count,
+                ));
+                cells.push(cell);
+            } else {
+                next.extend(quadrants(&cell));
+            }
+        }
+        pending = next;
+        depth += 1;
+    }
+    cells.iter()
+        .map(|cell| layer.envelope_query(cell))
+        .collect()
+}
+
+/// Object ids already written during a scrape. Features crossing an envelope boundary are
+/// returned by every envelope they intersect and must only be written once.
+#[derive(Debug)]
+pub(crate) struct SeenObjectIds {
+    oid_field: String,
+    seen: Mutex<HashSet<i64>>,
+}
+
+impl SeenObjectIds {
+    pub(crate) fn new(oid_field: &str) -> Self {
+        Self {
+            oid_field: oid_field.to_owned(),
+            seen: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// True the first time a feature's object id is seen. Features without an object id are
+    /// always kept.
+    pub(crate) fn first_sighting(&self, attributes: &Map<String, Value>) -> bool {
+        match self.object_id(attributes) {
+            Some(object_id) => self.seen.lock().unwrap().insert(object_id),
+            None => true,
+        }
+    }
+
+    /// Object id of a feature, None when it has none.
+    pub(crate) fn object_id(&self, attributes: &Map<String, Value>) -> Option<i64> {
+        attributes.get(&self.oid_field).and_then(object_id)
+    }
+
+    /// Take features with these object ids as unseen again, e.g. when the response they were
+    /// first seen in is thrown away.
+    pub(crate) fn forget(&self, object_ids: &[i64]) {
+        let mut seen = self.seen.lock().unwrap();
+        for object_id in object_ids {
+            seen.remove(object_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod quadtree_tests {
+    use serde_json::json;
+    use crate::filter::BoundingBox;
+    use crate::geometry::Extent;
+    use super::{quadrants, SeenObjectIds};
+
+    #[test]
+    fn quadrants_should_cover_envelope_when_split() {
+        let bbox = BoundingBox {
+            extent: Extent { x_min: 0.0, y_min: 0.0, x_max: 4.0, y_max: 2.0 },
+            spatial_reference: 3857,
+        };
+        let result = quadrants(&bbox);
+        assert_eq!(result[0].extent, Extent { x_min: 0.0, y_min: 0.0, x_max: 2.0, y_max: 1.0 });
+        assert_eq!(result[3].extent, Extent { x_min: 2.0, y_min: 1.0, x_max: 4.0, y_max: 2.0 });
+        let union = result.iter()
+            .skip(1)
+            .fold(result[0].extent, |extent, cell| extent.union(&cell.extent));
+        assert_eq!(union, bbox.extent);
+    }
+
+    #[test]
+    fn first_sighting_should_return_false_when_object_id_repeats() {
+        let seen = SeenObjectIds::new("OBJECTID");
+        let attributes = json!({"OBJECTID": 7}).as_object().unwrap().to_owned();
+        assert!(seen.first_sighting(&attributes));
+        assert!(!seen.first_sighting(&attributes));
+    }
+}
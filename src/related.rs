@@ -0,0 +1,268 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::path::Path;
+use reqwest::Url;
+use serde_json::Value;
+use crate::checksum::{Artifact, ChecksumFile};
+use crate::client::ServiceClient;
+use crate::filter::QueryFilter;
+use crate::metadata::{
+    object_id, parse_fields, request_object_ids, LayerRelationship, RestServiceField,
+    RestServiceGeometryType, RestServiceMetadata,
+};
+use crate::partition::file_name_part;
+use crate::report::{self, WarningKind};
+use crate::scraping::{convert_json_field, create_output_file, handle_csv_value, output_columns};
+
+/// Column holding the object id of the feature a related record belongs to.
+pub(crate) const ORIGIN_OID_COLUMN: &str = "_ORIGIN_OID";
+/// Origin object ids sent in a single queryRelatedRecords request. Batches whose related records
+/// exceed the transfer limit are halved.
+const ORIGIN_BATCH_SIZE: usize = 100;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum RelatedRecordsError {
+    ErrorJsonResponse(String),
+    MissingKey(String),
+    NoObjectIds(String),
+}
+
+impl Display for RelatedRecordsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RelatedRecordsError::ErrorJsonResponse(error) => {
+                write!(f, "Related records request returned an error: {}", error)
+            }
+            RelatedRecordsError::MissingKey(key) => {
+                write!(f, "Related records response is missing the key \"{}\"", key)
+            }
+            RelatedRecordsError::NoObjectIds(name) => {
+                write!(f, "Layer \"{}\" did not return its object ids", name)
+            }
+        }
+    }
+}
+
+impl Error for RelatedRecordsError {}
+
+/// Records related to the features of a layer through its relationships, each relationship
+/// written to `{layer}_{relationship}.csv` with the object id of the origin feature of every
+/// record.
+#[derive(Debug)]
+pub(crate) struct RelatedRecords {
+    pub(crate) layer_url: String,
+    layer_name: String,
+    relationships: Vec<LayerRelationship>,
+    filter: QueryFilter,
+    client: ServiceClient,
+}
+
+impl RelatedRecords {
+    /// Related records of `layer`, None when the layer has no relationships or no OID field to
+    /// follow them from.
+    pub(crate) fn new(layer: &RestServiceMetadata) -> Option<Self> {
+        if layer.relationships().is_empty() || layer.oid_field_name().is_none() {
+            return None
+        }
+        Some(Self {
+            layer_url: layer.url.to_owned(),
+            layer_name: layer.name.to_owned(),
+            relationships: layer.relationships().to_vec(),
+            filter: layer.filter.to_owned(),
+            client: layer.client.clone(),
+        })
+    }
+
+    /// Write the related records of every relationship into `output_path`. Relationships that
+    /// could not be scraped are warned about and leave no file.
+    pub(crate) async fn scrape(&self, output_path: &Path) -> Vec<Artifact> {
+        let object_ids = match request_object_ids(&self.client, &self.layer_url, &self.filter).await {
+            Ok(Some(object_ids)) => object_ids,
+            Ok(None) => {
+                self.warn(format_args!(
+                    "Related records of {} were not scraped: {}",
+                    self.layer_name,
+                    RelatedRecordsError::NoObjectIds(self.layer_name.to_owned()),
+                ));
+                return vec![]
+            }
+            Err(error) => {
+                self.warn(format_args!("Related records of {} were not scraped: {}", self.layer_name, error));
+                return vec![]
+            }
+        };
+        let mut artifacts = vec![];
+        for relationship in &self.relationships {
+            let path = output_path.join(format!(
+                "{}_{}.csv",
+                self.layer_name,
+                file_name_part(&relationship.name),
+            ));
+            match self.scrape_relationship(relationship, &object_ids, &path).await {
+                Ok(Some((artifact, records))) => {
+                    println!("Wrote {} records related by {} to {}", records, relationship.name, path.display());
+                    artifacts.push(artifact);
+                }
+                Ok(None) => {}
+                Err(error) => self.warn(format_args!(
+                    "Records related to {} by {} were not scraped: {}",
+                    self.layer_name,
+                    relationship.name,
+                    error,
+                )),
+            }
+        }
+        artifacts
+    }
+
+    fn warn(&self, message: std::fmt::Arguments<'_>) {
+        report::warn_about(WarningKind::Skipped, &self.layer_name, message);
+    }
+
+    /// Write the records related to `object_ids` by `relationship` to `path`, returning the file
+    /// and its record count. None when there are no object ids to follow.
+    async fn scrape_relationship(
+        &self,
+        relationship: &LayerRelationship,
+        object_ids: &[i64],
+        path: &Path,
+    ) -> Result<Option<(Artifact, usize)>, Box<dyn Error + Send + Sync>> {
+        let mut output: Option<(ChecksumFile, Vec<RestServiceField>)> = None;
+        let mut records = 0;
+        // Batches still to request, the next one last
+        let mut batches: Vec<&[i64]> = object_ids.chunks(ORIGIN_BATCH_SIZE).rev().collect();
+        while let Some(batch) = batches.pop() {
+            let response = self.request(relationship, batch).await?;
+            let exceeded = response["exceededTransferLimit"].as_bool().unwrap_or(false);
+            if exceeded && batch.len() > 1 {
+                let (first, second) = batch.split_at(batch.len() / 2);
+                batches.push(second);
+                batches.push(first);
+                continue
+            }
+            if exceeded {
+                self.warn(format_args!(
+                    "Feature {} of {} has more records related by {} than the server returns at once, some were not scraped",
+                    batch[0],
+                    self.layer_name,
+                    relationship.name,
+                ));
+            }
+            if output.is_none() {
+                let fields_json = response["fields"]
+                    .as_array()
+                    .ok_or(RelatedRecordsError::MissingKey("fields".to_owned()))?;
+                let fields = parse_fields(fields_json, &RestServiceGeometryType::None)?;
+                let columns: Vec<String> = Some(ORIGIN_OID_COLUMN.to_owned())
+                    .into_iter()
+                    .chain(output_columns(&fields, false, None, None, false))
+                    .collect();
+                output = Some((create_output_file(path, &columns)?, fields));
+            }
+            let (output_file, fields) = output.as_mut().unwrap();
+            for record in related_record_rows(fields, &response)? {
+                writeln!(output_file, "{}", record.iter().map(handle_csv_value).collect::<Vec<String>>().join(","))?;
+                records += 1;
+            }
+        }
+        match output {
+            Some((output_file, _)) => Ok(Some((output_file.finish()?, records))),
+            None => Ok(None),
+        }
+    }
+
+    async fn request(
+        &self,
+        relationship: &LayerRelationship,
+        object_ids: &[i64],
+    ) -> Result<Value, Box<dyn Error + Send + Sync>> {
+        let object_ids = object_ids.iter()
+            .map(|object_id| object_id.to_string())
+            .collect::<Vec<String>>()
+            .join(",");
+        let url = Url::parse_with_params(
+            format!("{}/queryRelatedRecords", self.layer_url).as_str(),
+            [
+                ("objectIds", object_ids),
+                ("relationshipId", relationship.id.to_string()),
+                ("outFields", String::from("*")),
+                ("returnGeometry", String::from("false")),
+                ("f", String::from("json")),
+            ],
+        )?;
+        let response: Value = self.client.get(url)
+            .await?
+            .send()
+            .await?
+            .json()
+            .await?;
+        if let Some(error) = response.get("error") {
+            return Err(RelatedRecordsError::ErrorJsonResponse(error.to_string()).into())
+        }
+        Ok(response)
+    }
+}
+
+/// Rows of the related records in a queryRelatedRecords response, the origin object id followed
+/// by the values of `fields`.
+fn related_record_rows(
+    fields: &[RestServiceField],
+    response: &Value,
+) -> Result<Vec<Vec<String>>, Box<dyn Error + Send + Sync>> {
+    let groups = response["relatedRecordGroups"]
+        .as_array()
+        .ok_or(RelatedRecordsError::MissingKey("relatedRecordGroups".to_owned()))?;
+    let mut rows = vec![];
+    for group in groups {
+        let origin = object_id(&group["objectId"])
+            .ok_or(RelatedRecordsError::MissingKey("relatedRecordGroups[objectId]".to_owned()))?;
+        for record in group["relatedRecords"].as_array().into_iter().flatten() {
+            let mut row = vec![origin.to_string()];
+            for field in fields {
+                row.extend(convert_json_field(field, &record["attributes"][field.name.as_str()])?);
+            }
+            rows.push(row);
+        }
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod related_tests {
+    use serde_json::json;
+    use crate::metadata::{parse_fields, RestServiceGeometryType};
+    use super::related_record_rows;
+
+    #[test]
+    fn related_record_rows_should_key_records_by_origin_oid_when_groups_hold_many_records() {
+        let fields = parse_fields(
+            &[
+                json!({"name": "INSPECTION_ID", "type": "esriFieldTypeOID", "alias": "Inspection Id"}),
+                json!({"name": "RESULT", "type": "esriFieldTypeString", "alias": "Result"}),
+            ],
+            &RestServiceGeometryType::None,
+        ).unwrap();
+        let response = json!({
+            "relatedRecordGroups": [
+                {
+                    "objectId": 2,
+                    "relatedRecords": [
+                        {"attributes": {"INSPECTION_ID": 10, "RESULT": "Pass"}},
+                        {"attributes": {"INSPECTION_ID": 11, "RESULT": null}},
+                    ],
+                },
+                {"objectId": 5, "relatedRecords": [{"attributes": {"INSPECTION_ID": 12, "RESULT": "Fail, retest"}}]},
+            ],
+        });
+        assert_eq!(
+            related_record_rows(&fields, &response).unwrap(),
+            vec![
+                vec!["2".to_owned(), "10".to_owned(), "Pass".to_owned()],
+                vec!["2".to_owned(), "11".to_owned(), "".to_owned()],
+                vec!["5".to_owned(), "12".to_owned(), "Fail, retest".to_owned()],
+            ],
+        );
+        assert!(related_record_rows(&fields, &json!({})).is_err());
+    }
+}
@@ -0,0 +1,231 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Failed requests in a row after which a replica is left out of the rotation.
+const FAILURE_THRESHOLD: u32 = 3;
+/// How long a degraded replica is left out of the rotation before it is tried again.
+const DEGRADED_COOLDOWN: Duration = Duration::from_secs(60);
+/// A replica is degraded once its average response time is this many times the fastest one's.
+const SLOW_FACTOR: f64 = 3.0;
+/// Responses timed before the average response time of a replica is compared to the others.
+const MIN_SAMPLES: u32 = 5;
+/// Weight of the latest response time in the average response time of a replica.
+const LATENCY_WEIGHT: f64 = 0.2;
+
+#[derive(Debug, Default)]
+struct ReplicaHealth {
+    consecutive_failures: u32,
+    /// Moving average of the response time in seconds
+    latency: f64,
+    samples: u32,
+    degraded_until: Option<Instant>,
+}
+
+impl ReplicaHealth {
+    fn is_degraded(&self, now: Instant) -> bool {
+        self.degraded_until.is_some_and(|until| until > now)
+    }
+
+    fn degrade(&mut self, now: Instant) {
+        *self = ReplicaHealth { degraded_until: Some(now + DEGRADED_COOLDOWN), ..ReplicaHealth::default() };
+    }
+}
+
+#[derive(Debug)]
+struct RotationState {
+    next: usize,
+    health: Vec<ReplicaHealth>,
+}
+
+/// Base url of a replica standing in for the `prefix` of the service's urls.
+#[derive(Debug)]
+struct Replica {
+    prefix: String,
+    base: String,
+}
+
+/// Mirrors of a service serving the same layers under another base url. Queries are sent to the
+/// service and its mirrors in turn, skipping a replica for a while once its requests keep failing
+/// or it answers much slower than the others.
+#[derive(Debug)]
+pub(crate) struct ReplicaSet {
+    /// The service itself, with an empty prefix and base, followed by its mirrors
+    replicas: Vec<Replica>,
+    state: Mutex<RotationState>,
+}
+
+impl ReplicaSet {
+    /// Replicas of the layer at `url`. Each mirror is a `rest/services` url standing in for the
+    /// one of `url` (any path after it is ignored) or a bare origin serving the same paths. None
+    /// without mirrors.
+    pub(crate) fn new(url: &str, mirrors: &[String]) -> Option<Self> {
+        let mut replicas = vec![Replica { prefix: String::new(), base: String::new() }];
+        for mirror in mirrors {
+            let prefix_and_base = match services_root(mirror) {
+                Some(mirror_root) => services_root(url).map(|root| (root, mirror_root)),
+                None => origin(url).map(|origin| (origin, mirror.trim_end_matches('/'))),
+            };
+            if let Some((prefix, base)) = prefix_and_base {
+                replicas.push(Replica { prefix: prefix.to_owned(), base: base.to_owned() });
+            }
+        }
+        if replicas.len() < 2 {
+            return None
+        }
+        let health = replicas.iter().map(|_| ReplicaHealth::default()).collect();
+        Some(Self { replicas, state: Mutex::new(RotationState { next: 0, health }) })
+    }
+
+    /// Index of the replica the next request goes to, the next one in turn that is not degraded
+    /// or the one back soonest when all are.
+    pub(crate) fn choose(&self, now: Instant) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let count = self.replicas.len();
+        let start = state.next;
+        let index = (0..count)
+            .map(|offset| (start + offset) % count)
+            .find(|&index| !state.health[index].is_degraded(now))
+            .unwrap_or_else(|| {
+                (0..count).min_by_key(|&index| state.health[index].degraded_until).unwrap_or(0)
+            });
+        state.next = (index + 1) % count;
+        index
+    }
+
+    /// `query` sent to the replica at `index` instead of the service.
+    pub(crate) fn route(&self, query: &str, index: usize) -> String {
+        let replica = &self.replicas[index];
+        match query.strip_prefix(replica.prefix.as_str()) {
+            Some(rest) => format!("{}{}", replica.base, rest),
+            None => query.to_owned(),
+        }
+    }
+
+    /// Base url of the mirror at `index`, None for the service itself.
+    pub(crate) fn mirror(&self, index: usize) -> Option<&str> {
+        Some(self.replicas[index].base.as_str()).filter(|base| !base.is_empty())
+    }
+
+    /// Time a successful request of the replica at `index`, returning true when the replica was
+    /// degraded for answering much slower than the fastest one.
+    pub(crate) fn record_success(&self, index: usize, elapsed: Duration, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let health = &mut state.health[index];
+        health.consecutive_failures = 0;
+        health.latency = match health.samples {
+            0 => elapsed.as_secs_f64(),
+            _ => health.latency * (1.0 - LATENCY_WEIGHT) + elapsed.as_secs_f64() * LATENCY_WEIGHT,
+        };
+        health.samples += 1;
+        if health.samples < MIN_SAMPLES {
+            return false
+        }
+        let latency = health.latency;
+        let fastest = state.health.iter()
+            .enumerate()
+            .filter(|(other, health)| *other != index && health.samples >= MIN_SAMPLES && !health.is_degraded(now))
+            .map(|(_, health)| health.latency)
+            .min_by(f64::total_cmp);
+        match fastest {
+            Some(fastest) if latency > fastest * SLOW_FACTOR => {
+                state.health[index].degrade(now);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Count a failed request of the replica at `index`, returning true when the replica was
+    /// degraded because its requests keep failing. The last healthy replica is never degraded, and
+    /// failures of a degraded replica are ignored.
+    pub(crate) fn record_failure(&self, index: usize, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        // Requests sent before the replica was degraded are still failing
+        if state.health[index].is_degraded(now) {
+            return false
+        }
+        state.health[index].consecutive_failures += 1;
+        let others_healthy = state.health.iter()
+            .enumerate()
+            .any(|(other, health)| other != index && !health.is_degraded(now));
+        if state.health[index].consecutive_failures >= FAILURE_THRESHOLD && others_healthy {
+            state.health[index].degrade(now);
+            return true
+        }
+        false
+    }
+}
+
+/// Part of `url` up to `/rest/services`, None when it is not a services directory url.
+fn services_root(url: &str) -> Option<&str> {
+    url.find("/rest/services").map(|index| &url[..index + "/rest/services".len()])
+}
+
+/// Scheme, host and port of `url`.
+fn origin(url: &str) -> Option<&str> {
+    let start = url.find("://")? + "://".len();
+    let end = url[start..].find('/').map_or(url.len(), |offset| start + offset);
+    Some(&url[..end])
+}
+
+#[cfg(test)]
+mod replica_tests {
+    use std::time::{Duration, Instant};
+    use super::ReplicaSet;
+
+    const LAYER_URL: &str = "https://gis.example.com/arcgis/rest/services/Parcels/FeatureServer/0";
+
+    fn replicas() -> ReplicaSet {
+        ReplicaSet::new(
+            LAYER_URL,
+            &[
+                "https://mirror1.example.com/server/rest/services/Parcels/FeatureServer/0".to_owned(),
+                "https://mirror2.example.com/".to_owned(),
+            ],
+        ).unwrap()
+    }
+
+    #[test]
+    fn route_should_swap_base_url_when_mirror_is_a_services_url_or_an_origin() {
+        let replicas = replicas();
+        let query = format!("{}/query?where=1%3D1&f=json", LAYER_URL);
+        assert_eq!(replicas.route(&query, 0), query);
+        assert_eq!(
+            replicas.route(&query, 1),
+            "https://mirror1.example.com/server/rest/services/Parcels/FeatureServer/0/query?where=1%3D1&f=json",
+        );
+        assert_eq!(
+            replicas.route(&query, 2),
+            "https://mirror2.example.com/arcgis/rest/services/Parcels/FeatureServer/0/query?where=1%3D1&f=json",
+        );
+        assert!(ReplicaSet::new(LAYER_URL, &[]).is_none());
+    }
+
+    #[test]
+    fn choose_should_skip_replica_when_it_keeps_failing_until_cooldown_ends() {
+        let replicas = replicas();
+        let now = Instant::now();
+        assert_eq!((0..4).map(|_| replicas.choose(now)).collect::<Vec<usize>>(), vec![0, 1, 2, 0]);
+        assert!(!replicas.record_failure(1, now));
+        assert!(!replicas.record_failure(1, now));
+        assert!(replicas.record_failure(1, now));
+        assert_eq!((0..4).map(|_| replicas.choose(now)).collect::<Vec<usize>>(), vec![2, 0, 2, 0]);
+        let later = now + Duration::from_secs(61);
+        assert_eq!((0..3).map(|_| replicas.choose(later)).collect::<Vec<usize>>(), vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn record_success_should_degrade_replica_when_much_slower_than_the_others() {
+        let replicas = replicas();
+        let now = Instant::now();
+        for _ in 0..5 {
+            assert!(!replicas.record_success(0, Duration::from_millis(200), now));
+            assert!(!replicas.record_success(1, Duration::from_millis(250), now));
+        }
+        for _ in 0..4 {
+            assert!(!replicas.record_success(2, Duration::from_secs(3), now));
+        }
+        assert!(replicas.record_success(2, Duration::from_secs(3), now));
+        assert_eq!((0..3).map(|_| replicas.choose(now)).collect::<Vec<usize>>(), vec![0, 1, 0]);
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{Arguments, Display, Formatter};
+use std::io;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use console::style;
+use serde::Serialize;
+use crate::audit::RunId;
+use crate::checkpoint::LayerCheckpoint;
+use crate::checksum::Artifact;
+use crate::deadline::DeadlineError;
+use crate::meter::{self, RequestLimitError, RequestUsage};
+
+/// Every warning printed during the run, for the summary, manifest and run report.
+static WARNINGS: Mutex<Vec<Warning>> = Mutex::new(Vec::new());
+
+/// What a warning is about, so anomalies in the data can be told apart from notices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum WarningKind {
+    /// A strategy returned a different number of features than the layer's count
+    CountMismatch,
+    /// Features (or parts of them) that may be missing from the output
+    DroppedFeatures,
+    /// Values replaced or kept despite being invalid
+    CoercedValues,
+    /// Fields, layers or outputs left out of the scrape
+    Skipped,
+    General,
+}
+
+impl Display for WarningKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WarningKind::CountMismatch => write!(f, "count mismatch"),
+            WarningKind::DroppedFeatures => write!(f, "dropped features"),
+            WarningKind::CoercedValues => write!(f, "coerced values"),
+            WarningKind::Skipped => write!(f, "skipped"),
+            WarningKind::General => write!(f, "general"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct Warning {
+    pub(crate) kind: WarningKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) layer: Option<String>,
+    pub(crate) message: String,
+}
+
+/// Print a warning and keep it for the end of the run.
+pub(crate) fn warn(message: Arguments) {
+    record(WarningKind::General, None, message);
+}
+
+/// Print a warning about the data of a layer and keep it for the end of the run.
+pub(crate) fn warn_about(kind: WarningKind, layer: &str, message: Arguments) {
+    record(kind, Some(layer), message);
+}
+
+/// Print a notice about the tool rather than the data, e.g. a newer release being available.
+/// Notices are not kept, so they never count towards `--warnings-as-errors`.
+pub(crate) fn notice(message: Arguments) {
+    println!("{} {}", style("Notice:").cyan().bold(), message);
+}
+
+fn record(kind: WarningKind, layer: Option<&str>, message: Arguments) {
+    let message = message.to_string();
+    println!("{} {}", style("Warning:").yellow().bold(), message);
+    WARNINGS.lock().unwrap().push(Warning { kind, layer: layer.map(ToOwned::to_owned), message });
+}
+
+/// Warnings recorded so far.
+pub(crate) fn warnings() -> Vec<Warning> {
+    WARNINGS.lock().unwrap().clone()
+}
+
+/// One line tally of the warnings by kind, None without warnings.
+pub(crate) fn summarize(warnings: &[Warning]) -> Option<String> {
+    if warnings.is_empty() {
+        return None
+    }
+    let mut counts = BTreeMap::new();
+    for warning in warnings {
+        *counts.entry(warning.kind).or_insert(0) += 1;
+    }
+    let counts: Vec<String> = counts.iter().map(|(kind, count)| format!("{} {}", count, kind)).collect();
+    Some(format!("{} warnings ({})", warnings.len(), counts.join(", ")))
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum RunReportError {
+    StdoutTaken,
+    /// --warnings-as-errors and the run had warnings
+    Warnings(usize),
+}
+
+impl Display for RunReportError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunReportError::StdoutTaken => {
+                write!(f, "--json cannot be combined with --archive -, both write to standard output")
+            }
+            RunReportError::Warnings(count) => {
+                write!(f, "The run had {} warnings and --warnings-as-errors is set", count)
+            }
+        }
+    }
+}
+
+impl Error for RunReportError {}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RunStatus {
+    Success,
+    /// Stopped by --max-duration or --max-requests, see the checkpoint
+    Stopped,
+    Failed,
+}
+
+/// Machine readable summary of a run, written to standard output with --json.
+#[derive(Debug, Serialize)]
+pub(crate) struct RunReport {
+    run_id: String,
+    status: RunStatus,
+    started_at: DateTime<Utc>,
+    duration_seconds: f64,
+    features: usize,
+    #[serde(flatten)]
+    requests: RequestUsage,
+    pub(crate) layers: Vec<LayerCheckpoint>,
+    pub(crate) outputs: Vec<Artifact>,
+    warnings: Vec<Warning>,
+    error: Option<String>,
+}
+
+impl RunReport {
+    pub(crate) fn new(run_id: &RunId, started_at: DateTime<Utc>) -> Self {
+        Self {
+            run_id: run_id.to_string(),
+            status: RunStatus::Success,
+            started_at,
+            duration_seconds: 0_f64,
+            features: 0,
+            requests: RequestUsage { requests: 0, estimated_cost: None },
+            layers: vec![],
+            outputs: vec![],
+            warnings: vec![],
+            error: None,
+        }
+    }
+
+    /// Complete the report with the outcome of the run and the warnings printed so far.
+    pub(crate) fn finish(&mut self, duration: Duration, error: Option<&(dyn Error + Send + Sync + 'static)>) {
+        self.duration_seconds = duration.as_secs_f64();
+        self.features = self.layers.iter().map(|layer| layer.features).sum();
+        self.requests = meter::usage();
+        self.warnings = warnings();
+        self.status = match error {
+            None => RunStatus::Success,
+            Some(error) if error.is::<DeadlineError>() || error.is::<RequestLimitError>() => RunStatus::Stopped,
+            Some(_) => RunStatus::Failed,
+        };
+        self.error = error.map(ToString::to_string);
+    }
+
+    pub(crate) fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writeln!(writer)?;
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod report_tests {
+    use std::time::Duration;
+    use chrono::Utc;
+    use crate::audit::RunId;
+    use crate::checkpoint::{LayerCheckpoint, LayerStatus};
+    use crate::deadline::DeadlineError;
+    use crate::meter::RequestLimitError;
+    use super::{summarize, RunReport, RunStatus, Warning, WarningKind};
+
+    #[test]
+    fn finish_should_mark_stopped_when_deadline_or_request_limit_reached() {
+        let mut report = RunReport::new(&RunId::generate(), Utc::now());
+        report.layers.push(LayerCheckpoint {
+            url: "https://example.com/0".to_owned(),
+            name: "Parcels".to_owned(),
+            status: LayerStatus::Partial,
+            features: 1500,
+        });
+        let error = DeadlineError::Reached(Duration::from_secs(60));
+        report.finish(Duration::from_secs(61), Some(&error));
+        assert_eq!(report.status, RunStatus::Stopped);
+        assert_eq!(report.features, 1500);
+        assert_eq!(report.error.as_deref(), Some(error.to_string().as_str()));
+        report.finish(Duration::from_secs(61), Some(&RequestLimitError::Reached(100)));
+        assert_eq!(report.status, RunStatus::Stopped);
+    }
+
+    #[test]
+    fn summarize_should_count_by_kind_when_warnings_recorded() {
+        let warning = |kind| Warning { kind, layer: Some("Parcels".to_owned()), message: String::new() };
+        let warnings = [
+            warning(WarningKind::Skipped),
+            warning(WarningKind::CountMismatch),
+            warning(WarningKind::Skipped),
+        ];
+        assert_eq!(summarize(&warnings).unwrap(), "3 warnings (1 count mismatch, 2 skipped)");
+        assert_eq!(summarize(&[]), None);
+    }
+}
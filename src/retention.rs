@@ -0,0 +1,399 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::{create_dir_all, read_dir, remove_dir, remove_dir_all, remove_file, File};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use indicatif::HumanBytes;
+use serde::{Deserialize, Serialize};
+use crate::audit::RunId;
+use crate::checksum::Artifact;
+use crate::state::state_directory;
+use crate::{lock, throttle};
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum RetentionError {
+    InvalidSize(String),
+}
+
+impl Display for RetentionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RetentionError::InvalidSize(value) => write!(
+                f,
+                "Could not parse the size \"{}\". Expected a number of B, KB, MB, GB, KiB, MiB or GiB (e.g. 20GB)",
+                value,
+            ),
+        }
+    }
+}
+
+impl Error for RetentionError {}
+
+/// Parse a human readable size (e.g. "20GB", "512MiB") into bytes, with the units of
+/// [`throttle::parse_bandwidth`].
+pub(crate) fn parse_size(value: &str) -> Result<u64, RetentionError> {
+    if value.contains('/') {
+        return Err(RetentionError::InvalidSize(value.to_owned()))
+    }
+    throttle::parse_bandwidth(value).map_err(|_| RetentionError::InvalidSize(value.to_owned()))
+}
+
+/// How much of what past runs left behind is kept: their leftovers in the state directory and
+/// the outputs recorded by [`RunOutputs`].
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RetentionPolicy {
+    /// Most recent runs whose leftovers and outputs are kept
+    pub(crate) keep_runs: Option<usize>,
+    /// Bytes the leftovers of the kept runs may take up, older runs being removed first. Outputs
+    /// do not count and are left alone
+    pub(crate) max_cache_size: Option<u64>,
+}
+
+impl RetentionPolicy {
+    pub(crate) fn is_set(&self) -> bool {
+        self.keep_runs.is_some() || self.max_cache_size.is_some()
+    }
+}
+
+/// File written by a run, removed by retention only while it is still the file the run wrote.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct RecordedOutput {
+    path: PathBuf,
+    sha256: String,
+}
+
+/// Output directory, output files and archive of a run, recorded in `outputs/{run id}.json` of
+/// the state directory so they can be removed once the run is beyond --keep-runs.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RunOutputs {
+    /// Removed once nothing but the run's manifest is left in it
+    directory: Option<PathBuf>,
+    files: Vec<RecordedOutput>,
+}
+
+impl RunOutputs {
+    /// Outputs of a run writing into `directory`, None when it is the temporary directory of an
+    /// archive.
+    pub(crate) fn new(directory: Option<&Path>) -> Self {
+        Self { directory: directory.map(Path::to_owned), files: vec![] }
+    }
+
+    /// Record `artifact`, its path relative to `directory` unless absolute.
+    pub(crate) fn push(&mut self, directory: &Path, artifact: &Artifact) {
+        self.files.push(RecordedOutput {
+            path: directory.join(&artifact.path),
+            sha256: artifact.sha256.to_owned(),
+        });
+    }
+
+    fn path(directory: &Path, run_id: &str) -> PathBuf {
+        directory.join("outputs").join(format!("{}.json", run_id))
+    }
+
+    pub(crate) fn write(&self, run_id: &RunId) -> io::Result<PathBuf> {
+        let path = Self::path(&state_directory()?, &run_id.to_string());
+        if let Some(directory) = path.parent() {
+            create_dir_all(directory)?;
+        }
+        let mut file = File::create(&path)?;
+        serde_json::to_writer(&mut file, self)?;
+        writeln!(file)?;
+        file.sync_all()?;
+        Ok(path)
+    }
+
+    /// Remove the recorded outputs, leaving those in `kept` and files changed since the run
+    /// (e.g. overwritten by a later run). Returns the bytes freed.
+    fn remove(&self, run_id: &str, kept: &HashSet<PathBuf>) -> io::Result<u64> {
+        let mut freed = 0;
+        for file in self.files.iter().filter(|file| !kept.contains(&file.path)) {
+            let Some(current) = Artifact::from_sidecar(&file.path) else { continue };
+            if current.sha256 != file.sha256 {
+                continue
+            }
+            let mut sidecar = file.path.as_os_str().to_owned();
+            sidecar.push(".sha256");
+            for path in [file.path.to_owned(), PathBuf::from(sidecar)] {
+                match remove_file(path) {
+                    Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                    _ => {}
+                }
+            }
+            freed += current.size;
+        }
+        let Some(directory) = self.directory.as_ref().filter(|directory| !kept.contains(*directory)) else {
+            return Ok(freed)
+        };
+        // Only a directory left empty is removed, whatever else was written there stays
+        let manifest = directory.join("manifest.json");
+        let mut remaining = entries(directory)?;
+        if remaining == [manifest.to_owned()] && manifest_run_id(&manifest).as_deref() == Some(run_id) {
+            remove_file(&manifest)?;
+            remaining.clear();
+        }
+        if remaining.is_empty() && directory.is_dir() {
+            remove_dir(directory)?;
+        }
+        Ok(freed)
+    }
+}
+
+/// Run id of the manifest at `path`, None when it is missing or unreadable.
+fn manifest_run_id(path: &Path) -> Option<String> {
+    let contents = std::fs::read(path).ok()?;
+    let manifest: serde_json::Value = serde_json::from_slice(&contents).ok()?;
+    manifest["run_id"].as_str().map(str::to_owned)
+}
+
+/// Spooled chunks (`spool/{run id}`) and checkpoint (`checkpoints/{run id}.json`) left by a
+/// run in the state directory, kept so the run can be resumed, along with the record of its
+/// outputs.
+#[derive(Debug)]
+struct RunLeftovers {
+    run_id: String,
+    paths: Vec<PathBuf>,
+    /// Size of `paths`, the outputs are not cached data
+    size: u64,
+    outputs: Option<PathBuf>,
+    modified: SystemTime,
+}
+
+/// Runs whose leftovers or outputs were removed.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct PruneSummary {
+    pub(crate) runs: usize,
+    pub(crate) outputs: usize,
+    pub(crate) bytes: u64,
+}
+
+impl Display for PruneSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Removed the spooled chunks and checkpoints of {} past runs and the outputs of {}, freeing {}",
+            self.runs,
+            self.outputs,
+            HumanBytes(self.bytes),
+        )
+    }
+}
+
+fn entries(directory: &Path) -> io::Result<Vec<PathBuf>> {
+    match read_dir(directory) {
+        Ok(entries) => entries.map(|entry| entry.map(|entry| entry.path())).collect(),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(error) => Err(error),
+    }
+}
+
+/// Size and latest modification time of the files under `path`, an empty directory's own
+/// modification time.
+fn measure(path: &Path) -> io::Result<(u64, SystemTime)> {
+    let metadata = path.metadata()?;
+    let entries = if metadata.is_dir() { entries(path)? } else { vec![] };
+    if !metadata.is_dir() {
+        return Ok((metadata.len(), metadata.modified()?))
+    }
+    if entries.is_empty() {
+        return Ok((0, metadata.modified()?))
+    }
+    let mut size = 0;
+    let mut modified = SystemTime::UNIX_EPOCH;
+    for entry in entries {
+        let (entry_size, entry_modified) = measure(&entry)?;
+        size += entry_size;
+        modified = modified.max(entry_modified);
+    }
+    Ok((size, modified))
+}
+
+/// Leftovers of every run in the state directory `directory`, the most recently modified first.
+fn run_leftovers(directory: &Path) -> io::Result<Vec<RunLeftovers>> {
+    let name = |path: &Path, stem: bool| {
+        let name = if stem { path.file_stem() } else { path.file_name() };
+        name.map(|name| name.to_string_lossy().into_owned())
+    };
+    let spooled = entries(&directory.join("spool"))?
+        .into_iter()
+        .filter(|path| path.is_dir())
+        .filter_map(|path| Some((name(&path, false)?, path)));
+    let checkpoints = entries(&directory.join("checkpoints"))?
+        .into_iter()
+        .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+        .filter_map(|path| Some((name(&path, true)?, path)));
+    let outputs = entries(&directory.join("outputs"))?
+        .into_iter()
+        .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+        .filter_map(|path| Some((name(&path, true)?, path)));
+    let mut runs = BTreeMap::new();
+    let tagged = spooled.chain(checkpoints)
+        .map(|(run_id, path)| (run_id, path, false))
+        .chain(outputs.map(|(run_id, path)| (run_id, path, true)));
+    for (run_id, path, is_outputs) in tagged {
+        let (size, modified) = measure(&path)?;
+        let run = runs.entry(run_id.to_owned()).or_insert_with(|| RunLeftovers {
+            run_id,
+            paths: vec![],
+            size: 0,
+            outputs: None,
+            modified: SystemTime::UNIX_EPOCH,
+        });
+        if is_outputs {
+            run.outputs = Some(path);
+        } else {
+            run.paths.push(path);
+            run.size += size;
+        }
+        run.modified = run.modified.max(modified);
+    }
+    let mut runs: Vec<RunLeftovers> = runs.into_values().collect();
+    runs.sort_by_key(|run| Reverse(run.modified));
+    Ok(runs)
+}
+
+/// Remove the leftovers and outputs of the runs in `directory` the policy does not keep. Once a
+/// run does not fit, every older run is removed: runs beyond --keep-runs lose both, runs only
+/// beyond --max-cache-size their leftovers. Runs in `protected` are always kept and count toward
+/// the policy. Outputs written again by a kept run are never removed.
+fn prune_directory(directory: &Path, policy: &RetentionPolicy, protected: &[String]) -> io::Result<PruneSummary> {
+    let mut summary = PruneSummary::default();
+    let mut kept_runs = 0;
+    let mut kept_size = 0;
+    let mut kept_outputs = HashSet::new();
+    let mut beyond_count = false;
+    let mut beyond_size = false;
+    for run in run_leftovers(directory)? {
+        beyond_count = beyond_count || policy.keep_runs.is_some_and(|keep| kept_runs >= keep);
+        beyond_size = beyond_size || policy.max_cache_size.is_some_and(|max| kept_size + run.size > max);
+        let is_protected = protected.contains(&run.run_id);
+        let outputs = match &run.outputs {
+            Some(path) => std::fs::read(path)
+                .ok()
+                .and_then(|contents| serde_json::from_slice::<RunOutputs>(&contents).ok()),
+            None => None,
+        };
+        if !beyond_count || is_protected {
+            kept_runs += 1;
+            if let Some(outputs) = &outputs {
+                kept_outputs.extend(outputs.files.iter().map(|file| file.path.to_owned()));
+                kept_outputs.extend(outputs.directory.iter().cloned());
+            }
+        } else if let Some(path) = &run.outputs {
+            if let Some(outputs) = &outputs {
+                summary.bytes += outputs.remove(&run.run_id, &kept_outputs)?;
+            }
+            remove_file(path)?;
+            summary.outputs += 1;
+        }
+        if !(beyond_count || beyond_size) || is_protected {
+            kept_size += run.size;
+            continue
+        }
+        if run.paths.is_empty() {
+            continue
+        }
+        for path in &run.paths {
+            let removed = if path.is_dir() { remove_dir_all(path) } else { remove_file(path) };
+            match removed {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                _ => {}
+            }
+        }
+        summary.runs += 1;
+        summary.bytes += run.size;
+    }
+    Ok(summary)
+}
+
+/// Apply the policy to the leftovers and outputs of past runs, keeping those of `current` and of
+/// every run still holding a lock.
+pub(crate) fn prune(policy: &RetentionPolicy, current: Option<&RunId>) -> Result<PruneSummary, Box<dyn Error + Send + Sync>> {
+    let protected: Vec<String> = lock::active_runs()?
+        .iter()
+        .chain(current)
+        .map(RunId::to_string)
+        .collect();
+    Ok(prune_directory(&state_directory()?, policy, &protected)?)
+}
+
+#[cfg(test)]
+mod retention_tests {
+    use std::fs::{create_dir_all, write, File};
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+    use crate::checksum::ChecksumFile;
+    use super::{parse_size, prune_directory, PruneSummary, RetentionPolicy, RunOutputs};
+
+    fn leave_file(path: &Path, size: usize, age_hours: u64) {
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write(path, vec![b'1'; size]).unwrap();
+        let modified = SystemTime::now() - Duration::from_secs(age_hours * 3600);
+        File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn prune_directory_should_remove_oldest_runs_when_beyond_count_or_size() {
+        let directory = tempfile::tempdir().unwrap();
+        let spool = directory.path().join("spool");
+        leave_file(&spool.join("newest").join("Parcels").join("chunk_000000.csv"), 100, 1);
+        leave_file(&spool.join("middle").join("Parcels").join("chunk_000000.csv"), 100, 2);
+        leave_file(&spool.join("oldest").join("Parcels").join("chunk_000000.csv"), 100, 3);
+        leave_file(&directory.path().join("checkpoints").join("oldest.json"), 2, 3);
+
+        let policy = RetentionPolicy { keep_runs: Some(1), max_cache_size: None };
+        let summary = prune_directory(directory.path(), &policy, &["middle".to_owned()]).unwrap();
+        assert_eq!(summary, PruneSummary { runs: 1, outputs: 0, bytes: 102 });
+        assert!(!spool.join("oldest").exists());
+        assert!(!directory.path().join("checkpoints").join("oldest.json").exists());
+
+        let policy = RetentionPolicy { keep_runs: None, max_cache_size: Some(parse_size("150B").unwrap()) };
+        let summary = prune_directory(directory.path(), &policy, &[]).unwrap();
+        assert_eq!(summary, PruneSummary { runs: 1, outputs: 0, bytes: 100 });
+        assert!(spool.join("newest").exists());
+        assert!(parse_size("20GB/s").is_err());
+    }
+
+    fn record_outputs(state: &Path, run_id: &str, outputs: &Path, files: &[(&str, &str)]) {
+        let mut record = RunOutputs::new(Some(outputs));
+        for (name, contents) in files {
+            let mut file = ChecksumFile::create(&outputs.join(name)).unwrap();
+            std::io::Write::write_all(&mut file, contents.as_bytes()).unwrap();
+            record.push(outputs, &file.finish().unwrap());
+        }
+        let path = RunOutputs::path(state, run_id);
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write(&path, serde_json::to_vec(&record).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn prune_directory_should_keep_outputs_when_written_again_by_kept_run() {
+        let state = tempfile::tempdir().unwrap();
+        let outputs = tempfile::tempdir().unwrap();
+        let older = outputs.path().join("older");
+        create_dir_all(&older).unwrap();
+        record_outputs(state.path(), "oldest", &older, &[("Roads.csv", "abc")]);
+        write(older.join("manifest.json"), r#"{"run_id": "oldest"}"#).unwrap();
+        let shared = outputs.path().join("output_files");
+        create_dir_all(&shared).unwrap();
+        record_outputs(state.path(), "middle", &shared, &[("Parcels.csv", "abc"), ("Zoning.csv", "abc")]);
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        record_outputs(state.path(), "newest", &shared, &[("Parcels.csv", "abcd")]);
+
+        let policy = RetentionPolicy { keep_runs: Some(1), max_cache_size: None };
+        let summary = prune_directory(state.path(), &policy, &[]).unwrap();
+        assert_eq!(summary, PruneSummary { runs: 0, outputs: 2, bytes: 6 });
+        assert!(!older.exists());
+        assert!(!shared.join("Zoning.csv").exists());
+        assert_eq!(std::fs::read_to_string(shared.join("Parcels.csv")).unwrap(), "abcd");
+        assert!(shared.join("Parcels.csv.sha256").exists());
+        assert_eq!(std::fs::read_dir(state.path().join("outputs")).unwrap().count(), 1);
+
+        let policy = RetentionPolicy { keep_runs: None, max_cache_size: Some(0) };
+        assert_eq!(prune_directory(state.path(), &policy, &[]).unwrap(), PruneSummary::default());
+        assert!(shared.join("Parcels.csv").exists());
+    }
+}
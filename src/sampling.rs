@@ -0,0 +1,119 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use clap::ValueEnum;
+use rand::seq::index::sample;
+use crate::metadata::{request_object_ids, RestServiceMetadata};
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum SamplingError {
+    NoObjectIds,
+}
+
+impl Display for SamplingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SamplingError::NoObjectIds => {
+                write!(f, "Random sampling requires a service that returns object ids")
+            }
+        }
+    }
+}
+
+impl Error for SamplingError {}
+
+/// How the features of a sample are picked from the layer.
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+pub(crate) enum SampleMethod {
+    /// The first features of the layer
+    First,
+    /// Randomly chosen object ids spread over the whole layer
+    Random,
+    /// Every kth chunk of the full scrape plan
+    Stride,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum SampleSize {
+    Count(i64),
+    Percent(f64),
+}
+
+impl SampleSize {
+    /// Number of features to sample from a layer of `feature_count` features. Never more than the
+    /// layer holds and at least one feature for a non-empty layer.
+    pub(crate) fn features(&self, feature_count: i64) -> i64 {
+        let requested = match self {
+            SampleSize::Count(count) => *count,
+            SampleSize::Percent(percent) => (feature_count as f64 * percent / 100_f64).ceil() as i64,
+        };
+        requested.clamp(feature_count.min(1), feature_count)
+    }
+}
+
+/// Plan the queries that fetch a sample of the layer instead of every feature.
+pub(crate) async fn sample_queries(
+    layer: &RestServiceMetadata,
+    size: &SampleSize,
+    method: &SampleMethod,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let sample_count = size.features(layer.feature_count()?);
+    let chunk_size = layer.scrape_count();
+    let mut queries = vec![];
+    match method {
+        SampleMethod::First => {
+            let mut remaining = sample_count;
+            let mut query_index = 0;
+            while remaining > 0 {
+                queries.push(layer.chunk_query(query_index, remaining.min(chunk_size))?);
+                remaining -= chunk_size;
+                query_index += 1;
+            }
+        }
+        SampleMethod::Stride => {
+            let total_chunks = (layer.feature_count()? + chunk_size - 1) / chunk_size;
+            let sample_chunks = (sample_count + chunk_size - 1) / chunk_size;
+            let mut remaining = sample_count;
+            for sample_index in 0..sample_chunks {
+                let query_index = sample_index * total_chunks / sample_chunks;
+                queries.push(layer.chunk_query(query_index, remaining.min(chunk_size))?);
+                remaining -= chunk_size;
+            }
+        }
+        SampleMethod::Random => {
+            let object_ids = request_object_ids(&layer.client, &layer.url, &layer.filter)
+                .await?
+                .ok_or(SamplingError::NoObjectIds)?;
+            let amount = (sample_count as usize).min(object_ids.len());
+            let mut chosen: Vec<i64> = sample(&mut rand::thread_rng(), object_ids.len(), amount)
+                .into_iter()
+                .map(|i| object_ids[i])
+                .collect();
+            chosen.sort_unstable();
+            for batch in chosen.chunks(chunk_size as usize) {
+                queries.push(layer.object_ids_query(batch)?);
+            }
+        }
+    }
+    Ok(queries)
+}
+
+#[cfg(test)]
+mod sample_size_tests {
+    use super::SampleSize;
+
+    #[test]
+    fn features_should_round_up_when_passed_percent() {
+        assert_eq!(SampleSize::Percent(1.5).features(1000), 15);
+        assert_eq!(SampleSize::Percent(0.01).features(1000), 1);
+    }
+
+    #[test]
+    fn features_should_cap_at_layer_count_when_count_exceeds_layer() {
+        assert_eq!(SampleSize::Count(500).features(120), 120);
+    }
+
+    #[test]
+    fn features_should_return_zero_when_layer_is_empty() {
+        assert_eq!(SampleSize::Count(500).features(0), 0);
+    }
+}
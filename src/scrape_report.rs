@@ -0,0 +1,345 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use crate::audit::RunId;
+use crate::checkpoint::{LayerCheckpoint, LayerStatus};
+use crate::checksum::{Artifact, ChecksumFile};
+use crate::meter::{self, RequestUsage};
+use crate::progress::{ChunkProgress, LayerRef, LayerSummary, ProgressReporter};
+
+/// A chunk query of a layer, from the moment a fetch worker started it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct QueryRecord {
+    /// Strategy attempt of the layer the query was planned by, from 1
+    attempt: usize,
+    chunk_id: usize,
+    started_at: Option<DateTime<Utc>>,
+    duration_seconds: Option<f64>,
+    features: Option<usize>,
+    bytes: Option<usize>,
+    retries: u32,
+    #[serde(skip)]
+    started: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct LayerRecord {
+    name: String,
+    /// Features the count query of the layer reported
+    expected_features: u64,
+    /// Chunk queries planned by the latest attempt
+    chunk_count: usize,
+    attempts: usize,
+    strategy: Option<String>,
+    features_written: Option<usize>,
+    queries: Vec<QueryRecord>,
+}
+
+impl LayerRecord {
+    fn query(&mut self, chunk_id: usize) -> &mut QueryRecord {
+        let attempt = self.attempts;
+        let index = match self.queries.iter().position(|query| query.attempt == attempt && query.chunk_id == chunk_id) {
+            Some(index) => index,
+            None => {
+                self.queries.push(QueryRecord {
+                    attempt,
+                    chunk_id,
+                    started_at: None,
+                    duration_seconds: None,
+                    features: None,
+                    bytes: None,
+                    retries: 0,
+                    started: None,
+                });
+                self.queries.len() - 1
+            }
+        };
+        &mut self.queries[index]
+    }
+
+    fn features_fetched(&self) -> usize {
+        self.queries.iter().filter_map(|query| query.features).sum()
+    }
+
+    /// How far a layer got that the run ended without recording in its checkpoint.
+    fn status(&self) -> LayerStatus {
+        let complete = self.features_written
+            .is_some_and(|features| u64::try_from(features).is_ok_and(|features| features >= self.expected_features));
+        if complete {
+            LayerStatus::Complete
+        } else if self.features_fetched() > 0 {
+            LayerStatus::Partial
+        } else {
+            LayerStatus::NotStarted
+        }
+    }
+
+    /// Chunks of the latest attempt that never finished, started or not.
+    fn skipped_chunks(&self) -> Vec<usize> {
+        (0..self.chunk_count)
+            .filter(|&chunk_id| {
+                !self.queries
+                    .iter()
+                    .any(|query| query.attempt == self.attempts && query.chunk_id == chunk_id && query.features.is_some())
+            })
+            .collect()
+    }
+}
+
+/// Provenance of a scraped layer in `scrape_report.json`.
+#[derive(Debug, Serialize)]
+struct LayerReport<'a> {
+    name: &'a str,
+    url: &'a str,
+    status: LayerStatus,
+    expected_features: Option<u64>,
+    /// Features returned by the queries of every attempt, written or not
+    features_fetched: usize,
+    features_written: Option<usize>,
+    strategy: Option<&'a str>,
+    retries: u32,
+    skipped_chunks: Vec<usize>,
+    queries: &'a [QueryRecord],
+}
+
+#[derive(Debug, Serialize)]
+struct ScrapeReport<'a> {
+    run_id: &'a str,
+    started_at: DateTime<Utc>,
+    finished_at: DateTime<Utc>,
+    #[serde(flatten)]
+    requests: RequestUsage,
+    layers: Vec<LayerReport<'a>>,
+}
+
+/// Records every chunk query of the run as its progress is reported, for the
+/// `scrape_report.json` written next to the outputs so each dataset carries its provenance:
+/// the counts it was checked against, how long each query took, what was retried and which
+/// chunks were skipped. Layers are told apart by their url.
+#[derive(Debug)]
+pub(crate) struct ScrapeRecorder {
+    run_id: RunId,
+    started_at: DateTime<Utc>,
+    layers: Mutex<BTreeMap<String, LayerRecord>>,
+    /// Output directory of the run once known, and whether the report was written to it
+    directory: Mutex<Option<(PathBuf, bool)>>,
+}
+
+impl ScrapeRecorder {
+    pub(crate) fn new(run_id: &RunId, started_at: DateTime<Utc>) -> Self {
+        Self { run_id: run_id.to_owned(), started_at, layers: Mutex::default(), directory: Mutex::default() }
+    }
+
+    /// Write the report to `directory`, once the run knows where its outputs go.
+    pub(crate) fn set_directory(&self, directory: &Path) {
+        *self.directory.lock().unwrap() = Some((directory.to_owned(), false));
+    }
+
+    /// Whether the report is still to be written, the run having set its output directory.
+    pub(crate) fn is_pending(&self) -> bool {
+        self.directory.lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|(directory, written)| !written && directory.is_dir())
+    }
+
+    /// Write the report to `scrape_report.json` in the output directory, with a `.sha256`
+    /// sidecar like the other outputs. Every layer of the checkpoint is reported, followed by
+    /// the layers the run started without recording them in its checkpoint. None before the
+    /// output directory is set.
+    pub(crate) fn write(&self, layers: &[LayerCheckpoint]) -> io::Result<Option<Artifact>> {
+        let mut directory = self.directory.lock().unwrap();
+        let Some((path, written)) = directory.as_mut() else { return Ok(None) };
+        let records = self.layers.lock().unwrap();
+        let checkpoint_layers = layers.iter().map(|layer| {
+            let record = records.get(&layer.url);
+            LayerReport {
+                name: &layer.name,
+                url: &layer.url,
+                status: layer.status.clone(),
+                expected_features: record.map(|record| record.expected_features),
+                features_fetched: record.map_or(0, LayerRecord::features_fetched),
+                features_written: record.and_then(|record| record.features_written).or(Some(layer.features)),
+                strategy: record.and_then(|record| record.strategy.as_deref()),
+                retries: record.map_or(0, |record| record.queries.iter().map(|query| query.retries).sum()),
+                skipped_chunks: record.map(LayerRecord::skipped_chunks).unwrap_or_default(),
+                queries: record.map_or(&[], |record| record.queries.as_slice()),
+            }
+        });
+        let unrecorded_layers = records.iter()
+            .filter(|(url, _)| !layers.iter().any(|layer| &layer.url == *url))
+            .map(|(url, record)| LayerReport {
+                name: &record.name,
+                url,
+                status: record.status(),
+                expected_features: Some(record.expected_features),
+                features_fetched: record.features_fetched(),
+                features_written: record.features_written,
+                strategy: record.strategy.as_deref(),
+                retries: record.queries.iter().map(|query| query.retries).sum(),
+                skipped_chunks: record.skipped_chunks(),
+                queries: &record.queries,
+            });
+        let run_id = self.run_id.to_string();
+        let report = ScrapeReport {
+            run_id: &run_id,
+            started_at: self.started_at,
+            finished_at: Utc::now(),
+            requests: meter::usage(),
+            layers: checkpoint_layers.chain(unrecorded_layers).collect(),
+        };
+        let mut file = ChecksumFile::create(&path.join("scrape_report.json"))?;
+        serde_json::to_writer_pretty(&mut file, &report)?;
+        writeln!(file)?;
+        let artifact = file.finish()?;
+        *written = true;
+        Ok(Some(artifact))
+    }
+
+    fn record<T>(&self, layer: LayerRef<'_>, update: impl FnOnce(&mut LayerRecord) -> T) -> T {
+        let mut layers = self.layers.lock().unwrap();
+        let record = layers.entry(layer.url.to_owned()).or_default();
+        if record.name.is_empty() {
+            record.name = layer.name.to_owned();
+        }
+        update(record)
+    }
+}
+
+impl ProgressReporter for ScrapeRecorder {
+    fn on_plan(&self, layer: LayerRef<'_>, chunk_count: usize, expected_features: u64) -> io::Result<()> {
+        self.record(layer, |record| {
+            record.expected_features = expected_features;
+            record.chunk_count = chunk_count;
+            record.attempts += 1;
+        });
+        Ok(())
+    }
+
+    fn on_chunk_start(&self, layer: LayerRef<'_>, chunk_id: usize) -> io::Result<()> {
+        self.record(layer, |record| {
+            let query = record.query(chunk_id);
+            query.started_at = Some(Utc::now());
+            query.started = Some(Instant::now());
+        });
+        Ok(())
+    }
+
+    fn on_chunk_done(&self, layer: LayerRef<'_>, progress: &ChunkProgress) -> io::Result<()> {
+        self.record(layer, |record| {
+            let query = record.query(progress.chunk_id);
+            query.duration_seconds = query.started.map(|started| started.elapsed().as_secs_f64());
+            query.features = Some(progress.features);
+            query.bytes = Some(progress.bytes);
+        });
+        Ok(())
+    }
+
+    fn on_retry(&self, layer: LayerRef<'_>, chunk_id: usize, _attempt: i32, _error: &str) -> io::Result<()> {
+        self.record(layer, |record| record.query(chunk_id).retries += 1);
+        Ok(())
+    }
+
+    fn on_finish(&self, layer: LayerRef<'_>, summary: &LayerSummary) -> io::Result<()> {
+        self.record(layer, |record| {
+            record.strategy = summary.strategy.clone();
+            record.features_written = Some(summary.features);
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod scrape_report_tests {
+    use chrono::Utc;
+    use serde_json::Value;
+    use crate::audit::RunId;
+    use crate::checkpoint::{LayerCheckpoint, LayerStatus};
+    use crate::progress::{ChunkProgress, LayerRef, LayerSummary, ProgressReporter};
+    use super::ScrapeRecorder;
+
+    const PARCELS: LayerRef = LayerRef {
+        url: "https://county.example.com/arcgis/rest/services/Parcels/FeatureServer/0",
+        name: "Parcels",
+    };
+    const CITY_PARCELS: LayerRef = LayerRef {
+        url: "https://city.example.com/arcgis/rest/services/Parcels/FeatureServer/0",
+        name: "Parcels",
+    };
+
+    fn chunk_done(chunk_id: usize, features: usize) -> ChunkProgress {
+        ChunkProgress {
+            chunk_id,
+            features,
+            bytes: features * 100,
+            completed_chunks: 0,
+            chunk_count: 3,
+            total_features: 0,
+            total_bytes: 0,
+            expected_features: 2500,
+            features_per_second: 0_f64,
+            eta: None,
+        }
+    }
+
+    #[test]
+    fn skipped_chunks_should_list_unfinished_chunks_of_latest_attempt_when_chunks_failed() {
+        let recorder = ScrapeRecorder::new(&RunId::generate(), Utc::now());
+        recorder.on_plan(PARCELS, 3, 2500).unwrap();
+        recorder.on_chunk_start(PARCELS, 0).unwrap();
+        recorder.on_chunk_done(PARCELS, &chunk_done(0, 1000)).unwrap();
+        recorder.on_plan(PARCELS, 3, 2500).unwrap();
+        recorder.on_chunk_start(PARCELS, 1).unwrap();
+        recorder.on_retry(PARCELS, 1, 1, "Error Status Code: 500").unwrap();
+        recorder.on_chunk_done(PARCELS, &chunk_done(1, 1000)).unwrap();
+        recorder.on_chunk_start(PARCELS, 2).unwrap();
+        let layers = recorder.layers.lock().unwrap();
+        let record = &layers[PARCELS.url];
+        assert_eq!(record.skipped_chunks(), vec![0, 2]);
+        assert_eq!(
+            record.queries.iter().map(|query| (query.attempt, query.chunk_id, query.retries)).collect::<Vec<_>>(),
+            vec![(1, 0, 0), (2, 1, 1), (2, 2, 0)],
+        );
+        assert!(record.queries[1].duration_seconds.is_some());
+    }
+
+    #[test]
+    fn write_should_report_layers_by_url_when_names_repeat_or_checkpoint_misses_them() {
+        let directory = tempfile::tempdir().unwrap();
+        let recorder = ScrapeRecorder::new(&RunId::generate(), Utc::now());
+        assert!(recorder.write(&[]).unwrap().is_none());
+        recorder.set_directory(directory.path());
+        for layer in [PARCELS, CITY_PARCELS] {
+            recorder.on_plan(layer, 1, 2500).unwrap();
+            recorder.on_chunk_start(layer, 0).unwrap();
+        }
+        recorder.on_chunk_done(PARCELS, &chunk_done(0, 2500)).unwrap();
+        let summary = LayerSummary { strategy: Some("pagination".to_owned()), features: 2500, bytes: 250000 };
+        recorder.on_finish(PARCELS, &summary).unwrap();
+        let checkpoint = LayerCheckpoint {
+            url: PARCELS.url.to_owned(),
+            name: PARCELS.name.to_owned(),
+            status: LayerStatus::Complete,
+            features: 2500,
+        };
+        assert!(recorder.is_pending());
+        let artifact = recorder.write(&[checkpoint]).unwrap().unwrap();
+        assert!(!recorder.is_pending());
+
+        let report: Value = serde_json::from_slice(&std::fs::read(&artifact.path).unwrap()).unwrap();
+        let layers: Vec<(&str, &str, u64)> = report["layers"].as_array()
+            .unwrap()
+            .iter()
+            .map(|layer| {
+                (layer["url"].as_str().unwrap(), layer["status"].as_str().unwrap(), layer["features_fetched"].as_u64().unwrap())
+            })
+            .collect();
+        assert_eq!(layers, vec![(PARCELS.url, "complete", 2500), (CITY_PARCELS.url, "not_started", 0)]);
+        assert_eq!(report["layers"][1]["skipped_chunks"], serde_json::json!([0]));
+    }
+}
@@ -0,0 +1,456 @@
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use clap::ValueEnum;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use crate::audit::{self, RunAudit, RunId, RunIdPlacement};
+use crate::auth::{AnonymousAuth, AuthProvider};
+use crate::capability;
+use crate::client::ServiceClient;
+use crate::config::ScrapeConfig;
+use crate::connection::{ConnectionPolicy, HostConnections};
+use crate::filter::QueryFilter;
+use crate::geometry::RingWinding;
+use crate::geometry_guard::{GeometryGuard, GeometryPolicy};
+use crate::metadata::{self, MetadataOverrides, RestServiceMetadata};
+use crate::pipeline::{self, RecordOutput, ScrapeSettings};
+use crate::report::{self, WarningKind};
+use crate::progress::{ProgressReporter, ProgressReporters};
+use crate::sampling::SampleMethod;
+use crate::scraping::{self, FetchOptions, OutputFormat, OutputFormatError, ResponseFormat, StallPolicy};
+use crate::spool::ChunkSpool;
+use crate::strategy::ScrapeStrategy;
+use crate::suspicious::{CoordinateGuard, SuspiciousPolicy};
+use crate::transform::{self, DomainGuard, DomainPolicy, FeatureTransformer, NumericGuard, NumericPolicy};
+use crate::wkt::{self, GeometryEncoding};
+
+/// Scrapes layers of ArcGIS REST services from within an async program, running the same
+/// pipeline as the command line for a single `--url` with its default options.
+///
+/// ```no_run
+/// # async fn scrape() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// let scraper = arcgis_scraper::Scraper::new().output_spatial_reference(4326);
+/// let layer = scraper
+///     .request_metadata("https://example.com/arcgis/rest/services/Parcels/FeatureServer/0")
+///     .await?;
+/// let features = scraper.scrape_to_csv(&layer, "parcels.csv").await?;
+/// println!("Scraped {} of {} features", features, layer.feature_count()?);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Scraper {
+    client: ServiceClient,
+    output_spatial_reference: Option<i64>,
+    query_retries: i32,
+    progress: Option<Arc<dyn ProgressReporter>>,
+    settings: ScrapeSettings,
+}
+
+impl Scraper {
+    /// Scraper sending anonymous requests, with a new run id. Use [`Scraper::auth`] for secured
+    /// services.
+    pub fn new() -> Self {
+        Self {
+            client: Self::client(None, Arc::new(AnonymousAuth)),
+            output_spatial_reference: None,
+            query_retries: 5,
+            progress: None,
+            settings: ScrapeSettings {
+                strategy: ScrapeStrategy::Auto,
+                sample: None,
+                sample_method: SampleMethod::First,
+                ignore_disk_space: false,
+                max_pending_chunks: 64,
+            },
+        }
+    }
+
+    fn client(contact: Option<&str>, auth: Arc<dyn AuthProvider>) -> ServiceClient {
+        let audit = RunAudit {
+            run_id: RunId::generate(),
+            placement: RunIdPlacement::Header,
+            user_agent: audit::user_agent(contact),
+        };
+        ServiceClient::new(auth, audit)
+    }
+
+    /// Email address added to the User-Agent of every request, so server admins can reach out
+    /// instead of blocking the scraper.
+    pub fn contact(mut self, email: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let contact = audit::parse_contact(email)?;
+        self.client = Self::client(Some(&contact), self.client.auth());
+        Ok(self)
+    }
+
+    /// Attach the credentials of `provider` to every request instead of sending anonymous
+    /// requests, e.g. for secured services behind an identity provider the command line does
+    /// not support.
+    pub fn auth(mut self, provider: Arc<dyn AuthProvider>) -> Self {
+        self.client = self.client.with_auth(provider);
+        self
+    }
+
+    /// Well-known id of the spatial reference the geometries are requested in, the layer's own
+    /// by default.
+    pub fn output_spatial_reference(mut self, wkid: i64) -> Self {
+        self.output_spatial_reference = Some(wkid);
+        self
+    }
+
+    /// Times each query is tried before the scrape fails.
+    pub fn query_retries(mut self, tries: i32) -> Self {
+        self.query_retries = tries;
+        self
+    }
+
+    /// Report the progress of every scrape to `reporter`, e.g. to show it in the program's own UI.
+    pub fn progress(mut self, reporter: Arc<dyn ProgressReporter>) -> Self {
+        self.progress = Some(reporter);
+        self
+    }
+
+    /// Warn instead of failing when the estimated size of a scrape exceeds the free disk space.
+    pub fn ignore_disk_space(mut self, ignore: bool) -> Self {
+        self.settings.ignore_disk_space = ignore;
+        self
+    }
+
+    /// Request the metadata of the layer at `url`, along with its feature count, extent and OID
+    /// range.
+    pub async fn request_metadata(&self, url: &str) -> Result<RestServiceMetadata, Box<dyn Error + Send + Sync>> {
+        capability::require_for_url(url)?;
+        metadata::request_service_metadata(
+            &self.client,
+            url,
+            self.output_spatial_reference,
+            &QueryFilter::default(),
+            &MetadataOverrides::default(),
+        ).await
+    }
+
+    /// Options of a fetch with the command line's defaults, spooling chunks into `spool`.
+    fn fetch_options(
+        &self,
+        layer: &RestServiceMetadata,
+        spool: &ChunkSpool,
+        geometry_encoding: GeometryEncoding,
+    ) -> Result<FetchOptions, Box<dyn Error + Send + Sync>> {
+        Ok(FetchOptions {
+            fields: layer.fields.clone(),
+            geo_type: layer.geo_type.clone(),
+            max_tries: self.query_retries,
+            limiter: None,
+            pacer: None,
+            request_permits: None,
+            transformer: FeatureTransformer::new(&ScrapeConfig::default())?,
+            numeric_guard: NumericGuard::new(&layer.fields, NumericPolicy::Keep),
+            domain_guard: DomainGuard::new(&layer.fields, DomainPolicy::Keep),
+            geometry_guard: GeometryGuard::new(&layer.geo_type, GeometryPolicy::Keep),
+            coordinate_guard: CoordinateGuard::new(layer, SuspiciousPolicy::Keep),
+            provenance: None,
+            merge_layout: None,
+            seen_object_ids: None,
+            layer: layer.name.to_owned(),
+            layer_url: layer.url.to_owned(),
+            feature_count: layer.feature_count().ok().and_then(|count| usize::try_from(count).ok()),
+            progress: self.progress.clone().unwrap_or_else(|| Arc::new(ProgressReporters::default())),
+            stall: Some(StallPolicy { timeout: Duration::from_secs(60), restart: false }),
+            chunk_timeout: None,
+            connections: Arc::new(HostConnections::new(
+                &layer.url,
+                ConnectionPolicy { failure_threshold: 3, rotate_addresses: false, replicas: vec![] },
+                &layer.client,
+            )),
+            tiles: None,
+            cell_indexer: None,
+            measurer: None,
+            attachments: None,
+            topology: vec![],
+            postgis: None,
+            parquet: None,
+            flatgeobuf: None,
+            shapefile: None,
+            ogr: None,
+            split_by: None,
+            ring_winding: RingWinding::Esri,
+            geometry_encoding,
+            fix_winding: false,
+            fix_antimeridian: false,
+            spool: spool.join(&layer.name),
+            deadline: None,
+            split_passes: None,
+            format: pipeline::response_format(layer, &ResponseFormat::Auto),
+            pbf_rejected: Arc::default(),
+        })
+    }
+
+    /// Scrape every feature of `layer` into a CSV file at `path`. When the features returned
+    /// do not match the layer's count the next strategy is tried, the best attempt is kept and
+    /// a warning is recorded. Returns the number of features written.
+    pub async fn scrape_to_csv<P: AsRef<Path>>(
+        &self,
+        layer: &RestServiceMetadata,
+        path: P,
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let path = path.as_ref();
+        let columns = scraping::output_columns(&layer.fields, false, None, None, false);
+        let mut output_file = scraping::create_output_file(path, &columns)?;
+        let spool = ChunkSpool::for_run(&RunId::generate())?;
+        let fetch_options = self.fetch_options(layer, &spool, GeometryEncoding::Esri)?;
+        let directory = path.parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        let features = pipeline::scrape_layer(
+            &self.settings,
+            layer,
+            Arc::new(fetch_options),
+            directory,
+            RecordOutput::File(&mut output_file),
+        ).await?;
+        output_file.finish()?;
+        spool.remove()?;
+        Ok(features)
+    }
+
+    /// Scrape the features of `layer` selected by `options` into `writer`, such as a socket or a
+    /// compressor, in the format of `options`. Records are written chunk by chunk as they are
+    /// accepted, the way [`Scraper::scrape_to_csv`] writes its file, and fetching waits while
+    /// 64 chunks are not yet written so a slow writer holds back the scrape. Only CSV, header row
+    /// first, can be streamed; the other formats are written to files and return an error. The
+    /// writer is flushed but not shut down. Returns the number of features written.
+    pub async fn scrape_to_writer<W: AsyncWrite + Unpin>(
+        &self,
+        layer: &RestServiceMetadata,
+        options: &WriterOptions,
+        mut writer: W,
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        if options.format != OutputFormat::Csv {
+            return Err(OutputFormatError::CannotStream(options.format.to_owned()).into())
+        }
+        let layer = &self.writer_layer(layer, options).await?;
+        let columns = scraping::output_columns(&layer.fields, false, None, None, false);
+        let spool = ChunkSpool::for_run(&RunId::generate())?;
+        let fetch_options = self.fetch_options(layer, &spool, options.geometry_encoding)?;
+        let (sender, mut receiver) = mpsc::channel(self.settings.max_pending_chunks.max(1));
+        sender.send(format!("{}\n", scraping::header_line(&columns)).into_bytes()).await?;
+        let settings = &self.settings;
+        let directory = spool.directory().to_owned();
+        let scrape = async move {
+            let features = pipeline::scrape_layer(
+                settings,
+                layer,
+                Arc::new(fetch_options),
+                &directory,
+                RecordOutput::Stream(&sender),
+            ).await;
+            // Closing the channel lets the writer finish
+            drop(sender);
+            features
+        };
+        let write = async {
+            while let Some(records) = receiver.recv().await {
+                writer.write_all(&records).await?;
+            }
+            writer.flush().await?;
+            Ok(())
+        };
+        let (features, ()) = tokio::try_join!(scrape, write)?;
+        spool.remove()?;
+        Ok(features)
+    }
+
+    /// `layer` restricted to the features and fields selected by `options`. A where clause
+    /// changes the feature count and OID range, so the metadata is requested again with it.
+    async fn writer_layer(
+        &self,
+        layer: &RestServiceMetadata,
+        options: &WriterOptions,
+    ) -> Result<RestServiceMetadata, Box<dyn Error + Send + Sync>> {
+        let mut layer = match &options.where_clause {
+            Some(where_clause) => {
+                let filter = QueryFilter { where_clause: Some(where_clause.to_owned()), ..layer.filter.to_owned() };
+                metadata::request_service_metadata(
+                    &self.client,
+                    &layer.url,
+                    self.output_spatial_reference,
+                    &filter,
+                    &MetadataOverrides::default(),
+                ).await?
+            }
+            None => layer.to_owned(),
+        };
+        if !options.fields.is_empty() {
+            for name in transform::unknown_fields(&layer.fields, &options.fields) {
+                report::warn_about(WarningKind::Skipped, &layer.name, format_args!("Field \"{}\" is not part of the layer", name));
+            }
+            layer.select_fields(&options.fields);
+        }
+        if options.geometry_encoding == GeometryEncoding::Wkt {
+            layer.fields = wkt::wkt_fields(layer.fields);
+        }
+        Ok(layer)
+    }
+}
+
+impl Default for Scraper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What [`Scraper::scrape_to_writer`] writes, CSV records of every feature and field of the
+/// layer with Esri JSON geometries by default.
+///
+/// ```
+/// # fn options() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// let options = arcgis_scraper::WriterOptions::new()
+///     .fields(&["PARCEL_NO", "OWNER_NAME"])
+///     .where_clause("LAND_USE = 1")
+///     .geometry_encoding("wkt")?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct WriterOptions {
+    format: OutputFormat,
+    fields: Vec<String>,
+    where_clause: Option<String>,
+    geometry_encoding: GeometryEncoding,
+}
+
+impl WriterOptions {
+    pub fn new() -> Self {
+        Self {
+            format: OutputFormat::Csv,
+            fields: vec![],
+            where_clause: None,
+            geometry_encoding: GeometryEncoding::Esri,
+        }
+    }
+
+    /// Format of the output, named like the values of --output-format. Only csv can be
+    /// streamed, scraping into a writer fails for the others.
+    pub fn output_format(mut self, format: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        self.format = scraping::parse_output_format(format)?;
+        Ok(self)
+    }
+
+    /// Only request and write these fields (case-insensitive), like --fields. Geometry is still
+    /// scraped.
+    pub fn fields(mut self, names: &[&str]) -> Self {
+        self.fields = names.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    /// SQL clause the features must match, sent as the `where` of every query.
+    pub fn where_clause(mut self, where_clause: &str) -> Self {
+        self.where_clause = Some(where_clause.to_owned());
+        self
+    }
+
+    /// How geometries are written, `esri` for Esri JSON columns or `wkt` for a single WKT
+    /// column.
+    pub fn geometry_encoding(mut self, encoding: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        self.geometry_encoding = GeometryEncoding::from_str(encoding, true)?;
+        Ok(self)
+    }
+}
+
+impl Default for WriterOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod scraper_tests {
+    use std::sync::Arc;
+    use reqwest::RequestBuilder;
+    use serde_json::json;
+    use crate::auth::{AuthFuture, AuthProvider};
+    use crate::filter::QueryFilter;
+    use crate::metadata::{parse_metadata, RestServiceMetadata};
+    use crate::scraping::{OutputFormat, OutputFormatError};
+    use super::{Scraper, WriterOptions};
+
+    /// Provider sending its key in a header, the way some gateways in front of services expect.
+    struct HeaderAuth;
+
+    impl AuthProvider for HeaderAuth {
+        fn get_token(&self) -> AuthFuture<'_, Option<String>> {
+            Box::pin(async { Ok(Some("abc123".to_owned())) })
+        }
+
+        fn refresh<'a>(&'a self, _rejected: Option<&'a str>) -> AuthFuture<'a, ()> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn attach_to_request(&self, request: RequestBuilder) -> AuthFuture<'_, RequestBuilder> {
+            Box::pin(async { Ok(request.header("X-Api-Key", "abc123")) })
+        }
+    }
+
+    #[tokio::test]
+    async fn auth_should_attach_custom_credentials_when_contact_set_afterwards() {
+        let scraper = Scraper::new()
+            .auth(Arc::new(HeaderAuth))
+            .contact("gis@example.com")
+            .unwrap();
+        let request = scraper.client
+            .get("https://example.com/arcgis/rest/services/Parcels/FeatureServer/0?f=json")
+            .await
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(request.headers()["X-Api-Key"], "abc123");
+        assert_eq!(request.url().query(), Some("f=json"));
+        assert_eq!(scraper.client.token().await.unwrap().as_deref(), Some("abc123"));
+    }
+
+    fn layer(scraper: &Scraper) -> RestServiceMetadata {
+        let metadata = json!({
+            "name": "Parcels",
+            "type": "Feature Layer",
+            "maxRecordCount": 2000,
+            "geometryType": "esriGeometryPoint",
+            "fields": [
+                {"name": "OBJECTID", "type": "esriFieldTypeOID", "alias": "OBJECTID"},
+                {"name": "PARCEL_NO", "type": "esriFieldTypeString", "alias": "Parcel", "length": 20},
+                {"name": "OWNER_NAME", "type": "esriFieldTypeString", "alias": "Owner", "length": 50},
+            ],
+            "sourceSpatialReference": {"wkid": 4326},
+        });
+        let url = "https://example.com/arcgis/rest/services/Parcels/FeatureServer/0";
+        parse_metadata(&scraper.client, url, &metadata, None, &QueryFilter::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn scrape_to_writer_should_fail_when_format_cannot_be_streamed() {
+        let scraper = Scraper::new();
+        let options = WriterOptions::new().output_format("geoparquet").unwrap();
+        let error = scraper.scrape_to_writer(&layer(&scraper), &options, Vec::new())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<OutputFormatError>(),
+            Some(&OutputFormatError::CannotStream(OutputFormat::Geoparquet)),
+        );
+        assert!(WriterOptions::new().output_format("xlsx").is_err());
+        assert!(WriterOptions::new().geometry_encoding("wkb").is_err());
+    }
+
+    #[tokio::test]
+    async fn writer_layer_should_keep_selected_fields_when_encoding_wkt() {
+        let scraper = Scraper::new();
+        let options = WriterOptions::new()
+            .fields(&["parcel_no"])
+            .geometry_encoding("wkt")
+            .unwrap();
+        let layer = scraper.writer_layer(&layer(&scraper), &options).await.unwrap();
+        let names: Vec<&str> = layer.fields.iter().map(|field| field.name.as_str()).collect();
+        assert_eq!(names, vec!["PARCEL_NO", "WKT"]);
+    }
+}
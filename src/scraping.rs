@@ -1,11 +1,56 @@
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
-use std::io::{Write};
-use std::time::Duration;
-use reqwest::{Client, StatusCode};
+use std::future::Future;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+use clap::ValueEnum;
+use flate2::read::{MultiGzDecoder, ZlibDecoder};
+use reqwest::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
+use regex::Regex;
+use reqwest::{StatusCode, Url};
+use crate::antimeridian::fix_antimeridian;
+use crate::attachments::{AttachmentDownloader, ATTACHMENTS_COLUMN};
+use crate::cell_index::{CellIndexKind, CellIndexer};
+use crate::checksum::ChecksumFile;
+use crate::client::ServiceClient;
+use serde::de::{self, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
 use serde_json::{json, Map, Value};
-use crate::metadata::{RestServiceField, RestServiceFieldType, RestServiceGeometryType};
+use crate::metadata::{object_id, RestServiceField, RestServiceFieldType, RestServiceGeometryType};
+use crate::connection::HostConnections;
+use crate::deadline::{Deadline, DeadlineError};
+use crate::meter::RequestLimitError;
+use crate::geometry::{rewind_rings, RingWinding};
+use crate::geometry_guard::{GeometryGuard, GeometryMismatches};
+use crate::flatgeobuf::{EncodedFeature, FlatgeobufLayout};
+use crate::geoparquet::{ParquetLayout, ParquetRows};
+use crate::wkt::{self, GeometryEncoding};
+use crate::measure::{Measure, Measurer};
+use crate::merge::MergeLayout;
+use crate::ogr::{self, OgrFeatures, OgrLayout};
+use crate::partition::partition_value;
+use crate::pbf::{PbfError, PbfFeatureCollection};
+use crate::pmtiles::TileOutput;
+use crate::postgis::PostgisRecords;
+use crate::progress::{LayerRef, ProgressReporter};
+use crate::quadtree::SeenObjectIds;
+use crate::shapefile::{ShapefileLayout, ShapefileRecord};
+use crate::spool::{ChunkSpool, SpooledChunk};
+use crate::suspicious::{CoordinateGuard, SuspiciousCoordinates};
+use crate::throttle::{BandwidthLimiter, RequestPacer};
+use crate::transform::{DomainGuard, DomainViolations, FeatureTransformer, NumericAnomalies, NumericGuard, Provenance};
+use crate::topology::{TopologyFeature, TopologySink};
+use crate::vector_tiles::TileFeature;
+use crate::profile;
+use crate::report::{self, WarningKind};
 
 #[derive(Debug, PartialEq)]
 pub(crate) enum RestServiceScrapingError {
@@ -16,6 +61,12 @@ pub(crate) enum RestServiceScrapingError {
     UnknownJsonResponse(String),
     TooManyRetires(i32),
     InvalidFeature(String),
+    Stalled(u64),
+    /// A single attempt took longer than --chunk-timeout seconds
+    TimedOut(u64),
+    InvalidToken(String),
+    /// The server rejected the requested `f` format
+    UnsupportedFormat(String),
 }
 
 impl Display for RestServiceScrapingError {
@@ -42,6 +93,18 @@ impl Display for RestServiceScrapingError {
             RestServiceScrapingError::InvalidFeature(raw_json) => {
                 write!(f, "Raw JSON:\n{}", raw_json)
             }
+            RestServiceScrapingError::Stalled(seconds) => {
+                write!(f, "No data received for {} seconds", seconds)
+            }
+            RestServiceScrapingError::TimedOut(seconds) => {
+                write!(f, "Request did not complete within {} seconds", seconds)
+            }
+            RestServiceScrapingError::InvalidToken(raw_json) => {
+                write!(f, "Token rejected. Raw JSON:\n{}", raw_json)
+            }
+            RestServiceScrapingError::UnsupportedFormat(response) => {
+                write!(f, "Format rejected by the server. Response:\n{}", response)
+            }
         }
     }
 }
@@ -68,7 +131,7 @@ fn convert_json_value(json_value: &Value) -> Result<String, RestServiceScrapingE
     }
 }
 
-fn convert_json_field(
+pub(crate) fn convert_json_field(
     field: &RestServiceField,
     json_value: &Value,
 ) -> Result<Vec<String>, RestServiceScrapingError> {
@@ -89,7 +152,7 @@ fn extract_geometry(
     feature: &Map<String, Value>,
     default_keys: Option<Vec<String>>,
 ) -> Map<String, Value> {
-    let geometry = feature["geometry"].as_object();
+    let geometry = feature.get("geometry").and_then(Value::as_object);
     if let Some(obj) = geometry {
         return obj.to_owned()
     }
@@ -196,6 +259,7 @@ fn convert_geometry(
 fn handle_record(
     fields: &Vec<RestServiceField>,
     geo_type: &RestServiceGeometryType,
+    geometry_encoding: GeometryEncoding,
     feature: &Map<String, Value>,
 ) -> Result<Vec<String>, RestServiceScrapingError> {
     let attributes = feature["attributes"]
@@ -212,17 +276,186 @@ fn handle_record(
             continue
         }
         let mut values = convert_json_field(
-            &field,
+            field,
             &attributes[field.name.as_str()]
         )?;
         record.append(&mut values);
     }
-    for geometry_field in convert_geometry(geo_type, feature)? {
-        record.push(geometry_field)
+    match geometry_encoding {
+        GeometryEncoding::Esri => record.extend(convert_geometry(geo_type, feature)?),
+        GeometryEncoding::Wkt if *geo_type != RestServiceGeometryType::None => {
+            record.push(wkt::esri_to_wkt(geo_type, feature))
+        }
+        GeometryEncoding::Wkt => {}
     }
     Ok(record)
 }
 
+/// Column names of the records produced for `fields`, in output order. Coded value fields get
+/// an extra `_DESC` column holding the description of the code.
+pub(crate) fn output_columns(
+    fields: &[RestServiceField],
+    provenance: bool,
+    cell_index: Option<&CellIndexKind>,
+    measure: Option<&Measure>,
+    attachments: bool,
+) -> Vec<String> {
+    fields.iter()
+        .flat_map(|field|
+            if field.codes.is_some() {
+                vec![field.name.clone(), format!("{}_DESC", field.name)]
+            } else {
+                vec![field.name.clone()]
+            }
+        )
+        .chain(
+            Some(Provenance::COLUMNS.map(str::to_owned))
+                .filter(|_| provenance)
+                .into_iter()
+                .flatten()
+        )
+        .chain(cell_index.map(|kind| kind.column().to_owned()))
+        .chain(measure.map(Measure::column))
+        .chain(Some(ATTACHMENTS_COLUMN.to_owned()).filter(|_| attachments))
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum OutputFormatError {
+    CannotMerge(OutputFormat),
+    CannotSplit(OutputFormat),
+    SplitMerged,
+    /// Format written to files only, not streamable into a writer
+    CannotStream(OutputFormat),
+    Unknown(String),
+}
+
+impl Display for OutputFormatError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormatError::CannotMerge(format) => write!(
+                f,
+                "--merge-into only writes CSV and cannot be combined with --output-format {}",
+                format,
+            ),
+            OutputFormatError::CannotSplit(format) => write!(
+                f,
+                "--split-by only writes CSV and cannot be combined with --output-format {}",
+                format,
+            ),
+            OutputFormatError::SplitMerged => {
+                write!(f, "--split-by cannot be combined with --merge-into")
+            }
+            OutputFormatError::CannotStream(format) => write!(
+                f,
+                "Only csv can be streamed into a writer, {} outputs are written to files",
+                format,
+            ),
+            OutputFormatError::Unknown(value) => write!(
+                f,
+                "Unknown output format '{}', expected csv, topojson, geoparquet, flatgeobuf, shp or ogr:<driver>",
+                value,
+            ),
+        }
+    }
+}
+
+impl Error for OutputFormatError {}
+
+/// Format of the file each layer is scraped into.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum OutputFormat {
+    Csv,
+    /// TopoJSON topology of the layer, see --simplify-tolerance and --quantization
+    Topojson,
+    /// GeoParquet file with typed columns and WKB geometries, readable by DuckDB, Spark and
+    /// GeoPandas
+    Geoparquet,
+    /// FlatGeobuf file with a packed spatial index, queryable by bounding box over HTTP range
+    /// requests
+    Flatgeobuf,
+    /// Shapefile (.shp, .shx, .dbf and .prj), field names cut to 10 characters as listed in its
+    /// .fields.csv report
+    Shp,
+    /// File written by the named OGR driver (e.g. GML, DXF or MapInfo File), needs the gdal
+    /// feature
+    Ogr(String),
+}
+
+/// Parse the value of --output-format, one of the format names or `ogr:<driver>`.
+pub(crate) fn parse_output_format(value: &str) -> Result<OutputFormat, OutputFormatError> {
+    match value.to_lowercase().as_str() {
+        "csv" => return Ok(OutputFormat::Csv),
+        "topojson" => return Ok(OutputFormat::Topojson),
+        "geoparquet" => return Ok(OutputFormat::Geoparquet),
+        "flatgeobuf" => return Ok(OutputFormat::Flatgeobuf),
+        "shp" => return Ok(OutputFormat::Shp),
+        _ => {}
+    }
+    match value.split_once(':') {
+        Some((prefix, driver)) if prefix.eq_ignore_ascii_case("ogr") && !driver.trim().is_empty() => {
+            Ok(OutputFormat::Ogr(driver.trim().to_owned()))
+        }
+        _ => Err(OutputFormatError::Unknown(value.to_owned())),
+    }
+}
+
+impl OutputFormat {
+    pub(crate) fn extension(&self) -> String {
+        match self {
+            OutputFormat::Csv => "csv".to_owned(),
+            OutputFormat::Topojson => "topojson".to_owned(),
+            OutputFormat::Geoparquet => "parquet".to_owned(),
+            OutputFormat::Flatgeobuf => "fgb".to_owned(),
+            OutputFormat::Shp => "shp".to_owned(),
+            OutputFormat::Ogr(driver) => ogr::driver_extension(driver),
+        }
+    }
+}
+
+/// Value of --output-format selecting the format.
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormat::Csv => write!(f, "csv"),
+            OutputFormat::Topojson => write!(f, "topojson"),
+            OutputFormat::Geoparquet => write!(f, "geoparquet"),
+            OutputFormat::Flatgeobuf => write!(f, "flatgeobuf"),
+            OutputFormat::Shp => write!(f, "shp"),
+            OutputFormat::Ogr(driver) => write!(f, "ogr:{}", driver),
+        }
+    }
+}
+
+/// CSV header row of `columns`, without the line break.
+pub(crate) fn header_line(columns: &[String]) -> String {
+    columns.iter()
+        .map(handle_csv_value)
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Create a CSV output file holding the header row of `columns`.
+pub(crate) fn create_output_file(path: &Path, columns: &[String]) -> io::Result<ChecksumFile> {
+    let mut output_file = ChecksumFile::create(path)?;
+    writeln!(&mut output_file, "{}", header_line(columns))?;
+    Ok(output_file)
+}
+
+/// Number of CSV records read, line breaks within quoted values not ending a record.
+fn count_records<R: BufRead>(reader: R) -> io::Result<usize> {
+    let mut records = 0;
+    let mut quoted = false;
+    for byte in reader.bytes() {
+        match byte? {
+            b'"' => quoted = !quoted,
+            b'\n' if !quoted => records += 1,
+            _ => {}
+        }
+    }
+    Ok(records)
+}
+
 pub(crate) fn handle_csv_value(value: &String) -> String {
     if value.chars().any(|chr| chr == '\r' || chr == '\n' || chr == ',' || chr == '"') {
         return format!("\"{}\"", value.replace("\"", "\"\""));
@@ -230,96 +463,699 @@ pub(crate) fn handle_csv_value(value: &String) -> String {
     value.to_owned()
 }
 
+/// When a request counts as stalled and whether it is abandoned (and retried) or only reported.
+#[derive(Debug, Clone)]
+pub(crate) struct StallPolicy {
+    pub(crate) timeout: Duration,
+    pub(crate) restart: bool,
+}
+
+/// Await `future`, warning every time `stall.timeout` passes without it completing. With a
+/// restarting policy the first stall abandons the future instead.
+async fn watch_stall<F: Future>(
+    future: F,
+    stall: Option<&StallPolicy>,
+    query: &str,
+) -> Result<F::Output, RestServiceScrapingError> {
+    let policy = match stall {
+        Some(policy) => policy,
+        None => return Ok(future.await),
+    };
+    tokio::pin!(future);
+    loop {
+        match tokio::time::timeout(policy.timeout, &mut future).await {
+            Ok(output) => return Ok(output),
+            Err(_) => {
+                let seconds = policy.timeout.as_secs();
+                report::warn(format_args!(
+                    "No data received for {} seconds from {}",
+                    seconds,
+                    query,
+                ));
+                if policy.restart {
+                    return Err(RestServiceScrapingError::Stalled(seconds))
+                }
+            }
+        }
+    }
+}
+
+async fn read_body(
+    mut response: reqwest::Response,
+    limiter: Option<&BandwidthLimiter>,
+    stall: Option<&StallPolicy>,
+    query: &str,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let mut body = Vec::new();
+    while let Some(chunk) = watch_stall(response.chunk(), stall, query).await?? {
+        if let Some(limiter) = limiter {
+            limiter.consume(chunk.len()).await;
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Reader quoting the bare NaN, Infinity and -Infinity tokens some services write into their
+/// JSON so the body parses, leaving the values to the numeric policy.
+struct NonFiniteQuoter<R> {
+    inner: R,
+    output: Vec<u8>,
+    position: usize,
+    in_string: bool,
+    escaped: bool,
+    /// Inside a bare token, quoted once it ends
+    in_token: bool,
+    /// A minus sign outside a string, held back until the next byte tells whether it starts
+    /// -Infinity
+    minus: bool,
+    finished: bool,
+}
+
+impl<R: Read> NonFiniteQuoter<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            output: vec![],
+            position: 0,
+            in_string: false,
+            escaped: false,
+            in_token: false,
+            minus: false,
+            finished: false,
+        }
+    }
+
+    fn quote(&mut self, byte: u8) {
+        if self.in_token {
+            if byte.is_ascii_alphabetic() {
+                self.output.push(byte);
+                return
+            }
+            self.output.push(b'"');
+            self.in_token = false;
+        }
+        if self.minus {
+            self.minus = false;
+            if byte == b'I' {
+                self.output.extend_from_slice(b"\"-I");
+                self.in_token = true;
+                return
+            }
+            self.output.push(b'-');
+        }
+        if self.in_string {
+            if self.escaped {
+                self.escaped = false;
+            } else if byte == b'\\' {
+                self.escaped = true;
+            } else if byte == b'"' {
+                self.in_string = false;
+            }
+        } else {
+            // Outside of strings JSON has no capital letters, so N and I can only start NaN or
+            // Infinity
+            match byte {
+                b'"' => self.in_string = true,
+                b'-' => {
+                    self.minus = true;
+                    return
+                }
+                b'N' | b'I' => {
+                    self.output.extend_from_slice(&[b'"', byte]);
+                    self.in_token = true;
+                    return
+                }
+                _ => {}
+            }
+        }
+        self.output.push(byte);
+    }
+
+    fn finish(&mut self) {
+        if self.minus {
+            self.output.push(b'-');
+        }
+        if self.in_token {
+            self.output.push(b'"');
+        }
+        self.finished = true;
+    }
+}
+
+impl<R: Read> Read for NonFiniteQuoter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.output.len() && !self.finished {
+            self.output.clear();
+            self.position = 0;
+            let mut input = [0; 8192];
+            let read = self.inner.read(&mut input)?;
+            if read == 0 {
+                self.finish();
+            }
+            for byte in &input[..read] {
+                self.quote(*byte);
+            }
+        }
+        let available = &self.output[self.position..];
+        let length = available.len().min(buf.len());
+        buf[..length].copy_from_slice(&available[..length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+/// Reader counting the bytes read through it.
+struct CountingReader<R> {
+    inner: R,
+    count: usize,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read;
+        Ok(read)
+    }
+}
+
+/// Blocking reader of the body chunks sent by the task downloading a response. The body ends
+/// once the task drops its sender.
+struct BodyReader {
+    chunks: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let available = &self.chunk[self.position..];
+        let length = available.len().min(buf.len());
+        buf[..length].copy_from_slice(&available[..length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+/// Reader decompressing a response body sent with the `Content-Encoding`, and whether the
+/// encoding is one it decompresses. Bodies with no or an unknown encoding are read as is.
+fn decoding_reader<R: Read + Send + 'static>(encoding: Option<&str>, body: R) -> (Box<dyn Read + Send>, bool) {
+    match encoding.map(str::trim) {
+        Some("gzip") | Some("x-gzip") => (Box::new(MultiGzDecoder::new(body)), true),
+        Some("deflate") => (Box::new(ZlibDecoder::new(body)), true),
+        #[cfg(feature = "brotli")]
+        Some("br") => (Box::new(brotli::Decompressor::new(body, 4096)), true),
+        _ => (Box::new(body), false),
+    }
+}
+
+/// Decompress a response body sent with the `Content-Encoding`. Bodies with no or an unknown
+/// encoding are returned as is.
+fn decode_body(encoding: Option<&str>, body: Vec<u8>) -> io::Result<(Vec<u8>, bool)> {
+    let (mut reader, compressed) = decoding_reader(encoding, io::Cursor::new(body));
+    let mut decoded = vec![];
+    reader.read_to_end(&mut decoded)?;
+    Ok((decoded, compressed))
+}
+
+/// Members of a query response other than its features, which are handed to a [`FeatureSink`]
+/// one at a time as they are parsed. Members after the features array (e.g.
+/// `exceededTransferLimit`) are only known once every feature was handled.
+#[derive(Debug, Default)]
+struct ResponseTrailer {
+    members: Map<String, Value>,
+    /// Features of the response, None when it has no features array (e.g. an error)
+    features: Option<usize>,
+    /// The whole response when it is not an object
+    invalid: Option<Value>,
+}
+
+impl ResponseTrailer {
+    fn invalid(value: Value) -> Self {
+        Self { invalid: Some(value), ..Default::default() }
+    }
+}
+
+/// Seed of a json query response, sending each of its features as soon as it is parsed.
+struct ResponseSeed<'a> {
+    features: &'a mpsc::Sender<Value>,
+}
+
+impl<'de> DeserializeSeed<'de> for ResponseSeed<'_> {
+    type Value = ResponseTrailer;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ResponseSeed<'_> {
+    type Value = ResponseTrailer;
+
+    fn expecting(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(formatter, "a query response object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut trailer = ResponseTrailer::default();
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "features" {
+                trailer.features = Some(map.next_value_seed(FeaturesSeed { features: self.features })?);
+            } else {
+                let value = map.next_value()?;
+                trailer.members.insert(key, value);
+            }
+        }
+        Ok(trailer)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(ResponseTrailer::invalid(Value::Null))
+    }
+
+    fn visit_bool<E: de::Error>(self, value: bool) -> Result<Self::Value, E> {
+        Ok(ResponseTrailer::invalid(Value::from(value)))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Self::Value, E> {
+        Ok(ResponseTrailer::invalid(Value::from(value)))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+        Ok(ResponseTrailer::invalid(Value::from(value)))
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Self::Value, E> {
+        Ok(ResponseTrailer::invalid(Value::from(value)))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        Ok(ResponseTrailer::invalid(Value::from(value)))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut values = vec![];
+        while let Some(value) = seq.next_element::<Value>()? {
+            values.push(value);
+        }
+        Ok(ResponseTrailer::invalid(Value::from(values)))
+    }
+}
+
+/// Seed of the features array of a json query response, sending each feature as soon as it is
+/// parsed. Its value is the number of features sent.
+struct FeaturesSeed<'a> {
+    features: &'a mpsc::Sender<Value>,
+}
+
+impl<'de> DeserializeSeed<'de> for FeaturesSeed<'_> {
+    type Value = usize;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for FeaturesSeed<'_> {
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(formatter, "an array of features")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut count = 0;
+        while let Some(feature) = seq.next_element::<Value>()? {
+            self.features.blocking_send(feature)
+                .map_err(|_| de::Error::custom("the response was abandoned"))?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+/// Parse the json response read from `body`, sending each feature to `features` as soon as it
+/// is parsed. Returns the rest of the response and the size of the body.
+fn parse_response<R: Read>(body: R, features: &mpsc::Sender<Value>) -> serde_json::Result<(ResponseTrailer, usize)> {
+    let mut reader = BufReader::new(NonFiniteQuoter::new(CountingReader { inner: body, count: 0 }));
+    let mut deserializer = serde_json::Deserializer::from_reader(&mut reader);
+    let trailer = ResponseSeed { features }.deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok((trailer, reader.into_inner().inner.count))
+}
+
+/// Body chunks waiting to be parsed, read ahead of the parser.
+const BODY_CHUNKS: usize = 16;
+/// Parsed features waiting to be handled, parsed ahead of the sink.
+const PARSED_FEATURES: usize = 256;
+
+/// Json response parsed on a blocking thread from the body chunks sent to it, handing back each
+/// feature as soon as it is parsed. Only a few chunks of the body and a few features are held
+/// at a time.
+struct ResponseParser {
+    features: mpsc::Receiver<Value>,
+    parsed: JoinHandle<serde_json::Result<(ResponseTrailer, usize, bool)>>,
+}
+
+impl ResponseParser {
+    /// Start parsing a body with the `Content-Encoding`, returning the sender of its chunks.
+    fn spawn(encoding: Option<String>) -> (mpsc::Sender<Vec<u8>>, Self) {
+        let (body_sender, chunks) = mpsc::channel(BODY_CHUNKS);
+        let (feature_sender, features) = mpsc::channel(PARSED_FEATURES);
+        let parsed = tokio::task::spawn_blocking(move || {
+            // The decoders read the body as they are created, so they are made on this thread
+            let body = BodyReader { chunks, chunk: vec![], position: 0 };
+            let (body, compressed) = decoding_reader(encoding.as_deref(), body);
+            let (trailer, decoded) = parse_response(body, &feature_sender)?;
+            Ok((trailer, decoded, compressed))
+        });
+        (body_sender, Self { features, parsed })
+    }
+
+    /// Hand every feature to `sink` as it is parsed. Returns the rest of the response, the size
+    /// of the decoded body and whether it was compressed.
+    async fn drain(
+        mut self,
+        sink: &mut FeatureSink<'_, '_>,
+    ) -> Result<(ResponseTrailer, usize, bool), Box<dyn Error + Send + Sync>> {
+        while let Some(feature) = self.features.recv().await {
+            sink.push(feature).await?;
+        }
+        Ok(self.parsed.await??)
+    }
+}
+
+/// Parse the json body of `response` as it is downloaded, handing each feature to `sink`.
+async fn stream_response(
+    mut response: reqwest::Response,
+    encoding: Option<String>,
+    settings: &RequestSettings<'_>,
+    query: &str,
+    sink: &mut FeatureSink<'_, '_>,
+) -> Result<(ResponseTrailer, ResponseSize), Box<dyn Error + Send + Sync>> {
+    let RequestSettings { limiter, stall, .. } = *settings;
+    let (body_sender, parser) = ResponseParser::spawn(encoding);
+    let download = async move {
+        let mut transferred = 0;
+        while let Some(chunk) = watch_stall(response.chunk(), stall, query).await?? {
+            if let Some(limiter) = limiter {
+                limiter.consume(chunk.len()).await;
+            }
+            transferred += chunk.len();
+            if body_sender.send(chunk.to_vec()).await.is_err() {
+                // The parser stopped early, its error is returned by the drain
+                break
+            }
+        }
+        Ok::<_, Box<dyn Error + Send + Sync>>(transferred)
+    };
+    let (transferred, (trailer, decoded, compressed)) = tokio::try_join!(download, parser.drain(sink))?;
+    Ok((trailer, ResponseSize { transferred, decoded, compressed }))
+}
+
+/// Hand the features of a decoded pbf body to `sink`, one at a time. Bodies starting as a json
+/// object are parsed as json, which servers send for errors.
+async fn read_pbf_response(
+    body: Vec<u8>,
+    sink: &mut FeatureSink<'_, '_>,
+) -> Result<ResponseTrailer, Box<dyn Error + Send + Sync>> {
+    if body.starts_with(b"{") {
+        let (body_sender, parser) = ResponseParser::spawn(None);
+        // A failed send means the parser stopped, its error is returned by the drain
+        let _ = body_sender.send(body).await;
+        drop(body_sender);
+        let (trailer, _, _) = parser.drain(sink).await?;
+        return Ok(trailer)
+    }
+    let collection = PbfFeatureCollection::decode(&body)?;
+    let mut trailer = ResponseTrailer::default();
+    if collection.exceeded_transfer_limit {
+        trailer.members.insert("exceededTransferLimit".to_owned(), Value::Bool(true));
+    }
+    let mut count = 0;
+    for feature in collection.features() {
+        sink.push(feature?).await?;
+        count += 1;
+    }
+    trailer.features = Some(count);
+    Ok(trailer)
+}
+
+/// Single attempt of a query, abandoned once it runs longer than the chunk timeout.
 async fn try_query(
-    client: &Client,
+    client: &ServiceClient,
     query: &String,
-) -> Result<Map<String, Value>, Box<dyn Error + Send + Sync>> {
-    let response = client.get(query)
-        .send()
-        .await?;
-    if response.status() != 200 {
-        return Err(Box::new(RestServiceScrapingError::InvalidResponse(response.status())))
-    }
-    let json_response = response.json::<Value>()
-        .await?;
-    let json_object = json_response
-        .as_object()
-        .ok_or(
-            Box::new(
-                RestServiceScrapingError::InvalidJsonResponse(json_response.to_string())
-            )
-        )?;
-    if !json_object.contains_key("features") {
-        let erroneous_json = json_response.to_string();
-        return if json_object.contains_key("error") {
+    settings: &RequestSettings<'_>,
+    sink: &mut FeatureSink<'_, '_>,
+) -> Result<(ResponseTrailer, ResponseSize), Box<dyn Error + Send + Sync>> {
+    match settings.chunk_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, try_query_once(client, query, settings, sink))
+            .await
+            .unwrap_or_else(|_| Err(Box::new(RestServiceScrapingError::TimedOut(timeout.as_secs())))),
+        None => try_query_once(client, query, settings, sink).await,
+    }
+}
+
+/// Send `query` once and hand the features of its response to `sink` as they are parsed. Json
+/// bodies are parsed as they are downloaded, pbf bodies are read whole but their features are
+/// decoded one at a time. A failed attempt leaves the features it handed over in `sink`.
+async fn try_query_once(
+    client: &ServiceClient,
+    query: &String,
+    settings: &RequestSettings<'_>,
+    sink: &mut FeatureSink<'_, '_>,
+) -> Result<(ResponseTrailer, ResponseSize), Box<dyn Error + Send + Sync>> {
+    let RequestSettings { limiter, stall, .. } = *settings;
+    let request = client.get(query).await?.header(ACCEPT_ENCODING, ACCEPTED_ENCODINGS);
+    let response = watch_stall(request.send(), stall, query).await??;
+    if is_invalid_token_code(response.status().as_u16().into()) {
+        return Err(Box::new(RestServiceScrapingError::InvalidToken(response.status().to_string())))
+    }
+    let status = response.status();
+    let rejects_format = status == StatusCode::BAD_REQUEST && settings.format == ResponseFormat::Pbf;
+    if status != 200 && !rejects_format {
+        return Err(Box::new(RestServiceScrapingError::InvalidResponse(status)))
+    }
+    let encoding = response.headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_ascii_lowercase);
+    let (trailer, size) = if settings.format == ResponseFormat::Pbf {
+        let body = read_body(response, limiter, stall, query).await?;
+        let transferred = body.len();
+        let (body, compressed) = decode_body(encoding.as_deref(), body)?;
+        if rejects_format {
+            // Only a 400 naming the format means the server cannot write pbf, other 400s are
+            // rejected queries
+            let error = serde_json::from_slice::<Value>(&body).ok()
+                .map(|json| if json["error"].is_object() { json["error"].to_owned() } else { json })
+                .filter(is_unsupported_format_error);
+            return Err(match error {
+                Some(error) => Box::new(RestServiceScrapingError::UnsupportedFormat(error.to_string())),
+                None => Box::new(RestServiceScrapingError::InvalidResponse(status)),
+            })
+        }
+        let size = ResponseSize { transferred, decoded: body.len(), compressed };
+        (read_pbf_response(body, sink).await?, size)
+    } else {
+        stream_response(response, encoding, settings, query, sink).await?
+    };
+    if let Some(invalid) = &trailer.invalid {
+        return Err(Box::new(RestServiceScrapingError::InvalidJsonResponse(invalid.to_string())))
+    }
+    if trailer.features.is_none() {
+        let members = &trailer.members;
+        let erroneous_json = serde_json::to_string(members)?;
+        return if members.get("error").and_then(|error| error["code"].as_i64()).is_some_and(is_invalid_token_code) {
+            Err(Box::new(RestServiceScrapingError::InvalidToken(erroneous_json)))
+        } else if settings.format == ResponseFormat::Pbf && members.get("error").is_some_and(is_unsupported_format_error) {
+            Err(Box::new(RestServiceScrapingError::UnsupportedFormat(erroneous_json)))
+        } else if members.contains_key("error") {
             Err(Box::new(RestServiceScrapingError::ErrorJsonResponse(erroneous_json)))
         } else {
             Err(Box::new(RestServiceScrapingError::UnknownJsonResponse(erroneous_json)))
         }
     }
-    Ok(json_object.to_owned())
+    Ok((trailer, size))
+}
+
+/// Error of a server rejecting the `f` parameter of a query, as older servers (10.0/10.1) do for
+/// formats they cannot write. The error is a 400 whose message or details name the format.
+fn is_unsupported_format_error(error: &Value) -> bool {
+    let names_format = |text: &str| {
+        let text = text.to_ascii_lowercase();
+        text.contains("format") || text.contains("pbf")
+    };
+    error["code"].as_i64() == Some(400)
+        && (error["message"].as_str().is_some_and(names_format)
+            || error["details"].as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .any(names_format))
+}
+
+/// ArcGIS codes for an expired or invalid token (498) and a missing token (499). Servers send them
+/// as the HTTP status or as the code of a JSON error.
+fn is_invalid_token_code(code: i64) -> bool {
+    code == 498 || code == 499
 }
 
 async fn decode_fetch_error(
     attempts: &mut i32,
     error: Box<dyn Error + Send + Sync>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    println!("Request had an error...");
     match error.downcast_ref::<RestServiceScrapingError>() {
         Some(scraping_error) => {
             match scraping_error {
                 RestServiceScrapingError::InvalidResponse(code) => {
                     *attempts += 1;
-                    println!("Error Status Code: {}", code);
+                    tracing::warn!(attempt = *attempts, status = %code, "Request returned an error status code, trying again in 10 seconds");
                     tokio::time::sleep(Duration::from_secs(10)).await;
-                    println!("Trying request again");
                     Ok(())
                 }
-                RestServiceScrapingError::InvalidJsonResponse(res) => {
+                RestServiceScrapingError::InvalidJsonResponse(res)
+                | RestServiceScrapingError::ErrorJsonResponse(res)
+                | RestServiceScrapingError::UnknownJsonResponse(res) => {
                     *attempts += 1;
-                    println!("Error JSON: {}", res);
+                    tracing::warn!(attempt = *attempts, response = %res, "Request returned an error JSON, trying again in 10 seconds");
                     tokio::time::sleep(Duration::from_secs(10)).await;
-                    println!("Trying request again");
                     Ok(())
                 }
-                RestServiceScrapingError::ErrorJsonResponse(res) => {
+                RestServiceScrapingError::Stalled(_) => {
                     *attempts += 1;
-                    println!("Error JSON: {}", res);
-                    tokio::time::sleep(Duration::from_secs(10)).await;
-                    println!("Trying request again");
+                    tracing::warn!(attempt = *attempts, "Restarting stalled request");
                     Ok(())
                 }
-                RestServiceScrapingError::UnknownJsonResponse(res) => {
+                RestServiceScrapingError::TimedOut(seconds) => {
                     *attempts += 1;
-                    println!("Error JSON: {}", res);
-                    tokio::time::sleep(Duration::from_secs(10)).await;
-                    println!("Trying request again");
+                    tracing::warn!(attempt = *attempts, seconds, "Request timed out, trying again");
+                    Ok(())
+                }
+                RestServiceScrapingError::InvalidToken(_) => {
+                    *attempts += 1;
+                    tracing::warn!(attempt = *attempts, "Token rejected, trying again with a new token");
                     Ok(())
                 }
-                _ => return Err(error)
+                _ => Err(error)
             }
         }
         None => {
-            Err(error)
+            match error.downcast_ref::<reqwest::Error>() {
+                Some(request_error) if is_connection_error(request_error) => {
+                    *attempts += 1;
+                    tracing::warn!(attempt = *attempts, error = %request_error, "Connection error, trying again in 10 seconds");
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                    Ok(())
+                }
+                _ => Err(error)
+            }
         }
     }
 }
 
-async fn loop_until_successful(
-    client: &Client,
-    query: &String,
+/// Transport level failures worth retrying on a new connection.
+fn is_connection_error(error: &reqwest::Error) -> bool {
+    error.is_connect() || error.is_timeout() || error.is_request() || error.is_body()
+}
+
+pub(crate) async fn loop_until_successful(
+    connections: &HostConnections,
+    query: &str,
     max_tries: i32,
+    limiter: Option<&BandwidthLimiter>,
 ) -> Result<Map<String, Value>, Box<dyn Error + Send + Sync>> {
+    let settings = RequestSettings { limiter, ..Default::default() };
+    let mut features = vec![];
+    let (mut trailer, _) = loop_until_successful_sized(
+        connections,
+        query,
+        max_tries,
+        &settings,
+        &mut FeatureSink::Keep(&mut features),
+    ).await?;
+    if trailer.features.is_some() {
+        trailer.members.insert("features".to_owned(), Value::from(features));
+    }
+    Ok(trailer.members)
+}
+
+/// Same as [`loop_until_successful`] but hands the features to `sink` as they are parsed,
+/// returning the rest of the response and the size of the successful response body, and
+/// applies the other settings of a fetch to every request. The features of failed attempts are
+/// taken back out of `sink`.
+async fn loop_until_successful_sized(
+    connections: &HostConnections,
+    query: &str,
+    max_tries: i32,
+    settings: &RequestSettings<'_>,
+    sink: &mut FeatureSink<'_, '_>,
+) -> Result<(ResponseTrailer, ResponseSize), Box<dyn Error + Send + Sync>> {
     let mut attempts = 0;
     let result = loop {
-        match try_query(client, query).await {
+        if let Some(pacer) = settings.pacer {
+            pacer.pause().await;
+        }
+        if let Some(deadline) = settings.deadline.filter(|deadline| deadline.is_reached()) {
+            return Err(Box::new(deadline.error()))
+        }
+        let (client, generation) = connections.client();
+        let token = client.token().await?;
+        let permit = match settings.request_permits {
+            Some(permits) => Some(permits.acquire().await?),
+            None => None,
+        };
+        let request = profile::start_request(connections.profile_host());
+        let (routed_query, replica) = connections.route(query);
+        let started = Instant::now();
+        let mark = sink.mark()?;
+        let attempt = try_query(&client, &routed_query, settings, sink).await;
+        drop(permit);
+        if attempt.is_err() {
+            sink.rollback(mark)?;
+        }
+        match attempt {
+            Err(error) if error.is::<RequestLimitError>() => return Err(error),
             Err(error) => {
-                match decode_fetch_error(&mut attempts, error).await {
-                    Err(decode_error) => return Err(decode_error),
-                    Ok(_) => {},
+                if !matches!(error.downcast_ref(), Some(RestServiceScrapingError::InvalidToken(_))) {
+                    connections.record_replica_failure(replica);
+                }
+                match error.downcast_ref::<RestServiceScrapingError>() {
+                    Some(RestServiceScrapingError::InvalidResponse(
+                        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE,
+                    )) => {
+                        request.throttled();
+                        connections.record_failure(generation, false).await;
+                    }
+                    Some(RestServiceScrapingError::InvalidToken(_)) => {
+                        client.refresh_token(token.as_deref()).await?;
+                    }
+                    Some(RestServiceScrapingError::Stalled(_) | RestServiceScrapingError::TimedOut(_)) => {
+                        connections.record_failure(generation, true).await;
+                    }
+                    _ => connections.record_failure(generation, false).await,
+                }
+                let message = error.to_string();
+                decode_fetch_error(&mut attempts, error).await?;
+                if let Some(retry) = settings.retry.filter(|_| attempts < max_tries) {
+                    retry.progress.on_retry(retry.layer, retry.chunk_id, attempts, &message)?;
                 }
             }
-            Ok(obj) => break obj
+            Ok(obj) => {
+                request.succeeded();
+                connections.record_success();
+                connections.record_replica_success(replica, started.elapsed());
+                break obj
+            }
         }
         if attempts >= max_tries {
             return Err(Box::new(RestServiceScrapingError::TooManyRetires(max_tries)))
@@ -328,35 +1164,1120 @@ async fn loop_until_successful(
     Ok(result)
 }
 
-pub(crate) async fn fetch_query(
-    client: &Client,
-    query: &String,
-    fields: &Vec<RestServiceField>,
-    geo_type: &RestServiceGeometryType,
-    max_tries: i32,
-) -> Result<File, Box<dyn Error + Send + Sync>> {
-    let mut file = tempfile::tempfile()?;
+/// Encodings requested for every query response, decoded by [`decode_body`].
+#[cfg(feature = "brotli")]
+const ACCEPTED_ENCODINGS: &str = "gzip, deflate, br";
+#[cfg(not(feature = "brotli"))]
+const ACCEPTED_ENCODINGS: &str = "gzip, deflate";
+
+/// Format requested for the features of a query.
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub(crate) enum ResponseFormat {
+    /// pbf for layers listing it in their supported query formats, json otherwise
+    Auto,
+    #[default]
+    Json,
+    /// Esri's protocol buffer format, much smaller than JSON but only offered by some services
+    Pbf,
+}
+
+impl ResponseSize {
+    /// Size of two responses making up one chunk.
+    fn combine(&self, other: &ResponseSize) -> ResponseSize {
+        ResponseSize {
+            transferred: self.transferred + other.transferred,
+            decoded: self.decoded + other.decoded,
+            compressed: self.compressed && other.compressed,
+        }
+    }
+}
+
+impl Display for ResponseFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResponseFormat::Auto => write!(f, "auto"),
+            ResponseFormat::Json => write!(f, "json"),
+            ResponseFormat::Pbf => write!(f, "pbf"),
+        }
+    }
+}
+
+impl ResponseFormat {
+    /// The query with its `f` parameter set to this format.
+    fn apply(&self, query: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+        with_params(query, &[("f", &self.to_string())])
+    }
+}
 
-    let json_response_object = loop_until_successful(
-        client,
+/// The query with these parameters set, replacing any existing value.
+fn with_params(query: &str, params: &[(&str, &str)]) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let mut url = Url::parse(query)?;
+    let mut pairs: Vec<(String, String)> = url.query_pairs()
+        .filter(|(key, _)| !params.iter().any(|(name, _)| name == key))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    pairs.extend(params.iter().map(|(key, value)| (key.to_string(), value.to_string())));
+    url.query_pairs_mut().clear().extend_pairs(pairs);
+    Ok(url.to_string())
+}
+
+/// Whether the server cut the response short of the features the query asked for.
+fn exceeded_transfer_limit(response: &Map<String, Value>) -> bool {
+    response.get("exceededTransferLimit").and_then(Value::as_bool).unwrap_or_default()
+}
+
+/// Features requested by a paged query and the features its page should hold, fewer than
+/// requested when the page runs past the layer's `feature_count` features. None for queries
+/// that are not paged.
+fn expected_page_features(query: &str, feature_count: usize) -> Result<Option<(usize, usize)>, Box<dyn Error + Send + Sync>> {
+    let url = Url::parse(query)?;
+    let param = |name: &str| url.query_pairs()
+        .find(|(key, _)| key == name)
+        .and_then(|(_, value)| value.parse::<usize>().ok());
+    Ok(param("resultRecordCount").map(|requested| {
+        let offset = param("resultOffset").unwrap_or_default();
+        (requested, requested.min(feature_count.saturating_sub(offset)))
+    }))
+}
+
+/// Pages of paged queries holding fewer features than requested without the server flagging
+/// their response as truncated, counted by features requested and returned.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct ShortPages {
+    pages: BTreeMap<(usize, usize), usize>,
+}
+
+impl ShortPages {
+    fn record(&mut self, requested: usize, returned: usize) {
+        *self.pages.entry((requested, returned)).or_default() += 1;
+    }
+
+    pub(crate) fn merge(&mut self, other: &ShortPages) {
+        for (sizes, count) in &other.pages {
+            *self.pages.entry(*sizes).or_default() += count;
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.pages.values().sum()
+    }
+
+    /// Features held by the most frequent short page, the page size of a server capping them.
+    pub(crate) fn cap(&self) -> Option<usize> {
+        self.pages.iter()
+            .filter(|((_, returned), _)| *returned > 0)
+            .max_by_key(|(_, count)| **count)
+            .map(|((_, returned), _)| *returned)
+    }
+
+    /// The most frequent short pages, e.g. "1000 of 2000 features x3", a server capping its
+    /// pages showing as a single size returned.
+    pub(crate) fn patterns(&self) -> String {
+        let mut pages: Vec<_> = self.pages.iter().collect();
+        pages.sort_by(|(_, first), (_, second)| second.cmp(first));
+        pages.iter()
+            .take(3)
+            .map(|((requested, returned), count)| format!("{} of {} features x{}", returned, requested, count))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
+
+/// Halves of a query whose response was truncated after `returned` features, splitting its
+/// page, its object ids or its OID range. None when the query is not truncated (a full page of
+/// a paged query also sets exceededTransferLimit), asks for a single feature or cannot be
+/// split.
+fn split_truncated_query(query: &str, returned: usize) -> Result<Option<(String, String)>, Box<dyn Error + Send + Sync>> {
+    let url = Url::parse(query)?;
+    let param = |name: &str| url.query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned());
+    if let Some(count) = param("resultRecordCount").and_then(|count| count.parse::<usize>().ok()) {
+        if returned >= count || count < 2 {
+            return Ok(None)
+        }
+        let offset = param("resultOffset").and_then(|offset| offset.parse::<usize>().ok()).unwrap_or_default();
+        let half = count / 2;
+        return Ok(Some((
+            with_params(query, &[("resultOffset", &offset.to_string()), ("resultRecordCount", &half.to_string())])?,
+            with_params(query, &[("resultOffset", &(offset + half).to_string()), ("resultRecordCount", &(count - half).to_string())])?,
+        )))
+    }
+    if let Some(object_ids) = param("objectIds") {
+        let object_ids: Vec<&str> = object_ids.split(',').collect();
+        if returned >= object_ids.len() || object_ids.len() < 2 {
+            return Ok(None)
+        }
+        let (first, second) = object_ids.split_at(object_ids.len() / 2);
+        return Ok(Some((
+            with_params(query, &[("objectIds", &first.join(","))])?,
+            with_params(query, &[("objectIds", &second.join(","))])?,
+        )))
+    }
+    let where_clause = param("where").unwrap_or_default();
+    let oid_range = Regex::new(r"(\w+) >= (-?\d+) and (\w+) <= (-?\d+)(\)?)$")?;
+    let captures = match oid_range.captures(&where_clause) {
+        Some(captures) if captures[1] == captures[3] => captures,
+        _ => return Ok(None),
+    };
+    let (lower, upper) = (captures[2].parse::<i64>()?, captures[4].parse::<i64>()?);
+    if upper <= lower {
+        return Ok(None)
+    }
+    let middle = lower + (upper - lower) / 2;
+    let range_start = captures.get(0).map_or(0, |range| range.start());
+    let with_range = |lower: i64, upper: i64| with_params(query, &[("where", &format!(
+        "{}{} >= {} and {} <= {}{}",
+        &where_clause[..range_start],
+        &captures[1],
+        lower,
+        &captures[1],
+        upper,
+        &captures[5],
+    ))]);
+    Ok(Some((with_range(lower, middle)?, with_range(middle + 1, upper)?)))
+}
+
+/// Queries of a chunk scraped in two passes, one for the attributes without geometry and one
+/// for the geometry with only the OID field. Both are ordered by OID so that paged chunks hold
+/// the same features.
+fn split_pass_queries(query: &str, oid_field: &str) -> Result<(String, String), Box<dyn Error + Send + Sync>> {
+    let attributes_query = with_params(
         query,
-        max_tries,
+        &[("returnGeometry", "false"), ("orderByFields", oid_field)],
+    )?;
+    let geometry_query = with_params(
+        query,
+        &[("outFields", oid_field), ("returnGeometry", "true"), ("orderByFields", oid_field)],
+    )?;
+    Ok((attributes_query, geometry_query))
+}
+
+/// Features of the attributes pass of a chunk, each given the geometry of the feature with the
+/// same OID as the geometry pass is parsed. Only the attributes pass is held, the geometries
+/// are joined as they arrive.
+struct PassJoin {
+    oid_field: String,
+    features: Vec<Value>,
+    /// Position in `features` of the features not given a geometry yet, by OID
+    positions: HashMap<i64, usize>,
+    /// Features given a geometry, in the order joined
+    joined: Vec<(i64, usize)>,
+}
+
+impl PassJoin {
+    fn new(features: Vec<Value>, oid_field: &str) -> Self {
+        let positions = features.iter()
+            .enumerate()
+            .filter(|(_, feature)| feature.is_object())
+            .filter_map(|(position, feature)| Some((object_id(&feature["attributes"][oid_field])?, position)))
+            .collect();
+        Self { oid_field: oid_field.to_owned(), features, positions, joined: vec![] }
+    }
+
+    /// Feature of the attributes pass with the geometry of `geometry_feature`, None when no
+    /// feature without a geometry has its OID.
+    fn attach(&mut self, mut geometry_feature: Value) -> Option<Value> {
+        let oid = object_id(&geometry_feature["attributes"][&self.oid_field])?;
+        let geometry = geometry_feature.get_mut("geometry")?.take();
+        let position = self.positions.remove(&oid)?;
+        self.joined.push((oid, position));
+        let mut feature = self.features[position].clone();
+        feature["geometry"] = geometry;
+        Some(feature)
+    }
+
+    fn joined(&self) -> usize {
+        self.joined.len()
+    }
+
+    /// Take back the geometries joined after the first `joined`, e.g. of a response thrown away.
+    fn rollback(&mut self, joined: usize) {
+        for (oid, position) in self.joined.drain(joined..) {
+            self.positions.insert(oid, position);
+        }
+    }
+
+    /// Features of the attributes pass never given a geometry, in their order.
+    fn unjoined(self) -> Vec<Value> {
+        let joined: HashSet<usize> = self.joined.into_iter().map(|(_, position)| position).collect();
+        self.features.into_iter()
+            .enumerate()
+            .filter(|(position, _)| !joined.contains(position))
+            .map(|(_, feature)| feature)
+            .collect()
+    }
+}
+
+/// Size of a response body as transferred and once decompressed.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ResponseSize {
+    pub(crate) transferred: usize,
+    pub(crate) decoded: usize,
+    /// The server sent the body with a content encoding
+    pub(crate) compressed: bool,
+}
+
+/// Settings applied to each request of a fetch.
+#[derive(Clone, Copy, Default)]
+struct RequestSettings<'a> {
+    limiter: Option<&'a BandwidthLimiter>,
+    stall: Option<&'a StallPolicy>,
+    /// Longest a single attempt may take, from sending the request to the end of the body
+    chunk_timeout: Option<Duration>,
+    deadline: Option<&'a Deadline>,
+    pacer: Option<&'a RequestPacer>,
+    /// Permits shared by every request of the run, one held for each attempt in flight
+    request_permits: Option<&'a Semaphore>,
+    format: ResponseFormat,
+    retry: Option<RetryReport<'a>>,
+}
+
+/// Chunk a request belongs to, so its retries reach the progress reporter.
+#[derive(Clone, Copy)]
+struct RetryReport<'a> {
+    progress: &'a dyn ProgressReporter,
+    layer: LayerRef<'a>,
+    chunk_id: usize,
+}
+
+/// Settings shared by every fetch worker of a single scrape.
+#[derive(Debug, Clone)]
+pub(crate) struct FetchOptions {
+    pub(crate) fields: Vec<RestServiceField>,
+    pub(crate) geo_type: RestServiceGeometryType,
+    pub(crate) max_tries: i32,
+    pub(crate) limiter: Option<Arc<BandwidthLimiter>>,
+    /// Spaces out requests during the quiet hours
+    pub(crate) pacer: Option<Arc<RequestPacer>>,
+    /// Caps the requests in flight across every layer of the run
+    pub(crate) request_permits: Option<Arc<Semaphore>>,
+    pub(crate) transformer: FeatureTransformer,
+    /// Checks numeric fields for NaN, Infinity and out of range values
+    pub(crate) numeric_guard: NumericGuard,
+    /// Checks numeric values against the range domains of their fields
+    pub(crate) domain_guard: DomainGuard,
+    /// Checks that geometries match the layer's geometry type
+    pub(crate) geometry_guard: GeometryGuard,
+    /// Flags features at null island or outside the layer's extent
+    pub(crate) coordinate_guard: CoordinateGuard,
+    pub(crate) provenance: Option<Provenance>,
+    pub(crate) merge_layout: Option<MergeLayout>,
+    pub(crate) seen_object_ids: Option<Arc<SeenObjectIds>>,
+    /// Name and url of the layer, for progress reports
+    pub(crate) layer: String,
+    pub(crate) layer_url: String,
+    /// Features reported by the layer's count query, telling the final page of a paged scrape
+    /// from a page the server cut short
+    pub(crate) feature_count: Option<usize>,
+    pub(crate) progress: Arc<dyn ProgressReporter>,
+    pub(crate) stall: Option<StallPolicy>,
+    /// Abandon and retry a chunk query attempt running longer than this
+    pub(crate) chunk_timeout: Option<Duration>,
+    pub(crate) connections: Arc<HostConnections>,
+    /// Also collect the features for a PMTiles archive
+    pub(crate) tiles: Option<TileOutput>,
+    /// Attach the grid cell of each feature's centroid
+    pub(crate) cell_indexer: Option<CellIndexer>,
+    /// Attach the geodesic area or length of each feature
+    pub(crate) measurer: Option<Measurer>,
+    /// Download the attachments of each feature, attaching the directory holding them
+    pub(crate) attachments: Option<AttachmentDownloader>,
+    /// Also collect the features for these shared boundary topologies
+    pub(crate) topology: Vec<Arc<TopologySink>>,
+    /// Also load the records into PostGIS
+    pub(crate) postgis: Option<PostgisRecords>,
+    /// Also keep typed rows of the records for a GeoParquet file
+    pub(crate) parquet: Option<Arc<ParquetLayout>>,
+    /// Also encode the features for a FlatGeobuf file
+    pub(crate) flatgeobuf: Option<Arc<FlatgeobufLayout>>,
+    /// Also encode the features for a shapefile
+    pub(crate) shapefile: Option<Arc<ShapefileLayout>>,
+    /// Also keep the records and geometries for a file written through OGR
+    pub(crate) ogr: Option<Arc<OgrLayout>>,
+    /// Write the records into one file per value of this field
+    pub(crate) split_by: Option<String>,
+    /// Orientation of the polygon rings written to the records
+    pub(crate) ring_winding: RingWinding,
+    /// Encoding of the geometry columns of the records
+    pub(crate) geometry_encoding: GeometryEncoding,
+    /// Classify polygon rings by nesting instead of trusting the server's winding
+    pub(crate) fix_winding: bool,
+    /// Split geometries crossing the antimeridian, only set for layers output in WGS84
+    pub(crate) fix_antimeridian: bool,
+    /// Where the records of each chunk are written
+    pub(crate) spool: ChunkSpool,
+    /// No query is started once reached
+    pub(crate) deadline: Option<Deadline>,
+    /// Scrape the attributes and the geometry in separate queries, joined by this OID field
+    pub(crate) split_passes: Option<String>,
+    /// Format requested for the features, only pbf when the layer supports it. Chunks whose
+    /// pbf response cannot be decoded are fetched again as json
+    pub(crate) format: ResponseFormat,
+    /// Set once the server rejects pbf queries, so the remaining chunks are fetched as json
+    pub(crate) pbf_rejected: Arc<AtomicBool>,
+}
+
+impl FetchOptions {
+    /// Chunks spooled by an earlier run can be reused, unless sinks only filled by fetched
+    /// features (tiles, topology, GeoParquet rows, FlatGeobuf features, shapefile records, OGR
+    /// features, seen OIDs) or partitions are part of the scrape.
+    pub(crate) fn reuses_spooled_chunks(&self) -> bool {
+        self.tiles.is_none()
+            && self.topology.is_empty()
+            && self.parquet.is_none()
+            && self.flatgeobuf.is_none()
+            && self.shapefile.is_none()
+            && self.ogr.is_none()
+            && self.seen_object_ids.is_none()
+            && self.split_by.is_none()
+    }
+}
+
+/// Records of a single chunk query written to its spooled chunk file, along with the features kept for
+/// tiles and the topology until the chunk is accepted.
+#[derive(Debug)]
+pub(crate) struct FetchedChunk {
+    pub(crate) file: File,
+    /// Records by value of the split field, instead of in `file`, when splitting
+    pub(crate) partitions: BTreeMap<String, File>,
+    pub(crate) feature_count: usize,
+    pub(crate) bytes_downloaded: usize,
+    pub(crate) response_size: ResponseSize,
+    pub(crate) tile_features: Vec<TileFeature>,
+    pub(crate) topology_features: Vec<TopologyFeature>,
+    pub(crate) parquet_rows: ParquetRows,
+    pub(crate) flatgeobuf_features: Vec<EncodedFeature>,
+    pub(crate) shapefile_records: Vec<ShapefileRecord>,
+    pub(crate) ogr_features: OgrFeatures,
+    pub(crate) short_pages: ShortPages,
+    pub(crate) numeric_anomalies: NumericAnomalies,
+    pub(crate) domain_violations: DomainViolations,
+    pub(crate) geometry_mismatches: GeometryMismatches,
+    pub(crate) suspicious_coordinates: SuspiciousCoordinates,
+}
+
+impl FetchedChunk {
+    /// Chunk completed by an earlier run, read back from its spooled records. Its features are
+    /// not checked again, so none of their anomalies or mismatches are counted.
+    pub(crate) fn resumed(file: File) -> io::Result<FetchedChunk> {
+        let feature_count = count_records(BufReader::new(&file))?;
+        Ok(FetchedChunk {
+            file,
+            partitions: BTreeMap::new(),
+            feature_count,
+            bytes_downloaded: 0,
+            response_size: ResponseSize::default(),
+            tile_features: vec![],
+            topology_features: vec![],
+            parquet_rows: ParquetRows::default(),
+            flatgeobuf_features: vec![],
+            shapefile_records: vec![],
+            ogr_features: OgrFeatures::default(),
+            short_pages: ShortPages::default(),
+            numeric_anomalies: NumericAnomalies::default(),
+            domain_violations: DomainViolations::default(),
+            geometry_mismatches: GeometryMismatches::default(),
+            suspicious_coordinates: SuspiciousCoordinates::default(),
+        })
+    }
+
+    /// Size of the records written for the chunk.
+    pub(crate) fn records_size(&self) -> io::Result<u64> {
+        let mut size = self.file.metadata()?.len();
+        for partition_file in self.partitions.values() {
+            size += partition_file.metadata()?.len();
+        }
+        Ok(size)
+    }
+}
+
+fn polygon_geometry<'a>(
+    geo_type: &RestServiceGeometryType,
+    feature: &'a mut Map<String, Value>,
+) -> Option<&'a mut Map<String, Value>> {
+    if *geo_type != RestServiceGeometryType::Polygon {
+        return None
+    }
+    feature.get_mut("geometry")?.as_object_mut()
+}
+
+/// Where the features of a response go as they are parsed.
+enum FeatureSink<'a, 'b> {
+    /// Kept for the caller, which reads them back with the rest of the response
+    Keep(&'a mut Vec<Value>),
+    /// Written to the records of a chunk
+    Chunk(&'a mut ChunkBuilder<'b>),
+    /// Features of a geometry pass, whose geometries are given to the features of the
+    /// attributes pass before they are written to the records of a chunk
+    Join(&'a mut PassJoin, &'a mut ChunkBuilder<'b>),
+}
+
+/// What a sink held before a response, restored when the response is thrown away.
+struct SinkMark {
+    kept: usize,
+    chunk: Option<ChunkMark>,
+}
+
+impl FeatureSink<'_, '_> {
+    async fn push(&mut self, feature: Value) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match self {
+            FeatureSink::Keep(features) => features.push(feature),
+            FeatureSink::Chunk(chunk) => chunk.push(feature).await?,
+            FeatureSink::Join(join, chunk) => {
+                if let Some(feature) = join.attach(feature) {
+                    chunk.push(feature).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn mark(&mut self) -> io::Result<SinkMark> {
+        let mark = match self {
+            FeatureSink::Keep(features) => SinkMark { kept: features.len(), chunk: None },
+            FeatureSink::Chunk(chunk) => SinkMark { kept: 0, chunk: Some(chunk.mark()?) },
+            FeatureSink::Join(join, chunk) => SinkMark { kept: join.joined(), chunk: Some(chunk.mark()?) },
+        };
+        Ok(mark)
+    }
+
+    fn rollback(&mut self, mark: SinkMark) -> io::Result<()> {
+        match self {
+            FeatureSink::Keep(features) => features.truncate(mark.kept),
+            FeatureSink::Chunk(chunk) => {
+                if let Some(chunk_mark) = mark.chunk {
+                    chunk.rollback(chunk_mark)?;
+                }
+            }
+            FeatureSink::Join(join, chunk) => {
+                join.rollback(mark.kept);
+                if let Some(chunk_mark) = mark.chunk {
+                    chunk.rollback(chunk_mark)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Response of a chunk query in the fetch's format, fetched again as json when a pbf response
+/// cannot be decoded or the server rejects the pbf format. A rejected format switches the rest
+/// of the layer to json.
+async fn fetch_response(
+    query: &str,
+    chunk_id: usize,
+    options: &FetchOptions,
+    settings: &RequestSettings<'_>,
+    sink: &mut FeatureSink<'_, '_>,
+) -> Result<(ResponseTrailer, ResponseSize), Box<dyn Error + Send + Sync>> {
+    let json_settings = RequestSettings { format: ResponseFormat::Json, ..*settings };
+    match options.format {
+        ResponseFormat::Pbf if options.pbf_rejected.load(Ordering::Relaxed) => {
+            loop_until_successful_sized(&options.connections, query, options.max_tries, &json_settings, sink).await
+        }
+        ResponseFormat::Pbf => {
+            let pbf_query = ResponseFormat::Pbf.apply(query)?;
+            match loop_until_successful_sized(&options.connections, &pbf_query, options.max_tries, settings, sink).await {
+                Err(error) if error.is::<PbfError>() => {
+                    report::warn(format_args!(
+                        "{}, fetching chunk {} as json",
+                        error,
+                        chunk_id,
+                    ));
+                    loop_until_successful_sized(&options.connections, query, options.max_tries, &json_settings, sink).await
+                }
+                Err(error) if matches!(
+                    error.downcast_ref(),
+                    Some(RestServiceScrapingError::UnsupportedFormat(_)),
+                ) => {
+                    profile::observe_pbf(options.connections.profile_host(), false);
+                    if !options.pbf_rejected.swap(true, Ordering::Relaxed) {
+                        report::warn_about(WarningKind::General, &options.layer, format_args!(
+                            "{} rejected pbf queries, fetching the remaining chunks as json. {}",
+                            options.layer,
+                            error,
+                        ));
+                    }
+                    loop_until_successful_sized(&options.connections, query, options.max_tries, &json_settings, sink).await
+                }
+                result => {
+                    if result.is_ok() {
+                        profile::observe_pbf(options.connections.profile_host(), true);
+                    }
+                    result
+                }
+            }
+        }
+        _ => loop_until_successful_sized(&options.connections, query, options.max_tries, settings, sink).await,
+    }
+}
+
+/// Response of a chunk query written to `chunk`, scraped in two passes joined by OID when the
+/// fetch splits them. The attributes pass is fetched first and held, so the geometry pass can
+/// be joined to it as it is parsed.
+async fn fetch_passes(
+    query: &str,
+    chunk_id: usize,
+    options: &FetchOptions,
+    settings: &RequestSettings<'_>,
+    chunk: &mut ChunkBuilder<'_>,
+) -> Result<(ResponseTrailer, ResponseSize), Box<dyn Error + Send + Sync>> {
+    let oid_field = match &options.split_passes {
+        Some(oid_field) => oid_field,
+        None => return fetch_response(query, chunk_id, options, settings, &mut FeatureSink::Chunk(chunk)).await,
+    };
+    let (attributes_query, geometry_query) = split_pass_queries(query, oid_field)?;
+    let mut features = vec![];
+    let (mut trailer, attributes_size) = fetch_response(
+        &attributes_query,
+        chunk_id,
+        options,
+        settings,
+        &mut FeatureSink::Keep(&mut features),
     ).await?;
-    let features = json_response_object["features"].as_array().unwrap();
-    for feature_value in features {
-        let feature = feature_value.as_object()
-            .ok_or(
-                Box::new(
-                    RestServiceScrapingError::InvalidFeature(feature_value.to_string())
-                )
-            )?;
-        let record = handle_record(fields, geo_type, feature)?;
+    let mut join = PassJoin::new(features, oid_field);
+    let (geometry_trailer, geometry_size) = fetch_response(
+        &geometry_query,
+        chunk_id,
+        options,
+        settings,
+        &mut FeatureSink::Join(&mut join, chunk),
+    ).await?;
+    let unjoined = join.unjoined();
+    if !unjoined.is_empty() {
+        report::warn_about(WarningKind::DroppedFeatures, &options.layer, format_args!(
+            "{} features of chunk {} were missing from the geometry pass",
+            unjoined.len(),
+            chunk_id,
+        ));
+    }
+    for feature in unjoined {
+        chunk.push(feature).await?;
+    }
+    if exceeded_transfer_limit(&geometry_trailer.members) {
+        trailer.members.insert("exceededTransferLimit".to_owned(), Value::Bool(true));
+    }
+    Ok((trailer, attributes_size.combine(&geometry_size)))
+}
+
+/// Write every feature the chunk query asks for to `chunk`, returning the size of its responses
+/// and its short pages. The features of responses the server truncated are taken back out and
+/// the halves of their query fetched instead, until no response is truncated or the query
+/// cannot be split further. Pages holding fewer features than requested are taken as truncated,
+/// unless they are the final page of the layer's features.
+async fn fetch_complete_response(
+    query: &String,
+    chunk_id: usize,
+    options: &FetchOptions,
+    settings: &RequestSettings<'_>,
+    chunk: &mut ChunkBuilder<'_>,
+) -> Result<(ResponseSize, ShortPages), Box<dyn Error + Send + Sync>> {
+    let mut pending = vec![query.to_owned()];
+    let mut total_size: Option<ResponseSize> = None;
+    let mut short_pages = ShortPages::default();
+    while let Some(query) = pending.pop() {
+        let mark = chunk.mark()?;
+        let (trailer, size) = fetch_passes(&query, chunk_id, options, settings, chunk).await?;
+        total_size = Some(total_size.map_or(size, |total| total.combine(&size)));
+        let returned = trailer.features.unwrap_or_default();
+        let flagged = exceeded_transfer_limit(&trailer.members);
+        let expected = match options.feature_count {
+            Some(feature_count) if !flagged => expected_page_features(&query, feature_count)?,
+            _ => None,
+        };
+        let short_page = expected.filter(|(_, expected)| returned < *expected);
+        if let Some((requested, _)) = short_page {
+            short_pages.record(requested, returned);
+        }
+        if flagged || short_page.is_some() {
+            if let Some((first, second)) = split_truncated_query(&query, returned)? {
+                chunk.rollback(mark)?;
+                match short_page {
+                    Some((_, expected)) => tracing::info!(
+                        returned,
+                        expected,
+                        "Chunk returned fewer features than expected without being flagged as truncated, fetching it in halves",
+                    ),
+                    None => tracing::info!(returned, "Chunk was truncated, fetching it in halves"),
+                }
+                pending.extend([second, first]);
+            }
+        }
+    }
+    Ok((total_size.unwrap_or_default(), short_pages))
+}
+
+/// Fetch the chunk of `query` in a span naming its layer and chunk id, so the events of its
+/// requests and retries say which chunk they belong to.
+pub(crate) async fn fetch_query(
+    query: &String,
+    chunk_id: usize,
+    options: &FetchOptions,
+) -> Result<FetchedChunk, Box<dyn Error + Send + Sync>> {
+    let span = tracing::info_span!("query", layer = %options.layer, chunk_id);
+    async {
+        let started = Instant::now();
+        match fetch_chunk(query, chunk_id, options).await {
+            Ok(chunk) => {
+                tracing::debug!(
+                    features = chunk.feature_count,
+                    bytes = chunk.bytes_downloaded,
+                    elapsed_ms = started.elapsed().as_millis() as u64,
+                    "Fetched chunk",
+                );
+                Ok(chunk)
+            }
+            Err(error) if error.is::<DeadlineError>() || error.is::<RequestLimitError>() => Err(error),
+            Err(error) => {
+                tracing::error!(%error, query = %query, "Fetch worker failed");
+                Err(error)
+            }
+        }
+    }.instrument(span).await
+}
+
+async fn fetch_chunk(
+    query: &String,
+    chunk_id: usize,
+    options: &FetchOptions,
+) -> Result<FetchedChunk, Box<dyn Error + Send + Sync>> {
+    let settings = RequestSettings {
+        limiter: options.limiter.as_deref(),
+        stall: options.stall.as_ref(),
+        chunk_timeout: options.chunk_timeout,
+        deadline: options.deadline.as_ref(),
+        pacer: options.pacer.as_deref(),
+        request_permits: options.request_permits.as_deref(),
+        format: options.format,
+        retry: Some(RetryReport {
+            progress: options.progress.as_ref(),
+            layer: LayerRef { url: &options.layer_url, name: &options.layer },
+            chunk_id,
+        }),
+    };
+    let mut chunk = ChunkBuilder::new(chunk_id, options)?;
+    let (response_size, short_pages) = fetch_complete_response(query, chunk_id, options, &settings, &mut chunk).await?;
+    Ok(chunk.finish(response_size, short_pages)?)
+}
+
+/// Records of a chunk written to its spooled chunk file, and the features kept for its other
+/// outputs, filled one feature at a time as its responses are parsed.
+struct ChunkBuilder<'a> {
+    options: &'a FetchOptions,
+    chunk_id: usize,
+    provenance_values: Vec<String>,
+    spooled: SpooledChunk,
+    spooled_partitions: BTreeMap<String, SpooledChunk>,
+    feature_count: usize,
+    tile_features: Vec<TileFeature>,
+    topology_features: Vec<TopologyFeature>,
+    parquet_rows: ParquetRows,
+    flatgeobuf_features: Vec<EncodedFeature>,
+    shapefile_records: Vec<ShapefileRecord>,
+    ogr_features: OgrFeatures,
+    numeric_anomalies: NumericAnomalies,
+    domain_violations: DomainViolations,
+    geometry_mismatches: GeometryMismatches,
+    suspicious_coordinates: SuspiciousCoordinates,
+    /// OIDs first seen in this chunk, forgotten again when the response holding them is thrown
+    /// away
+    sighted: Vec<i64>,
+}
+
+/// What a chunk held before a response was written to it, restored when the response is thrown
+/// away (a failed attempt or a truncated page).
+struct ChunkMark {
+    records: u64,
+    partitions: BTreeMap<String, u64>,
+    feature_count: usize,
+    tile_features: usize,
+    topology_features: usize,
+    parquet_rows: usize,
+    flatgeobuf_features: usize,
+    shapefile_records: usize,
+    ogr_features: usize,
+    numeric_anomalies: NumericAnomalies,
+    domain_violations: DomainViolations,
+    geometry_mismatches: GeometryMismatches,
+    suspicious_coordinates: SuspiciousCoordinates,
+    sighted: usize,
+}
+
+/// Cut `file` back to `length` bytes, writing on from there.
+fn truncate_file(file: &mut File, length: u64) -> io::Result<()> {
+    file.set_len(length)?;
+    file.seek(SeekFrom::Start(length))?;
+    Ok(())
+}
+
+impl<'a> ChunkBuilder<'a> {
+    fn new(chunk_id: usize, options: &'a FetchOptions) -> io::Result<Self> {
+        let provenance_values = options.provenance
+            .as_ref()
+            .map(|provenance| provenance.values(chunk_id))
+            .unwrap_or_default();
+        Ok(Self {
+            options,
+            chunk_id,
+            provenance_values,
+            spooled: options.spool.begin(chunk_id, None)?,
+            spooled_partitions: BTreeMap::new(),
+            feature_count: 0,
+            tile_features: vec![],
+            topology_features: vec![],
+            parquet_rows: ParquetRows::default(),
+            flatgeobuf_features: vec![],
+            shapefile_records: vec![],
+            ogr_features: OgrFeatures::default(),
+            numeric_anomalies: NumericAnomalies::default(),
+            domain_violations: DomainViolations::default(),
+            geometry_mismatches: GeometryMismatches::default(),
+            suspicious_coordinates: SuspiciousCoordinates::default(),
+            sighted: vec![],
+        })
+    }
+
+    /// Check, transform and write a single feature, keeping it for the other outputs.
+    async fn push(&mut self, mut feature_value: Value) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let options = self.options;
+        let feature = match feature_value.as_object_mut() {
+            Some(feature) => feature,
+            None => return Err(Box::new(RestServiceScrapingError::InvalidFeature(feature_value.to_string()))),
+        };
+        if let Some(attributes) = feature.get_mut("attributes").and_then(Value::as_object_mut) {
+            if let Some(seen_object_ids) = &options.seen_object_ids {
+                if !seen_object_ids.first_sighting(attributes) {
+                    return Ok(())
+                }
+                self.sighted.extend(seen_object_ids.object_id(attributes));
+            }
+            options.numeric_guard.apply(attributes, &mut self.numeric_anomalies)?;
+            options.domain_guard.apply(attributes, &mut self.domain_violations);
+            options.transformer.apply(attributes);
+        }
+        if !options.geometry_guard.apply(feature, &mut self.geometry_mismatches)? {
+            return Ok(())
+        }
+        if !options.coordinate_guard.apply(feature, &mut self.suspicious_coordinates) {
+            return Ok(())
+        }
+        if options.fix_antimeridian {
+            if let Some(geometry) = feature.get_mut("geometry").and_then(Value::as_object_mut) {
+                fix_antimeridian(geometry);
+            }
+        }
+        if options.fix_winding {
+            if let Some(geometry) = polygon_geometry(&options.geo_type, feature) {
+                rewind_rings(geometry, &RingWinding::Esri, true);
+            }
+        }
+        let cell = options.cell_indexer.as_ref().map(|indexer| {
+            let cell = indexer.index(feature);
+            if let Some(attributes) = feature.get_mut("attributes").and_then(Value::as_object_mut) {
+                attributes.insert(indexer.kind.column().to_owned(), cell.clone().map_or(Value::Null, Value::from));
+            }
+            cell.unwrap_or_default()
+        });
+        let measurement = options.measurer.as_ref().map(|measurer| {
+            let measurement = measurer.measure(feature);
+            if let Some(attributes) = feature.get_mut("attributes").and_then(Value::as_object_mut) {
+                attributes.insert(measurer.measure.column(), measurement.map_or(Value::Null, Value::from));
+            }
+            measurement.map(|value| value.to_string()).unwrap_or_default()
+        });
+        let attachments = match &options.attachments {
+            Some(downloader) => {
+                let directory = downloader.download(&options.connections, options.request_permits.as_deref(), feature).await;
+                if let Some(attributes) = feature.get_mut("attributes").and_then(Value::as_object_mut) {
+                    attributes.insert(ATTACHMENTS_COLUMN.to_owned(), Value::from(directory.as_str()));
+                }
+                Some(directory)
+            }
+            None => None,
+        };
+        if let Some(tiles) = &options.tiles {
+            let tile_feature = TileFeature::from_esri_json(feature, &options.geo_type, tiles.spatial_reference);
+            self.tile_features.extend(tile_feature);
+        }
+        if !options.topology.is_empty() {
+            self.topology_features.extend(TopologyFeature::from_esri_json(feature, &options.geo_type));
+        }
+        if options.ring_winding != RingWinding::Esri {
+            if let Some(geometry) = polygon_geometry(&options.geo_type, feature) {
+                rewind_rings(geometry, &options.ring_winding, false);
+            }
+        }
+        let partition = options.split_by.as_ref().map(|field| partition_value(feature, field));
+        let mut record = handle_record(&options.fields, &options.geo_type, options.geometry_encoding, feature)?;
+        record.extend(self.provenance_values.iter().cloned());
+        record.extend(cell);
+        record.extend(measurement);
+        record.extend(attachments);
+        if let Some(layout) = &options.merge_layout {
+            record = layout.arrange(record);
+        }
+        if let Some(layout) = &options.parquet {
+            layout.push(&mut self.parquet_rows, &record, feature);
+        }
+        if let Some(layout) = &options.flatgeobuf {
+            self.flatgeobuf_features.push(layout.encode(&record, feature));
+        }
+        if let Some(layout) = &options.shapefile {
+            self.shapefile_records.push(layout.encode(&record, feature));
+        }
+        if let Some(layout) = &options.ogr {
+            layout.push(&mut self.ogr_features, &record, feature);
+        }
         let record_transformed = record.iter()
-            .map(|value| handle_csv_value(value))
+            .map(handle_csv_value)
             .collect::<Vec<String>>()
             .join(",");
-        writeln!(&mut file, "{}", record_transformed)?;
+        let records_file = match partition {
+            Some(value) => {
+                if !self.spooled_partitions.contains_key(&value) {
+                    let partition = options.spool.begin(self.chunk_id, Some(&value))?;
+                    self.spooled_partitions.insert(value.to_owned(), partition);
+                }
+                &mut self.spooled_partitions.get_mut(&value).unwrap().file
+            }
+            None => &mut self.spooled.file,
+        };
+        writeln!(records_file, "{}", record_transformed)?;
+        self.feature_count += 1;
+        Ok(())
+    }
+
+    fn mark(&mut self) -> io::Result<ChunkMark> {
+        let mut partitions = BTreeMap::new();
+        for (value, partition) in self.spooled_partitions.iter_mut() {
+            partitions.insert(value.to_owned(), partition.file.stream_position()?);
+        }
+        Ok(ChunkMark {
+            records: self.spooled.file.stream_position()?,
+            partitions,
+            feature_count: self.feature_count,
+            tile_features: self.tile_features.len(),
+            topology_features: self.topology_features.len(),
+            parquet_rows: self.parquet_rows.len(),
+            flatgeobuf_features: self.flatgeobuf_features.len(),
+            shapefile_records: self.shapefile_records.len(),
+            ogr_features: self.ogr_features.len(),
+            numeric_anomalies: self.numeric_anomalies.clone(),
+            domain_violations: self.domain_violations.clone(),
+            geometry_mismatches: self.geometry_mismatches.clone(),
+            suspicious_coordinates: self.suspicious_coordinates.clone(),
+            sighted: self.sighted.len(),
+        })
+    }
+
+    /// Take out everything written since `mark`.
+    fn rollback(&mut self, mark: ChunkMark) -> io::Result<()> {
+        truncate_file(&mut self.spooled.file, mark.records)?;
+        for (value, mut partition) in std::mem::take(&mut self.spooled_partitions) {
+            match mark.partitions.get(&value) {
+                Some(length) => {
+                    truncate_file(&mut partition.file, *length)?;
+                    self.spooled_partitions.insert(value, partition);
+                }
+                None => partition.discard()?,
+            }
+        }
+        self.feature_count = mark.feature_count;
+        self.tile_features.truncate(mark.tile_features);
+        self.topology_features.truncate(mark.topology_features);
+        self.parquet_rows.truncate(mark.parquet_rows);
+        self.flatgeobuf_features.truncate(mark.flatgeobuf_features);
+        self.shapefile_records.truncate(mark.shapefile_records);
+        self.ogr_features.truncate(mark.ogr_features);
+        self.numeric_anomalies = mark.numeric_anomalies;
+        self.domain_violations = mark.domain_violations;
+        self.geometry_mismatches = mark.geometry_mismatches;
+        self.suspicious_coordinates = mark.suspicious_coordinates;
+        if let Some(seen_object_ids) = &self.options.seen_object_ids {
+            seen_object_ids.forget(&self.sighted[mark.sighted..]);
+        }
+        self.sighted.truncate(mark.sighted);
+        Ok(())
+    }
+
+    fn finish(self, response_size: ResponseSize, short_pages: ShortPages) -> io::Result<FetchedChunk> {
+        let file = self.spooled.complete()?;
+        let mut partitions = BTreeMap::new();
+        for (value, partition) in self.spooled_partitions {
+            partitions.insert(value, partition.complete()?);
+        }
+        Ok(FetchedChunk {
+            file,
+            partitions,
+            feature_count: self.feature_count,
+            bytes_downloaded: response_size.transferred,
+            response_size,
+            tile_features: self.tile_features,
+            topology_features: self.topology_features,
+            parquet_rows: self.parquet_rows,
+            flatgeobuf_features: self.flatgeobuf_features,
+            shapefile_records: self.shapefile_records,
+            ogr_features: self.ogr_features,
+            short_pages,
+            numeric_anomalies: self.numeric_anomalies,
+            domain_violations: self.domain_violations,
+            geometry_mismatches: self.geometry_mismatches,
+            suspicious_coordinates: self.suspicious_coordinates,
+        })
+    }
+}
+
+#[cfg(test)]
+mod scraping_tests {
+    use std::io::{Read, Write};
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use serde_json::json;
+    use tokio::sync::mpsc;
+    use super::{
+        count_records, decode_body, expected_page_features, handle_csv_value, is_unsupported_format_error,
+        parse_output_format, parse_response, split_truncated_query, NonFiniteQuoter, OutputFormat, OutputFormatError,
+        PassJoin, ResponseFormat,
+    };
+    use reqwest::Url;
+
+    #[test]
+    fn non_finite_quoter_should_skip_tokens_inside_strings() {
+        let quoted = |body: &[u8]| {
+            let mut quoted = String::new();
+            NonFiniteQuoter::new(body).read_to_string(&mut quoted).unwrap();
+            quoted
+        };
+        assert_eq!(
+            quoted(br#"{"a": NaN, "b": [-Infinity, Infinity, -1e-5], "c": "NaN \" NaN"}"#),
+            r#"{"a": "NaN", "b": ["-Infinity", "Infinity", -1e-5], "c": "NaN \" NaN"}"#,
+        );
+        assert_eq!(quoted(b"[1,NaN"), r#"[1,"NaN""#);
+    }
+
+    #[test]
+    fn parse_response_should_send_each_feature_when_trailing_members_follow() {
+        let body = br#"{"objectIdFieldName": "OBJECTID", "features": [
+            {"attributes": {"OBJECTID": 1, "VAL": NaN}},
+            {"attributes": {"OBJECTID": 2, "VAL": 1.5}}
+        ], "exceededTransferLimit": true}"#;
+        let (sender, mut receiver) = mpsc::channel(4);
+        let (trailer, decoded) = parse_response(&body[..], &sender).unwrap();
+        assert_eq!(trailer.features, Some(2));
+        assert_eq!(trailer.members["exceededTransferLimit"], json!(true));
+        assert_eq!(decoded, body.len());
+        assert_eq!(receiver.try_recv().unwrap(), json!({"attributes": {"OBJECTID": 1, "VAL": "NaN"}}));
+        assert_eq!(receiver.try_recv().unwrap(), json!({"attributes": {"OBJECTID": 2, "VAL": 1.5}}));
+        assert!(receiver.try_recv().is_err());
+        let (trailer, _) = parse_response(&br#"{"error": {"code": 400}}"#[..], &sender).unwrap();
+        assert_eq!((trailer.features, trailer.members["error"]["code"].as_i64()), (None, Some(400)));
+        let (trailer, _) = parse_response(&br#""Service unavailable""#[..], &sender).unwrap();
+        assert_eq!(trailer.invalid, Some(json!("Service unavailable")));
+    }
+
+    #[test]
+    fn count_records_should_skip_line_breaks_when_inside_quoted_values() {
+        let records = format!("1,{}\n2,\"\"\n3,plain\n", handle_csv_value(&"a\n\"b\"\r\nc".to_owned()));
+        assert_eq!(count_records(records.as_bytes()).unwrap(), 3);
+    }
+
+    #[test]
+    fn decode_body_should_decompress_when_encoding_is_gzip() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(br#"{"features": []}"#).unwrap();
+        let body = encoder.finish().unwrap();
+        assert_eq!(decode_body(Some("gzip"), body).unwrap(), (br#"{"features": []}"#.to_vec(), true));
+        assert_eq!(decode_body(None, b"{}".to_vec()).unwrap(), (b"{}".to_vec(), false));
+    }
+
+    #[test]
+    fn parse_output_format_should_keep_driver_when_prefixed_with_ogr() {
+        assert_eq!(parse_output_format("Shp"), Ok(OutputFormat::Shp));
+        assert_eq!(parse_output_format("ogr:MapInfo File"), Ok(OutputFormat::Ogr("MapInfo File".to_owned())));
+        assert_eq!(parse_output_format("ogr:"), Err(OutputFormatError::Unknown("ogr:".to_owned())));
+        assert_eq!(OutputFormat::Ogr("GML".to_owned()).to_string(), "ogr:GML");
+    }
+
+    #[test]
+    fn apply_should_replace_format_parameter_when_format_is_pbf() {
+        let query = ResponseFormat::Pbf.apply("https://example.com/0/query?where=1%3D1&f=json").unwrap();
+        assert_eq!(query, "https://example.com/0/query?where=1%3D1&f=pbf");
+    }
+
+    #[test]
+    fn pass_join_should_attach_geometry_by_oid_when_passes_differ() {
+        let mut join = PassJoin::new(vec![
+            json!({"attributes": {"OBJECTID": 1, "NAME": "a"}}),
+            json!({"attributes": {"OBJECTID": 2, "NAME": "b"}}),
+            json!({"attributes": {"OBJECTID": 3, "NAME": "c"}}),
+        ], "OBJECTID");
+        let geometry = |oid: i64| json!({"attributes": {"OBJECTID": oid}, "geometry": {"x": oid, "y": 3.0}});
+        assert_eq!(
+            join.attach(geometry(2)),
+            Some(json!({"attributes": {"OBJECTID": 2, "NAME": "b"}, "geometry": {"x": 2, "y": 3.0}})),
+        );
+        assert_eq!(join.attach(geometry(2)), None);
+        assert_eq!(join.attach(geometry(4)), None);
+        let joined = join.joined();
+        assert!(join.attach(geometry(3)).is_some());
+        join.rollback(joined);
+        assert_eq!(
+            join.unjoined(),
+            vec![json!({"attributes": {"OBJECTID": 1, "NAME": "a"}}), json!({"attributes": {"OBJECTID": 3, "NAME": "c"}})],
+        );
+    }
+
+    #[test]
+    fn is_unsupported_format_error_should_match_when_details_name_the_format() {
+        assert!(is_unsupported_format_error(&json!({
+            "code": 400,
+            "message": "Unable to complete operation.",
+            "details": ["Invalid format 'pbf'"],
+        })));
+        assert!(!is_unsupported_format_error(&json!({
+            "code": 400,
+            "message": "Unable to perform query. Invalid query parameters.",
+            "details": [],
+        })));
+        assert!(!is_unsupported_format_error(&json!({"code": 500, "message": "Invalid format", "details": []})));
+    }
+
+    #[test]
+    fn split_truncated_query_should_halve_page_or_oid_range_when_truncated() {
+        let param = |query: &str, name: &str| Url::parse(query).unwrap()
+            .query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+            .unwrap();
+        let paged = "https://example.com/0/query?where=1%3D1&resultOffset=2000&resultRecordCount=1000";
+        let (first, second) = split_truncated_query(paged, 300).unwrap().unwrap();
+        assert_eq!((param(&first, "resultOffset"), param(&first, "resultRecordCount")), ("2000".to_owned(), "500".to_owned()));
+        assert_eq!((param(&second, "resultOffset"), param(&second, "resultRecordCount")), ("2500".to_owned(), "500".to_owned()));
+        assert!(split_truncated_query(paged, 1000).unwrap().is_none());
+
+        let ranged = "https://example.com/0/query?where=%28CODE%3D1%29+and+%28OBJECTID+%3E%3D+1+and+OBJECTID+%3C%3D+1000%29";
+        let (first, second) = split_truncated_query(ranged, 300).unwrap().unwrap();
+        assert_eq!(param(&first, "where"), "(CODE=1) and (OBJECTID >= 1 and OBJECTID <= 500)");
+        assert_eq!(param(&second, "where"), "(CODE=1) and (OBJECTID >= 501 and OBJECTID <= 1000)");
+
+        let object_ids = "https://example.com/0/query?objectIds=1%2C2%2C3";
+        let (first, second) = split_truncated_query(object_ids, 1).unwrap().unwrap();
+        assert_eq!((param(&first, "objectIds"), param(&second, "objectIds")), ("1".to_owned(), "2,3".to_owned()));
+        assert!(split_truncated_query("https://example.com/0/query?geometry=1%2C2%2C3%2C4", 10).unwrap().is_none());
+    }
+
+    #[test]
+    fn expected_page_features_should_allow_short_final_page_when_passed_layer_count() {
+        let paged = "https://example.com/0/query?where=1%3D1&resultOffset=2000&resultRecordCount=1000";
+        assert_eq!(expected_page_features(paged, 5000).unwrap(), Some((1000, 1000)));
+        assert_eq!(expected_page_features(paged, 2300).unwrap(), Some((1000, 300)));
+        assert_eq!(expected_page_features(paged, 1500).unwrap(), Some((1000, 0)));
+        let ranged = "https://example.com/0/query?where=OBJECTID+%3E%3D+1+and+OBJECTID+%3C%3D+1000";
+        assert_eq!(expected_page_features(ranged, 5000).unwrap(), None);
     }
-    file.sync_all()?;
-    Ok(file)
 }
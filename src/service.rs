@@ -0,0 +1,275 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use crate::audit::RunId;
+use crate::progress::{ChunkProgress, LayerRef, LayerSummary, ProgressReporter};
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum ServiceError {
+    Interrupted(String),
+}
+
+impl Display for ServiceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServiceError::Interrupted(signal) => {
+                write!(f, "Scrape interrupted by {}", signal)
+            }
+        }
+    }
+}
+
+impl Error for ServiceError {}
+
+/// PID file written when the scraper starts and removed when it exits, so service managers
+/// (systemd `PIDFile=`, Windows service wrappers) can track the process. Runs are one-shot, a
+/// timer or scheduler starts each one, their health is told by a [`HealthFile`].
+#[derive(Debug)]
+pub(crate) struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub(crate) fn create(path: &Path) -> io::Result<Self> {
+        fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(Self { path: path.to_owned() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Progress of a run written to its health file.
+#[derive(Debug, Default, Clone, Serialize)]
+struct HealthStatus {
+    /// Layer being scraped, None before the first one is planned
+    layer: Option<String>,
+    completed_chunks: usize,
+    chunk_count: usize,
+}
+
+#[derive(Serialize)]
+struct HealthRecord<'a> {
+    run_id: &'a RunId,
+    pid: u32,
+    updated_at: DateTime<Utc>,
+    #[serde(flatten)]
+    status: &'a HealthStatus,
+}
+
+/// Status file rewritten as a run plans, fetches and retries chunks, and removed when it exits.
+/// A health check can tell a stuck run from a working one by the age of `updated_at`. Written
+/// under a `.part` name and renamed, so readers never see half a file.
+#[derive(Debug)]
+pub(crate) struct HealthFile {
+    path: PathBuf,
+    run_id: RunId,
+    status: Mutex<HealthStatus>,
+}
+
+impl HealthFile {
+    pub(crate) fn create(path: &Path, run_id: &RunId) -> io::Result<Self> {
+        let health = Self {
+            path: path.to_owned(),
+            run_id: run_id.to_owned(),
+            status: Mutex::default(),
+        };
+        health.update(|_| {})?;
+        Ok(health)
+    }
+
+    /// Apply `change` to the status and rewrite the file, also when nothing changed so its
+    /// `updated_at` shows the run is alive.
+    fn update(&self, change: impl FnOnce(&mut HealthStatus)) -> io::Result<()> {
+        let mut status = self.status.lock().unwrap();
+        change(&mut status);
+        let record = HealthRecord {
+            run_id: &self.run_id,
+            pid: std::process::id(),
+            updated_at: Utc::now(),
+            status: &status,
+        };
+        let mut part_name = self.path.as_os_str().to_owned();
+        part_name.push(".part");
+        let part_path = PathBuf::from(part_name);
+        let mut file = File::create(&part_path)?;
+        serde_json::to_writer(&mut file, &record)?;
+        writeln!(file)?;
+        fs::rename(&part_path, &self.path)
+    }
+}
+
+impl ProgressReporter for HealthFile {
+    fn on_plan(&self, layer: LayerRef<'_>, chunk_count: usize, _expected_features: u64) -> io::Result<()> {
+        self.update(|status| {
+            *status = HealthStatus { layer: Some(layer.name.to_owned()), completed_chunks: 0, chunk_count };
+        })
+    }
+
+    fn on_chunk_done(&self, _layer: LayerRef<'_>, progress: &ChunkProgress) -> io::Result<()> {
+        self.update(|status| {
+            status.completed_chunks = progress.completed_chunks;
+            status.chunk_count = progress.chunk_count;
+        })
+    }
+
+    /// Retries against a slow server are progress too
+    fn on_retry(&self, _layer: LayerRef<'_>, _chunk_id: usize, _attempt: i32, _error: &str) -> io::Result<()> {
+        self.update(|_| {})
+    }
+
+    fn on_finish(&self, _layer: LayerRef<'_>, _summary: &LayerSummary) -> io::Result<()> {
+        self.update(|status| status.completed_chunks = status.chunk_count)
+    }
+}
+
+impl Drop for HealthFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Resolves with the name of the first termination signal received by the process. Covers
+/// Ctrl-C everywhere, SIGTERM on unix (sent by systemd on stop) and console close/shutdown events
+/// on Windows.
+#[cfg(unix)]
+pub(crate) async fn shutdown_signal() -> io::Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.map(|_| "SIGINT"),
+        _ = terminate.recv() => Ok("SIGTERM"),
+    }
+}
+
+#[cfg(windows)]
+pub(crate) async fn shutdown_signal() -> io::Result<&'static str> {
+    use tokio::signal::windows::{ctrl_close, ctrl_shutdown};
+    let mut close = ctrl_close()?;
+    let mut shutdown = ctrl_shutdown()?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.map(|_| "CTRL_C_EVENT"),
+        _ = close.recv() => Ok("CTRL_CLOSE_EVENT"),
+        _ = shutdown.recv() => Ok("CTRL_SHUTDOWN_EVENT"),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) async fn shutdown_signal() -> io::Result<&'static str> {
+    tokio::signal::ctrl_c().await.map(|_| "Ctrl-C")
+}
+
+/// Niceness the scraper lowers itself to with --nice.
+#[cfg(unix)]
+const NICENESS: i32 = 10;
+
+/// Lower the CPU priority of the process and, on Linux, move its disk IO to the idle class so
+/// scrapes yield to everything else running on the machine.
+#[cfg(unix)]
+pub(crate) fn lower_priority() -> io::Result<()> {
+    // SAFETY: plain syscalls on the current process with no pointers involved
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, NICENESS) } != 0 {
+        return Err(io::Error::last_os_error())
+    }
+    #[cfg(target_os = "linux")]
+    {
+        const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+        const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+        const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+        // SAFETY: see above
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_ioprio_set,
+                IOPRIO_WHO_PROCESS,
+                0,
+                IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error())
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn lower_priority() -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Lowering the process priority is only supported on unix"))
+}
+
+/// Keep standard output for machine readable output (e.g. an archive streamed with
+/// `--archive -`), returning a handle to it, and send everything printed from then on to
+/// standard error instead.
+#[cfg(unix)]
+pub(crate) fn reserve_stdout() -> io::Result<File> {
+    use std::os::unix::io::FromRawFd;
+    io::stdout().flush()?;
+    // SAFETY: duplicates of the standard descriptors, the copy of stdout is owned by the file
+    let reserved = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if reserved < 0 {
+        return Err(io::Error::last_os_error())
+    }
+    // SAFETY: see above
+    if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        return Err(io::Error::last_os_error())
+    }
+    // SAFETY: `reserved` is a newly opened descriptor owned by nothing else
+    Ok(unsafe { File::from_raw_fd(reserved) })
+}
+
+#[cfg(not(unix))]
+pub(crate) fn reserve_stdout() -> io::Result<File> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Writing to standard output is only supported on unix"))
+}
+
+#[cfg(test)]
+mod health_file_tests {
+    use std::time::Duration;
+    use serde_json::Value;
+    use crate::audit::RunId;
+    use crate::progress::{ChunkProgress, LayerRef, ProgressReporter};
+    use super::HealthFile;
+
+    #[test]
+    fn on_chunk_done_should_rewrite_status_when_chunk_fetched() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("health.json");
+        let read = || serde_json::from_slice::<Value>(&std::fs::read(&path).unwrap()).unwrap();
+        let health = HealthFile::create(&path, &RunId::generate()).unwrap();
+        assert_eq!(read()["layer"], Value::Null);
+        assert_eq!(read()["pid"], std::process::id());
+
+        let layer = LayerRef { url: "https://example.com/arcgis/rest/services/Parcels/FeatureServer/0", name: "Parcels" };
+        health.on_plan(layer, 4, 8000).unwrap();
+        let planned_at = read()["updated_at"].to_owned();
+        std::thread::sleep(Duration::from_millis(5));
+        let progress = ChunkProgress {
+            chunk_id: 0,
+            features: 2000,
+            bytes: 1024,
+            completed_chunks: 1,
+            chunk_count: 4,
+            total_features: 2000,
+            total_bytes: 1024,
+            expected_features: 8000,
+            features_per_second: 1000.0,
+            eta: None,
+        };
+        health.on_chunk_done(layer, &progress).unwrap();
+        let status = read();
+        assert_eq!((status["layer"].as_str(), status["completed_chunks"].as_u64()), (Some("Parcels"), Some(1)));
+        assert_ne!(status["updated_at"], planned_at);
+        drop(health);
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,524 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Datelike, Utc};
+use serde_json::{Map, Value};
+use crate::checksum::{Artifact, ChecksumFile};
+use crate::geometry::{esri_parts, rewind_rings, Extent, RingWinding};
+use crate::metadata::{RestServiceFieldType, RestServiceGeometryType, RestServiceMetadata};
+use crate::projection;
+use crate::scraping::{handle_csv_value, header_line};
+use crate::wkt::Ring;
+
+/// File code opening the .shp and .shx files.
+const FILE_CODE: i32 = 9994;
+const VERSION: i32 = 1000;
+/// Bytes of the header of the .shp and .shx files.
+const HEADER_BYTES: u64 = 100;
+/// Longest field name of a .dbf file.
+const MAX_FIELD_NAME: usize = 10;
+/// Longest text value of a .dbf file.
+const MAX_TEXT_LENGTH: u8 = 254;
+
+const WGS84_PRJ: &str = "GEOGCS[\"GCS_WGS_1984\",DATUM[\"D_WGS_1984\",SPHEROID[\"WGS_1984\",6378137.0,298.257223563]],\
+PRIMEM[\"Greenwich\",0.0],UNIT[\"Degree\",0.0174532925199433]]";
+const NAD83_PRJ: &str = "GEOGCS[\"GCS_North_American_1983\",DATUM[\"D_North_American_1983\",\
+SPHEROID[\"GRS_1980\",6378137.0,298.257222101]],PRIMEM[\"Greenwich\",0.0],UNIT[\"Degree\",0.0174532925199433]]";
+const WEB_MERCATOR_PRJ: &str = "PROJCS[\"WGS_1984_Web_Mercator_Auxiliary_Sphere\",GEOGCS[\"GCS_WGS_1984\",\
+DATUM[\"D_WGS_1984\",SPHEROID[\"WGS_1984\",6378137.0,298.257223563]],PRIMEM[\"Greenwich\",0.0],\
+UNIT[\"Degree\",0.0174532925199433]],PROJECTION[\"Mercator_Auxiliary_Sphere\"],PARAMETER[\"False_Easting\",0.0],\
+PARAMETER[\"False_Northing\",0.0],PARAMETER[\"Central_Meridian\",0.0],PARAMETER[\"Standard_Parallel_1\",0.0],\
+PARAMETER[\"Auxiliary_Sphere_Type\",0.0],UNIT[\"Meter\",1.0]]";
+
+/// Esri WKT of the common spatial references, for layers whose metadata holds only the wkid of
+/// the spatial reference or whose geometries are projected into another one.
+fn prj_definition(wkid: i64) -> Option<&'static str> {
+    match wkid {
+        4326 => Some(WGS84_PRJ),
+        4269 => Some(NAD83_PRJ),
+        _ if projection::same_spatial_reference(wkid, 3857) => Some(WEB_MERCATOR_PRJ),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ShapeType {
+    Null = 0,
+    Point = 1,
+    PolyLine = 3,
+    Polygon = 5,
+    MultiPoint = 8,
+}
+
+impl ShapeType {
+    fn of(geo_type: &RestServiceGeometryType) -> Option<Self> {
+        match geo_type {
+            RestServiceGeometryType::Point => Some(ShapeType::Point),
+            RestServiceGeometryType::Multipoint => Some(ShapeType::MultiPoint),
+            RestServiceGeometryType::Polyline => Some(ShapeType::PolyLine),
+            RestServiceGeometryType::Polygon | RestServiceGeometryType::Envelope => Some(ShapeType::Polygon),
+            RestServiceGeometryType::None => None,
+        }
+    }
+}
+
+/// Type of a .dbf field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DbfType {
+    /// Text of at most this many bytes
+    Character(u8),
+    /// Integer of at most this many digits and sign
+    Integer(u8),
+    Decimal,
+    /// Day of a date, the time of day being dropped as .dbf dates have none
+    Date,
+}
+
+impl DbfType {
+    fn of(field_type: &RestServiceFieldType, length: Option<i64>) -> Self {
+        match field_type {
+            RestServiceFieldType::OID => DbfType::Integer(19),
+            RestServiceFieldType::Integer => DbfType::Integer(11),
+            RestServiceFieldType::SmallInteger => DbfType::Integer(6),
+            RestServiceFieldType::Double | RestServiceFieldType::Single | RestServiceFieldType::Float => DbfType::Decimal,
+            RestServiceFieldType::Date => DbfType::Date,
+            _ => {
+                let length = length.map_or(MAX_TEXT_LENGTH, |length| length.clamp(1, MAX_TEXT_LENGTH as i64) as u8);
+                DbfType::Character(length)
+            }
+        }
+    }
+
+    fn code(&self) -> u8 {
+        match self {
+            DbfType::Character(_) => b'C',
+            DbfType::Integer(_) | DbfType::Decimal => b'N',
+            DbfType::Date => b'D',
+        }
+    }
+
+    fn length(&self) -> u8 {
+        match self {
+            DbfType::Character(length) | DbfType::Integer(length) => *length,
+            DbfType::Decimal => 24,
+            DbfType::Date => 8,
+        }
+    }
+
+    fn decimals(&self) -> u8 {
+        match self {
+            DbfType::Decimal => 15,
+            _ => 0,
+        }
+    }
+
+    /// Text of `value` in a field of this type, None when it does not fit or parse, written as
+    /// a blank (null) value.
+    fn format(&self, value: &str) -> Option<String> {
+        let width = self.length() as usize;
+        let integer = || {
+            value.parse::<i64>()
+                .ok()
+                .or_else(|| value.parse::<f64>().ok().filter(|number| number.fract() == 0.0).map(|number| number as i64))
+        };
+        match self {
+            DbfType::Character(length) => {
+                let mut end = value.len().min(*length as usize);
+                while !value.is_char_boundary(end) {
+                    end -= 1;
+                }
+                Some(value[..end].to_owned())
+            }
+            DbfType::Integer(_) => integer()
+                .map(|number| number.to_string())
+                .filter(|text| text.len() <= width),
+            DbfType::Decimal => {
+                let number = value.parse::<f64>().ok().filter(|number| number.is_finite())?;
+                // Large values give up decimals to fit the field
+                (0..=self.decimals() as usize).rev()
+                    .map(|decimals| format!("{:.*}", decimals, number))
+                    .find(|text| text.len() <= width)
+            }
+            DbfType::Date => integer()
+                .and_then(DateTime::from_timestamp_millis)
+                .map(|date: DateTime<Utc>| date.format("%Y%m%d").to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DbfField {
+    /// Record column the field holds
+    column: String,
+    /// Name of the field, at most 10 characters
+    name: String,
+    dbf_type: DbfType,
+    /// Position of the column's values in the records
+    index: usize,
+}
+
+/// Names of .dbf fields for `columns`, cut to 10 ASCII characters. Names clashing once cut (in
+/// any case) end with a number instead, e.g. "OWNER_NA_1".
+fn field_names(columns: &[&str]) -> Vec<String> {
+    let mut taken = HashSet::new();
+    columns.iter()
+        .map(|column| {
+            let name: String = column.chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect();
+            let mut candidate: String = name.chars().take(MAX_FIELD_NAME).collect();
+            let mut suffix = 0;
+            while !taken.insert(candidate.to_uppercase()) {
+                suffix += 1;
+                let suffix = format!("_{}", suffix);
+                candidate = name.chars().take(MAX_FIELD_NAME - suffix.len()).collect::<String>() + &suffix;
+            }
+            candidate
+        })
+        .collect()
+}
+
+/// Fields of a layer's .dbf file and the shape type of its .shp file, taken from the record
+/// columns without the geometry columns.
+#[derive(Debug, Clone)]
+pub(crate) struct ShapefileLayout {
+    fields: Vec<DbfField>,
+    geo_type: RestServiceGeometryType,
+    shape_type: ShapeType,
+    /// Esri WKT of the spatial reference of the geometries, when known
+    prj: Option<String>,
+}
+
+/// Shape and attributes of a feature, encoded as the records of the .shp and .dbf files.
+#[derive(Debug)]
+pub(crate) struct ShapefileRecord {
+    /// Bounds of the shape, None for null shapes
+    extent: Option<Extent>,
+    shape: Vec<u8>,
+    attributes: Vec<u8>,
+}
+
+impl ShapefileLayout {
+    /// Layout of the records of `layer` with `columns`, None for tables. Columns that are not
+    /// fields of the layer (code descriptions, provenance, cells) are text, except the measure
+    /// column.
+    pub(crate) fn new(layer: &RestServiceMetadata, columns: &[String], measure_column: Option<&str>) -> Option<Self> {
+        let shape_type = ShapeType::of(&layer.geo_type)?;
+        let columns: Vec<(usize, &String, DbfType)> = columns.iter()
+            .enumerate()
+            .filter_map(|(index, name)| {
+                let field = layer.fields.iter().find(|field| field.name == *name);
+                let dbf_type = match field {
+                    Some(field) if field.field_type == RestServiceFieldType::Geometry => return None,
+                    Some(field) => DbfType::of(&field.field_type, field.length),
+                    None if measure_column == Some(name.as_str()) => DbfType::Decimal,
+                    None => DbfType::Character(MAX_TEXT_LENGTH),
+                };
+                Some((index, name, dbf_type))
+            })
+            .collect();
+        let names = field_names(&columns.iter().map(|(_, name, _)| name.as_str()).collect::<Vec<&str>>());
+        let fields = columns.into_iter()
+            .zip(names)
+            .map(|((index, column, dbf_type), name)| DbfField { column: column.to_owned(), name, dbf_type, index })
+            .collect();
+        let prj = layer.output_spatial_reference_wkt()
+            .or_else(|| layer.output_spatial_reference().and_then(prj_definition))
+            .map(str::to_owned);
+        Some(Self { fields, geo_type: layer.geo_type.clone(), shape_type, prj })
+    }
+
+    /// Number of columns whose field name differs from the column name.
+    pub(crate) fn renamed(&self) -> usize {
+        self.fields.iter().filter(|field| field.name != field.column).count()
+    }
+
+    /// Whether the spatial reference of the layer has a .prj file.
+    pub(crate) fn has_prj(&self) -> bool {
+        self.prj.is_some()
+    }
+
+    /// Encode the record of a feature and its geometry.
+    pub(crate) fn encode(&self, record: &[String], feature: &Map<String, Value>) -> ShapefileRecord {
+        let mut attributes = vec![b' '];
+        for field in &self.fields {
+            let width = field.dbf_type.length() as usize;
+            let text = record.get(field.index)
+                .filter(|value| !value.is_empty())
+                .and_then(|value| field.dbf_type.format(value))
+                .unwrap_or_default();
+            let padded = match field.dbf_type {
+                DbfType::Character(_) => format!("{:<width$}", text, width = width),
+                _ => format!("{:>width$}", text, width = width),
+            };
+            attributes.extend(padded.as_bytes());
+        }
+        let geometry = feature.get("geometry").and_then(Value::as_object);
+        let (shape, extent) = match geometry.and_then(|geometry| self.shape(geometry)) {
+            Some((shape, extent)) => (shape, Some(extent)),
+            None => ((ShapeType::Null as i32).to_le_bytes().to_vec(), None),
+        };
+        ShapefileRecord { extent, shape, attributes }
+    }
+
+    /// Content of the .shp record of an Esri JSON geometry and its bounds, None when it has no
+    /// coordinates.
+    fn shape(&self, geometry: &Map<String, Value>) -> Option<(Vec<u8>, Extent)> {
+        let coordinate = |key: &str| geometry.get(key).and_then(Value::as_f64);
+        match self.geo_type {
+            RestServiceGeometryType::Point => {
+                let (x, y) = coordinate("x").zip(coordinate("y"))?;
+                let mut shape = (ShapeType::Point as i32).to_le_bytes().to_vec();
+                shape.extend(x.to_le_bytes());
+                shape.extend(y.to_le_bytes());
+                Some((shape, Extent::from_point(x, y)))
+            }
+            RestServiceGeometryType::Multipoint => {
+                let points: Ring = esri_parts(geometry, "points").into_iter().flatten().collect();
+                let extent = bounds(&points)?;
+                let mut shape = (ShapeType::MultiPoint as i32).to_le_bytes().to_vec();
+                push_extent(&mut shape, &extent);
+                shape.extend((points.len() as i32).to_le_bytes());
+                push_points(&mut shape, &points);
+                Some((shape, extent))
+            }
+            RestServiceGeometryType::Polyline => {
+                let paths: Vec<Ring> = esri_parts(geometry, "paths")
+                    .into_iter()
+                    .filter(|path| path.len() >= 2)
+                    .collect();
+                multi_part_shape(ShapeType::PolyLine, &paths)
+            }
+            RestServiceGeometryType::Polygon => {
+                // Shapefiles wind exteriors clockwise and holes counter-clockwise as Esri JSON
+                // does, which --ring-winding may have changed
+                let mut geometry = geometry.clone();
+                rewind_rings(&mut geometry, &RingWinding::Esri, true);
+                let rings: Vec<Ring> = esri_parts(&geometry, "rings")
+                    .into_iter()
+                    .filter(|ring| ring.len() >= 3)
+                    .map(|mut ring| {
+                        if ring.first() != ring.last() {
+                            ring.push(ring[0]);
+                        }
+                        ring
+                    })
+                    .collect();
+                multi_part_shape(ShapeType::Polygon, &rings)
+            }
+            RestServiceGeometryType::Envelope => {
+                let (x_min, y_min) = coordinate("xmin").zip(coordinate("ymin"))?;
+                let (x_max, y_max) = coordinate("xmax").zip(coordinate("ymax"))?;
+                let ring = vec![(x_min, y_min), (x_min, y_max), (x_max, y_max), (x_max, y_min), (x_min, y_min)];
+                multi_part_shape(ShapeType::Polygon, &[ring])
+            }
+            RestServiceGeometryType::None => None,
+        }
+    }
+}
+
+fn bounds(points: &[(f64, f64)]) -> Option<Extent> {
+    let (first, rest) = points.split_first()?;
+    Some(rest.iter().fold(Extent::from_point(first.0, first.1), |extent, point| {
+        extent.union(&Extent::from_point(point.0, point.1))
+    }))
+}
+
+fn push_extent(shape: &mut Vec<u8>, extent: &Extent) {
+    for value in [extent.x_min, extent.y_min, extent.x_max, extent.y_max] {
+        shape.extend(value.to_le_bytes());
+    }
+}
+
+fn push_points(shape: &mut Vec<u8>, points: &[(f64, f64)]) {
+    for (x, y) in points {
+        shape.extend(x.to_le_bytes());
+        shape.extend(y.to_le_bytes());
+    }
+}
+
+/// Content of a polyline or polygon record, its parts followed by every point.
+fn multi_part_shape(shape_type: ShapeType, parts: &[Ring]) -> Option<(Vec<u8>, Extent)> {
+    let points: Ring = parts.iter().flatten().copied().collect();
+    let extent = bounds(&points)?;
+    let mut shape = (shape_type as i32).to_le_bytes().to_vec();
+    push_extent(&mut shape, &extent);
+    shape.extend((parts.len() as i32).to_le_bytes());
+    shape.extend((points.len() as i32).to_le_bytes());
+    let mut start = 0;
+    for part in parts {
+        shape.extend((start as i32).to_le_bytes());
+        start += part.len();
+    }
+    push_points(&mut shape, &points);
+    Some((shape, extent))
+}
+
+/// Writes the features of a layer into a shapefile, its .shp, .shx, .dbf, .prj and .cpg files,
+/// along with a CSV report of the field name each column was given. The shapes and attributes
+/// are spooled to temporary files next to the output, since the headers hold their count and
+/// bounds.
+pub(crate) struct ShapefileWriter {
+    layout: ShapefileLayout,
+    path: PathBuf,
+    shapes: File,
+    attributes: File,
+    /// Length of each shape record's content, in bytes
+    shape_lengths: Vec<u32>,
+    shape_bytes: u64,
+    extent: Option<Extent>,
+}
+
+impl ShapefileWriter {
+    /// Writer of the shapefile at `path`, the .shp file whose siblings share its name.
+    pub(crate) fn create(path: &Path, layout: ShapefileLayout) -> io::Result<Self> {
+        let directory = path.parent().unwrap_or(Path::new("."));
+        Ok(Self {
+            layout,
+            path: path.to_owned(),
+            shapes: tempfile::tempfile_in(directory)?,
+            attributes: tempfile::tempfile_in(directory)?,
+            shape_lengths: vec![],
+            shape_bytes: 0,
+            extent: None,
+        })
+    }
+
+    /// Spool the records of a chunk.
+    pub(crate) fn write(&mut self, records: Vec<ShapefileRecord>) -> io::Result<()> {
+        let mut shapes = BufWriter::new(&mut self.shapes);
+        let mut attributes = BufWriter::new(&mut self.attributes);
+        for record in records {
+            let record_number = self.shape_lengths.len() as i32 + 1;
+            shapes.write_all(&record_number.to_be_bytes())?;
+            shapes.write_all(&(record.shape.len() as i32 / 2).to_be_bytes())?;
+            shapes.write_all(&record.shape)?;
+            attributes.write_all(&record.attributes)?;
+            self.shape_lengths.push(record.shape.len() as u32);
+            self.shape_bytes += 8 + record.shape.len() as u64;
+            self.extent = match (self.extent, record.extent) {
+                (Some(extent), Some(other)) => Some(extent.union(&other)),
+                (extent, other) => extent.or(other),
+            };
+        }
+        shapes.flush()?;
+        attributes.flush()
+    }
+
+    /// Header of the .shp and .shx files, given the length of the file in bytes.
+    fn main_header(&self, file_bytes: u64) -> io::Result<Vec<u8>> {
+        // Offsets are 32-bit counts of 16-bit words, capping shapefiles at 2 GiB to most readers
+        let file_words = i32::try_from(file_bytes / 2).map_err(|_| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is larger than shapefiles allow", self.path.display()),
+        ))?;
+        let mut header = FILE_CODE.to_be_bytes().to_vec();
+        header.extend([0; 20]);
+        header.extend(file_words.to_be_bytes());
+        header.extend(VERSION.to_le_bytes());
+        header.extend((self.layout.shape_type as i32).to_le_bytes());
+        let extent = self.extent.unwrap_or(Extent { x_min: 0.0, y_min: 0.0, x_max: 0.0, y_max: 0.0 });
+        push_extent(&mut header, &extent);
+        // No z or m ranges
+        header.extend([0; 32]);
+        Ok(header)
+    }
+
+    fn dbf_header(&self) -> io::Result<Vec<u8>> {
+        let field_count = self.layout.fields.len();
+        let header_length = u16::try_from(32 + 32 * field_count + 1)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "too many fields for a .dbf file"))?;
+        let record_length: usize = 1 + self.layout.fields.iter().map(|field| field.dbf_type.length() as usize).sum::<usize>();
+        let record_length = u16::try_from(record_length)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "records too long for a .dbf file"))?;
+        let today = Utc::now();
+        let mut header = vec![3, (today.year() - 1900) as u8, today.month() as u8, today.day() as u8];
+        header.extend((self.shape_lengths.len() as u32).to_le_bytes());
+        header.extend(header_length.to_le_bytes());
+        header.extend(record_length.to_le_bytes());
+        header.extend([0; 20]);
+        for field in &self.layout.fields {
+            let mut name = field.name.as_bytes().to_vec();
+            name.resize(11, 0);
+            header.extend(name);
+            header.push(field.dbf_type.code());
+            header.extend([0; 4]);
+            header.push(field.dbf_type.length());
+            header.push(field.dbf_type.decimals());
+            header.extend([0; 14]);
+        }
+        header.push(0x0D);
+        Ok(header)
+    }
+
+    /// Write the shapefile's files from the spooled records, returning them as artifacts.
+    pub(crate) fn finish(mut self) -> io::Result<Vec<Artifact>> {
+        let mut artifacts = vec![];
+
+        let mut shp = ChecksumFile::create(&self.path)?;
+        shp.write_all(&self.main_header(HEADER_BYTES + self.shape_bytes)?)?;
+        self.shapes.seek(SeekFrom::Start(0))?;
+        io::copy(&mut self.shapes, &mut shp)?;
+        artifacts.push(shp.finish()?);
+
+        let mut shx = ChecksumFile::create(&self.path.with_extension("shx"))?;
+        let mut index = self.main_header(HEADER_BYTES + 8 * self.shape_lengths.len() as u64)?;
+        let mut offset = HEADER_BYTES;
+        for length in &self.shape_lengths {
+            index.extend(((offset / 2) as i32).to_be_bytes());
+            index.extend((*length as i32 / 2).to_be_bytes());
+            offset += 8 + *length as u64;
+        }
+        shx.write_all(&index)?;
+        artifacts.push(shx.finish()?);
+
+        let mut dbf = ChecksumFile::create(&self.path.with_extension("dbf"))?;
+        dbf.write_all(&self.dbf_header()?)?;
+        self.attributes.seek(SeekFrom::Start(0))?;
+        io::copy(&mut self.attributes, &mut dbf)?;
+        dbf.write_all(&[0x1A])?;
+        artifacts.push(dbf.finish()?);
+
+        if let Some(definition) = &self.layout.prj {
+            let mut prj = ChecksumFile::create(&self.path.with_extension("prj"))?;
+            prj.write_all(definition.as_bytes())?;
+            artifacts.push(prj.finish()?);
+        }
+        let mut cpg = ChecksumFile::create(&self.path.with_extension("cpg"))?;
+        cpg.write_all(b"UTF-8")?;
+        artifacts.push(cpg.finish()?);
+
+        let mut report = ChecksumFile::create(&self.path.with_extension("fields.csv"))?;
+        writeln!(report, "{}", header_line(&["column".to_owned(), "field".to_owned(), "type".to_owned()]))?;
+        for field in &self.layout.fields {
+            let dbf_type = format!("{}({},{})", field.dbf_type.code() as char, field.dbf_type.length(), field.dbf_type.decimals());
+            writeln!(report, "{},{},{}", handle_csv_value(&field.column), field.name, dbf_type)?;
+        }
+        artifacts.push(report.finish()?);
+        Ok(artifacts)
+    }
+}
+
+#[cfg(test)]
+mod shapefile_tests {
+    use super::{field_names, DbfType};
+
+    #[test]
+    fn field_names_should_number_clashing_names_when_cut_to_ten_characters() {
+        let columns = ["OWNER_NAME_FIRST", "OWNER_NAME_LAST", "owner_name", "PARCEL ID", "VAL"];
+        assert_eq!(field_names(&columns), ["OWNER_NAME", "OWNER_NA_1", "owner_na_2", "PARCEL_ID", "VAL"]);
+    }
+
+    #[test]
+    fn format_should_fit_values_to_field_when_passed_record_values() {
+        assert_eq!(DbfType::Character(5).format("Ça va bien"), Some("Ça v".to_owned()));
+        assert_eq!(DbfType::Integer(6).format("1234567"), None);
+        assert_eq!(DbfType::Integer(11).format("42.0"), Some("42".to_owned()));
+        assert_eq!(DbfType::Decimal.format("0.5"), Some("0.500000000000000".to_owned()));
+        assert_eq!(DbfType::Decimal.format("123456789012345.5"), Some("123456789012345.50000000".to_owned()));
+        assert_eq!(DbfType::Date.format("1600000000000"), Some("20200913".to_owned()));
+        assert_eq!(DbfType::Date.format("soon"), None);
+    }
+}
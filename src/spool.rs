@@ -0,0 +1,148 @@
+use std::fs::{create_dir_all, read_to_string, remove_dir_all, remove_file, rename, write, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+use crate::audit::RunId;
+use crate::partition::{file_name_part, partition_file_part};
+use crate::state::state_directory;
+
+const PART_EXTENSION: &str = "part";
+/// Hash of the queries the chunks of a spool were fetched for.
+const PLAN_FILE: &str = "plan.sha256";
+
+/// Directory holding the records of every fetched chunk of a layer, named by the stable id of
+/// the chunk in its query plan (`spool/{run id}/{layer}/{attempt}` in the state directory).
+/// Chunk files are written under a `.part` name and only renamed once complete, so the
+/// completed chunks of a crashed run can be trusted while partial ones never are.
+#[derive(Debug, Clone)]
+pub(crate) struct ChunkSpool {
+    directory: PathBuf,
+}
+
+impl ChunkSpool {
+    /// Spool of a run. Nothing is created until the first chunk is written.
+    pub(crate) fn for_run(run_id: &RunId) -> io::Result<Self> {
+        let directory = state_directory()?.join("spool").join(run_id.to_string());
+        Ok(Self { directory })
+    }
+
+    /// Spool nested in this one, for a layer of the run or an attempt at a layer (e.g. a
+    /// strategy), since chunk ids are only stable within a single query plan.
+    pub(crate) fn join(&self, name: &str) -> Self {
+        Self { directory: self.directory.join(file_name_part(name)) }
+    }
+
+    pub(crate) fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Keep the chunks completed by an earlier run of this spool (see --resume) when `keep` is
+    /// set and they were fetched for the same queries, otherwise start over. Returns whether the
+    /// chunks were kept.
+    pub(crate) fn resume_plan(&self, queries: &[String], keep: bool) -> io::Result<bool> {
+        let plan = format!("{:x}", Sha256::digest(queries.join("\n").as_bytes()));
+        let plan_path = self.directory.join(PLAN_FILE);
+        let kept = keep && read_to_string(&plan_path).is_ok_and(|planned| planned == plan);
+        if !kept {
+            self.remove()?;
+        }
+        create_dir_all(&self.directory)?;
+        write(plan_path, plan)?;
+        Ok(kept)
+    }
+
+    /// Records of a chunk completed by an earlier run, None when it has to be fetched.
+    pub(crate) fn completed(&self, chunk_id: usize) -> io::Result<Option<File>> {
+        match File::open(self.directory.join(format!("chunk_{:06}.csv", chunk_id))) {
+            Ok(file) => Ok(Some(file)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Start writing the records of a chunk, optionally as one of its named parts (e.g. a
+    /// partition value). A part file left by an earlier try of the chunk is truncated.
+    pub(crate) fn begin(&self, chunk_id: usize, part: Option<&str>) -> io::Result<SpooledChunk> {
+        create_dir_all(&self.directory)?;
+        let name = match part {
+            Some(part) => format!("chunk_{:06}_{}.csv", chunk_id, partition_file_part(part)),
+            None => format!("chunk_{:06}.csv", chunk_id),
+        };
+        let path = self.directory.join(name);
+        let part_path = path.with_extension(format!("csv.{}", PART_EXTENSION));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&part_path)?;
+        Ok(SpooledChunk { file, part_path, path })
+    }
+
+    /// Delete the spool once the layer's output is written.
+    pub(crate) fn remove(&self) -> io::Result<()> {
+        match remove_dir_all(&self.directory) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Chunk file being written under its `.part` name.
+#[derive(Debug)]
+pub(crate) struct SpooledChunk {
+    pub(crate) file: File,
+    part_path: PathBuf,
+    path: PathBuf,
+}
+
+impl SpooledChunk {
+    /// Flush the chunk to disk and atomically give it its final name.
+    pub(crate) fn complete(self) -> io::Result<File> {
+        self.file.sync_all()?;
+        rename(&self.part_path, &self.path)?;
+        Ok(self.file)
+    }
+
+    /// Delete the part file of a chunk part that is no longer written, e.g. a partition only
+    /// reached by a response thrown away.
+    pub(crate) fn discard(self) -> io::Result<()> {
+        remove_file(&self.part_path)
+    }
+}
+
+#[cfg(test)]
+mod spool_tests {
+    use std::io::Write;
+    use super::ChunkSpool;
+
+    #[test]
+    fn complete_should_rename_part_file_when_chunk_written() {
+        let directory = tempfile::tempdir().unwrap();
+        let spool = ChunkSpool { directory: directory.path().to_owned() }
+            .join("Parcels")
+            .join("pagination");
+        let mut chunk = spool.begin(12, None).unwrap();
+        writeln!(chunk.file, "1,a").unwrap();
+        let part_path = spool.directory().join("chunk_000012.csv.part");
+        assert!(part_path.is_file());
+        chunk.complete().unwrap();
+        assert!(!part_path.exists());
+        assert_eq!(std::fs::read_to_string(spool.directory().join("chunk_000012.csv")).unwrap(), "1,a\n");
+    }
+
+    #[test]
+    fn resume_plan_should_keep_completed_chunks_only_when_queries_match() {
+        let directory = tempfile::tempdir().unwrap();
+        let spool = ChunkSpool { directory: directory.path().to_owned() }.join("Parcels");
+        let queries = vec!["query?offset=0".to_owned(), "query?offset=1000".to_owned()];
+        assert!(!spool.resume_plan(&queries, true).unwrap());
+        spool.begin(0, None).unwrap().complete().unwrap();
+        spool.begin(1, None).unwrap();
+        assert!(spool.resume_plan(&queries, true).unwrap());
+        assert!(spool.completed(0).unwrap().is_some());
+        assert!(spool.completed(1).unwrap().is_none());
+        assert!(!spool.resume_plan(&queries[..1], true).unwrap());
+        assert!(spool.completed(0).unwrap().is_none());
+    }
+}
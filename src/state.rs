@@ -0,0 +1,23 @@
+use std::env;
+use std::fs::create_dir_all;
+use std::io;
+use std::path::PathBuf;
+
+/// Directory holding everything the scraper persists between runs (run history, etc.). Uses
+/// `ARCGIS_SCRAPER_HOME` when set, otherwise `.arcgis_scraper` in the user's home directory.
+pub(crate) fn state_directory() -> io::Result<PathBuf> {
+    let directory = match env::var_os("ARCGIS_SCRAPER_HOME") {
+        Some(home) => PathBuf::from(home),
+        None => {
+            let user_home = env::var_os("HOME")
+                .or_else(|| env::var_os("USERPROFILE"))
+                .ok_or_else(|| io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "Could not find a home directory. Set ARCGIS_SCRAPER_HOME to choose a state directory",
+                ))?;
+            PathBuf::from(user_home).join(".arcgis_scraper")
+        }
+    };
+    create_dir_all(&directory)?;
+    Ok(directory)
+}
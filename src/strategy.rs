@@ -0,0 +1,280 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Write};
+use clap::ValueEnum;
+use crate::metadata::{request_object_ids, RestServiceGeometryType, RestServiceMetadata};
+use crate::pipeline::ScrapeSettings;
+use crate::report::{self, WarningKind};
+use crate::sampling::SampleMethod;
+use crate::{quadtree, sampling};
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum StrategyError {
+    NoObjectIds(String),
+    NoStrategy(String),
+}
+
+impl Display for StrategyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StrategyError::NoObjectIds(name) => {
+                write!(f, "Layer \"{}\" did not return its object ids", name)
+            }
+            StrategyError::NoStrategy(name) => {
+                write!(f, "Layer \"{}\" supports no scraping strategy (no pagination, OID field or geometry)", name)
+            }
+        }
+    }
+}
+
+impl Error for StrategyError {}
+
+/// How the features of a layer are split into chunk queries.
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+pub(crate) enum ScrapeStrategy {
+    /// Try every strategy the layer supports, in order, until one returns the expected features
+    Auto,
+    /// resultOffset/resultRecordCount pages
+    Pagination,
+    /// where clauses over consecutive object id ranges
+    OidRanges,
+    /// Batches of object ids fetched with returnIdsOnly
+    ObjectIds,
+    /// Recursively split the layer extent into envelopes holding at most one chunk of features
+    Quadtree,
+}
+
+impl Display for ScrapeStrategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScrapeStrategy::Auto => write!(f, "auto"),
+            ScrapeStrategy::Pagination => write!(f, "pagination"),
+            ScrapeStrategy::OidRanges => write!(f, "OID ranges"),
+            ScrapeStrategy::ObjectIds => write!(f, "object id batches"),
+            ScrapeStrategy::Quadtree => write!(f, "envelope quadtree"),
+        }
+    }
+}
+
+impl ScrapeStrategy {
+    /// Strategies to walk for `layer`, in order. Auto expands to every strategy the layer
+    /// supports, any other strategy is used on its own. Layers with virtual OIDs leave out the
+    /// strategies relying on OIDs matching between queries.
+    pub(crate) fn chain(&self, layer: &RestServiceMetadata) -> Vec<ScrapeStrategy> {
+        if *self != ScrapeStrategy::Auto {
+            return vec![self.to_owned()]
+        }
+        let has_oid = layer.oid_field_name().is_some();
+        let stable_oids = layer.virtual_oids().is_none();
+        [
+            (ScrapeStrategy::Pagination, layer.supports_pagination()),
+            (ScrapeStrategy::OidRanges, layer.supports_oid_ranges() && stable_oids),
+            (ScrapeStrategy::ObjectIds, has_oid),
+            (ScrapeStrategy::Quadtree, layer.geo_type != RestServiceGeometryType::None && stable_oids),
+        ].into_iter()
+            .filter(|(_, supported)| *supported)
+            .map(|(strategy, _)| strategy)
+            .collect()
+    }
+}
+
+async fn object_ids_queries(
+    layer: &RestServiceMetadata,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let object_ids = request_object_ids(&layer.client, &layer.url, &layer.filter)
+        .await?
+        .ok_or_else(|| StrategyError::NoObjectIds(layer.name.to_owned()))?;
+    let chunk_size = usize::try_from(layer.scrape_count())?;
+    object_ids.chunks(chunk_size)
+        .map(|batch| layer.object_ids_query(batch))
+        .collect()
+}
+
+/// OID range queries balanced by the percentiles of the layer's object ids when it supports
+/// them, otherwise ranges of equal width.
+async fn oid_range_queries(
+    layer: &RestServiceMetadata,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    match layer.balanced_oid_range_queries().await {
+        Ok(Some(queries)) => Ok(queries),
+        Ok(None) => layer.oid_range_queries(),
+        Err(error) => {
+            report::warn_about(WarningKind::General, &layer.name, format_args!(
+                "Could not balance the OID ranges of {} using percentiles, ranges have equal widths. {}",
+                layer.name,
+                error,
+            ));
+            layer.oid_range_queries()
+        }
+    }
+}
+
+/// Write the chunk queries `settings` would fetch for `layer` into `out`, without fetching any
+/// feature. With a sample only the sampled queries are written. Strategies that ask the server
+/// to plan their queries are only planned when --strategy names them, and never offline.
+pub(crate) async fn write_plan<W: Write>(
+    out: &mut W,
+    layer: &RestServiceMetadata,
+    settings: &ScrapeSettings,
+    offline: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Some(size) = &settings.sample {
+        if offline && settings.sample_method == SampleMethod::Random {
+            writeln!(out, "Random samples need the server to pick their object ids, skipped")?;
+            return Ok(())
+        }
+        match sampling::sample_queries(layer, size, &settings.sample_method).await {
+            Ok(queries) => write_queries(out, "The sample", &queries)?,
+            Err(error) => report::warn_about(WarningKind::Skipped, &layer.name, format_args!(
+                "The sample of the layer cannot be planned. {}",
+                error,
+            )),
+        }
+        return Ok(())
+    }
+    let strategies = settings.strategy.chain(layer);
+    if strategies.is_empty() {
+        report::warn_about(WarningKind::Skipped, &layer.name, format_args!("{}", StrategyError::NoStrategy(layer.name.to_owned())));
+    }
+    for strategy in strategies {
+        let server_planned = matches!(strategy, ScrapeStrategy::ObjectIds | ScrapeStrategy::Quadtree);
+        if server_planned && offline {
+            writeln!(out, "The {} strategy needs the server to plan its queries, skipped", strategy)?;
+            continue
+        }
+        if server_planned && settings.strategy == ScrapeStrategy::Auto {
+            let name = strategy.to_possible_value().map(|value| value.get_name()).unwrap_or_default();
+            writeln!(out, "The {} strategy sends requests to plan its queries, pass --strategy {} to plan it", strategy, name)?;
+            continue
+        }
+        // Balancing OID ranges asks the server for percentiles, offline the ranges are uniform
+        let planned = if offline && strategy == ScrapeStrategy::OidRanges {
+            layer.oid_range_queries()
+        } else {
+            plan_queries(layer, &strategy).await
+        };
+        match planned {
+            Ok(queries) => write_queries(out, &format!("The {} strategy", strategy), &queries)?,
+            Err(error) => report::warn_about(WarningKind::Skipped, &layer.name, format_args!(
+                "The {} strategy cannot plan the layer. {}",
+                strategy,
+                error,
+            )),
+        }
+    }
+    Ok(())
+}
+
+fn write_queries<W: Write>(out: &mut W, planner: &str, queries: &[String]) -> io::Result<()> {
+    writeln!(out, "{} plans {} queries", planner, queries.len())?;
+    for query in queries {
+        writeln!(out, "  {}", query)?;
+    }
+    Ok(())
+}
+
+/// Plan every chunk query needed to scrape the whole layer with `strategy`.
+pub(crate) async fn plan_queries(
+    layer: &RestServiceMetadata,
+    strategy: &ScrapeStrategy,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    match strategy {
+        // Auto is expanded by chain, pagination being its primary strategy
+        ScrapeStrategy::Auto | ScrapeStrategy::Pagination => layer.pagination_queries(),
+        ScrapeStrategy::OidRanges => oid_range_queries(layer).await,
+        ScrapeStrategy::ObjectIds => object_ids_queries(layer).await,
+        ScrapeStrategy::Quadtree => quadtree::quadtree_queries(layer).await,
+    }
+}
+
+#[cfg(test)]
+mod strategy_tests {
+    use std::sync::Arc;
+    use serde_json::json;
+    use crate::audit::{user_agent, RunAudit, RunId, RunIdPlacement};
+    use crate::auth::AnonymousAuth;
+    use crate::client::ServiceClient;
+    use crate::filter::QueryFilter;
+    use crate::metadata::{read_service_metadata, MetadataOverrides, RestServiceMetadata};
+    use crate::pipeline::ScrapeSettings;
+    use crate::sampling::{SampleMethod, SampleSize};
+    use super::{write_plan, ScrapeStrategy};
+
+    async fn layer() -> RestServiceMetadata {
+        let client = ServiceClient::new(
+            Arc::new(AnonymousAuth),
+            RunAudit { run_id: RunId::generate(), placement: RunIdPlacement::Header, user_agent: user_agent(None) },
+        );
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("metadata.json");
+        let metadata = json!({
+            "name": "Parcels",
+            "type": "Feature Layer",
+            "maxRecordCount": 2000,
+            "geometryType": "esriGeometryPolygon",
+            "fields": [{"name": "OBJECTID", "type": "esriFieldTypeOID", "alias": "OBJECTID"}],
+            "sourceSpatialReference": {"wkid": 2913},
+            "advancedQueryCapabilities": {"supportsPagination": true},
+            "count": 5000,
+            "oidRange": [1, 5000],
+        });
+        std::fs::write(&path, metadata.to_string()).unwrap();
+        let url = "https://example.com/arcgis/rest/services/Parcels/FeatureServer/0";
+        read_service_metadata(&client, url, None, &QueryFilter::default(), &path, true, &MetadataOverrides::default())
+            .await
+            .unwrap()
+    }
+
+    fn settings(strategy: ScrapeStrategy) -> ScrapeSettings {
+        ScrapeSettings {
+            strategy,
+            sample: None,
+            sample_method: SampleMethod::First,
+            ignore_disk_space: false,
+            max_pending_chunks: 64,
+        }
+    }
+
+    async fn plan(settings: &ScrapeSettings, offline: bool) -> Vec<String> {
+        let mut out = vec![];
+        write_plan(&mut out, &layer().await, settings, offline).await.unwrap();
+        String::from_utf8(out).unwrap()
+            .lines()
+            .filter(|line| !line.starts_with("  "))
+            .map(str::to_owned)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn write_plan_should_skip_server_planned_strategies_when_strategy_is_auto() {
+        assert_eq!(
+            plan(&settings(ScrapeStrategy::Auto), false).await,
+            vec![
+                "The pagination strategy plans 3 queries",
+                "The OID ranges strategy plans 3 queries",
+                "The object id batches strategy sends requests to plan its queries, pass --strategy object-ids to plan it",
+                "The envelope quadtree strategy sends requests to plan its queries, pass --strategy quadtree to plan it",
+            ],
+        );
+        assert_eq!(
+            plan(&settings(ScrapeStrategy::Quadtree), true).await,
+            vec!["The envelope quadtree strategy needs the server to plan its queries, skipped"],
+        );
+    }
+
+    #[tokio::test]
+    async fn write_plan_should_only_plan_sample_when_sample_is_set() {
+        let mut out = vec![];
+        let settings = ScrapeSettings {
+            sample: Some(SampleSize::Count(2500)),
+            sample_method: SampleMethod::Stride,
+            ..settings(ScrapeStrategy::Auto)
+        };
+        write_plan(&mut out, &layer().await, &settings, false).await.unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "The sample plans 2 queries");
+        assert!(lines[1].contains("resultOffset=0&") && lines[2].contains("resultRecordCount=500"));
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::Write;
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+use tablestream::{col, Stream};
+use crate::geometry::{esri_coordinates, Extent};
+use crate::metadata::RestServiceMetadata;
+
+/// Share of the extent's width and height a coordinate may fall outside of it before it counts
+/// as suspicious, since the extents servers report are often rounded or slightly stale.
+const EXTENT_TOLERANCE: f64 = 0.01;
+
+/// What happens to features with suspicious coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub(crate) enum SuspiciousPolicy {
+    /// Write the feature as sent, only counting it
+    Keep,
+    /// Leave the feature out of the output
+    Drop,
+}
+
+impl SuspiciousPolicy {
+    /// What happens to a suspicious feature, to complete "features were ...".
+    pub(crate) fn outcome(&self) -> &'static str {
+        match self {
+            SuspiciousPolicy::Keep => "written as sent",
+            SuspiciousPolicy::Drop => "dropped",
+        }
+    }
+}
+
+impl Display for SuspiciousPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SuspiciousPolicy::Keep => write!(f, "keep"),
+            SuspiciousPolicy::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+/// Why the coordinates of a feature are suspicious. A feature counts once, under the first
+/// reason found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Suspicion {
+    /// A coordinate at (0, 0), the usual result of a failed geocode
+    NullIsland,
+    /// A coordinate outside the extent of the layer
+    OutsideExtent,
+}
+
+impl Display for Suspicion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Suspicion::NullIsland => write!(f, "null island (0, 0)"),
+            Suspicion::OutsideExtent => write!(f, "outside the layer extent"),
+        }
+    }
+}
+
+/// Features of a layer with suspicious coordinates, by reason.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct SuspiciousCoordinates {
+    reasons: BTreeMap<Suspicion, usize>,
+    dropped: usize,
+}
+
+#[derive(Clone)]
+struct SuspicionRow {
+    reason: String,
+    features: usize,
+}
+
+impl SuspiciousCoordinates {
+    pub(crate) fn merge(&mut self, other: &SuspiciousCoordinates) {
+        for (reason, features) in &other.reasons {
+            *self.reasons.entry(*reason).or_default() += features;
+        }
+        self.dropped += other.dropped;
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.reasons.is_empty()
+    }
+
+    /// Suspicious features for every reason.
+    pub(crate) fn count(&self) -> usize {
+        self.reasons.values().sum()
+    }
+
+    /// Features left out of the output by the drop policy.
+    pub(crate) fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Summary of the suspicious features of a layer.
+    pub(crate) fn write_to_console(&self, layer: &str, policy: &SuspiciousPolicy) -> io::Result<()> {
+        println!("Suspicious coordinates in {} (--suspicious-coordinates {})", layer, policy);
+        let mut out = io::stdout();
+        let mut stream = Stream::new(
+            &mut out,
+            vec![
+                col!(SuspicionRow: .reason).header("Reason"),
+                col!(SuspicionRow: .features).header("Features"),
+            ],
+        );
+        for (reason, features) in &self.reasons {
+            stream.row(SuspicionRow { reason: reason.to_string(), features: *features })?;
+        }
+        stream.finish()?;
+        out.flush()
+    }
+}
+
+/// Flags features with a coordinate at null island or outside the layer's extent, and applies
+/// the policy. The extent is only checked when it is expressed in the spatial reference of the
+/// scraped geometries.
+#[derive(Debug, Clone)]
+pub(crate) struct CoordinateGuard {
+    extent: Option<Extent>,
+    policy: SuspiciousPolicy,
+}
+
+impl CoordinateGuard {
+    pub(crate) fn new(layer: &RestServiceMetadata, policy: SuspiciousPolicy) -> Self {
+        let extent = layer.extent.as_ref()
+            .filter(|extent| extent.spatial_reference == layer.output_spatial_reference())
+            .map(|extent| {
+                let x_margin = (extent.bounds.x_max - extent.bounds.x_min) * EXTENT_TOLERANCE;
+                let y_margin = (extent.bounds.y_max - extent.bounds.y_min) * EXTENT_TOLERANCE;
+                Extent {
+                    x_min: extent.bounds.x_min - x_margin,
+                    y_min: extent.bounds.y_min - y_margin,
+                    x_max: extent.bounds.x_max + x_margin,
+                    y_max: extent.bounds.y_max + y_margin,
+                }
+            });
+        Self { extent, policy }
+    }
+
+    pub(crate) fn policy(&self) -> &SuspiciousPolicy {
+        &self.policy
+    }
+
+    fn suspicion(&self, geometry: &Map<String, Value>) -> Option<Suspicion> {
+        let coordinates = esri_coordinates(geometry);
+        if coordinates.iter().any(|(x, y)| *x == 0.0 && *y == 0.0) {
+            return Some(Suspicion::NullIsland)
+        }
+        let extent = self.extent.as_ref()?;
+        coordinates.iter()
+            .any(|(x, y)| *x < extent.x_min || *x > extent.x_max || *y < extent.y_min || *y > extent.y_max)
+            .then_some(Suspicion::OutsideExtent)
+    }
+
+    /// Check the coordinates of a feature, returning false when the feature is to be dropped.
+    pub(crate) fn apply(&self, feature: &Map<String, Value>, suspicious: &mut SuspiciousCoordinates) -> bool {
+        let suspicion = feature.get("geometry")
+            .and_then(Value::as_object)
+            .and_then(|geometry| self.suspicion(geometry));
+        let suspicion = match suspicion {
+            Some(suspicion) => suspicion,
+            None => return true,
+        };
+        *suspicious.reasons.entry(suspicion).or_default() += 1;
+        if self.policy == SuspiciousPolicy::Drop {
+            suspicious.dropped += 1;
+            return false
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod suspicious_tests {
+    use serde_json::{json, Map, Value};
+    use crate::geometry::Extent;
+    use super::{CoordinateGuard, SuspiciousCoordinates, SuspiciousPolicy};
+
+    fn feature(geometry: Value) -> Map<String, Value> {
+        json!({"attributes": {"OBJECTID": 1}, "geometry": geometry}).as_object().unwrap().to_owned()
+    }
+
+    #[test]
+    fn apply_should_drop_feature_when_at_null_island_or_outside_extent() {
+        let guard = CoordinateGuard {
+            extent: Some(Extent { x_min: -124.0, y_min: 45.0, x_max: -122.0, y_max: 46.0 }),
+            policy: SuspiciousPolicy::Drop,
+        };
+        let mut suspicious = SuspiciousCoordinates::default();
+        assert!(guard.apply(&feature(json!({"x": -123.0, "y": 45.5})), &mut suspicious));
+        assert!(!guard.apply(&feature(json!({"x": 0.0, "y": 0.0})), &mut suspicious));
+        assert!(!guard.apply(&feature(json!({"paths": [[[-123.0, 45.5], [-80.0, 45.5]]]})), &mut suspicious));
+        assert!(guard.apply(&feature(Value::Null), &mut suspicious));
+        assert_eq!(suspicious.count(), 2);
+        assert_eq!(suspicious.dropped(), 2);
+    }
+
+    #[test]
+    fn apply_should_only_check_null_island_when_extent_is_unknown() {
+        let guard = CoordinateGuard { extent: None, policy: SuspiciousPolicy::Keep };
+        let mut suspicious = SuspiciousCoordinates::default();
+        assert!(guard.apply(&feature(json!({"x": 0, "y": 0})), &mut suspicious));
+        assert!(guard.apply(&feature(json!({"x": 170.0, "y": -80.0})), &mut suspicious));
+        assert_eq!(suspicious.count(), 1);
+        assert_eq!(suspicious.dropped(), 0);
+    }
+}
@@ -0,0 +1,242 @@
+use std::fmt::{Display, Formatter};
+use std::error::Error;
+use std::time::Duration;
+use chrono::{Local, NaiveTime};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum BandwidthParseError {
+    InvalidNumber(String),
+    InvalidUnit(String),
+    NotPositive(String),
+}
+
+impl Display for BandwidthParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BandwidthParseError::InvalidNumber(value) => {
+                write!(f, "Could not parse a number from bandwidth \"{}\"", value)
+            }
+            BandwidthParseError::InvalidUnit(value) => {
+                write!(f, "Unknown unit in bandwidth \"{}\". Expected B, KB, MB, GB, KiB, MiB or GiB", value)
+            }
+            BandwidthParseError::NotPositive(value) => {
+                write!(f, "Bandwidth must be greater than zero, got \"{}\"", value)
+            }
+        }
+    }
+}
+
+impl Error for BandwidthParseError {}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum QuietHoursParseError {
+    InvalidWindow(String),
+}
+
+impl Display for QuietHoursParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuietHoursParseError::InvalidWindow(value) => {
+                write!(f, "Could not parse quiet hours \"{}\". Expected HH:MM-HH:MM (e.g. 08:00-18:00)", value)
+            }
+        }
+    }
+}
+
+impl Error for QuietHoursParseError {}
+
+/// Parse a human readable transfer rate (e.g. "10MB/s", "512KiB", "2.5mb/s") into bytes per
+/// second. Decimal units (KB, MB, GB) are powers of 1000 and binary units (KiB, MiB, GiB) are
+/// powers of 1024. The "/s" suffix is optional.
+pub(crate) fn parse_bandwidth(value: &str) -> Result<u64, BandwidthParseError> {
+    let trimmed = value.trim();
+    let without_rate = trimmed.strip_suffix("/s")
+        .or_else(|| trimmed.strip_suffix("/S"))
+        .unwrap_or(trimmed)
+        .trim();
+    let unit_start = without_rate
+        .find(|chr: char| !(chr.is_ascii_digit() || chr == '.'))
+        .unwrap_or(without_rate.len());
+    let (number, unit) = without_rate.split_at(unit_start);
+    let number: f64 = number.parse()
+        .map_err(|_| BandwidthParseError::InvalidNumber(value.to_owned()))?;
+    let multiplier = match unit.trim().to_uppercase().as_str() {
+        "" | "B" => 1_f64,
+        "K" | "KB" => 1_000_f64,
+        "M" | "MB" => 1_000_000_f64,
+        "G" | "GB" => 1_000_000_000_f64,
+        "KIB" => 1_024_f64,
+        "MIB" => 1_048_576_f64,
+        "GIB" => 1_073_741_824_f64,
+        _ => return Err(BandwidthParseError::InvalidUnit(value.to_owned())),
+    };
+    let bytes_per_second = (number * multiplier).round();
+    if bytes_per_second < 1_f64 {
+        return Err(BandwidthParseError::NotPositive(value.to_owned()))
+    }
+    Ok(bytes_per_second as u64)
+}
+
+/// Shared limiter that caps the aggregate download rate of every fetch worker holding a
+/// reference to it. Workers report bytes as they arrive and are put to sleep until the
+/// transfer falls back under the configured rate.
+#[derive(Debug)]
+pub(crate) struct BandwidthLimiter {
+    bytes_per_second: u64,
+    next_available: Mutex<Instant>,
+}
+
+impl BandwidthLimiter {
+    pub(crate) fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            next_available: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub(crate) async fn consume(&self, bytes: usize) {
+        let transfer_time = Duration::from_secs_f64(
+            bytes as f64 / self.bytes_per_second as f64
+        );
+        let wake_time = {
+            let mut next_available = self.next_available.lock().await;
+            let start = (*next_available).max(Instant::now());
+            *next_available = start + transfer_time;
+            *next_available
+        };
+        tokio::time::sleep_until(wake_time).await;
+    }
+}
+
+/// Daily window of local time, wrapping past midnight when it ends before it starts (e.g.
+/// 22:00-06:00).
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct QuietHours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl QuietHours {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Parse a window of local time written as `HH:MM-HH:MM`.
+pub(crate) fn parse_quiet_hours(value: &str) -> Result<QuietHours, QuietHoursParseError> {
+    let invalid = || QuietHoursParseError::InvalidWindow(value.to_owned());
+    let (start, end) = value.trim().split_once('-').ok_or_else(invalid)?;
+    let parse_time = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").map_err(|_| invalid());
+    let (start, end) = (parse_time(start)?, parse_time(end)?);
+    if start == end {
+        return Err(invalid())
+    }
+    Ok(QuietHours { start, end })
+}
+
+/// Shared pacer spacing out the requests of every fetch worker holding a reference to it by a
+/// fixed delay, but only during the quiet hours (e.g. the business hours of the source server's
+/// users). Outside of them requests go out as fast as the workers make them.
+#[derive(Debug)]
+pub(crate) struct RequestPacer {
+    quiet_hours: QuietHours,
+    delay: Duration,
+    next_request: Mutex<Instant>,
+}
+
+impl RequestPacer {
+    pub(crate) fn new(quiet_hours: QuietHours, delay: Duration) -> Self {
+        Self {
+            quiet_hours,
+            delay,
+            next_request: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait for the next request slot when in the quiet hours.
+    pub(crate) async fn pause(&self) {
+        if !self.quiet_hours.contains(Local::now().time()) {
+            return
+        }
+        let wake_time = {
+            let mut next_request = self.next_request.lock().await;
+            let start = (*next_request).max(Instant::now());
+            *next_request = start + self.delay;
+            start
+        };
+        tokio::time::sleep_until(wake_time).await;
+    }
+}
+
+#[cfg(test)]
+mod quiet_hours_tests {
+    use chrono::NaiveTime;
+    use super::{parse_quiet_hours, QuietHoursParseError};
+
+    #[test]
+    fn parse_quiet_hours_should_wrap_past_midnight_when_end_before_start() -> Result<(), QuietHoursParseError> {
+        let quiet_hours = parse_quiet_hours("22:00-06:30")?;
+        assert!(quiet_hours.contains(NaiveTime::from_hms_opt(23, 15, 0).unwrap()));
+        assert!(quiet_hours.contains(NaiveTime::from_hms_opt(6, 0, 0).unwrap()));
+        assert!(!quiet_hours.contains(NaiveTime::from_hms_opt(12, 0, 0).unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_quiet_hours_should_fail_when_passed_single_time() {
+        assert_eq!(
+            parse_quiet_hours("08:00").unwrap_err(),
+            QuietHoursParseError::InvalidWindow("08:00".to_owned()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod bandwidth_tests {
+    use super::{parse_bandwidth, BandwidthParseError};
+
+    #[test]
+    fn parse_bandwidth_should_return_bytes_when_passed_decimal_megabytes() -> Result<(), BandwidthParseError> {
+        let result = parse_bandwidth("10MB/s")?;
+        assert_eq!(result, 10_000_000);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_bandwidth_should_return_bytes_when_passed_binary_kilobytes() -> Result<(), BandwidthParseError> {
+        let result = parse_bandwidth("512KiB")?;
+        assert_eq!(result, 524_288);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_bandwidth_should_return_bytes_when_passed_fractional_value() -> Result<(), BandwidthParseError> {
+        let result = parse_bandwidth("2.5 mb/s")?;
+        assert_eq!(result, 2_500_000);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_bandwidth_should_fail_when_passed_unknown_unit() {
+        let result = parse_bandwidth("10XB/s");
+        assert_eq!(
+            result.unwrap_err(),
+            BandwidthParseError::InvalidUnit("10XB/s".to_owned()),
+        );
+    }
+
+    #[test]
+    fn parse_bandwidth_should_fail_when_passed_zero() {
+        let result = parse_bandwidth("0MB/s");
+        assert_eq!(
+            result.unwrap_err(),
+            BandwidthParseError::NotPositive("0MB/s".to_owned()),
+        );
+    }
+}
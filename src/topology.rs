@@ -0,0 +1,822 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use clap::ValueEnum;
+use serde_json::{json, Map, Value};
+use crate::checksum::{Artifact, ChecksumFile};
+use crate::geometry::Extent;
+use crate::metadata::RestServiceGeometryType;
+use crate::projection::transform_point;
+use crate::report;
+use crate::vector_tiles::{group_rings, signed_area};
+
+const WGS84: i64 = 4326;
+
+/// Coordinate in the spatial reference of the scraped layers.
+type Point = [f64; 2];
+/// Exact identity of a coordinate, so coordinates can be hashed.
+type PointKey = (u64, u64);
+
+fn point_key(point: &Point) -> PointKey {
+    // Adding zero turns -0.0 into 0.0 so both have the same bits
+    ((point[0] + 0_f64).to_bits(), (point[1] + 0_f64).to_bits())
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum StrictGeojsonError {
+    NotGeojson,
+    /// Spatial reference of the layers that cannot be projected to WGS84 client-side
+    Unprojectable(Option<i64>),
+}
+
+impl Display for StrictGeojsonError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StrictGeojsonError::NotGeojson => {
+                write!(f, "--strict-geojson applies to a --topology file with --topology-format geojson")
+            }
+            StrictGeojsonError::Unprojectable(spatial_reference) => write!(
+                f,
+                "RFC 7946 GeoJSON is in WGS84 but the layers are in spatial reference {}. Pass -s 4326 or 3857",
+                spatial_reference.map_or_else(|| "unknown".to_owned(), |wkid| wkid.to_string()),
+            ),
+        }
+    }
+}
+
+impl Error for StrictGeojsonError {}
+
+/// Position in WGS84 of `point`, in `spatial_reference`. None when a coordinate is not finite.
+fn wgs84_position(point: &Point, spatial_reference: i64) -> Option<Point> {
+    let (x, y) = transform_point(point[0], point[1], spatial_reference, WGS84)?;
+    Some([x, y]).filter(|_| x.is_finite() && y.is_finite())
+}
+
+/// `ring` wound counter-clockwise when `counter_clockwise`, clockwise otherwise.
+fn wind(mut ring: Vec<Point>, counter_clockwise: bool) -> Vec<Point> {
+    if (signed_area(&ring) > 0_f64) != counter_clockwise {
+        ring.reverse();
+    }
+    ring
+}
+
+/// Check RFC 7946 GeoJSON can be written in `format` from layers in `spatial_reference`,
+/// returning the spatial reference to project from.
+pub(crate) fn check_strict_geojson(
+    format: &TopologyFormat,
+    spatial_reference: Option<i64>,
+) -> Result<i64, StrictGeojsonError> {
+    if *format != TopologyFormat::Geojson {
+        return Err(StrictGeojsonError::NotGeojson)
+    }
+    spatial_reference
+        .filter(|wkid| transform_point(0_f64, 0_f64, *wkid, WGS84).is_some())
+        .ok_or(StrictGeojsonError::Unprojectable(spatial_reference))
+}
+
+/// Format of the --topology output.
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+pub(crate) enum TopologyFormat {
+    /// A TopoJSON topology with one object per layer
+    Topojson,
+    /// A GeoJSON FeatureCollection rebuilt from the simplified arcs
+    Geojson,
+}
+
+/// Geometry of a feature before it is cut into arcs.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum TopologyGeometry {
+    Points(Vec<Point>),
+    Lines(Vec<Vec<Point>>),
+    /// Each polygon is its exterior ring followed by its holes, rings not repeating the
+    /// first point
+    Polygons(Vec<Vec<Vec<Point>>>),
+}
+
+/// Scraped feature kept in the spatial reference of the layer.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TopologyFeature {
+    pub(crate) geometry: TopologyGeometry,
+    pub(crate) properties: Map<String, Value>,
+}
+
+fn parse_position(position: &Value) -> Option<Point> {
+    let position = position.as_array()?;
+    Some([position.first()?.as_f64()?, position.get(1)?.as_f64()?])
+}
+
+/// Parts of an Esri geometry with consecutive duplicate positions removed.
+fn parse_parts(parts: &Value) -> Option<Vec<Vec<Point>>> {
+    parts.as_array()?
+        .iter()
+        .map(|part| {
+            let mut positions = part.as_array()?
+                .iter()
+                .map(parse_position)
+                .collect::<Option<Vec<Point>>>()?;
+            positions.dedup();
+            Some(positions)
+        })
+        .collect()
+}
+
+impl TopologyFeature {
+    /// Feature from an Esri JSON feature, None when it has no usable geometry.
+    pub(crate) fn from_esri_json(
+        feature: &Map<String, Value>,
+        geo_type: &RestServiceGeometryType,
+    ) -> Option<TopologyFeature> {
+        let geometry = feature.get("geometry")?.as_object()?;
+        let geometry = match geo_type {
+            RestServiceGeometryType::Point => {
+                let position = Value::from(vec![geometry.get("x")?.to_owned(), geometry.get("y")?.to_owned()]);
+                TopologyGeometry::Points(vec![parse_position(&position)?])
+            }
+            RestServiceGeometryType::Multipoint => TopologyGeometry::Points(
+                geometry.get("points")?
+                    .as_array()?
+                    .iter()
+                    .map(parse_position)
+                    .collect::<Option<Vec<Point>>>()?
+            ),
+            RestServiceGeometryType::Polyline => TopologyGeometry::Lines(
+                parse_parts(geometry.get("paths")?)?
+                    .into_iter()
+                    .filter(|path| path.len() > 1)
+                    .collect()
+            ),
+            RestServiceGeometryType::Polygon => {
+                TopologyGeometry::Polygons(group_rings(parse_parts(geometry.get("rings")?)?))
+            }
+            RestServiceGeometryType::Envelope => {
+                let extent = Extent::from_esri_json(&Value::from(geometry.to_owned()))?;
+                TopologyGeometry::Polygons(vec![vec![vec![
+                    [extent.x_min, extent.y_min],
+                    [extent.x_min, extent.y_max],
+                    [extent.x_max, extent.y_max],
+                    [extent.x_max, extent.y_min],
+                ]]])
+            }
+            RestServiceGeometryType::None => return None,
+        };
+        let properties = feature.get("attributes")
+            .and_then(Value::as_object)
+            .cloned()
+            .unwrap_or_default();
+        Some(TopologyFeature { geometry, properties })
+    }
+}
+
+/// Geometry expressed as references into the shared arcs. A reference `i` follows arc `i`
+/// and `!i` (`-i - 1`) follows arc `i` reversed, as in TopoJSON.
+#[derive(Debug, Clone, PartialEq)]
+enum ArcGeometry {
+    Points(Vec<Point>),
+    Lines(Vec<Vec<i64>>),
+    Polygons(Vec<Vec<Vec<i64>>>),
+}
+
+/// Positions where lines or rings meet or part ways. Arcs are cut at every junction so the
+/// stretches shared by several features become a single arc.
+fn find_junctions(lines: &[&[Point]], rings: &[&[Point]]) -> HashSet<PointKey> {
+    let mut junctions = HashSet::new();
+    let mut neighbours: HashMap<PointKey, (Option<PointKey>, Option<PointKey>)> = HashMap::new();
+    let mut visit = |previous: Option<&Point>, point: &Point, next: Option<&Point>| {
+        let key = point_key(point);
+        let (previous, next) = (previous.map(point_key), next.map(point_key));
+        let pair = if previous <= next { (previous, next) } else { (next, previous) };
+        match neighbours.get(&key) {
+            Some(seen) if *seen != pair => {
+                junctions.insert(key);
+            }
+            Some(_) => {}
+            None => {
+                neighbours.insert(key, pair);
+            }
+        }
+    };
+    for line in lines {
+        for (index, point) in line.iter().enumerate() {
+            visit(index.checked_sub(1).map(|previous| &line[previous]), point, line.get(index + 1));
+        }
+    }
+    for ring in rings {
+        for (index, point) in ring.iter().enumerate() {
+            let previous = &ring[(index + ring.len() - 1) % ring.len()];
+            visit(Some(previous), point, Some(&ring[(index + 1) % ring.len()]));
+        }
+    }
+    for line in lines {
+        for end in [line.first(), line.last()].into_iter().flatten() {
+            junctions.insert(point_key(end));
+        }
+    }
+    junctions
+}
+
+/// Distance from `point` to the segment `start`-`end`.
+fn segment_distance(point: &Point, start: &Point, end: &Point) -> f64 {
+    let (dx, dy) = (end[0] - start[0], end[1] - start[1]);
+    let length = dx * dx + dy * dy;
+    let t = if length == 0_f64 {
+        0_f64
+    } else {
+        (((point[0] - start[0]) * dx + (point[1] - start[1]) * dy) / length).clamp(0_f64, 1_f64)
+    };
+    (point[0] - start[0] - t * dx).hypot(point[1] - start[1] - t * dy)
+}
+
+/// Douglas-Peucker simplification keeping both ends of `points`.
+fn simplify(points: &[Point], tolerance: f64) -> Vec<Point> {
+    if tolerance <= 0_f64 || points.len() < 3 {
+        return points.to_vec()
+    }
+    let last = points.len() - 1;
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[last] = true;
+    let mut stack = vec![(0, last)];
+    while let Some((start, end)) = stack.pop() {
+        let farthest = (start + 1..end)
+            .map(|index| (index, segment_distance(&points[index], &points[start], &points[end])))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((index, distance)) = farthest {
+            if distance > tolerance {
+                keep[index] = true;
+                stack.push((start, index));
+                stack.push((index, end));
+            }
+        }
+    }
+    points.iter()
+        .zip(keep)
+        .filter_map(|(point, keep)| Some(*point).filter(|_| keep))
+        .collect()
+}
+
+/// Arcs shared by the lines and rings of every feature.
+#[derive(Debug, Default)]
+struct Arcs {
+    arcs: Vec<Vec<Point>>,
+    index: HashMap<Vec<PointKey>, usize>,
+}
+
+impl Arcs {
+    /// Reference to `arc`, reusing an existing arc when the same positions were seen before in
+    /// either direction.
+    fn intern(&mut self, arc: Vec<Point>) -> i64 {
+        let keys: Vec<PointKey> = arc.iter().map(point_key).collect();
+        if let Some(index) = self.index.get(&keys) {
+            return *index as i64
+        }
+        let reversed: Vec<PointKey> = keys.iter().rev().cloned().collect();
+        if let Some(index) = self.index.get(&reversed) {
+            return !(*index as i64)
+        }
+        self.index.insert(keys, self.arcs.len());
+        self.arcs.push(arc);
+        self.arcs.len() as i64 - 1
+    }
+
+    /// Cut `points` at every interior junction into arcs.
+    fn cut(&mut self, points: &[Point], junctions: &HashSet<PointKey>) -> Vec<i64> {
+        let mut references = vec![];
+        let mut start = 0;
+        for index in 1..points.len() {
+            if index == points.len() - 1 || junctions.contains(&point_key(&points[index])) {
+                references.push(self.intern(points[start..=index].to_vec()));
+                start = index;
+            }
+        }
+        references
+    }
+
+    fn line(&mut self, line: &[Point], junctions: &HashSet<PointKey>) -> Vec<i64> {
+        self.cut(line, junctions)
+    }
+
+    /// Arcs of a ring, starting at its first junction. Rings without a junction become a single
+    /// closed arc starting at their smallest position, so the same ring is found again whatever
+    /// position it starts at.
+    fn ring(&mut self, ring: &[Point], junctions: &HashSet<PointKey>) -> Vec<i64> {
+        let start = ring.iter()
+            .position(|point| junctions.contains(&point_key(point)))
+            .unwrap_or_else(|| {
+                (0..ring.len()).min_by_key(|index| point_key(&ring[*index])).unwrap_or_default()
+            });
+        let mut closed: Vec<Point> = ring[start..].iter().chain(&ring[..start]).cloned().collect();
+        closed.push(ring[start]);
+        self.cut(&closed, junctions)
+    }
+
+    /// Simplify every arc once, so features sharing an arc stay coincident. Closed arcs keep
+    /// enough positions to remain a ring.
+    fn simplify(&mut self, tolerance: f64) {
+        for arc in &mut self.arcs {
+            let simplified = simplify(arc, tolerance);
+            if arc.first() != arc.last() || simplified.len() >= 4 {
+                *arc = simplified;
+            }
+        }
+    }
+
+    /// Positions of a line or ring following `references`.
+    fn positions(&self, references: &[i64]) -> Vec<Point> {
+        let mut positions: Vec<Point> = vec![];
+        for reference in references {
+            let arc = &self.arcs[if *reference < 0 { !*reference } else { *reference } as usize];
+            let mut arc_positions: Vec<Point> = if *reference < 0 {
+                arc.iter().rev().cloned().collect()
+            } else {
+                arc.to_owned()
+            };
+            if !positions.is_empty() {
+                arc_positions.remove(0);
+            }
+            positions.append(&mut arc_positions);
+        }
+        positions
+    }
+}
+
+/// Grid TopoJSON coordinates are snapped to, as in the `transform` member of a quantized
+/// topology. Positions are `(x - translate) / scale` rounded to an integer.
+#[derive(Debug, Clone, PartialEq)]
+struct Quantization {
+    scale: [f64; 2],
+    translate: [f64; 2],
+}
+
+impl Quantization {
+    /// Grid of `positions` positions per axis spanning `bounds`.
+    fn new(bounds: &Extent, positions: u32) -> Quantization {
+        let scale = |min: f64, max: f64| {
+            if max > min { (max - min) / f64::from(positions - 1) } else { 1_f64 }
+        };
+        Quantization {
+            scale: [scale(bounds.x_min, bounds.x_max), scale(bounds.y_min, bounds.y_max)],
+            translate: [bounds.x_min, bounds.y_min],
+        }
+    }
+
+    fn quantize(&self, point: &Point) -> [i64; 2] {
+        [
+            ((point[0] - self.translate[0]) / self.scale[0]).round() as i64,
+            ((point[1] - self.translate[1]) / self.scale[1]).round() as i64,
+        ]
+    }
+
+    /// Quantized arc, delta encoded so every position after the first is relative to the one
+    /// before it. Positions snapping together are merged, keeping at least two.
+    fn arc(&self, arc: &[Point]) -> Vec<[i64; 2]> {
+        let mut positions: Vec<[i64; 2]> = arc.iter().map(|point| self.quantize(point)).collect();
+        positions.dedup();
+        if positions.len() == 1 {
+            positions.push(positions[0]);
+        }
+        let mut previous = [0, 0];
+        positions.into_iter()
+            .map(|position| {
+                let delta = [position[0] - previous[0], position[1] - previous[1]];
+                previous = position;
+                delta
+            })
+            .collect()
+    }
+}
+
+/// Geometry (None once it collapsed) and properties of a feature in a topology.
+type ArcFeature = (Option<ArcGeometry>, Map<String, Value>);
+
+/// Layers of a topology with their features as references into the shared arcs.
+#[derive(Debug)]
+struct Topology {
+    arcs: Arcs,
+    layers: Vec<(String, Vec<ArcFeature>)>,
+}
+
+impl Topology {
+    /// Cut the features of every layer into shared arcs and simplify them. Rings collapsing
+    /// under the tolerance are dropped, and polygons losing their exterior ring with them.
+    fn build(layers: Vec<SinkLayer>, tolerance: f64) -> Topology {
+        let (mut lines, mut rings): (Vec<&[Point]>, Vec<&[Point]>) = (vec![], vec![]);
+        for feature in layers.iter().flat_map(|layer| &layer.features) {
+            match &feature.geometry {
+                TopologyGeometry::Points(_) => {}
+                TopologyGeometry::Lines(parts) => lines.extend(parts.iter().map(Vec::as_slice)),
+                TopologyGeometry::Polygons(polygons) => {
+                    rings.extend(polygons.iter().flatten().map(Vec::as_slice))
+                }
+            }
+        }
+        let junctions = find_junctions(&lines, &rings);
+        let mut arcs = Arcs::default();
+        let mut arc_layers = vec![];
+        for layer in &layers {
+            let features = layer.features.iter()
+                .map(|feature| {
+                    let geometry = match &feature.geometry {
+                        TopologyGeometry::Points(points) => ArcGeometry::Points(points.to_owned()),
+                        TopologyGeometry::Lines(parts) => ArcGeometry::Lines(
+                            parts.iter().map(|line| arcs.line(line, &junctions)).collect()
+                        ),
+                        TopologyGeometry::Polygons(polygons) => ArcGeometry::Polygons(
+                            polygons.iter()
+                                .map(|polygon| polygon.iter().map(|ring| arcs.ring(ring, &junctions)).collect())
+                                .collect()
+                        ),
+                    };
+                    (geometry, feature.properties.to_owned())
+                })
+                .collect::<Vec<_>>();
+            arc_layers.push((layer.name.to_owned(), features));
+        }
+        arcs.simplify(tolerance);
+        let layers = arc_layers.into_iter()
+            .map(|(name, features)| {
+                let features = features.into_iter()
+                    .map(|(geometry, properties)| (Topology::prune(&arcs, geometry), properties))
+                    .collect();
+                (name, features)
+            })
+            .collect();
+        Topology { arcs, layers }
+    }
+
+    fn prune(arcs: &Arcs, geometry: ArcGeometry) -> Option<ArcGeometry> {
+        let geometry = match geometry {
+            ArcGeometry::Polygons(polygons) => ArcGeometry::Polygons(
+                polygons.into_iter()
+                    .filter_map(|polygon| {
+                        let rings: Vec<Vec<i64>> = polygon.into_iter()
+                            .filter(|ring| arcs.positions(ring).len() >= 4)
+                            .collect();
+                        Some(rings).filter(|rings| !rings.is_empty())
+                    })
+                    .collect()
+            ),
+            other => other,
+        };
+        match &geometry {
+            ArcGeometry::Points(points) if points.is_empty() => None,
+            ArcGeometry::Lines(lines) if lines.is_empty() => None,
+            ArcGeometry::Polygons(polygons) if polygons.is_empty() => None,
+            _ => Some(geometry),
+        }
+    }
+
+    /// Bounds of every arc and point, None when there are no positions.
+    fn bounds(&self) -> Option<Extent> {
+        let points = self.layers.iter()
+            .flat_map(|(_, features)| features)
+            .filter_map(|(geometry, _)| match geometry {
+                Some(ArcGeometry::Points(points)) => Some(points),
+                _ => None,
+            })
+            .flatten();
+        self.arcs.arcs.iter()
+            .flatten()
+            .chain(points)
+            .map(|point| Extent { x_min: point[0], y_min: point[1], x_max: point[0], y_max: point[1] })
+            .reduce(|bounds, point| bounds.union(&point))
+    }
+
+    /// TopoJSON geometry object of a feature. Point coordinates are quantized when the
+    /// topology is.
+    fn topojson_geometry(
+        geometry: &Option<ArcGeometry>,
+        properties: &Map<String, Value>,
+        quantization: Option<&Quantization>,
+    ) -> Value {
+        let coordinates = |points: &[Point]| -> Vec<Value> {
+            points.iter()
+                .map(|point| match quantization {
+                    Some(quantization) => json!(quantization.quantize(point)),
+                    None => json!(point),
+                })
+                .collect()
+        };
+        let mut object = match geometry {
+            None => json!({"type": null}),
+            Some(ArcGeometry::Points(points)) if points.len() == 1 => {
+                json!({"type": "Point", "coordinates": coordinates(points)[0]})
+            }
+            Some(ArcGeometry::Points(points)) => {
+                json!({"type": "MultiPoint", "coordinates": coordinates(points)})
+            }
+            Some(ArcGeometry::Lines(lines)) if lines.len() == 1 => {
+                json!({"type": "LineString", "arcs": lines[0]})
+            }
+            Some(ArcGeometry::Lines(lines)) => json!({"type": "MultiLineString", "arcs": lines}),
+            Some(ArcGeometry::Polygons(polygons)) if polygons.len() == 1 => {
+                json!({"type": "Polygon", "arcs": polygons[0]})
+            }
+            Some(ArcGeometry::Polygons(polygons)) => json!({"type": "MultiPolygon", "arcs": polygons}),
+        };
+        if !properties.is_empty() {
+            object["properties"] = Value::Object(properties.to_owned());
+        }
+        object
+    }
+
+    /// GeoJSON geometry of a feature rebuilt from the arcs. Rings are reversed to the
+    /// counter-clockwise exteriors of RFC 7946.
+    fn geojson_geometry(&self, geometry: &Option<ArcGeometry>) -> Value {
+        let ring = |references: &Vec<i64>| {
+            let mut positions = self.arcs.positions(references);
+            positions.reverse();
+            positions
+        };
+        match geometry {
+            None => Value::Null,
+            Some(ArcGeometry::Points(points)) if points.len() == 1 => {
+                json!({"type": "Point", "coordinates": points[0]})
+            }
+            Some(ArcGeometry::Points(points)) => json!({"type": "MultiPoint", "coordinates": points}),
+            Some(ArcGeometry::Lines(lines)) if lines.len() == 1 => {
+                json!({"type": "LineString", "coordinates": self.arcs.positions(&lines[0])})
+            }
+            Some(ArcGeometry::Lines(lines)) => json!({
+                "type": "MultiLineString",
+                "coordinates": lines.iter().map(|line| self.arcs.positions(line)).collect::<Vec<_>>(),
+            }),
+            Some(ArcGeometry::Polygons(polygons)) if polygons.len() == 1 => json!({
+                "type": "Polygon",
+                "coordinates": polygons[0].iter().map(ring).collect::<Vec<_>>(),
+            }),
+            Some(ArcGeometry::Polygons(polygons)) => json!({
+                "type": "MultiPolygon",
+                "coordinates": polygons.iter()
+                    .map(|polygon| polygon.iter().map(ring).collect::<Vec<_>>())
+                    .collect::<Vec<_>>(),
+            }),
+        }
+    }
+
+    /// RFC 7946 geometry of a feature, rebuilt from the arcs with positions projected from
+    /// `spatial_reference` to WGS84, exterior rings counter-clockwise and holes clockwise
+    /// whatever their winding in the layer. None when a position is not finite.
+    fn strict_geojson_geometry(&self, geometry: &Option<ArcGeometry>, spatial_reference: i64) -> Option<Value> {
+        let project = |points: &[Point]| {
+            points.iter()
+                .map(|point| wgs84_position(point, spatial_reference))
+                .collect::<Option<Vec<Point>>>()
+        };
+        // The exterior ring of a polygon comes first, then its holes
+        let polygon = |polygon: &Vec<Vec<i64>>| {
+            polygon.iter()
+                .enumerate()
+                .map(|(index, ring)| Some(wind(project(&self.arcs.positions(ring))?, index == 0)))
+                .collect::<Option<Vec<_>>>()
+        };
+        let geometry = match geometry {
+            None => Value::Null,
+            Some(ArcGeometry::Points(points)) if points.len() == 1 => {
+                json!({"type": "Point", "coordinates": project(points)?[0]})
+            }
+            Some(ArcGeometry::Points(points)) => json!({"type": "MultiPoint", "coordinates": project(points)?}),
+            Some(ArcGeometry::Lines(lines)) if lines.len() == 1 => {
+                json!({"type": "LineString", "coordinates": project(&self.arcs.positions(&lines[0]))?})
+            }
+            Some(ArcGeometry::Lines(lines)) => json!({
+                "type": "MultiLineString",
+                "coordinates": lines.iter()
+                    .map(|line| project(&self.arcs.positions(line)))
+                    .collect::<Option<Vec<_>>>()?,
+            }),
+            Some(ArcGeometry::Polygons(polygons)) if polygons.len() == 1 => {
+                json!({"type": "Polygon", "coordinates": polygon(&polygons[0])?})
+            }
+            Some(ArcGeometry::Polygons(polygons)) => json!({
+                "type": "MultiPolygon",
+                "coordinates": polygons.iter().map(polygon).collect::<Option<Vec<_>>>()?,
+            }),
+        };
+        Some(geometry)
+    }
+
+    /// Write the TopoJSON topology, quantized to `quantization` positions per axis when given.
+    fn write_topojson(&self, writer: &mut impl Write, quantization: Option<u32>) -> io::Result<()> {
+        let quantization = quantization
+            .zip(self.bounds())
+            .map(|(positions, bounds)| Quantization::new(&bounds, positions));
+        write!(writer, "{{\"type\":\"Topology\",")?;
+        if let Some(quantization) = &quantization {
+            let transform = json!({"scale": quantization.scale, "translate": quantization.translate});
+            write!(writer, "\"transform\":{},", transform)?;
+        }
+        write!(writer, "\"objects\":{{")?;
+        for (layer_index, (name, features)) in self.layers.iter().enumerate() {
+            if layer_index > 0 {
+                write!(writer, ",")?;
+            }
+            write!(writer, "{}:{{\"type\":\"GeometryCollection\",\"geometries\":[", Value::from(name.as_str()))?;
+            for (index, (geometry, properties)) in features.iter().enumerate() {
+                if index > 0 {
+                    write!(writer, ",")?;
+                }
+                let object = Topology::topojson_geometry(geometry, properties, quantization.as_ref());
+                serde_json::to_writer(&mut *writer, &object)?;
+            }
+            write!(writer, "]}}")?;
+        }
+        write!(writer, "}},\"arcs\":[")?;
+        for (index, arc) in self.arcs.arcs.iter().enumerate() {
+            if index > 0 {
+                write!(writer, ",")?;
+            }
+            match &quantization {
+                Some(quantization) => serde_json::to_writer(&mut *writer, &quantization.arc(arc))?,
+                None => serde_json::to_writer(&mut *writer, arc)?,
+            }
+        }
+        write!(writer, "]}}")
+    }
+
+    /// Write the GeoJSON FeatureCollection. With `strict`, the spatial reference of the layers,
+    /// it follows RFC 7946 and features with non-finite positions are written without geometry.
+    /// Returns the number of such features.
+    fn write_geojson(&self, writer: &mut impl Write, strict: Option<i64>) -> io::Result<usize> {
+        let mut invalid = 0;
+        write!(writer, "{{\"type\":\"FeatureCollection\",\"features\":[")?;
+        let features = self.layers.iter()
+            .flat_map(|(name, features)| features.iter().map(move |feature| (name, feature)));
+        for (index, (name, (geometry, properties))) in features.enumerate() {
+            if index > 0 {
+                write!(writer, ",")?;
+            }
+            let mut properties = properties.to_owned();
+            properties.insert("source_layer".to_owned(), Value::from(name.as_str()));
+            let geometry = match strict {
+                Some(spatial_reference) => self.strict_geojson_geometry(geometry, spatial_reference)
+                    .unwrap_or_else(|| {
+                        invalid += 1;
+                        Value::Null
+                    }),
+                None => self.geojson_geometry(geometry),
+            };
+            let feature = json!({
+                "type": "Feature",
+                "geometry": geometry,
+                "properties": properties,
+            });
+            serde_json::to_writer(&mut *writer, &feature)?;
+        }
+        write!(writer, "]}}")?;
+        Ok(invalid)
+    }
+}
+
+/// Features of one scraped layer.
+#[derive(Debug)]
+struct SinkLayer {
+    name: String,
+    features: Vec<TopologyFeature>,
+}
+
+/// Collects the features of every scraped layer and writes them with the boundaries they
+/// share simplified once, so neighbouring features (in the same or different layers) stay
+/// coincident and no slivers open between them.
+#[derive(Debug)]
+pub(crate) struct TopologySink {
+    format: TopologyFormat,
+    /// Simplification tolerance in units of the spatial reference. 0 keeps every position
+    tolerance: f64,
+    /// Positions per axis TopoJSON coordinates are quantized to, None keeps full precision
+    quantization: Option<u32>,
+    /// Spatial reference of the layers when GeoJSON is written following RFC 7946
+    strict: Option<i64>,
+    layers: Mutex<Vec<SinkLayer>>,
+}
+
+impl TopologySink {
+    pub(crate) fn new(format: TopologyFormat, tolerance: f64, quantization: Option<u32>) -> Self {
+        Self { format, tolerance, quantization, strict: None, layers: Mutex::new(vec![]) }
+    }
+
+    /// Sink writing RFC 7946 GeoJSON from layers in `spatial_reference`, checked with
+    /// [`check_strict_geojson`].
+    pub(crate) fn strict(self, spatial_reference: i64) -> Self {
+        Self { strict: Some(spatial_reference), ..self }
+    }
+
+    pub(crate) fn add_features(&self, layer_name: &str, mut features: Vec<TopologyFeature>) {
+        let mut layers = self.layers.lock().unwrap();
+        match layers.iter_mut().find(|layer| layer.name == layer_name) {
+            Some(layer) => layer.features.append(&mut features),
+            None => layers.push(SinkLayer { name: layer_name.to_owned(), features }),
+        }
+    }
+
+    /// Build the topology of every collected feature and write it to `path`. Returns the number
+    /// of arcs and the written file.
+    pub(crate) fn write(&self, path: &Path) -> io::Result<(usize, Artifact)> {
+        let layers = std::mem::take(&mut *self.layers.lock().unwrap());
+        let topology = Topology::build(layers, self.tolerance);
+        let mut file = BufWriter::new(ChecksumFile::create(path)?);
+        match self.format {
+            TopologyFormat::Topojson => topology.write_topojson(&mut file, self.quantization)?,
+            TopologyFormat::Geojson => {
+                let invalid = topology.write_geojson(&mut file, self.strict)?;
+                if invalid > 0 {
+                    report::warn(format_args!(
+                        "{} features had coordinates that are not finite numbers and were written to {} without geometry",
+                        invalid,
+                        path.display(),
+                    ));
+                }
+            }
+        }
+        let artifact = file.into_inner().map_err(|error| error.into_error())?.finish()?;
+        Ok((topology.arcs.arcs.len(), artifact))
+    }
+}
+
+#[cfg(test)]
+mod topology_tests {
+    use serde_json::{Map, Value};
+    use crate::geometry::Extent;
+    use crate::vector_tiles::signed_area;
+    use super::{
+        check_strict_geojson, simplify, Quantization, SinkLayer, StrictGeojsonError, Topology, TopologyFeature,
+        TopologyFormat, TopologyGeometry,
+    };
+
+    fn polygon(ring: &[[f64; 2]]) -> TopologyFeature {
+        TopologyFeature {
+            geometry: TopologyGeometry::Polygons(vec![vec![ring.to_vec()]]),
+            properties: Map::new(),
+        }
+    }
+
+    #[test]
+    fn build_should_share_arc_when_polygons_are_adjacent() {
+        let left = polygon(&[[0.0, 0.0], [0.0, 1.0], [1.0, 1.0], [1.0, 0.0]]);
+        let right = polygon(&[[1.0, 0.0], [1.0, 1.0], [2.0, 1.0], [2.0, 0.0]]);
+        let layers = vec![
+            SinkLayer { name: "Left".to_owned(), features: vec![left] },
+            SinkLayer { name: "Right".to_owned(), features: vec![right] },
+        ];
+        let topology = Topology::build(layers, 0.0);
+        assert_eq!(topology.arcs.arcs.len(), 3);
+        assert!(topology.arcs.arcs.contains(&vec![[1.0, 1.0], [1.0, 0.0]]));
+    }
+
+    #[test]
+    fn build_should_keep_shared_boundary_coincident_when_simplified() {
+        let boundary = [[1.0, 0.0], [1.2, 0.5], [1.0, 1.0], [1.1, 1.5], [1.0, 2.0]];
+        let mut left = vec![[0.0, 0.0], [0.0, 2.0]];
+        left.extend(boundary.iter().rev());
+        let mut right: Vec<[f64; 2]> = boundary.to_vec();
+        right.extend([[2.0, 2.0], [2.0, 0.0]]);
+        let layers = vec![SinkLayer { name: "Districts".to_owned(), features: vec![polygon(&left), polygon(&right)] }];
+        let topology = Topology::build(layers, 0.15);
+        let shared = topology.arcs.arcs.iter()
+            .find(|arc| arc.first() == Some(&[1.0, 0.0]) || arc.last() == Some(&[1.0, 0.0]))
+            .unwrap();
+        assert_eq!(shared.len(), 3);
+        assert!(shared.contains(&[1.2, 0.5]));
+    }
+
+    #[test]
+    fn quantization_arc_should_delta_encode_when_positions_snap_together() {
+        let bounds = Extent { x_min: 0.0, y_min: 0.0, x_max: 10.0, y_max: 10.0 };
+        let quantization = Quantization::new(&bounds, 11);
+        let arc = [[0.0, 0.0], [0.1, 0.0], [4.0, 2.0], [10.0, 10.0]];
+        assert_eq!(quantization.arc(&arc), vec![[0, 0], [4, 2], [6, 8]]);
+    }
+
+    #[test]
+    fn simplify_should_drop_positions_within_tolerance() {
+        let line = [[0.0, 0.0], [1.0, 0.05], [2.0, -0.05], [3.0, 0.0]];
+        assert_eq!(simplify(&line, 0.1), vec![[0.0, 0.0], [3.0, 0.0]]);
+        assert_eq!(simplify(&line, 0.0), line.to_vec());
+    }
+
+    #[test]
+    fn write_geojson_should_follow_rfc_7946_when_strict() {
+        // Counter-clockwise exterior, as servers reversing rings send them, in Web Mercator
+        let reversed = polygon(&[[0.0, 0.0], [100_000.0, 0.0], [100_000.0, 100_000.0], [0.0, 100_000.0]]);
+        let broken = TopologyFeature {
+            geometry: TopologyGeometry::Points(vec![[f64::NAN, 0.0]]),
+            properties: Map::new(),
+        };
+        let layers = vec![SinkLayer { name: "Parcels".to_owned(), features: vec![reversed, broken] }];
+        let topology = Topology::build(layers, 0.0);
+        let mut written = vec![];
+        assert_eq!(topology.write_geojson(&mut written, Some(3857)).unwrap(), 1);
+
+        let collection: Value = serde_json::from_slice(&written).unwrap();
+        assert!(collection.get("crs").is_none());
+        let ring: Vec<[f64; 2]> = serde_json::from_value(collection["features"][0]["geometry"]["coordinates"][0].to_owned()).unwrap();
+        assert!(ring.iter().all(|[x, y]| (0.0..=1.0).contains(x) && (0.0..=1.0).contains(y)));
+        assert!(signed_area(&ring) > 0.0);
+        assert_eq!(collection["features"][1]["geometry"], Value::Null);
+        assert_eq!(check_strict_geojson(&TopologyFormat::Geojson, Some(2913)), Err(StrictGeojsonError::Unprojectable(Some(2913))));
+        assert_eq!(check_strict_geojson(&TopologyFormat::Topojson, Some(4326)), Err(StrictGeojsonError::NotGeojson));
+    }
+}
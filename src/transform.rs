@@ -0,0 +1,803 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::Write;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, SecondsFormat, Utc};
+use clap::ValueEnum;
+use regex::Regex;
+use reqwest::Url;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use tablestream::{Stream, col};
+use crate::config::{NumberLocale, ScrapeConfig, ValueParser};
+use crate::metadata::{RestServiceField, RestServiceFieldType};
+
+/// Doubles this large are the DBL_MAX style sentinels some services use for missing values.
+const DOUBLE_SENTINEL: f64 = 1e308;
+
+fn normalize_name(name: &str) -> String {
+    name.trim().to_uppercase()
+}
+
+/// Remove the fields listed in `drop_fields` (case-insensitive, like ArcGIS field names) so they
+/// are never read from a response or written to the output. Geometry columns are always kept.
+pub(crate) fn retain_fields(
+    fields: Vec<RestServiceField>,
+    drop_fields: &[String],
+) -> Vec<RestServiceField> {
+    let dropped: HashSet<String> = drop_fields.iter()
+        .map(|name| normalize_name(name))
+        .collect();
+    fields.into_iter()
+        .filter(|field| {
+            field.field_type == RestServiceFieldType::Geometry
+                || !dropped.contains(&normalize_name(&field.name))
+        })
+        .collect()
+}
+
+/// Names from `requested` that do not match any field of the layer.
+pub(crate) fn unknown_fields(
+    fields: &[RestServiceField],
+    requested: &[String],
+) -> Vec<String> {
+    requested.iter()
+        .filter(|name| {
+            !fields.iter().any(|field| normalize_name(&field.name) == normalize_name(name))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Give the fields of `fields` the type of the values the transformations of `config` write, so
+/// typed outputs (GeoParquet, PostGIS, shapefiles) get columns that hold them. Parse rules write
+/// dates for date rules and doubles for number rules, values that do not parse are written as
+/// sent to text outputs and as nulls to typed columns. Hashes are text, as are redacted numbers
+/// and dates once a pattern replaced part of them.
+pub(crate) fn transformed_field_types(
+    fields: Vec<RestServiceField>,
+    config: &ScrapeConfig,
+) -> Vec<RestServiceField> {
+    let parsers: HashMap<String, &ValueParser> = config.parse_rules.iter()
+        .map(|rule| (normalize_name(&rule.field), &rule.parser))
+        .collect();
+    let redacted: HashSet<String> = config.redactions.iter()
+        .map(|rule| normalize_name(&rule.field))
+        .collect();
+    let hashed: HashSet<String> = config.hash_fields.iter()
+        .map(|name| normalize_name(name))
+        .collect();
+    fields.into_iter()
+        .map(|mut field| {
+            let name = normalize_name(&field.name);
+            if let Some(parser) = parsers.get(&name) {
+                field.field_type = match parser {
+                    ValueParser::Date { .. } => RestServiceFieldType::Date,
+                    ValueParser::Number { .. } => RestServiceFieldType::Double,
+                };
+                field.length = None;
+            }
+            let redacted_number = redacted.contains(&name) && matches!(
+                field.field_type,
+                RestServiceFieldType::Date
+                    | RestServiceFieldType::Double
+                    | RestServiceFieldType::Float
+                    | RestServiceFieldType::Integer
+                    | RestServiceFieldType::OID
+                    | RestServiceFieldType::Single
+                    | RestServiceFieldType::SmallInteger
+            );
+            if redacted_number || hashed.contains(&name) {
+                field.field_type = RestServiceFieldType::String;
+                field.length = None;
+            }
+            field
+        })
+        .collect()
+}
+
+/// Lineage columns optionally appended to every output record so each row can be traced back to
+/// the service, layer, run and chunk it was scraped from.
+#[derive(Debug, Clone)]
+pub(crate) struct Provenance {
+    source_url: String,
+    layer_id: String,
+    scraped_at: String,
+}
+
+impl Provenance {
+    pub(crate) const COLUMNS: [&'static str; 4] = [
+        "_SOURCE_URL",
+        "_LAYER_ID",
+        "_SCRAPED_AT",
+        "_CHUNK_ID",
+    ];
+
+    pub(crate) fn new(url: &str, scraped_at: DateTime<Utc>) -> Self {
+        // The layer id is the last path segment, whatever the query string of the url holds
+        let layer_id = Url::parse(url).ok()
+            .and_then(|url| {
+                url.path_segments()?
+                    .rfind(|segment| !segment.is_empty())
+                    .filter(|segment| segment.chars().all(|chr| chr.is_ascii_digit()))
+                    .map(str::to_owned)
+            })
+            .unwrap_or_default();
+        Self {
+            source_url: url.to_owned(),
+            layer_id,
+            scraped_at: scraped_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+        }
+    }
+
+    pub(crate) fn values(&self, chunk_id: usize) -> Vec<String> {
+        vec![
+            self.source_url.to_owned(),
+            self.layer_id.to_owned(),
+            self.scraped_at.to_owned(),
+            chunk_id.to_string(),
+        ]
+    }
+}
+
+/// Attribute level transformations applied to every feature before it is converted to an output
+/// record.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FeatureTransformer {
+    hash_fields: HashSet<String>,
+    hash_salt: String,
+    redactions: HashMap<String, Vec<(Regex, String)>>,
+    parsers: HashMap<String, ValueParser>,
+}
+
+/// Number written with the separators of `locale`, None when it is not one.
+fn parse_number(value: &str, locale: NumberLocale) -> Option<f64> {
+    let (decimal, groups) = locale.separators();
+    let normalized: String = value.trim()
+        .chars()
+        .filter(|chr| !groups.contains(chr))
+        .map(|chr| if chr == decimal { '.' } else { chr })
+        .collect();
+    normalized.parse::<f64>().ok().filter(|number| number.is_finite())
+}
+
+/// Epoch milliseconds of a date written in `format`, trying it with an offset, as a date and
+/// time, then as a date alone.
+fn parse_date(value: &str, format: &str) -> Option<i64> {
+    let value = value.trim();
+    if let Ok(date_time) = DateTime::parse_from_str(value, format) {
+        return Some(date_time.timestamp_millis())
+    }
+    NaiveDateTime::parse_from_str(value, format)
+        .or_else(|_| NaiveDate::parse_from_str(value, format).map(|date| date.and_time(NaiveTime::MIN)))
+        .ok()
+        .map(|date_time| date_time.and_utc().timestamp_millis())
+}
+
+impl FeatureTransformer {
+    pub(crate) fn new(config: &ScrapeConfig) -> Result<Self, regex::Error> {
+        let mut redactions: HashMap<String, Vec<(Regex, String)>> = HashMap::new();
+        for rule in &config.redactions {
+            let pattern = Regex::new(&rule.pattern)?;
+            redactions.entry(normalize_name(&rule.field))
+                .or_default()
+                .push((pattern, rule.replacement.to_owned()));
+        }
+        Ok(Self {
+            hash_fields: config.hash_fields.iter()
+                .map(|name| normalize_name(name))
+                .collect(),
+            hash_salt: config.hash_salt.to_owned().unwrap_or_default(),
+            redactions,
+            parsers: config.parse_rules.iter()
+                .map(|rule| (normalize_name(&rule.field), rule.parser.to_owned()))
+                .collect(),
+        })
+    }
+
+    /// Field names targeted by a redaction rule, as written in the config.
+    pub(crate) fn redacted_fields(config: &ScrapeConfig) -> Vec<String> {
+        config.redactions.iter()
+            .map(|rule| rule.field.to_owned())
+            .collect()
+    }
+
+    /// Field names targeted by a parse rule, as written in the config.
+    pub(crate) fn parsed_fields(config: &ScrapeConfig) -> Vec<String> {
+        config.parse_rules.iter()
+            .map(|rule| rule.field.to_owned())
+            .collect()
+    }
+
+    fn parse_value(parser: &ValueParser, value: &Value) -> Option<Value> {
+        let string = value.as_str()?;
+        match parser {
+            ValueParser::Date { format } => parse_date(string, format).map(Value::from),
+            ValueParser::Number { locale } => parse_number(string, *locale).map(Value::from),
+        }
+    }
+
+    fn redact_value(rules: &[(Regex, String)], value: &Value) -> Value {
+        let original = match value {
+            Value::String(string) => string.to_owned(),
+            Value::Number(num) => num.to_string(),
+            other => return other.to_owned(),
+        };
+        let mut redacted = original.clone();
+        for (pattern, replacement) in rules {
+            redacted = pattern.replace_all(&redacted, replacement.as_str()).into_owned();
+        }
+        if redacted == original { value.to_owned() } else { Value::String(redacted) }
+    }
+
+    fn hash_value(&self, value: &Value) -> Value {
+        let raw = match value {
+            Value::Null => return Value::Null,
+            Value::String(string) => string.to_owned(),
+            other => other.to_string(),
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(self.hash_salt.as_bytes());
+        hasher.update(raw.as_bytes());
+        Value::String(format!("{:x}", hasher.finalize()))
+    }
+
+    pub(crate) fn apply(&self, attributes: &mut Map<String, Value>) {
+        if self.hash_fields.is_empty() && self.redactions.is_empty() && self.parsers.is_empty() {
+            return
+        }
+        for (name, value) in attributes.iter_mut() {
+            let name = normalize_name(name);
+            if let Some(parsed) = self.parsers.get(&name).and_then(|parser| Self::parse_value(parser, value)) {
+                *value = parsed;
+            }
+            if let Some(rules) = self.redactions.get(&name) {
+                *value = Self::redact_value(rules, value);
+            }
+            if self.hash_fields.contains(&name) {
+                *value = self.hash_value(value);
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum NumericValueError {
+    Invalid(String, String),
+}
+
+impl Display for NumericValueError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NumericValueError::Invalid(field, value) => {
+                write!(f, "Field \"{}\" has the invalid numeric value {} (see --invalid-numerics)", field, value)
+            }
+        }
+    }
+}
+
+impl Error for NumericValueError {}
+
+/// What happens to NaN, Infinity and out of range values of numeric fields.
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+pub(crate) enum NumericPolicy {
+    /// Replace the value with null
+    Null,
+    /// Write the value as sent, only counting it
+    Keep,
+    /// Fail the scrape
+    Fail,
+}
+
+impl NumericPolicy {
+    /// What happens to an invalid value, to complete "values were ...".
+    pub(crate) fn outcome(&self) -> &'static str {
+        match self {
+            NumericPolicy::Null => "replaced with null",
+            NumericPolicy::Keep => "written as sent",
+            NumericPolicy::Fail => "rejected",
+        }
+    }
+}
+
+impl Display for NumericPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NumericPolicy::Null => write!(f, "null"),
+            NumericPolicy::Keep => write!(f, "keep"),
+            NumericPolicy::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NumericAnomaly {
+    NonFinite,
+    OutOfRange,
+}
+
+/// Invalid numeric values found in a layer, by field.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct NumericAnomalies {
+    /// Non-finite and out of range counts of each field
+    fields: BTreeMap<String, (usize, usize)>,
+}
+
+#[derive(Clone)]
+struct NumericAnomalyRow {
+    field: String,
+    non_finite: usize,
+    out_of_range: usize,
+}
+
+impl NumericAnomalies {
+    fn record(&mut self, field: &str, anomaly: NumericAnomaly) {
+        let counts = self.fields.entry(field.to_owned()).or_default();
+        match anomaly {
+            NumericAnomaly::NonFinite => counts.0 += 1,
+            NumericAnomaly::OutOfRange => counts.1 += 1,
+        }
+    }
+
+    pub(crate) fn merge(&mut self, other: &NumericAnomalies) {
+        for (field, (non_finite, out_of_range)) in &other.fields {
+            let counts = self.fields.entry(field.to_owned()).or_default();
+            counts.0 += non_finite;
+            counts.1 += out_of_range;
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Invalid values of every field.
+    pub(crate) fn count(&self) -> usize {
+        self.fields.values().map(|(non_finite, out_of_range)| non_finite + out_of_range).sum()
+    }
+
+    /// Summary of the invalid values of a layer and what was done with them.
+    pub(crate) fn write_to_console(&self, layer: &str, policy: &NumericPolicy) -> io::Result<()> {
+        println!("Invalid numeric values in {} (--invalid-numerics {})", layer, policy);
+        let mut out = io::stdout();
+        let mut stream = Stream::new(
+            &mut out,
+            vec![
+                col!(NumericAnomalyRow: .field).header("Field"),
+                col!(NumericAnomalyRow: .non_finite).header("NaN/Infinity"),
+                col!(NumericAnomalyRow: .out_of_range).header("Out of Range"),
+            ],
+        );
+        for (field, (non_finite, out_of_range)) in &self.fields {
+            stream.row(NumericAnomalyRow {
+                field: field.to_owned(),
+                non_finite: *non_finite,
+                out_of_range: *out_of_range,
+            })?;
+        }
+        stream.finish()?;
+        out.flush()
+    }
+}
+
+/// Detects NaN, Infinity and values outside the range of the field type in the numeric fields
+/// of a layer, which break strict JSON consumers and typed writers, and applies the policy.
+#[derive(Debug, Clone)]
+pub(crate) struct NumericGuard {
+    fields: HashMap<String, RestServiceFieldType>,
+    policy: NumericPolicy,
+}
+
+impl NumericGuard {
+    pub(crate) fn new(fields: &[RestServiceField], policy: NumericPolicy) -> Self {
+        let fields = fields.iter()
+            .filter(|field| matches!(
+                field.field_type,
+                RestServiceFieldType::SmallInteger
+                    | RestServiceFieldType::Integer
+                    | RestServiceFieldType::Single
+                    | RestServiceFieldType::Float
+                    | RestServiceFieldType::Double
+            ))
+            .map(|field| (normalize_name(&field.name), field.field_type.to_owned()))
+            .collect();
+        Self { fields, policy }
+    }
+
+    pub(crate) fn policy(&self) -> &NumericPolicy {
+        &self.policy
+    }
+
+    fn check(field_type: &RestServiceFieldType, value: &Value) -> Option<NumericAnomaly> {
+        let number = match value {
+            Value::String(string) => {
+                let text = string.trim().to_lowercase();
+                let text = text.trim_start_matches(['+', '-']);
+                return Some(NumericAnomaly::NonFinite).filter(|_| ["nan", "infinity", "inf"].contains(&text))
+            }
+            Value::Number(number) => number.as_f64()?,
+            _ => return None,
+        };
+        if !number.is_finite() {
+            return Some(NumericAnomaly::NonFinite)
+        }
+        let in_range = match field_type {
+            RestServiceFieldType::SmallInteger => (i16::MIN as f64..=i16::MAX as f64).contains(&number),
+            RestServiceFieldType::Integer => (i32::MIN as f64..=i32::MAX as f64).contains(&number),
+            RestServiceFieldType::Single | RestServiceFieldType::Float => number.abs() <= f32::MAX as f64,
+            _ => number.abs() < DOUBLE_SENTINEL,
+        };
+        Some(NumericAnomaly::OutOfRange).filter(|_| !in_range)
+    }
+
+    pub(crate) fn apply(
+        &self,
+        attributes: &mut Map<String, Value>,
+        anomalies: &mut NumericAnomalies,
+    ) -> Result<(), NumericValueError> {
+        if self.fields.is_empty() {
+            return Ok(())
+        }
+        for (name, value) in attributes.iter_mut() {
+            let anomaly = self.fields
+                .get(&normalize_name(name))
+                .and_then(|field_type| Self::check(field_type, value));
+            let anomaly = match anomaly {
+                Some(anomaly) => anomaly,
+                None => continue,
+            };
+            anomalies.record(name, anomaly);
+            match self.policy {
+                NumericPolicy::Null => *value = Value::Null,
+                NumericPolicy::Keep => {}
+                NumericPolicy::Fail => {
+                    return Err(NumericValueError::Invalid(name.to_owned(), value.to_string()))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What happens to numeric values outside the range domain of their field.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub(crate) enum DomainPolicy {
+    /// Write the value as sent, only counting it
+    Keep,
+    /// Replace the value with null
+    Null,
+}
+
+impl DomainPolicy {
+    /// What happens to a value outside its domain, to complete "values were ...".
+    pub(crate) fn outcome(&self) -> &'static str {
+        match self {
+            DomainPolicy::Keep => "written as sent",
+            DomainPolicy::Null => "replaced with null",
+        }
+    }
+}
+
+impl Display for DomainPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DomainPolicy::Keep => write!(f, "keep"),
+            DomainPolicy::Null => write!(f, "null"),
+        }
+    }
+}
+
+/// Values of a layer outside the range domain of their field, by field.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct DomainViolations {
+    /// Values below the minimum and above the maximum of each field
+    fields: BTreeMap<String, (usize, usize)>,
+}
+
+#[derive(Clone)]
+struct DomainViolationRow {
+    field: String,
+    range: String,
+    below: usize,
+    above: usize,
+}
+
+impl DomainViolations {
+    pub(crate) fn merge(&mut self, other: &DomainViolations) {
+        for (field, (below, above)) in &other.fields {
+            let counts = self.fields.entry(field.to_owned()).or_default();
+            counts.0 += below;
+            counts.1 += above;
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Values outside the domain of every field.
+    pub(crate) fn count(&self) -> usize {
+        self.fields.values().map(|(below, above)| below + above).sum()
+    }
+
+    /// Summary of the values of a layer outside their domains, along with the domains.
+    pub(crate) fn write_to_console(
+        &self,
+        layer: &str,
+        fields: &[RestServiceField],
+        policy: &DomainPolicy,
+    ) -> io::Result<()> {
+        println!("Values outside their range domain in {} (--domain-violations {})", layer, policy);
+        let mut out = io::stdout();
+        let mut stream = Stream::new(
+            &mut out,
+            vec![
+                col!(DomainViolationRow: .field).header("Field"),
+                col!(DomainViolationRow: .range).header("Range"),
+                col!(DomainViolationRow: .below).header("Below"),
+                col!(DomainViolationRow: .above).header("Above"),
+            ],
+        );
+        for (name, (below, above)) in &self.fields {
+            let range = fields.iter()
+                .find(|field| normalize_name(&field.name) == normalize_name(name))
+                .and_then(|field| field.range)
+                .map(|(min, max)| format!("{} to {}", min, max))
+                .unwrap_or_default();
+            stream.row(DomainViolationRow { field: name.to_owned(), range, below: *below, above: *above })?;
+        }
+        stream.finish()?;
+        out.flush()
+    }
+}
+
+/// Validates the numeric values of fields with a range domain against the domain's bounds and
+/// applies the policy. Values that are not finite numbers are left to the [`NumericGuard`].
+#[derive(Debug, Clone)]
+pub(crate) struct DomainGuard {
+    ranges: HashMap<String, (f64, f64)>,
+    policy: DomainPolicy,
+}
+
+impl DomainGuard {
+    pub(crate) fn new(fields: &[RestServiceField], policy: DomainPolicy) -> Self {
+        let ranges = fields.iter()
+            .filter_map(|field| Some((normalize_name(&field.name), field.range?)))
+            .collect();
+        Self { ranges, policy }
+    }
+
+    pub(crate) fn policy(&self) -> &DomainPolicy {
+        &self.policy
+    }
+
+    pub(crate) fn apply(&self, attributes: &mut Map<String, Value>, violations: &mut DomainViolations) {
+        if self.ranges.is_empty() {
+            return
+        }
+        for (name, value) in attributes.iter_mut() {
+            let (min, max) = match self.ranges.get(&normalize_name(name)) {
+                Some(range) => *range,
+                None => continue,
+            };
+            let number = match value.as_f64().filter(|number| number.is_finite()) {
+                Some(number) => number,
+                None => continue,
+            };
+            if (min..=max).contains(&number) {
+                continue
+            }
+            let counts = violations.fields.entry(name.to_owned()).or_default();
+            if number < min {
+                counts.0 += 1;
+            } else {
+                counts.1 += 1;
+            }
+            if self.policy == DomainPolicy::Null {
+                *value = Value::Null;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod feature_transformer_tests {
+    use serde_json::{json, Value};
+    use chrono::Utc;
+    use crate::config::{RedactionRule, ScrapeConfig};
+    use crate::metadata::{parse_fields, RestServiceFieldType, RestServiceGeometryType};
+    use super::{transformed_field_types, FeatureTransformer, Provenance};
+
+    #[test]
+    fn apply_should_hash_listed_fields_when_names_differ_in_case() {
+        let config = ScrapeConfig {
+            hash_fields: vec!["owner_name".to_owned()],
+            ..Default::default()
+        };
+        let transformer = FeatureTransformer::new(&config).unwrap();
+        let mut attributes = json!({"OWNER_NAME": "Jane Doe", "PARCEL": 12}).as_object().unwrap().to_owned();
+        transformer.apply(&mut attributes);
+        assert_eq!(
+            attributes["OWNER_NAME"],
+            Value::String("01332c876518a793b7c1b8dfaf6d4b404ff5db09b21c6627ca59710cc24f696a".to_owned()),
+        );
+        assert_eq!(attributes["PARCEL"], json!(12));
+    }
+
+    #[test]
+    fn apply_should_keep_null_when_hashing_null_value() {
+        let config = ScrapeConfig {
+            hash_fields: vec!["PHONE".to_owned()],
+            ..Default::default()
+        };
+        let transformer = FeatureTransformer::new(&config).unwrap();
+        let mut attributes = json!({"PHONE": null}).as_object().unwrap().to_owned();
+        transformer.apply(&mut attributes);
+        assert_eq!(attributes["PHONE"], Value::Null);
+    }
+
+    #[test]
+    fn apply_should_replace_matches_when_field_has_redaction_rule() {
+        let config = ScrapeConfig {
+            redactions: vec![
+                RedactionRule {
+                    field: "CASE_NO".to_owned(),
+                    pattern: r"(\d{4})-\d+".to_owned(),
+                    replacement: "$1-XXXX".to_owned(),
+                },
+            ],
+            ..Default::default()
+        };
+        let transformer = FeatureTransformer::new(&config).unwrap();
+        let mut attributes = json!({"case_no": "Case 2021-00451", "NOTE": "2021-00451"}).as_object().unwrap().to_owned();
+        transformer.apply(&mut attributes);
+        assert_eq!(attributes["case_no"], json!("Case 2021-XXXX"));
+        assert_eq!(attributes["NOTE"], json!("2021-00451"));
+    }
+
+    #[test]
+    fn new_should_fail_when_redaction_pattern_is_invalid() {
+        let config = ScrapeConfig {
+            redactions: vec![
+                RedactionRule {
+                    field: "CASE_NO".to_owned(),
+                    pattern: "(".to_owned(),
+                    replacement: "".to_owned(),
+                },
+            ],
+            ..Default::default()
+        };
+        assert!(FeatureTransformer::new(&config).is_err());
+    }
+
+    #[test]
+    fn apply_should_parse_local_dates_and_numbers_when_rules_are_configured() {
+        let config: ScrapeConfig = serde_json::from_value(json!({
+            "parse_rules": [
+                {"field": "SALE_DATE", "type": "date", "format": "%d.%m.%Y"},
+                {"field": "PRICE", "type": "number", "locale": "de"},
+                {"field": "AREA", "type": "number", "locale": "fr"},
+            ],
+        })).unwrap();
+        let transformer = FeatureTransformer::new(&config).unwrap();
+        let mut attributes = json!({
+            "SALE_DATE": "17.10.2026",
+            "PRICE": "1.234,5",
+            "AREA": "12\u{a0}000",
+            "NOTE": "1.234,5",
+        }).as_object().unwrap().to_owned();
+        transformer.apply(&mut attributes);
+        assert_eq!(attributes["SALE_DATE"], json!(1792195200000i64));
+        assert_eq!(attributes["PRICE"], json!(1234.5));
+        assert_eq!(attributes["AREA"], json!(12000.0));
+        assert_eq!(attributes["NOTE"], json!("1.234,5"));
+
+        let fields = parse_fields(&[
+            json!({"name": "sale_date", "type": "esriFieldTypeString", "alias": "Sale Date", "length": 10}),
+            json!({"name": "PRICE", "type": "esriFieldTypeString", "alias": "Price", "length": 20}),
+            json!({"name": "NOTE", "type": "esriFieldTypeString", "alias": "Note", "length": 255}),
+        ], &RestServiceGeometryType::None).unwrap();
+        let types: Vec<_> = transformed_field_types(fields, &config).into_iter()
+            .map(|field| (field.field_type, field.length))
+            .collect();
+        assert_eq!(types, vec![
+            (RestServiceFieldType::Date, None),
+            (RestServiceFieldType::Double, None),
+            (RestServiceFieldType::String, Some(255)),
+        ]);
+
+        let mut attributes = json!({"SALE_DATE": "unknown", "PRICE": null}).as_object().unwrap().to_owned();
+        transformer.apply(&mut attributes);
+        assert_eq!(attributes["SALE_DATE"], json!("unknown"));
+        assert_eq!(attributes["PRICE"], Value::Null);
+    }
+
+    #[test]
+    fn provenance_should_take_layer_id_from_path_when_url_has_query_string() {
+        let scraped_at = Utc::now();
+        let layer_id = |url: &str| Provenance::new(url, scraped_at).layer_id;
+        assert_eq!(layer_id("https://example.com/arcgis/rest/services/Parcels/MapServer/3/"), "3");
+        assert_eq!(layer_id("https://example.com/arcgis/rest/services/Parcels/MapServer/3?token=abc/1"), "3");
+        assert_eq!(layer_id("https://example.com/arcgis/rest/services/Parcels/MapServer"), "");
+    }
+}
+
+#[cfg(test)]
+mod numeric_guard_tests {
+    use std::collections::HashMap;
+    use serde_json::json;
+    use crate::metadata::RestServiceFieldType;
+    use super::{NumericAnomalies, NumericGuard, NumericPolicy, NumericValueError};
+
+    fn guard(policy: NumericPolicy) -> NumericGuard {
+        NumericGuard {
+            fields: HashMap::from([
+                ("ACRES".to_owned(), RestServiceFieldType::Double),
+                ("FLOORS".to_owned(), RestServiceFieldType::SmallInteger),
+            ]),
+            policy,
+        }
+    }
+
+    #[test]
+    fn apply_should_null_invalid_values_when_policy_is_null() {
+        let mut attributes = json!({"acres": "NaN", "FLOORS": 40000, "NAME": "Infinity"})
+            .as_object()
+            .unwrap()
+            .to_owned();
+        let mut anomalies = NumericAnomalies::default();
+        guard(NumericPolicy::Null).apply(&mut attributes, &mut anomalies).unwrap();
+        assert_eq!(attributes, json!({"acres": null, "FLOORS": null, "NAME": "Infinity"}).as_object().unwrap().to_owned());
+        assert_eq!(anomalies.fields.get("acres"), Some(&(1, 0)));
+        assert_eq!(anomalies.fields.get("FLOORS"), Some(&(0, 1)));
+    }
+
+    #[test]
+    fn apply_should_flag_double_sentinel_when_policy_is_keep() {
+        let mut attributes = json!({"ACRES": -1.7976931348623157e308, "FLOORS": 3}).as_object().unwrap().to_owned();
+        let mut anomalies = NumericAnomalies::default();
+        guard(NumericPolicy::Keep).apply(&mut attributes, &mut anomalies).unwrap();
+        assert_eq!(attributes["ACRES"], json!(-1.7976931348623157e308));
+        assert_eq!(anomalies.fields.get("ACRES"), Some(&(0, 1)));
+        assert_eq!(anomalies.fields.len(), 1);
+    }
+
+    #[test]
+    fn apply_should_fail_when_policy_is_fail() {
+        let mut attributes = json!({"ACRES": "-Infinity"}).as_object().unwrap().to_owned();
+        assert_eq!(
+            guard(NumericPolicy::Fail).apply(&mut attributes, &mut NumericAnomalies::default()),
+            Err(NumericValueError::Invalid("ACRES".to_owned(), "\"-Infinity\"".to_owned())),
+        );
+    }
+}
+
+#[cfg(test)]
+mod domain_guard_tests {
+    use std::collections::HashMap;
+    use serde_json::{json, Value};
+    use super::{DomainGuard, DomainPolicy, DomainViolations};
+
+    #[test]
+    fn apply_should_null_values_outside_range_when_policy_is_null() {
+        let guard = DomainGuard {
+            ranges: HashMap::from([("SLOPE".to_owned(), (0.0, 90.0))]),
+            policy: DomainPolicy::Null,
+        };
+        let mut violations = DomainViolations::default();
+        let mut attributes = json!({"slope": 120, "OTHER": 120}).as_object().unwrap().to_owned();
+        guard.apply(&mut attributes, &mut violations);
+        let mut attributes_below = json!({"SLOPE": -1.5}).as_object().unwrap().to_owned();
+        guard.apply(&mut attributes_below, &mut violations);
+        let mut attributes_valid = json!({"SLOPE": 45, "OTHER": "NaN"}).as_object().unwrap().to_owned();
+        guard.apply(&mut attributes_valid, &mut violations);
+
+        assert_eq!(attributes["slope"], Value::Null);
+        assert_eq!(attributes["OTHER"], json!(120));
+        assert_eq!(attributes_valid["SLOPE"], json!(45));
+        assert_eq!(violations.fields["slope"], (0, 1));
+        assert_eq!(violations.fields["SLOPE"], (1, 0));
+        assert_eq!(violations.count(), 2);
+    }
+}
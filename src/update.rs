@@ -0,0 +1,324 @@
+use std::env;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::rename;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use reqwest::header::ACCEPT;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::audit::user_agent;
+use crate::report;
+use crate::state::state_directory;
+
+const LATEST_RELEASE_URL: &str = "https://api.github.com/repos/ClasicRando/arcgis_scraper/releases/latest";
+/// Set to skip the startup version check, like --no-version-check.
+pub(crate) const NO_VERSION_CHECK_VARIABLE: &str = "ARCGIS_SCRAPER_NO_VERSION_CHECK";
+/// Startup checks reuse the last answer for this long, so GitHub is asked at most once a day.
+const CHECK_INTERVAL_HOURS: i64 = 24;
+/// A slow or blocked GitHub should never hold up a scrape for long.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+/// Release note lines shown in the startup notice.
+const MAX_FIXES: usize = 5;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum UpdateError {
+    InvalidVersion(String),
+    NoAsset(String),
+    ChecksumMismatch(String),
+    NoChecksum(String),
+}
+
+impl Display for UpdateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpdateError::InvalidVersion(version) => write!(f, "\"{}\" is not a release version", version),
+            UpdateError::NoAsset(platform) => {
+                write!(f, "The latest release has no binary for {}, build it from source instead", platform)
+            }
+            UpdateError::ChecksumMismatch(name) => {
+                write!(f, "Download of {} does not match its published checksum", name)
+            }
+            UpdateError::NoChecksum(name) => {
+                write!(f, "{} has no published checksum to verify, it is not installed", name)
+            }
+        }
+    }
+}
+
+impl Error for UpdateError {}
+
+/// Release version as major, minor and patch. Pre-release suffixes are ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Version(u64, u64, u64);
+
+impl Version {
+    pub(crate) fn current() -> Self {
+        env!("CARGO_PKG_VERSION").parse().expect("Package version is semver")
+    }
+}
+
+impl FromStr for Version {
+    type Err = UpdateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || UpdateError::InvalidVersion(s.to_owned());
+        let core = s.trim().trim_start_matches('v').split(['-', '+']).next().unwrap_or_default();
+        let parts = core.split('.')
+            .map(|part| part.parse::<u64>().map_err(|_| invalid()))
+            .collect::<Result<Vec<u64>, UpdateError>>()?;
+        match parts[..] {
+            [major, minor, patch] => Ok(Version(major, minor, patch)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ReleaseAsset {
+    name: String,
+    browser_download_url: String,
+}
+
+/// Latest GitHub release. Binaries are published as bare executables named
+/// `arcgis_scraper-{arch}-{os}` (`.exe` on Windows), each with a `.sha256` sidecar.
+#[derive(Debug, Clone, Deserialize)]
+struct Release {
+    tag_name: String,
+    html_url: String,
+    #[serde(default)]
+    body: Option<String>,
+    #[serde(default)]
+    assets: Vec<ReleaseAsset>,
+}
+
+impl Release {
+    fn version(&self) -> Result<Version, UpdateError> {
+        self.tag_name.parse()
+    }
+
+    /// Lines of the release notes describing a fix, without their list markers.
+    fn fixes(&self) -> Vec<String> {
+        self.body.as_deref()
+            .unwrap_or_default()
+            .lines()
+            .map(|line| line.trim().trim_start_matches(['-', '*']).trim())
+            .filter(|line| line.to_ascii_lowercase().contains("fix"))
+            .take(MAX_FIXES)
+            .map(ToOwned::to_owned)
+            .collect()
+    }
+
+    /// Binary published for `platform`, named exactly as the release workflow names it.
+    fn asset(&self, platform: &str) -> Option<&ReleaseAsset> {
+        let name = format!("arcgis_scraper-{}{}", platform, env::consts::EXE_SUFFIX);
+        self.assets.iter().find(|asset| asset.name == name)
+    }
+
+    fn checksum_asset(&self, asset: &ReleaseAsset) -> Option<&ReleaseAsset> {
+        let name = format!("{}.sha256", asset.name);
+        self.assets.iter().find(|candidate| candidate.name == name)
+    }
+}
+
+/// Architecture and OS of this binary, as found in release asset names.
+fn platform() -> String {
+    format!("{}-{}", env::consts::ARCH, env::consts::OS)
+}
+
+fn client(timeout: Duration) -> reqwest::Result<Client> {
+    Client::builder()
+        .user_agent(user_agent(None))
+        .timeout(timeout)
+        .build()
+}
+
+async fn latest_release(client: &Client) -> Result<Release, Box<dyn Error + Send + Sync>> {
+    let release = client.get(LATEST_RELEASE_URL)
+        .header(ACCEPT, "application/vnd.github+json")
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(release)
+}
+
+/// Answer of the last startup check, kept in the state directory. A check that could not reach
+/// GitHub is kept without a latest release, so it is not retried on every run.
+#[derive(Debug, Serialize, Deserialize)]
+struct VersionCheck {
+    checked_at: DateTime<Utc>,
+    #[serde(default)]
+    latest: Option<String>,
+    #[serde(default)]
+    fixes: Vec<String>,
+}
+
+fn version_check_path() -> std::io::Result<PathBuf> {
+    Ok(state_directory()?.join("version_check.json"))
+}
+
+fn load_version_check() -> Option<VersionCheck> {
+    let contents = std::fs::read_to_string(version_check_path().ok()?).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+fn save_version_check(check: &VersionCheck) -> Result<(), Box<dyn Error + Send + Sync>> {
+    std::fs::write(version_check_path()?, serde_json::to_string(check)?)?;
+    Ok(())
+}
+
+/// Print a notice when a newer release fixes bugs, so long lived copies do not keep reporting them.
+/// Failing to reach GitHub is never an error, the check is simply skipped.
+pub(crate) async fn check_for_update() {
+    if env::var_os(NO_VERSION_CHECK_VARIABLE).is_some() {
+        return
+    }
+    let fresh = |check: &VersionCheck| (Utc::now() - check.checked_at).num_hours() < CHECK_INTERVAL_HOURS;
+    let check = match load_version_check().filter(fresh) {
+        Some(check) => check,
+        None => {
+            let release = match client(CHECK_TIMEOUT) {
+                Ok(client) => latest_release(&client).await.ok(),
+                Err(_) => None,
+            };
+            let check = VersionCheck {
+                checked_at: Utc::now(),
+                latest: release.as_ref().map(|release| release.tag_name.to_owned()),
+                fixes: release.as_ref().map(Release::fixes).unwrap_or_default(),
+            };
+            if let Err(error) = save_version_check(&check) {
+                report::notice(format_args!("Could not save the version check. {}", error));
+            }
+            check
+        }
+    };
+    let Some(latest) = check.latest else { return };
+    let newer = latest.parse::<Version>().is_ok_and(|latest| latest > Version::current());
+    if newer && !check.fixes.is_empty() {
+        report::notice(format_args!(
+            "arcgis_scraper {} fixes bugs in this version ({}): {}. Update with `arcgis_scraper self-update`",
+            latest.trim_start_matches('v'),
+            Version::current(),
+            check.fixes.join("; "),
+        ));
+    }
+}
+
+/// Replace the running executable with the latest release's binary for this platform, after
+/// checking its published checksum. With `check_only`, only report whether one is available.
+pub(crate) async fn self_update(check_only: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let client = client(DOWNLOAD_TIMEOUT)?;
+    let release = latest_release(&client).await?;
+    let latest = release.version()?;
+    let current = Version::current();
+    println!("Running {}, latest release is {}", current, latest);
+    if latest <= current {
+        println!("Already up to date");
+        return Ok(())
+    }
+    for fix in release.fixes() {
+        println!("  {}", fix);
+    }
+    if check_only {
+        println!("Release notes: {}", release.html_url);
+        return Ok(())
+    }
+    let platform = platform();
+    let asset = release.asset(&platform).ok_or(UpdateError::NoAsset(platform))?;
+    let checksum_asset = release.checksum_asset(asset).ok_or_else(|| UpdateError::NoChecksum(asset.name.to_owned()))?;
+    println!("Downloading {}", asset.name);
+    let binary = client.get(&asset.browser_download_url).send().await?.error_for_status()?.bytes().await?;
+    let sidecar = client.get(&checksum_asset.browser_download_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let expected = sidecar.split_whitespace().next().unwrap_or_default().to_ascii_lowercase();
+    if format!("{:x}", Sha256::digest(&binary)) != expected {
+        return Err(UpdateError::ChecksumMismatch(asset.name.to_owned()).into())
+    }
+    replace_executable(&binary)?;
+    println!("Updated to {}", latest);
+    Ok(())
+}
+
+/// Write the new binary next to the running one and move it into place. Windows cannot replace
+/// a running executable, so the old one is moved aside to `.old` first.
+fn replace_executable(binary: &[u8]) -> std::io::Result<()> {
+    let executable = env::current_exe()?;
+    let mut part_name = executable.as_os_str().to_owned();
+    part_name.push(".part");
+    let part_path = PathBuf::from(part_name);
+    std::fs::write(&part_path, binary)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&part_path, std::fs::Permissions::from_mode(0o755))?;
+    }
+    if cfg!(windows) {
+        let mut old_name = executable.as_os_str().to_owned();
+        old_name.push(".old");
+        rename(&executable, PathBuf::from(old_name))?;
+    }
+    rename(&part_path, &executable)
+}
+
+#[cfg(test)]
+mod update_tests {
+    use super::{Release, ReleaseAsset, UpdateError, Version};
+
+    fn release(body: &str, assets: &[&str]) -> Release {
+        Release {
+            tag_name: "v0.3.0".to_owned(),
+            html_url: "https://github.com/ClasicRando/arcgis_scraper/releases/tag/v0.3.0".to_owned(),
+            body: Some(body.to_owned()),
+            assets: assets.iter()
+                .map(|name| ReleaseAsset {
+                    name: name.to_string(),
+                    browser_download_url: format!("https://example.com/{}", name),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn version_should_order_numerically_when_parsed_from_tags() {
+        assert!("v0.10.0".parse::<Version>().unwrap() > "0.9.3".parse().unwrap());
+        assert_eq!("v1.2.3-rc.1".parse::<Version>(), Ok(Version(1, 2, 3)));
+        assert_eq!("latest".parse::<Version>(), Err(UpdateError::InvalidVersion("latest".to_owned())));
+    }
+
+    #[test]
+    fn fixes_should_keep_fix_lines_when_notes_are_a_list() {
+        let release = release("## Changes\n- Fixed pagination skipping features\n* Add PBF\n- fix crash on null geometry", &[]);
+        assert_eq!(release.fixes(), vec!["Fixed pagination skipping features", "fix crash on null geometry"]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn asset_should_skip_checksum_when_matching_platform() {
+        let release = release("", &[
+            "arcgis_scraper-x86_64-linux-musl",
+            "arcgis_scraper-x86_64-linux.sha256",
+            "arcgis_scraper-x86_64-linux",
+            "arcgis_scraper-x86_64-windows.exe",
+        ]);
+        let asset = release.asset("x86_64-linux").unwrap();
+        assert_eq!(asset.name, "arcgis_scraper-x86_64-linux");
+        assert_eq!(release.checksum_asset(asset).unwrap().name, "arcgis_scraper-x86_64-linux.sha256");
+        assert!(release.asset("aarch64-macos").is_none());
+    }
+}
@@ -0,0 +1,617 @@
+use std::collections::HashMap;
+use serde_json::{Map, Value};
+use crate::geometry::Extent;
+use crate::metadata::RestServiceGeometryType;
+use crate::projection::transform_point;
+
+const WEB_MERCATOR: i64 = 3857;
+/// Half the width of the Web Mercator world, in meters.
+const HALF_WORLD: f64 = 20_037_508.342_789_244;
+/// Tile coordinate space of every tile (the usual Mapbox Vector Tile extent).
+pub(crate) const TILE_EXTENT: u32 = 4096;
+/// Geometries are kept this far past the tile edge so renderers do not show seams.
+const TILE_BUFFER: f64 = 64_f64;
+
+/// Coordinate in Web Mercator meters, or in tile units once a feature is cut into a tile.
+type Point = [f64; 2];
+
+/// Geometry of a feature in Web Mercator meters.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum TileGeometry {
+    Points(Vec<Point>),
+    Lines(Vec<Vec<Point>>),
+    /// Each polygon is its exterior ring followed by its holes, rings not repeating the
+    /// first point
+    Polygons(Vec<Vec<Vec<Point>>>),
+}
+
+/// Attribute value of a tile feature.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum TileValue {
+    String(String),
+    Double(f64),
+    Int(i64),
+    Bool(bool),
+}
+
+impl TileValue {
+    fn from_json(value: &Value) -> Option<TileValue> {
+        match value {
+            Value::Null => None,
+            Value::Bool(boolean) => Some(TileValue::Bool(*boolean)),
+            Value::Number(number) => match number.as_i64() {
+                Some(int) => Some(TileValue::Int(int)),
+                None => number.as_f64().map(TileValue::Double),
+            },
+            Value::String(string) => Some(TileValue::String(string.to_owned())),
+            other => Some(TileValue::String(other.to_string())),
+        }
+    }
+
+    /// Field type reported in the `vector_layers` metadata.
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            TileValue::String(_) => "String",
+            TileValue::Double(_) | TileValue::Int(_) => "Number",
+            TileValue::Bool(_) => "Boolean",
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buffer = vec![];
+        match self {
+            TileValue::String(string) => write_bytes(&mut buffer, 1, string.as_bytes()),
+            TileValue::Double(double) => {
+                write_key(&mut buffer, 3, 1);
+                buffer.extend_from_slice(&double.to_le_bytes());
+            }
+            TileValue::Int(int) => {
+                write_key(&mut buffer, 6, 0);
+                write_varint(&mut buffer, ((int << 1) ^ (int >> 63)) as u64);
+            }
+            TileValue::Bool(boolean) => {
+                write_key(&mut buffer, 7, 0);
+                write_varint(&mut buffer, u64::from(*boolean));
+            }
+        }
+        buffer
+    }
+}
+
+/// Scraped feature projected to Web Mercator, ready to be cut into tiles.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TileFeature {
+    pub(crate) geometry: TileGeometry,
+    pub(crate) properties: Vec<(String, TileValue)>,
+    pub(crate) bounds: Extent,
+}
+
+fn project_position(position: &Value, spatial_reference: i64) -> Option<Point> {
+    let position = position.as_array()?;
+    let x = position.first()?.as_f64()?;
+    let y = position.get(1)?.as_f64()?;
+    let (x, y) = transform_point(x, y, spatial_reference, WEB_MERCATOR)?;
+    Some([x, y.clamp(-HALF_WORLD, HALF_WORLD)])
+}
+
+fn project_parts(parts: &Value, spatial_reference: i64) -> Option<Vec<Vec<Point>>> {
+    parts.as_array()?
+        .iter()
+        .map(|part| {
+            part.as_array()?
+                .iter()
+                .map(|position| project_position(position, spatial_reference))
+                .collect()
+        })
+        .collect()
+}
+
+/// Twice the signed area of a ring, positive when counter-clockwise with y pointing up.
+pub(crate) fn signed_area(ring: &[Point]) -> f64 {
+    ring.iter()
+        .zip(ring.iter().cycle().skip(1))
+        .map(|(a, b)| a[0] * b[1] - b[0] * a[1])
+        .sum()
+}
+
+fn contains(ring: &[Point], point: &Point) -> bool {
+    let mut inside = false;
+    for (a, b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+        if (a[1] > point[1]) != (b[1] > point[1])
+            && point[0] < (b[0] - a[0]) * (point[1] - a[1]) / (b[1] - a[1]) + a[0] {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// Group Esri rings into polygons. Esri exterior rings are clockwise and holes
+/// counter-clockwise, holes belonging to the exterior ring that contains them. Servers that
+/// wind every ring the other way get their unowned holes kept as exteriors.
+pub(crate) fn group_rings(rings: Vec<Vec<Point>>) -> Vec<Vec<Vec<Point>>> {
+    let mut polygons: Vec<Vec<Vec<Point>>> = vec![];
+    let mut holes = vec![];
+    let mut orphans = vec![];
+    for mut ring in rings {
+        if ring.len() > 1 && ring.first() == ring.last() {
+            ring.pop();
+        }
+        if ring.len() < 3 {
+            continue
+        }
+        if signed_area(&ring) < 0_f64 {
+            polygons.push(vec![ring]);
+        } else {
+            holes.push(ring);
+        }
+    }
+    for hole in holes {
+        let owner = polygons.iter_mut()
+            .find(|polygon| contains(&polygon[0], &hole[0]));
+        match owner {
+            Some(polygon) => polygon.push(hole),
+            None => orphans.push(hole.into_iter().rev().collect()),
+        }
+    }
+    polygons.extend(orphans.into_iter().map(|ring| vec![ring]));
+    polygons
+}
+
+fn bounds_of<'a>(mut points: impl Iterator<Item = &'a Point>) -> Option<Extent> {
+    let first = points.next()?;
+    let mut bounds = Extent { x_min: first[0], y_min: first[1], x_max: first[0], y_max: first[1] };
+    for point in points {
+        bounds = bounds.union(&Extent { x_min: point[0], y_min: point[1], x_max: point[0], y_max: point[1] });
+    }
+    Some(bounds)
+}
+
+impl TileFeature {
+    /// Project an Esri JSON feature in `spatial_reference` to Web Mercator. None when the
+    /// feature has no geometry or its spatial reference cannot be projected client-side.
+    pub(crate) fn from_esri_json(
+        feature: &Map<String, Value>,
+        geo_type: &RestServiceGeometryType,
+        spatial_reference: i64,
+    ) -> Option<TileFeature> {
+        let geometry = feature.get("geometry")?.as_object()?;
+        let geometry = match geo_type {
+            RestServiceGeometryType::Point => {
+                let position = Value::from(vec![geometry.get("x")?.to_owned(), geometry.get("y")?.to_owned()]);
+                TileGeometry::Points(vec![project_position(&position, spatial_reference)?])
+            }
+            RestServiceGeometryType::Multipoint => TileGeometry::Points(
+                geometry.get("points")?
+                    .as_array()?
+                    .iter()
+                    .map(|position| project_position(position, spatial_reference))
+                    .collect::<Option<Vec<Point>>>()?
+            ),
+            RestServiceGeometryType::Polyline => TileGeometry::Lines(
+                project_parts(geometry.get("paths")?, spatial_reference)?
+                    .into_iter()
+                    .filter(|path| path.len() > 1)
+                    .collect()
+            ),
+            RestServiceGeometryType::Polygon => TileGeometry::Polygons(
+                group_rings(project_parts(geometry.get("rings")?, spatial_reference)?)
+            ),
+            RestServiceGeometryType::Envelope => {
+                let extent = Extent::from_esri_json(&Value::from(geometry.to_owned()))?;
+                let ring = Value::from(vec![
+                    vec![extent.x_min, extent.y_min],
+                    vec![extent.x_min, extent.y_max],
+                    vec![extent.x_max, extent.y_max],
+                    vec![extent.x_max, extent.y_min],
+                ]);
+                TileGeometry::Polygons(group_rings(project_parts(&Value::from(vec![ring]), spatial_reference)?))
+            }
+            RestServiceGeometryType::None => return None,
+        };
+        let bounds = match &geometry {
+            TileGeometry::Points(points) => bounds_of(points.iter()),
+            TileGeometry::Lines(lines) => bounds_of(lines.iter().flatten()),
+            TileGeometry::Polygons(polygons) => bounds_of(polygons.iter().flatten().flatten()),
+        }?;
+        let properties = feature.get("attributes")
+            .and_then(Value::as_object)
+            .map(|attributes| {
+                attributes.iter()
+                    .filter_map(|(key, value)| Some((key.to_owned(), TileValue::from_json(value)?)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Some(TileFeature { geometry, properties, bounds })
+    }
+}
+
+/// Tiles of zoom level `zoom` covering `bounds`, as inclusive x and y ranges.
+pub(crate) fn tile_range(bounds: &Extent, zoom: u8) -> ((u32, u32), (u32, u32)) {
+    let tiles = 1_u32 << zoom;
+    let column = |x: f64| {
+        (((x + HALF_WORLD) / (2_f64 * HALF_WORLD) * f64::from(tiles)).floor() as i64)
+            .clamp(0, i64::from(tiles) - 1) as u32
+    };
+    let row = |y: f64| {
+        (((HALF_WORLD - y) / (2_f64 * HALF_WORLD) * f64::from(tiles)).floor() as i64)
+            .clamp(0, i64::from(tiles) - 1) as u32
+    };
+    ((column(bounds.x_min), column(bounds.x_max)), (row(bounds.y_max), row(bounds.y_min)))
+}
+
+/// Longitude/latitude of a Web Mercator coordinate, for the archive header.
+pub(crate) fn to_lon_lat(x: f64, y: f64) -> (f64, f64) {
+    transform_point(x, y, WEB_MERCATOR, 4326).unwrap_or((x, y))
+}
+
+/// Position of `point` in the tile `x`/`y` of `zoom`, y pointing down.
+fn to_tile(point: &Point, zoom: u8, x: u32, y: u32) -> Point {
+    let scale = f64::from(1_u32 << zoom) / (2_f64 * HALF_WORLD);
+    [
+        ((point[0] + HALF_WORLD) * scale - f64::from(x)) * f64::from(TILE_EXTENT),
+        ((HALF_WORLD - point[1]) * scale - f64::from(y)) * f64::from(TILE_EXTENT),
+    ]
+}
+
+const CLIP_MIN: f64 = -TILE_BUFFER;
+const CLIP_MAX: f64 = TILE_EXTENT as f64 + TILE_BUFFER;
+
+fn in_clip(point: &Point) -> bool {
+    (CLIP_MIN..=CLIP_MAX).contains(&point[0]) && (CLIP_MIN..=CLIP_MAX).contains(&point[1])
+}
+
+/// Sutherland-Hodgman clip of a ring against the buffered tile square.
+fn clip_ring(ring: &[Point]) -> Vec<Point> {
+    let mut output = ring.to_vec();
+    for (axis, bound, keep_below) in [(0, CLIP_MIN, false), (0, CLIP_MAX, true), (1, CLIP_MIN, false), (1, CLIP_MAX, true)] {
+        let input = std::mem::take(&mut output);
+        let inside = |point: &Point| if keep_below { point[axis] <= bound } else { point[axis] >= bound };
+        for (index, current) in input.iter().enumerate() {
+            let previous = &input[(index + input.len() - 1) % input.len()];
+            let intersection = || {
+                let t = (bound - previous[axis]) / (current[axis] - previous[axis]);
+                let mut point = [
+                    previous[0] + t * (current[0] - previous[0]),
+                    previous[1] + t * (current[1] - previous[1]),
+                ];
+                point[axis] = bound;
+                point
+            };
+            match (inside(previous), inside(current)) {
+                (true, true) => output.push(*current),
+                (true, false) => output.push(intersection()),
+                (false, true) => {
+                    output.push(intersection());
+                    output.push(*current);
+                }
+                (false, false) => {}
+            }
+        }
+    }
+    output
+}
+
+/// Liang-Barsky clip of a single segment, None when it lies outside the tile.
+fn clip_segment(start: &Point, end: &Point) -> Option<(Point, Point)> {
+    let delta = [end[0] - start[0], end[1] - start[1]];
+    let (mut t_start, mut t_end) = (0_f64, 1_f64);
+    for axis in 0..2 {
+        for (p, q) in [(-delta[axis], start[axis] - CLIP_MIN), (delta[axis], CLIP_MAX - start[axis])] {
+            if p == 0_f64 {
+                if q < 0_f64 {
+                    return None
+                }
+            } else {
+                let t = q / p;
+                if p < 0_f64 {
+                    t_start = t_start.max(t);
+                } else {
+                    t_end = t_end.min(t);
+                }
+            }
+        }
+    }
+    if t_start > t_end {
+        return None
+    }
+    let at = |t: f64| [start[0] + t * delta[0], start[1] + t * delta[1]];
+    Some((at(t_start), at(t_end)))
+}
+
+/// Clip a line into the pieces that lie inside the tile.
+fn clip_line(line: &[Point]) -> Vec<Vec<Point>> {
+    let mut pieces = vec![];
+    let mut current: Vec<Point> = vec![];
+    for segment in line.windows(2) {
+        match clip_segment(&segment[0], &segment[1]) {
+            Some((start, end)) => {
+                if current.last() != Some(&start) && !current.is_empty() {
+                    pieces.push(std::mem::take(&mut current));
+                }
+                if current.is_empty() {
+                    current.push(start);
+                }
+                current.push(end);
+                if end != segment[1] {
+                    pieces.push(std::mem::take(&mut current));
+                }
+            }
+            None if !current.is_empty() => pieces.push(std::mem::take(&mut current)),
+            None => {}
+        }
+    }
+    if !current.is_empty() {
+        pieces.push(current);
+    }
+    pieces
+}
+
+/// Round to integer tile units, dropping repeated positions.
+fn snap(points: &[Point]) -> Vec<[i32; 2]> {
+    let mut snapped: Vec<[i32; 2]> = Vec::with_capacity(points.len());
+    for point in points {
+        let position = [point[0].round() as i32, point[1].round() as i32];
+        if snapped.last() != Some(&position) {
+            snapped.push(position);
+        }
+    }
+    snapped
+}
+
+fn snapped_area(ring: &[[i32; 2]]) -> i64 {
+    ring.iter()
+        .zip(ring.iter().cycle().skip(1))
+        .map(|(a, b)| i64::from(a[0]) * i64::from(b[1]) - i64::from(b[0]) * i64::from(a[1]))
+        .sum()
+}
+
+/// MVT geometry command stream, tracking the cursor between parts.
+#[derive(Default)]
+struct CommandWriter {
+    commands: Vec<u32>,
+    cursor: [i32; 2],
+}
+
+impl CommandWriter {
+    fn command(&mut self, id: u32, count: usize) {
+        self.commands.push((id & 0x7) | ((count as u32) << 3));
+    }
+
+    fn position(&mut self, position: &[i32; 2]) {
+        for (value, cursor) in position.iter().zip(self.cursor) {
+            let delta = value - cursor;
+            self.commands.push(((delta << 1) ^ (delta >> 31)) as u32);
+        }
+        self.cursor = *position;
+    }
+
+    /// MoveTo the first position and LineTo the rest.
+    fn path(&mut self, positions: &[[i32; 2]]) {
+        self.command(1, 1);
+        self.position(&positions[0]);
+        self.command(2, positions.len() - 1);
+        for position in &positions[1..] {
+            self.position(position);
+        }
+    }
+}
+
+/// Geometry type and command stream of `feature` within the tile `x`/`y` of `zoom`. None when
+/// nothing of the feature falls inside the tile.
+fn encode_geometry(feature: &TileFeature, zoom: u8, x: u32, y: u32) -> Option<(u32, Vec<u32>)> {
+    let mut writer = CommandWriter::default();
+    let geometry_type = match &feature.geometry {
+        TileGeometry::Points(points) => {
+            let positions: Vec<[i32; 2]> = points.iter()
+                .map(|point| to_tile(point, zoom, x, y))
+                .filter(in_clip)
+                .map(|point| [point[0].round() as i32, point[1].round() as i32])
+                .collect();
+            if positions.is_empty() {
+                return None
+            }
+            writer.command(1, positions.len());
+            for position in &positions {
+                writer.position(position);
+            }
+            1
+        }
+        TileGeometry::Lines(lines) => {
+            for line in lines {
+                let line: Vec<Point> = line.iter().map(|point| to_tile(point, zoom, x, y)).collect();
+                for piece in clip_line(&line) {
+                    let positions = snap(&piece);
+                    if positions.len() > 1 {
+                        writer.path(&positions);
+                    }
+                }
+            }
+            2
+        }
+        TileGeometry::Polygons(polygons) => {
+            for polygon in polygons {
+                let mut rings = polygon.iter().map(|ring| {
+                    let ring: Vec<Point> = ring.iter().map(|point| to_tile(point, zoom, x, y)).collect();
+                    let mut positions = snap(&clip_ring(&ring));
+                    if positions.len() > 1 && positions.first() == positions.last() {
+                        positions.pop();
+                    }
+                    positions
+                });
+                let exterior = rings.next().unwrap_or_default();
+                if exterior.len() < 3 || snapped_area(&exterior) <= 0 {
+                    continue
+                }
+                writer.path(&exterior);
+                writer.command(7, 1);
+                for hole in rings.filter(|hole| hole.len() >= 3 && snapped_area(hole) < 0) {
+                    writer.path(&hole);
+                    writer.command(7, 1);
+                }
+            }
+            3
+        }
+    };
+    if writer.commands.is_empty() {
+        return None
+    }
+    Some((geometry_type, writer.commands))
+}
+
+/// Features of one layer within a single tile, with the key and value tables they share.
+#[derive(Default)]
+pub(crate) struct TileLayer {
+    keys: Vec<String>,
+    key_indexes: HashMap<String, u32>,
+    values: Vec<Vec<u8>>,
+    value_indexes: HashMap<Vec<u8>, u32>,
+    features: Vec<u8>,
+}
+
+impl TileLayer {
+    /// Add the part of `feature` inside the tile, if any.
+    pub(crate) fn add(&mut self, feature: &TileFeature, zoom: u8, x: u32, y: u32) {
+        let (geometry_type, commands) = match encode_geometry(feature, zoom, x, y) {
+            Some(geometry) => geometry,
+            None => return,
+        };
+        let mut tags = Vec::with_capacity(feature.properties.len() * 2);
+        for (key, value) in &feature.properties {
+            let key_index = match self.key_indexes.get(key) {
+                Some(index) => *index,
+                None => {
+                    let index = self.keys.len() as u32;
+                    self.keys.push(key.to_owned());
+                    self.key_indexes.insert(key.to_owned(), index);
+                    index
+                }
+            };
+            let encoded = value.encode();
+            let value_index = match self.value_indexes.get(&encoded) {
+                Some(index) => *index,
+                None => {
+                    let index = self.values.len() as u32;
+                    self.values.push(encoded.to_owned());
+                    self.value_indexes.insert(encoded, index);
+                    index
+                }
+            };
+            tags.push(key_index);
+            tags.push(value_index);
+        }
+        let mut message = vec![];
+        write_packed(&mut message, 2, &tags);
+        write_key(&mut message, 3, 0);
+        write_varint(&mut message, u64::from(geometry_type));
+        write_packed(&mut message, 4, &commands);
+        write_bytes(&mut self.features, 2, &message);
+    }
+
+    pub(crate) fn has_features(&self) -> bool {
+        !self.features.is_empty()
+    }
+
+    /// Layer message named `name`, to be added to a tile with [`encode_tile`].
+    pub(crate) fn encode(&self, name: &str) -> Vec<u8> {
+        let mut layer = vec![];
+        write_key(&mut layer, 15, 0);
+        write_varint(&mut layer, 2);
+        write_bytes(&mut layer, 1, name.as_bytes());
+        layer.extend_from_slice(&self.features);
+        for key in &self.keys {
+            write_bytes(&mut layer, 3, key.as_bytes());
+        }
+        for value in &self.values {
+            write_bytes(&mut layer, 4, value);
+        }
+        write_key(&mut layer, 5, 0);
+        write_varint(&mut layer, u64::from(TILE_EXTENT));
+        layer
+    }
+}
+
+/// Tile message made of already encoded layers.
+pub(crate) fn encode_tile(layers: &[Vec<u8>]) -> Vec<u8> {
+    let mut tile = vec![];
+    for layer in layers {
+        write_bytes(&mut tile, 3, layer);
+    }
+    tile
+}
+
+pub(crate) fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn write_key(buffer: &mut Vec<u8>, field: u32, wire_type: u32) {
+    write_varint(buffer, u64::from((field << 3) | wire_type));
+}
+
+fn write_bytes(buffer: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    write_key(buffer, field, 2);
+    write_varint(buffer, bytes.len() as u64);
+    buffer.extend_from_slice(bytes);
+}
+
+fn write_packed(buffer: &mut Vec<u8>, field: u32, values: &[u32]) {
+    let mut packed = vec![];
+    for value in values {
+        write_varint(&mut packed, u64::from(*value));
+    }
+    write_bytes(buffer, field, &packed);
+}
+
+#[cfg(test)]
+mod vector_tiles_tests {
+    use serde_json::json;
+    use crate::metadata::RestServiceGeometryType;
+    use super::{clip_line, encode_geometry, group_rings, TileFeature, TileGeometry};
+
+    #[test]
+    fn encode_geometry_should_write_spec_commands_when_passed_point() {
+        // Web Mercator origin is the top left corner of tile 1/1/1
+        let feature = TileFeature::from_esri_json(
+            json!({"geometry": {"x": 0.0, "y": 0.0}, "attributes": {}}).as_object().unwrap(),
+            &RestServiceGeometryType::Point,
+            3857,
+        ).unwrap();
+        assert_eq!(encode_geometry(&feature, 1, 1, 1), Some((1, vec![9, 0, 0])));
+        assert_eq!(encode_geometry(&feature, 2, 0, 0), None);
+    }
+
+    #[test]
+    fn group_rings_should_attach_hole_to_containing_exterior() {
+        let exterior = vec![[0.0, 0.0], [0.0, 10.0], [10.0, 10.0], [10.0, 0.0], [0.0, 0.0]];
+        let hole = vec![[2.0, 2.0], [8.0, 2.0], [8.0, 8.0], [2.0, 8.0], [2.0, 2.0]];
+        let polygons = group_rings(vec![hole.clone(), exterior.clone()]);
+        assert_eq!(polygons, vec![vec![exterior[..4].to_vec(), hole[..4].to_vec()]]);
+    }
+
+    #[test]
+    fn group_rings_should_keep_unowned_hole_as_exterior() {
+        let ring = vec![[0.0, 0.0], [10.0, 0.0], [10.0, 10.0], [0.0, 10.0], [0.0, 0.0]];
+        let polygons = group_rings(vec![ring]);
+        assert_eq!(polygons, vec![vec![vec![[0.0, 10.0], [10.0, 10.0], [10.0, 0.0], [0.0, 0.0]]]]);
+    }
+
+    #[test]
+    fn clip_line_should_split_line_when_leaving_and_reentering_tile() {
+        let line = vec![[100.0, 100.0], [100.0, 8292.0], [200.0, 8292.0], [200.0, 100.0]];
+        let pieces = clip_line(&line);
+        assert_eq!(pieces, vec![
+            vec![[100.0, 100.0], [100.0, 4160.0]],
+            vec![[200.0, 4160.0], [200.0, 100.0]],
+        ]);
+    }
+
+    #[test]
+    fn from_esri_json_should_return_none_when_spatial_reference_unsupported() {
+        let feature = json!({"geometry": {"x": 1.0, "y": 2.0}, "attributes": {}});
+        let result = TileFeature::from_esri_json(feature.as_object().unwrap(), &RestServiceGeometryType::Point, 2913);
+        assert_eq!(result.map(|feature| feature.geometry), None::<TileGeometry>);
+    }
+}
@@ -0,0 +1,193 @@
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+use crate::geometry::{esri_parts, ring_contains};
+use crate::metadata::{RestServiceField, RestServiceFieldType, RestServiceGeometryType};
+
+/// Ring or path of x/y positions.
+pub(crate) type Ring = Vec<(f64, f64)>;
+
+/// Column holding the geometry of each feature in CSV outputs with WKT geometries.
+const WKT_COLUMN: &str = "WKT";
+
+/// How the geometry of each feature is written to CSV outputs.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub(crate) enum GeometryEncoding {
+    /// Esri JSON coordinates, X and Y columns for points and a POINTS, PATHS, RINGS or ENVELOPE
+    /// column otherwise
+    Esri,
+    /// A single WKT column, readable by spreadsheets, pandas and most GIS tools
+    Wkt,
+}
+
+/// Fields of a layer with its geometry columns replaced by the single WKT column. Tables are
+/// left as is.
+pub(crate) fn wkt_fields(fields: Vec<RestServiceField>) -> Vec<RestServiceField> {
+    let has_geometry = fields.iter().any(|field| field.field_type == RestServiceFieldType::Geometry);
+    let mut fields: Vec<RestServiceField> = fields.into_iter()
+        .filter(|field| field.field_type != RestServiceFieldType::Geometry)
+        .collect();
+    if has_geometry {
+        fields.push(RestServiceField::for_geometry(WKT_COLUMN));
+    }
+    fields
+}
+
+fn position(point: &(f64, f64)) -> String {
+    format!("{} {}", point.0, point.1)
+}
+
+fn sequence(points: &[(f64, f64)]) -> String {
+    format!("({})", points.iter().map(position).collect::<Vec<String>>().join(", "))
+}
+
+/// Ring closed on its first position, as WKT requires.
+fn closed(mut ring: Ring) -> Ring {
+    if let (Some(first), Some(last)) = (ring.first().copied(), ring.last()) {
+        if first != *last {
+            ring.push(first);
+        }
+    }
+    ring
+}
+
+/// Group rings into polygons by how they nest, a ring inside an odd number of rings being a
+/// hole of the smallest exterior holding it. Unlike the Esri winding this also holds once the
+/// rings were rewound for --ring-winding or sent reversed by the server.
+pub(crate) fn group_rings(rings: Vec<Ring>) -> Vec<Vec<Ring>> {
+    let rings: Vec<Ring> = rings.into_iter()
+        .filter(|ring| ring.len() >= 3)
+        .map(closed)
+        .collect();
+    let containers: Vec<Vec<usize>> = rings.iter()
+        .enumerate()
+        .map(|(index, ring)| {
+            (0..rings.len())
+                .filter(|other| *other != index && ring_contains(&rings[*other], &ring[0]))
+                .collect()
+        })
+        .collect();
+    let mut polygons: Vec<(usize, Vec<Ring>)> = containers.iter()
+        .enumerate()
+        .filter(|(_, holders)| holders.len() % 2 == 0)
+        .map(|(index, _)| (index, vec![rings[index].clone()]))
+        .collect();
+    for (index, holders) in containers.iter().enumerate().filter(|(_, holders)| holders.len() % 2 == 1) {
+        // The innermost holder is the one held by every other holder
+        let owner = holders.iter()
+            .max_by_key(|holder| containers[**holder].len())
+            .and_then(|holder| polygons.iter_mut().find(|(exterior, _)| exterior == holder));
+        if let Some((_, polygon)) = owner {
+            polygon.push(rings[index].clone());
+        }
+    }
+    polygons.into_iter().map(|(_, polygon)| polygon).collect()
+}
+
+fn polygon_text(polygon: &[Ring]) -> String {
+    format!("({})", polygon.iter().map(|ring| sequence(ring)).collect::<Vec<String>>().join(", "))
+}
+
+/// WKT of an Esri JSON geometry, ignoring z and m values. Features without a geometry get an
+/// empty value, geometries without coordinates an EMPTY one.
+pub(crate) fn esri_to_wkt(geo_type: &RestServiceGeometryType, feature: &Map<String, Value>) -> String {
+    let geometry = match feature.get("geometry").and_then(Value::as_object) {
+        Some(geometry) => geometry,
+        None => return String::new(),
+    };
+    let coordinate = |key: &str| geometry.get(key).and_then(Value::as_f64);
+    match geo_type {
+        RestServiceGeometryType::Point => match (coordinate("x"), coordinate("y")) {
+            (Some(x), Some(y)) => format!("POINT ({})", position(&(x, y))),
+            _ => "POINT EMPTY".to_owned(),
+        },
+        RestServiceGeometryType::Multipoint => {
+            let points: Vec<String> = esri_parts(geometry, "points")
+                .into_iter()
+                .flatten()
+                .map(|point| format!("({})", position(&point)))
+                .collect();
+            if points.is_empty() {
+                return "MULTIPOINT EMPTY".to_owned()
+            }
+            format!("MULTIPOINT ({})", points.join(", "))
+        }
+        RestServiceGeometryType::Polyline => {
+            let paths: Vec<Ring> = esri_parts(geometry, "paths")
+                .into_iter()
+                .filter(|path| path.len() >= 2)
+                .collect();
+            match paths.as_slice() {
+                [] => "LINESTRING EMPTY".to_owned(),
+                [path] => format!("LINESTRING {}", sequence(path)),
+                paths => format!(
+                    "MULTILINESTRING ({})",
+                    paths.iter().map(|path| sequence(path)).collect::<Vec<String>>().join(", "),
+                ),
+            }
+        }
+        RestServiceGeometryType::Polygon => {
+            match group_rings(esri_parts(geometry, "rings")).as_slice() {
+                [] => "POLYGON EMPTY".to_owned(),
+                [polygon] => format!("POLYGON {}", polygon_text(polygon)),
+                polygons => format!(
+                    "MULTIPOLYGON ({})",
+                    polygons.iter().map(|polygon| polygon_text(polygon)).collect::<Vec<String>>().join(", "),
+                ),
+            }
+        }
+        RestServiceGeometryType::Envelope => {
+            match (coordinate("xmin"), coordinate("ymin"), coordinate("xmax"), coordinate("ymax")) {
+                (Some(x_min), Some(y_min), Some(x_max), Some(y_max)) => format!(
+                    "POLYGON ({})",
+                    sequence(&[(x_min, y_min), (x_max, y_min), (x_max, y_max), (x_min, y_max), (x_min, y_min)]),
+                ),
+                _ => "POLYGON EMPTY".to_owned(),
+            }
+        }
+        RestServiceGeometryType::None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod wkt_tests {
+    use serde_json::json;
+    use crate::metadata::RestServiceGeometryType;
+    use super::esri_to_wkt;
+
+    fn wkt(geo_type: RestServiceGeometryType, geometry: serde_json::Value) -> String {
+        let feature = json!({"attributes": {}, "geometry": geometry});
+        esri_to_wkt(&geo_type, feature.as_object().unwrap())
+    }
+
+    #[test]
+    fn esri_to_wkt_should_write_point_when_passed_point() {
+        assert_eq!(wkt(RestServiceGeometryType::Point, json!({"x": 1.5, "y": -2})), "POINT (1.5 -2)");
+        assert_eq!(wkt(RestServiceGeometryType::Point, json!({"x": "NaN", "y": null})), "POINT EMPTY");
+        let feature = json!({"attributes": {}});
+        assert_eq!(esri_to_wkt(&RestServiceGeometryType::Point, feature.as_object().unwrap()), "");
+    }
+
+    #[test]
+    fn esri_to_wkt_should_write_multilinestring_when_passed_several_paths() {
+        let geometry = json!({"paths": [[[0, 0], [1, 1]], [[2, 2], [3, 3]]]});
+        assert_eq!(
+            wkt(RestServiceGeometryType::Polyline, geometry),
+            "MULTILINESTRING ((0 0, 1 1), (2 2, 3 3))",
+        );
+    }
+
+    #[test]
+    fn esri_to_wkt_should_group_holes_when_passed_rings_in_any_winding() {
+        let exterior = json!([[0, 0], [0, 10], [10, 10], [10, 0], [0, 0]]);
+        let hole = json!([[2, 2], [4, 2], [4, 4], [2, 4], [2, 2]]);
+        let island = json!([[20, 20], [20, 21], [21, 21], [21, 20], [20, 20]]);
+        assert_eq!(
+            wkt(RestServiceGeometryType::Polygon, json!({"rings": [hole, exterior]})),
+            "POLYGON ((0 0, 0 10, 10 10, 10 0, 0 0), (2 2, 4 2, 4 4, 2 4, 2 2))",
+        );
+        assert_eq!(
+            wkt(RestServiceGeometryType::Polygon, json!({"rings": [exterior, island]})),
+            "MULTIPOLYGON (((0 0, 0 10, 10 10, 10 0, 0 0)), ((20 20, 20 21, 21 21, 21 20, 20 20)))",
+        );
+    }
+}